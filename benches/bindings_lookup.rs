@@ -0,0 +1,60 @@
+//! Times `ResolvedBindings::lookup`, the per-keystroke path, and checks it never allocates.
+//!
+//! Run with `cargo bench --bench bindings_lookup`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use visiosynth::synth::{Config, KeyId, ResolvedBindings};
+use winit::keyboard::{Key, NamedKey, SmolStr};
+
+/// Counts every allocation made through it.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const LOOKUPS: usize = 1_000_000;
+
+fn main() {
+    let config: Config = serde_yaml::from_str(include_str!("../resources/config/settings.yaml"))
+        .expect("the bundled settings should parse");
+    let bindings = ResolvedBindings::from_config(&config);
+    let keys = [
+        KeyId::new(Key::Character(SmolStr::new("a")), false),
+        KeyId::new(Key::Character(SmolStr::new("a")), true),
+        KeyId::new(Key::Named(NamedKey::ArrowUp), false),
+        KeyId::new(Key::Character(SmolStr::new("@")), false),
+    ];
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        black_box(bindings.lookup(black_box(&keys[i % keys.len()])));
+    }
+    let elapsed = start.elapsed();
+    let allocated = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "lookup: {:.1} ns each over {} lookups, {} allocations",
+        elapsed.as_nanos() as f64 / LOOKUPS as f64,
+        LOOKUPS,
+        allocated
+    );
+    assert_eq!(allocated, 0, "lookup allocated");
+}
@@ -0,0 +1,37 @@
+//! Times `WaveformGenerator::get_sample` in each interpolation mode, the cost of one voice for
+//! one sample.
+//!
+//! Run with `cargo bench --bench interpolation`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use visiosynth::synth::{Interpolation, OscillatorWaveform, WaveformGenerator};
+
+const SAMPLES: usize = 10_000_000;
+
+fn main() {
+    for interpolation in [
+        Interpolation::Nearest,
+        Interpolation::Linear,
+        Interpolation::CubicHermite,
+    ] {
+        // A frequency that never settles into reading the same entries, like a played note.
+        let mut generator =
+            WaveformGenerator::new(OscillatorWaveform::Sawtooth, 440.0 * 2f32.sqrt(), 48_000.0);
+        generator.set_interpolation(interpolation);
+
+        let start = Instant::now();
+        for _ in 0..SAMPLES {
+            black_box(generator.get_sample());
+        }
+        let elapsed = start.elapsed();
+
+        println!(
+            "{:?}: {:.2} ns a sample over {} samples",
+            interpolation,
+            elapsed.as_nanos() as f64 / SAMPLES as f64,
+            SAMPLES
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! Times a rate-limited log site on a hot loop against the same loop without one, and checks
+//! that suppressed calls never allocate.
+//!
+//! Run with `cargo bench --bench log_limit`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use visiosynth::synth::log_limit::{LogLimiter, HOT_PATH_LOG_INTERVAL};
+
+/// Counts every allocation made through it.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const CALLS: usize = 1_000_000;
+
+/// The per-sample work the log site sits in, standing in for a block of audio.
+fn step(sample: f32) -> f32 {
+    (sample * 0.999 + 0.001).sin()
+}
+
+/// Runs `body` over `CALLS` samples, returning how long it took and how many allocations it
+/// made.
+fn time(mut body: impl FnMut(usize, f32) -> f32) -> (Duration, usize) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut sample = 0.0;
+    for i in 0..CALLS {
+        sample = body(black_box(i), black_box(sample));
+    }
+    black_box(sample);
+    (
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+    )
+}
+
+fn main() {
+    // A subscriber that formats every event, so an event let through costs what it would in
+    // the app.
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_writer(io::sink)
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        // What `log_rate_limited!` expands to; the macro itself is private to the crate.
+        static LIMITER: LogLimiter = LogLimiter::new();
+        let mut limited = |i: usize, sample: f32| {
+            if LIMITER.allow(HOT_PATH_LOG_INTERVAL) {
+                tracing::warn!("sample {} out of range: {}", i, sample);
+            }
+            step(sample)
+        };
+        // The first call lets its message out; time the suppressed calls after it.
+        limited(0, 0.0);
+
+        let (bare, _) = time(|_, sample| step(sample));
+        let (guarded, allocated) = time(&mut limited);
+        println!(
+            "without log site: {:.1} ns per sample over {} samples",
+            bare.as_nanos() as f64 / CALLS as f64,
+            CALLS
+        );
+        println!(
+            "rate-limited log site: {:.1} ns per sample, {} allocations",
+            guarded.as_nanos() as f64 / CALLS as f64,
+            allocated
+        );
+        if guarded < HOT_PATH_LOG_INTERVAL {
+            assert_eq!(allocated, 0, "a suppressed log call allocated");
+        }
+    });
+}
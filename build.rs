@@ -0,0 +1,29 @@
+//! Generates the C header from src/ffi.rs into OUT_DIR when building with the `ffi` feature.
+//! tests/ffi_host.rs checks the committed include/visiosynth.h against it, so the header can't
+//! drift from the functions it declares.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "ffi")]
+    generate_header();
+}
+
+#[cfg(feature = "ffi")]
+fn generate_header() {
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=src/synth/engine.rs");
+
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+        .expect("cbindgen.toml is invalid");
+    // The functions are all in ffi.rs; engine.rs is only read for the opaque `SynthEngine`.
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(format!("{}/src/ffi.rs", crate_dir))
+        .with_src(format!("{}/src/synth/engine.rs", crate_dir))
+        .generate()
+        .expect("couldn't generate the C header")
+        .write_to_file(format!("{}/visiosynth.h", out_dir));
+}
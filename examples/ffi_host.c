@@ -0,0 +1,63 @@
+/*
+ * Plays a short arpeggio through the C interface and prints the peak level of each note.
+ *
+ * Build the library and link against it from the repository root (tests/ffi_host.rs does the
+ * same on every `cargo test --features ffi`):
+ *   cargo build --release --lib --features ffi
+ *   cc examples/ffi_host.c -Iinclude -Ltarget/release -lvisiosynth -lm -o ffi_host
+ *   LD_LIBRARY_PATH=target/release ./ffi_host
+ */
+
+#include <math.h>
+#include <stdio.h>
+
+#include "visiosynth.h"
+
+#define SAMPLE_RATE 48000.0f
+#define CHANNELS 2
+#define FRAMES 512
+
+int main(void) {
+  SynthEngine *engine = synth_engine_new(SAMPLE_RATE, 8);
+  if (engine == NULL) {
+    fprintf(stderr, "could not create the engine\n");
+    return 1;
+  }
+
+  printf("parameters:");
+  for (uint32_t id = 0; id < synth_param_count(); id++) {
+    printf(" %u=%s", id, synth_param_name(id));
+  }
+  printf("\n");
+
+  /* 2 is the square wave; see the `waveform` parameter. */
+  if (synth_engine_set_param(engine, 0, 2.0f) != SYNTH_STATUS_OK) {
+    fprintf(stderr, "could not set the waveform\n");
+    synth_engine_free(engine);
+    return 1;
+  }
+
+  static float buffer[FRAMES * CHANNELS];
+  const uint8_t notes[] = {60, 64, 67, 72};
+  for (size_t i = 0; i < sizeof(notes); i++) {
+    float peak = 0.0f;
+    synth_engine_note_on(engine, notes[i], 100);
+    /* About a quarter of a second per note. */
+    for (int block = 0; block < 24; block++) {
+      SynthStatus status = synth_engine_process(engine, buffer, FRAMES, CHANNELS);
+      if (status != SYNTH_STATUS_OK) {
+        fprintf(stderr, "processing failed with status %d\n", status);
+        synth_engine_free(engine);
+        return 1;
+      }
+      for (size_t s = 0; s < FRAMES * CHANNELS; s++) {
+        peak = fmaxf(peak, fabsf(buffer[s]));
+      }
+    }
+    synth_engine_note_off(engine, notes[i]);
+    printf("note %u: peak %.4f\n", notes[i], peak);
+  }
+
+  synth_engine_free(engine);
+  return 0;
+}
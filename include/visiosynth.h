@@ -0,0 +1,90 @@
+#ifndef VISIOSYNTH_H
+#define VISIOSYNTH_H
+
+/* Generated by cbindgen from src/ffi.rs. Don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// What a call into the engine came to.
+typedef enum SynthStatus {
+  SYNTH_STATUS_OK = 0,
+  // The engine or buffer pointer was null.
+  SYNTH_STATUS_NULL_POINTER = -1,
+  // An argument was out of range, such as a MIDI note above 127 or an unknown parameter.
+  SYNTH_STATUS_INVALID_ARGUMENT = -2,
+  // The engine panicked. It may be left in an inconsistent state and should be freed.
+  SYNTH_STATUS_PANIC = -3,
+} SynthStatus;
+
+// The synthesis half of the audio callback: turns the shared note state into samples.
+//
+// The engine knows nothing about devices or windows, so the same code drives the cpal stream
+// and offline rendering.
+typedef struct SynthEngine SynthEngine;
+
+// Creates an engine rendering at `sample_rate` with at most `max_voices` notes sounding at
+// once, or any number when `max_voices` is 0. Returns null if the sample rate isn't positive.
+//
+// Free the engine with `synth_engine_free`.
+struct SynthEngine *synth_engine_new(float sample_rate, uint32_t max_voices);
+
+// Frees an engine made by `synth_engine_new`. Null is ignored.
+//
+// # Safety
+//
+// `engine` must be null or a pointer returned by `synth_engine_new` that hasn't been freed,
+// and mustn't be used again afterwards.
+void synth_engine_free(struct SynthEngine *engine);
+
+// Starts MIDI note `midi_note` struck at `velocity`. A velocity of 0 stops the note, as in
+// MIDI.
+//
+// # Safety
+//
+// `engine` must be null or a live pointer from `synth_engine_new`.
+enum SynthStatus synth_engine_note_on(struct SynthEngine *engine,
+                                      uint8_t midi_note,
+                                      uint8_t velocity);
+
+// Stops MIDI note `midi_note`, letting its release ring out.
+//
+// # Safety
+//
+// `engine` must be null or a live pointer from `synth_engine_new`.
+enum SynthStatus synth_engine_note_off(struct SynthEngine *engine, uint8_t midi_note);
+
+// Sets parameter `param_id` to `value`, clamped to its range. The ids are listed by
+// `synth_param_count` and `synth_param_name`.
+//
+// # Safety
+//
+// `engine` must be null or a live pointer from `synth_engine_new`.
+enum SynthStatus synth_engine_set_param(struct SynthEngine *engine, uint32_t param_id, float value);
+
+// Renders `frames` frames of `channels` interleaved channels into `out`, which must hold
+// `frames * channels` floats. The engine renders mono, copied to every channel, unless its
+// unison stack has a `stereo_spread`. Then it renders stereo, planar inside the engine: the
+// left and right channels are interleaved into the first two channels of `out` and any
+// others are left silent.
+//
+// # Safety
+//
+// `engine` must be null or a live pointer from `synth_engine_new`, and `out` must be null or
+// valid for writing `frames * channels` floats.
+enum SynthStatus synth_engine_process(struct SynthEngine *engine,
+                                      float *out,
+                                      size_t frames,
+                                      size_t channels);
+
+// How many parameters there are. Their ids run from 0 to one less than this.
+uint32_t synth_param_count(void);
+
+// The name of parameter `param_id`, such as `"tremolo_rate"`, or null for an unknown id. The
+// string is static; don't free it.
+const char *synth_param_name(uint32_t param_id);
+
+#endif /* VISIOSYNTH_H */
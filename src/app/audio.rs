@@ -0,0 +1,495 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use tracing::{info, warn};
+
+use crate::app::Shared;
+use crate::synth::{
+    backend::{connect_jack_outputs, Backend},
+    route_channels, AudioConfigInfo, CallbackTimer, DitherConfig, OutputConverter, SynthEngine,
+    VisualFeed,
+};
+
+/// Starts the audio stream on a thread of its own, in the device's sample format. The thread
+/// returns once the stream is done with.
+///
+/// The engine is built there and mixes the playing voices, applies the wave shaper and feeds
+/// the visualizer from inside the device's callback.
+pub fn spawn_audio_thread(
+    backend: Backend,
+    device: cpal::Device,
+    config: cpal::SupportedStreamConfig,
+    shared: Shared,
+    global_time: Arc<AtomicU64>,
+) -> JoinHandle<Result<()>> {
+    std::thread::spawn(move || match config.sample_format() {
+        cpal::SampleFormat::F32 => {
+            run_audio_loop::<f32, _>(backend, &device, &config.into(), shared, global_time)
+        }
+        cpal::SampleFormat::I16 => {
+            run_audio_loop::<i16, _>(backend, &device, &config.into(), shared, global_time)
+        }
+        cpal::SampleFormat::U16 => {
+            run_audio_loop::<u16, _>(backend, &device, &config.into(), shared, global_time)
+        }
+        _ => panic!("Unsupported sample format"),
+    })
+}
+
+/// Block length `CaptureOutput` runs the callback with when the config leaves it to the device.
+const DEFAULT_CAPTURE_FRAMES: usize = 512;
+
+/// Where the audio loop's samples go: a cpal device, or a stand-in such as `CaptureOutput`.
+pub trait AudioOutput {
+    type Stream;
+
+    /// Builds a stream that calls `callback` for every block with the block's interleaved
+    /// samples to fill, and the time since the stream's first block when the output knows it.
+    /// The samples reach the output as `T`, dithered as `dither` says when `T` needs it.
+    fn build_stream<T, F>(
+        &self,
+        config: &cpal::StreamConfig,
+        dither: &DitherConfig,
+        callback: F,
+    ) -> Result<Self::Stream>
+    where
+        T: cpal::SizedSample + cpal::FromSample<f32>,
+        F: FnMut(&mut [f32], Option<Duration>) + Send + 'static;
+
+    fn play(&self, stream: &Self::Stream) -> Result<()>;
+
+    /// Blocks for as long as the stream is meant to play.
+    fn wait(&self, stream: &Self::Stream);
+}
+
+impl AudioOutput for cpal::Device {
+    type Stream = cpal::Stream;
+
+    fn build_stream<T, F>(
+        &self,
+        config: &cpal::StreamConfig,
+        dither: &DitherConfig,
+        mut callback: F,
+    ) -> Result<cpal::Stream>
+    where
+        T: cpal::SizedSample + cpal::FromSample<f32>,
+        F: FnMut(&mut [f32], Option<Duration>) + Send + 'static,
+    {
+        let mut samples = Vec::new();
+        let mut stream_origin: Option<cpal::StreamInstant> = None;
+        let mut converter =
+            OutputConverter::for_format(dither, T::FORMAT, config.channels as usize);
+
+        // We define an error function to handle any errors that may occur during audio streaming.
+        let err_fn = |err| eprintln!("An error occurred on the audio stream: {}", err);
+
+        let stream = self.build_output_stream(
+            config,
+            move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+                // Callback timestamps are measured from the first one, so gaps between callbacks
+                // can be told apart from the engine running slow.
+                let callback_instant = info.timestamp().callback;
+                let stream_time =
+                    callback_instant.duration_since(stream_origin.get_or_insert(callback_instant));
+
+                samples.resize(data.len(), 0.0);
+                callback(&mut samples, stream_time);
+
+                // We convert the floating-point samples to the output sample type and write them
+                // to the audio output buffer. This ensures that the synthesized audio is
+                // compatible with the audio backend and can be played back through the audio
+                // device. Integer formats short on precision are dithered first; float output
+                // goes straight through.
+                match converter.as_mut() {
+                    Some(converter) => converter.convert(&samples, data),
+                    None => {
+                        for (out, sample) in data.iter_mut().zip(samples.iter()) {
+                            *out = T::from_sample(*sample);
+                        }
+                    }
+                }
+            },
+            err_fn,
+            None,
+        )?;
+        Ok(stream)
+    }
+
+    fn play(&self, stream: &cpal::Stream) -> Result<()> {
+        stream.play()?;
+        Ok(())
+    }
+
+    fn wait(&self, _stream: &cpal::Stream) {
+        std::thread::sleep(Duration::from_secs(100));
+    }
+}
+
+/// An output with no device behind it, for running the whole path from notes to samples where
+/// there is no audio hardware. Waiting on its stream runs the callback for a set number of
+/// blocks, as fast as it can, and keeps every sample written.
+pub struct CaptureOutput {
+    blocks: usize,
+    captured: Arc<Mutex<Vec<f32>>>,
+}
+
+/// What a `CaptureOutput` stream calls for every block.
+type BlockCallback = Box<dyn FnMut(&mut [f32], Option<Duration>) + Send>;
+
+/// A `CaptureOutput` stream: the callback, and how many samples a block holds.
+pub struct CaptureStream {
+    callback: Mutex<BlockCallback>,
+    block_len: usize,
+}
+
+impl CaptureOutput {
+    /// An output whose streams play `blocks` blocks.
+    pub fn new(blocks: usize) -> Self {
+        CaptureOutput {
+            blocks,
+            captured: Arc::default(),
+        }
+    }
+
+    /// The interleaved samples written so far, laid out over the config's channels.
+    pub fn captured(&self) -> Vec<f32> {
+        self.captured.lock().unwrap().clone()
+    }
+}
+
+impl AudioOutput for CaptureOutput {
+    type Stream = CaptureStream;
+
+    /// Keeps the samples as the callback wrote them, so `dither` doesn't apply.
+    fn build_stream<T, F>(
+        &self,
+        config: &cpal::StreamConfig,
+        _dither: &DitherConfig,
+        callback: F,
+    ) -> Result<CaptureStream>
+    where
+        T: cpal::SizedSample + cpal::FromSample<f32>,
+        F: FnMut(&mut [f32], Option<Duration>) + Send + 'static,
+    {
+        let frames = match config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => frames as usize,
+            cpal::BufferSize::Default => DEFAULT_CAPTURE_FRAMES,
+        };
+        Ok(CaptureStream {
+            callback: Mutex::new(Box::new(callback)),
+            block_len: frames * config.channels as usize,
+        })
+    }
+
+    fn play(&self, _stream: &CaptureStream) -> Result<()> {
+        Ok(())
+    }
+
+    /// Runs every block at once. There is no device clock, so the callback gets no timestamps.
+    fn wait(&self, stream: &CaptureStream) {
+        let mut callback = stream.callback.lock().unwrap();
+        let mut block = vec![0.0; stream.block_len];
+        for _ in 0..self.blocks {
+            block.fill(0.0);
+            callback(&mut block, None);
+            self.captured.lock().unwrap().extend_from_slice(&block);
+        }
+    }
+}
+
+/// Builds the engine from `shared` and plays it through `output` until the output is done
+/// waiting, then logs what the engine saw.
+pub fn run_audio_loop<T, O>(
+    backend: Backend,
+    output: &O,
+    config: &cpal::StreamConfig,
+    shared: Shared,
+    global_time: Arc<AtomicU64>,
+) -> Result<(), anyhow::Error>
+where
+    T: cpal::Sample + cpal::SizedSample + cpal::FromSample<f32>,
+    O: AudioOutput,
+{
+    let Shared {
+        keys_config,
+        display_config,
+        waveform_type,
+        note_state,
+        octave_shift,
+        tremolo_effect,
+        scale,
+        downsampled_audio_data,
+        visuals_enabled,
+        quality_level,
+        demo,
+    } = shared;
+
+    AudioConfigInfo::new(config, T::FORMAT).log();
+
+    // The visualizer gets its frames through a feed that gathers a frame's worth of audio at a
+    // time and averages it down, which keeps the load off the render thread. At low frame rates
+    // or high sample rates a frame can hold more than `max_audio_samples`; the most recent
+    // values are the ones shown.
+    let sample_rate: f32 = config.sample_rate.0 as f32;
+    let mut visual_feed = VisualFeed::new(
+        sample_rate,
+        config.channels as usize,
+        display_config.visualizer.max_audio_samples,
+        display_config.visualizer.downsample.clone(),
+        downsampled_audio_data,
+    );
+    let channels = config.channels as usize;
+    let extra_channels = keys_config.audio.routing.extra_channels;
+    // The engine renders mono unless a unison stack is spread in stereo and the device can play
+    // it; the signal is spread over the device's channels after it is rendered.
+    let engine_channels = if channels >= 2 && keys_config.oscillator.unison.is_stereo() {
+        2
+    } else {
+        1
+    };
+    let mut routed_samples = Vec::new();
+    let mut routed_pre_effects = Vec::new();
+
+    let mut engine = SynthEngine::builder()
+        .num_channels(engine_channels)
+        .waveform_type(waveform_type)
+        .note_state(note_state)
+        .octave_shift(octave_shift)
+        .global_time(global_time)
+        .tremolo_effect(tremolo_effect)
+        .scale(scale)
+        .oscillator_config(keys_config.oscillator.clone())
+        .ribbon_config(keys_config.ribbon.clone())
+        .diagnostics_config(keys_config.diagnostics.clone())
+        .waveform_sequence_config(keys_config.waveform_sequence.clone())
+        .wave_shaper_config(keys_config.wave_shaper.clone())
+        .ducking_config(keys_config.ducking.clone())
+        .looper_config(keys_config.looper.clone())
+        .mute_config(keys_config.mute.clone())
+        .effects_config(keys_config.effects.clone())
+        .reference_tone_config(keys_config.reference_tone.clone())
+        .randomizer_config(keys_config.randomizer.clone())
+        .dc_blocker_config(keys_config.dc_blocker.clone())
+        .limiter_config(keys_config.limiter.clone())
+        .buses(keys_config.buses.clone())
+        .cpu_budget_config(keys_config.cpu_budget.clone())
+        .quality_level(Arc::clone(&quality_level))
+        .keyboard_split(keys_config.keyboard_split.clone())
+        .build(sample_rate);
+
+    // The startup settings land before the stream starts, so the first sound already uses
+    // them.
+    engine.apply_startup_events(&keys_config.on_startup);
+    if keys_config.randomizer.on_startup {
+        engine.apply_random_patch();
+    }
+    let note_state = Arc::clone(engine.note_state());
+
+    let watchdog_counters = engine.watchdog_counters();
+    let refused_voices = engine.refused_voices();
+    let dc_level = engine.dc_level();
+
+    // In measurement mode the whole callback is timed, downsampling included, since all of it
+    // has to finish before the device's deadline.
+    let mut callback_timer = keys_config
+        .diagnostics
+        .measure_callback_time
+        .then(|| CallbackTimer::new(&keys_config.diagnostics));
+    let callback_timing = callback_timer
+        .as_ref()
+        .map(|timer| Arc::clone(timer.timing()));
+
+    let stream = output.build_stream::<T, _>(
+        config,
+        &keys_config.audio.dither,
+        move |data: &mut [f32], stream_time: Option<Duration>| {
+            let callback_started = callback_timer.is_some().then(Instant::now);
+            engine.callback_started(stream_time);
+
+            // The engine mixes the playing voices and applies the wave shaper, one sample per
+            // frame, and the result is laid out over the device's channels.
+            let output_buffer = engine.render(data.len() / channels);
+            routed_samples.resize(data.len(), 0.0);
+            route_channels(
+                &output_buffer.interleaved(),
+                output_buffer.num_channels(),
+                &mut routed_samples,
+                channels,
+                extra_channels,
+            );
+
+            // Without anything drawing them, the visualizer's samples aren't worth preparing.
+            // The engine keeps the mix from before the effects while the visual tap shows it,
+            // and it is spread over the channels like the output so the two line up.
+            if visuals_enabled.load(Ordering::Relaxed) {
+                let visual_tap = engine.visual_tap();
+                if visual_tap.uses_pre_effects() {
+                    let pre_effects = engine.pre_effects();
+                    routed_pre_effects.resize(data.len(), 0.0);
+                    route_channels(
+                        &pre_effects.interleaved(),
+                        pre_effects.num_channels(),
+                        &mut routed_pre_effects,
+                        channels,
+                        extra_channels,
+                    );
+                }
+                visual_feed.set_cycle_reference(engine.cycle_reference());
+                visual_feed.push_tapped(visual_tap, &routed_pre_effects, &routed_samples);
+            }
+
+            data.copy_from_slice(&routed_samples);
+
+            if let (Some(timer), Some(started)) = (callback_timer.as_mut(), callback_started) {
+                timer.record(started.elapsed(), data.len() / channels, sample_rate);
+            }
+        },
+    )?;
+    output.play(&stream)?;
+
+    if let Some(demo) = &demo {
+        demo.start(note_state);
+    }
+
+    // JACK ports only exist once the stream runs, so they're routed here.
+    if backend == Backend::Jack {
+        if let Some(pattern) = &keys_config.audio.jack.auto_connect {
+            if let Err(err) = connect_jack_outputs(&keys_config.audio.jack.client_name, pattern) {
+                warn!(
+                    "Unable to auto-connect JACK outputs to '{}': {}",
+                    pattern, err
+                );
+            }
+        }
+    }
+
+    output.wait(&stream);
+    info!(
+        "Audio callback diagnostics: {}",
+        watchdog_counters.summary()
+    );
+    let refused_voices = refused_voices.load(Ordering::Relaxed);
+    if refused_voices > 0 {
+        warn!(
+            "{} voices refused to start on an invalid frequency",
+            refused_voices
+        );
+    }
+    info!(
+        "Output DC offset ahead of the DC blocker: {:.4}",
+        dc_level.get()
+    );
+    let quality_level = quality_level.load(Ordering::Relaxed);
+    if quality_level > 0 {
+        info!(
+            "Quality was {} step(s) below full at the end, to stay within the CPU budget",
+            quality_level
+        );
+    }
+    if let Some(callback_timing) = callback_timing {
+        info!("Audio callback timing: {}", callback_timing.summary());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::RwLock;
+
+    use super::*;
+    use crate::synth::{
+        load_config, DownsampledAudioData, NoteState, TremoloEffect, DEFAULT_VISUAL_FPS,
+    };
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// Plays `note`, held if given, through the audio loop into a stand-in device for `blocks`
+    /// blocks. Returns the samples the device was given and how many frames were published for
+    /// the visualizer.
+    fn play(note: Option<&str>, visuals_enabled: bool, blocks: usize) -> (Vec<f32>, u64) {
+        let keys_config =
+            Arc::new(load_config(Path::new("resources/config/settings.yaml"), None).unwrap());
+        let mut note_state = NoteState::new();
+        if let Some(note) = note {
+            note_state.note_on(note.to_string());
+        }
+        let downsampled_audio_data =
+            Arc::new(Mutex::new(DownsampledAudioData::new(DEFAULT_VISUAL_FPS)));
+        let shared = Shared {
+            waveform_type: Arc::new(RwLock::new(keys_config.initial.waveform)),
+            note_state: Arc::new(Mutex::new(note_state)),
+            octave_shift: Arc::new(RwLock::new(0)),
+            tremolo_effect: Arc::new(TremoloEffect::builder().build(SAMPLE_RATE as f32)),
+            scale: Arc::new(Mutex::new(keys_config.initial.scale())),
+            downsampled_audio_data: Arc::clone(&downsampled_audio_data),
+            visuals_enabled: Arc::new(AtomicBool::new(visuals_enabled)),
+            quality_level: Arc::new(AtomicUsize::new(0)),
+            demo: None,
+            keys_config,
+            display_config: Arc::default(),
+        };
+        let config = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(SAMPLE_RATE),
+            buffer_size: cpal::BufferSize::Fixed(512),
+        };
+        let output = CaptureOutput::new(blocks);
+        run_audio_loop::<f32, _>(
+            Backend::Default,
+            &output,
+            &config,
+            shared,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap();
+        let published = downsampled_audio_data.lock().unwrap().published_frames();
+        (output.captured(), published)
+    }
+
+    /// Plays a held note for `blocks` blocks, returning how many frames were published for the
+    /// visualizer.
+    fn published_frames(visuals_enabled: bool, blocks: usize) -> u64 {
+        play(Some("A"), visuals_enabled, blocks).1
+    }
+
+    #[test]
+    fn a_held_note_fills_the_device_buffer_with_sound() {
+        let (captured, _) = play(Some("A"), false, 20);
+        assert_eq!(captured.len(), 20 * 512 * 2);
+        assert!(captured.iter().all(|sample| sample.is_finite()));
+        let rms = (captured.iter().map(|s| s * s).sum::<f32>() / captured.len() as f32).sqrt();
+        assert!(rms > 0.01, "{}", rms);
+        // Both channels of each frame carry the same mono voice.
+        assert!(captured.chunks(2).all(|frame| frame[0] == frame[1]));
+        // A waveform, not a constant: it crosses zero again and again.
+        let left: Vec<f32> = captured.iter().step_by(2).copied().collect();
+        let crossings = left
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        assert!(crossings > 100, "{}", crossings);
+    }
+
+    #[test]
+    fn with_no_note_held_the_device_buffer_stays_silent() {
+        let (captured, _) = play(None, false, 20);
+        assert_eq!(captured.len(), 20 * 512 * 2);
+        assert!(captured.iter().all(|sample| sample.abs() < 1e-6));
+    }
+
+    #[test]
+    fn disabled_visuals_skip_the_snapshot_work() {
+        // A second of audio is 60 frames at the default frame rate.
+        let blocks = SAMPLE_RATE as usize / 512;
+        assert_eq!(published_frames(false, blocks), 0);
+        let published = published_frames(true, blocks);
+        assert!((58..=60).contains(&published), "{}", published);
+    }
+}
@@ -0,0 +1,644 @@
+use std::collections::{HashMap, HashSet};
+
+use tracing::debug;
+use winit::{
+    event::{ElementState, KeyEvent},
+    keyboard::{Key, ModifiersState, NamedKey, PhysicalKey},
+    platform::modifier_supplement::KeyEventExtModifierSupplement,
+};
+
+use crate::synth::{KeyId, KeyZone, NoteEvent, NoteId, NoteSource, ResolvedBindings};
+
+/// A key press or release, with the key read as if no modifiers were held.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyInput {
+    pub key: Key,
+    pub physical_key: PhysicalKey,
+    pub state: ElementState,
+}
+
+impl KeyInput {
+    pub fn from_event(event: &KeyEvent) -> Self {
+        KeyInput {
+            key: event.key_without_modifiers(),
+            physical_key: event.physical_key,
+            state: event.state,
+        }
+    }
+}
+
+/// What a key press or release asks of the app.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyAction {
+    /// Passed on to the window for display controls, or to `NoteState::handle_event`.
+    Event(NoteEvent),
+    /// Moves the octave shift by this many octaves, staying within two either way.
+    ShiftOctave(i32),
+    StartNote {
+        id: NoteId,
+        zone: KeyZone,
+    },
+    StopNote(NoteId),
+    /// Scrolls the help screen by this many rows.
+    ScrollHelp(i32),
+    /// Stops the demo, which any key press takes over from.
+    CancelDemo,
+}
+
+/// Rows the help screen scrolls by when `key` is pressed while it's shown.
+pub fn help_scroll_rows(key: &Key) -> Option<i32> {
+    match key {
+        Key::Named(NamedKey::ArrowUp) => Some(-1),
+        Key::Named(NamedKey::ArrowDown) => Some(1),
+        _ => None,
+    }
+}
+
+fn octave_step(direction: &str) -> i32 {
+    if direction == "up" {
+        1
+    } else {
+        -1
+    }
+}
+
+/// Turns key presses and releases into what they ask the app to do.
+///
+/// It remembers the note each physical key started, so a release stops that note even if the
+/// modifiers changed while the key was held, and which octave keys are down, so holding one
+/// shifts the octave once rather than on every auto-repeat.
+#[derive(Debug, Default)]
+pub struct KeyTranslator {
+    /// Keys holding the same pitch share one voice instead of holding one each.
+    merge_unison_notes: bool,
+    held_notes: HashMap<PhysicalKey, NoteId>,
+    held_octave_keys: HashSet<PhysicalKey>,
+}
+
+impl KeyTranslator {
+    pub fn new(merge_unison_notes: bool) -> Self {
+        KeyTranslator {
+            merge_unison_notes,
+            held_notes: HashMap::new(),
+            held_octave_keys: HashSet::new(),
+        }
+    }
+
+    /// The actions for `input` with `modifiers` held. While the help screen is visible the
+    /// arrow keys scroll it instead of doing their usual job.
+    pub fn translate(
+        &mut self,
+        input: &KeyInput,
+        modifiers: ModifiersState,
+        bindings: &ResolvedBindings,
+        help_visible: bool,
+    ) -> Vec<KeyAction> {
+        // Bindings are written against the unmodified key, so Shift+a still looks up
+        // 'Character("a")' and the shift picks the shifted variant of the binding.
+        let key_id = KeyId::new(input.key.clone(), modifiers.shift_key());
+        let help_scroll = help_visible
+            .then(|| help_scroll_rows(&key_id.key))
+            .flatten();
+        debug!("Current state: {:#?}", input.state);
+
+        let mut actions = Vec::new();
+        match input.state {
+            ElementState::Pressed => {
+                debug!("Key {:?} pressed", key_id);
+                actions.push(KeyAction::CancelDemo);
+                if let Some(rows) = help_scroll {
+                    actions.push(KeyAction::ScrollHelp(rows));
+                } else if let Some(event) = bindings.lookup(&key_id) {
+                    actions.push(match event {
+                        // The octave moves when the key goes down, once however long it is
+                        // held.
+                        NoteEvent::ChangeOctave(_)
+                            if !self.held_octave_keys.insert(input.physical_key) =>
+                        {
+                            return actions;
+                        }
+                        NoteEvent::ChangeOctave(direction) => {
+                            KeyAction::ShiftOctave(octave_step(direction))
+                        }
+                        // A key held down repeats its press, but still holds its note only once.
+                        NoteEvent::On(_) if self.held_notes.contains_key(&input.physical_key) => {
+                            return actions;
+                        }
+                        NoteEvent::On(note) => {
+                            // Each key holds a voice of its own, even when another key plays the
+                            // same pitch, unless unison notes are merged.
+                            let source = if self.merge_unison_notes {
+                                NoteSource::Shared
+                            } else {
+                                NoteSource::Key(input.physical_key)
+                            };
+                            let id = NoteId::new(note.clone(), source);
+                            self.held_notes.insert(input.physical_key, id.clone());
+                            KeyAction::StartNote {
+                                id,
+                                zone: bindings.zone(&key_id.key),
+                            }
+                        }
+                        event => KeyAction::Event(event.clone()),
+                    });
+                    debug!("Key pressed: {:?}", key_id.key);
+                }
+            }
+            ElementState::Released => {
+                debug!("Key {:?} released", key_id);
+                self.held_octave_keys.remove(&input.physical_key);
+                if let Some(id) = self.held_notes.remove(&input.physical_key) {
+                    actions.push(KeyAction::StopNote(id));
+                    debug!("Key released: {:?}", key_id.key);
+                } else if help_scroll.is_some() {
+                    debug!("Help screen scrolled with {:?}", key_id.key);
+                } else if let Some(event) = bindings.lookup(&key_id) {
+                    match event {
+                        NoteEvent::On(note) => {
+                            actions.push(KeyAction::StopNote(NoteId::shared(note.clone())))
+                        }
+                        NoteEvent::ToggleTremolo => {
+                            actions.push(KeyAction::Event(NoteEvent::ToggleTremolo))
+                        }
+                        _ => (),
+                    }
+                    debug!("Key released: {:?}", key_id.key);
+                }
+            }
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use winit::keyboard::{KeyCode, SmolStr};
+
+    use super::*;
+    use crate::synth::Config;
+
+    fn bindings(shift_behavior: &str) -> ResolvedBindings {
+        let config: Config = serde_yaml::from_str(&format!(
+            r#"
+keybindings:
+  notes:
+    keys:
+      'Character("a")': 'C'
+      'Character("s")': 'C'
+  bass_notes:
+    keys:
+      'Character("z")': 'C-1'
+  key_change:
+    keys: {{}}
+  octave:
+    up: 'Named(ArrowUp)'
+    down: 'Named(ArrowDown)'
+  tremolo:
+    toggle: 'Named(Tab)'
+  display:
+    gain_up: 'Named(ArrowRight)'
+    gain_down: 'Named(ArrowLeft)'
+    auto_gain: 'Named(F2)'
+  help:
+    toggle: 'Named(F1)'
+  shift_behavior: {}
+  notes_shifted:
+    keys:
+      'Character("a")': 'G'
+action_keys:
+  toggle_notes: {{}}
+  change_waveform:
+    'Character("1")': Sine
+"#,
+            shift_behavior
+        ))
+        .unwrap();
+        ResolvedBindings::from_config(&config)
+    }
+
+    fn input(character: &str, code: KeyCode, state: ElementState) -> KeyInput {
+        KeyInput {
+            key: Key::Character(SmolStr::new(character)),
+            physical_key: PhysicalKey::Code(code),
+            state,
+        }
+    }
+
+    fn named(key: NamedKey, code: KeyCode, state: ElementState) -> KeyInput {
+        KeyInput {
+            key: Key::Named(key),
+            physical_key: PhysicalKey::Code(code),
+            state,
+        }
+    }
+
+    fn shift(held: bool) -> ModifiersState {
+        if held {
+            ModifiersState::SHIFT
+        } else {
+            ModifiersState::empty()
+        }
+    }
+
+    fn started(actions: &[KeyAction]) -> Option<&NoteId> {
+        actions.iter().find_map(|action| match action {
+            KeyAction::StartNote { id, .. } => Some(id),
+            _ => None,
+        })
+    }
+
+    fn stopped(actions: &[KeyAction]) -> Option<&NoteId> {
+        actions.iter().find_map(|action| match action {
+            KeyAction::StopNote(id) => Some(id),
+            _ => None,
+        })
+    }
+
+    /// Presses A with Shift `shift_on_press`, flips Shift, releases A, and returns the notes
+    /// started and stopped.
+    fn press_flip_shift_release(shift_behavior: &str, shift_on_press: bool) -> (NoteId, NoteId) {
+        let bindings = bindings(shift_behavior);
+        let mut translator = KeyTranslator::new(false);
+        let press = translator.translate(
+            &input("a", KeyCode::KeyA, ElementState::Pressed),
+            shift(shift_on_press),
+            &bindings,
+            false,
+        );
+        let release = translator.translate(
+            &input("a", KeyCode::KeyA, ElementState::Released),
+            shift(!shift_on_press),
+            &bindings,
+            false,
+        );
+        (
+            started(&press).cloned().unwrap(),
+            stopped(&release).cloned().unwrap(),
+        )
+    }
+
+    #[test]
+    fn a_note_started_before_shift_stops_on_release_in_every_mode() {
+        for mode in ["octave_up", "sharp", "separate_binding"] {
+            let (started, stopped) = press_flip_shift_release(mode, false);
+            assert_eq!(started.note, "C", "{}", mode);
+            assert_eq!(stopped, started, "{}", mode);
+        }
+    }
+
+    #[test]
+    fn a_shifted_note_stops_after_shift_is_let_go_in_every_mode() {
+        for (mode, shifted) in [
+            ("octave_up", "C+1"),
+            ("sharp", "C_SHARP"),
+            ("separate_binding", "G"),
+        ] {
+            let (started, stopped) = press_flip_shift_release(mode, true);
+            assert_eq!(started.note, shifted, "{}", mode);
+            assert_eq!(stopped, started, "{}", mode);
+        }
+    }
+
+    use ElementState::{Pressed, Released};
+
+    fn key_id(code: KeyCode) -> NoteSource {
+        NoteSource::Key(PhysicalKey::Code(code))
+    }
+
+    /// The note `key` plays with Shift `shift`, as the bindings have it.
+    fn bound_note(bindings: &ResolvedBindings, key: &str, shift: bool) -> String {
+        match bindings.lookup(&KeyId::new(Key::Character(SmolStr::new(key)), shift)) {
+            Some(NoteEvent::On(note)) => note.clone(),
+            other => panic!("{} is bound to {:?}", key, other),
+        }
+    }
+
+    #[test]
+    fn a_note_key_starts_and_stops_its_note_with_and_without_shift() {
+        let bindings = bindings("octave_up");
+        for shift_held in [false, true] {
+            let mut translator = KeyTranslator::new(false);
+            let press = translator.translate(
+                &input("a", KeyCode::KeyA, Pressed),
+                shift(shift_held),
+                &bindings,
+                false,
+            );
+            let id = NoteId::new(
+                if shift_held { "C+1" } else { "C" }.to_string(),
+                key_id(KeyCode::KeyA),
+            );
+            assert_eq!(
+                press,
+                [
+                    KeyAction::CancelDemo,
+                    KeyAction::StartNote {
+                        id: id.clone(),
+                        zone: KeyZone::Lead
+                    }
+                ]
+            );
+            let release = translator.translate(
+                &input("a", KeyCode::KeyA, Released),
+                shift(shift_held),
+                &bindings,
+                false,
+            );
+            assert_eq!(release, [KeyAction::StopNote(id)], "shift {}", shift_held);
+        }
+    }
+
+    #[test]
+    fn a_bass_key_plays_in_the_bass_zone_with_and_without_shift() {
+        let bindings = bindings("octave_up");
+        for shift_held in [false, true] {
+            let mut translator = KeyTranslator::new(false);
+            let press = translator.translate(
+                &input("z", KeyCode::KeyZ, Pressed),
+                shift(shift_held),
+                &bindings,
+                false,
+            );
+            let id = NoteId::new(
+                bound_note(&bindings, "z", shift_held),
+                key_id(KeyCode::KeyZ),
+            );
+            assert_eq!(
+                press[1],
+                KeyAction::StartNote {
+                    id: id.clone(),
+                    zone: KeyZone::Bass
+                },
+                "shift {}",
+                shift_held
+            );
+            let release = translator.translate(
+                &input("z", KeyCode::KeyZ, Released),
+                shift(shift_held),
+                &bindings,
+                false,
+            );
+            assert_eq!(release, [KeyAction::StopNote(id)]);
+        }
+        assert_ne!(
+            bound_note(&bindings, "z", false),
+            bound_note(&bindings, "z", true)
+        );
+    }
+
+    #[test]
+    fn shift_picks_the_sharp_or_the_separate_binding() {
+        for (mode, shifted) in [("sharp", "C_SHARP"), ("separate_binding", "G")] {
+            let mut translator = KeyTranslator::new(false);
+            let press = translator.translate(
+                &input("a", KeyCode::KeyA, Pressed),
+                shift(true),
+                &bindings(mode),
+                false,
+            );
+            assert_eq!(started(&press).unwrap().note, shifted, "{}", mode);
+        }
+    }
+
+    #[test]
+    fn a_note_released_without_being_pressed_here_stops_the_shared_note() {
+        let mut translator = KeyTranslator::new(false);
+        let release = translator.translate(
+            &input("a", KeyCode::KeyA, Released),
+            shift(false),
+            &bindings("octave_up"),
+            false,
+        );
+        assert_eq!(
+            release,
+            [KeyAction::StopNote(NoteId::shared("C".to_string()))]
+        );
+    }
+
+    #[test]
+    fn an_auto_repeated_note_key_holds_its_note_once() {
+        let bindings = bindings("octave_up");
+        let mut translator = KeyTranslator::new(false);
+        let mut started_notes = 0;
+        for _ in 0..5 {
+            let press = translator.translate(
+                &input("a", KeyCode::KeyA, Pressed),
+                shift(false),
+                &bindings,
+                false,
+            );
+            started_notes += press
+                .iter()
+                .filter(|action| matches!(action, KeyAction::StartNote { .. }))
+                .count();
+        }
+        assert_eq!(started_notes, 1);
+        let release = translator.translate(
+            &input("a", KeyCode::KeyA, Released),
+            shift(false),
+            &bindings,
+            false,
+        );
+        assert_eq!(release.len(), 1);
+        // Pressed again afterwards, it starts a fresh note.
+        let press = translator.translate(
+            &input("a", KeyCode::KeyA, Pressed),
+            shift(false),
+            &bindings,
+            false,
+        );
+        assert!(started(&press).is_some());
+    }
+
+    #[test]
+    fn two_keys_on_one_pitch_hold_their_own_notes_unless_merged() {
+        let bindings = bindings("octave_up");
+        for merge in [false, true] {
+            let mut translator = KeyTranslator::new(merge);
+            let a = translator.translate(
+                &input("a", KeyCode::KeyA, Pressed),
+                shift(false),
+                &bindings,
+                false,
+            );
+            let s = translator.translate(
+                &input("s", KeyCode::KeyS, Pressed),
+                shift(false),
+                &bindings,
+                false,
+            );
+            let (a, s) = (started(&a).unwrap(), started(&s).unwrap());
+            assert_eq!(a.note, s.note);
+            if merge {
+                assert_eq!(a.source, NoteSource::Shared);
+                assert_eq!(a, s);
+            } else {
+                assert_ne!(a, s);
+            }
+        }
+    }
+
+    #[test]
+    fn the_octave_keys_shift_once_per_press_with_and_without_shift() {
+        let bindings = bindings("octave_up");
+        for shift_held in [false, true] {
+            for (key, code, step) in [
+                (NamedKey::ArrowUp, KeyCode::ArrowUp, 1),
+                (NamedKey::ArrowDown, KeyCode::ArrowDown, -1),
+            ] {
+                let mut translator = KeyTranslator::new(false);
+                let press = translator.translate(
+                    &named(key, code, Pressed),
+                    shift(shift_held),
+                    &bindings,
+                    false,
+                );
+                assert_eq!(press, [KeyAction::CancelDemo, KeyAction::ShiftOctave(step)]);
+                // Letting go doesn't move it again.
+                let release = translator.translate(
+                    &named(key, code, Released),
+                    shift(shift_held),
+                    &bindings,
+                    false,
+                );
+                assert!(release.is_empty(), "{:?}", release);
+            }
+        }
+    }
+
+    #[test]
+    fn an_auto_repeated_octave_key_shifts_once_until_released() {
+        let bindings = bindings("octave_up");
+        let mut translator = KeyTranslator::new(false);
+        let mut shift_octave = |state| {
+            translator
+                .translate(
+                    &named(NamedKey::ArrowUp, KeyCode::ArrowUp, state),
+                    shift(false),
+                    &bindings,
+                    false,
+                )
+                .into_iter()
+                .filter(|action| matches!(action, KeyAction::ShiftOctave(_)))
+                .count()
+        };
+        let repeated: usize = (0..4).map(|_| shift_octave(Pressed)).sum();
+        assert_eq!(repeated, 1);
+        assert_eq!(shift_octave(Released), 0);
+        assert_eq!(shift_octave(Pressed), 1);
+    }
+
+    #[test]
+    fn the_tremolo_key_toggles_on_press_and_release_with_and_without_shift() {
+        let bindings = bindings("octave_up");
+        for shift_held in [false, true] {
+            let mut translator = KeyTranslator::new(false);
+            for (state, expected) in [
+                (
+                    Pressed,
+                    vec![
+                        KeyAction::CancelDemo,
+                        KeyAction::Event(NoteEvent::ToggleTremolo),
+                    ],
+                ),
+                (Released, vec![KeyAction::Event(NoteEvent::ToggleTremolo)]),
+            ] {
+                let actions = translator.translate(
+                    &named(NamedKey::Tab, KeyCode::Tab, state),
+                    shift(shift_held),
+                    &bindings,
+                    false,
+                );
+                assert_eq!(actions, expected, "{:?} shift {}", state, shift_held);
+            }
+        }
+    }
+
+    #[test]
+    fn action_keys_act_on_press_only_with_and_without_shift() {
+        let bindings = bindings("octave_up");
+        let cases = [
+            (
+                input("1", KeyCode::Digit1, Pressed),
+                NoteEvent::ChangeWaveform(crate::synth::OscillatorWaveform::Sine),
+            ),
+            (
+                named(NamedKey::ArrowRight, KeyCode::ArrowRight, Pressed),
+                NoteEvent::DisplayGainUp,
+            ),
+            (
+                named(NamedKey::F2, KeyCode::F2, Pressed),
+                NoteEvent::ToggleAutoGain,
+            ),
+            (
+                named(NamedKey::F1, KeyCode::F1, Pressed),
+                NoteEvent::ToggleHelp,
+            ),
+        ];
+        for shift_held in [false, true] {
+            for (press, event) in &cases {
+                let mut translator = KeyTranslator::new(false);
+                let actions = translator.translate(press, shift(shift_held), &bindings, false);
+                assert_eq!(
+                    actions,
+                    [KeyAction::CancelDemo, KeyAction::Event(event.clone())],
+                    "shift {}",
+                    shift_held
+                );
+                let release = KeyInput {
+                    state: Released,
+                    ..press.clone()
+                };
+                let actions = translator.translate(&release, shift(shift_held), &bindings, false);
+                assert!(actions.is_empty(), "{:?} released: {:?}", event, actions);
+            }
+        }
+    }
+
+    #[test]
+    fn the_arrow_keys_scroll_the_help_instead_of_shifting_the_octave() {
+        let bindings = bindings("octave_up");
+        let mut translator = KeyTranslator::new(false);
+        for (key, code, rows) in [
+            (NamedKey::ArrowUp, KeyCode::ArrowUp, -1),
+            (NamedKey::ArrowDown, KeyCode::ArrowDown, 1),
+        ] {
+            let press =
+                translator.translate(&named(key, code, Pressed), shift(false), &bindings, true);
+            assert_eq!(press, [KeyAction::CancelDemo, KeyAction::ScrollHelp(rows)]);
+            let release =
+                translator.translate(&named(key, code, Released), shift(false), &bindings, true);
+            assert!(release.is_empty());
+        }
+        // Notes still play while the help is shown.
+        let press = translator.translate(
+            &input("a", KeyCode::KeyA, Pressed),
+            shift(false),
+            &bindings,
+            true,
+        );
+        assert!(started(&press).is_some());
+    }
+
+    #[test]
+    fn an_unbound_key_only_cancels_the_demo() {
+        let bindings = bindings("octave_up");
+        for shift_held in [false, true] {
+            let mut translator = KeyTranslator::new(false);
+            let press = translator.translate(
+                &input("q", KeyCode::KeyQ, Pressed),
+                shift(shift_held),
+                &bindings,
+                false,
+            );
+            assert_eq!(press, [KeyAction::CancelDemo]);
+            let release = translator.translate(
+                &input("q", KeyCode::KeyQ, Released),
+                shift(shift_held),
+                &bindings,
+                false,
+            );
+            assert!(release.is_empty());
+        }
+    }
+}
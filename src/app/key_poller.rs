@@ -0,0 +1,233 @@
+use device_query::{DeviceQuery, DeviceState, Keycode};
+use winit::{
+    event::ElementState,
+    keyboard::{Key, KeyCode, ModifiersState, NamedKey, PhysicalKey, SmolStr},
+};
+
+use crate::app::KeyInput;
+
+/// Keyboard input for when no window can be created: polls which keys the system has held down
+/// and turns the difference since the last poll into the presses and releases a window would
+/// have sent.
+///
+/// Keys are read as on a US layout, since there is no window to ask for the real one.
+pub struct KeyPoller {
+    device_state: DeviceState,
+    held: Vec<Keycode>,
+}
+
+impl KeyPoller {
+    /// None when the system's keyboard can't be read, as with no display at all.
+    pub fn new() -> Option<Self> {
+        let device_state = DeviceState::checked_new()?;
+        Some(KeyPoller {
+            device_state,
+            held: Vec::new(),
+        })
+    }
+
+    /// The presses and releases since the last poll, with the modifiers held now.
+    pub fn poll(&mut self) -> (Vec<KeyInput>, ModifiersState) {
+        let held = self.device_state.get_keys();
+        let inputs = key_changes(&self.held, &held);
+        self.held = held;
+        (inputs, modifiers(&self.held))
+    }
+}
+
+/// The releases of keys in `before` that aren't in `after`, then the presses of keys in `after`
+/// that weren't in `before`. Keys with no counterpart in winit are left out.
+fn key_changes(before: &[Keycode], after: &[Keycode]) -> Vec<KeyInput> {
+    let released = before
+        .iter()
+        .filter(|keycode| !after.contains(keycode))
+        .map(|keycode| (keycode, ElementState::Released));
+    let pressed = after
+        .iter()
+        .filter(|keycode| !before.contains(keycode))
+        .map(|keycode| (keycode, ElementState::Pressed));
+    released
+        .chain(pressed)
+        .filter_map(|(keycode, state)| {
+            let (key, code) = winit_key(keycode)?;
+            Some(KeyInput {
+                key,
+                physical_key: PhysicalKey::Code(code),
+                state,
+            })
+        })
+        .collect()
+}
+
+/// The modifiers among `held`.
+fn modifiers(held: &[Keycode]) -> ModifiersState {
+    let mut modifiers = ModifiersState::empty();
+    for keycode in held {
+        match keycode {
+            Keycode::LShift | Keycode::RShift => modifiers |= ModifiersState::SHIFT,
+            Keycode::LControl | Keycode::RControl => modifiers |= ModifiersState::CONTROL,
+            Keycode::LAlt | Keycode::RAlt => modifiers |= ModifiersState::ALT,
+            _ => (),
+        }
+    }
+    modifiers
+}
+
+/// The key `keycode` types with no modifiers held, and where it is on the keyboard.
+fn winit_key(keycode: &Keycode) -> Option<(Key, KeyCode)> {
+    let character = |text: &str, code| Some((Key::Character(SmolStr::new(text)), code));
+    let named = |named_key, code| Some((Key::Named(named_key), code));
+    match keycode {
+        Keycode::A => character("a", KeyCode::KeyA),
+        Keycode::B => character("b", KeyCode::KeyB),
+        Keycode::C => character("c", KeyCode::KeyC),
+        Keycode::D => character("d", KeyCode::KeyD),
+        Keycode::E => character("e", KeyCode::KeyE),
+        Keycode::F => character("f", KeyCode::KeyF),
+        Keycode::G => character("g", KeyCode::KeyG),
+        Keycode::H => character("h", KeyCode::KeyH),
+        Keycode::I => character("i", KeyCode::KeyI),
+        Keycode::J => character("j", KeyCode::KeyJ),
+        Keycode::K => character("k", KeyCode::KeyK),
+        Keycode::L => character("l", KeyCode::KeyL),
+        Keycode::M => character("m", KeyCode::KeyM),
+        Keycode::N => character("n", KeyCode::KeyN),
+        Keycode::O => character("o", KeyCode::KeyO),
+        Keycode::P => character("p", KeyCode::KeyP),
+        Keycode::Q => character("q", KeyCode::KeyQ),
+        Keycode::R => character("r", KeyCode::KeyR),
+        Keycode::S => character("s", KeyCode::KeyS),
+        Keycode::T => character("t", KeyCode::KeyT),
+        Keycode::U => character("u", KeyCode::KeyU),
+        Keycode::V => character("v", KeyCode::KeyV),
+        Keycode::W => character("w", KeyCode::KeyW),
+        Keycode::X => character("x", KeyCode::KeyX),
+        Keycode::Y => character("y", KeyCode::KeyY),
+        Keycode::Z => character("z", KeyCode::KeyZ),
+        Keycode::Key0 => character("0", KeyCode::Digit0),
+        Keycode::Key1 => character("1", KeyCode::Digit1),
+        Keycode::Key2 => character("2", KeyCode::Digit2),
+        Keycode::Key3 => character("3", KeyCode::Digit3),
+        Keycode::Key4 => character("4", KeyCode::Digit4),
+        Keycode::Key5 => character("5", KeyCode::Digit5),
+        Keycode::Key6 => character("6", KeyCode::Digit6),
+        Keycode::Key7 => character("7", KeyCode::Digit7),
+        Keycode::Key8 => character("8", KeyCode::Digit8),
+        Keycode::Key9 => character("9", KeyCode::Digit9),
+        Keycode::Grave => character("`", KeyCode::Backquote),
+        Keycode::Minus => character("-", KeyCode::Minus),
+        Keycode::Equal => character("=", KeyCode::Equal),
+        Keycode::LeftBracket => character("[", KeyCode::BracketLeft),
+        Keycode::RightBracket => character("]", KeyCode::BracketRight),
+        Keycode::BackSlash => character("\\", KeyCode::Backslash),
+        Keycode::Semicolon => character(";", KeyCode::Semicolon),
+        Keycode::Apostrophe => character("'", KeyCode::Quote),
+        Keycode::Comma => character(",", KeyCode::Comma),
+        Keycode::Dot => character(".", KeyCode::Period),
+        Keycode::Slash => character("/", KeyCode::Slash),
+        Keycode::Up => named(NamedKey::ArrowUp, KeyCode::ArrowUp),
+        Keycode::Down => named(NamedKey::ArrowDown, KeyCode::ArrowDown),
+        Keycode::Left => named(NamedKey::ArrowLeft, KeyCode::ArrowLeft),
+        Keycode::Right => named(NamedKey::ArrowRight, KeyCode::ArrowRight),
+        Keycode::LShift => named(NamedKey::Shift, KeyCode::ShiftLeft),
+        Keycode::RShift => named(NamedKey::Shift, KeyCode::ShiftRight),
+        Keycode::LControl => named(NamedKey::Control, KeyCode::ControlLeft),
+        Keycode::RControl => named(NamedKey::Control, KeyCode::ControlRight),
+        Keycode::LAlt => named(NamedKey::Alt, KeyCode::AltLeft),
+        Keycode::RAlt => named(NamedKey::Alt, KeyCode::AltRight),
+        Keycode::Space => named(NamedKey::Space, KeyCode::Space),
+        Keycode::Enter => named(NamedKey::Enter, KeyCode::Enter),
+        Keycode::Tab => named(NamedKey::Tab, KeyCode::Tab),
+        Keycode::Escape => named(NamedKey::Escape, KeyCode::Escape),
+        Keycode::Backspace => named(NamedKey::Backspace, KeyCode::Backspace),
+        Keycode::Delete => named(NamedKey::Delete, KeyCode::Delete),
+        Keycode::Insert => named(NamedKey::Insert, KeyCode::Insert),
+        Keycode::Home => named(NamedKey::Home, KeyCode::Home),
+        Keycode::End => named(NamedKey::End, KeyCode::End),
+        Keycode::PageUp => named(NamedKey::PageUp, KeyCode::PageUp),
+        Keycode::PageDown => named(NamedKey::PageDown, KeyCode::PageDown),
+        Keycode::F1 => named(NamedKey::F1, KeyCode::F1),
+        Keycode::F2 => named(NamedKey::F2, KeyCode::F2),
+        Keycode::F3 => named(NamedKey::F3, KeyCode::F3),
+        Keycode::F4 => named(NamedKey::F4, KeyCode::F4),
+        Keycode::F5 => named(NamedKey::F5, KeyCode::F5),
+        Keycode::F6 => named(NamedKey::F6, KeyCode::F6),
+        Keycode::F7 => named(NamedKey::F7, KeyCode::F7),
+        Keycode::F8 => named(NamedKey::F8, KeyCode::F8),
+        Keycode::F9 => named(NamedKey::F9, KeyCode::F9),
+        Keycode::F10 => named(NamedKey::F10, KeyCode::F10),
+        Keycode::F11 => named(NamedKey::F11, KeyCode::F11),
+        Keycode::F12 => named(NamedKey::F12, KeyCode::F12),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(key: Key, code: KeyCode, state: ElementState) -> KeyInput {
+        KeyInput {
+            key,
+            physical_key: PhysicalKey::Code(code),
+            state,
+        }
+    }
+
+    fn character(text: &str) -> Key {
+        Key::Character(SmolStr::new(text))
+    }
+
+    #[test]
+    fn keys_going_down_and_up_between_polls_are_pressed_and_released() {
+        use ElementState::{Pressed, Released};
+
+        assert_eq!(
+            key_changes(&[], &[Keycode::A]),
+            [input(character("a"), KeyCode::KeyA, Pressed)]
+        );
+        // A key still held is neither pressed again nor released.
+        assert_eq!(key_changes(&[Keycode::A], &[Keycode::A]), []);
+        assert_eq!(
+            key_changes(&[Keycode::A], &[Keycode::A, Keycode::S]),
+            [input(character("s"), KeyCode::KeyS, Pressed)]
+        );
+        // Releases come first, so a key swapped for another in one poll lets go of its note
+        // before the next starts.
+        assert_eq!(
+            key_changes(&[Keycode::A, Keycode::S], &[Keycode::S, Keycode::Up]),
+            [
+                input(character("a"), KeyCode::KeyA, Released),
+                input(Key::Named(NamedKey::ArrowUp), KeyCode::ArrowUp, Pressed),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_read_as_their_unshifted_characters_with_shift_as_a_modifier() {
+        let held = [Keycode::LShift, Keycode::Key1, Keycode::Slash];
+        assert_eq!(
+            key_changes(&[], &held),
+            [
+                input(
+                    Key::Named(NamedKey::Shift),
+                    KeyCode::ShiftLeft,
+                    ElementState::Pressed
+                ),
+                input(character("1"), KeyCode::Digit1, ElementState::Pressed),
+                input(character("/"), KeyCode::Slash, ElementState::Pressed),
+            ]
+        );
+        assert_eq!(modifiers(&held), ModifiersState::SHIFT);
+        assert_eq!(
+            modifiers(&[Keycode::RControl, Keycode::RAlt, Keycode::A]),
+            ModifiersState::CONTROL | ModifiersState::ALT
+        );
+        assert_eq!(modifiers(&[Keycode::A]), ModifiersState::empty());
+    }
+
+    #[test]
+    fn keys_winit_has_no_key_for_are_left_out() {
+        assert_eq!(key_changes(&[], &[Keycode::Numpad5, Keycode::F20]), []);
+    }
+}
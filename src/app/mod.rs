@@ -0,0 +1,11 @@
+pub mod audio;
+pub mod input;
+pub mod key_poller;
+pub mod run;
+pub mod self_test;
+
+pub use audio::{spawn_audio_thread, AudioOutput, CaptureOutput};
+pub use input::{help_scroll_rows, KeyAction, KeyInput, KeyTranslator};
+pub use key_poller::KeyPoller;
+pub use run::{run, Args, Shared};
+pub use self_test::{run_self_test, CheckReport, CheckStatus};
@@ -0,0 +1,1142 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use cpal::traits::DeviceTrait;
+use tracing::{debug, error, info, warn};
+use winit::{
+    dpi::PhysicalPosition,
+    event::{ElementState, Event, MouseButton, MouseScrollDelta, WindowEvent},
+    event_loop::{ControlFlow, EventLoop},
+    keyboard::{Key, ModifiersState, NamedKey},
+    window::WindowBuilder,
+};
+
+use crate::app::{
+    run_self_test, spawn_audio_thread, KeyAction, KeyInput, KeyPoller, KeyTranslator,
+};
+use crate::graphics::{
+    help_lines, sounding_notes, visual_fps, AudioBufferLayout, AudioData, DisplayConfig,
+    EnvelopeWidget, RibbonStrip, SilenceHold, State, TitleState, TitleUpdater, WaveformLayout,
+};
+use crate::synth::{
+    backend::{open_output_device, Backend},
+    device_report::list_output_devices,
+    export_keymap, load_config,
+    params::waveform_to_param,
+    render::render_to_wav,
+    Config, ConfigWatcher, Demo, DeviceReport, DownsampledAudioData, EventHistory, NoteEvent,
+    NoteState, OscillatorWaveform, ParamChange, ParamHistory, ParamId, PerformanceLog,
+    ResolvedBindings, RollingStats, Scale, TremoloEffect, UndoStep, VisualTap, DEFAULT_VISUAL_FPS,
+};
+
+/// The config file the synth reads its settings from.
+const CONFIG_PATH: &str = "resources/config/settings.yaml";
+
+/// Sample rate used by `--render`, which has no device to take one from.
+const OFFLINE_SAMPLE_RATE: u32 = 44100;
+
+/// Octave offsets `--check-tuning` covers, and how far out a note may be.
+const TUNING_CHECK_OCTAVES: std::ops::RangeInclusive<i32> = -3..=3;
+const TUNING_TOLERANCE_CENTS: f32 = 0.5;
+
+/// How often the keyboard is read when there is no window to send key events.
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How often the config and its keymap are checked for edits.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What the command line asked for.
+#[derive(Debug, Default, Clone)]
+pub struct Args {
+    /// `--render <score> --out <wav>`: render the score to a WAV file instead of playing.
+    pub render: Option<(PathBuf, PathBuf)>,
+    /// `--export-midi <file>`: write the performance out as MIDI on exit.
+    pub export_midi: Option<String>,
+    /// `--keymap <file>`: load the bindings from this keymap, over the config's `keymap:`.
+    pub keymap: Option<PathBuf>,
+    /// `--export-keymap <file>`: write the bindings out as a keymap file, then exit.
+    pub export_keymap: Option<PathBuf>,
+    /// `--check-tuning`: check every keyboard note's frequency against 12-TET, then exit.
+    pub check_tuning: bool,
+    /// `--list-devices`, with `--verbose` for every configuration each device supports.
+    pub list_devices: bool,
+    pub verbose: bool,
+    /// `--backend <name>`, over the config's choice.
+    pub backend: Option<Backend>,
+    pub no_graphics: bool,
+    pub demo: bool,
+    /// `--random-patch`: start on a random patch, drawn as the config's `randomizer:` says.
+    pub random_patch: bool,
+    /// `--self-test`: check the audio and graphics paths, print a report and exit, failing if
+    /// any check did.
+    pub self_test: bool,
+    /// `--self-test-device`: also open and close the output device in the self-test.
+    pub self_test_device: bool,
+}
+
+/// The state the audio thread and the event loop share.
+#[derive(Clone)]
+pub struct Shared {
+    pub keys_config: Arc<Config>,
+    pub display_config: Arc<DisplayConfig>,
+    pub waveform_type: Arc<RwLock<OscillatorWaveform>>,
+    pub note_state: Arc<Mutex<NoteState>>,
+    pub octave_shift: Arc<RwLock<i32>>,
+    pub tremolo_effect: Arc<TremoloEffect>,
+    pub scale: Arc<Mutex<Scale>>,
+    pub downsampled_audio_data: Arc<Mutex<DownsampledAudioData>>,
+    /// Whether the audio callback prepares samples for the visualizer.
+    pub visuals_enabled: Arc<AtomicBool>,
+    /// Steps below full quality the engine runs at to stay within its CPU budget.
+    pub quality_level: Arc<AtomicUsize>,
+    pub demo: Option<Demo>,
+}
+
+/// Runs the synth as `args` ask: renders a score, lists the audio devices, or opens the
+/// device and the window and plays until the window is closed.
+pub async fn run(args: Args) -> Result<()> {
+    // Render a score to a WAV file without opening a device or a window
+    if let Some((score_path, out_path)) = &args.render {
+        return render_to_wav(score_path, out_path, OFFLINE_SAMPLE_RATE);
+    }
+
+    // Check the notes the keys play against equal temperament, then exit
+    if args.check_tuning {
+        let scale = Scale {
+            root_note: "C".to_string(),
+            intervals: Vec::new(),
+        };
+        let errors = scale.check_tuning(TUNING_CHECK_OCTAVES, TUNING_TOLERANCE_CENTS);
+        for error in &errors {
+            warn!(
+                "{} is {:?} Hz, expected {:.2} Hz",
+                error.note, error.actual, error.expected
+            );
+        }
+        if !errors.is_empty() {
+            bail!("{} notes are out of tune", errors.len());
+        }
+        info!("Every note is within {} cents", TUNING_TOLERANCE_CENTS);
+        return Ok(());
+    }
+
+    // Load and parse the YAML config file, and the keymap it takes its bindings from
+    let mut keys_config = load_config(Path::new(CONFIG_PATH), args.keymap.as_deref())?;
+    let display_config = DisplayConfig::load(Path::new(CONFIG_PATH))?;
+
+    // Write the bindings out as a keymap of their own, then exit
+    if let Some(keymap_path) = &args.export_keymap {
+        return export_keymap(&keys_config, keymap_path);
+    }
+
+    if let Some(midi_path) = args.export_midi {
+        keys_config.midi_export.export_on_exit = Some(midi_path);
+    }
+    if args.random_patch {
+        keys_config.randomizer.on_startup = true;
+    }
+
+    // The split visualizer is the one feature that needs a stereo output
+    let stereo_wanted = display_config.visualizer.layout == WaveformLayout::Split;
+
+    // List the output devices and what they support, then exit
+    if args.list_devices {
+        return list_output_devices(args.verbose, stereo_wanted);
+    }
+
+    // Check the audio and graphics paths without playing, then exit
+    if args.self_test || args.self_test_device {
+        return run_self_test(args.self_test_device, args.backend, keys_config).await;
+    }
+
+    // Set up audio host and device
+    let (backend, device) = open_output_device(args.backend, &keys_config.audio)?;
+    let config = device.default_output_config()?;
+    let device_report =
+        match DeviceReport::probe(backend.host_name(), &device, config.clone(), stereo_wanted) {
+            Ok(device_report) => {
+                device_report.log();
+                Some(device_report)
+            }
+            Err(err) => {
+                warn!("Unable to query the audio device's capabilities: {}", err);
+                None
+            }
+        };
+
+    // Create shared state variables:
+    let global_time = Arc::new(AtomicU64::new(0));
+    let waveform_type = Arc::new(RwLock::new(keys_config.initial.waveform));
+    let octave_shift = Arc::new(RwLock::new(0));
+    let mut initial_note_state = NoteState::new();
+    initial_note_state.performance_log = PerformanceLog::new(keys_config.midi_export.max_events);
+    initial_note_state.event_history = EventHistory::new(keys_config.diagnostics.event_history);
+    initial_note_state.device_report = device_report;
+    initial_note_state.visual_tap = display_config.visualizer.visual_tap;
+    let note_state = Arc::new(Mutex::new(initial_note_state));
+
+    let keys_config = Arc::new(keys_config);
+    let display_config = Arc::new(display_config);
+    let tremolo_effect = Arc::new(
+        TremoloEffect::builder()
+            .rate(keys_config.effects.tremolo_rate)
+            .depth(keys_config.effects.tremolo_depth)
+            .enabled(false)
+            .attack(keys_config.tremolo.attack)
+            .release(keys_config.tremolo.release)
+            .depth_attack(keys_config.tremolo.depth_attack)
+            .build(config.sample_rate().0 as f32),
+    );
+    let scale = Arc::new(Mutex::new(keys_config.initial.scale()));
+
+    // Edits to the config or its keymap change the key bindings while running
+    let config_watcher =
+        ConfigWatcher::new(Path::new(CONFIG_PATH), args.keymap.as_deref(), &keys_config);
+
+    // Create the window and event loop. The window is wanted even with --no-graphics, since
+    // that is where keyboard input comes from. Without one the keyboard is read directly.
+    let no_graphics = args.no_graphics;
+    let window_parts = EventLoop::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|event_loop| {
+            let window = WindowBuilder::new()
+                .with_title("oscillator")
+                .build(&event_loop)?;
+            Ok((event_loop, window))
+        });
+    let window_parts = match window_parts {
+        Ok(window_parts) => Some(window_parts),
+        Err(err) => {
+            warn!("Unable to create a window ({:#}); running audio-only", err);
+            None
+        }
+    };
+
+    // The audio callback only prepares samples for the visualizer while something draws them
+    let visuals_enabled = Arc::new(AtomicBool::new(!no_graphics && window_parts.is_some()));
+    if no_graphics {
+        info!("Graphics disabled with --no-graphics; running audio-only");
+    }
+
+    // The visualizer draws at the monitor's refresh rate unless the config pins it
+    let initial_visual_fps = window_parts
+        .as_ref()
+        .map_or(DEFAULT_VISUAL_FPS, |(_, window)| {
+            visual_fps(window, &display_config.visualizer)
+        });
+    if visuals_enabled.load(Ordering::Relaxed) {
+        info!("Visualizer running at {:.1} fps", initial_visual_fps);
+    }
+    let downsampled_audio_data =
+        Arc::new(Mutex::new(DownsampledAudioData::new(initial_visual_fps)));
+
+    // With --demo the configured demo score plays itself until a key is pressed
+    let demo = args.demo.then(|| {
+        if keys_config.demo.notes.is_empty() {
+            warn!("--demo was given but the config has no demo notes");
+        }
+        Demo::new(keys_config.demo.clone())
+    });
+
+    // Start the audio stream based on the sample format
+    // - Initialize the oscillator and modulator
+    // - Build and start the output stream
+    //   - Update note_state and octave_shift
+    //   - Generate audio samples based on the playing notes and oscillators
+    //   - Apply wave shaping to the audio buffer
+    //   - Write the audio samples to the output buffer
+    let shared = Shared {
+        keys_config,
+        display_config,
+        waveform_type,
+        note_state,
+        octave_shift,
+        tremolo_effect,
+        scale,
+        downsampled_audio_data,
+        visuals_enabled,
+        quality_level: Arc::new(AtomicUsize::new(0)),
+        demo,
+    };
+    let audio_thread = spawn_audio_thread(backend, device, config, shared.clone(), global_time);
+
+    // Run the main event loop
+    // - Handle window events (e.g., close, resize)
+    // - Handle user events (e.g., redraw)
+    // - Update audio data based on the current time and sample rate
+    // - Render the graphics and audio
+    match window_parts {
+        Some((event_loop, window)) => {
+            debug!("Starting event loop");
+            run_event_loop(event_loop, &window, !no_graphics, shared, config_watcher).await?;
+        }
+        None => match KeyPoller::new() {
+            Some(key_poller) => {
+                info!("Reading the keyboard directly, without a window");
+                run_headless_input(key_poller, config_watcher, &shared, &audio_thread);
+            }
+            // Startup events and the demo still play; there is just no way to play along.
+            None => info!(
+                "Unable to read the keyboard either; the synth keeps playing until the audio \
+                 thread ends"
+            ),
+        },
+    }
+
+    audio_thread.join().unwrap()?;
+
+    Ok(())
+}
+
+async fn run_event_loop(
+    event_loop: EventLoop<()>,
+    window: &winit::window::Window,
+    graphics_enabled: bool,
+    shared: Shared,
+    mut config_watcher: ConfigWatcher,
+) -> Result<()> {
+    info!("run_event_loop function called");
+    let Shared {
+        keys_config,
+        display_config,
+        waveform_type,
+        note_state,
+        octave_shift,
+        tremolo_effect,
+        scale,
+        downsampled_audio_data,
+        visuals_enabled,
+        quality_level,
+        demo: _,
+    } = shared.clone();
+
+    // A missing or broken GPU shouldn't take the synth down with it; the window stays up for
+    // keyboard input and nothing is drawn.
+    let mut state = if graphics_enabled {
+        match State::new(window, display_config.visualizer.present_mode)
+            .await
+            .context("Failed to initialize graphics")
+        {
+            Ok(state) => Some(state),
+            Err(err) => {
+                warn!("{:#}; continuing audio-only", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if state.is_none() {
+        visuals_enabled.store(false, Ordering::Relaxed);
+    }
+
+    let mut audio_data = AudioData::default();
+    // The pre-effects waveform drawn over the output with the `both` visual tap; empty without.
+    let mut overlay_data = AudioData::default();
+    let mut silence_hold = SilenceHold::new(display_config.visualizer.silence_hold.clone());
+
+    let mut bindings = ResolvedBindings::from_config(&keys_config);
+    info!("Resolved {} key bindings", bindings.len());
+    let mut last_config_check = Instant::now();
+
+    let ribbon_strip = RibbonStrip {
+        height: keys_config.ribbon.height,
+    };
+    if let Some(state) = state.as_mut() {
+        state.set_ribbon_strip(ribbon_strip);
+        state.set_note_names_config(display_config.note_names.clone());
+        state.set_help_config(display_config.help.clone());
+        state.set_theme_config(display_config.theme.clone());
+        state.set_accessibility_config(display_config.accessibility.clone());
+        state.set_line_width(display_config.visualizer.line_width);
+        state.set_waveform_points(display_config.visualizer.waveform_points);
+        state.set_waveform_layout(display_config.visualizer.layout);
+        state.set_display_scale_config(display_config.visualizer.scale.clone());
+        state.reconfigure_audio_buffer(AudioBufferLayout {
+            mode: display_config.visualizer.layout,
+            samples_per_channel: display_config.visualizer.max_audio_samples,
+            streams: if display_config.visualizer.visual_tap == VisualTap::Both {
+                2
+            } else {
+                1
+            },
+        });
+    }
+    // The envelope editor starts from the lead preset, and edits it for the notes that follow.
+    let mut envelope_widget = EnvelopeWidget::new(
+        display_config.envelope_editor.clone(),
+        keys_config.keyboard_split.lead.envelope(),
+    );
+    if let Some(state) = state.as_mut() {
+        state.set_envelope_widget(Some(envelope_widget.clone()));
+    }
+    // The envelope as it was when a breakpoint was grabbed, so the drag undoes as one step.
+    let mut envelope_at_grab = None;
+    let mut cursor_position = PhysicalPosition::new(0.0, 0.0);
+    let mut ribbon_held = false;
+    // Undo lives here, beside the input, and sets parameters back through `SetParam` events.
+    let mut param_history = ParamHistory::new(keys_config.undo.clone());
+
+    // The title shows a summary of the synth's state, refreshed after the events that can
+    // change it. The quality level changes on the audio thread with no event to follow, so it
+    // is picked up where held-back changes are sent from.
+    let title_state = {
+        let waveform_type = waveform_type.clone();
+        let octave_shift = octave_shift.clone();
+        let tremolo_effect = tremolo_effect.clone();
+        let scale = scale.clone();
+        let quality_level = quality_level.clone();
+        let tempo = keys_config.waveform_sequence.tempo;
+        move || {
+            TitleState::capture(
+                &waveform_type,
+                &octave_shift,
+                &tremolo_effect,
+                &scale,
+                &quality_level,
+                tempo,
+            )
+        }
+    };
+    let mut title_updater = display_config
+        .window_title
+        .enabled
+        .then(|| TitleUpdater::new(&display_config.window_title));
+    if let Some(title) = title_updater
+        .as_mut()
+        .and_then(|updater| updater.update(title_state(), Instant::now()))
+    {
+        window.set_title(&title);
+    }
+
+    let mut modifiers = ModifiersState::empty();
+    let mut key_translator = KeyTranslator::new(keys_config.keybindings.merge_unison_notes);
+
+    // How long the recent frames took to render, logged on exit
+    let mut frame_times = keys_config
+        .diagnostics
+        .measure_frame_time
+        .then(|| RollingStats::new(keys_config.diagnostics.timing_window));
+
+    let _ = event_loop.run(move |event, event_loop_window_target| match event {
+        Event::WindowEvent {
+            event: WindowEvent::CloseRequested,
+            ..
+        } => {
+            debug!("The close button was pressed; stopping");
+            if let Some(frame_times) = frame_times.as_ref().filter(|times| !times.is_empty()) {
+                info!(
+                    "Frame time over the last {} frames: average {:?}, max {:?}",
+                    frame_times.len(),
+                    frame_times.average(),
+                    frame_times.max()
+                );
+            }
+            if let Some(midi_path) = &keys_config.midi_export.export_on_exit {
+                if let Ok(note_state) = note_state.lock() {
+                    if let Err(err) = note_state
+                        .performance_log
+                        .export(Path::new(midi_path), &keys_config.midi_export)
+                    {
+                        error!("{:#}", err);
+                    }
+                }
+            }
+            event_loop_window_target.exit();
+            std::process::exit(0);
+        }
+
+        Event::WindowEvent {
+            event: WindowEvent::Resized(new_size),
+            ..
+        } => {
+            if let Some(state) = state.as_mut() {
+                state.resize(new_size);
+            }
+        }
+
+        // The window may have landed on a monitor with a different refresh rate.
+        Event::WindowEvent {
+            event: WindowEvent::Moved(_) | WindowEvent::ScaleFactorChanged { .. },
+            ..
+        } => {
+            let new_visual_fps = visual_fps(window, &display_config.visualizer);
+            if let Ok(mut downsampled_audio_data) = downsampled_audio_data.lock() {
+                if downsampled_audio_data.visual_fps != new_visual_fps {
+                    info!("Visualizer now running at {:.1} fps", new_visual_fps);
+                    downsampled_audio_data.visual_fps = new_visual_fps;
+                }
+            }
+        }
+
+        Event::UserEvent(_) => {
+            debug!("Received a UserEvent");
+            window.request_redraw();
+        }
+
+        Event::WindowEvent {
+            event: WindowEvent::CursorMoved { position, .. },
+            ..
+        } => {
+            cursor_position = position;
+            // Shift drags a breakpoint a tenth as far, for fine adjustment.
+            if envelope_widget.drag(position, window.inner_size(), modifiers.shift_key()) {
+                if let Some(state) = state.as_mut() {
+                    state.set_envelope_widget(Some(envelope_widget.clone()));
+                }
+                note_state.lock().unwrap().handle_event(
+                    NoteEvent::SetEnvelope(envelope_widget.envelope()),
+                    &waveform_type,
+                    &tremolo_effect,
+                    &scale,
+                );
+            }
+            if ribbon_held {
+                let normalized_x = ribbon_strip.normalized_x(position, window.inner_size());
+                if let Some(state) = state.as_mut() {
+                    state.set_ribbon_touch(Some(normalized_x));
+                }
+                note_state.lock().unwrap().handle_event(
+                    NoteEvent::RibbonMove { normalized_x },
+                    &waveform_type,
+                    &tremolo_effect,
+                    &scale,
+                );
+            }
+        }
+
+        Event::WindowEvent {
+            event:
+                WindowEvent::MouseInput {
+                    state: button_state,
+                    button: MouseButton::Left,
+                    ..
+                },
+            ..
+        } => {
+            let size = window.inner_size();
+            // A click on one of the envelope editor's breakpoints grabs it, and nothing else.
+            let envelope_grab = match button_state {
+                ElementState::Pressed => {
+                    envelope_at_grab = Some(envelope_widget.envelope());
+                    envelope_widget.grab(cursor_position, size)
+                }
+                ElementState::Released => match envelope_widget.release() {
+                    Some(_) => {
+                        let envelope = envelope_widget.envelope();
+                        if let Some(grabbed) = envelope_at_grab.take() {
+                            let changes = [
+                                (ParamId::Attack, grabbed.attack, envelope.attack),
+                                (ParamId::Decay, grabbed.decay, envelope.decay),
+                                (ParamId::Sustain, grabbed.sustain, envelope.sustain),
+                                (ParamId::Release, grabbed.release, envelope.release),
+                            ]
+                            .into_iter()
+                            .map(|(param, before, after)| ParamChange {
+                                param,
+                                before,
+                                after,
+                            })
+                            .collect();
+                            param_history.record("envelope", changes, Instant::now());
+                        }
+                        info!(
+                            "Envelope: attack {:.3} s, decay {:.3} s, sustain {:.2}, release {:.3} s; set them under keyboard_split.lead to keep them",
+                            envelope.attack, envelope.decay, envelope.sustain, envelope.release
+                        );
+                        true
+                    }
+                    None => false,
+                },
+            };
+            if envelope_grab {
+                if let Some(state) = state.as_mut() {
+                    state.set_envelope_widget(Some(envelope_widget.clone()));
+                }
+                return;
+            }
+            let event = match button_state {
+                ElementState::Pressed => ribbon_strip
+                    .hit_test(cursor_position, size)
+                    .map(|normalized_x| NoteEvent::RibbonStart { normalized_x }),
+                ElementState::Released if ribbon_held => Some(NoteEvent::RibbonEnd {
+                    normalized_x: ribbon_strip.normalized_x(cursor_position, size),
+                }),
+                ElementState::Released => None,
+            };
+
+            if let Some(event) = event {
+                let touch = match event {
+                    NoteEvent::RibbonStart { normalized_x } => Some(normalized_x),
+                    _ => None,
+                };
+                ribbon_held = touch.is_some();
+                if let Some(state) = state.as_mut() {
+                    state.set_ribbon_touch(touch);
+                }
+                note_state.lock().unwrap().handle_event(
+                    event,
+                    &waveform_type,
+                    &tremolo_effect,
+                    &scale,
+                );
+            }
+        }
+
+        // The wheel only scrolls the help screen, which ignores it while hidden.
+        Event::WindowEvent {
+            event: WindowEvent::MouseWheel { delta, .. },
+            ..
+        } => {
+            if let Some(state) = state.as_mut() {
+                let rows = match delta {
+                    MouseScrollDelta::LineDelta(_, lines) => -lines * 3.0,
+                    MouseScrollDelta::PixelDelta(position) => -position.y as f32 / 20.0,
+                };
+                state.scroll_help(rows.round() as i32);
+            }
+        }
+
+        Event::WindowEvent {
+            event: WindowEvent::ModifiersChanged(new_modifiers),
+            ..
+        } => {
+            modifiers = new_modifiers.state();
+        }
+
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    device_id: _,
+                    event: key_event,
+                    is_synthetic,
+                    ..
+                },
+            ..
+        } => {
+            debug!("Received a KeyboardInput");
+            if is_synthetic {
+                println!("Received a synthetic keyboard event.");
+            } else {
+                let input = KeyInput::from_event(&key_event);
+                // Shift while dragging an envelope breakpoint only makes the drag finer.
+                if envelope_widget.grabbed().is_some() && input.key == Key::Named(NamedKey::Shift)
+                {
+                    return;
+                }
+                let undone = handle_key_input(
+                    &input,
+                    modifiers,
+                    &mut key_translator,
+                    &bindings,
+                    state.as_mut(),
+                    &shared,
+                    &mut param_history,
+                );
+                // An undo of an envelope drag moves the breakpoints back with it.
+                let mut envelope_moved = false;
+                for (param, value) in undone {
+                    envelope_moved |= envelope_widget.set_param(param, value);
+                }
+                if envelope_moved {
+                    if let Some(state) = state.as_mut() {
+                        state.set_envelope_widget(Some(envelope_widget.clone()));
+                    }
+                }
+            }
+
+            if let Some(title) = title_updater
+                .as_mut()
+                .and_then(|updater| updater.update(title_state(), Instant::now()))
+            {
+                window.set_title(&title);
+            }
+        }
+
+        // Edits to the config or its keymap are picked up between events. Without rendering
+        // there are no redraws to send a debounced title change from, so the loop wakes up on
+        // its own for both.
+        Event::AboutToWait => {
+            if last_config_check.elapsed() >= CONFIG_POLL_INTERVAL {
+                last_config_check = Instant::now();
+                if let Some(config) = config_watcher.poll() {
+                    apply_reloaded_config(&config, &mut bindings, &mut param_history);
+                }
+            }
+            if state.is_some() {
+                return;
+            }
+            if let Some(title) = title_updater
+                .as_mut()
+                .and_then(|updater| updater.update(title_state(), Instant::now()))
+            {
+                window.set_title(&title);
+            }
+            event_loop_window_target.set_control_flow(ControlFlow::wait_duration(
+                Duration::from_secs_f32(display_config.window_title.min_interval.max(0.01))
+                    .min(CONFIG_POLL_INTERVAL),
+            ));
+        }
+
+        Event::WindowEvent {
+            event: WindowEvent::RedrawRequested,
+            ..
+        } => {
+            let Some(state) = state.as_mut() else {
+                return;
+            };
+            // The level the audio thread published with the frame, which the silence hold goes
+            // by rather than the downsampled samples drawn.
+            let mut level_db = f32::NEG_INFINITY;
+            // Access the shared DownsampledAudioData structure to retrieve the downsampled audio samples
+            if let Ok(mut downsampled_audio_data) = downsampled_audio_data.lock() {
+                // Take the next frame the audio thread queued; with none queued, the last one is
+                // drawn again.
+                downsampled_audio_data.next_frame();
+                audio_data.set_samples(
+                    &downsampled_audio_data.samples,
+                    &downsampled_audio_data.right_samples,
+                );
+                match &downsampled_audio_data.overlay {
+                    Some((samples, right_samples)) => {
+                        overlay_data.set_samples(samples, right_samples)
+                    }
+                    None => overlay_data.set_samples(&[], &[]),
+                }
+                state.set_waveform_dimmed(downsampled_audio_data.dimmed);
+                level_db = downsampled_audio_data.rms_db;
+            }
+            state.set_waveform_fade(silence_hold.apply(&mut audio_data, level_db, Instant::now()));
+            let has_overlay = overlay_data.count() > 0;
+            state.limit_motion(&mut audio_data, has_overlay.then_some(&mut overlay_data));
+
+            if let Ok(note_state) = note_state.lock() {
+                state.set_sounding_notes(sounding_notes(
+                    &note_state.voice_debug_info(),
+                    note_state.reference_tone,
+                ));
+            }
+            if let (Ok(scale), Ok(octave_shift)) = (scale.lock(), octave_shift.read()) {
+                state.set_background_key(&scale.root_note, *octave_shift);
+            }
+
+            // A title change held back by the debounce goes out once it's due.
+            if let Some(title) = title_updater
+                .as_mut()
+                .and_then(|updater| updater.update(title_state(), Instant::now()))
+            {
+                window.set_title(&title);
+            }
+
+            let frame_started = Instant::now();
+            if let Err(e) = state.render(&audio_data, has_overlay.then_some(&overlay_data)) {
+                error!("Render error: {}", e);
+            }
+            if let Some(frame_times) = frame_times.as_mut() {
+                frame_times.push(frame_started.elapsed());
+            }
+
+            window.request_redraw();
+        }
+
+        _ => debug!("Other event: {:?}", event),
+    });
+    Ok(())
+}
+
+/// The parameters keys set directly, with their values now: the waveform, unless it is a
+/// custom one with no number, the octave shift and whether the tremolo is on.
+fn key_param_values(shared: &Shared) -> Vec<(ParamId, f32)> {
+    let waveform = *shared
+        .waveform_type
+        .read()
+        .unwrap_or_else(PoisonError::into_inner);
+    let octave_shift = *shared
+        .octave_shift
+        .read()
+        .unwrap_or_else(PoisonError::into_inner);
+    let tremolo_enabled = shared.tremolo_effect.enabled.load(Ordering::Relaxed);
+    waveform_to_param(waveform)
+        .map(|waveform| (ParamId::Waveform, waveform))
+        .into_iter()
+        .chain([
+            (ParamId::OctaveShift, octave_shift as f32),
+            (
+                ParamId::TremoloEnabled,
+                if tremolo_enabled { 1.0 } else { 0.0 },
+            ),
+        ])
+        .collect()
+}
+
+/// Plays the keys `key_poller` reads until the audio thread ends, picking up edits to the config
+/// `config_watcher` watches. Nothing is drawn, so the display controls do nothing.
+fn run_headless_input(
+    mut key_poller: KeyPoller,
+    mut config_watcher: ConfigWatcher,
+    shared: &Shared,
+    audio_thread: &JoinHandle<Result<()>>,
+) {
+    let mut bindings = ResolvedBindings::from_config(&shared.keys_config);
+    info!("Resolved {} key bindings", bindings.len());
+    let mut last_config_check = Instant::now();
+    let mut key_translator = KeyTranslator::new(shared.keys_config.keybindings.merge_unison_notes);
+    let mut param_history = ParamHistory::new(shared.keys_config.undo.clone());
+    while !audio_thread.is_finished() {
+        if last_config_check.elapsed() >= CONFIG_POLL_INTERVAL {
+            last_config_check = Instant::now();
+            if let Some(config) = config_watcher.poll() {
+                apply_reloaded_config(&config, &mut bindings, &mut param_history);
+            }
+        }
+        let (inputs, modifiers) = key_poller.poll();
+        for input in inputs {
+            handle_key_input(
+                &input,
+                modifiers,
+                &mut key_translator,
+                &bindings,
+                None,
+                shared,
+                &mut param_history,
+            );
+        }
+        thread::sleep(KEY_POLL_INTERVAL);
+    }
+}
+
+/// Swaps in the key bindings of a reloaded `config`, and clears the undo history, whose steps
+/// may no longer match it, taking on its undo settings. The rest of it takes effect on the next
+/// start.
+fn apply_reloaded_config(
+    config: &Config,
+    bindings: &mut ResolvedBindings,
+    history: &mut ParamHistory,
+) {
+    *bindings = ResolvedBindings::from_config(config);
+    history.reload(config.undo.clone());
+    info!(
+        "Reloaded {} key bindings and the undo settings; the rest take effect on restart",
+        bindings.len()
+    );
+}
+
+/// Sends `values` to the engine as `SetParam` events.
+fn send_params(note_state: &mut NoteState, values: &[(ParamId, f32)], shared: &Shared) {
+    for &(param, value) in values {
+        note_state.handle_event(
+            NoteEvent::SetParam(param, value),
+            &shared.waveform_type,
+            &shared.tremolo_effect,
+            &shared.scale,
+        );
+    }
+}
+
+/// Plays or carries out what the key `input` is bound to, with `modifiers` held. The display
+/// controls act on `state`, and do nothing while nothing is drawn.
+///
+/// Parameters the key changes are recorded in `history`, as one gesture with any presses just
+/// before it that changed the same ones. Returns the values an undo or redo sent.
+fn handle_key_input(
+    input: &KeyInput,
+    modifiers: ModifiersState,
+    key_translator: &mut KeyTranslator,
+    bindings: &ResolvedBindings,
+    mut state: Option<&mut State<'_>>,
+    shared: &Shared,
+    history: &mut ParamHistory,
+) -> Vec<(ParamId, f32)> {
+    let now = Instant::now();
+    // The help screen stays up while playing and lights up keys as they're held.
+    let help_visible = state.as_deref().is_some_and(State::help_visible);
+    if let Some(state) = state.as_deref_mut() {
+        state.set_key_held(input.key.clone(), input.state == ElementState::Pressed);
+    }
+
+    let actions = key_translator.translate(input, modifiers, bindings, help_visible);
+    let mut note_state = shared.note_state.lock().unwrap();
+    // Random patches set since the last key go in the history before anything this one does.
+    for UndoStep { label, changes } in std::mem::take(&mut note_state.random_patches) {
+        history.record(label, changes, now);
+    }
+    let before = key_param_values(shared);
+    let mut sent = Vec::new();
+    for action in actions {
+        match action {
+            KeyAction::CancelDemo => {
+                if let Some(demo) = &shared.demo {
+                    demo.cancel(&mut note_state);
+                }
+            }
+            KeyAction::ScrollHelp(rows) => {
+                if let Some(state) = state.as_deref_mut() {
+                    state.scroll_help(rows);
+                }
+            }
+            KeyAction::ShiftOctave(step) => {
+                if let Ok(mut octave_shift) = shared.octave_shift.write() {
+                    *octave_shift = (*octave_shift + step).clamp(-2, 2);
+                }
+            }
+            KeyAction::StartNote { id, zone } => note_state.start_note_in_zone(id, None, zone),
+            KeyAction::StopNote(id) => note_state.stop_note(&id),
+            // Display controls do nothing while nothing is drawn.
+            KeyAction::Event(NoteEvent::ToggleNoteNames) => {
+                if let Some(state) = state.as_deref_mut() {
+                    state.toggle_note_names();
+                }
+            }
+            KeyAction::Event(NoteEvent::DisplayGainUp) => {
+                if let Some(state) = state.as_deref_mut() {
+                    state.display_gain_up();
+                }
+            }
+            KeyAction::Event(NoteEvent::DisplayGainDown) => {
+                if let Some(state) = state.as_deref_mut() {
+                    state.display_gain_down();
+                }
+            }
+            KeyAction::Event(NoteEvent::ToggleAutoGain) => {
+                if let Some(state) = state.as_deref_mut() {
+                    state.toggle_auto_gain();
+                }
+            }
+            KeyAction::Event(NoteEvent::ToggleHighContrast) => {
+                if let Some(state) = state.as_deref_mut() {
+                    state.toggle_high_contrast();
+                }
+            }
+            KeyAction::Event(NoteEvent::ToggleReducedMotion) => {
+                if let Some(state) = state.as_deref_mut() {
+                    state.toggle_reduced_motion();
+                }
+            }
+            // Listed fresh each time, so it matches the bindings in use.
+            KeyAction::Event(NoteEvent::ToggleHelp) => {
+                if let Some(state) = state.as_deref_mut() {
+                    state.toggle_help(help_lines(bindings));
+                }
+            }
+            KeyAction::Event(NoteEvent::ExportMidi) => {
+                if let Err(err) = note_state.performance_log.export(
+                    Path::new(&shared.keys_config.midi_export.path),
+                    &shared.keys_config.midi_export,
+                ) {
+                    error!("{:#}", err);
+                }
+            }
+            KeyAction::Event(NoteEvent::Undo) => match history.undo() {
+                Some(step) => {
+                    let values = step.before_values();
+                    send_params(&mut note_state, &values, shared);
+                    sent.extend(values);
+                    info!("Undid {}", step);
+                }
+                None => info!("Nothing to undo"),
+            },
+            KeyAction::Event(NoteEvent::Redo) => match history.redo() {
+                Some(step) => {
+                    let values = step.after_values();
+                    send_params(&mut note_state, &values, shared);
+                    sent.extend(values);
+                    info!("Redid {}", step);
+                }
+                None => info!("Nothing to redo"),
+            },
+            KeyAction::Event(NoteEvent::DumpUndoHistory) => history.log(),
+            KeyAction::Event(event) => note_state.handle_event(
+                event,
+                &shared.waveform_type,
+                &shared.tremolo_effect,
+                &shared.scale,
+            ),
+        }
+    }
+
+    let after = key_param_values(shared);
+    let changes: Vec<ParamChange> = before
+        .into_iter()
+        .filter_map(|(param, before)| {
+            let &(_, after) = after.iter().find(|(other, _)| *other == param)?;
+            Some(ParamChange {
+                param,
+                before,
+                after,
+            })
+        })
+        .filter(|change| change.before != change.after)
+        .collect();
+    if !changes.is_empty() {
+        let label = changes
+            .iter()
+            .map(|change| change.param.name())
+            .collect::<Vec<_>>()
+            .join(", ");
+        history.record(label, changes, now);
+    }
+    sent
+}
+
+#[cfg(test)]
+mod tests {
+    use winit::keyboard::{KeyCode, PhysicalKey, SmolStr};
+
+    use super::*;
+
+    /// The state the event loop shares, from the shipped config.
+    fn shared() -> Shared {
+        let keys_config = Arc::new(load_config(Path::new(CONFIG_PATH), None).unwrap());
+        Shared {
+            waveform_type: Arc::new(RwLock::new(OscillatorWaveform::Sine)),
+            note_state: Arc::new(Mutex::new(NoteState::new())),
+            octave_shift: Arc::new(RwLock::new(0)),
+            tremolo_effect: Arc::new(TremoloEffect::builder().build(48_000.0)),
+            scale: Arc::new(Mutex::new(keys_config.initial.scale())),
+            downsampled_audio_data: Arc::new(Mutex::new(DownsampledAudioData::new(
+                DEFAULT_VISUAL_FPS,
+            ))),
+            visuals_enabled: Arc::new(AtomicBool::new(false)),
+            quality_level: Arc::new(AtomicUsize::new(0)),
+            demo: None,
+            keys_config,
+            display_config: Arc::default(),
+        }
+    }
+
+    fn input(key: Key, code: KeyCode, state: ElementState) -> KeyInput {
+        KeyInput {
+            key,
+            physical_key: PhysicalKey::Code(code),
+            state,
+        }
+    }
+
+    fn character(character: &str) -> Key {
+        Key::Character(SmolStr::new(character))
+    }
+
+    #[test]
+    fn keys_play_and_control_the_synth_with_nothing_drawn() {
+        let shared = shared();
+        let bindings = ResolvedBindings::from_config(&shared.keys_config);
+        let mut key_translator = KeyTranslator::new(false);
+        let mut history = ParamHistory::new(shared.keys_config.undo.clone());
+        let mut press = |key: Key, code: KeyCode, state: ElementState| {
+            handle_key_input(
+                &input(key, code, state),
+                ModifiersState::empty(),
+                &mut key_translator,
+                &bindings,
+                None,
+                &shared,
+                &mut history,
+            );
+        };
+        let held_notes = || {
+            let note_state = shared.note_state.lock().unwrap();
+            let mut held: Vec<String> = note_state
+                .playing_notes
+                .iter()
+                .filter(|(_, holds)| **holds > 0)
+                .map(|(id, _)| id.note.clone())
+                .collect();
+            held.sort();
+            held
+        };
+
+        press(character("a"), KeyCode::KeyA, ElementState::Pressed);
+        assert_eq!(held_notes(), ["C"]);
+
+        // The octave keys still move the shift, within two octaves either way.
+        for _ in 0..4 {
+            let up = Key::Named(NamedKey::ArrowUp);
+            press(up.clone(), KeyCode::ArrowUp, ElementState::Pressed);
+            press(up, KeyCode::ArrowUp, ElementState::Released);
+        }
+        assert_eq!(*shared.octave_shift.read().unwrap(), 2);
+
+        // Display controls have nothing to act on, and leave everything else alone.
+        for (key, code) in [
+            (NamedKey::ArrowRight, KeyCode::ArrowRight),
+            (NamedKey::Tab, KeyCode::Tab),
+            (NamedKey::F1, KeyCode::F1),
+            (NamedKey::PageUp, KeyCode::PageUp),
+        ] {
+            press(Key::Named(key), code, ElementState::Pressed);
+            press(Key::Named(key), code, ElementState::Released);
+        }
+        assert_eq!(held_notes(), ["C"]);
+
+        press(character("0"), KeyCode::Digit0, ElementState::Pressed);
+        assert_eq!(
+            *shared.waveform_type.read().unwrap(),
+            OscillatorWaveform::Square
+        );
+
+        press(character("a"), KeyCode::KeyA, ElementState::Released);
+        assert!(held_notes().is_empty());
+    }
+
+    #[test]
+    fn undo_keys_send_back_the_values_from_before_each_gesture() {
+        let shared = shared();
+        let bindings = ResolvedBindings::from_config(&shared.keys_config);
+        let mut key_translator = KeyTranslator::new(false);
+        let mut history = ParamHistory::new(shared.keys_config.undo.clone());
+        let mut tap = |(key, code): (Key, KeyCode)| {
+            let mut sent = Vec::new();
+            for state in [ElementState::Pressed, ElementState::Released] {
+                sent.extend(handle_key_input(
+                    &input(key.clone(), code, state),
+                    ModifiersState::empty(),
+                    &mut key_translator,
+                    &bindings,
+                    None,
+                    &shared,
+                    &mut history,
+                ));
+            }
+            sent
+        };
+        let requests = || std::mem::take(&mut shared.note_state.lock().unwrap().param_requests);
+        let sine = waveform_to_param(OscillatorWaveform::Sine).unwrap();
+
+        // Three presses in a row are one gesture, the last going nowhere past the limit.
+        for _ in 0..3 {
+            tap((Key::Named(NamedKey::ArrowUp), KeyCode::ArrowUp));
+        }
+        tap((character("0"), KeyCode::Digit0));
+        assert!(requests().is_empty());
+
+        let undo = || (character("o"), KeyCode::KeyO);
+        let redo = || (character("i"), KeyCode::KeyI);
+        assert_eq!(tap(undo()), [(ParamId::Waveform, sine)]);
+        assert_eq!(requests(), [(ParamId::Waveform, sine)]);
+        assert_eq!(tap(undo()), [(ParamId::OctaveShift, 0.0)]);
+        assert_eq!(requests(), [(ParamId::OctaveShift, 0.0)]);
+        assert_eq!(tap(redo()), [(ParamId::OctaveShift, 2.0)]);
+        assert_eq!(requests(), [(ParamId::OctaveShift, 2.0)]);
+
+        // A random patch the engine set goes in the history at the next key.
+        shared
+            .note_state
+            .lock()
+            .unwrap()
+            .random_patches
+            .push(UndoStep {
+                label: "random patch 1".to_string(),
+                changes: vec![ParamChange {
+                    param: ParamId::Drive,
+                    before: 1.0,
+                    after: 3.0,
+                }],
+            });
+        assert_eq!(tap(undo()), [(ParamId::Drive, 1.0)]);
+        assert_eq!(requests(), [(ParamId::Drive, 1.0)]);
+    }
+}
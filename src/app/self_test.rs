@@ -0,0 +1,257 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use tracing::warn;
+
+use crate::app::{audio::run_audio_loop, CaptureOutput, Shared};
+use crate::graphics::{check_offscreen_render, RenderCheck};
+use crate::synth::{
+    backend::{open_output_device, Backend},
+    check_offline_render, AudioConfig, Config, DownsampledAudioData, NoteState, TremoloEffect,
+    DEFAULT_VISUAL_FPS,
+};
+
+/// Sample rate the offline audio check renders at.
+const SELF_TEST_SAMPLE_RATE: f32 = 44100.0;
+
+/// How long the device check keeps its stream open.
+const DEVICE_CHECK_TIME: Duration = Duration::from_millis(200);
+
+/// The note the pipeline check holds, and how many blocks of it the audio loop plays.
+const PIPELINE_CHECK_NOTE: &str = "A";
+const PIPELINE_CHECK_BLOCKS: u32 = 40;
+
+/// Block length and channel count of the stand-in device the pipeline check plays through.
+const PIPELINE_CHECK_FRAMES: u32 = 512;
+const PIPELINE_CHECK_CHANNELS: u16 = 2;
+
+/// Quietest the pipeline check's output may be, as an RMS level, to count as sounding.
+const PIPELINE_MIN_RMS: f32 = 0.01;
+
+/// How a single self-test check came out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// The check couldn't run here, which isn't counted as a failure.
+    Skip,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "SKIP",
+        })
+    }
+}
+
+/// One line of the self-test report: which subsystem was checked and what was found.
+#[derive(Debug, Clone)]
+pub struct CheckReport {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl CheckReport {
+    fn from_result(name: &'static str, result: Result<String>) -> Self {
+        match result {
+            Ok(detail) => CheckReport {
+                name,
+                status: CheckStatus::Pass,
+                detail,
+            },
+            Err(err) => CheckReport {
+                name,
+                status: CheckStatus::Fail,
+                detail: format!("{:#}", err),
+            },
+        }
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<10} {}  {}", self.name, self.status, self.detail)
+    }
+}
+
+/// Renders a short note offline and checks its level and pitch.
+pub fn audio_check() -> CheckReport {
+    let result = check_offline_render(SELF_TEST_SAMPLE_RATE).map(|check| {
+        format!(
+            "rms {:.3}, fundamental {:.2} Hz (expected {:.2} Hz)",
+            check.rms, check.fundamental, check.expected_fundamental
+        )
+    });
+    CheckReport::from_result("audio", result)
+}
+
+/// Holds a note and plays it through the audio loop into a stand-in device, as the synth would
+/// with a real one, and checks what comes out isn't silent.
+pub fn pipeline_check(keys_config: Arc<Config>) -> CheckReport {
+    CheckReport::from_result("pipeline", play_held_note(keys_config))
+}
+
+fn play_held_note(keys_config: Arc<Config>) -> Result<String> {
+    let config = cpal::StreamConfig {
+        channels: PIPELINE_CHECK_CHANNELS,
+        sample_rate: cpal::SampleRate(SELF_TEST_SAMPLE_RATE as u32),
+        buffer_size: cpal::BufferSize::Fixed(PIPELINE_CHECK_FRAMES),
+    };
+    let mut note_state = NoteState::new();
+    note_state.note_on(PIPELINE_CHECK_NOTE.to_string());
+    let shared = Shared {
+        waveform_type: Arc::new(RwLock::new(keys_config.initial.waveform)),
+        note_state: Arc::new(Mutex::new(note_state)),
+        octave_shift: Arc::new(RwLock::new(0)),
+        tremolo_effect: Arc::new(TremoloEffect::builder().build(SELF_TEST_SAMPLE_RATE)),
+        scale: Arc::new(Mutex::new(keys_config.initial.scale())),
+        downsampled_audio_data: Arc::new(Mutex::new(DownsampledAudioData::new(DEFAULT_VISUAL_FPS))),
+        visuals_enabled: Arc::new(AtomicBool::new(false)),
+        quality_level: Arc::new(AtomicUsize::new(0)),
+        demo: None,
+        keys_config,
+        display_config: Arc::default(),
+    };
+
+    let output = CaptureOutput::new(PIPELINE_CHECK_BLOCKS as usize);
+    run_audio_loop::<f32, _>(
+        Backend::Default,
+        &output,
+        &config,
+        shared,
+        Arc::new(AtomicU64::new(0)),
+    )?;
+
+    let samples = output.captured();
+    if let Some(position) = samples.iter().position(|sample| !sample.is_finite()) {
+        bail!("Sample {} of the output is {}", position, samples[position]);
+    }
+    let rms = (samples.iter().map(|sample| sample * sample).sum::<f32>()
+        / samples.len().max(1) as f32)
+        .sqrt();
+    if rms < PIPELINE_MIN_RMS {
+        bail!(
+            "Holding {} played {} samples at RMS {:.4}, below {}",
+            PIPELINE_CHECK_NOTE,
+            samples.len(),
+            rms,
+            PIPELINE_MIN_RMS
+        );
+    }
+    Ok(format!(
+        "held {} for {} blocks, rms {:.3}",
+        PIPELINE_CHECK_NOTE, PIPELINE_CHECK_BLOCKS, rms
+    ))
+}
+
+/// Draws into an offscreen texture and reads it back. Skipped when there is no GPU adapter.
+pub async fn graphics_check() -> CheckReport {
+    match check_offscreen_render().await {
+        Ok(RenderCheck::NoAdapter) => {
+            warn!("No GPU adapter found; skipping the graphics check");
+            CheckReport {
+                name: "graphics",
+                status: CheckStatus::Skip,
+                detail: "no GPU adapter".to_string(),
+            }
+        }
+        Ok(RenderCheck::Rendered {
+            adapter,
+            drawn_pixels,
+            total_pixels,
+        }) => CheckReport::from_result(
+            "graphics",
+            Ok(format!(
+                "{}: drew {} of {} pixels",
+                adapter, drawn_pixels, total_pixels
+            )),
+        ),
+        Err(err) => CheckReport::from_result("graphics", Err(err)),
+    }
+}
+
+/// Opens the output device, plays silence through it briefly and closes it again.
+pub fn device_check(cli_backend: Option<Backend>, audio_config: &AudioConfig) -> CheckReport {
+    CheckReport::from_result("device", open_silent_stream(cli_backend, audio_config))
+}
+
+fn open_silent_stream(cli_backend: Option<Backend>, audio_config: &AudioConfig) -> Result<String> {
+    let (backend, device) = open_output_device(cli_backend, audio_config)?;
+    let config = device.default_output_config()?;
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => build_silent_stream::<f32>(&device, &config.config())?,
+        cpal::SampleFormat::I16 => build_silent_stream::<i16>(&device, &config.config())?,
+        cpal::SampleFormat::U16 => build_silent_stream::<u16>(&device, &config.config())?,
+        format => bail!("Unsupported sample format {}", format),
+    };
+    stream.play()?;
+    std::thread::sleep(DEVICE_CHECK_TIME);
+    drop(stream);
+    Ok(format!(
+        "{} on {}, {} channels at {} Hz",
+        device
+            .name()
+            .unwrap_or_else(|_| "unnamed device".to_string()),
+        backend.host_name(),
+        config.channels(),
+        config.sample_rate().0
+    ))
+}
+
+fn build_silent_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+) -> Result<cpal::Stream>
+where
+    T: cpal::Sample + cpal::SizedSample,
+{
+    let err_fn = |err| eprintln!("An error occurred on the audio stream: {}", err);
+    let stream = device.build_output_stream(
+        config,
+        |data: &mut [T], _: &cpal::OutputCallbackInfo| data.fill(T::EQUILIBRIUM),
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Runs every self-test check, prints a line for each and fails if any of them did. The device
+/// check only runs with `check_device`, since build machines usually have no audio device.
+pub async fn run_self_test(
+    check_device: bool,
+    cli_backend: Option<Backend>,
+    keys_config: Config,
+) -> Result<()> {
+    let keys_config = Arc::new(keys_config);
+    let mut reports = vec![
+        audio_check(),
+        pipeline_check(Arc::clone(&keys_config)),
+        graphics_check().await,
+    ];
+    if check_device {
+        reports.push(device_check(cli_backend, &keys_config.audio));
+    }
+
+    println!("Self-test:");
+    for report in &reports {
+        println!("  {}", report);
+    }
+    let failed: Vec<&str> = reports
+        .iter()
+        .filter(|report| report.status == CheckStatus::Fail)
+        .map(|report| report.name)
+        .collect();
+    if !failed.is_empty() {
+        bail!("Self-test failed: {}", failed.join(", "));
+    }
+    println!("Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,400 @@
+//! A C interface to the synthesis engine, for embedding it in hosts that aren't written in Rust.
+//!
+//! Every function catches panics and reports them as `SynthStatus::Panic` (or a null engine)
+//! instead of unwinding into the host. `include/visiosynth.h` is generated from this file with
+//! cbindgen by build.rs; see `cbindgen.toml`.
+
+use std::ffi::{c_char, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use lazy_static::lazy_static;
+use tracing::warn;
+
+use crate::synth::{
+    keys::keys::midi_note_to_name, route_channels, ExtraChannels, ParamId, SynthEngine,
+};
+
+/// What a call into the engine came to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum SynthStatus {
+    Ok = 0,
+    /// The engine or buffer pointer was null.
+    NullPointer = -1,
+    /// An argument was out of range, such as a MIDI note above 127 or an unknown parameter.
+    InvalidArgument = -2,
+    /// The engine panicked. It may be left in an inconsistent state and should be freed.
+    Panic = -3,
+}
+
+lazy_static! {
+    static ref PARAM_NAMES: Vec<CString> = ParamId::ALL
+        .iter()
+        .map(|param| CString::new(param.name()).unwrap())
+        .collect();
+}
+
+/// Runs `f` on the engine behind `engine`, turning a null pointer or a panic into a status.
+///
+/// # Safety
+///
+/// `engine` must be null or a pointer returned by `synth_engine_new` that hasn't been freed.
+unsafe fn with_engine(
+    engine: *mut SynthEngine,
+    f: impl FnOnce(&mut SynthEngine) -> SynthStatus,
+) -> SynthStatus {
+    let Some(engine) = engine.as_mut() else {
+        return SynthStatus::NullPointer;
+    };
+    panic::catch_unwind(AssertUnwindSafe(|| f(engine))).unwrap_or(SynthStatus::Panic)
+}
+
+/// Creates an engine rendering at `sample_rate` with at most `max_voices` notes sounding at
+/// once, or any number when `max_voices` is 0. Returns null if the sample rate isn't positive.
+///
+/// Free the engine with `synth_engine_free`.
+#[no_mangle]
+pub extern "C" fn synth_engine_new(sample_rate: f32, max_voices: u32) -> *mut SynthEngine {
+    if !(sample_rate.is_finite() && sample_rate > 0.0) {
+        return ptr::null_mut();
+    }
+    panic::catch_unwind(|| {
+        let mut builder = SynthEngine::builder();
+        if max_voices > 0 {
+            builder = builder.max_voices(max_voices as usize);
+        }
+        Box::into_raw(Box::new(builder.build(sample_rate)))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Frees an engine made by `synth_engine_new`. Null is ignored.
+///
+/// # Safety
+///
+/// `engine` must be null or a pointer returned by `synth_engine_new` that hasn't been freed,
+/// and mustn't be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn synth_engine_free(engine: *mut SynthEngine) {
+    if engine.is_null() {
+        return;
+    }
+    let engine = Box::from_raw(engine);
+    if panic::catch_unwind(AssertUnwindSafe(|| drop(engine))).is_err() {
+        warn!("Panicked while freeing the engine");
+    }
+}
+
+/// Starts MIDI note `midi_note` struck at `velocity`. A velocity of 0 stops the note, as in
+/// MIDI.
+///
+/// # Safety
+///
+/// `engine` must be null or a live pointer from `synth_engine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn synth_engine_note_on(
+    engine: *mut SynthEngine,
+    midi_note: u8,
+    velocity: u8,
+) -> SynthStatus {
+    with_engine(engine, |engine| {
+        if midi_note > 127 || velocity > 127 {
+            return SynthStatus::InvalidArgument;
+        }
+        let note = midi_note_to_name(midi_note as i32);
+        let Ok(mut note_state) = engine.note_state().lock() else {
+            return SynthStatus::Panic;
+        };
+        if velocity == 0 {
+            note_state.note_off(note);
+        } else {
+            note_state.note_on_with_velocity(note, velocity);
+        }
+        SynthStatus::Ok
+    })
+}
+
+/// Stops MIDI note `midi_note`, letting its release ring out.
+///
+/// # Safety
+///
+/// `engine` must be null or a live pointer from `synth_engine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn synth_engine_note_off(
+    engine: *mut SynthEngine,
+    midi_note: u8,
+) -> SynthStatus {
+    with_engine(engine, |engine| {
+        if midi_note > 127 {
+            return SynthStatus::InvalidArgument;
+        }
+        let Ok(mut note_state) = engine.note_state().lock() else {
+            return SynthStatus::Panic;
+        };
+        note_state.note_off(midi_note_to_name(midi_note as i32));
+        SynthStatus::Ok
+    })
+}
+
+/// Sets parameter `param_id` to `value`, clamped to its range. The ids are listed by
+/// `synth_param_count` and `synth_param_name`.
+///
+/// # Safety
+///
+/// `engine` must be null or a live pointer from `synth_engine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn synth_engine_set_param(
+    engine: *mut SynthEngine,
+    param_id: u32,
+    value: f32,
+) -> SynthStatus {
+    with_engine(engine, |engine| {
+        let Some(param) = ParamId::from_index(param_id) else {
+            return SynthStatus::InvalidArgument;
+        };
+        match engine.set_param(param, value) {
+            Ok(()) => SynthStatus::Ok,
+            Err(_) => SynthStatus::InvalidArgument,
+        }
+    })
+}
+
+/// Renders `frames` frames of `channels` interleaved channels into `out`, which must hold
+/// `frames * channels` floats. The engine renders mono, copied to every channel, unless its
+/// unison stack has a `stereo_spread`. Then it renders stereo, planar inside the engine: the
+/// left and right channels are interleaved into the first two channels of `out` and any
+/// others are left silent.
+///
+/// # Safety
+///
+/// `engine` must be null or a live pointer from `synth_engine_new`, and `out` must be null or
+/// valid for writing `frames * channels` floats.
+#[no_mangle]
+pub unsafe extern "C" fn synth_engine_process(
+    engine: *mut SynthEngine,
+    out: *mut f32,
+    frames: usize,
+    channels: usize,
+) -> SynthStatus {
+    if out.is_null() {
+        return SynthStatus::NullPointer;
+    }
+    with_engine(engine, |engine| {
+        let Some(num_samples) = frames.checked_mul(channels).filter(|_| channels > 0) else {
+            return SynthStatus::InvalidArgument;
+        };
+        if num_samples == 0 {
+            return SynthStatus::Ok;
+        }
+        let out = std::slice::from_raw_parts_mut(out, num_samples);
+        let rendered = engine.render(frames);
+        route_channels(
+            &rendered.interleaved(),
+            rendered.num_channels(),
+            out,
+            channels,
+            ExtraChannels::Silent,
+        );
+        SynthStatus::Ok
+    })
+}
+
+/// How many parameters there are. Their ids run from 0 to one less than this.
+#[no_mangle]
+pub extern "C" fn synth_param_count() -> u32 {
+    ParamId::ALL.len() as u32
+}
+
+/// The name of parameter `param_id`, such as `"tremolo_rate"`, or null for an unknown id. The
+/// string is static; don't free it.
+#[no_mangle]
+pub extern "C" fn synth_param_name(param_id: u32) -> *const c_char {
+    panic::catch_unwind(|| {
+        ParamId::from_index(param_id).map_or(ptr::null(), |param| {
+            PARAM_NAMES[param.index() as usize].as_ptr()
+        })
+    })
+    .unwrap_or(ptr::null())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    const FRAMES: usize = 256;
+    const CHANNELS: usize = 2;
+
+    /// A change made to the engine between blocks.
+    #[derive(Clone, Copy)]
+    enum Step {
+        NoteOn(u8, u8),
+        NoteOff(u8),
+        SetParam(ParamId, f32),
+        Render,
+    }
+
+    const SCRIPT: &[Step] = &[
+        Step::SetParam(ParamId::Waveform, 2.0),
+        Step::NoteOn(60, 100),
+        Step::Render,
+        Step::NoteOn(64, 40),
+        Step::Render,
+        Step::SetParam(ParamId::Drive, 3.0),
+        Step::Render,
+        Step::NoteOff(60),
+        // A note-on at velocity 0 is a note-off.
+        Step::NoteOn(64, 0),
+        Step::Render,
+        Step::Render,
+    ];
+
+    /// Plays `SCRIPT` through the C interface, returning everything it rendered.
+    fn render_through_ffi() -> Vec<f32> {
+        let engine = synth_engine_new(SAMPLE_RATE, 4);
+        assert!(!engine.is_null());
+        let mut rendered = Vec::new();
+        for step in SCRIPT {
+            let status = unsafe {
+                match *step {
+                    Step::NoteOn(note, velocity) => synth_engine_note_on(engine, note, velocity),
+                    Step::NoteOff(note) => synth_engine_note_off(engine, note),
+                    Step::SetParam(param, value) => {
+                        synth_engine_set_param(engine, param.index(), value)
+                    }
+                    Step::Render => {
+                        let mut out = vec![f32::NAN; FRAMES * CHANNELS];
+                        let status =
+                            synth_engine_process(engine, out.as_mut_ptr(), FRAMES, CHANNELS);
+                        rendered.extend(out);
+                        status
+                    }
+                }
+            };
+            assert_eq!(status, SynthStatus::Ok);
+        }
+        unsafe { synth_engine_free(engine) };
+        rendered
+    }
+
+    /// Plays `SCRIPT` through the Rust API, returning everything it rendered.
+    fn render_natively() -> Vec<f32> {
+        let mut engine = SynthEngine::builder().max_voices(4).build(SAMPLE_RATE);
+        let mut rendered = Vec::new();
+        for step in SCRIPT {
+            match *step {
+                Step::NoteOn(note, 0) | Step::NoteOff(note) => engine
+                    .note_state()
+                    .lock()
+                    .unwrap()
+                    .note_off(midi_note_to_name(note as i32)),
+                Step::NoteOn(note, velocity) => engine
+                    .note_state()
+                    .lock()
+                    .unwrap()
+                    .note_on_with_velocity(midi_note_to_name(note as i32), velocity),
+                Step::SetParam(param, value) => engine.set_param(param, value).unwrap(),
+                Step::Render => {
+                    let block = engine.render(FRAMES);
+                    let mut out = vec![0.0; FRAMES * CHANNELS];
+                    route_channels(
+                        &block.interleaved(),
+                        block.num_channels(),
+                        &mut out,
+                        CHANNELS,
+                        ExtraChannels::Silent,
+                    );
+                    rendered.extend(out);
+                }
+            }
+        }
+        rendered
+    }
+
+    #[test]
+    fn the_c_interface_renders_what_the_engine_does() {
+        let through_ffi = render_through_ffi();
+        let native = render_natively();
+        assert_eq!(through_ffi.len(), 5 * FRAMES * CHANNELS);
+        assert!(through_ffi.iter().any(|sample| sample.abs() > 1e-3));
+        assert_eq!(
+            through_ffi.iter().map(|s| s.to_bits()).collect::<Vec<_>>(),
+            native.iter().map(|s| s.to_bits()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn bad_arguments_are_reported_rather_than_acted_on() {
+        assert!(synth_engine_new(0.0, 4).is_null());
+        assert!(synth_engine_new(f32::NAN, 4).is_null());
+
+        let null = ptr::null_mut();
+        let mut out = [0.0f32; 8];
+        unsafe {
+            assert_eq!(
+                synth_engine_note_on(null, 60, 100),
+                SynthStatus::NullPointer
+            );
+            assert_eq!(synth_engine_note_off(null, 60), SynthStatus::NullPointer);
+            assert_eq!(
+                synth_engine_set_param(null, 0, 1.0),
+                SynthStatus::NullPointer
+            );
+            assert_eq!(
+                synth_engine_process(null, out.as_mut_ptr(), 4, 2),
+                SynthStatus::NullPointer
+            );
+            synth_engine_free(null);
+        }
+
+        let engine = synth_engine_new(SAMPLE_RATE, 0);
+        unsafe {
+            assert_eq!(
+                synth_engine_note_on(engine, 128, 100),
+                SynthStatus::InvalidArgument
+            );
+            assert_eq!(
+                synth_engine_note_on(engine, 60, 128),
+                SynthStatus::InvalidArgument
+            );
+            assert_eq!(
+                synth_engine_note_off(engine, 200),
+                SynthStatus::InvalidArgument
+            );
+            assert_eq!(
+                synth_engine_set_param(engine, synth_param_count(), 1.0),
+                SynthStatus::InvalidArgument
+            );
+            assert_eq!(
+                synth_engine_process(engine, ptr::null_mut(), 4, 2),
+                SynthStatus::NullPointer
+            );
+            assert_eq!(
+                synth_engine_process(engine, out.as_mut_ptr(), 4, 0),
+                SynthStatus::InvalidArgument
+            );
+            assert_eq!(
+                synth_engine_process(engine, out.as_mut_ptr(), usize::MAX, 2),
+                SynthStatus::InvalidArgument
+            );
+            assert_eq!(
+                synth_engine_process(engine, out.as_mut_ptr(), 0, 2),
+                SynthStatus::Ok
+            );
+            synth_engine_free(engine);
+        }
+    }
+
+    #[test]
+    fn parameters_are_listed_by_id() {
+        assert_eq!(synth_param_count() as usize, ParamId::ALL.len());
+        for param in ParamId::ALL {
+            let name = unsafe { CStr::from_ptr(synth_param_name(param.index())) };
+            assert_eq!(name.to_str().unwrap(), param.name());
+        }
+        assert!(synth_param_name(synth_param_count()).is_null());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::graphics::{lerp_hue, AudioData};
+
+/// Display options for people who find the visuals hard to see or uncomfortable to watch.
+/// Both can also be switched while playing with the accessibility keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessibilityConfig {
+    /// A white waveform on a black background, drawn with a thicker line.
+    pub high_contrast: bool,
+    /// Slower, steadier visuals: brightness and hue change gradually and the waveform is
+    /// averaged over several frames.
+    pub reduced_motion: bool,
+    /// Width in screen pixels of the waveform line with high contrast on.
+    pub high_contrast_line_width: f32,
+    /// Most the background's brightness may change per second with reduced motion on, from 0
+    /// to 1.
+    pub max_brightness_rate: f32,
+    /// How far below full brightness the waveform may dim with the audio with reduced motion
+    /// on, from 0 to 1.
+    pub waveform_brightness_range: f32,
+    /// Degrees per second the background's hue may turn with reduced motion on.
+    pub max_hue_rate: f32,
+    /// Frames averaged into the drawn waveform with reduced motion on.
+    pub history_frames: usize,
+}
+
+impl Default for AccessibilityConfig {
+    fn default() -> Self {
+        AccessibilityConfig {
+            high_contrast: false,
+            reduced_motion: false,
+            high_contrast_line_width: 3.0,
+            max_brightness_rate: 0.5,
+            waveform_brightness_range: 0.3,
+            max_hue_rate: 10.0,
+            history_frames: 4,
+        }
+    }
+}
+
+/// Moves from `current` towards `target`, by no more than `max_rate` per second over `dt`
+/// seconds.
+pub fn limit_rate(current: f32, target: f32, max_rate: f32, dt: f32) -> f32 {
+    let max_step = (max_rate * dt).max(0.0);
+    current + (target - current).clamp(-max_step, max_step)
+}
+
+/// Turns from hue `current` towards hue `target`, in degrees, the short way around the color
+/// wheel and by no more than `max_rate` degrees per second over `dt` seconds.
+pub fn limit_hue_rate(current: f32, target: f32, max_rate: f32, dt: f32) -> f32 {
+    let mut difference = (target - current).rem_euclid(360.0);
+    if difference > 180.0 {
+        difference -= 360.0;
+    }
+    let max_step = (max_rate * dt).max(0.0);
+    if difference.abs() <= max_step {
+        return target.rem_euclid(360.0);
+    }
+    lerp_hue(current, target, max_step / difference.abs())
+}
+
+/// Averages the drawn waveform over the last few frames, so it scrolls and changes shape
+/// more slowly.
+///
+/// Frames with a different number of samples than the one before restart the average, since
+/// their samples don't line up.
+#[derive(Debug, Default)]
+pub struct FrameHistory {
+    frames: VecDeque<(Vec<f32>, Vec<f32>)>,
+}
+
+impl FrameHistory {
+    /// Adds `audio_data` to the history and replaces it with the average of the last `frames`
+    /// frames, itself included.
+    pub fn apply(&mut self, audio_data: &mut AudioData, frames: usize) {
+        let count = audio_data.count();
+        if self
+            .frames
+            .back()
+            .is_some_and(|(samples, _)| samples.len() != count)
+        {
+            self.frames.clear();
+        }
+        self.frames.push_back((
+            audio_data.samples[..count].to_vec(),
+            audio_data.right_samples[..count].to_vec(),
+        ));
+        while self.frames.len() > frames.max(1) {
+            self.frames.pop_front();
+        }
+
+        let scale = 1.0 / self.frames.len() as f32;
+        let mut samples = vec![0.0; count];
+        let mut right_samples = vec![0.0; count];
+        for (frame_samples, frame_right_samples) in self.frames.iter() {
+            for (sum, sample) in samples.iter_mut().zip(frame_samples) {
+                *sum += sample * scale;
+            }
+            for (sum, sample) in right_samples.iter_mut().zip(frame_right_samples) {
+                *sum += sample * scale;
+            }
+        }
+        audio_data.set_samples(&samples, &right_samples);
+    }
+
+    /// Forgets the frames seen so far.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_level_moves_no_faster_than_the_rate_allows() {
+        // Half a unit a second over a tenth of a second is at most 0.05.
+        assert!((limit_rate(0.2, 1.0, 0.5, 0.1) - 0.25).abs() < 1e-6);
+        assert!((limit_rate(0.8, 0.0, 0.5, 0.1) - 0.75).abs() < 1e-6);
+        // A target within reach is met exactly.
+        assert_eq!(limit_rate(0.5, 0.52, 0.5, 0.1), 0.52);
+        // No time, or a negative rate, moves nothing.
+        assert_eq!(limit_rate(0.5, 1.0, 0.5, 0.0), 0.5);
+        assert_eq!(limit_rate(0.5, 1.0, -1.0, 0.1), 0.5);
+    }
+
+    #[test]
+    fn the_level_reaches_a_step_after_the_time_the_rate_takes() {
+        let mut level = 0.0;
+        let mut frames = 0;
+        while level < 1.0 {
+            level = limit_rate(level, 1.0, 0.5, 1.0 / 60.0);
+            frames += 1;
+        }
+        // Two seconds at 60 fps.
+        assert!((119..=121).contains(&frames), "{}", frames);
+    }
+
+    #[test]
+    fn the_hue_turns_the_short_way_around_at_the_limited_rate() {
+        // From 350 to 10 is 20 degrees up through 0, not 340 down.
+        let hue = limit_hue_rate(350.0, 10.0, 10.0, 0.5);
+        assert!((hue - 355.0).abs() < 1e-3, "{}", hue);
+        let hue = limit_hue_rate(10.0, 350.0, 10.0, 0.5);
+        assert!((hue - 5.0).abs() < 1e-3, "{}", hue);
+        // Within reach it lands on the target, wrapped into 0..360.
+        assert_eq!(limit_hue_rate(355.0, 362.0, 10.0, 1.0), 2.0);
+    }
+
+    fn frame(level: f32, count: usize) -> AudioData {
+        let mut audio_data = AudioData::default();
+        audio_data.set_samples(&vec![level; count], &vec![-level; count]);
+        audio_data
+    }
+
+    #[test]
+    fn the_waveform_is_averaged_over_the_last_frames() {
+        let mut history = FrameHistory::default();
+        let mut averaged = Vec::new();
+        for level in [0.0, 0.4, 0.8, 1.2, 1.6] {
+            let mut audio_data = frame(level, 8);
+            history.apply(&mut audio_data, 4);
+            averaged.push(audio_data.samples[0]);
+            assert_eq!(audio_data.right_samples[0], -audio_data.samples[0]);
+        }
+        let expected = [0.0, 0.2, 0.4, 0.6, 1.0];
+        for (averaged, expected) in averaged.iter().zip(expected) {
+            assert!((averaged - expected).abs() < 1e-6, "{:?}", averaged);
+        }
+    }
+
+    #[test]
+    fn a_frame_of_another_length_restarts_the_average() {
+        let mut history = FrameHistory::default();
+        history.apply(&mut frame(1.0, 8), 4);
+        let mut audio_data = frame(0.5, 16);
+        history.apply(&mut audio_data, 4);
+        assert_eq!(audio_data.count(), 16);
+        assert!(audio_data.samples[..16].iter().all(|&sample| sample == 0.5));
+    }
+}
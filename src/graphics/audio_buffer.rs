@@ -0,0 +1,270 @@
+use crate::graphics::{waveform_shader_constants, WaveformLayout};
+use crate::synth::MAX_VISUAL_SAMPLES;
+
+/// Size of the audio array in the uniform fallback, in `vec4` entries: both channels at
+/// `MAX_VISUAL_SAMPLES`, which stays well inside WebGL's 16 KiB uniform limit.
+pub const UNIFORM_AUDIO_ENTRIES: usize = 2 * MAX_VISUAL_SAMPLES / 4;
+
+const SHADER_SOURCE: &str = include_str!("shader.wgsl");
+const STORAGE_BINDING: &str = "var<storage, read> audio";
+const STORAGE_ARRAY: &str = "array<vec4<f32>>,";
+
+/// How the audio samples reach the waveform shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioBufferBinding {
+    /// A read-only storage buffer, sized at runtime.
+    Storage,
+    /// A fixed `UNIFORM_AUDIO_ENTRIES` uniform array, for adapters that can't read storage
+    /// buffers from a vertex shader, such as WebGL2.
+    Uniform,
+}
+
+impl AudioBufferBinding {
+    /// Picks storage when the adapter can read it from a vertex shader, uniform otherwise.
+    pub fn for_adapter(adapter: &wgpu::Adapter) -> Self {
+        let vertex_storage = adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::VERTEX_STORAGE);
+        if vertex_storage && adapter.limits().max_storage_buffers_per_shader_stage > 0 {
+            AudioBufferBinding::Storage
+        } else {
+            AudioBufferBinding::Uniform
+        }
+    }
+
+    pub fn buffer_binding_type(&self) -> wgpu::BufferBindingType {
+        match self {
+            AudioBufferBinding::Storage => wgpu::BufferBindingType::Storage { read_only: true },
+            AudioBufferBinding::Uniform => wgpu::BufferBindingType::Uniform,
+        }
+    }
+
+    pub fn buffer_usage(&self) -> wgpu::BufferUsages {
+        match self {
+            AudioBufferBinding::Storage => wgpu::BufferUsages::STORAGE,
+            AudioBufferBinding::Uniform => wgpu::BufferUsages::UNIFORM,
+        }
+    }
+
+    /// Number of `vec4` entries a buffer for `required` entries is allocated with. The uniform
+    /// array has a fixed size, so it can't grow.
+    pub fn capacity_for(&self, required: usize) -> usize {
+        match self {
+            AudioBufferBinding::Storage => required.max(1),
+            AudioBufferBinding::Uniform => UNIFORM_AUDIO_ENTRIES,
+        }
+    }
+
+    /// What to do with a buffer of `capacity` entries so it holds `layout`.
+    pub fn resize_for(&self, capacity: usize, layout: AudioBufferLayout) -> AudioBufferResize {
+        let required = layout.entries();
+        if required <= capacity {
+            return AudioBufferResize::Keep;
+        }
+        let new_capacity = self.capacity_for(required);
+        if new_capacity < required {
+            AudioBufferResize::Full {
+                capacity: new_capacity,
+                required,
+            }
+        } else {
+            AudioBufferResize::Grow(new_capacity)
+        }
+    }
+
+    /// The waveform shader, after the constants it shares with vertex.rs, declaring the audio
+    /// array to match the binding.
+    pub fn shader_source(&self) -> String {
+        let source = waveform_shader_constants() + SHADER_SOURCE;
+        match self {
+            AudioBufferBinding::Storage => source,
+            AudioBufferBinding::Uniform => source
+                .replace(STORAGE_BINDING, "var<uniform> audio")
+                .replace(
+                    STORAGE_ARRAY,
+                    &format!("array<vec4<f32>, {}>,", UNIFORM_AUDIO_ENTRIES),
+                ),
+        }
+    }
+}
+
+/// What a new layout takes of the GPU audio buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioBufferResize {
+    /// The buffer already holds it.
+    Keep,
+    /// The buffer, and its bind group, are recreated with this many entries.
+    Grow(usize),
+    /// The buffer can't grow to what is needed, so fewer samples are drawn.
+    Full { capacity: usize, required: usize },
+}
+
+/// What the GPU audio buffer has to hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioBufferLayout {
+    pub mode: WaveformLayout,
+    /// Most samples per channel that will be drawn.
+    pub samples_per_channel: usize,
+    /// Waveforms drawn over each other: 1, or 2 with the `both` visual tap's overlay.
+    pub streams: usize,
+}
+
+impl AudioBufferLayout {
+    /// Number of `vec4` entries the layout needs: one channel for a single waveform, both for
+    /// a split, for each stream.
+    pub fn entries(&self) -> usize {
+        self.streams.max(1) * layout_channels(self.mode) * self.samples_per_channel.div_ceil(4)
+    }
+}
+
+/// Number of channels packed into the audio buffer for `mode`.
+pub fn layout_channels(mode: WaveformLayout) -> usize {
+    match mode {
+        WaveformLayout::Single => 1,
+        WaveformLayout::Split => 2,
+    }
+}
+
+/// Value the shader sees for `mode`.
+pub fn layout_index(mode: WaveformLayout) -> u32 {
+    match mode {
+        WaveformLayout::Single => 0,
+        WaveformLayout::Split => 1,
+    }
+}
+
+/// Packs `left`, and `right` after it for a split, four samples to an entry, the way the
+/// shader reads them. Each channel's last entry is padded with zeros.
+pub fn pack_channels(mode: WaveformLayout, left: &[f32], right: &[f32]) -> Vec<[f32; 4]> {
+    let channels: &[&[f32]] = match mode {
+        WaveformLayout::Single => &[left],
+        WaveformLayout::Split => &[left, right],
+    };
+    channels
+        .iter()
+        .flat_map(|samples| samples.chunks(4))
+        .map(|chunk| {
+            let mut entry = [0.0; 4];
+            entry[..chunk.len()].copy_from_slice(chunk);
+            entry
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEFT: [f32; 6] = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+    const RIGHT: [f32; 6] = [-1.0, -2.0, -3.0, -4.0, -5.0, -6.0];
+
+    fn layout(
+        mode: WaveformLayout,
+        samples_per_channel: usize,
+        streams: usize,
+    ) -> AudioBufferLayout {
+        AudioBufferLayout {
+            mode,
+            samples_per_channel,
+            streams,
+        }
+    }
+
+    #[test]
+    fn a_single_waveform_packs_the_left_channel_alone() {
+        assert_eq!(
+            pack_channels(WaveformLayout::Single, &LEFT, &RIGHT),
+            [[1.0, 2.0, 3.0, 4.0], [5.0, 6.0, 0.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn a_split_packs_the_right_channel_after_the_left() {
+        assert_eq!(
+            pack_channels(WaveformLayout::Split, &LEFT, &RIGHT),
+            [
+                [1.0, 2.0, 3.0, 4.0],
+                [5.0, 6.0, 0.0, 0.0],
+                [-1.0, -2.0, -3.0, -4.0],
+                [-5.0, -6.0, 0.0, 0.0],
+            ]
+        );
+    }
+
+    #[test]
+    fn packing_fills_exactly_the_entries_the_layout_asks_for() {
+        for mode in [WaveformLayout::Single, WaveformLayout::Split] {
+            for count in [0, 1, 4, 5, 255, 256] {
+                let left = vec![0.5; count];
+                let packed = pack_channels(mode, &left, &left);
+                assert_eq!(
+                    packed.len(),
+                    layout(mode, count, 1).entries(),
+                    "{:?} {}",
+                    mode,
+                    count
+                );
+                // The overlay's samples follow the waveform's, laid out the same way.
+                assert_eq!(2 * packed.len(), layout(mode, count, 2).entries());
+            }
+        }
+        assert_eq!(layout_channels(WaveformLayout::Single), 1);
+        assert_eq!(layout_channels(WaveformLayout::Split), 2);
+        assert_ne!(
+            layout_index(WaveformLayout::Single),
+            layout_index(WaveformLayout::Split)
+        );
+    }
+
+    #[test]
+    fn a_storage_buffer_is_only_recreated_when_it_has_to_grow() {
+        let binding = AudioBufferBinding::Storage;
+        let split = layout(WaveformLayout::Split, 256, 1);
+        assert_eq!(split.entries(), 128);
+        assert_eq!(binding.resize_for(128, split), AudioBufferResize::Keep);
+        assert_eq!(binding.resize_for(1_000, split), AudioBufferResize::Keep);
+        assert_eq!(binding.resize_for(64, split), AudioBufferResize::Grow(128));
+        // Switching to fewer samples or a single waveform never shrinks it.
+        assert_eq!(
+            binding.resize_for(128, layout(WaveformLayout::Single, 100, 1)),
+            AudioBufferResize::Keep
+        );
+        assert_eq!(
+            binding.resize_for(128, layout(WaveformLayout::Split, 256, 2)),
+            AudioBufferResize::Grow(256)
+        );
+        assert_eq!(
+            binding.resize_for(0, layout(WaveformLayout::Single, 0, 1)),
+            AudioBufferResize::Keep
+        );
+    }
+
+    #[test]
+    fn the_uniform_fallback_never_grows() {
+        let binding = AudioBufferBinding::Uniform;
+        let fits = layout(WaveformLayout::Split, MAX_VISUAL_SAMPLES, 1);
+        assert_eq!(
+            binding.resize_for(UNIFORM_AUDIO_ENTRIES, fits),
+            AudioBufferResize::Keep
+        );
+        let too_big = layout(WaveformLayout::Split, MAX_VISUAL_SAMPLES, 2);
+        assert_eq!(
+            binding.resize_for(UNIFORM_AUDIO_ENTRIES, too_big),
+            AudioBufferResize::Full {
+                capacity: UNIFORM_AUDIO_ENTRIES,
+                required: 2 * UNIFORM_AUDIO_ENTRIES,
+            }
+        );
+    }
+
+    #[test]
+    fn the_uniform_shader_declares_a_fixed_uniform_array() {
+        let storage = AudioBufferBinding::Storage.shader_source();
+        assert!(storage.contains(STORAGE_BINDING));
+        let uniform = AudioBufferBinding::Uniform.shader_source();
+        assert!(!uniform.contains(STORAGE_BINDING));
+        assert!(uniform.contains("var<uniform> audio"));
+        assert!(uniform.contains(&format!("array<vec4<f32>, {}>,", UNIFORM_AUDIO_ENTRIES)));
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::graphics::{
+    AccessibilityConfig, EnvelopeEditorConfig, HelpConfig, NoteNamesConfig, ThemeConfig,
+    VisualizerConfig, WindowTitleConfig,
+};
+
+/// The sections of the settings file the window and the visualizer read.
+///
+/// They sit in the same file as the synth's `Config` but are parsed on their own, so the synth's
+/// config reads the same whether or not it is built with visualization.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    pub note_names: NoteNamesConfig,
+    pub visualizer: VisualizerConfig,
+    pub window_title: WindowTitleConfig,
+    pub help: HelpConfig,
+    pub envelope_editor: EnvelopeEditorConfig,
+    pub theme: ThemeConfig,
+    pub accessibility: AccessibilityConfig,
+}
+
+impl DisplayConfig {
+    /// Reads the display sections of the settings file at `path`, ignoring the rest of it.
+    pub fn load(path: &Path) -> Result<DisplayConfig> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read the config file {}", path.display()))?;
+        serde_yaml::from_str(&contents)
+            .with_context(|| format!("Unable to parse the display settings in {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::WaveformLayout;
+
+    const SETTINGS: &str = r#"
+keybindings:
+  notes:
+    keys: {}
+visualizer:
+  layout: split
+  line_width: 4.0
+note_names:
+  visible: false
+"#;
+
+    #[test]
+    fn the_display_sections_are_read_and_the_rest_ignored() {
+        let display_config: DisplayConfig = serde_yaml::from_str(SETTINGS).unwrap();
+        assert_eq!(display_config.visualizer.layout, WaveformLayout::Split);
+        assert_eq!(display_config.visualizer.line_width, 4.0);
+        assert!(!display_config.note_names.visible);
+        // Sections left out take their defaults.
+        assert_eq!(display_config.help.size, HelpConfig::default().size);
+    }
+
+    #[test]
+    fn the_bundled_settings_parse() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/config/settings.yaml");
+        DisplayConfig::load(&path).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::graphics::{
+    text::{text_vertices, text_width},
+    ColorVertex,
+};
+
+/// Lowest and highest display gain the gain keys can reach.
+const MIN_GAIN: f32 = 1.0 / 16.0;
+const MAX_GAIN: f32 = 64.0;
+/// Distance of the auto-gain indicator from the window's top-right corner, in screen pixels.
+const INDICATOR_MARGIN: f32 = 8.0;
+
+/// How the waveform is scaled vertically. Only the drawing changes; the samples handed to the
+/// GPU, and the audio, stay as they are.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayScaleConfig {
+    /// Vertical gain of the drawn waveform.
+    pub gain: f32,
+    /// How far each press of the gain keys moves the gain, in dB.
+    pub gain_step_db: f32,
+    /// Whether auto-gain starts out on. It scales the display so the recent peak sits at
+    /// `auto_gain_target`, on top of `gain`.
+    pub auto_gain: bool,
+    /// Level, from 0.0 to 1.0, auto-gain brings the recent peak to.
+    pub auto_gain_target: f32,
+    /// Seconds for auto-gain to settle after the sound gets louder.
+    pub auto_gain_attack: f32,
+    /// Seconds for auto-gain to settle after the sound gets quieter.
+    pub auto_gain_release: f32,
+    /// Most auto-gain boosts a quiet passage by, in dB.
+    pub auto_gain_max_db: f32,
+    /// Frame peaks below this level, in dBFS, count as silence, which auto-gain holds steady
+    /// through instead of boosting the noise floor up to the target.
+    pub auto_gain_silence_db: f32,
+    /// Draw the amplitude on a dB scale, so quiet detail stays visible next to loud parts.
+    pub db_scale: bool,
+    /// Level, in dB, drawn on the center line when `db_scale` is on. Anything quieter is drawn
+    /// flat.
+    pub db_floor: f32,
+}
+
+impl Default for DisplayScaleConfig {
+    fn default() -> Self {
+        DisplayScaleConfig {
+            gain: 1.0,
+            gain_step_db: 3.0,
+            auto_gain: false,
+            auto_gain_target: 0.8,
+            auto_gain_attack: 0.05,
+            auto_gain_release: 1.5,
+            auto_gain_max_db: 30.0,
+            auto_gain_silence_db: -80.0,
+            db_scale: false,
+            db_floor: -60.0,
+        }
+    }
+}
+
+/// Maps a sample onto a dB scale: full scale stays at ±1.0, `floor_db` lands on 0.0, and the
+/// levels in between are spread evenly in dB. The sign is kept, so the curve is symmetric
+/// around zero and never decreasing.
+///
+/// The waveform shader's `display_level` does the same on the GPU.
+pub fn db_display_map(sample: f32, floor_db: f32) -> f32 {
+    // A floor at or above full scale would divide by zero or flip the curve.
+    let floor_db = floor_db.min(-1.0);
+    let level_db = 20.0 * sample.abs().max(f32::MIN_POSITIVE).log10();
+    ((level_db - floor_db) / -floor_db)
+        .max(0.0)
+        .copysign(sample)
+}
+
+/// Fraction of the way a one-pole smoother with `time_constant` moves towards its target in
+/// `dt` seconds. A time constant of zero or less jumps straight there.
+pub fn smoothing_coefficient(dt: f32, time_constant: f32) -> f32 {
+    if time_constant > 0.0 {
+        1.0 - (-dt.max(0.0) / time_constant).exp()
+    } else {
+        1.0
+    }
+}
+
+/// Follows the display's recent peak and works out the gain that brings it to a target level.
+///
+/// The gain comes down with the attack time constant when the sound gets louder and goes back
+/// up with the slower release one when it gets quieter.
+#[derive(Debug, Clone)]
+pub struct AutoGain {
+    gain: f32,
+    target: f32,
+    attack: f32,
+    release: f32,
+    max_gain: f32,
+    /// Peaks at or below this are silence.
+    silence: f32,
+}
+
+impl AutoGain {
+    pub fn new(config: &DisplayScaleConfig) -> Self {
+        AutoGain {
+            gain: 1.0,
+            target: config.auto_gain_target.clamp(0.01, 1.0),
+            attack: config.auto_gain_attack,
+            release: config.auto_gain_release,
+            max_gain: 10.0f32.powf(config.auto_gain_max_db.max(0.0) / 20.0),
+            silence: 10.0f32.powf(config.auto_gain_silence_db / 20.0),
+        }
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Moves the gain towards the one that puts `peak` at the target, over `dt` seconds.
+    pub fn update(&mut self, peak: f32, dt: f32) -> f32 {
+        if peak.is_finite() && peak > self.silence {
+            let wanted = (self.target / peak).min(self.max_gain);
+            let time_constant = if wanted < self.gain {
+                self.attack
+            } else {
+                self.release
+            };
+            self.gain += (wanted - self.gain) * smoothing_coefficient(dt, time_constant);
+        }
+        self.gain
+    }
+}
+
+/// The display scaling as it is right now, changed by the display keys.
+#[derive(Debug, Clone)]
+pub struct DisplayScale {
+    config: DisplayScaleConfig,
+    gain: f32,
+    auto_gain_enabled: bool,
+    auto_gain: AutoGain,
+}
+
+impl Default for DisplayScale {
+    fn default() -> Self {
+        DisplayScale::new(DisplayScaleConfig::default())
+    }
+}
+
+impl DisplayScale {
+    pub fn new(config: DisplayScaleConfig) -> Self {
+        DisplayScale {
+            gain: config.gain.clamp(MIN_GAIN, MAX_GAIN),
+            auto_gain_enabled: config.auto_gain,
+            auto_gain: AutoGain::new(&config),
+            config,
+        }
+    }
+
+    pub fn gain_up(&mut self) {
+        self.step_gain(self.config.gain_step_db);
+    }
+
+    pub fn gain_down(&mut self) {
+        self.step_gain(-self.config.gain_step_db);
+    }
+
+    fn step_gain(&mut self, db: f32) {
+        self.gain = (self.gain * 10.0f32.powf(db / 20.0)).clamp(MIN_GAIN, MAX_GAIN);
+        info!("Display gain {:+.1} dB", 20.0 * self.gain.log10());
+    }
+
+    pub fn toggle_auto_gain(&mut self) {
+        self.auto_gain_enabled = !self.auto_gain_enabled;
+        info!(
+            "Display auto-gain {}",
+            if self.auto_gain_enabled { "on" } else { "off" }
+        );
+    }
+
+    pub fn auto_gain_enabled(&self) -> bool {
+        self.auto_gain_enabled
+    }
+
+    /// The dB scale's floor, or `None` for a linear display.
+    pub fn db_floor(&self) -> Option<f32> {
+        self.config.db_scale.then_some(self.config.db_floor)
+    }
+
+    /// Gain to draw the next frame with, given the frame's peak and the seconds since the last
+    /// one.
+    pub fn update(&mut self, peak: f32, dt: f32) -> f32 {
+        if self.auto_gain_enabled {
+            self.gain * self.auto_gain.update(peak, dt)
+        } else {
+            self.gain
+        }
+    }
+}
+
+/// Builds the "AUTO" label shown in the window's top-right corner while auto-gain is on.
+pub fn auto_gain_indicator_vertices(
+    width: f32,
+    height: f32,
+    scale: f32,
+    color: [f32; 4],
+) -> Vec<ColorVertex> {
+    let text = "AUTO";
+    let x = width - INDICATOR_MARGIN - text_width(text, scale);
+    if x < 0.0 || height <= 0.0 {
+        return Vec::new();
+    }
+    text_vertices(text, x, INDICATOR_MARGIN, scale, color, width, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_db_map_rises_with_the_level_and_keeps_the_sign() {
+        let floor = -60.0;
+        let samples: Vec<f32> = (-2000..=2000).map(|i| i as f32 / 2000.0).collect();
+        let mapped: Vec<f32> = samples.iter().map(|s| db_display_map(*s, floor)).collect();
+        assert!(mapped.windows(2).all(|pair| pair[1] >= pair[0]));
+        for sample in samples {
+            assert_eq!(
+                db_display_map(-sample, floor),
+                -db_display_map(sample, floor)
+            );
+        }
+        assert_eq!(db_display_map(1.0, floor), 1.0);
+        assert_eq!(db_display_map(-1.0, floor), -1.0);
+        // Halfway down in dB is halfway down the display.
+        assert!((db_display_map(10.0f32.powf(-30.0 / 20.0), floor) - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn levels_at_or_below_the_floor_are_drawn_flat() {
+        let floor = -40.0;
+        let at_floor = 10.0f32.powf(floor / 20.0);
+        assert!(db_display_map(at_floor, floor).abs() < 1e-5);
+        assert_eq!(db_display_map(at_floor / 10.0, floor), 0.0);
+        assert_eq!(db_display_map(0.0, floor), 0.0);
+        assert!(db_display_map(at_floor * 2.0, floor) > 0.0);
+        // A floor at or above full scale is pulled down to -1 dB rather than flipping the curve.
+        assert_eq!(db_display_map(1.0, 0.0), 1.0);
+        assert_eq!(db_display_map(0.5, 6.0), db_display_map(0.5, -1.0));
+    }
+
+    #[test]
+    fn the_smoother_covers_most_of_the_way_in_one_time_constant() {
+        let one = smoothing_coefficient(0.05, 0.05);
+        assert!((one - (1.0 - (-1.0f32).exp())).abs() < 1e-6);
+        assert_eq!(smoothing_coefficient(0.01, 0.0), 1.0);
+        assert_eq!(smoothing_coefficient(-1.0, 0.05), 0.0);
+    }
+
+    /// Runs auto-gain on a steady `peak` for `seconds` in 60 Hz frames.
+    fn settle(auto_gain: &mut AutoGain, peak: f32, seconds: f32) -> f32 {
+        let dt = 1.0 / 60.0;
+        for _ in 0..(seconds / dt).round() as usize {
+            auto_gain.update(peak, dt);
+        }
+        auto_gain.gain()
+    }
+
+    #[test]
+    fn auto_gain_comes_down_at_the_attack_and_up_at_the_release() {
+        let config = DisplayScaleConfig::default();
+        let mut auto_gain = AutoGain::new(&config);
+        let target = config.auto_gain_target;
+
+        // Louder: from unity towards 0.8 / 1.6 = 0.5, 63% of the way in one attack time.
+        let gain = settle(&mut auto_gain, 1.6, config.auto_gain_attack);
+        let progress = (1.0 - gain) / (1.0 - target / 1.6);
+        assert!((progress - 0.632).abs() < 0.01, "{}", progress);
+        let gain = settle(&mut auto_gain, 1.6, 10.0 * config.auto_gain_attack);
+        assert!((gain - 0.5).abs() < 1e-3);
+
+        // Quieter: back up towards 0.8 / 0.4 = 2.0 over the much slower release.
+        let gain = settle(&mut auto_gain, 0.4, config.auto_gain_release);
+        let progress = (gain - 0.5) / (2.0 - 0.5);
+        assert!((progress - 0.632).abs() < 0.01, "{}", progress);
+    }
+
+    #[test]
+    fn auto_gain_is_capped_and_holds_through_silence() {
+        let config = DisplayScaleConfig::default();
+        let mut auto_gain = AutoGain::new(&config);
+        let max_gain = 10.0f32.powf(config.auto_gain_max_db / 20.0);
+        let gain = settle(&mut auto_gain, 1e-3, 20.0);
+        assert!((gain - max_gain).abs() / max_gain < 1e-3);
+
+        let mut auto_gain = AutoGain::new(&config);
+        settle(&mut auto_gain, 1.6, 1.0);
+        let before = auto_gain.gain();
+        assert_eq!(settle(&mut auto_gain, 0.0, 5.0), before);
+        assert_eq!(settle(&mut auto_gain, f32::NAN, 1.0), before);
+    }
+
+    #[test]
+    fn auto_gain_follows_a_loud_quiet_and_silent_sequence() {
+        let config = DisplayScaleConfig::default();
+        let mut auto_gain = AutoGain::new(&config);
+
+        let gain = settle(&mut auto_gain, 0.8, 2.0);
+        assert!((gain - 1.0).abs() < 1e-3, "{}", gain);
+        // 20 dB quieter is brought up 20 dB, within the 30 dB limit.
+        let gain = settle(&mut auto_gain, 0.08, 20.0);
+        assert!((gain - 10.0).abs() < 1e-2, "{}", gain);
+        // Silence below -80 dBFS leaves the gain where the quiet passage put it.
+        assert_eq!(settle(&mut auto_gain, 1e-5, 5.0), gain);
+        // Loud again comes back down at the attack, within half a second.
+        let gain = settle(&mut auto_gain, 0.8, 0.5);
+        assert!((gain - 1.0).abs() < 1e-3, "{}", gain);
+    }
+
+    #[test]
+    fn the_silence_threshold_is_taken_from_the_config() {
+        let silence = |auto_gain_silence_db: f32| {
+            AutoGain::new(&DisplayScaleConfig {
+                auto_gain_silence_db,
+                ..DisplayScaleConfig::default()
+            })
+        };
+        let max_gain = 10.0f32.powf(DisplayScaleConfig::default().auto_gain_max_db / 20.0);
+
+        // With the floor far down, the same 1e-5 counts as sound and is boosted to the limit.
+        let gain = settle(&mut silence(-120.0), 1e-5, 20.0);
+        assert!((gain - max_gain).abs() / max_gain < 1e-3, "{}", gain);
+        // With it at -20 dB, peaks of 0.05 are silence and leave the gain at unity.
+        assert_eq!(settle(&mut silence(-20.0), 0.05, 5.0), 1.0);
+        // A peak right on the threshold is still silence.
+        assert_eq!(settle(&mut silence(-40.0), 0.01, 5.0), 1.0);
+        assert!(settle(&mut silence(-40.0), 0.011, 5.0) > 1.0);
+    }
+
+    #[test]
+    fn auto_gain_multiplies_the_gain_set_by_the_keys() {
+        let mut scale = DisplayScale::new(DisplayScaleConfig {
+            auto_gain: true,
+            ..DisplayScaleConfig::default()
+        });
+        scale.gain_up();
+        let key_gain = 10.0f32.powf(3.0 / 20.0);
+        let mut gain = 0.0;
+        for _ in 0..600 {
+            gain = scale.update(1.6, 1.0 / 60.0);
+        }
+        assert!((gain - key_gain * 0.5).abs() < 1e-3, "{}", gain);
+
+        scale.toggle_auto_gain();
+        assert!(!scale.auto_gain_enabled());
+        assert_eq!(scale.update(1.6, 1.0 / 60.0), key_gain);
+    }
+
+    #[test]
+    fn display_gain_steps_in_db_within_its_range() {
+        let mut scale = DisplayScale::default();
+        scale.gain_up();
+        assert!((scale.update(1.0, 0.0) - 10.0f32.powf(3.0 / 20.0)).abs() < 1e-6);
+        for _ in 0..100 {
+            scale.gain_up();
+        }
+        assert_eq!(scale.update(1.0, 0.0), MAX_GAIN);
+        for _ in 0..200 {
+            scale.gain_down();
+        }
+        assert_eq!(scale.update(1.0, 0.0), MIN_GAIN);
+        assert_eq!(scale.db_floor(), None);
+    }
+}
@@ -0,0 +1,608 @@
+use serde::{Deserialize, Serialize};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+
+use crate::graphics::ColorVertex;
+use crate::synth::{EnvelopeShape, ParamId};
+
+/// How close to a breakpoint, in screen pixels, a click has to land to grab it.
+pub const HIT_RADIUS: f32 = 10.0;
+
+/// Half the size of a breakpoint's square, in screen pixels.
+const HANDLE_HALF_SIZE: f32 = 4.0;
+
+/// Half the width of the curve, in screen pixels.
+const CURVE_HALF_WIDTH: f32 = 1.0;
+
+/// How much a drag moves the envelope with Shift held, relative to without.
+const FINE_SCALE: f32 = 0.1;
+
+/// Vertices needed to draw the widget: its backdrop, the four segments of the curve and the
+/// four breakpoints, each a quad.
+pub const ENVELOPE_MAX_VERTICES: usize = 6 * 9;
+
+/// A corner of the window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+    TopLeft,
+    #[default]
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Settings for the envelope editor, a small picture of the lead envelope whose breakpoints
+/// can be dragged with the mouse.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnvelopeEditorConfig {
+    pub enabled: bool,
+    pub corner: Corner,
+    /// Size in screen pixels, shrunk to fit small windows.
+    pub width: f32,
+    pub height: f32,
+    /// Distance from the window's edges, in screen pixels.
+    pub margin: f32,
+    /// Colors as RGBA.
+    pub background: [f32; 4],
+    pub curve_color: [f32; 4],
+    pub handle_color: [f32; 4],
+    /// Color of the breakpoint being dragged.
+    pub grabbed_color: [f32; 4],
+}
+
+impl Default for EnvelopeEditorConfig {
+    fn default() -> Self {
+        EnvelopeEditorConfig {
+            enabled: false,
+            corner: Corner::TopRight,
+            width: 240.0,
+            height: 100.0,
+            margin: 8.0,
+            background: [0.0, 0.0, 0.0, 0.5],
+            curve_color: [1.0, 1.0, 1.0, 0.8],
+            handle_color: [1.0, 0.8, 0.3, 0.9],
+            grabbed_color: [0.3, 1.0, 0.4, 1.0],
+        }
+    }
+}
+
+/// A breakpoint of the drawn envelope, and the setting dragging it changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeHandle {
+    /// The peak; sideways sets the attack time.
+    Attack,
+    /// Where the decay reaches the sustain level; sideways sets the decay time.
+    Decay,
+    /// The end of the sustain; up and down sets the sustain level.
+    Sustain,
+    /// The end of the release; sideways sets the release time.
+    Release,
+}
+
+impl EnvelopeHandle {
+    pub const ALL: [EnvelopeHandle; 4] = [
+        EnvelopeHandle::Attack,
+        EnvelopeHandle::Decay,
+        EnvelopeHandle::Sustain,
+        EnvelopeHandle::Release,
+    ];
+
+    /// The parameter the handle sets, whose range the drag is clamped to.
+    pub fn param(self) -> ParamId {
+        match self {
+            EnvelopeHandle::Attack => ParamId::Attack,
+            EnvelopeHandle::Decay => ParamId::Decay,
+            EnvelopeHandle::Sustain => ParamId::Sustain,
+            EnvelopeHandle::Release => ParamId::Release,
+        }
+    }
+
+    fn value(self, envelope: &EnvelopeShape) -> f32 {
+        match self {
+            EnvelopeHandle::Attack => envelope.attack,
+            EnvelopeHandle::Decay => envelope.decay,
+            EnvelopeHandle::Sustain => envelope.sustain,
+            EnvelopeHandle::Release => envelope.release,
+        }
+    }
+
+    fn set_value(self, envelope: &mut EnvelopeShape, value: f32) {
+        match self {
+            EnvelopeHandle::Attack => envelope.attack = value,
+            EnvelopeHandle::Decay => envelope.decay = value,
+            EnvelopeHandle::Sustain => envelope.sustain = value,
+            EnvelopeHandle::Release => envelope.release = value,
+        }
+    }
+}
+
+/// The widget's rectangle in screen pixels, from the window's top-left corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WidgetRect {
+    pub left: f32,
+    pub top: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl WidgetRect {
+    /// Where a widget of the configured size sits in a window of `size`.
+    pub fn place(config: &EnvelopeEditorConfig, size: PhysicalSize<u32>) -> Self {
+        let margin = config.margin.max(0.0);
+        let width = config.width.min(size.width as f32 - 2.0 * margin).max(0.0);
+        let height = config
+            .height
+            .min(size.height as f32 - 2.0 * margin)
+            .max(0.0);
+        let left = match config.corner {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            Corner::TopRight | Corner::BottomRight => size.width as f32 - margin - width,
+        };
+        let top = match config.corner {
+            Corner::TopLeft | Corner::TopRight => margin,
+            Corner::BottomLeft | Corner::BottomRight => size.height as f32 - margin - height,
+        };
+        WidgetRect {
+            left,
+            top,
+            width,
+            height,
+        }
+    }
+
+    /// The area the curve is drawn in, inset so breakpoints at the edges can still be grabbed.
+    fn plot(&self) -> WidgetRect {
+        let inset = HIT_RADIUS.min(self.width / 4.0).min(self.height / 4.0);
+        WidgetRect {
+            left: self.left + inset,
+            top: self.top + inset,
+            width: self.width - 2.0 * inset,
+            height: self.height - 2.0 * inset,
+        }
+    }
+
+    /// Width given to each of the attack, decay, sustain and release.
+    fn column_width(&self) -> f32 {
+        self.plot().width / 4.0
+    }
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.left
+            && x <= self.left + self.width
+            && y >= self.top
+            && y <= self.top + self.height
+    }
+}
+
+/// Where `time` falls in a time parameter's range, from 0.0 to 1.0, on a log scale so short
+/// times get as much room as long ones.
+pub fn time_fraction(param: ParamId, time: f32) -> f32 {
+    let (min, max) = param.range();
+    ((time.max(min) / min).ln() / (max / min).ln()).clamp(0.0, 1.0)
+}
+
+/// The value of `handle`'s parameter after dragging it `delta` screen pixels from `value`:
+/// sideways for times, which move on a log scale so a column's width covers their whole
+/// range, and up for the sustain level, which covers its range over the plot's height.
+/// `fine` moves it a tenth as far. The result is clamped to the parameter's range.
+pub fn drag_value(
+    handle: EnvelopeHandle,
+    value: f32,
+    delta: PhysicalPosition<f32>,
+    rect: &WidgetRect,
+    fine: bool,
+) -> f32 {
+    let param = handle.param();
+    let (min, max) = param.range();
+    let scale = if fine { FINE_SCALE } else { 1.0 };
+    let value = match handle {
+        EnvelopeHandle::Sustain => {
+            let height = rect.plot().height;
+            if height <= 0.0 {
+                return value.clamp(min, max);
+            }
+            value - delta.y / height * scale
+        }
+        _ => {
+            let width = rect.column_width();
+            if width <= 0.0 {
+                return value.clamp(min, max);
+            }
+            value.max(min) * ((max / min).ln() * delta.x / width * scale).exp()
+        }
+    };
+    value.clamp(min, max)
+}
+
+/// The envelope editor: where its breakpoints are drawn, and which one is held.
+#[derive(Debug, Clone)]
+pub struct EnvelopeWidget {
+    config: EnvelopeEditorConfig,
+    envelope: EnvelopeShape,
+    /// The breakpoint being dragged and where the cursor was last.
+    grab: Option<(EnvelopeHandle, PhysicalPosition<f64>)>,
+}
+
+impl EnvelopeWidget {
+    pub fn new(config: EnvelopeEditorConfig, envelope: EnvelopeShape) -> Self {
+        EnvelopeWidget {
+            config,
+            envelope,
+            grab: None,
+        }
+    }
+
+    pub fn envelope(&self) -> EnvelopeShape {
+        self.envelope
+    }
+
+    /// The breakpoint being dragged, if any.
+    pub fn grabbed(&self) -> Option<EnvelopeHandle> {
+        self.grab.map(|(handle, _)| handle)
+    }
+
+    pub fn rect(&self, size: PhysicalSize<u32>) -> WidgetRect {
+        WidgetRect::place(&self.config, size)
+    }
+
+    /// Where each breakpoint is drawn in a window of `size`, in screen pixels: the start, then
+    /// the attack, decay, sustain and release breakpoints.
+    pub fn points(&self, size: PhysicalSize<u32>) -> [PhysicalPosition<f32>; 5] {
+        let plot = self.rect(size).plot();
+        let column = plot.width / 4.0;
+        let bottom = plot.top + plot.height;
+        let sustain_y = bottom - self.envelope.sustain.clamp(0.0, 1.0) * plot.height;
+        let attack_x = plot.left + column * time_fraction(ParamId::Attack, self.envelope.attack);
+        let decay_x = attack_x + column * time_fraction(ParamId::Decay, self.envelope.decay);
+        let sustain_x = decay_x + column;
+        let release_x = sustain_x + column * time_fraction(ParamId::Release, self.envelope.release);
+        [
+            PhysicalPosition::new(plot.left, bottom),
+            PhysicalPosition::new(attack_x, plot.top),
+            PhysicalPosition::new(decay_x, sustain_y),
+            PhysicalPosition::new(sustain_x, sustain_y),
+            PhysicalPosition::new(release_x, bottom),
+        ]
+    }
+
+    /// The breakpoint within `HIT_RADIUS` of `position` in a window of `size`, the nearest if
+    /// several are.
+    pub fn hit_test(
+        &self,
+        position: PhysicalPosition<f64>,
+        size: PhysicalSize<u32>,
+    ) -> Option<EnvelopeHandle> {
+        let (x, y) = (position.x as f32, position.y as f32);
+        if !self.config.enabled || !self.rect(size).contains(x, y) {
+            return None;
+        }
+        let points = self.points(size);
+        EnvelopeHandle::ALL
+            .into_iter()
+            .zip(&points[1..])
+            .map(|(handle, point)| (handle, (point.x - x).hypot(point.y - y)))
+            .filter(|(_, distance)| *distance <= HIT_RADIUS)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(handle, _)| handle)
+    }
+
+    /// Grabs the breakpoint under `position`, if there is one. Returns whether one was grabbed.
+    pub fn grab(&mut self, position: PhysicalPosition<f64>, size: PhysicalSize<u32>) -> bool {
+        self.grab = self
+            .hit_test(position, size)
+            .map(|handle| (handle, position));
+        self.grab.is_some()
+    }
+
+    /// Moves the grabbed breakpoint with the cursor, now at `position`, a tenth as far when
+    /// `fine`. Returns whether the envelope changed.
+    pub fn drag(
+        &mut self,
+        position: PhysicalPosition<f64>,
+        size: PhysicalSize<u32>,
+        fine: bool,
+    ) -> bool {
+        let Some((handle, last)) = self.grab else {
+            return false;
+        };
+        let delta =
+            PhysicalPosition::new((position.x - last.x) as f32, (position.y - last.y) as f32);
+        let value = handle.value(&self.envelope);
+        let dragged = drag_value(handle, value, delta, &self.rect(size), fine);
+        self.grab = Some((handle, position));
+        handle.set_value(&mut self.envelope, dragged);
+        dragged != value
+    }
+
+    /// Moves the breakpoint `param` sets to `value`, as when an undo sets it. Returns whether
+    /// `param` is one of the envelope's and the value changed.
+    pub fn set_param(&mut self, param: ParamId, value: f32) -> bool {
+        let Some(handle) = EnvelopeHandle::ALL
+            .into_iter()
+            .find(|handle| handle.param() == param)
+        else {
+            return false;
+        };
+        let changed = handle.value(&self.envelope) != value;
+        handle.set_value(&mut self.envelope, value);
+        changed
+    }
+
+    /// Lets go of the grabbed breakpoint, returning which it was.
+    pub fn release(&mut self) -> Option<EnvelopeHandle> {
+        self.grab.take().map(|(handle, _)| handle)
+    }
+
+    /// Builds the backdrop, the curve and the breakpoints for a window of `size`.
+    pub fn vertices(&self, size: PhysicalSize<u32>) -> Vec<ColorVertex> {
+        if !self.config.enabled || size.width == 0 || size.height == 0 {
+            return Vec::new();
+        }
+        let rect = self.rect(size);
+        if rect.width <= 0.0 || rect.height <= 0.0 {
+            return Vec::new();
+        }
+        let to_ndc = |x: f32, y: f32| {
+            [
+                2.0 * x / size.width as f32 - 1.0,
+                1.0 - 2.0 * y / size.height as f32,
+            ]
+        };
+        let [left, top] = to_ndc(rect.left, rect.top);
+        let [right, bottom] = to_ndc(rect.left + rect.width, rect.top + rect.height);
+        let mut vertices = ColorVertex::quad(left, bottom, right, top, self.config.background);
+
+        let points = self.points(size);
+        for pair in points.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            let (dx, dy) = (to.x - from.x, to.y - from.y);
+            let length = dx.hypot(dy);
+            if length <= f32::EPSILON {
+                continue;
+            }
+            // The segment is widened across its own direction, so slopes keep their width.
+            let (nx, ny) = (
+                -dy / length * CURVE_HALF_WIDTH,
+                dx / length * CURVE_HALF_WIDTH,
+            );
+            let corners = [
+                to_ndc(from.x + nx, from.y + ny),
+                to_ndc(to.x + nx, to.y + ny),
+                to_ndc(to.x - nx, to.y - ny),
+                to_ndc(from.x - nx, from.y - ny),
+            ];
+            vertices.extend([0, 1, 2, 0, 2, 3].into_iter().map(|corner| ColorVertex {
+                position: corners[corner],
+                color: self.config.curve_color,
+            }));
+        }
+
+        for (handle, point) in EnvelopeHandle::ALL.into_iter().zip(&points[1..]) {
+            let color = if self.grabbed() == Some(handle) {
+                self.config.grabbed_color
+            } else {
+                self.config.handle_color
+            };
+            let [left, top] = to_ndc(point.x - HANDLE_HALF_SIZE, point.y - HANDLE_HALF_SIZE);
+            let [right, bottom] = to_ndc(point.x + HANDLE_HALF_SIZE, point.y + HANDLE_HALF_SIZE);
+            vertices.extend(ColorVertex::quad(left, bottom, right, top, color));
+        }
+        vertices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [PhysicalSize<u32>; 3] = [
+        PhysicalSize::new(800, 600),
+        PhysicalSize::new(1920, 1080),
+        PhysicalSize::new(200, 90),
+    ];
+
+    const CORNERS: [Corner; 4] = [
+        Corner::TopLeft,
+        Corner::TopRight,
+        Corner::BottomLeft,
+        Corner::BottomRight,
+    ];
+
+    fn widget(corner: Corner) -> EnvelopeWidget {
+        let config = EnvelopeEditorConfig {
+            enabled: true,
+            corner,
+            ..EnvelopeEditorConfig::default()
+        };
+        let envelope = EnvelopeShape {
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 0.6,
+            release: 0.5,
+        };
+        EnvelopeWidget::new(config, envelope)
+    }
+
+    fn at(point: PhysicalPosition<f32>, dx: f32, dy: f32) -> PhysicalPosition<f64> {
+        PhysicalPosition::new((point.x + dx) as f64, (point.y + dy) as f64)
+    }
+
+    #[test]
+    fn the_widget_sits_in_its_corner_and_shrinks_to_fit_small_windows() {
+        let config = EnvelopeEditorConfig::default();
+        for corner in CORNERS {
+            let config = EnvelopeEditorConfig {
+                corner,
+                ..config.clone()
+            };
+            let rect = WidgetRect::place(&config, PhysicalSize::new(800, 600));
+            assert_eq!((rect.width, rect.height), (240.0, 100.0));
+            let from_left = matches!(corner, Corner::TopLeft | Corner::BottomLeft);
+            let from_top = matches!(corner, Corner::TopLeft | Corner::TopRight);
+            assert_eq!(rect.left, if from_left { 8.0 } else { 800.0 - 8.0 - 240.0 });
+            assert_eq!(rect.top, if from_top { 8.0 } else { 600.0 - 8.0 - 100.0 });
+        }
+        let rect = WidgetRect::place(&config, PhysicalSize::new(200, 90));
+        assert_eq!((rect.width, rect.height), (184.0, 74.0));
+        let rect = WidgetRect::place(&config, PhysicalSize::new(10, 10));
+        assert_eq!((rect.width, rect.height), (0.0, 0.0));
+    }
+
+    #[test]
+    fn every_breakpoint_is_hit_where_it_is_drawn_in_every_corner_and_size() {
+        for corner in CORNERS {
+            let widget = widget(corner);
+            for size in SIZES {
+                let points = widget.points(size);
+                for (handle, point) in EnvelopeHandle::ALL.into_iter().zip(&points[1..]) {
+                    assert_eq!(widget.hit_test(at(*point, 0.0, 0.0), size), Some(handle));
+                    // Off to the side of it, but within reach.
+                    assert_eq!(widget.hit_test(at(*point, 0.0, 7.0), size), Some(handle));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clicks_away_from_the_breakpoints_or_outside_the_widget_grab_nothing() {
+        let size = PhysicalSize::new(800, 600);
+        let widget = widget(Corner::TopRight);
+        let rect = widget.rect(size);
+        let release = widget.points(size)[4];
+        // Inside the widget but out of reach of every breakpoint.
+        let empty = PhysicalPosition::new(
+            (rect.left + rect.width - 2.0) as f64,
+            (rect.top + 2.0) as f64,
+        );
+        assert_eq!(widget.hit_test(empty, size), None);
+        assert_eq!(
+            widget.hit_test(at(release, HIT_RADIUS + 1.0, 0.0), size),
+            None
+        );
+        // The rest of the window belongs to the visualizer.
+        assert_eq!(
+            widget.hit_test(PhysicalPosition::new(10.0, 300.0), size),
+            None
+        );
+
+        let mut disabled = widget.clone();
+        disabled.config.enabled = false;
+        assert_eq!(disabled.hit_test(at(release, 0.0, 0.0), size), None);
+        assert!(disabled.vertices(size).is_empty());
+    }
+
+    #[test]
+    fn times_are_placed_on_a_log_scale_across_their_range() {
+        let (min, max) = ParamId::Attack.range();
+        assert_eq!(time_fraction(ParamId::Attack, min), 0.0);
+        assert!((time_fraction(ParamId::Attack, max) - 1.0).abs() < 1e-6);
+        assert!((time_fraction(ParamId::Attack, (min * max).sqrt()) - 0.5).abs() < 1e-6);
+        assert!((time_fraction(ParamId::Attack, 0.01) - 0.25).abs() < 1e-6);
+        assert_eq!(time_fraction(ParamId::Attack, 0.0), 0.0);
+        assert_eq!(time_fraction(ParamId::Attack, 100.0), 1.0);
+    }
+
+    #[test]
+    fn a_column_of_drag_covers_a_time_range_whatever_the_widget_size() {
+        for size in SIZES {
+            let rect = widget(Corner::TopLeft).rect(size);
+            let column = rect.column_width();
+            let (min, max) = ParamId::Decay.range();
+            let across = PhysicalPosition::new(column, 0.0);
+            let dragged = drag_value(EnvelopeHandle::Decay, min, across, &rect, false);
+            assert!((dragged - max).abs() < max * 1e-4, "{:?} {}", size, dragged);
+
+            // A quarter column is a quarter of the range's decades, a tenth of that with Shift.
+            let quarter = PhysicalPosition::new(column / 4.0, 0.0);
+            let dragged = drag_value(EnvelopeHandle::Decay, 0.01, quarter, &rect, false);
+            assert!((dragged - 0.1).abs() < 1e-5, "{:?} {}", size, dragged);
+            let fine = drag_value(EnvelopeHandle::Decay, 0.01, quarter, &rect, true);
+            assert!(
+                (fine - 0.01 * 10f32.powf(0.1)).abs() < 1e-6,
+                "{:?} {}",
+                size,
+                fine
+            );
+            let back = PhysicalPosition::new(-column / 4.0, 0.0);
+            let dragged = drag_value(EnvelopeHandle::Decay, 0.1, back, &rect, false);
+            assert!((dragged - 0.01).abs() < 1e-6, "{:?} {}", size, dragged);
+
+            // The sustain level moves with the cursor up and down over the plot's height.
+            let height = rect.plot().height;
+            let up = PhysicalPosition::new(0.0, -height / 4.0);
+            let dragged = drag_value(EnvelopeHandle::Sustain, 0.5, up, &rect, false);
+            assert!((dragged - 0.75).abs() < 1e-6, "{:?} {}", size, dragged);
+            let fine = drag_value(EnvelopeHandle::Sustain, 0.5, up, &rect, true);
+            assert!((fine - 0.525).abs() < 1e-6, "{:?} {}", size, fine);
+        }
+    }
+
+    #[test]
+    fn drags_clamp_at_the_ends_of_each_range() {
+        let rect = widget(Corner::TopLeft).rect(PhysicalSize::new(800, 600));
+        let far = |x: f32, y: f32| PhysicalPosition::new(x, y);
+        for handle in [
+            EnvelopeHandle::Attack,
+            EnvelopeHandle::Decay,
+            EnvelopeHandle::Release,
+        ] {
+            let (min, max) = handle.param().range();
+            assert_eq!(
+                drag_value(handle, 1.0, far(10_000.0, 0.0), &rect, false),
+                max
+            );
+            assert_eq!(
+                drag_value(handle, 1.0, far(-10_000.0, 0.0), &rect, false),
+                min
+            );
+            // Up and down doesn't move a time.
+            assert_eq!(drag_value(handle, 1.0, far(0.0, 500.0), &rect, false), 1.0);
+        }
+        let sustain = EnvelopeHandle::Sustain;
+        assert_eq!(
+            drag_value(sustain, 0.5, far(0.0, -1_000.0), &rect, false),
+            1.0
+        );
+        assert_eq!(
+            drag_value(sustain, 0.5, far(0.0, 1_000.0), &rect, false),
+            0.0
+        );
+        assert_eq!(drag_value(sustain, 0.5, far(300.0, 0.0), &rect, false), 0.5);
+        // A widget squeezed to nothing still keeps the value in range.
+        let squeezed =
+            WidgetRect::place(&EnvelopeEditorConfig::default(), PhysicalSize::new(10, 10));
+        assert_eq!(
+            drag_value(sustain, 2.0, far(0.0, -5.0), &squeezed, false),
+            1.0
+        );
+        assert_eq!(
+            drag_value(EnvelopeHandle::Attack, 0.0, far(5.0, 0.0), &squeezed, false),
+            0.001
+        );
+    }
+
+    #[test]
+    fn a_dragged_breakpoint_follows_the_cursor() {
+        let size = PhysicalSize::new(1920, 1080);
+        for corner in CORNERS {
+            let mut widget = widget(corner);
+            let attack = widget.points(size)[1];
+            assert!(widget.grab(at(attack, 0.0, 0.0), size));
+            assert_eq!(widget.grabbed(), Some(EnvelopeHandle::Attack));
+            assert!(widget.drag(at(attack, 12.0, 3.0), size, false));
+            assert!((widget.points(size)[1].x - (attack.x + 12.0)).abs() < 1e-3);
+            // With Shift, a tenth as far.
+            assert!(widget.drag(at(attack, 22.0, 3.0), size, true));
+            assert!((widget.points(size)[1].x - (attack.x + 13.0)).abs() < 1e-3);
+            assert_eq!(widget.release(), Some(EnvelopeHandle::Attack));
+            assert!(!widget.drag(at(attack, 40.0, 0.0), size, false));
+
+            let sustain = widget.points(size)[3];
+            assert!(widget.grab(at(sustain, 0.0, 0.0), size));
+            assert!(widget.drag(at(sustain, 0.0, -10.0), size, false));
+            assert!((widget.points(size)[3].y - (sustain.y - 10.0)).abs() < 1e-3);
+            assert_eq!(widget.release(), Some(EnvelopeHandle::Sustain));
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use winit::window::Window;
+
+use crate::graphics::VisualizerConfig;
+use crate::synth::DEFAULT_VISUAL_FPS;
+
+/// Converts a monitor refresh rate in millihertz, as winit reports it, to frames per second.
+pub fn refresh_rate_fps(refresh_rate_millihertz: Option<u32>) -> Option<f32> {
+    refresh_rate_millihertz
+        .filter(|millihertz| *millihertz > 0)
+        .map(|millihertz| millihertz as f32 / 1000.0)
+}
+
+/// The rate the visualizer should draw at in `window`: the configured `visual_fps` if there is
+/// one, otherwise the refresh rate of the monitor the window is on.
+pub fn visual_fps(window: &Window, config: &VisualizerConfig) -> f32 {
+    config
+        .visual_fps
+        .filter(|fps| fps.is_finite() && *fps > 0.0)
+        .or_else(|| {
+            refresh_rate_fps(
+                window
+                    .current_monitor()
+                    .and_then(|monitor| monitor.refresh_rate_millihertz()),
+            )
+        })
+        .unwrap_or(DEFAULT_VISUAL_FPS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_rates_convert_from_millihertz() {
+        assert_eq!(refresh_rate_fps(Some(60_000)), Some(60.0));
+        assert_eq!(refresh_rate_fps(Some(143_999)), Some(143.999));
+        assert_eq!(refresh_rate_fps(Some(0)), None);
+        assert_eq!(refresh_rate_fps(None), None);
+    }
+}
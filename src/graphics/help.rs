@@ -0,0 +1,461 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use winit::keyboard::Key;
+
+use crate::graphics::{
+    text::{text_vertices, GLYPH_ADVANCE, GLYPH_HEIGHT},
+    ColorVertex,
+};
+use crate::synth::{key_label, CycleDirection, KeyId, NoteEvent, ResolvedBindings};
+
+/// Distance of the list from the window's edges, in screen pixels.
+const MARGIN: f32 = 8.0;
+
+/// Blank font pixels between lines.
+const LINE_GAP: usize = 3;
+
+/// Blank characters between columns.
+const COLUMN_GAP: usize = 3;
+
+/// Upper bound on the help screen's vertices, sizing its vertex buffer. Whole quads past it are
+/// dropped.
+pub const HELP_MAX_VERTICES: usize = 6 * 32768;
+
+/// How the help screen is drawn.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HelpConfig {
+    /// Size of one font pixel in screen pixels.
+    pub size: f32,
+    /// Color of the bindings as RGB.
+    pub color: [f32; 3],
+    /// Color of the category headings as RGB.
+    pub heading_color: [f32; 3],
+    /// Color of a binding whose key is held down, as RGB.
+    pub highlight_color: [f32; 3],
+    /// Backdrop drawn over the waveform behind the list, as RGBA.
+    pub background: [f32; 4],
+}
+
+impl Default for HelpConfig {
+    fn default() -> Self {
+        HelpConfig {
+            size: 2.0,
+            color: [0.85, 0.85, 0.85],
+            heading_color: [1.0, 0.8, 0.3],
+            highlight_color: [0.3, 1.0, 0.4],
+            background: [0.0, 0.0, 0.0, 0.75],
+        }
+    }
+}
+
+/// The groups bindings are listed under, in the order they are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HelpCategory {
+    Notes,
+    Octave,
+    Waveform,
+    Tremolo,
+    Looper,
+    Display,
+    Actions,
+}
+
+impl HelpCategory {
+    /// Which group `event` is listed under, or `None` for events keys aren't bound to.
+    pub fn of(event: &NoteEvent) -> Option<Self> {
+        let category = match event {
+            NoteEvent::On(_) => HelpCategory::Notes,
+            NoteEvent::ChangeOctave(_) => HelpCategory::Octave,
+            NoteEvent::ChangeWaveform(_)
+            | NoteEvent::CycleWaveform(_)
+            | NoteEvent::ToggleWaveformSequence => HelpCategory::Waveform,
+            NoteEvent::ToggleTremolo => HelpCategory::Tremolo,
+            NoteEvent::ToggleLoop | NoteEvent::UndoLoopOverdub | NoteEvent::ClearLoop => {
+                HelpCategory::Looper
+            }
+            NoteEvent::ToggleNoteNames
+            | NoteEvent::DisplayGainUp
+            | NoteEvent::DisplayGainDown
+            | NoteEvent::ToggleAutoGain
+            | NoteEvent::CycleVisualTap
+            | NoteEvent::ToggleHighContrast
+            | NoteEvent::ToggleReducedMotion => HelpCategory::Display,
+            NoteEvent::ExportMidi
+            | NoteEvent::ToggleMute
+            | NoteEvent::ToggleWaveShaperBypass
+            | NoteEvent::ToggleReferenceTone
+            | NoteEvent::RandomPatch
+            | NoteEvent::Undo
+            | NoteEvent::Redo
+            | NoteEvent::DumpUndoHistory
+            | NoteEvent::DumpVoices
+            | NoteEvent::ToggleHelp => HelpCategory::Actions,
+            NoteEvent::Off(_)
+            | NoteEvent::ChangeKey(_)
+            | NoteEvent::SetEnvelope(_)
+            | NoteEvent::SetParam(..)
+            | NoteEvent::RibbonStart { .. }
+            | NoteEvent::RibbonMove { .. }
+            | NoteEvent::RibbonEnd { .. } => return None,
+        };
+        Some(category)
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            HelpCategory::Notes => "Notes",
+            HelpCategory::Octave => "Octave",
+            HelpCategory::Waveform => "Waveform",
+            HelpCategory::Tremolo => "Tremolo",
+            HelpCategory::Looper => "Looper",
+            HelpCategory::Display => "Display",
+            HelpCategory::Actions => "Actions",
+        }
+    }
+}
+
+/// One line of the help screen.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HelpLine {
+    Heading(String),
+    /// A binding, with the key it is bound to so it can light up while that key is held.
+    Binding {
+        key: Key,
+        text: String,
+    },
+}
+
+impl HelpLine {
+    pub fn text(&self) -> &str {
+        match self {
+            HelpLine::Heading(text) | HelpLine::Binding { text, .. } => text,
+        }
+    }
+}
+
+/// What an event does, as the help screen describes it.
+fn describe(event: &NoteEvent) -> String {
+    match event {
+        NoteEvent::On(note) => note.replace("_SHARP", "#"),
+        NoteEvent::ChangeOctave(direction) => format!("Octave {}", direction),
+        NoteEvent::ChangeWaveform(waveform) => format!("{:?}", waveform),
+        NoteEvent::CycleWaveform(CycleDirection::Next) => "Next waveform".to_string(),
+        NoteEvent::CycleWaveform(CycleDirection::Previous) => "Previous waveform".to_string(),
+        NoteEvent::ToggleWaveformSequence => "Waveform sequence".to_string(),
+        NoteEvent::ToggleTremolo => "Tremolo".to_string(),
+        NoteEvent::ToggleLoop => "Record/play/stop".to_string(),
+        NoteEvent::UndoLoopOverdub => "Undo overdub".to_string(),
+        NoteEvent::ClearLoop => "Clear loop".to_string(),
+        NoteEvent::ToggleNoteNames => "Note names".to_string(),
+        NoteEvent::DisplayGainUp => "Display gain up".to_string(),
+        NoteEvent::DisplayGainDown => "Display gain down".to_string(),
+        NoteEvent::ToggleAutoGain => "Auto-gain".to_string(),
+        NoteEvent::CycleVisualTap => "Visual tap".to_string(),
+        NoteEvent::ToggleHighContrast => "High contrast".to_string(),
+        NoteEvent::ToggleReducedMotion => "Reduced motion".to_string(),
+        NoteEvent::ExportMidi => "Export MIDI".to_string(),
+        NoteEvent::ToggleMute => "Mute".to_string(),
+        NoteEvent::ToggleWaveShaperBypass => "Bypass wave shaper".to_string(),
+        NoteEvent::ToggleReferenceTone => "Reference tone".to_string(),
+        NoteEvent::RandomPatch => "Random patch".to_string(),
+        NoteEvent::Undo => "Undo".to_string(),
+        NoteEvent::Redo => "Redo".to_string(),
+        NoteEvent::DumpUndoHistory => "Log undo history".to_string(),
+        NoteEvent::DumpVoices => "Log voices".to_string(),
+        NoteEvent::ToggleHelp => "This help".to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// Lists `bindings` grouped by category, sorted by key within each group.
+///
+/// Built from the bindings as they are when called, so it shows whatever is bound right now.
+/// A Shift variant is only listed when it does something other than the plain key.
+pub fn help_lines(bindings: &ResolvedBindings) -> Vec<HelpLine> {
+    let mut entries: Vec<(HelpCategory, String, bool, Key, String)> = bindings
+        .iter()
+        .filter_map(|(key_id, event)| {
+            let category = HelpCategory::of(event)?;
+            let description = describe(event);
+            if key_id.shift {
+                let plain = KeyId::new(key_id.key.clone(), false);
+                if bindings.lookup(&plain).map(describe).as_ref() == Some(&description) {
+                    return None;
+                }
+            }
+            Some((
+                category,
+                key_label(&key_id.key),
+                key_id.shift,
+                key_id.key.clone(),
+                description,
+            ))
+        })
+        .collect();
+    entries.sort_by(|a, b| (a.0, &a.1, a.2).cmp(&(b.0, &b.1, b.2)));
+
+    let mut lines = Vec::new();
+    let mut current_category = None;
+    for (category, label, shift, key, description) in entries {
+        if current_category != Some(category) {
+            current_category = Some(category);
+            lines.push(HelpLine::Heading(category.title().to_string()));
+        }
+        let label = if shift {
+            format!("Shift+{}", label)
+        } else {
+            label
+        };
+        lines.push(HelpLine::Binding {
+            key,
+            text: format!("  {:<10} {}", label, description),
+        });
+    }
+    lines
+}
+
+/// Where the help lines go in a window of a given size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HelpLayout {
+    pub columns: usize,
+    pub rows_per_column: usize,
+    /// Rows that fit in the window at once.
+    pub visible_rows: usize,
+    /// Longest line, in characters, that fits in a column; longer ones are cut short.
+    pub max_chars: usize,
+}
+
+impl HelpLayout {
+    /// How far down the list can be scrolled, in rows.
+    pub fn max_scroll(&self) -> usize {
+        self.rows_per_column.saturating_sub(self.visible_rows)
+    }
+}
+
+/// Fits `lines` into `width` by `height` screen pixels with font pixels `scale` pixels wide.
+///
+/// Uses as many columns as fit side by side and as the list needs to avoid scrolling. A window
+/// too narrow for even one full column gets a single column with the lines cut short.
+pub fn layout_help(lines: &[HelpLine], width: f32, height: f32, scale: f32) -> HelpLayout {
+    let char_width = GLYPH_ADVANCE as f32 * scale;
+    let line_height = (GLYPH_HEIGHT + LINE_GAP) as f32 * scale;
+    let available_chars = ((width - 2.0 * MARGIN) / char_width).max(0.0) as usize;
+    let visible_rows = (((height - 2.0 * MARGIN) / line_height).max(0.0) as usize).max(1);
+
+    let longest = lines
+        .iter()
+        .map(|line| line.text().chars().count())
+        .max()
+        .unwrap_or(0);
+    let columns_that_fit = (available_chars + COLUMN_GAP) / (longest + COLUMN_GAP).max(1);
+    let columns_needed = lines.len().div_ceil(visible_rows).max(1);
+    let columns = columns_that_fit.min(columns_needed).max(1);
+
+    HelpLayout {
+        columns,
+        rows_per_column: lines.len().div_ceil(columns),
+        visible_rows,
+        max_chars: if columns_that_fit == 0 {
+            available_chars
+        } else {
+            longest
+        },
+    }
+}
+
+/// Cuts `text` down to `max_chars` characters, marking the cut with "..".
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    if max_chars <= 2 {
+        return text.chars().take(max_chars).collect();
+    }
+    let mut truncated: String = text.chars().take(max_chars - 2).collect();
+    truncated.push_str("..");
+    truncated
+}
+
+/// Builds the help screen's geometry: a backdrop over the whole window, then `lines` laid out
+/// by `layout_help`, starting `scroll` rows down. Bindings whose key is in `held_keys` are
+/// drawn in the highlight color.
+pub fn help_vertices(
+    lines: &[HelpLine],
+    held_keys: &HashSet<Key>,
+    scroll: usize,
+    config: &HelpConfig,
+    width: f32,
+    height: f32,
+) -> Vec<ColorVertex> {
+    if width <= 0.0 || height <= 0.0 {
+        return Vec::new();
+    }
+
+    let mut vertices = ColorVertex::quad(-1.0, -1.0, 1.0, 1.0, config.background);
+    let layout = layout_help(lines, width, height, config.size);
+    let scroll = scroll.min(layout.max_scroll());
+    let column_width = (layout.max_chars + COLUMN_GAP) as f32 * GLYPH_ADVANCE as f32 * config.size;
+    let line_height = (GLYPH_HEIGHT + LINE_GAP) as f32 * config.size;
+
+    for (index, line) in lines.iter().enumerate() {
+        let column = index / layout.rows_per_column;
+        let row = index % layout.rows_per_column;
+        if row < scroll || row >= scroll + layout.visible_rows {
+            continue;
+        }
+        let [r, g, b] = match line {
+            HelpLine::Heading(_) => config.heading_color,
+            HelpLine::Binding { key, .. } if held_keys.contains(key) => config.highlight_color,
+            HelpLine::Binding { .. } => config.color,
+        };
+        vertices.extend(text_vertices(
+            &truncate(line.text(), layout.max_chars),
+            MARGIN + column as f32 * column_width,
+            MARGIN + (row - scroll) as f32 * line_height,
+            config.size,
+            [r, g, b, 1.0],
+            width,
+            height,
+        ));
+    }
+    vertices.truncate(HELP_MAX_VERTICES);
+    vertices
+}
+
+#[cfg(test)]
+mod tests {
+    use winit::keyboard::{NamedKey, SmolStr};
+
+    use super::*;
+    use crate::synth::Config;
+
+    fn bindings() -> ResolvedBindings {
+        let config: Config = serde_yaml::from_str(
+            r#"
+keybindings:
+  notes:
+    keys:
+      'Character("a")': 'C'
+      'Character("w")': 'C_SHARP'
+  bass_notes:
+    keys: {}
+  key_change:
+    keys: {}
+  octave:
+    up: 'Named(ArrowUp)'
+    down: 'Named(ArrowDown)'
+  tremolo:
+    toggle: 'Named(Tab)'
+  help:
+    toggle: 'Named(F1)'
+action_keys:
+  toggle_notes: {}
+  change_waveform:
+    'Character("1")': Sine
+"#,
+        )
+        .unwrap();
+        ResolvedBindings::from_config(&config)
+    }
+
+    fn texts(lines: &[HelpLine]) -> Vec<&str> {
+        lines.iter().map(HelpLine::text).collect()
+    }
+
+    #[test]
+    fn bindings_are_listed_by_category_and_key() {
+        let lines = help_lines(&bindings());
+        assert_eq!(
+            texts(&lines),
+            [
+                "Notes",
+                "  A          C",
+                "  Shift+A    C+1",
+                "  W          C#",
+                "  Shift+W    C#+1",
+                "Octave",
+                "  ArrowDown  Octave down",
+                "  ArrowUp    Octave up",
+                "Waveform",
+                "  1          Sine",
+                "Tremolo",
+                "  Tab        Tremolo",
+                "Actions",
+                "  F1         This help",
+            ]
+        );
+        assert!(matches!(
+            &lines[1],
+            HelpLine::Binding { key: Key::Character(key), .. } if key == "a"
+        ));
+    }
+
+    fn lines(count: usize, length: usize) -> Vec<HelpLine> {
+        (0..count)
+            .map(|_| HelpLine::Heading("x".repeat(length)))
+            .collect()
+    }
+
+    #[test]
+    fn a_large_window_fits_the_list_without_scrolling() {
+        let lines = lines(200, 20);
+        let layout = layout_help(&lines, 2_000.0, 1_000.0, 2.0);
+        assert!(layout.columns > 1);
+        assert!(layout.rows_per_column <= layout.visible_rows);
+        assert_eq!(layout.max_scroll(), 0);
+        assert_eq!(layout.max_chars, 20);
+    }
+
+    #[test]
+    fn a_tiny_window_falls_back_to_one_truncated_column_that_scrolls() {
+        let lines = lines(40, 20);
+        let char_width = GLYPH_ADVANCE as f32 * 2.0;
+        let width = 2.0 * MARGIN + 10.0 * char_width;
+        let layout = layout_help(&lines, width, 60.0, 2.0);
+        assert_eq!(layout.columns, 1);
+        assert_eq!(layout.rows_per_column, 40);
+        assert_eq!(layout.max_chars, 10);
+        assert_eq!(layout.max_scroll(), 40 - layout.visible_rows);
+
+        // Even a window with no room at all shows a row.
+        let layout = layout_help(&lines, 0.0, 0.0, 2.0);
+        assert_eq!(layout.columns, 1);
+        assert_eq!(layout.visible_rows, 1);
+    }
+
+    #[test]
+    fn long_lines_are_cut_short_with_a_marker() {
+        assert_eq!(truncate("Display gain up", 20), "Display gain up");
+        assert_eq!(truncate("Display gain up", 9), "Display..");
+        assert_eq!(truncate("Display gain up", 2), "Di");
+        assert_eq!(truncate("Display gain up", 0), "");
+    }
+
+    #[test]
+    fn a_held_key_lights_up_its_binding() {
+        let config = HelpConfig::default();
+        let lines = vec![
+            HelpLine::Heading("Notes".to_string()),
+            HelpLine::Binding {
+                key: Key::Character(SmolStr::new("a")),
+                text: "  A  C".to_string(),
+            },
+            HelpLine::Binding {
+                key: Key::Named(NamedKey::F1),
+                text: "  F1 This help".to_string(),
+            },
+        ];
+        let held = HashSet::from([Key::Character(SmolStr::new("a"))]);
+        let vertices = help_vertices(&lines, &held, 0, &config, 800.0, 600.0);
+        let has_color =
+            |[r, g, b]: [f32; 3]| vertices.iter().any(|vertex| vertex.color == [r, g, b, 1.0]);
+        assert_eq!(vertices[0].color, config.background);
+        assert!(has_color(config.heading_color));
+        assert!(has_color(config.highlight_color));
+        assert!(has_color(config.color));
+
+        assert!(help_vertices(&lines, &HashSet::new(), 0, &config, 0.0, 600.0).is_empty());
+    }
+}
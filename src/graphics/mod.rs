@@ -1,6 +1,40 @@
+pub mod accessibility;
+pub mod audio_buffer;
+pub mod display_config;
+pub mod display_scale;
+pub mod envelope;
+pub mod frame_rate;
+pub mod help;
+pub mod note_names;
+pub mod present_mode;
+pub mod readback;
+pub mod render_check;
+pub mod ribbon;
+pub mod silence_hold;
 pub mod state;
+pub mod text;
+pub mod theme;
+pub mod title;
 pub mod uniforms;
 pub mod vertex;
 
 pub use state::{AudioData, State};
-pub use vertex::{Vertex, generate_waveform_vertices};
+pub use accessibility::{AccessibilityConfig, FrameHistory};
+pub use audio_buffer::{AudioBufferBinding, AudioBufferLayout};
+pub use display_config::DisplayConfig;
+pub use display_scale::{AutoGain, DisplayScale, DisplayScaleConfig};
+pub use envelope::{Corner, EnvelopeEditorConfig, EnvelopeHandle, EnvelopeWidget, WidgetRect};
+pub use note_names::{sounding_notes, NoteNamesConfig, SoundingNote};
+pub use frame_rate::{refresh_rate_fps, visual_fps};
+pub use help::{help_lines, HelpConfig, HelpLine};
+pub use present_mode::{select_present_mode, PresentMode};
+pub use readback::{count_drawn_pixels, padded_bytes_per_row, unpad_rows, OffscreenTarget};
+pub use render_check::{check_offscreen_render, RenderCheck};
+pub use ribbon::RibbonStrip;
+pub use silence_hold::{HoldPhase, SilenceHold, SilenceHoldConfig};
+pub use theme::{lerp_hue, PaletteMap, ThemeConfig};
+pub use title::{format_title, TitleState, TitleUpdater, WindowTitleConfig};
+pub use vertex::{
+    line_half_width, line_offset, sample_index, screen_x, strip_x, waveform_shader_constants,
+    ColorVertex, VisualizerConfig, WaveformLayout, MIN_WAVEFORM_POINTS,
+};
@@ -0,0 +1,338 @@
+use serde::{Deserialize, Serialize};
+
+use crate::graphics::{
+    text::{text_vertices, text_width, GLYPH_HEIGHT},
+    ColorVertex,
+};
+use crate::synth::{
+    keys::keys::{frequency_to_midi_note, midi_note_to_name},
+    EnvelopeStage, VoiceInfo,
+};
+
+/// Distance of the overlay from the window's top-left corner, in screen pixels.
+const MARGIN: f32 = 8.0;
+
+/// Upper bound on the overlay's vertices, sizing its vertex buffer. Whole quads past it are
+/// dropped.
+pub const NOTE_NAMES_MAX_VERTICES: usize = 6 * 2048;
+
+/// How the names of the sounding notes are drawn along the top of the window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoteNamesConfig {
+    /// Whether the overlay starts out shown.
+    pub visible: bool,
+    /// Text color as RGB; the alpha follows each note's envelope.
+    pub color: [f32; 3],
+    /// Size of one font pixel in screen pixels.
+    pub size: f32,
+    /// Most names shown at once; the rest are summarized as "+N".
+    pub max_names: usize,
+}
+
+impl Default for NoteNamesConfig {
+    fn default() -> Self {
+        NoteNamesConfig {
+            visible: true,
+            color: [1.0, 1.0, 1.0],
+            size: 3.0,
+            max_names: 16,
+        }
+    }
+}
+
+/// A note currently sounding, as the overlay shows it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoundingNote {
+    pub name: String,
+    pub frequency: f32,
+    pub amplitude: f32,
+}
+
+/// The notes `voices` are sounding, named by their actual pitch. A note played by several
+/// voices is listed once, at its loudest. The reference tone, while on, is listed first as
+/// "REF".
+pub fn sounding_notes(voices: &[VoiceInfo], reference_tone: bool) -> Vec<SoundingNote> {
+    let mut sounding_notes: Vec<SoundingNote> = Vec::new();
+    if reference_tone {
+        // Below every note's frequency, so the overlay puts it first.
+        sounding_notes.push(SoundingNote {
+            name: "REF".to_string(),
+            frequency: 0.0,
+            amplitude: 1.0,
+        });
+    }
+    for voice in voices {
+        let Some(midi_note) = frequency_to_midi_note(voice.freq) else {
+            continue;
+        };
+        if voice.envelope_stage == EnvelopeStage::Finished {
+            continue;
+        }
+        let name = midi_note_to_name(midi_note);
+        match sounding_notes.iter_mut().find(|note| note.name == name) {
+            Some(note) => note.amplitude = note.amplitude.max(voice.amplitude),
+            None => sounding_notes.push(SoundingNote {
+                name,
+                frequency: voice.freq,
+                amplitude: voice.amplitude,
+            }),
+        }
+    }
+    sounding_notes
+}
+
+/// A piece of text placed by the layout, in screen pixels from the window's top-left corner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlacedText {
+    pub text: String,
+    pub x: f32,
+    pub alpha: f32,
+}
+
+/// The notes to show for a frame where `current` are sounding and `previous` were last frame.
+///
+/// A note only leaves the list once its voice is gone, which for a voice let go normally is
+/// after its release has faded to nothing. A voice cut off early, by voice stealing or the
+/// silence timeout, can go while still loud, so a note missing from `current` is kept for this
+/// one frame at half its last level rather than vanishing. `previous` should be last frame's
+/// `current`, not what this returned, so the fade never lasts longer than the frame.
+pub fn with_vanished_notes(
+    previous: &[SoundingNote],
+    mut current: Vec<SoundingNote>,
+) -> Vec<SoundingNote> {
+    let vanished: Vec<SoundingNote> = previous
+        .iter()
+        .filter(|note| note.amplitude > 0.0 && !current.iter().any(|n| n.name == note.name))
+        .map(|note| SoundingNote {
+            amplitude: note.amplitude * 0.5,
+            ..note.clone()
+        })
+        .collect();
+    current.extend(vanished);
+    current
+}
+
+/// Lays out `notes` left to right in pitch order within `width` screen pixels.
+///
+/// Names that don't fit, or that go past `max_names`, are replaced by a trailing "+N".
+pub fn layout_note_names(
+    notes: &[SoundingNote],
+    width: f32,
+    scale: f32,
+    max_names: usize,
+) -> Vec<PlacedText> {
+    let mut notes = notes.to_vec();
+    notes.sort_by(|a, b| a.frequency.total_cmp(&b.frequency));
+
+    let space = text_width(" ", scale);
+    let available = width - 2.0 * MARGIN;
+    let mut placed = Vec::new();
+    let mut x = 0.0;
+
+    for (index, note) in notes.iter().enumerate().take(max_names) {
+        let hidden_after = notes.len() - index - 1;
+        let reserve = if hidden_after > 0 {
+            space + text_width(&format!("+{}", hidden_after), scale)
+        } else {
+            0.0
+        };
+        let name_width = text_width(&note.name, scale);
+        if x + name_width + reserve > available {
+            break;
+        }
+        placed.push(PlacedText {
+            text: note.name.clone(),
+            x: MARGIN + x,
+            alpha: note.amplitude.clamp(0.0, 1.0),
+        });
+        x += name_width + space;
+    }
+
+    let hidden = notes.len() - placed.len();
+    if hidden > 0 {
+        placed.push(PlacedText {
+            text: format!("+{}", hidden),
+            x: MARGIN + x,
+            alpha: 1.0,
+        });
+    }
+    placed
+}
+
+/// Builds the overlay geometry for `notes` in a window of `width` by `height` screen pixels.
+pub fn note_name_vertices(
+    notes: &[SoundingNote],
+    config: &NoteNamesConfig,
+    width: f32,
+    height: f32,
+) -> Vec<ColorVertex> {
+    if width <= 0.0 || height <= 0.0 || height < MARGIN + GLYPH_HEIGHT as f32 * config.size {
+        return Vec::new();
+    }
+
+    let [r, g, b] = config.color;
+    let mut vertices = Vec::new();
+    for text in layout_note_names(notes, width, config.size, config.max_names) {
+        vertices.extend(text_vertices(
+            &text.text,
+            text.x,
+            MARGIN,
+            config.size,
+            [r, g, b, text.alpha],
+            width,
+            height,
+        ));
+    }
+    vertices.truncate(NOTE_NAMES_MAX_VERTICES);
+    vertices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voice(freq: f32, envelope_stage: EnvelopeStage, amplitude: f32) -> VoiceInfo {
+        VoiceInfo {
+            note: String::new(),
+            freq,
+            envelope_stage,
+            amplitude,
+            age_samples: 0,
+        }
+    }
+
+    fn note(name: &str, frequency: f32) -> SoundingNote {
+        SoundingNote {
+            name: name.to_string(),
+            frequency,
+            amplitude: 1.0,
+        }
+    }
+
+    #[test]
+    fn voices_are_named_by_pitch_once_each_at_their_loudest() {
+        let voices = [
+            voice(440.0, EnvelopeStage::Decay, 0.4),
+            voice(261.63, EnvelopeStage::Attack, 0.2),
+            // A detuned unison voice of the same A.
+            voice(441.0, EnvelopeStage::Decay, 0.9),
+            voice(329.63, EnvelopeStage::Finished, 0.0),
+        ];
+        let notes = sounding_notes(&voices, false);
+        let names: Vec<(&str, f32)> = notes
+            .iter()
+            .map(|note| (note.name.as_str(), note.amplitude))
+            .collect();
+        assert_eq!(names, [("A4", 0.9), ("C4", 0.2)]);
+    }
+
+    #[test]
+    fn the_reference_tone_is_listed_first() {
+        let notes = sounding_notes(&[voice(110.0, EnvelopeStage::Decay, 0.5)], true);
+        assert_eq!(notes[0].name, "REF");
+        let placed = layout_note_names(&notes, 800.0, 3.0, 16);
+        assert_eq!(placed[0].text, "REF");
+        assert_eq!(placed[1].text, "A2");
+    }
+
+    #[test]
+    fn names_past_the_limit_collapse_into_a_count() {
+        let notes = [note("E4", 329.63), note("C4", 261.63), note("G4", 392.0)];
+        let placed = layout_note_names(&notes, 800.0, 3.0, 2);
+        let texts: Vec<&str> = placed.iter().map(|text| text.text.as_str()).collect();
+        assert_eq!(texts, ["C4", "E4", "+1"]);
+        // A window too narrow for any name shows only the count.
+        let placed = layout_note_names(&notes, 40.0, 3.0, 16);
+        assert_eq!(placed.len(), 1);
+        assert_eq!(placed[0].text, "+3");
+    }
+
+    #[test]
+    fn a_note_cut_off_while_loud_lingers_for_one_frame() {
+        let previous = [note("C4", 261.63), note("E4", 329.63)];
+        let current = with_vanished_notes(&previous, vec![note("C4", 261.63)]);
+        assert_eq!(current.len(), 2);
+        assert_eq!(current[1].name, "E4");
+        assert_eq!(current[1].amplitude, 0.5);
+    }
+
+    #[test]
+    fn a_vanished_note_is_dropped_after_its_one_frame() {
+        let first = [note("C4", 261.63), note("E4", 329.63)];
+        let second = vec![note("C4", 261.63)];
+        assert_eq!(with_vanished_notes(&first, second.clone()).len(), 2);
+        // The next frame looks back at what was sounding, not at what was drawn.
+        let third = with_vanished_notes(&second, vec![note("C4", 261.63)]);
+        assert_eq!(third, [note("C4", 261.63)]);
+        // A note that had already faded to nothing isn't brought back.
+        let silent = SoundingNote {
+            amplitude: 0.0,
+            ..note("G4", 392.0)
+        };
+        assert!(with_vanished_notes(&[silent], Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn a_released_note_fades_with_its_envelope_then_leaves_the_list() {
+        use std::sync::{Arc, RwLock};
+
+        use crate::synth::{
+            KeyboardSplitConfig, NoteId, OscillatorWaveform, SynthEngine, ZonePreset,
+        };
+
+        const SAMPLE_RATE: f32 = 48_000.0;
+        /// A block of 10 ms, the rate the overlay is checked at here.
+        const BLOCK: usize = 480;
+
+        let keyboard_split = KeyboardSplitConfig {
+            lead: ZonePreset {
+                attack: 0.01,
+                decay: 0.05,
+                sustain: 0.6,
+                release: 2.0,
+                ..ZonePreset::default()
+            },
+            ..KeyboardSplitConfig::default()
+        };
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sine)))
+            .keyboard_split(keyboard_split)
+            .build(SAMPLE_RATE);
+        let id = NoteId::shared("A".to_string());
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(id.clone(), None);
+        for _ in 0..5 {
+            engine.render(BLOCK);
+        }
+        engine.note_state().lock().unwrap().stop_note(&id);
+
+        let mut amplitudes = Vec::new();
+        for _ in 0..300 {
+            engine.render(BLOCK);
+            let voices = engine.note_state().lock().unwrap().voice_debug_info();
+            let notes = sounding_notes(&voices, false);
+            match notes.iter().find(|note| note.name == "A4") {
+                Some(note) => amplitudes.push(note.amplitude),
+                None => break,
+            }
+        }
+        // Down all the way over the two seconds of release, and gone once it ends.
+        assert!(amplitudes[0] > 0.3, "{}", amplitudes[0]);
+        assert!(amplitudes.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(amplitudes[amplitudes.len() - 1] < 0.01);
+        assert!(
+            (195..=201).contains(&amplitudes.len()),
+            "{}",
+            amplitudes.len()
+        );
+        for _ in 0..50 {
+            engine.render(BLOCK);
+            let voices = engine.note_state().lock().unwrap().voice_debug_info();
+            assert!(sounding_notes(&voices, false).is_empty());
+        }
+    }
+}
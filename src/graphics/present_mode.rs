@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// How finished frames reach the screen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresentMode {
+    /// Wait for vertical sync. Supported everywhere, never tears.
+    #[default]
+    Fifo,
+    /// Wait for vertical sync, but show a late frame straight away.
+    FifoRelaxed,
+    /// Replace a waiting frame with a newer one each sync, for low latency without tearing.
+    Mailbox,
+    /// Show each frame as soon as it is drawn, for the lowest latency. May tear.
+    Immediate,
+}
+
+impl PresentMode {
+    pub fn to_wgpu(self) -> wgpu::PresentMode {
+        match self {
+            PresentMode::Fifo => wgpu::PresentMode::Fifo,
+            PresentMode::FifoRelaxed => wgpu::PresentMode::FifoRelaxed,
+            PresentMode::Mailbox => wgpu::PresentMode::Mailbox,
+            PresentMode::Immediate => wgpu::PresentMode::Immediate,
+        }
+    }
+}
+
+/// Picks `requested` if the surface supports it, falling back to Fifo, which every surface
+/// supports, with a warning otherwise.
+pub fn select_present_mode(
+    requested: PresentMode,
+    supported: &[wgpu::PresentMode],
+) -> wgpu::PresentMode {
+    let requested = requested.to_wgpu();
+    if supported.contains(&requested) {
+        requested
+    } else {
+        warn!(
+            "Present mode {:?} isn't supported here (supported: {:?}); using Fifo",
+            requested, supported
+        );
+        wgpu::PresentMode::Fifo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [PresentMode; 4] = [
+        PresentMode::Fifo,
+        PresentMode::FifoRelaxed,
+        PresentMode::Mailbox,
+        PresentMode::Immediate,
+    ];
+
+    #[test]
+    fn a_supported_mode_is_used_as_asked() {
+        let supported = ALL.map(PresentMode::to_wgpu);
+        for mode in ALL {
+            assert_eq!(select_present_mode(mode, &supported), mode.to_wgpu());
+        }
+        let supported = [wgpu::PresentMode::Fifo, wgpu::PresentMode::Mailbox];
+        assert_eq!(
+            select_present_mode(PresentMode::Mailbox, &supported),
+            wgpu::PresentMode::Mailbox
+        );
+    }
+
+    #[test]
+    fn an_unsupported_mode_falls_back_to_fifo() {
+        let supported = [wgpu::PresentMode::Fifo, wgpu::PresentMode::FifoRelaxed];
+        assert_eq!(
+            select_present_mode(PresentMode::Immediate, &supported),
+            wgpu::PresentMode::Fifo
+        );
+        assert_eq!(
+            select_present_mode(PresentMode::Mailbox, &supported),
+            wgpu::PresentMode::Fifo
+        );
+        // Even a surface that lists nothing gets Fifo, which every surface supports.
+        assert_eq!(
+            select_present_mode(PresentMode::Immediate, &[]),
+            wgpu::PresentMode::Fifo
+        );
+    }
+
+    #[test]
+    fn present_modes_parse_from_config() {
+        let modes: Vec<PresentMode> =
+            serde_yaml::from_str("[fifo, fifo_relaxed, mailbox, immediate]").unwrap();
+        assert_eq!(modes, ALL);
+        assert!(serde_yaml::from_str::<PresentMode>("vsync").is_err());
+    }
+}
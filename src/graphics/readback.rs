@@ -0,0 +1,193 @@
+use std::sync::mpsc;
+
+use anyhow::{Context, Result};
+
+/// Bytes per pixel of the RGBA8 textures read back.
+const BYTES_PER_PIXEL: u32 = 4;
+
+/// Bytes per row of a `width` pixel wide RGBA8 texture in a copy to a buffer, which wgpu wants
+/// padded to `COPY_BYTES_PER_ROW_ALIGNMENT`.
+pub fn padded_bytes_per_row(width: u32) -> u32 {
+    let unpadded = width * BYTES_PER_PIXEL;
+    unpadded.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
+}
+
+/// Strips the padding `padded_bytes_per_row` added from `height` rows of `width` pixels,
+/// leaving the pixels packed row after row.
+pub fn unpad_rows(padded: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let padded_row = padded_bytes_per_row(width) as usize;
+    let row = (width * BYTES_PER_PIXEL) as usize;
+    padded
+        .chunks(padded_row)
+        .take(height as usize)
+        .flat_map(|padded| &padded[..row])
+        .copied()
+        .collect()
+}
+
+/// Counts the pixels of packed RGBA8 `pixels` that differ from `background` by more than
+/// `tolerance` in any channel.
+pub fn count_drawn_pixels(pixels: &[u8], background: [u8; 4], tolerance: u8) -> usize {
+    pixels
+        .chunks_exact(BYTES_PER_PIXEL as usize)
+        .filter(|pixel| {
+            pixel
+                .iter()
+                .zip(background)
+                .any(|(channel, background)| channel.abs_diff(background) > tolerance)
+        })
+        .count()
+}
+
+/// A texture to render into instead of a window, whose pixels can be read back afterwards.
+pub struct OffscreenTarget {
+    texture: wgpu::Texture,
+    width: u32,
+    height: u32,
+}
+
+impl OffscreenTarget {
+    /// Format of the target. Readback expects four bytes a pixel in RGBA order.
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        OffscreenTarget {
+            texture,
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn view(&self) -> wgpu::TextureView {
+        self.texture
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    /// Copies the target's pixels back from the GPU, waiting for everything submitted so far to
+    /// finish. They come back packed row after row from the top, RGBA8.
+    pub fn read_pixels(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Vec<u8>> {
+        let padded_row = padded_bytes_per_row(self.width);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size: (padded_row * self.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Readback Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row),
+                    rows_per_image: Some(self.height),
+                },
+            },
+            wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .context("The readback buffer was dropped before it was mapped")?
+            .context("Failed to map the readback buffer")?;
+
+        let pixels = unpad_rows(&slice.get_mapped_range(), self.width, self.height);
+        buffer.unmap();
+        Ok(pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKGROUND: [u8; 4] = [0, 0, 0, 255];
+
+    /// A `width` by `height` image laid out as a copy to a buffer would be, padding and all,
+    /// with a white square in its top left quarter. The padding is filled with a color that
+    /// would count as drawn if it were left in.
+    fn synthetic_render(width: u32, height: u32) -> Vec<u8> {
+        let padded_row = padded_bytes_per_row(width) as usize;
+        let mut padded = vec![0xAB; padded_row * height as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let inside = x < width / 2 && y < height / 2;
+                let pixel = if inside { [255; 4] } else { BACKGROUND };
+                let start = y as usize * padded_row + (x * BYTES_PER_PIXEL) as usize;
+                padded[start..start + 4].copy_from_slice(&pixel);
+            }
+        }
+        padded
+    }
+
+    #[test]
+    fn rows_are_padded_to_the_copy_alignment() {
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        assert_eq!(padded_bytes_per_row(64), 256);
+        assert_eq!(padded_bytes_per_row(1), alignment);
+        assert_eq!(padded_bytes_per_row(65), 2 * alignment);
+        for width in [1, 17, 63, 64, 100, 1_920] {
+            let padded = padded_bytes_per_row(width);
+            assert_eq!(padded % alignment, 0);
+            assert!(padded >= width * BYTES_PER_PIXEL);
+            assert!(padded - width * BYTES_PER_PIXEL < alignment);
+        }
+    }
+
+    #[test]
+    fn a_synthetic_render_reads_back_without_its_padding() {
+        for (width, height) in [(64, 64), (50, 30), (3, 5)] {
+            let pixels = unpad_rows(&synthetic_render(width, height), width, height);
+            assert_eq!(pixels.len(), (width * height * BYTES_PER_PIXEL) as usize);
+            let square = (width / 2 * (height / 2)) as usize;
+            assert_eq!(count_drawn_pixels(&pixels, BACKGROUND, 8), square);
+            // The first pixel is in the square and the last is background.
+            assert_eq!(pixels[..4], [255; 4]);
+            assert_eq!(pixels[pixels.len() - 4..], BACKGROUND);
+        }
+    }
+
+    #[test]
+    fn pixels_within_the_tolerance_count_as_background() {
+        let pixels = [0, 0, 0, 255, 5, 0, 3, 250, 9, 0, 0, 255];
+        assert_eq!(count_drawn_pixels(&pixels, BACKGROUND, 8), 1);
+        assert_eq!(count_drawn_pixels(&pixels, BACKGROUND, 0), 2);
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::{bail, Context, Result};
+use wgpu::util::DeviceExt;
+
+use crate::graphics::{readback::count_drawn_pixels, ColorVertex, OffscreenTarget};
+
+/// Size in pixels of the square target the check draws into.
+const CHECK_SIZE: u32 = 64;
+
+/// Share of the target the check's square covers: the middle half along each side.
+const EXPECTED_COVERAGE: f32 = 0.25;
+
+/// How far the drawn share may be from `EXPECTED_COVERAGE` before the check fails.
+const COVERAGE_TOLERANCE: f32 = 0.05;
+
+/// What the offscreen render check found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenderCheck {
+    /// The square was drawn and read back as expected.
+    Rendered {
+        adapter: String,
+        drawn_pixels: usize,
+        total_pixels: usize,
+    },
+    /// There is no GPU adapter to check, as on a headless machine without a software renderer.
+    NoAdapter,
+}
+
+/// Checks the graphics path without a window: creates a device, draws a white square over a
+/// black background into an offscreen texture with the flat-color pipeline, reads it back and
+/// checks the square covers the share of the pixels it should.
+pub async fn check_offscreen_render() -> Result<RenderCheck> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        ..Default::default()
+    });
+    let Some(adapter) = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        })
+        .await
+    else {
+        return Ok(RenderCheck::NoAdapter);
+    };
+    let adapter_name = adapter.get_info().name;
+
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("Render Check Device"),
+                required_features: wgpu::Features::empty(),
+                required_limits: wgpu::Limits::downlevel_defaults(),
+            },
+            None,
+        )
+        .await
+        .context("Failed to request device")?;
+
+    let shader = device.create_shader_module(wgpu::include_wgsl!("ribbon.wgsl"));
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Render Check Pipeline Layout"),
+        bind_group_layouts: &[],
+        push_constant_ranges: &[],
+    });
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Render Check Pipeline"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[ColorVertex::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: OffscreenTarget::FORMAT,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            cull_mode: None,
+            ..Default::default()
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    });
+
+    let vertices: Vec<ColorVertex> = [
+        [-0.5, -0.5],
+        [0.5, -0.5],
+        [0.5, 0.5],
+        [-0.5, -0.5],
+        [0.5, 0.5],
+        [-0.5, 0.5],
+    ]
+    .into_iter()
+    .map(|position| ColorVertex {
+        position,
+        color: [1.0, 1.0, 1.0, 1.0],
+    })
+    .collect();
+    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Render Check Vertex Buffer"),
+        contents: bytemuck::cast_slice(&vertices),
+        usage: wgpu::BufferUsages::VERTEX,
+    });
+
+    let target = OffscreenTarget::new(&device, CHECK_SIZE, CHECK_SIZE);
+    let view = target.view();
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Render Check Encoder"),
+    });
+    {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render check pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(&pipeline);
+        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+        render_pass.draw(0..vertices.len() as u32, 0..1);
+    }
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let pixels = target.read_pixels(&device, &queue)?;
+    let total_pixels = (target.width() * target.height()) as usize;
+    let drawn_pixels = count_drawn_pixels(&pixels, [0, 0, 0, 255], 8);
+    let coverage = drawn_pixels as f32 / total_pixels as f32;
+    if (coverage - EXPECTED_COVERAGE).abs() > COVERAGE_TOLERANCE {
+        bail!(
+            "{} drew {} of {} pixels, expected about {:.0}%",
+            adapter_name,
+            drawn_pixels,
+            total_pixels,
+            EXPECTED_COVERAGE * 100.0
+        );
+    }
+    Ok(RenderCheck::Rendered {
+        adapter: adapter_name,
+        drawn_pixels,
+        total_pixels,
+    })
+}
@@ -0,0 +1,132 @@
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+
+use crate::graphics::ColorVertex;
+
+const STRIP_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 0.15];
+const MARKER_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 0.9];
+const MARKER_WIDTH: f32 = 0.01;
+
+/// Vertices needed to draw the strip and its touch marker.
+pub const RIBBON_MAX_VERTICES: usize = 12;
+
+/// The ribbon's rectangle along the bottom of the window.
+#[derive(Debug, Clone, Copy)]
+pub struct RibbonStrip {
+    /// Height of the strip as a fraction of the window height.
+    pub height: f32,
+}
+
+impl RibbonStrip {
+    /// Returns the normalized x position of `position` if it lies inside the strip.
+    pub fn hit_test(
+        &self,
+        position: PhysicalPosition<f64>,
+        size: PhysicalSize<u32>,
+    ) -> Option<f32> {
+        if size.width == 0 || size.height == 0 {
+            return None;
+        }
+        let top = size.height as f64 * (1.0 - self.height as f64);
+        let inside = position.x >= 0.0
+            && position.x <= size.width as f64
+            && position.y >= top
+            && position.y <= size.height as f64;
+        inside.then(|| self.normalized_x(position, size))
+    }
+
+    /// Maps a cursor position to 0.0 at the strip's left edge and 1.0 at its right, clamping
+    /// positions outside the window so a drag can leave the strip without jumping.
+    pub fn normalized_x(&self, position: PhysicalPosition<f64>, size: PhysicalSize<u32>) -> f32 {
+        if size.width == 0 {
+            return 0.0;
+        }
+        (position.x / size.width as f64).clamp(0.0, 1.0) as f32
+    }
+
+    /// Builds the strip quad, plus a marker quad at `touch` while the strip is held.
+    pub fn vertices(&self, touch: Option<f32>) -> Vec<ColorVertex> {
+        let bottom = -1.0;
+        let top = -1.0 + 2.0 * self.height;
+        let mut vertices = ColorVertex::quad(-1.0, bottom, 1.0, top, STRIP_COLOR);
+        if let Some(normalized_x) = touch {
+            let x = -1.0 + 2.0 * normalized_x;
+            vertices.extend(ColorVertex::quad(
+                x - MARKER_WIDTH,
+                bottom,
+                x + MARKER_WIDTH,
+                top,
+                MARKER_COLOR,
+            ));
+        }
+        vertices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRIP: RibbonStrip = RibbonStrip { height: 0.1 };
+
+    #[test]
+    fn touches_inside_the_strip_give_their_position_along_it() {
+        let size = PhysicalSize::new(800, 600);
+        let left = STRIP.hit_test(PhysicalPosition::new(0.0, 599.0), size);
+        let middle = STRIP.hit_test(PhysicalPosition::new(400.0, 560.0), size);
+        let right = STRIP.hit_test(PhysicalPosition::new(800.0, 545.0), size);
+        assert_eq!(left, Some(0.0));
+        assert_eq!(middle, Some(0.5));
+        assert_eq!(right, Some(1.0));
+    }
+
+    #[test]
+    fn touches_above_the_strip_miss() {
+        let size = PhysicalSize::new(800, 600);
+        assert_eq!(
+            STRIP.hit_test(PhysicalPosition::new(400.0, 530.0), size),
+            None
+        );
+        assert_eq!(
+            STRIP.hit_test(PhysicalPosition::new(400.0, 10.0), size),
+            None
+        );
+    }
+
+    #[test]
+    fn the_strip_follows_the_window_size() {
+        let position = PhysicalPosition::new(400.0, 560.0);
+        assert_eq!(
+            STRIP.hit_test(position, PhysicalSize::new(800, 600)),
+            Some(0.5)
+        );
+        // Twice as tall, the same point is well above the strip.
+        assert_eq!(STRIP.hit_test(position, PhysicalSize::new(800, 1200)), None);
+        assert_eq!(
+            STRIP.hit_test(
+                PhysicalPosition::new(400.0, 1150.0),
+                PhysicalSize::new(1600, 1200)
+            ),
+            Some(0.25)
+        );
+        assert_eq!(STRIP.hit_test(position, PhysicalSize::new(0, 0)), None);
+    }
+
+    #[test]
+    fn a_drag_off_the_window_clamps_to_the_edges() {
+        let size = PhysicalSize::new(800, 600);
+        assert_eq!(
+            STRIP.normalized_x(PhysicalPosition::new(-50.0, 0.0), size),
+            0.0
+        );
+        assert_eq!(
+            STRIP.normalized_x(PhysicalPosition::new(900.0, 0.0), size),
+            1.0
+        );
+    }
+
+    #[test]
+    fn the_marker_is_drawn_only_while_touched() {
+        assert_eq!(STRIP.vertices(None).len(), 6);
+        assert_eq!(STRIP.vertices(Some(0.5)).len(), RIBBON_MAX_VERTICES);
+    }
+}
@@ -1,22 +1,44 @@
-struct VertexInput {
-    @location(0) position: vec2<f32>,
-};
-
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec4<f32>,
 };
 
+// Left channel samples, followed by the right channel's in the split layout, then the same again
+// for the overlay when there is one. Adapters without vertex storage buffers get a fixed-size
+// uniform array instead (see audio_buffer.rs).
 struct AudioData {
-    samples: array<vec4<f32>, 256>,
+    samples: array<vec4<f32>>,
 };
 
 struct Uniform {
     time: f32,
+    // Number of entries per channel that hold samples this frame.
+    sample_count: u32,
+    // 0 for a single waveform, 1 for the left/right split.
+    layout_mode: u32,
+    // 1 to draw the amplitude on a dB scale down to `db_floor`.
+    db_scale: u32,
+    // Vertical gain of the drawn waveform, including auto-gain.
+    display_gain: f32,
+    db_floor: f32,
+    // Degrees per second the waveform's hue turns.
+    hue_rate: f32,
+    // 1 to draw the waveform plain white, for high contrast.
+    high_contrast: u32,
+    // Half the line's width in NDC along x and y, from `line_half_width` in vertex.rs.
+    line_half_width: vec2<f32>,
+    // Points along each strip; the strip is drawn with two vertices to a point.
+    waveform_points: u32,
+    // Least brightness the waveform dims to with the audio.
+    brightness_floor: f32,
+    // Color of the overlaid second stream, drawn as instance 1 of each strip.
+    overlay_color: vec4<f32>,
+    // Share of full brightness the waveform is drawn at; below 1 for a held, dimmed frame.
+    brightness: f32,
 };
 
 @group(0) @binding(0)
-var<uniform> audio: AudioData;
+var<storage, read> audio: AudioData;
 
 @group(1) @binding(0)
 var<uniform> uni: Uniform;
@@ -78,37 +100,138 @@ fn hsv2rgb(h: f32, s: f32, v: f32) -> vec4<f32> {
     }
 }
 
-@vertex
-fn vs_main(model: VertexInput) -> VertexOutput {
-    let x = model.position.x;
-    let i = i32((x + 1.0) * 0.5 * 255.0);
-    let j = i / 16;
-    let k = i % 16;
+// Scales a sample for drawing. Mirrors `db_display_map` in display_scale.rs: on the dB scale
+// full scale stays at 1.0 and `db_floor` lands on 0.0, keeping the sign.
+fn display_level(sample: f32) -> f32 {
+    let scaled = sample * uni.display_gain;
+    if uni.db_scale == 0u {
+        return scaled;
+    }
+    let floor_db = min(uni.db_floor, -1.0);
+    // 20 * log10(x), written with log2 since WGSL has no log10.
+    let level_db = 6.0206 * log2(max(abs(scaled), 1e-30));
+    return sign(scaled) * max((level_db - floor_db) / -floor_db, 0.0);
+}
 
-    if j >= 0 && j < 16 && k >= 0 && k < 16 {
-        let index = j * 16 + k;
-        let sample = audio.samples[index];
+// Mirrors `strip_x` in vertex.rs: position along a strip of `points` points of its point
+// `point`, from -1.0 at the first to 1.0 at the last.
+fn strip_x_at(point: u32, points: u32) -> f32 {
+    return -1.0 + 2.0 * f32(min(point, points - 1u)) / f32(points - 1u);
+}
 
-        let wave_amplitude = sample[0] * 0.5;
-        let wave_frequency = sample[1] * 10.0;
-        let wave_phase = uni.time * sample[2] * 6.0;
+// Mirrors `sample_index` in vertex.rs: which of `count` entries point `point` of a strip of
+// `points` points shows, the entries spread evenly across the strip.
+fn sample_index(point: u32, points: u32, count: u32) -> u32 {
+    return min(u32(f32(min(point, points - 1u)) * f32(count) / f32(points - 1u)), count - 1u);
+}
 
-        let y = sin(x * wave_frequency + wave_phase) * wave_amplitude * 5.0;
+// The audio entry point `point` of a strip of `points` points of `channel` of `stream` shows:
+// stream 0 is the waveform, stream 1 the overlay.
+fn sample_at(point: u32, points: u32, channel: u32, stream: u32) -> vec4<f32> {
+    let count = uni.sample_count;
+    var index = sample_index(point, points, count);
+    if channel == 1u && uni.layout_mode == 1u {
+        index += count;
+    }
+    index += stream * count * (uni.layout_mode + 1u);
+    return audio.samples[index];
+}
 
-        let clip_position = vec4<f32>(x, y, 0.0, 1.0);
+// Height of the waveform at point `point` of a strip of `points` points, or 0.0 while there
+// are no samples.
+fn wave_y(point: u32, points: u32, channel: u32, stream: u32) -> f32 {
+    if uni.sample_count == 0u {
+        return 0.0;
+    }
+    let sample = sample_at(point, points, channel, stream);
+    let strip_x = strip_x_at(point, points);
 
-        let hue = degrees(atan2(y, x)) + uni.time * 100.0;
-        let saturation = length(vec2<f32>(sample[0], sample[1])) * 2.0;
-        let value = sample[3];
+    let wave_amplitude = display_level(sample[0]) * 0.5;
+    let wave_frequency = sample[1] * 10.0;
+    let wave_phase = uni.time * sample[2] * 6.0;
 
-        let color = hsv2rgb(hue, saturation, value);
+    return sin(strip_x * wave_frequency + wave_phase) * wave_amplitude * 5.0;
+}
 
-        return VertexOutput(clip_position, color);
-    } else {
-        let clip_position = vec4<f32>(x, 0.0, 0.0, 1.0);
-        let color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
-        return VertexOutput(clip_position, color);
+// Mirrors `line_offset` in vertex.rs: how far a point on a line from `previous` to `next` is
+// pushed out to its edge, at right angles to the line on screen.
+fn line_offset(previous: vec2<f32>, next: vec2<f32>) -> vec2<f32> {
+    let direction = (next - previous) / uni.line_half_width;
+    let distance = length(direction);
+    if distance == 0.0 {
+        return vec2<f32>(0.0, uni.line_half_width.y);
     }
+    return vec2<f32>(-direction.y, direction.x) / distance * uni.line_half_width;
+}
+
+// `color` at the frame's brightness.
+fn at_brightness(color: vec4<f32>) -> vec4<f32> {
+    return vec4<f32>(color.rgb * uni.brightness, color.a);
+}
+
+// There is no vertex buffer: each strip is `waveform_points` pairs of vertices, one for either
+// edge of the line at each point, and the strips follow one another, so the vertex index alone
+// gives the channel, the point and the edge.
+// `MIN_WAVEFORM_POINTS` is declared ahead of this file by `waveform_shader_constants` in
+// vertex.rs.
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex_index: u32,
+    @builtin(instance_index) stream: u32,
+) -> VertexOutput {
+    let points = max(uni.waveform_points, MIN_WAVEFORM_POINTS);
+    let channel = vertex_index / (2u * points);
+    let point = (vertex_index / 2u) % points;
+    let strip_x = strip_x_at(point, points);
+    var side = -1.0;
+    if vertex_index % 2u == 1u {
+        side = 1.0;
+    }
+
+    // A split puts the left channel's strip in the left half of the window and the right
+    // channel's in the right half, each squeezed to half the width. Mirrors `screen_x` in
+    // vertex.rs.
+    var x = strip_x;
+    var strip_scale = 1.0;
+    if uni.layout_mode == 1u {
+        strip_scale = 0.5;
+        x = -1.0 + f32(channel) + (strip_x + 1.0) * strip_scale;
+    }
+    let y = wave_y(point, points, channel, stream);
+
+    // The neighbouring points on the strip give the line's direction here. A split strip moves
+    // half as far across the screen.
+    let previous_point = max(point, 1u) - 1u;
+    let next_point = min(point + 1u, points - 1u);
+    let previous = vec2<f32>(
+        x + (strip_x_at(previous_point, points) - strip_x) * strip_scale,
+        wave_y(previous_point, points, channel, stream),
+    );
+    let next = vec2<f32>(
+        x + (strip_x_at(next_point, points) - strip_x) * strip_scale,
+        wave_y(next_point, points, channel, stream),
+    );
+    let offset = line_offset(previous, next) * side;
+    let clip_position = vec4<f32>(vec2<f32>(x, y) + offset, 0.0, 1.0);
+
+    if uni.sample_count == 0u {
+        return VertexOutput(clip_position, vec4<f32>(0.0, 0.0, 0.0, 1.0));
+    }
+    if stream == 1u {
+        return VertexOutput(clip_position, at_brightness(uni.overlay_color));
+    }
+    if uni.high_contrast == 1u {
+        return VertexOutput(clip_position, at_brightness(vec4<f32>(1.0, 1.0, 1.0, 1.0)));
+    }
+
+    let sample = sample_at(point, points, channel, stream);
+    let hue = degrees(atan2(y, x)) + uni.time * uni.hue_rate;
+    let saturation = length(vec2<f32>(sample[0], sample[1])) * 2.0;
+    let value = max(sample[3], uni.brightness_floor);
+
+    let color = hsv2rgb(hue, saturation, value);
+
+    return VertexOutput(clip_position, at_brightness(color));
 }
 
 @fragment
@@ -0,0 +1,353 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::graphics::AudioData;
+
+/// Keeps the waveform on screen for a moment after the sound stops.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SilenceHoldConfig {
+    /// Off, silence is drawn as the flat line it is.
+    pub enabled: bool,
+    /// Frames whose RMS level, as the audio thread published it with them, is at or below
+    /// this, in dBFS, count as silent.
+    pub threshold_db: f32,
+    /// Seconds the last frame with sound in it is held at full brightness once silence begins.
+    pub hold: f32,
+    /// Seconds the held frame then takes to fade out.
+    pub fade: f32,
+}
+
+impl Default for SilenceHoldConfig {
+    fn default() -> Self {
+        SilenceHoldConfig {
+            enabled: false,
+            threshold_db: -60.0,
+            hold: 0.1,
+            fade: 0.5,
+        }
+    }
+}
+
+/// Where the display is in holding the last sound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HoldPhase {
+    /// Frames are drawn as they come.
+    Live,
+    /// Silence began at `since`; the last frame with sound in it is drawn in its place.
+    Holding { since: Instant },
+    /// The held frame is fading out, `progress` of the way from full brightness to none.
+    FadingOut { progress: f32 },
+}
+
+/// Replaces silent frames with the last frame that had sound in it, then fades it out.
+///
+/// The held frame is drawn at full brightness for the hold time and faded to nothing over the
+/// fade time, after which silence is drawn as it is. The fade dims the line rather than
+/// shrinking it, so the shape stays put as it goes. Any frame with sound in it is drawn as it
+/// is the moment it arrives, whatever the hold was doing, and becomes the new held frame.
+#[derive(Debug)]
+pub struct SilenceHold {
+    config: SilenceHoldConfig,
+    held: Vec<f32>,
+    held_right: Vec<f32>,
+    phase: HoldPhase,
+    /// When the last frame was passed through, for how far the fade moves each frame.
+    last_frame: Option<Instant>,
+}
+
+impl SilenceHold {
+    pub fn new(config: SilenceHoldConfig) -> Self {
+        SilenceHold {
+            config,
+            held: Vec::new(),
+            held_right: Vec::new(),
+            phase: HoldPhase::Live,
+            last_frame: None,
+        }
+    }
+
+    pub fn phase(&self) -> HoldPhase {
+        self.phase
+    }
+
+    /// Passes `audio_data`, drawn at `now`, through, or swaps it for the held frame if its
+    /// published RMS level `level_db` is silent. Returns the share of full brightness to draw
+    /// it at.
+    pub fn apply(&mut self, audio_data: &mut AudioData, level_db: f32, now: Instant) -> f32 {
+        if !self.config.enabled {
+            return 1.0;
+        }
+        let elapsed = self
+            .last_frame
+            .map_or(Duration::ZERO, |last| now.saturating_duration_since(last));
+        self.last_frame = Some(now);
+
+        if level_db > self.config.threshold_db {
+            let count = audio_data.count();
+            self.held.clear();
+            self.held.extend_from_slice(&audio_data.samples[..count]);
+            self.held_right.clear();
+            self.held_right
+                .extend_from_slice(&audio_data.right_samples[..count]);
+            self.phase = HoldPhase::Live;
+            return 1.0;
+        }
+
+        self.phase = self.next_phase(now, elapsed);
+        let brightness = match self.phase {
+            HoldPhase::Live => return 1.0,
+            HoldPhase::Holding { .. } => 1.0,
+            HoldPhase::FadingOut { progress } => 1.0 - progress,
+        };
+        audio_data.set_samples(&self.held, &self.held_right);
+        brightness
+    }
+
+    /// Where a silent frame at `now`, `elapsed` after the last frame, takes the hold.
+    fn next_phase(&mut self, now: Instant, elapsed: Duration) -> HoldPhase {
+        let fade = self.config.fade.max(0.0);
+        match self.phase {
+            HoldPhase::Live if self.held.is_empty() => HoldPhase::Live,
+            HoldPhase::Live => HoldPhase::Holding { since: now },
+            HoldPhase::Holding { since } => {
+                let silent_for = now.saturating_duration_since(since).as_secs_f32();
+                let fading_for = silent_for - self.config.hold.max(0.0);
+                if fading_for < 0.0 {
+                    HoldPhase::Holding { since }
+                } else if fading_for < fade {
+                    HoldPhase::FadingOut {
+                        progress: fading_for / fade,
+                    }
+                } else {
+                    self.finish()
+                }
+            }
+            HoldPhase::FadingOut { progress } => {
+                let progress = progress + elapsed.as_secs_f32() / fade;
+                if progress < 1.0 {
+                    HoldPhase::FadingOut { progress }
+                } else {
+                    self.finish()
+                }
+            }
+        }
+    }
+
+    /// Lets go of the held frame once it has faded out.
+    fn finish(&mut self) -> HoldPhase {
+        self.held.clear();
+        self.held_right.clear();
+        HoldPhase::Live
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame of the visualizer at 60 fps.
+    const FRAME: Duration = Duration::from_micros(16_667);
+    /// Published levels of a frame with sound in it and of a silent one.
+    const LOUD: f32 = -12.0;
+    const SILENT: f32 = f32::NEG_INFINITY;
+
+    fn config() -> SilenceHoldConfig {
+        SilenceHoldConfig {
+            enabled: true,
+            threshold_db: -60.0,
+            hold: 0.1,
+            fade: 0.5,
+        }
+    }
+
+    fn frame(level: f32) -> AudioData {
+        let mut audio_data = AudioData::default();
+        let samples: Vec<f32> = (0..64).map(|i| level * (i as f32 * 0.3).sin()).collect();
+        audio_data.set_samples(&samples, &samples);
+        audio_data
+    }
+
+    /// Where the hold is, without the details each phase carries.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Phase {
+        Live,
+        Holding,
+        FadingOut,
+    }
+
+    fn phase(hold: &SilenceHold) -> Phase {
+        match hold.phase() {
+            HoldPhase::Live => Phase::Live,
+            HoldPhase::Holding { .. } => Phase::Holding,
+            HoldPhase::FadingOut { .. } => Phase::FadingOut,
+        }
+    }
+
+    /// Feeds `levels` one frame apart from `start`, each with a frame of `shape` samples, and
+    /// returns the phase and brightness after each.
+    fn run(
+        hold: &mut SilenceHold,
+        levels: &[f32],
+        shape: f32,
+        start: Instant,
+    ) -> Vec<(Phase, f32)> {
+        levels
+            .iter()
+            .enumerate()
+            .map(|(index, &level)| {
+                let brightness = hold.apply(&mut frame(shape), level, start + FRAME * index as u32);
+                (phase(hold), brightness)
+            })
+            .collect()
+    }
+
+    /// The phases `steps` went through, each once however many frames it lasted.
+    fn phases(steps: &[(Phase, f32)]) -> Vec<Phase> {
+        let mut phases: Vec<Phase> = steps.iter().map(|(phase, _)| *phase).collect();
+        phases.dedup();
+        phases
+    }
+
+    #[test]
+    fn the_last_sound_fades_out_over_the_configured_time_once_silence_begins() {
+        let mut hold = SilenceHold::new(config());
+        let start = Instant::now();
+        let loud = frame(0.5);
+        assert_eq!(hold.apply(&mut frame(0.5), LOUD, start), 1.0);
+
+        let mut brightness = Vec::new();
+        for index in 1..=60 {
+            let mut audio_data = frame(0.0);
+            brightness.push(hold.apply(&mut audio_data, SILENT, start + FRAME * index));
+            if hold.phase() != HoldPhase::Live {
+                // The held frame is drawn in place of the silence.
+                assert_eq!(audio_data.samples, loud.samples);
+            }
+        }
+        // Full brightness for the hold, a tenth of a second, then down evenly to nothing over
+        // the half second fade.
+        let holding = brightness.iter().take_while(|&&b| b == 1.0).count();
+        assert!((6..=7).contains(&holding), "{}", holding);
+        let fading = &brightness[holding..];
+        let faded_for = fading.iter().take_while(|&&b| b < 1.0).count();
+        assert!((29..=31).contains(&faded_for), "{}", faded_for);
+        assert!(fading[..faded_for].windows(2).all(|pair| pair[1] < pair[0]));
+        // Afterwards silence is drawn as the flat line it is.
+        assert_eq!(hold.phase(), HoldPhase::Live);
+        let mut audio_data = frame(0.0);
+        assert_eq!(hold.apply(&mut audio_data, SILENT, start + FRAME * 61), 1.0);
+        assert!(audio_data.samples.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn silence_goes_from_live_to_holding_to_fading_and_back_to_live() {
+        let mut hold = SilenceHold::new(config());
+        let mut levels = vec![LOUD; 3];
+        levels.extend([SILENT; 60]);
+        let steps = run(&mut hold, &levels, 0.5, Instant::now());
+        assert_eq!(
+            phases(&steps),
+            [Phase::Live, Phase::Holding, Phase::FadingOut, Phase::Live]
+        );
+    }
+
+    #[test]
+    fn a_blip_during_the_fade_returns_to_live_at_once() {
+        let mut hold = SilenceHold::new(config());
+        let start = Instant::now();
+        let mut levels = vec![LOUD];
+        levels.extend([SILENT; 20]);
+        let steps = run(&mut hold, &levels, 0.5, start);
+        assert_eq!(steps.last().unwrap().0, Phase::FadingOut);
+
+        // One frame of sound is drawn live, as it is, at full brightness.
+        let blip_at = start + FRAME * levels.len() as u32;
+        let mut blip = frame(0.2);
+        assert_eq!(hold.apply(&mut blip, LOUD, blip_at), 1.0);
+        assert_eq!(phase(&hold), Phase::Live);
+        assert_eq!(blip.samples, frame(0.2).samples);
+
+        // The blip is what's held through the silence after it, from full brightness again.
+        let mut audio_data = frame(0.0);
+        assert_eq!(hold.apply(&mut audio_data, SILENT, blip_at + FRAME), 1.0);
+        assert_eq!(phase(&hold), Phase::Holding);
+        assert_eq!(audio_data.samples, frame(0.2).samples);
+    }
+
+    #[test]
+    fn a_blip_during_the_hold_starts_the_hold_over() {
+        let mut hold = SilenceHold::new(config());
+        let mut levels = vec![LOUD];
+        levels.extend([SILENT; 4]);
+        levels.push(LOUD);
+        levels.extend([SILENT; 6]);
+        let steps = run(&mut hold, &levels, 0.5, Instant::now());
+        assert_eq!(steps[5], (Phase::Live, 1.0));
+        // Six silent frames after the blip are still inside a fresh tenth of a second's hold.
+        assert!(steps[6..].iter().all(|&step| step == (Phase::Holding, 1.0)));
+    }
+
+    #[test]
+    fn the_published_level_decides_silence_not_the_drawn_samples() {
+        let mut hold = SilenceHold::new(config());
+        let start = Instant::now();
+        hold.apply(&mut frame(0.5), LOUD, start);
+
+        // Samples that look loud are still silence when the level published with them is.
+        let mut audio_data = frame(0.3);
+        hold.apply(&mut audio_data, SILENT, start + FRAME);
+        assert_eq!(phase(&hold), Phase::Holding);
+        assert_eq!(audio_data.samples, frame(0.5).samples);
+
+        // A frame drawn flat, as when downsampling averages a high note away, is live when
+        // the published level says there is sound.
+        let mut audio_data = frame(0.0);
+        assert_eq!(hold.apply(&mut audio_data, LOUD, start + FRAME * 2), 1.0);
+        assert_eq!(phase(&hold), Phase::Live);
+        assert!(audio_data.samples.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn a_level_on_the_threshold_counts_as_silence() {
+        let mut hold = SilenceHold::new(config());
+        let steps = run(&mut hold, &[LOUD, -60.0, -59.9], 0.5, Instant::now());
+        assert_eq!(phases(&steps), [Phase::Live, Phase::Holding, Phase::Live]);
+    }
+
+    #[test]
+    fn silence_with_nothing_heard_before_it_is_drawn_live() {
+        let mut hold = SilenceHold::new(config());
+        let steps = run(&mut hold, &[SILENT; 30], 0.0, Instant::now());
+        assert!(steps.iter().all(|&step| step == (Phase::Live, 1.0)));
+    }
+
+    #[test]
+    fn with_no_hold_or_fade_the_sound_is_let_go_a_frame_into_silence() {
+        let mut hold = SilenceHold::new(SilenceHoldConfig {
+            hold: 0.0,
+            fade: 0.0,
+            ..config()
+        });
+        let steps = run(&mut hold, &[LOUD, SILENT, SILENT], 0.5, Instant::now());
+        assert_eq!(
+            steps,
+            [
+                (Phase::Live, 1.0),
+                (Phase::Holding, 1.0),
+                (Phase::Live, 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_silence_is_drawn_as_it_is() {
+        let mut hold = SilenceHold::new(SilenceHoldConfig::default());
+        let now = Instant::now();
+        hold.apply(&mut frame(0.5), LOUD, now);
+        let mut audio_data = frame(0.0);
+        assert_eq!(hold.apply(&mut audio_data, SILENT, now + FRAME), 1.0);
+        assert!(audio_data.samples.iter().all(|&sample| sample == 0.0));
+    }
+}
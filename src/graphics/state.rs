@@ -1,16 +1,77 @@
-use crate::graphics::{generate_waveform_vertices, Vertex};
+use crate::graphics::{
+    accessibility::FrameHistory,
+    audio_buffer::{
+        layout_channels, layout_index, pack_channels, AudioBufferBinding, AudioBufferLayout,
+        AudioBufferResize,
+    },
+    display_scale::auto_gain_indicator_vertices,
+    envelope::ENVELOPE_MAX_VERTICES,
+    help::{help_vertices, layout_help, HELP_MAX_VERTICES},
+    line_half_width,
+    note_names::{note_name_vertices, with_vanished_notes, SoundingNote, NOTE_NAMES_MAX_VERTICES},
+    ribbon::RIBBON_MAX_VERTICES,
+    select_present_mode,
+    theme::{background_vertices, Background, BACKGROUND_VERTICES},
+    AccessibilityConfig, ColorVertex, DisplayScale, DisplayScaleConfig, EnvelopeWidget, HelpConfig,
+    HelpLine, NoteNamesConfig, PresentMode, RibbonStrip, ThemeConfig, WaveformLayout,
+    MIN_WAVEFORM_POINTS,
+};
+use crate::synth::MAX_VISUAL_SAMPLES;
 use anyhow::{Context, Ok, Result};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::time::Instant;
+use tracing::{info, warn};
 use wgpu::util::DeviceExt;
-use winit::window::Window;
+use winit::{keyboard::Key, window::Window};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct Uniform {
     time: f32,
+    /// Number of `vec4` entries per channel of the audio buffer that hold samples this frame.
+    sample_count: u32,
+    /// Which `WaveformLayout` the audio buffer is packed for, from `layout_index`.
+    layout_mode: u32,
+    /// 1 when the amplitude is drawn on a dB scale down to `db_floor`.
+    db_scale: u32,
+    /// Vertical gain of the drawn waveform, including auto-gain.
+    display_gain: f32,
+    db_floor: f32,
+    /// Degrees per second the waveform's hue turns.
+    hue_rate: f32,
+    /// 1 when the waveform is drawn plain white, for high contrast.
+    high_contrast: u32,
+    /// Half the waveform line's width in NDC along x and y.
+    line_half_width: [f32; 2],
+    /// Points along each strip of the waveform line, two vertices to a point.
+    waveform_points: u32,
+    /// Least brightness the waveform dims to with the audio.
+    brightness_floor: f32,
+    /// Color of the overlaid second stream, when there is one.
+    overlay_color: [f32; 4],
+    /// Share of full brightness the waveform is drawn at.
+    brightness: f32,
+    /// Pads the struct to the 16-byte multiple WGSL rounds uniform structs up to.
+    _padding: [f32; 3],
 }
 
+/// Share of full brightness a held frame is drawn at, with nothing playing it.
+const DIMMED_WAVEFORM_BRIGHTNESS: f32 = 0.35;
+
+/// Degrees per second the waveform's hue turns, unless reduced motion stops it.
+const WAVEFORM_HUE_RATE: f32 = 100.0;
+
+/// Points along each strip of the waveform line, until the visualizer config sets it.
+const WAVEFORM_POINTS: u32 = 100;
+
+/// The samples the visualizer draws. Both channels hold `count` samples, padded with zeros to
+/// a whole number of `vec4` entries.
+#[derive(Default)]
 pub struct AudioData {
-    pub samples: [[f32; 16]; 256],
+    pub samples: Vec<f32>,
+    pub right_samples: Vec<f32>,
+    count: usize,
 }
 
 impl AudioData {
@@ -25,17 +86,46 @@ impl AudioData {
             samples.push(sample);
         }
 
-        let samples_vec4 = samples
-            .chunks_exact(4)
-            .map(|chunk| chunk.try_into().unwrap_or_else(|_| [0.0; 16]))
-            .collect::<Vec<_>>();
+        let mut audio_data = AudioData::default();
+        audio_data.set_samples(&samples, &samples);
+        audio_data
+    }
 
-        let mut samples_array = [[0.0; 16]; 256];
-        samples_array[..samples_vec4.len()].copy_from_slice(&samples_vec4);
+    /// Copies in a new block of samples for each channel. The right channel is cut or padded
+    /// with zeros to the left channel's length.
+    pub fn set_samples(&mut self, samples: &[f32], right_samples: &[f32]) {
+        let count = samples.len();
+        let padded = count.div_ceil(4) * 4;
+        let right_count = right_samples.len().min(count);
+        self.samples.clear();
+        self.samples.extend_from_slice(samples);
+        self.samples.resize(padded, 0.0);
+        self.right_samples.clear();
+        self.right_samples
+            .extend_from_slice(&right_samples[..right_count]);
+        self.right_samples.resize(padded, 0.0);
+        self.count = count;
+    }
 
-        AudioData {
-            samples: samples_array,
-        }
+    /// Number of samples per channel in use.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Number of `vec4` entries the shader reads per channel, four samples to an entry.
+    fn vec4_count(&self) -> u32 {
+        self.count.div_ceil(4) as u32
+    }
+
+    /// Packs the samples in use four to an entry, the way the shader reads them: the left
+    /// channel alone for a single waveform, followed by the right channel for a split.
+    pub fn packed(&self, layout: WaveformLayout) -> Vec<[f32; 4]> {
+        let padded = self.vec4_count() as usize * 4;
+        pack_channels(
+            layout,
+            &self.samples[..padded],
+            &self.right_samples[..padded],
+        )
     }
 }
 
@@ -47,19 +137,64 @@ pub struct State<'a> {
     #[allow(dead_code)]
     size: winit::dpi::PhysicalSize<u32>,
     render_pipeline: wgpu::RenderPipeline,
-    vertex_buffer: wgpu::Buffer,
-    num_vertices: u32,
+    /// Points along each strip of the waveform line. The shader places them from the vertex
+    /// index, so changing this needs no buffer.
+    waveform_points: u32,
+    waveform_layout: WaveformLayout,
+    /// Whether the waveform is drawn dimmed, as a held shape with nothing playing it.
+    waveform_dimmed: bool,
+    /// Share of full brightness the waveform is drawn at besides that, as the silence hold
+    /// fades it out.
+    waveform_fade: f32,
     #[allow(dead_code)]
     audio_data: AudioData,
     audio_bind_group: wgpu::BindGroup,
+    audio_bind_group_layout: wgpu::BindGroupLayout,
     audio_buffer: wgpu::Buffer,
+    audio_buffer_binding: AudioBufferBinding,
+    /// Size of `audio_buffer` in `vec4` entries.
+    audio_buffer_capacity: usize,
+    audio_buffer_layout: AudioBufferLayout,
     uniform_buffer: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
+    ribbon_pipeline: wgpu::RenderPipeline,
+    ribbon_vertex_buffer: wgpu::Buffer,
+    ribbon_strip: Option<RibbonStrip>,
+    ribbon_touch: Option<f32>,
+    envelope_vertex_buffer: wgpu::Buffer,
+    envelope_widget: Option<EnvelopeWidget>,
+    note_names_vertex_buffer: wgpu::Buffer,
+    note_names_config: NoteNamesConfig,
+    note_names_visible: bool,
+    sounding_notes: Vec<SoundingNote>,
+    /// The notes sounding as of the last frame, without the ones faded out on it.
+    live_notes: Vec<SoundingNote>,
+    help_vertex_buffer: wgpu::Buffer,
+    help_config: HelpConfig,
+    /// The lines of the help screen while it is shown.
+    help_lines: Option<Vec<HelpLine>>,
+    help_scroll: usize,
+    /// Keys held down, lit up on the help screen.
+    held_keys: HashSet<Key>,
+    background_vertex_buffer: wgpu::Buffer,
+    theme_config: ThemeConfig,
+    background: Background,
+    /// Base hue of the key being played in, and the octave shift, for the background.
+    key_hue: Option<f32>,
+    octave_shift: i32,
+    /// Width of the waveform line in screen pixels, when high contrast doesn't widen it.
+    line_width: f32,
+    accessibility: AccessibilityConfig,
+    frame_history: FrameHistory,
+    overlay_history: FrameHistory,
+    display_scale: DisplayScale,
+    /// When the last frame was drawn, for timing the auto-gain.
+    last_frame: Option<Instant>,
 }
 
 impl<'a> State<'a> {
     // Creating some of the wgpu types requires async code
-    pub async fn new(window: &'a Window) -> Result<Self> {
+    pub async fn new(window: &'a Window, present_mode: PresentMode) -> Result<Self> {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
@@ -112,7 +247,7 @@ impl<'a> State<'a> {
             format: surface_format,
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode: select_present_mode(present_mode, &surface_caps.present_modes),
             alpha_mode: surface_caps.alpha_modes[0],
             view_formats: vec![],
             desired_maximum_frame_latency: 2,
@@ -125,11 +260,21 @@ impl<'a> State<'a> {
         let duration = 1.0;
         let audio_data = AudioData::new(duration);
 
-        let audio_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Audio Buffer"),
-            contents: bytemuck::cast_slice(&audio_data.samples),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
+        // Storage buffers aren't readable from vertex shaders everywhere (WebGL2 notably), so
+        // those adapters get a fixed-size uniform array instead.
+        let audio_buffer_binding = AudioBufferBinding::for_adapter(&adapter);
+        if audio_buffer_binding == AudioBufferBinding::Uniform {
+            info!("No vertex storage buffers on this adapter; using a uniform audio buffer");
+        }
+        let audio_buffer_layout = AudioBufferLayout {
+            mode: WaveformLayout::Single,
+            samples_per_channel: MAX_VISUAL_SAMPLES,
+            streams: 1,
+        };
+        let audio_buffer_capacity =
+            audio_buffer_binding.capacity_for(audio_buffer_layout.entries());
+        let audio_buffer =
+            Self::create_audio_buffer(&device, audio_buffer_binding, audio_buffer_capacity);
 
         let audio_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -137,7 +282,7 @@ impl<'a> State<'a> {
                     binding: 0,
                     visibility: wgpu::ShaderStages::VERTEX,
                     ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
+                        ty: audio_buffer_binding.buffer_binding_type(),
                         has_dynamic_offset: false,
                         min_binding_size: None,
                     },
@@ -146,14 +291,8 @@ impl<'a> State<'a> {
                 label: Some("audio_bind_group_layout"),
             });
 
-        let audio_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &audio_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: audio_buffer.as_entire_binding(),
-            }],
-            label: Some("audio_bind_group"),
-        });
+        let audio_bind_group =
+            Self::create_audio_bind_group(&device, &audio_bind_group_layout, &audio_buffer);
 
         let uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -172,7 +311,22 @@ impl<'a> State<'a> {
 
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Uniform Buffer"),
-            contents: bytemuck::cast_slice(&[Uniform { time: 0.0 }]),
+            contents: bytemuck::cast_slice(&[Uniform {
+                time: 0.0,
+                sample_count: audio_data.vec4_count(),
+                layout_mode: layout_index(audio_buffer_layout.mode),
+                db_scale: 0,
+                display_gain: 1.0,
+                db_floor: 0.0,
+                hue_rate: WAVEFORM_HUE_RATE,
+                high_contrast: 0,
+                line_half_width: line_half_width(1.0, size.width as f32, size.height as f32),
+                waveform_points: WAVEFORM_POINTS,
+                brightness_floor: 0.0,
+                overlay_color: [0.0; 4],
+                brightness: 1.0,
+                _padding: [0.0; 3],
+            }]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
@@ -185,7 +339,10 @@ impl<'a> State<'a> {
             }],
         });
 
-        let shader = device.create_shader_module(wgpu::include_wgsl!("shader.wgsl"));
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("shader.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(audio_buffer_binding.shader_source())),
+        });
 
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -194,22 +351,14 @@ impl<'a> State<'a> {
                 push_constant_ranges: &[],
             });
 
-        let num_vertices = 100;
-        let vertices = generate_waveform_vertices(num_vertices);
-
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Vertex Buffer"),
-            contents: bytemuck::cast_slice(&vertices),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
-
         let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("Render Pipeline"),
             layout: Some(&render_pipeline_layout),
             vertex: wgpu::VertexState {
                 module: &shader,
-                entry_point: "vs_main",     // 1.
-                buffers: &[Vertex::desc()], // 2.
+                entry_point: "vs_main",
+                // The waveform's vertices are placed from the vertex index alone.
+                buffers: &[],
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader,
@@ -220,11 +369,14 @@ impl<'a> State<'a> {
                     write_mask: wgpu::ColorWrites::ALL,
                 })],
             }),
+            // The line is a triangle strip rather than a line strip, since lines can only be one
+            // pixel wide. Its triangles alternate in winding, so culling stays off or every other
+            // one would go missing.
             primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::LineStrip,
+                topology: wgpu::PrimitiveTopology::TriangleStrip,
                 strip_index_format: None,
                 front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
+                cull_mode: None,
                 polygon_mode: wgpu::PolygonMode::Fill,
                 unclipped_depth: false,
                 conservative: false,
@@ -238,6 +390,91 @@ impl<'a> State<'a> {
             multiview: None,
         });
 
+        // The ribbon strip is flat-colored geometry drawn underneath the waveform.
+        let ribbon_shader = device.create_shader_module(wgpu::include_wgsl!("ribbon.wgsl"));
+
+        let ribbon_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Ribbon Pipeline Layout"),
+                bind_group_layouts: &[],
+                push_constant_ranges: &[],
+            });
+
+        let ribbon_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Ribbon Pipeline"),
+            layout: Some(&ribbon_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &ribbon_shader,
+                entry_point: "vs_main",
+                buffers: &[ColorVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &ribbon_shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: config.format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        });
+
+        let ribbon_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Ribbon Vertex Buffer"),
+            size: (RIBBON_MAX_VERTICES * std::mem::size_of::<ColorVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let envelope_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Envelope Vertex Buffer"),
+            size: (ENVELOPE_MAX_VERTICES * std::mem::size_of::<ColorVertex>())
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // Note names are flat-colored quads too, so they share the ribbon pipeline.
+        let note_names_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Note Names Vertex Buffer"),
+            size: (NOTE_NAMES_MAX_VERTICES * std::mem::size_of::<ColorVertex>())
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let help_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Help Vertex Buffer"),
+            size: (HELP_MAX_VERTICES * std::mem::size_of::<ColorVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // The background gradient is one more flat-colored quad, drawn first.
+        let background_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Background Vertex Buffer"),
+            size: (BACKGROUND_VERTICES * std::mem::size_of::<ColorVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let note_names_config = NoteNamesConfig::default();
         Ok(State {
             surface,
             device,
@@ -245,17 +482,299 @@ impl<'a> State<'a> {
             config,
             size,
             render_pipeline,
-            vertex_buffer,
-            num_vertices: num_vertices as u32,
+            waveform_points: WAVEFORM_POINTS,
+            waveform_layout: WaveformLayout::Single,
+            waveform_dimmed: false,
+            waveform_fade: 1.0,
             audio_data,
             audio_buffer,
+            audio_buffer_binding,
+            audio_buffer_capacity,
+            audio_buffer_layout,
             audio_bind_group,
+            audio_bind_group_layout,
             uniform_buffer,
             uniform_bind_group,
+            ribbon_pipeline,
+            ribbon_vertex_buffer,
+            ribbon_strip: None,
+            ribbon_touch: None,
+            envelope_vertex_buffer,
+            envelope_widget: None,
+            note_names_vertex_buffer,
+            note_names_visible: note_names_config.visible,
+            note_names_config,
+            sounding_notes: Vec::new(),
+            live_notes: Vec::new(),
+            help_vertex_buffer,
+            help_config: HelpConfig::default(),
+            help_lines: None,
+            help_scroll: 0,
+            held_keys: HashSet::new(),
+            background_vertex_buffer,
+            theme_config: ThemeConfig::default(),
+            background: Background::default(),
+            key_hue: None,
+            octave_shift: 0,
+            line_width: 1.0,
+            accessibility: AccessibilityConfig::default(),
+            frame_history: FrameHistory::default(),
+            overlay_history: FrameHistory::default(),
+            display_scale: DisplayScale::default(),
+            last_frame: None,
+        })
+    }
+
+    fn create_audio_buffer(
+        device: &wgpu::Device,
+        binding: AudioBufferBinding,
+        capacity: usize,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Audio Buffer"),
+            size: (capacity * std::mem::size_of::<[f32; 4]>()) as wgpu::BufferAddress,
+            usage: binding.buffer_usage() | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
         })
     }
 
-    fn _resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+    fn create_audio_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        audio_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: audio_buffer.as_entire_binding(),
+            }],
+            label: Some("audio_bind_group"),
+        })
+    }
+
+    /// Makes room in the GPU audio buffer for `layout`. The buffer and its bind group are only
+    /// recreated when the layout needs more than the buffer holds; the bind group layout, and
+    /// so the pipeline, stay as they are.
+    pub fn reconfigure_audio_buffer(&mut self, layout: AudioBufferLayout) {
+        self.audio_buffer_layout = layout;
+        let capacity = match self
+            .audio_buffer_binding
+            .resize_for(self.audio_buffer_capacity, layout)
+        {
+            AudioBufferResize::Keep => return,
+            AudioBufferResize::Grow(capacity) => capacity,
+            AudioBufferResize::Full { capacity, required } => {
+                warn!(
+                    "The uniform audio buffer holds {} entries, {} needed; drawing fewer samples",
+                    capacity, required
+                );
+                return;
+            }
+        };
+        self.audio_buffer =
+            Self::create_audio_buffer(&self.device, self.audio_buffer_binding, capacity);
+        self.audio_bind_group = Self::create_audio_bind_group(
+            &self.device,
+            &self.audio_bind_group_layout,
+            &self.audio_buffer,
+        );
+        self.audio_buffer_capacity = capacity;
+    }
+
+    /// Shows the ribbon strip; it isn't drawn until this is called.
+    pub fn set_ribbon_strip(&mut self, ribbon_strip: RibbonStrip) {
+        self.ribbon_strip = Some(ribbon_strip);
+    }
+
+    /// Moves the ribbon's touch marker, or hides it with `None`.
+    pub fn set_ribbon_touch(&mut self, touch: Option<f32>) {
+        self.ribbon_touch = touch;
+    }
+
+    /// Shows the envelope editor as `envelope_widget` has it, or hides it with `None`. Called
+    /// again whenever its envelope or grabbed breakpoint changes.
+    pub fn set_envelope_widget(&mut self, envelope_widget: Option<EnvelopeWidget>) {
+        self.envelope_widget = envelope_widget;
+    }
+
+    /// Draws the waveform dimmed, as for a held shape with nothing playing it, or at full
+    /// brightness again.
+    pub fn set_waveform_dimmed(&mut self, dimmed: bool) {
+        self.waveform_dimmed = dimmed;
+    }
+
+    /// Fades the waveform to `brightness` of full, from 0.0 to 1.0, on top of any dimming.
+    pub fn set_waveform_fade(&mut self, brightness: f32) {
+        self.waveform_fade = brightness.clamp(0.0, 1.0);
+    }
+
+    /// Switches between one waveform across the window and a left/right split.
+    pub fn set_waveform_layout(&mut self, layout: WaveformLayout) {
+        self.waveform_layout = layout;
+        self.reconfigure_audio_buffer(AudioBufferLayout {
+            mode: layout,
+            ..self.audio_buffer_layout
+        });
+    }
+
+    /// Sets how many points each strip of the waveform line is drawn with, from the next frame.
+    pub fn set_waveform_points(&mut self, points: usize) {
+        self.waveform_points = points.clamp(MIN_WAVEFORM_POINTS, u32::MAX as usize / 4) as u32;
+    }
+
+    /// Sets how the note name overlay looks, and whether it is shown.
+    pub fn set_note_names_config(&mut self, config: NoteNamesConfig) {
+        self.note_names_visible = config.visible;
+        self.note_names_config = config;
+    }
+
+    pub fn toggle_note_names(&mut self) {
+        self.note_names_visible = !self.note_names_visible;
+    }
+
+    /// Replaces the notes shown by the note name overlay.
+    /// Sets the notes sounding as of this frame. Notes that were sounding last frame and no
+    /// longer are faded out over this one.
+    pub fn set_sounding_notes(&mut self, sounding_notes: Vec<SoundingNote>) {
+        self.sounding_notes = with_vanished_notes(&self.live_notes, sounding_notes.clone());
+        self.live_notes = sounding_notes;
+    }
+
+    pub fn set_help_config(&mut self, config: HelpConfig) {
+        self.help_config = config;
+    }
+
+    /// Shows the help screen with `lines`, from the top, or hides it if it is already shown.
+    pub fn toggle_help(&mut self, lines: Vec<HelpLine>) {
+        self.help_lines = match self.help_lines {
+            Some(_) => None,
+            None => Some(lines),
+        };
+        self.help_scroll = 0;
+    }
+
+    pub fn help_visible(&self) -> bool {
+        self.help_lines.is_some()
+    }
+
+    /// Scrolls the help screen by `rows`, down for positive values, staying within the list.
+    pub fn scroll_help(&mut self, rows: i32) {
+        let Some(lines) = &self.help_lines else {
+            return;
+        };
+        let max_scroll = layout_help(
+            lines,
+            self.config.width as f32,
+            self.config.height as f32,
+            self.help_config.size,
+        )
+        .max_scroll();
+        self.help_scroll = self
+            .help_scroll
+            .saturating_add_signed(rows as isize)
+            .min(max_scroll);
+    }
+
+    /// Notes `key` as held down or let go, for the help screen's highlighting.
+    pub fn set_key_held(&mut self, key: Key, held: bool) {
+        if held {
+            self.held_keys.insert(key);
+        } else {
+            self.held_keys.remove(&key);
+        }
+    }
+
+    pub fn set_theme_config(&mut self, config: ThemeConfig) {
+        self.theme_config = config;
+    }
+
+    /// Sets the key and octave shift being played in, which the background's color follows.
+    pub fn set_background_key(&mut self, root_note: &str, octave_shift: i32) {
+        self.key_hue = self.theme_config.palette.hue(root_note);
+        self.octave_shift = octave_shift;
+    }
+
+    /// Sets the waveform line's width in screen pixels.
+    pub fn set_line_width(&mut self, line_width: f32) {
+        self.line_width = line_width;
+    }
+
+    pub fn set_accessibility_config(&mut self, config: AccessibilityConfig) {
+        self.accessibility = config;
+    }
+
+    pub fn accessibility(&self) -> &AccessibilityConfig {
+        &self.accessibility
+    }
+
+    pub fn toggle_high_contrast(&mut self) {
+        self.accessibility.high_contrast = !self.accessibility.high_contrast;
+        info!(
+            "High contrast {}",
+            if self.accessibility.high_contrast {
+                "on"
+            } else {
+                "off"
+            }
+        );
+    }
+
+    pub fn toggle_reduced_motion(&mut self) {
+        self.accessibility.reduced_motion = !self.accessibility.reduced_motion;
+        info!(
+            "Reduced motion {}",
+            if self.accessibility.reduced_motion {
+                "on"
+            } else {
+                "off"
+            }
+        );
+    }
+
+    /// With reduced motion on, averages `audio_data` and any `overlay_data` drawn over it
+    /// over the last few frames drawn so the waveforms move more slowly. Otherwise passes them
+    /// through.
+    pub fn limit_motion(
+        &mut self,
+        audio_data: &mut AudioData,
+        overlay_data: Option<&mut AudioData>,
+    ) {
+        if !self.accessibility.reduced_motion {
+            self.frame_history.clear();
+            self.overlay_history.clear();
+            return;
+        }
+        self.frame_history
+            .apply(audio_data, self.accessibility.history_frames);
+        match overlay_data {
+            Some(overlay_data) => self
+                .overlay_history
+                .apply(overlay_data, self.accessibility.history_frames),
+            None => self.overlay_history.clear(),
+        }
+    }
+
+    /// Sets how the waveform is scaled vertically, resetting the display gain and auto-gain.
+    pub fn set_display_scale_config(&mut self, config: DisplayScaleConfig) {
+        self.display_scale = DisplayScale::new(config);
+    }
+
+    pub fn display_gain_up(&mut self) {
+        self.display_scale.gain_up();
+    }
+
+    pub fn display_gain_down(&mut self) {
+        self.display_scale.gain_down();
+    }
+
+    pub fn toggle_auto_gain(&mut self) {
+        self.display_scale.toggle_auto_gain();
+    }
+
+    /// Reconfigures the surface for the window's new size. A minimized window reports a zero
+    /// size, which the surface can't take, so that is ignored.
+    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
             self.config.width = new_size.width;
@@ -272,21 +791,22 @@ impl<'a> State<'a> {
         todo!("todo: State::update()")
     }
 
-    pub async fn render(&mut self, window: &'a Window, audio_data: &AudioData) -> Result<()> {
-        // Check if window size has changed
-        let current_size = window.inner_size();
-
-        if self.config.width != current_size.width || self.config.height != current_size.height {
-            // Get the new aspect ratio for the camera later
-            let _new_aspect_ratio = current_size.width as f32 / current_size.height as f32;
-
-            // Update the surface configuration with the new size
-            self.config.width = current_size.width;
-            self.config.height = current_size.height;
-            self.surface.configure(&self.device, &self.config);
-        }
-
-        let output = self.surface.get_current_texture()?;
+    /// Draws a frame, with `overlay_data` drawn over the waveform in the theme's overlay color
+    /// when it has as many samples. Resizes reach the surface through `resize` beforehand.
+    pub fn render(
+        &mut self,
+        audio_data: &AudioData,
+        overlay_data: Option<&AudioData>,
+    ) -> Result<()> {
+        let output = match self.surface.get_current_texture() {
+            // The surface goes stale when the window changes under it; set it up again and
+            // skip this frame.
+            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                self.surface.configure(&self.device, &self.config);
+                return Ok(());
+            }
+            texture => texture?,
+        };
         let view = output
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
@@ -297,21 +817,219 @@ impl<'a> State<'a> {
                 label: Some("Render Encoder"),
             });
 
-        // Write the updated audio data to the audio buffer
-        self.queue.write_buffer(
-            &self.audio_buffer,
-            0,
-            bytemuck::cast_slice(&audio_data.samples),
-        );
+        // Write the updated audio data to the audio buffer, growing it first if this frame
+        // holds more samples than it was sized for. An overlay's samples follow the waveform's,
+        // laid out the same way.
+        let mode = self.audio_buffer_layout.mode;
+        let overlay_data =
+            overlay_data.filter(|overlay_data| overlay_data.count() == audio_data.count());
+        let streams = 1 + overlay_data.is_some() as usize;
+        let mut packed = audio_data.packed(mode);
+        if let Some(overlay_data) = overlay_data {
+            packed.extend(overlay_data.packed(mode));
+        }
+        if packed.len() > self.audio_buffer_capacity {
+            self.reconfigure_audio_buffer(AudioBufferLayout {
+                mode,
+                samples_per_channel: audio_data.count(),
+                streams,
+            });
+        }
+        let channels = layout_channels(mode) * streams;
+        let sample_count =
+            (audio_data.vec4_count() as usize).min(self.audio_buffer_capacity / channels);
+        if sample_count < audio_data.vec4_count() as usize {
+            // Only the uniform fallback can't grow; keep the most recent end of each channel.
+            let per_channel = audio_data.vec4_count() as usize;
+            packed = packed
+                .chunks(per_channel)
+                .flat_map(|channel| channel[per_channel - sample_count..].iter().copied())
+                .collect();
+        }
+        self.queue
+            .write_buffer(&self.audio_buffer, 0, bytemuck::cast_slice(&packed));
+
+        // The display gain follows this frame's peak. Long gaps, such as a minimized window,
+        // count as one typical frame so auto-gain doesn't jump.
+        let now = Instant::now();
+        let dt = self
+            .last_frame
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f32().min(0.25));
+        self.last_frame = Some(now);
+        let drawn_channels: &[&[f32]] = match mode {
+            WaveformLayout::Single => &[&audio_data.samples],
+            WaveformLayout::Split => &[&audio_data.samples, &audio_data.right_samples],
+        };
+        let peak = drawn_channels
+            .iter()
+            .flat_map(|samples| samples[..audio_data.count()].iter())
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        let display_gain = self.display_scale.update(peak, dt);
+
+        // The background's color follows the key, drifting slowly and brightening with the
+        // level of the audio on screen.
+        // High contrast keeps the background plain black behind a white waveform.
+        let high_contrast = self.accessibility.high_contrast;
+        let background_hue = self
+            .key_hue
+            .filter(|_| self.theme_config.background && !high_contrast);
+        if let Some(key_hue) = background_hue {
+            let (sum, count) = drawn_channels
+                .iter()
+                .flat_map(|samples| samples[..audio_data.count()].iter())
+                .fold((0.0f32, 0usize), |(sum, count), sample| {
+                    (sum + sample * sample, count + 1)
+                });
+            let rms = (sum / count.max(1) as f32).sqrt();
+            let [top, bottom] = self.background.update(
+                &self.theme_config,
+                &self.accessibility,
+                key_hue,
+                self.octave_shift,
+                rms,
+                dt,
+            );
+            self.queue.write_buffer(
+                &self.background_vertex_buffer,
+                0,
+                bytemuck::cast_slice(&background_vertices(top, bottom)),
+            );
+        }
+        let db_floor = self.display_scale.db_floor();
+
+        // Reduced motion stops the waveform's hue cycling and keeps its brightness from
+        // flickering with the audio.
+        let reduced_motion = self.accessibility.reduced_motion;
+        let line_width = if high_contrast {
+            self.accessibility
+                .high_contrast_line_width
+                .max(self.line_width)
+        } else {
+            self.line_width
+        };
 
         // Get the current time and write it to the uniform buffer
         let time = std::time::Instant::now().elapsed().as_secs_f32();
         self.queue.write_buffer(
             &self.uniform_buffer,
             0,
-            bytemuck::cast_slice(&[Uniform { time }]),
+            bytemuck::cast_slice(&[Uniform {
+                time,
+                sample_count: sample_count as u32,
+                layout_mode: layout_index(mode),
+                db_scale: db_floor.is_some() as u32,
+                display_gain,
+                db_floor: db_floor.unwrap_or(0.0),
+                hue_rate: if reduced_motion {
+                    0.0
+                } else {
+                    WAVEFORM_HUE_RATE
+                },
+                high_contrast: high_contrast as u32,
+                line_half_width: line_half_width(
+                    line_width,
+                    self.config.width as f32,
+                    self.config.height as f32,
+                ),
+                waveform_points: self.waveform_points,
+                brightness_floor: if reduced_motion {
+                    1.0 - self.accessibility.waveform_brightness_range
+                } else {
+                    0.0
+                },
+                overlay_color: {
+                    let [r, g, b] = self.theme_config.overlay_color;
+                    [r, g, b, 1.0]
+                },
+                brightness: if self.waveform_dimmed {
+                    DIMMED_WAVEFORM_BRIGHTNESS * self.waveform_fade
+                } else {
+                    self.waveform_fade
+                },
+                _padding: [0.0; 3],
+            }]),
         );
 
+        // Rebuild the ribbon geometry so the marker follows the current touch position
+        let ribbon_vertices = self
+            .ribbon_strip
+            .map(|strip| strip.vertices(self.ribbon_touch))
+            .unwrap_or_default();
+        if !ribbon_vertices.is_empty() {
+            self.queue.write_buffer(
+                &self.ribbon_vertex_buffer,
+                0,
+                bytemuck::cast_slice(&ribbon_vertices),
+            );
+        }
+
+        // Lay the note names out against the current surface size, so they follow resizes. The
+        // auto-gain label shares their buffer.
+        let mut overlay_vertices = if self.note_names_visible {
+            note_name_vertices(
+                &self.sounding_notes,
+                &self.note_names_config,
+                self.config.width as f32,
+                self.config.height as f32,
+            )
+        } else {
+            Vec::new()
+        };
+        if self.display_scale.auto_gain_enabled() {
+            let [r, g, b] = self.note_names_config.color;
+            overlay_vertices.extend(auto_gain_indicator_vertices(
+                self.config.width as f32,
+                self.config.height as f32,
+                self.note_names_config.size,
+                [r, g, b, 0.6],
+            ));
+            overlay_vertices.truncate(NOTE_NAMES_MAX_VERTICES);
+        }
+        if !overlay_vertices.is_empty() {
+            self.queue.write_buffer(
+                &self.note_names_vertex_buffer,
+                0,
+                bytemuck::cast_slice(&overlay_vertices),
+            );
+        }
+
+        // The envelope editor sits in its corner over the waveform, placed afresh each frame so
+        // it follows resizes.
+        let mut envelope_vertices = self
+            .envelope_widget
+            .as_ref()
+            .map(|widget| widget.vertices(self.size))
+            .unwrap_or_default();
+        envelope_vertices.truncate(ENVELOPE_MAX_VERTICES);
+        if !envelope_vertices.is_empty() {
+            self.queue.write_buffer(
+                &self.envelope_vertex_buffer,
+                0,
+                bytemuck::cast_slice(&envelope_vertices),
+            );
+        }
+
+        // The help screen goes over everything else, laid out afresh each frame so it follows
+        // resizes.
+        let help_vertices = match &self.help_lines {
+            Some(lines) => help_vertices(
+                lines,
+                &self.held_keys,
+                self.help_scroll,
+                &self.help_config,
+                self.config.width as f32,
+                self.config.height as f32,
+            ),
+            None => Vec::new(),
+        };
+        if !help_vertices.is_empty() {
+            self.queue.write_buffer(
+                &self.help_vertex_buffer,
+                0,
+                bytemuck::cast_slice(&help_vertices),
+            );
+        }
+
         // Begin the render pass
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -320,11 +1038,15 @@ impl<'a> State<'a> {
                     view: &view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
+                        load: wgpu::LoadOp::Clear(if high_contrast {
+                            wgpu::Color::BLACK
+                        } else {
+                            wgpu::Color {
+                                r: 0.1,
+                                g: 0.2,
+                                b: 0.3,
+                                a: 1.0,
+                            }
                         }),
                         store: wgpu::StoreOp::Store,
                     },
@@ -334,11 +1056,47 @@ impl<'a> State<'a> {
                 timestamp_writes: None,
             });
 
+            if background_hue.is_some() {
+                render_pass.set_pipeline(&self.ribbon_pipeline);
+                render_pass.set_vertex_buffer(0, self.background_vertex_buffer.slice(..));
+                render_pass.draw(0..BACKGROUND_VERTICES as u32, 0..1);
+            }
+
+            if !ribbon_vertices.is_empty() {
+                render_pass.set_pipeline(&self.ribbon_pipeline);
+                render_pass.set_vertex_buffer(0, self.ribbon_vertex_buffer.slice(..));
+                render_pass.draw(0..ribbon_vertices.len() as u32, 0..1);
+            }
+
             render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_bind_group(0, &self.audio_bind_group, &[]);
             render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-            render_pass.draw(0..self.num_vertices, 0..1);
+            // Each strip is drawn on its own, so the split halves aren't joined in the middle.
+            // The shader takes a strip's channel from where its vertex indices start. The
+            // overlay is a second instance of every strip, drawn on top.
+            let per_strip = 2 * self.waveform_points;
+            for channel in 0..layout_channels(self.waveform_layout) as u32 {
+                let strip_start = channel * per_strip;
+                render_pass.draw(strip_start..strip_start + per_strip, 0..streams as u32);
+            }
+
+            if !overlay_vertices.is_empty() {
+                render_pass.set_pipeline(&self.ribbon_pipeline);
+                render_pass.set_vertex_buffer(0, self.note_names_vertex_buffer.slice(..));
+                render_pass.draw(0..overlay_vertices.len() as u32, 0..1);
+            }
+
+            if !envelope_vertices.is_empty() {
+                render_pass.set_pipeline(&self.ribbon_pipeline);
+                render_pass.set_vertex_buffer(0, self.envelope_vertex_buffer.slice(..));
+                render_pass.draw(0..envelope_vertices.len() as u32, 0..1);
+            }
+
+            if !help_vertices.is_empty() {
+                render_pass.set_pipeline(&self.ribbon_pipeline);
+                render_pass.set_vertex_buffer(0, self.help_vertex_buffer.slice(..));
+                render_pass.draw(0..help_vertices.len() as u32, 0..1);
+            }
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
@@ -347,3 +1105,38 @@ impl<'a> State<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_data_hands_the_shader_exactly_count_samples() {
+        let mut audio_data = AudioData::default();
+        let samples: Vec<f32> = (1..=10).map(|i| i as f32).collect();
+        audio_data.set_samples(&samples, &samples);
+        assert_eq!(audio_data.count(), 10);
+        assert_eq!(audio_data.vec4_count(), 3);
+        let packed = audio_data.packed(WaveformLayout::Single);
+        assert_eq!(packed.len(), 3);
+        assert_eq!(packed[2], [9.0, 10.0, 0.0, 0.0]);
+
+        // A shorter frame leaves nothing of the longer one behind.
+        audio_data.set_samples(&samples[..5], &samples[..5]);
+        assert_eq!(audio_data.count(), 5);
+        assert_eq!(
+            audio_data.packed(WaveformLayout::Single),
+            [[1.0, 2.0, 3.0, 4.0], [5.0, 0.0, 0.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn render_is_callable_from_non_async_code() {
+        // Checked when this compiles: an async `render` would return a future, not a `Result`,
+        // and wouldn't coerce to this plain function pointer.
+        fn plain_render<'w>() -> fn(&mut State<'w>, &AudioData, Option<&AudioData>) -> Result<()> {
+            State::<'w>::render
+        }
+        let _ = plain_render();
+    }
+}
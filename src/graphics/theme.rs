@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::graphics::accessibility::{limit_hue_rate, limit_rate};
+use crate::graphics::{AccessibilityConfig, ColorVertex};
+use crate::synth::keys::keys::{parse_note_name, NOTE_SEQUENCE};
+
+/// Vertices in the background gradient: one quad.
+pub const BACKGROUND_VERTICES: usize = 6;
+
+/// Pitch class of a root note, named either as in `NOTE_SEQUENCE` (`"C_SHARP"`) or in pitch
+/// notation without an octave (`"C#"`, `"Db"`).
+fn pitch_class(name: &str) -> Option<usize> {
+    if let Some(index) = NOTE_SEQUENCE.iter().position(|&note| note == name) {
+        return Some(index % 12);
+    }
+    parse_note_name(&format!("{}4", name.trim())).map(|note| note.rem_euclid(12) as usize)
+}
+
+/// Moves `t` of the way from hue `from` to hue `to`, in degrees, going the short way around
+/// the color wheel. The result is kept within 0 to 360.
+pub fn lerp_hue(from: f32, to: f32, t: f32) -> f32 {
+    let mut difference = (to - from).rem_euclid(360.0);
+    if difference > 180.0 {
+        difference -= 360.0;
+    }
+    (from + difference * t).rem_euclid(360.0)
+}
+
+/// A base hue for each of the 12 keys.
+///
+/// In the settings it's a map from root note to hue in degrees. Keys left out get a hue
+/// interpolated between the nearest keys either side that are in the map, wrapping from B back
+/// round to C; an empty map spreads the hues evenly, one semitone to 30°.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "BTreeMap<String, f32>", into = "BTreeMap<String, f32>")]
+pub struct PaletteMap {
+    hues: [f32; 12],
+}
+
+impl Default for PaletteMap {
+    fn default() -> Self {
+        PaletteMap {
+            hues: std::array::from_fn(|pitch_class| pitch_class as f32 * 30.0),
+        }
+    }
+}
+
+impl PaletteMap {
+    /// Fills in the keys missing from `entries`, indexed by pitch class.
+    pub fn from_partial(entries: &[Option<f32>; 12]) -> Self {
+        let known: Vec<usize> = (0..12).filter(|&index| entries[index].is_some()).collect();
+        if known.is_empty() {
+            return PaletteMap::default();
+        }
+
+        let hues = std::array::from_fn(|index| {
+            if let Some(hue) = entries[index] {
+                return hue.rem_euclid(360.0);
+            }
+            // The nearest mapped keys below and above, counting round the octave.
+            let below = (1..12)
+                .map(|step| (index + 12 - step) % 12)
+                .find(|&other| entries[other].is_some())
+                .unwrap_or(known[0]);
+            let above = (1..12)
+                .map(|step| (index + step) % 12)
+                .find(|&other| entries[other].is_some())
+                .unwrap_or(known[0]);
+            let span = (above + 12 - below) % 12;
+            let span = if span == 0 { 12 } else { span };
+            let offset = (index + 12 - below) % 12;
+            lerp_hue(
+                entries[below].unwrap_or(0.0),
+                entries[above].unwrap_or(0.0),
+                offset as f32 / span as f32,
+            )
+        });
+        PaletteMap { hues }
+    }
+
+    /// Base hue of `root_note`, or `None` for a name that isn't a note.
+    pub fn hue(&self, root_note: &str) -> Option<f32> {
+        pitch_class(root_note).map(|pitch_class| self.hues[pitch_class])
+    }
+}
+
+impl TryFrom<BTreeMap<String, f32>> for PaletteMap {
+    type Error = String;
+
+    fn try_from(map: BTreeMap<String, f32>) -> Result<Self, Self::Error> {
+        let mut entries = [None; 12];
+        for (name, hue) in map {
+            let pitch_class =
+                pitch_class(&name).ok_or_else(|| format!("Unknown palette note: {}", name))?;
+            entries[pitch_class] = Some(hue);
+        }
+        Ok(PaletteMap::from_partial(&entries))
+    }
+}
+
+impl From<PaletteMap> for BTreeMap<String, f32> {
+    fn from(palette: PaletteMap) -> Self {
+        NOTE_SEQUENCE[..12]
+            .iter()
+            .zip(palette.hues)
+            .map(|(name, hue)| (name.to_string(), hue))
+            .collect()
+    }
+}
+
+/// The background: a vertical gradient whose hue follows the key being played in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThemeConfig {
+    /// Off, the background is the plain clear color.
+    pub background: bool,
+    pub palette: PaletteMap,
+    /// Saturation and brightness of the gradient's top edge when nothing is playing, from 0 to 1.
+    pub saturation: f32,
+    pub brightness: f32,
+    /// How much the audio's RMS level adds to the saturation and brightness.
+    pub level_saturation: f32,
+    pub level_brightness: f32,
+    /// Seconds the level takes to follow the audio.
+    pub level_smoothing: f32,
+    /// Brightness of the bottom edge relative to the top.
+    pub gradient: f32,
+    /// Degrees the hue wanders either side of the key's hue, over `drift_period` seconds.
+    pub drift: f32,
+    pub drift_period: f32,
+    /// Degrees the hue turns per octave of octave shift.
+    pub octave_hue: f32,
+    /// Seconds a key change takes to fade to the new hue.
+    pub transition_time: f32,
+    /// Color of the pre-effects waveform drawn over the output with the `both` visual tap.
+    pub overlay_color: [f32; 3],
+}
+
+impl Default for ThemeConfig {
+    fn default() -> Self {
+        ThemeConfig {
+            background: false,
+            palette: PaletteMap::default(),
+            saturation: 0.5,
+            brightness: 0.3,
+            level_saturation: 0.3,
+            level_brightness: 0.6,
+            level_smoothing: 0.15,
+            gradient: 0.4,
+            drift: 15.0,
+            drift_period: 30.0,
+            octave_hue: 12.0,
+            transition_time: 1.0,
+            overlay_color: [1.0, 0.6, 0.2],
+        }
+    }
+}
+
+/// Converts a color from hue in degrees, saturation and value to RGB.
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [f32; 3] {
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    [r + m, g + m, b + m]
+}
+
+/// Keeps the background's hue and level moving smoothly from frame to frame.
+#[derive(Debug, Clone, Default)]
+pub struct Background {
+    /// The hue a key change started fading from, and the key's hue it's fading to.
+    from_hue: f32,
+    to_hue: Option<f32>,
+    /// Seconds since the last key change.
+    since_change: f32,
+    /// Seconds of drift.
+    time: f32,
+    level: f32,
+    /// The hue, saturation and brightness last drawn, which reduced motion moves on from
+    /// gradually.
+    shown: Option<[f32; 3]>,
+}
+
+impl Background {
+    /// The key's hue, partway through the fade after a key change.
+    pub fn hue(&self, config: &ThemeConfig) -> f32 {
+        let Some(to_hue) = self.to_hue else {
+            return 0.0;
+        };
+        let progress = if config.transition_time > 0.0 {
+            (self.since_change / config.transition_time).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        // Eased, so the fade neither starts nor stops abruptly.
+        let eased = progress * progress * (3.0 - 2.0 * progress);
+        lerp_hue(self.from_hue, to_hue, eased)
+    }
+
+    /// Moves on by `dt` seconds with the key's hue at `key_hue` and the audio at `rms`, and
+    /// returns the gradient's top and bottom colors as RGB. With reduced motion on, the hue and
+    /// brightness change no faster than `accessibility` allows.
+    pub fn update(
+        &mut self,
+        config: &ThemeConfig,
+        accessibility: &AccessibilityConfig,
+        key_hue: f32,
+        octave_shift: i32,
+        rms: f32,
+        dt: f32,
+    ) -> [[f32; 3]; 2] {
+        match self.to_hue {
+            None => {
+                self.from_hue = key_hue;
+                self.to_hue = Some(key_hue);
+                self.since_change = config.transition_time;
+            }
+            Some(to_hue) if to_hue != key_hue => {
+                self.from_hue = self.hue(config);
+                self.to_hue = Some(key_hue);
+                self.since_change = 0.0;
+            }
+            Some(_) => self.since_change += dt,
+        }
+        self.time += dt;
+        self.level += (rms - self.level) * (dt / config.level_smoothing.max(dt)).min(1.0);
+
+        let drift = if config.drift_period > 0.0 {
+            config.drift * (std::f32::consts::TAU * self.time / config.drift_period).sin()
+        } else {
+            0.0
+        };
+        let mut hue = self.hue(config) + drift + octave_shift as f32 * config.octave_hue;
+        let mut saturation =
+            (config.saturation + config.level_saturation * self.level).clamp(0.0, 1.0);
+        let mut brightness =
+            (config.brightness + config.level_brightness * self.level).clamp(0.0, 1.0);
+        if let Some([shown_hue, shown_saturation, shown_brightness]) =
+            self.shown.filter(|_| accessibility.reduced_motion)
+        {
+            hue = limit_hue_rate(shown_hue, hue, accessibility.max_hue_rate, dt);
+            saturation = limit_rate(
+                shown_saturation,
+                saturation,
+                accessibility.max_brightness_rate,
+                dt,
+            );
+            brightness = limit_rate(
+                shown_brightness,
+                brightness,
+                accessibility.max_brightness_rate,
+                dt,
+            );
+        }
+        self.shown = Some([hue, saturation, brightness]);
+        [
+            hsv_to_rgb(hue, saturation, brightness),
+            hsv_to_rgb(hue, saturation, brightness * config.gradient),
+        ]
+    }
+}
+
+/// A quad over the whole window shading from `top` down to `bottom`.
+pub fn background_vertices(top: [f32; 3], bottom: [f32; 3]) -> Vec<ColorVertex> {
+    let [top, bottom] = [top, bottom].map(|[r, g, b]| [r, g, b, 1.0]);
+    [
+        ([-1.0, -1.0], bottom),
+        ([1.0, -1.0], bottom),
+        ([1.0, 1.0], top),
+        ([-1.0, -1.0], bottom),
+        ([1.0, 1.0], top),
+        ([-1.0, 1.0], top),
+    ]
+    .into_iter()
+    .map(|(position, color)| ColorVertex { position, color })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn hues_are_blended_the_short_way_round_the_wheel() {
+        assert!(close(lerp_hue(350.0, 10.0, 0.5), 0.0));
+        assert!(close(lerp_hue(10.0, 350.0, 0.25), 5.0));
+        assert!(close(lerp_hue(340.0, 20.0, 0.75), 10.0));
+        assert!(close(lerp_hue(30.0, 90.0, 0.5), 60.0));
+        // The ends are the hues themselves, kept within 0 to 360.
+        assert!(close(lerp_hue(350.0, 10.0, 0.0), 350.0));
+        assert!(close(lerp_hue(350.0, 10.0, 1.0), 10.0));
+        assert!(close(lerp_hue(-30.0, 400.0, 0.0), 330.0));
+        for t in [0.0, 0.3, 0.7, 1.0] {
+            let hue = lerp_hue(300.0, 60.0, t);
+            assert!((0.0..360.0).contains(&hue), "{}", hue);
+        }
+    }
+
+    #[test]
+    fn missing_keys_are_blended_from_their_neighbours() {
+        let palette: PaletteMap = serde_yaml::from_str("{C: 0.0, E: 120.0}").unwrap();
+        assert!(close(palette.hue("C").unwrap(), 0.0));
+        assert!(close(palette.hue("D").unwrap(), 60.0));
+        assert!(close(palette.hue("E").unwrap(), 120.0));
+        // From E round to C the short way back is down through 60.
+        assert!(close(palette.hue("G_SHARP").unwrap(), 60.0));
+        assert!(close(palette.hue("Ab").unwrap(), 60.0));
+        assert!(close(palette.hue("B").unwrap(), 15.0));
+    }
+
+    #[test]
+    fn one_key_colors_them_all_and_none_spreads_them_evenly() {
+        let palette: PaletteMap = serde_yaml::from_str("{'F#': 200.0}").unwrap();
+        for note in ["C", "F_SHARP", "Gb", "B"] {
+            assert!(close(palette.hue(note).unwrap(), 200.0), "{}", note);
+        }
+        let palette: PaletteMap = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(palette, PaletteMap::default());
+        assert!(close(palette.hue("A").unwrap(), 270.0));
+    }
+
+    #[test]
+    fn unknown_palette_notes_are_refused() {
+        assert!(serde_yaml::from_str::<PaletteMap>("{H: 10.0}").is_err());
+        assert_eq!(PaletteMap::default().hue("nonsense"), None);
+    }
+
+    #[test]
+    fn a_key_change_fades_the_hue_over_the_transition_time() {
+        let config = ThemeConfig {
+            drift: 0.0,
+            ..ThemeConfig::default()
+        };
+        let accessibility = AccessibilityConfig::default();
+        let mut background = Background::default();
+        background.update(&config, &accessibility, 0.0, 0, 0.0, 0.1);
+        assert_eq!(background.hue(&config), 0.0);
+
+        background.update(&config, &accessibility, 90.0, 0, 0.0, 0.1);
+        assert_eq!(background.hue(&config), 0.0);
+        let mut hues = Vec::new();
+        for _ in 0..10 {
+            background.update(&config, &accessibility, 90.0, 0, 0.0, 0.1);
+            hues.push(background.hue(&config));
+        }
+        assert!(hues.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(close(hues[4], 45.0));
+        assert!(close(*hues.last().unwrap(), 90.0));
+    }
+
+    #[test]
+    fn hsv_primaries_convert_to_rgb() {
+        assert_eq!(hsv_to_rgb(0.0, 1.0, 1.0), [1.0, 0.0, 0.0]);
+        assert_eq!(hsv_to_rgb(120.0, 1.0, 1.0), [0.0, 1.0, 0.0]);
+        assert_eq!(hsv_to_rgb(240.0, 1.0, 1.0), [0.0, 0.0, 1.0]);
+        assert_eq!(hsv_to_rgb(600.0, 1.0, 1.0), [0.0, 0.0, 1.0]);
+        assert_eq!(hsv_to_rgb(50.0, 0.0, 0.5), [0.5, 0.5, 0.5]);
+    }
+}
@@ -0,0 +1,311 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{OscillatorWaveform, Scale, TremoloEffect};
+
+/// Settings for the state summary shown in the window title.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowTitleConfig {
+    pub enabled: bool,
+    /// Title with placeholders filled in from the synth's state; see `format_title`.
+    pub template: String,
+    /// Shortest time between two title changes, in seconds, so window managers aren't flooded.
+    pub min_interval: f32,
+}
+
+impl Default for WindowTitleConfig {
+    fn default() -> Self {
+        WindowTitleConfig {
+            enabled: true,
+            template: "visiosynth — {waveform} | Oct {octave} | {key} {scale} | Trem {tremolo} | {tempo} BPM"
+                .to_string(),
+            min_interval: 0.25,
+        }
+    }
+}
+
+/// The values the window title shows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TitleState {
+    pub waveform: OscillatorWaveform,
+    pub octave: i32,
+    pub key: String,
+    pub scale: String,
+    pub tremolo: bool,
+    pub tempo: f32,
+    /// Steps below full quality the engine runs at.
+    pub quality: usize,
+}
+
+impl TitleState {
+    /// Reads the current values out of the shared synth state.
+    pub fn capture(
+        waveform_type: &RwLock<OscillatorWaveform>,
+        octave_shift: &RwLock<i32>,
+        tremolo_effect: &Arc<TremoloEffect>,
+        scale: &Mutex<Scale>,
+        quality_level: &AtomicUsize,
+        tempo: f32,
+    ) -> Self {
+        let (key, scale) = scale
+            .lock()
+            .map(|scale| (key_name(&scale.root_note), scale_name(&scale.intervals)))
+            .unwrap_or_default();
+        TitleState {
+            waveform: waveform_type
+                .read()
+                .map_or(OscillatorWaveform::Silence, |waveform| *waveform),
+            octave: octave_shift.read().map_or(0, |octave| *octave),
+            key,
+            scale: scale.to_string(),
+            tremolo: tremolo_effect.enabled.load(Ordering::Relaxed),
+            tempo,
+            quality: quality_level.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Fills in `template` from `state`. The placeholders are:
+///
+/// - `{waveform}`: the waveform, abbreviated (`Saw`, `Tri`, ...)
+/// - `{octave}`: the octave shift, signed (`+1`, `0`, `-2`)
+/// - `{key}`: the scale's root note (`C#`)
+/// - `{scale}`: `major`, `minor` or `custom`
+/// - `{tremolo}`: `on` or `off`
+/// - `{tempo}`: the tempo in beats per minute
+/// - `{quality}`: `full`, or how many steps the CPU budget has lowered it by (`-2`)
+///
+/// Anything else in braces is left as written.
+pub fn format_title(template: &str, state: &TitleState) -> String {
+    let mut title = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        title.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let Some(close) = rest.find('}') else {
+            break;
+        };
+        let value = match &rest[1..close] {
+            "waveform" => Some(waveform_name(state.waveform).to_string()),
+            "octave" if state.octave > 0 => Some(format!("+{}", state.octave)),
+            "octave" => Some(state.octave.to_string()),
+            "key" => Some(state.key.clone()),
+            "scale" => Some(state.scale.clone()),
+            "tremolo" => Some(if state.tremolo { "on" } else { "off" }.to_string()),
+            "tempo" => Some(format!("{}", state.tempo.round())),
+            "quality" if state.quality > 0 => Some(format!("-{}", state.quality)),
+            "quality" => Some("full".to_string()),
+            _ => None,
+        };
+        match value {
+            Some(value) => {
+                title.push_str(&value);
+                rest = &rest[close + 1..];
+            }
+            None => {
+                // Leave the brace as text and keep looking after it.
+                title.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    title.push_str(rest);
+    title
+}
+
+fn waveform_name(waveform: OscillatorWaveform) -> &'static str {
+    match waveform {
+        OscillatorWaveform::Silence => "Off",
+        OscillatorWaveform::Sine => "Sine",
+        OscillatorWaveform::Square => "Square",
+        OscillatorWaveform::Sawtooth => "Saw",
+        OscillatorWaveform::Triangle => "Tri",
+        OscillatorWaveform::Custom(_) => "Custom",
+    }
+}
+
+fn key_name(root_note: &str) -> String {
+    root_note.replace("_SHARP", "#").replace("_HIGH", "")
+}
+
+fn scale_name(intervals: &[i32]) -> &'static str {
+    match intervals {
+        [2, 2, 1, 2, 2, 2, 1] => "major",
+        [2, 1, 2, 2, 1, 2, 2] => "minor",
+        _ => "custom",
+    }
+}
+
+/// Works out when the window title needs changing: only when the formatted title differs from
+/// the one shown, and no more than once per `min_interval`. A change arriving too soon waits
+/// for `poll`.
+#[derive(Debug)]
+pub struct TitleUpdater {
+    template: String,
+    min_interval: Duration,
+    state: Option<TitleState>,
+    shown: Option<String>,
+    pending: Option<String>,
+    last_update: Option<Instant>,
+}
+
+impl TitleUpdater {
+    pub fn new(config: &WindowTitleConfig) -> Self {
+        TitleUpdater {
+            template: config.template.clone(),
+            min_interval: Duration::from_secs_f32(config.min_interval.max(0.0)),
+            state: None,
+            shown: None,
+            pending: None,
+            last_update: None,
+        }
+    }
+
+    /// Records the synth's state at `now`. Returns the title to set, if there is one due.
+    pub fn update(&mut self, state: TitleState, now: Instant) -> Option<String> {
+        if self.state.as_ref() != Some(&state) {
+            let title = format_title(&self.template, &state);
+            self.state = Some(state);
+            self.pending = (self.shown.as_ref() != Some(&title)).then_some(title);
+        }
+        self.poll(now)
+    }
+
+    /// Returns a title that was held back, once `min_interval` has passed since the last one.
+    pub fn poll(&mut self, now: Instant) -> Option<String> {
+        let too_soon = self
+            .last_update
+            .is_some_and(|last| now.saturating_duration_since(last) < self.min_interval);
+        if too_soon {
+            return None;
+        }
+        let title = self.pending.take()?;
+        self.last_update = Some(now);
+        self.shown = Some(title.clone());
+        Some(title)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> TitleState {
+        TitleState {
+            waveform: OscillatorWaveform::Sawtooth,
+            octave: 1,
+            key: "C".to_string(),
+            scale: "major".to_string(),
+            tremolo: true,
+            tempo: 120.0,
+            quality: 0,
+        }
+    }
+
+    #[test]
+    fn the_default_template_fills_in_every_value() {
+        let template = WindowTitleConfig::default().template;
+        assert_eq!(
+            format_title(&template, &state()),
+            "visiosynth — Saw | Oct +1 | C major | Trem on | 120 BPM"
+        );
+    }
+
+    #[test]
+    fn placeholders_are_formatted_compactly() {
+        let state = TitleState {
+            waveform: OscillatorWaveform::Triangle,
+            octave: -2,
+            tremolo: false,
+            tempo: 97.6,
+            quality: 2,
+            ..state()
+        };
+        assert_eq!(
+            format_title("{waveform} {octave} {tremolo} {tempo} {quality}", &state),
+            "Tri -2 off 98 -2"
+        );
+        let state = TitleState { octave: 0, ..state };
+        assert_eq!(format_title("{octave} {quality}", &state), "0 -2");
+        assert_eq!(
+            format_title(
+                "{quality}",
+                &TitleState {
+                    quality: 0,
+                    ..state
+                }
+            ),
+            "full"
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_and_stray_braces_are_left_as_written() {
+        let state = state();
+        assert_eq!(format_title("{bpm} {key}", &state), "{bpm} C");
+        assert_eq!(format_title("{{key}}", &state), "{C}");
+        assert_eq!(format_title("{key", &state), "{key");
+        assert_eq!(format_title("key}", &state), "key}");
+        assert_eq!(format_title("", &state), "");
+    }
+
+    #[test]
+    fn note_names_and_intervals_read_as_keys_and_scales() {
+        assert_eq!(key_name("C_SHARP"), "C#");
+        assert_eq!(key_name("C_HIGH"), "C");
+        assert_eq!(scale_name(&[2, 1, 2, 2, 1, 2, 2]), "minor");
+        assert_eq!(scale_name(&[2, 2, 3, 2, 3]), "custom");
+    }
+
+    #[test]
+    fn a_burst_of_changes_sets_the_title_at_most_once_per_interval() {
+        let mut updater = TitleUpdater::new(&WindowTitleConfig {
+            template: "Oct {octave}".to_string(),
+            ..WindowTitleConfig::default()
+        });
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        assert_eq!(updater.update(state(), at(0)), Some("Oct +1".to_string()));
+
+        // Twenty changes over 100 ms: none shown before the 250 ms interval is up.
+        let mut shown = Vec::new();
+        for i in 0..20 {
+            let octave = i % 5 - 2;
+            shown.extend(updater.update(TitleState { octave, ..state() }, at(5 * i as u64 + 5)));
+        }
+        assert!(shown.is_empty());
+        assert_eq!(updater.poll(at(200)), None);
+        // The last of them is shown once the interval is up, and only once.
+        assert_eq!(updater.poll(at(250)), Some("Oct +2".to_string()));
+        assert_eq!(updater.poll(at(1_000)), None);
+    }
+
+    #[test]
+    fn unchanged_titles_are_not_set_again() {
+        let mut updater = TitleUpdater::new(&WindowTitleConfig::default());
+        let start = Instant::now();
+        assert!(updater.update(state(), start).is_some());
+        let later = start + Duration::from_secs(1);
+        assert_eq!(updater.update(state(), later), None);
+        // A change that doesn't show in the template doesn't set it either.
+        let quality = TitleState {
+            quality: 1,
+            ..state()
+        };
+        assert_eq!(updater.update(quality, later), None);
+        let changed = TitleState {
+            octave: 2,
+            ..state()
+        };
+        assert!(updater.update(changed.clone(), later).is_some());
+        // A change that is undone before it is due is never shown.
+        let soon = later + Duration::from_millis(100);
+        assert!(updater.update(state(), soon).is_none());
+        assert!(updater.update(changed, soon).is_none());
+        assert_eq!(updater.poll(soon + Duration::from_secs(1)), None);
+    }
+}
@@ -1,36 +1,375 @@
+use serde::{Deserialize, Serialize};
+
+use crate::graphics::{DisplayScaleConfig, PresentMode, SilenceHoldConfig};
+use crate::synth::{DownsampleConfig, VisualTap, MAX_VISUAL_SAMPLES};
+
+/// A vertex for flat-colored overlay geometry such as the ribbon strip.
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct Vertex {
-    position: [f32; 2],
+pub struct ColorVertex {
+    pub position: [f32; 2],
+    pub color: [f32; 4],
 }
 
-impl Vertex {
+impl ColorVertex {
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
         wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            array_stride: std::mem::size_of::<ColorVertex>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Vertex,
             attributes: &[
                 wgpu::VertexAttribute {
                     offset: 0,
                     shader_location: 0,
                     format: wgpu::VertexFormat::Float32x2,
-                }
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
+
+    /// Two triangles covering the rectangle between (left, bottom) and (right, top) in NDC.
+    pub fn quad(left: f32, bottom: f32, right: f32, top: f32, color: [f32; 4]) -> Vec<ColorVertex> {
+        [
+            [left, bottom],
+            [right, bottom],
+            [right, top],
+            [left, bottom],
+            [right, top],
+            [left, top],
+        ]
+        .into_iter()
+        .map(|position| ColorVertex { position, color })
+        .collect()
+    }
+}
+
+/// Fewest points a waveform strip can be drawn with, one at either edge.
+pub const MIN_WAVEFORM_POINTS: usize = 2;
+
+/// WGSL declarations of the constants the strip mapping shares with shader.wgsl, put ahead of
+/// the shader source so the GPU and `strip_x` read them from the same place.
+pub fn waveform_shader_constants() -> String {
+    format!(
+        "const MIN_WAVEFORM_POINTS: u32 = {}u;\n",
+        MIN_WAVEFORM_POINTS
+    )
+}
+
+/// Position along a strip of `points` points of its point `point`, from -1.0 at the first to
+/// 1.0 at the last. The waveform is drawn with no vertex buffer, two vertices to a point, and
+/// this mirrors how `vs_main` in shader.wgsl places them from the vertex index.
+pub fn strip_x(point: u32, points: u32) -> f32 {
+    let points = points.max(MIN_WAVEFORM_POINTS as u32);
+    -1.0 + 2.0 * point.min(points - 1) as f32 / (points - 1) as f32
+}
+
+/// Where on screen, in NDC, the point at `strip_x` of `channel`'s strip is drawn. A split puts
+/// the left channel in the left half of the window and the right channel in the right half,
+/// each squeezed to half the width. Mirrors the layout in `vs_main` in shader.wgsl.
+pub fn screen_x(strip_x: f32, channel: u32, layout: WaveformLayout) -> f32 {
+    match layout {
+        WaveformLayout::Single => strip_x,
+        WaveformLayout::Split => -1.0 + channel as f32 + (strip_x + 1.0) * 0.5,
+    }
+}
+
+/// Which of `sample_count` audio entries point `point` of a strip of `points` points shows,
+/// spreading the entries evenly across the strip whether it has more points than entries or
+/// fewer. It is worked out from the point's number rather than from `strip_x`, whose rounding
+/// would skip entries when there are only a few more points than entries. Mirrors
+/// `sample_index` in shader.wgsl; `sample_count` must not be zero.
+pub fn sample_index(point: u32, points: u32, sample_count: u32) -> u32 {
+    let points = points.max(MIN_WAVEFORM_POINTS as u32);
+    let point = point.min(points - 1);
+    ((point as f32 * sample_count as f32 / (points - 1) as f32) as u32).min(sample_count - 1)
+}
+
+/// Half the width of a `width` pixel line in NDC along x and along y, for a surface of
+/// `surface_width` by `surface_height` pixels. NDC spans 2.0 across either axis whatever the
+/// aspect ratio, so the same width in pixels is a different width in NDC along each.
+pub fn line_half_width(width: f32, surface_width: f32, surface_height: f32) -> [f32; 2] {
+    [
+        width / surface_width.max(1.0),
+        width / surface_height.max(1.0),
+    ]
 }
 
-// Function to generate vertices for the waveform
-pub fn generate_waveform_vertices(num_vertices: usize) -> Vec<Vertex> {
-    let mut vertices = Vec::with_capacity(num_vertices);
-    let x_step = 2.0 / (num_vertices as f32 - 1.0); // Step to cover [-1, 1] range
-    
-    for i in 0..num_vertices {
-        let x = -1.0 + (i as f32 * x_step);
-        // Placeholder Y value, you might want to adjust it based on your audio data
-        let y = 0.0; 
-        vertices.push(Vertex { position: [x, y] });
+/// How far a point on a line running from `previous` to `next` is pushed out to its edge, in
+/// NDC, with `half_width` from `line_half_width`. The offset is at right angles to the line on
+/// screen, so the line keeps its width in pixels at any slope and aspect ratio. Mirrors
+/// `line_offset` in shader.wgsl.
+pub fn line_offset(previous: [f32; 2], next: [f32; 2], half_width: [f32; 2]) -> [f32; 2] {
+    // The direction in units of the half width, which are square on screen.
+    let direction = [
+        (next[0] - previous[0]) / half_width[0],
+        (next[1] - previous[1]) / half_width[1],
+    ];
+    let length = direction[0].hypot(direction[1]);
+    if length == 0.0 {
+        return [0.0, half_width[1]];
+    }
+    [
+        -direction[1] / length * half_width[0],
+        direction[0] / length * half_width[1],
+    ]
+}
+
+/// How the waveform is laid out across the window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaveformLayout {
+    /// One waveform of the left channel across the whole width.
+    #[default]
+    Single,
+    /// The left channel in the left half of the window and the right channel in the right half.
+    Split,
+}
+
+/// Settings for the waveform display.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VisualizerConfig {
+    pub layout: WaveformLayout,
+    /// Draw at this many frames per second instead of following the monitor's refresh rate.
+    pub visual_fps: Option<f32>,
+    /// Most downsampled samples per channel drawn each frame; when a frame produces more, the
+    /// most recent are kept. The GPU audio buffer is allocated for this many.
+    pub max_audio_samples: usize,
+    /// How the audio is averaged down into those samples.
+    pub downsample: DownsampleConfig,
+    /// Where in the signal chain the drawn audio is taken from. The visual tap key cycles it.
+    pub visual_tap: VisualTap,
+    /// Vertical scaling of the drawn waveform.
+    pub scale: DisplayScaleConfig,
+    /// How frames are presented. Falls back to `fifo` when the surface can't do it.
+    pub present_mode: PresentMode,
+    /// Fading the last sound out instead of dropping straight to a flat line.
+    pub silence_hold: SilenceHoldConfig,
+    /// Width of the waveform line in screen pixels.
+    pub line_width: f32,
+    /// Points along each strip of the waveform line. More draw a smoother line on a large
+    /// window at no extra upload, since the points are placed on the GPU.
+    pub waveform_points: usize,
+}
+
+impl Default for VisualizerConfig {
+    fn default() -> Self {
+        VisualizerConfig {
+            layout: WaveformLayout::default(),
+            visual_fps: None,
+            max_audio_samples: MAX_VISUAL_SAMPLES,
+            downsample: DownsampleConfig::default(),
+            visual_tap: VisualTap::default(),
+            scale: DisplayScaleConfig::default(),
+            present_mode: PresentMode::default(),
+            silence_hold: SilenceHoldConfig::default(),
+            line_width: 1.0,
+            waveform_points: 100,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::AudioBufferBinding;
+
+    const POINTS: u32 = 64;
+
+    /// The entry each of `points` points shows of `sample_count`.
+    fn indices(points: u32, sample_count: u32) -> Vec<u32> {
+        (0..points)
+            .map(|point| sample_index(point, points, sample_count))
+            .collect()
+    }
+
+    #[test]
+    fn a_strip_runs_edge_to_edge_in_even_steps() {
+        for points in [2, 3, 64, 100, 4096] {
+            let step = 2.0 / (points - 1) as f32;
+            for point in 0..points {
+                let expected = -1.0 + step * point as f32;
+                assert!((strip_x(point, points) - expected).abs() < 1e-5);
+            }
+            assert_eq!(strip_x(points - 1, points), 1.0);
+            // Points past the end stay on the last.
+            assert_eq!(strip_x(points + 5, points), 1.0);
+        }
+        // Fewer than two points draw as two.
+        for points in [0, 1] {
+            assert_eq!((strip_x(0, points), strip_x(1, points)), (-1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn with_as_many_points_as_samples_each_point_shows_its_own() {
+        for count in [2, 3, 100, 1024, 4096] {
+            let expected: Vec<u32> = (0..count).collect();
+            assert_eq!(indices(count, count), expected, "{}", count);
+        }
+    }
+
+    #[test]
+    fn more_points_than_samples_reach_every_sample_in_order() {
+        for count in [1, 2, 7, 100, 512, 2048] {
+            // Just one more point than samples is where rounding would skip one.
+            for points in [count + 1, count + 2, 2 * count + 3, 4096] {
+                let indices = indices(points, count);
+                assert_eq!(indices[0], 0);
+                assert_eq!(*indices.last().unwrap(), count - 1);
+                assert!(indices.windows(2).all(|pair| pair[1] - pair[0] <= 1));
+            }
+        }
+    }
+
+    #[test]
+    fn fewer_points_than_samples_spread_evenly_over_them() {
+        for count in [3, 100, 1024, 2048] {
+            for points in [2, 3, 64, count - 1]
+                .into_iter()
+                .filter(|&points| points < count)
+            {
+                let indices = indices(points, count);
+                assert_eq!(indices[0], 0);
+                assert_eq!(*indices.last().unwrap(), count - 1);
+                // Every step skips about the same number of samples, never going back.
+                let step = count as f32 / (points - 1) as f32;
+                for pair in indices.windows(2) {
+                    let gap = (pair[1] - pair[0]) as f32;
+                    assert!(
+                        gap >= 1.0 && (gap - step).abs() <= 1.0,
+                        "{} of {}",
+                        gap,
+                        step
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_point_past_the_end_or_a_strip_too_short_still_shows_a_sample() {
+        assert_eq!(sample_index(70, 64, 10), 9);
+        assert_eq!(sample_index(30, 64, 1), 0);
+        // Fewer than two points draw as two, showing the first and last samples.
+        for points in [0, 1] {
+            assert_eq!(sample_index(0, points, 10), 0);
+            assert_eq!(sample_index(1, points, 10), 9);
+        }
     }
 
-    vertices
+    /// `code` with the whitespace taken out, to compare it whatever its layout.
+    fn squeezed(code: &str) -> String {
+        code.split_whitespace().collect()
+    }
+
+    /// The WGSL function `name` in shader.wgsl, from its signature to its closing brace.
+    fn shader_function(name: &str) -> String {
+        let source = include_str!("shader.wgsl");
+        let start = source.find(&format!("fn {}(", name)).unwrap();
+        let end = start + source[start..].find("\n}\n").unwrap();
+        squeezed(&source[start..end])
+    }
+
+    #[test]
+    fn the_shader_reads_the_generated_constants_and_mirrors_the_mapping() {
+        let constants = waveform_shader_constants();
+        assert_eq!(constants, "const MIN_WAVEFORM_POINTS: u32 = 2u;\n");
+        for binding in [AudioBufferBinding::Storage, AudioBufferBinding::Uniform] {
+            let source = binding.shader_source();
+            assert!(source.starts_with(&constants));
+            // Declared only by the generated line, so the shader can't drift from vertex.rs.
+            assert_eq!(source.matches("const MIN_WAVEFORM_POINTS").count(), 1);
+        }
+        assert!(shader_function("vs_main")
+            .contains("letpoints=max(uni.waveform_points,MIN_WAVEFORM_POINTS);"));
+        assert!(shader_function("strip_x_at")
+            .contains("return-1.0+2.0*f32(min(point,points-1u))/f32(points-1u);"));
+        assert!(shader_function("sample_index").contains(
+            "returnmin(u32(f32(min(point,points-1u))*f32(count)/f32(points-1u)),count-1u);"
+        ));
+    }
+
+    fn channel_xs(channel: u32, layout: WaveformLayout) -> Vec<f32> {
+        (0..POINTS)
+            .map(|point| screen_x(strip_x(point, POINTS), channel, layout))
+            .collect()
+    }
+
+    #[test]
+    fn a_split_puts_the_left_channel_in_the_left_half_and_the_right_in_the_right() {
+        let left = channel_xs(0, WaveformLayout::Split);
+        let right = channel_xs(1, WaveformLayout::Split);
+        assert!(left.iter().all(|x| (-1.0..=0.0).contains(x)));
+        assert!(right.iter().all(|x| (0.0..=1.0).contains(x)));
+        // Each strip spans its whole half, edge to edge.
+        assert_eq!((left[0], left[POINTS as usize - 1]), (-1.0, 0.0));
+        assert_eq!((right[0], right[POINTS as usize - 1]), (0.0, 1.0));
+        assert!(left.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn a_single_strip_spans_the_whole_window() {
+        let xs = channel_xs(0, WaveformLayout::Single);
+        assert_eq!((xs[0], xs[POINTS as usize - 1]), (-1.0, 1.0));
+    }
+
+    /// `offset`, in NDC, in pixels on a `width` by `height` surface.
+    fn in_pixels(offset: [f32; 2], width: f32, height: f32) -> [f32; 2] {
+        [offset[0] * width / 2.0, offset[1] * height / 2.0]
+    }
+
+    #[test]
+    fn the_half_width_is_half_the_line_in_pixels_on_each_axis() {
+        for (width, height) in [(800.0, 600.0), (1920.0, 400.0), (300.0, 900.0)] {
+            let half_width = line_half_width(3.0, width, height);
+            let pixels = in_pixels(half_width, width, height);
+            assert!((pixels[0] - 1.5).abs() < 1e-4 && (pixels[1] - 1.5).abs() < 1e-4);
+        }
+        // A zero-sized surface, while minimized, doesn't divide by zero.
+        assert!(line_half_width(3.0, 0.0, 0.0)
+            .iter()
+            .all(|half| half.is_finite()));
+    }
+
+    #[test]
+    fn the_line_keeps_its_width_at_any_slope_and_aspect_ratio() {
+        for (width, height) in [(800.0, 600.0), (1920.0, 400.0), (300.0, 900.0)] {
+            let half_width = line_half_width(3.0, width, height);
+            for (previous, next) in [
+                ([-0.5, 0.0], [0.5, 0.0]),
+                ([0.0, -0.5], [0.0, 0.5]),
+                ([-0.5, -0.5], [0.5, 0.5]),
+                ([-0.1, 0.8], [0.1, -0.8]),
+            ] {
+                let offset = in_pixels(line_offset(previous, next, half_width), width, height);
+                let length = offset[0].hypot(offset[1]);
+                assert!((length - 1.5).abs() < 1e-3, "{} px", length);
+                // At right angles to the line as it is on screen.
+                let along = in_pixels(
+                    [next[0] - previous[0], next[1] - previous[1]],
+                    width,
+                    height,
+                );
+                let dot = offset[0] * along[0] + offset[1] * along[1];
+                assert!(dot.abs() < 1e-3 * along[0].hypot(along[1]), "{}", dot);
+            }
+        }
+    }
+
+    #[test]
+    fn a_flat_line_is_widened_straight_up_and_a_point_does_not_vanish() {
+        let half_width = line_half_width(2.0, 800.0, 600.0);
+        let offset = line_offset([-1.0, 0.2], [1.0, 0.2], half_width);
+        assert!(offset[0].abs() < 1e-7);
+        assert!((offset[1] - half_width[1]).abs() < 1e-7);
+        assert_eq!(
+            line_offset([0.3, 0.3], [0.3, 0.3], half_width),
+            [0.0, half_width[1]]
+        );
+    }
 }
@@ -1,2 +1,7 @@
+#[cfg(feature = "visualization")]
+pub mod app;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "visualization")]
 pub mod graphics;
 pub mod synth;
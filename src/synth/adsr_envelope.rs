@@ -1,12 +1,74 @@
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug)]
 pub struct AmplitudeEnvelope {
     pub attack_time: f32,
     pub decay_time: f32,
     pub sustain_level: f32,
     pub release_time: f32,
+    /// How long the fade after a release takes, when that isn't `release_time`, as when
+    /// release scaling sets it from how long the note was held.
+    pub release_fade: Option<f32>,
+}
+
+/// Release times that follow how long a note was held, so short taps die away quickly and
+/// long holds ring on. Off by default.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReleaseScalingConfig {
+    /// How closely the release follows the hold: 0.0 leaves it alone, 1.0 makes it
+    /// proportional, so a note held twice as long takes twice as long to release.
+    pub amount: f32,
+    /// Seconds held that get the preset's release time unchanged.
+    pub reference_hold: f32,
+    /// Shortest and longest release, in seconds, the scaling gives.
+    pub min_release: f32,
+    pub max_release: f32,
+}
+
+impl Default for ReleaseScalingConfig {
+    fn default() -> Self {
+        ReleaseScalingConfig {
+            amount: 0.0,
+            reference_hold: 0.5,
+            min_release: 0.01,
+            max_release: 5.0,
+        }
+    }
+}
+
+impl ReleaseScalingConfig {
+    /// The release time for a note held `held` seconds, from the preset's `release_time`: it
+    /// is multiplied by the hold relative to `reference_hold`, raised to `amount`.
+    pub fn release_time(&self, release_time: f32, held: f32) -> f32 {
+        if self.amount <= 0.0 {
+            return release_time;
+        }
+        let ratio = (held.max(0.0) / self.reference_hold.max(1e-3)).powf(self.amount);
+        (release_time * ratio).clamp(self.min_release, self.max_release.max(self.min_release))
+    }
+}
+
+/// Where a voice's envelope currently is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeStage {
+    Idle,
+    Attack,
+    Decay,
+    Release,
+    Finished,
 }
 
 impl AmplitudeEnvelope {
+    /// Attack time for a note struck at MIDI `velocity`, shortened by `velocity_to_attack`.
+    ///
+    /// With an amount of 0.0 velocity has no effect; with 1.0 a note at full velocity has no
+    /// attack at all. Amounts in between shorten the attack in proportion to velocity.
+    pub fn velocity_attack_time(attack_time: f32, velocity: u8, velocity_to_attack: f32) -> f32 {
+        let velocity = velocity.min(127) as f32 / 127.0;
+        attack_time * (1.0 - velocity_to_attack.clamp(0.0, 1.0) * velocity)
+    }
+
     pub fn amplitude_at_time(&self, time: f32) -> f32 {
         if time < self.attack_time {
             // Attack stage
@@ -23,4 +85,169 @@ impl AmplitudeEnvelope {
             0.0
         }
     }
+
+    /// Amplitude at `time` for a note released at `released_at` (both relative to the start).
+    ///
+    /// Releasing fades linearly from whatever level the envelope had reached at release, in
+    /// whichever stage, to silence over the release fade. A note let go halfway through its
+    /// attack fades from half level instead of jumping to one based on `sustain_level`.
+    pub fn amplitude_released(&self, time: f32, released_at: Option<f32>) -> f32 {
+        match released_at {
+            Some(released_at) if time >= released_at => {
+                let elapsed = time - released_at;
+                let fade = self.fade_time();
+                if elapsed >= fade {
+                    0.0
+                } else {
+                    let level = self.amplitude_at_time(released_at);
+                    level * (1.0 - elapsed / fade)
+                }
+            }
+            _ => self.amplitude_at_time(time),
+        }
+    }
+
+    pub fn stage_at_time(&self, time: f32, released_at: Option<f32>) -> EnvelopeStage {
+        let natural_end = self.attack_time + self.decay_time + self.release_time;
+        match released_at {
+            Some(released_at) if time >= released_at => {
+                // A note released after its envelope had already run out has nothing to fade.
+                if time - released_at >= self.fade_time() || released_at >= natural_end {
+                    EnvelopeStage::Finished
+                } else {
+                    EnvelopeStage::Release
+                }
+            }
+            _ if time < 0.0 => EnvelopeStage::Idle,
+            _ if time < self.attack_time => EnvelopeStage::Attack,
+            _ if time < self.attack_time + self.decay_time => EnvelopeStage::Decay,
+            _ if time < natural_end => EnvelopeStage::Release,
+            _ => EnvelopeStage::Finished,
+        }
+    }
+
+    /// Seconds the fade after a release takes.
+    fn fade_time(&self) -> f32 {
+        self.release_fade.unwrap_or(self.release_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn harder_notes_get_shorter_attacks() {
+        let soft = AmplitudeEnvelope::velocity_attack_time(0.2, 20, 0.5);
+        let hard = AmplitudeEnvelope::velocity_attack_time(0.2, 120, 0.5);
+        assert!(hard < soft, "{} against {}", hard, soft);
+        assert_eq!(AmplitudeEnvelope::velocity_attack_time(0.2, 127, 0.5), 0.1);
+        assert_eq!(AmplitudeEnvelope::velocity_attack_time(0.2, 127, 1.0), 0.0);
+        assert_eq!(AmplitudeEnvelope::velocity_attack_time(0.2, 0, 1.0), 0.2);
+    }
+
+    #[test]
+    fn no_scaling_leaves_the_attack_alone() {
+        for velocity in [0, 64, 127, 255] {
+            assert_eq!(
+                AmplitudeEnvelope::velocity_attack_time(0.2, velocity, 0.0),
+                0.2
+            );
+        }
+        // Amounts past 1.0 can't make the attack negative.
+        assert_eq!(AmplitudeEnvelope::velocity_attack_time(0.2, 127, 3.0), 0.0);
+    }
+
+    fn envelope() -> AmplitudeEnvelope {
+        AmplitudeEnvelope {
+            attack_time: 0.1,
+            decay_time: 0.1,
+            sustain_level: 0.5,
+            release_time: 0.2,
+            release_fade: None,
+        }
+    }
+
+    /// The largest step between neighbouring samples of the released envelope at 48 kHz.
+    fn largest_step(envelope: &AmplitudeEnvelope, released_at: f32) -> f32 {
+        let levels: Vec<f32> = (0..24_000)
+            .map(|i| envelope.amplitude_released(i as f32 / 48_000.0, Some(released_at)))
+            .collect();
+        levels
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn releasing_during_the_attack_fades_from_the_level_reached() {
+        let envelope = envelope();
+        let released_at = 0.05;
+        let before = envelope.amplitude_released(released_at - 1.0 / 48_000.0, Some(released_at));
+        let at = envelope.amplitude_released(released_at, Some(released_at));
+        assert!((at - 0.5).abs() < 1e-6);
+        assert!((at - before).abs() < 1e-3, "{} then {}", before, at);
+        // Nowhere does it step by more than the steeper of the attack and the fade allow.
+        assert!(largest_step(&envelope, released_at) < 1e-3);
+        assert_eq!(
+            envelope.amplitude_released(released_at + 0.2, Some(released_at)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn releasing_in_any_stage_has_no_jump() {
+        let envelope = envelope();
+        for released_at in [0.0, 0.01, 0.099, 0.1, 0.15, 0.2, 0.3] {
+            assert!(
+                largest_step(&envelope, released_at) < 1e-3,
+                "released at {}",
+                released_at
+            );
+        }
+    }
+
+    #[test]
+    fn a_scaled_release_fades_over_its_own_time() {
+        let envelope = AmplitudeEnvelope {
+            release_fade: Some(0.4),
+            ..envelope()
+        };
+        let level = envelope.amplitude_released(0.25, Some(0.2));
+        assert!((level - 0.5 * (1.0 - 0.05 / 0.4)).abs() < 1e-6);
+        assert_eq!(
+            envelope.stage_at_time(0.5, Some(0.2)),
+            EnvelopeStage::Release
+        );
+        assert_eq!(
+            envelope.stage_at_time(0.6, Some(0.2)),
+            EnvelopeStage::Finished
+        );
+    }
+
+    fn scaling(amount: f32) -> ReleaseScalingConfig {
+        ReleaseScalingConfig {
+            amount,
+            ..ReleaseScalingConfig::default()
+        }
+    }
+
+    #[test]
+    fn the_release_scales_with_the_hold_by_the_amount() {
+        // Held for the reference half second, the preset's release is kept.
+        assert_eq!(scaling(1.0).release_time(0.3, 0.5), 0.3);
+        // Proportional at 1.0, the square root of the ratio at 0.5.
+        assert!((scaling(1.0).release_time(0.3, 1.0) - 0.6).abs() < 1e-6);
+        assert!((scaling(1.0).release_time(0.3, 0.25) - 0.15).abs() < 1e-6);
+        assert!((scaling(0.5).release_time(0.3, 2.0) - 0.6).abs() < 1e-6);
+        // Off, the hold makes no difference.
+        assert_eq!(scaling(0.0).release_time(0.3, 4.0), 0.3);
+    }
+
+    #[test]
+    fn the_scaled_release_stays_within_its_limits() {
+        assert_eq!(scaling(1.0).release_time(0.3, 0.0), 0.01);
+        assert_eq!(scaling(1.0).release_time(0.3, 60.0), 5.0);
+        assert_eq!(scaling(1.0).release_time(0.3, -1.0), 0.01);
+    }
 }
@@ -0,0 +1,141 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+
+use crate::synth::{render::render_score, Scale, Score, ScoreNote, SynthEngine};
+
+/// The note the check plays, and for how long in seconds.
+const CHECK_NOTE: &str = "A";
+const CHECK_DURATION: f32 = 1.0;
+
+/// The stretch of the render, in seconds, that is measured: well into the attack, before the
+/// note is let go.
+const MEASURE_START: f32 = 0.25;
+const MEASURE_END: f32 = 0.75;
+
+/// Quietest and loudest RMS level the measured stretch may have.
+const MIN_RMS: f32 = 0.01;
+const MAX_RMS: f32 = 1.0;
+
+/// How far the measured fundamental may be from the note's frequency, in cents.
+const FUNDAMENTAL_TOLERANCE_CENTS: f32 = 10.0;
+
+/// What the offline audio check measured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioCheck {
+    pub rms: f32,
+    pub fundamental: f32,
+    pub expected_fundamental: f32,
+}
+
+/// Checks the audio path without a device: renders a short note through a default engine at
+/// `sample_rate` and checks its level and pitch.
+pub fn check_offline_render(sample_rate: f32) -> Result<AudioCheck> {
+    let scale = Scale {
+        root_note: "C".to_string(),
+        intervals: vec![2, 2, 1, 2, 2, 2, 1],
+    };
+    let expected_fundamental = scale
+        .calculate_frequency(CHECK_NOTE)
+        .context("The scale has no frequency for the check note")?;
+    let mut engine = SynthEngine::builder()
+        .scale(Arc::new(Mutex::new(scale)))
+        .build(sample_rate);
+    let score = Score {
+        notes: vec![ScoreNote {
+            note: CHECK_NOTE.to_string(),
+            start: 0.0,
+            duration: CHECK_DURATION,
+        }],
+    };
+    let samples = render_score(&mut engine, &score);
+
+    if let Some(position) = samples.iter().position(|sample| !sample.is_finite()) {
+        bail!("Sample {} of the render is {}", position, samples[position]);
+    }
+    let start = ((MEASURE_START * sample_rate) as usize).min(samples.len());
+    let end = ((MEASURE_END * sample_rate) as usize).min(samples.len());
+    let measured = &samples[start..end];
+
+    let rms = (measured.iter().map(|sample| sample * sample).sum::<f32>()
+        / measured.len().max(1) as f32)
+        .sqrt();
+    if !(MIN_RMS..=MAX_RMS).contains(&rms) {
+        bail!("RMS level {:.4} is outside {} to {}", rms, MIN_RMS, MAX_RMS);
+    }
+
+    let fundamental = estimate_fundamental(measured, sample_rate)
+        .context("No fundamental found; the render doesn't cross zero")?;
+    let cents = 1200.0 * (fundamental / expected_fundamental).log2();
+    if cents.abs() > FUNDAMENTAL_TOLERANCE_CENTS {
+        bail!(
+            "Fundamental {:.2} Hz is {:+.1} cents off the expected {:.2} Hz",
+            fundamental,
+            cents,
+            expected_fundamental
+        );
+    }
+
+    Ok(AudioCheck {
+        rms,
+        fundamental,
+        expected_fundamental,
+    })
+}
+
+/// Estimates the fundamental of `samples` in Hz from the spacing of their upward zero
+/// crossings, each placed between samples by linear interpolation. Good for the clean,
+/// periodic tones of the check; `None` with fewer than two crossings.
+pub fn estimate_fundamental(samples: &[f32], sample_rate: f32) -> Option<f32> {
+    let crossings: Vec<f32> = samples
+        .windows(2)
+        .enumerate()
+        .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+        .map(|(index, pair)| index as f32 + pair[0] / (pair[0] - pair[1]))
+        .collect();
+    if crossings.len() < 2 {
+        return None;
+    }
+    let span = crossings[crossings.len() - 1] - crossings[0];
+    Some((crossings.len() - 1) as f32 * sample_rate / span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_offline_render_passes_at_the_common_sample_rates() {
+        for sample_rate in [44_100.0, 48_000.0, 96_000.0] {
+            let check = check_offline_render(sample_rate).unwrap();
+            assert_eq!(check.expected_fundamental, 440.0);
+            let cents = 1200.0 * (check.fundamental / 440.0).log2();
+            assert!(
+                cents.abs() < 1.0,
+                "{} Hz at {}",
+                check.fundamental,
+                sample_rate
+            );
+            assert!((MIN_RMS..=MAX_RMS).contains(&check.rms));
+        }
+    }
+
+    #[test]
+    fn the_fundamental_is_read_from_the_zero_crossings() {
+        let sample_rate = 48_000.0;
+        for frequency in [55.0, 440.0, 3_520.0] {
+            let sine: Vec<f32> = (0..sample_rate as usize / 4)
+                .map(|i| (std::f32::consts::TAU * frequency * i as f32 / sample_rate).sin())
+                .collect();
+            let estimate = estimate_fundamental(&sine, sample_rate).unwrap();
+            assert!((estimate / frequency - 1.0).abs() < 1e-3, "{}", estimate);
+        }
+    }
+
+    #[test]
+    fn a_signal_that_never_crosses_zero_has_no_fundamental() {
+        assert_eq!(estimate_fundamental(&[0.5; 1_000], 48_000.0), None);
+        assert_eq!(estimate_fundamental(&[0.0; 1_000], 48_000.0), None);
+        assert_eq!(estimate_fundamental(&[-1.0, 1.0], 48_000.0), None);
+    }
+}
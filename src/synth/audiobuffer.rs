@@ -1,3 +1,16 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+/// Default for the most downsampled values per channel handed to the visualizer each frame.
+/// The `max_audio_samples` visualizer setting overrides it.
+pub const MAX_VISUAL_SAMPLES: usize = 1024;
+
+/// Frame rate the visualizer assumes when the display's refresh rate is unknown.
+pub const DEFAULT_VISUAL_FPS: f32 = 60.0;
+
 #[derive(Clone)]
 pub struct AudioBuffer {
     pub data: Vec<f32>,
@@ -23,8 +36,405 @@ impl AudioBuffer {
         let channel_len = self.num_frames();
         &mut self.data[start_index..start_index + channel_len]
     }
+
+    /// The samples frame by frame, every channel's sample for a frame together, as audio
+    /// devices take them. A mono buffer is already laid out that way and is borrowed as it is.
+    pub fn interleaved(&self) -> Cow<'_, [f32]> {
+        if self.num_channels <= 1 {
+            return Cow::Borrowed(&self.data);
+        }
+        let mut interleaved = vec![0.0; self.data.len()];
+        for channel in 0..self.num_channels {
+            for (frame, sample) in self.channel(channel).iter().enumerate() {
+                interleaved[frame * self.num_channels + channel] = *sample;
+            }
+        }
+        interleaved.into()
+    }
+}
+
+/// One visual frame's worth of downsampled audio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VisualFrame {
+    /// The first (left) channel of the tapped signal.
+    pub samples: Vec<f32>,
+    /// The second (right) channel, or a copy of the first on a mono device.
+    pub right_samples: Vec<f32>,
+    /// With the `both` visual tap, the pre-effects mix's left and right channels, drawn over
+    /// the output.
+    pub overlay: Option<(Vec<f32>, Vec<f32>)>,
+    /// Whether the frame is a held shape with nothing playing it, drawn dimmed.
+    pub dimmed: bool,
+    /// RMS level of the tapped audio the frame was made from, across its channels and before
+    /// downsampling, in dBFS.
+    pub rms_db: f32,
+}
+
+impl Default for VisualFrame {
+    fn default() -> Self {
+        VisualFrame {
+            samples: Vec::new(),
+            right_samples: Vec::new(),
+            overlay: None,
+            dimmed: false,
+            rms_db: f32::NEG_INFINITY,
+        }
+    }
+}
+
+/// RMS level of `samples`, in dBFS. Silence, and no samples at all, are -inf.
+pub fn rms_db(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let squares: f32 = samples.iter().map(|sample| sample * sample).sum();
+    10.0 * (squares / samples.len() as f32).log10()
 }
 
 pub struct DownsampledAudioData {
-    pub samples: [[f32; 16]; 256],
+    /// The first (left) channel, at most the configured number of values.
+    pub samples: Vec<f32>,
+    /// The second (right) channel, or a copy of the first on a mono device.
+    pub right_samples: Vec<f32>,
+    /// The second stream of the `both` visual tap, as left and right channels.
+    pub overlay: Option<(Vec<f32>, Vec<f32>)>,
+    /// Whether the current frame is drawn dimmed.
+    pub dimmed: bool,
+    /// The current frame's RMS level, in dBFS, as published with it.
+    pub rms_db: f32,
+    /// Rate the visualizer draws at. The audio thread hands over one block of samples per
+    /// visual frame, so this sets how much audio goes into each block.
+    pub visual_fps: f32,
+    /// Frames handed over but not drawn yet, the oldest first.
+    queued: VecDeque<VisualFrame>,
+    /// Frames handed over since the start, drawn or not.
+    published: u64,
+}
+
+impl DownsampledAudioData {
+    pub fn new(visual_fps: f32) -> Self {
+        DownsampledAudioData {
+            samples: Vec::new(),
+            right_samples: Vec::new(),
+            overlay: None,
+            dimmed: false,
+            rms_db: f32::NEG_INFINITY,
+            visual_fps,
+            queued: VecDeque::new(),
+            published: 0,
+        }
+    }
+
+    /// Queues `frames`, the oldest first, to be drawn one per visual frame. A large audio
+    /// callback hands over several frames at once; frames from before the previous batch are
+    /// dropped so the display doesn't fall behind the audio.
+    pub fn publish(&mut self, frames: Vec<VisualFrame>) {
+        let batch = frames.len();
+        self.published += batch as u64;
+        self.queued.extend(frames);
+        while self.queued.len() > batch + 1 {
+            self.queued.pop_front();
+        }
+    }
+
+    /// Moves the oldest queued frame into `samples`, `right_samples`, `overlay`, `dimmed` and
+    /// `rms_db`. Returns false, leaving the last frame in place, when nothing new has arrived.
+    pub fn next_frame(&mut self) -> bool {
+        let Some(frame) = self.queued.pop_front() else {
+            return false;
+        };
+        self.samples = frame.samples;
+        self.right_samples = frame.right_samples;
+        self.overlay = frame.overlay;
+        self.dimmed = frame.dimmed;
+        self.rms_db = frame.rms_db;
+        true
+    }
+
+    /// Frames waiting to be drawn.
+    pub fn queued_frames(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// Frames handed over since the start, drawn or not.
+    pub fn published_frames(&self) -> u64 {
+        self.published
+    }
+}
+
+/// How many samples of each channel go into one visual frame at `visual_fps`. Rates that
+/// aren't positive fall back to `DEFAULT_VISUAL_FPS`.
+pub fn visual_downsample_factor(sample_rate: f32, visual_fps: f32) -> usize {
+    let visual_fps = if visual_fps.is_finite() && visual_fps > 0.0 {
+        visual_fps
+    } else {
+        DEFAULT_VISUAL_FPS
+    };
+    ((sample_rate / visual_fps) as usize).max(1)
+}
+
+/// How the samples behind each downsampled value are weighted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownsampleWindow {
+    /// Every sample counts the same. Cheapest, but high notes can alias into slow false waves.
+    #[default]
+    Boxcar,
+    /// Samples near the middle count most, tapering off towards the edges, for smoother
+    /// visuals.
+    Hann,
+}
+
+/// How the audio is averaged down for the visualizer.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownsampleConfig {
+    pub window: DownsampleWindow,
+    /// Samples averaged into each value, centered on the stretch of audio the value stands
+    /// for. More than the downsample factor overlaps neighbouring values, which smooths further
+    /// with a Hann window. Unset uses exactly the downsample factor.
+    pub taps: Option<usize>,
+}
+
+/// Weight of tap `index` of `len` under `window`. The Hann window is sampled at the middle of
+/// each tap, so neither end tap is wasted on a zero weight.
+fn window_weight(window: DownsampleWindow, index: usize, len: usize) -> f32 {
+    match window {
+        DownsampleWindow::Boxcar => 1.0,
+        DownsampleWindow::Hann => 0.5 - 0.5 * (2.0 * PI * (index as f32 + 0.5) / len as f32).cos(),
+    }
+}
+
+/// The average of `samples` weighted by `window`, or 0.0 for no samples.
+pub fn windowed_average(samples: &[f32], window: DownsampleWindow) -> f32 {
+    let (weighted_sum, total_weight) = samples.iter().enumerate().fold(
+        (0.0, 0.0),
+        |(weighted_sum, total_weight), (index, sample)| {
+            let weight = window_weight(window, index, samples.len());
+            (weighted_sum + weight * sample, total_weight + weight)
+        },
+    );
+    if total_weight > 0.0 {
+        weighted_sum / total_weight
+    } else {
+        0.0
+    }
+}
+
+/// Averages `channel` of `interleaved` audio down to one value per `factor` samples, keeping
+/// the most recent `capacity` values when there are more. Channels past the last repeat it, so
+/// a mono device shows the same channel on both sides of a split display.
+pub fn downsample_channel(
+    interleaved: &[f32],
+    channels: usize,
+    channel: usize,
+    factor: usize,
+    capacity: usize,
+    config: &DownsampleConfig,
+) -> Vec<f32> {
+    let channels = channels.max(1);
+    let factor = factor.max(1);
+    let channel_samples: Vec<f32> = interleaved
+        .iter()
+        .skip(channel.min(channels - 1))
+        .step_by(channels)
+        .cloned()
+        .collect();
+    let taps = config.taps.unwrap_or(factor).max(1);
+    let mut downsampled: Vec<f32> = (0..channel_samples.len())
+        .step_by(factor)
+        .map(|chunk_start| {
+            // The taps are centered on the chunk, so with as many taps as the factor they cover
+            // the chunk exactly. At the edges of the audio they are cut short.
+            let start = (chunk_start + factor / 2).saturating_sub(taps / 2);
+            let end = (start + taps).min(channel_samples.len());
+            windowed_average(&channel_samples[start..end], config.window)
+        })
+        .collect();
+    let excess = downsampled.len().saturating_sub(capacity);
+    downsampled.drain(..excess);
+    downsampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_downsample_factor_follows_the_refresh_rate() {
+        assert_eq!(visual_downsample_factor(48_000.0, 60.0), 800);
+        assert_eq!(visual_downsample_factor(48_000.0, 120.0), 400);
+        assert_eq!(visual_downsample_factor(48_000.0, 144.0), 333);
+        assert_eq!(visual_downsample_factor(44_100.0, 60.0), 735);
+        assert_eq!(visual_downsample_factor(44_100.0, 144.0), 306);
+    }
+
+    #[test]
+    fn bad_refresh_rates_fall_back_to_the_default() {
+        let default = visual_downsample_factor(48_000.0, DEFAULT_VISUAL_FPS);
+        for fps in [0.0, -60.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(visual_downsample_factor(48_000.0, fps), default, "{}", fps);
+        }
+        // A rate above the sample rate still takes at least one sample a frame.
+        assert_eq!(visual_downsample_factor(48_000.0, 96_000.0), 1);
+    }
+
+    /// `frames` stereo frames, each run of `factor` holding its run's index, negated on the
+    /// right.
+    fn numbered_runs(frames: usize, factor: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|frame| {
+                let run = (frame / factor) as f32;
+                [run, -run]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn more_than_256_downsampled_values_keep_the_most_recent() {
+        let interleaved = numbered_runs(300 * 16, 16);
+        let config = DownsampleConfig::default();
+        let left = downsample_channel(&interleaved, 2, 0, 16, 256, &config);
+        let right = downsample_channel(&interleaved, 2, 1, 16, 256, &config);
+        let expected: Vec<f32> = (44..300).map(|run| run as f32).collect();
+        assert_eq!(left, expected);
+        assert_eq!(right, expected.iter().map(|run| -run).collect::<Vec<_>>());
+
+        // A larger capacity takes all 300.
+        let all = downsample_channel(&interleaved, 2, 0, 16, MAX_VISUAL_SAMPLES, &config);
+        assert_eq!(all.len(), 300);
+        assert_eq!(all[0], 0.0);
+    }
+
+    #[test]
+    fn a_mono_device_repeats_its_channel_on_both_sides() {
+        let mono: Vec<f32> = (0..64).map(|i| (i / 16) as f32).collect();
+        let config = DownsampleConfig::default();
+        let left = downsample_channel(&mono, 1, 0, 16, 256, &config);
+        assert_eq!(left, [0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(downsample_channel(&mono, 1, 1, 16, 256, &config), left);
+    }
+
+    #[test]
+    fn a_hann_average_matches_the_weighted_sum() {
+        // Sampled at the middle of each of four taps, the Hann window weighs the ends
+        // (2 - sqrt 2) / 4 and the middle two (2 + sqrt 2) / 4, for a total of 2.
+        let edge = (2.0 - 2.0f32.sqrt()) / 4.0;
+        let middle = (2.0 + 2.0f32.sqrt()) / 4.0;
+        let samples = [4.0, 0.0, 1.0, -2.0];
+        let expected = (4.0 * edge + 1.0 * middle - 2.0 * edge) / 2.0;
+        let average = windowed_average(&samples, DownsampleWindow::Hann);
+        assert!(
+            (average - expected).abs() < 1e-6,
+            "{} against {}",
+            average,
+            expected
+        );
+    }
+
+    #[test]
+    fn a_boxcar_average_is_the_mean_and_no_samples_average_to_zero() {
+        assert_eq!(
+            windowed_average(&[4.0, 0.0, 1.0, -1.0], DownsampleWindow::Boxcar),
+            1.0
+        );
+        assert_eq!(windowed_average(&[], DownsampleWindow::Boxcar), 0.0);
+        assert_eq!(windowed_average(&[], DownsampleWindow::Hann), 0.0);
+        // A single tap sits at the window's peak.
+        assert_eq!(windowed_average(&[0.3], DownsampleWindow::Hann), 0.3);
+    }
+
+    #[test]
+    fn overlapping_hann_taps_let_less_of_a_high_tone_through() {
+        // A tone just off the downsample rate, which a boxcar aliases into a slow wave.
+        let factor = 64;
+        let tone: Vec<f32> = (0..factor * 64)
+            .map(|i| (2.0 * PI * i as f32 * 1.1 / factor as f32).sin())
+            .collect();
+        let peak = |config: &DownsampleConfig| {
+            downsample_channel(&tone, 1, 0, factor, 256, config)
+                .iter()
+                .fold(0.0f32, |peak, value| peak.max(value.abs()))
+        };
+        let boxcar = peak(&DownsampleConfig::default());
+        let hann = peak(&DownsampleConfig {
+            window: DownsampleWindow::Hann,
+            taps: Some(2 * factor),
+        });
+        assert!(
+            hann < boxcar / 2.0,
+            "hann {} against boxcar {}",
+            hann,
+            boxcar
+        );
+    }
+
+    /// A frame whose samples are all `value`.
+    fn visual_frame(value: f32) -> VisualFrame {
+        VisualFrame {
+            samples: vec![value; 4],
+            right_samples: vec![value; 4],
+            ..VisualFrame::default()
+        }
+    }
+
+    #[test]
+    fn rms_is_measured_across_every_sample() {
+        assert_eq!(rms_db(&[]), f32::NEG_INFINITY);
+        assert_eq!(rms_db(&[0.0; 8]), f32::NEG_INFINITY);
+        assert!(rms_db(&[1.0, -1.0, 1.0, -1.0]).abs() < 1e-6);
+        assert!((rms_db(&[1.0, 0.0, 1.0, 0.0]) + 3.0103).abs() < 1e-3);
+    }
+
+    #[test]
+    fn the_published_level_moves_with_its_frame() {
+        let mut shared = DownsampledAudioData::new(60.0);
+        assert_eq!(shared.rms_db, f32::NEG_INFINITY);
+        shared.publish(vec![
+            VisualFrame {
+                rms_db: -6.0,
+                ..visual_frame(1.0)
+            },
+            visual_frame(0.0),
+        ]);
+        shared.next_frame();
+        assert_eq!(shared.rms_db, -6.0);
+        shared.next_frame();
+        assert_eq!(shared.rms_db, f32::NEG_INFINITY);
+        // The last frame stays up, and its level with it.
+        shared.next_frame();
+        assert_eq!(shared.rms_db, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn published_frames_are_drawn_oldest_first_one_at_a_time() {
+        let mut shared = DownsampledAudioData::new(60.0);
+        assert!(!shared.next_frame());
+        shared.publish(vec![
+            visual_frame(1.0),
+            visual_frame(2.0),
+            visual_frame(3.0),
+        ]);
+        for value in [1.0, 2.0, 3.0] {
+            assert!(shared.next_frame());
+            assert_eq!(shared.samples[0], value);
+        }
+        // With nothing new the last frame stays up.
+        assert!(!shared.next_frame());
+        assert_eq!(shared.samples[0], 3.0);
+        assert_eq!(shared.published_frames(), 3);
+    }
+
+    #[test]
+    fn an_undrawn_backlog_is_cut_to_the_latest_batch_and_one_more() {
+        let mut shared = DownsampledAudioData::new(60.0);
+        for batch in 0..4 {
+            let base = 10.0 * batch as f32;
+            shared.publish(vec![visual_frame(base), visual_frame(base + 1.0)]);
+        }
+        assert_eq!(shared.queued_frames(), 3);
+        assert_eq!(shared.published_frames(), 8);
+        shared.next_frame();
+        assert_eq!(shared.samples[0], 21.0);
+    }
 }
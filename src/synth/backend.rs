@@ -0,0 +1,301 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use cpal::traits::HostTrait;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::synth::{ChannelRoutingConfig, DitherConfig};
+
+/// The audio host the synth plays through.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// cpal's default host for the platform, e.g. ALSA on Linux.
+    #[default]
+    Default,
+    /// A named JACK client. Needs the `jack` cargo feature.
+    Jack,
+}
+
+impl Backend {
+    /// Name of the audio host, as shown in the device report.
+    pub fn host_name(&self) -> &'static str {
+        match self {
+            Backend::Default => cpal::default_host().id().name(),
+            Backend::Jack => "JACK",
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "default" => Ok(Backend::Default),
+            "jack" => Ok(Backend::Jack),
+            _ => bail!(
+                "Unknown audio backend '{}', expected 'default' or 'jack'",
+                name
+            ),
+        }
+    }
+}
+
+/// Settings for the audio host.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// Backend to try when none is given with `--backend`. Falls back to the default host if
+    /// it isn't available.
+    pub backend: Backend,
+    pub jack: JackConfig,
+    /// How the signal is spread over the output device's channels.
+    pub routing: ChannelRoutingConfig,
+    /// How samples are dithered for devices that take 16-bit or 8-bit integers.
+    pub dither: DitherConfig,
+}
+
+/// Settings for the JACK backend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JackConfig {
+    /// Client name; JACK shows the output client as `<client_name>_out`.
+    pub client_name: String,
+    /// Port name pattern to connect the outputs to, e.g. `system:playback_*`. When unset the
+    /// outputs are left unconnected.
+    pub auto_connect: Option<String>,
+}
+
+impl Default for JackConfig {
+    fn default() -> Self {
+        JackConfig {
+            client_name: "visiosynth".to_string(),
+            auto_connect: None,
+        }
+    }
+}
+
+/// Decides which backend to open.
+///
+/// A backend passed explicitly on the command line must be available. One that only comes from
+/// the config falls back to the default host with a warning, so a missing JACK server doesn't
+/// stop the synth from starting.
+pub fn select_backend(
+    cli_backend: Option<Backend>,
+    config_backend: Backend,
+    jack_available: bool,
+) -> Result<Backend> {
+    let requested = cli_backend.unwrap_or(config_backend);
+    match requested {
+        Backend::Default => Ok(Backend::Default),
+        Backend::Jack if jack_available => Ok(Backend::Jack),
+        Backend::Jack if cli_backend.is_some() => Err(anyhow!(
+            "JACK was requested with --backend jack, but no JACK server is running{}",
+            jack_unavailable_hint()
+        )),
+        Backend::Jack => {
+            warn!(
+                "JACK is not available{}; falling back to the default audio host",
+                jack_unavailable_hint()
+            );
+            Ok(Backend::Default)
+        }
+    }
+}
+
+fn jack_unavailable_hint() -> &'static str {
+    if cfg!(feature = "jack") {
+        ""
+    } else {
+        " (built without the `jack` feature)"
+    }
+}
+
+/// Opens the output device for the selected backend, returning which backend it came from.
+pub fn open_output_device(
+    cli_backend: Option<Backend>,
+    audio_config: &AudioConfig,
+) -> Result<(Backend, cpal::Device)> {
+    let wants_jack = cli_backend.unwrap_or(audio_config.backend) == Backend::Jack;
+    let jack_device = if wants_jack {
+        open_jack_device(&audio_config.jack)
+    } else {
+        None
+    };
+
+    match select_backend(cli_backend, audio_config.backend, jack_device.is_some())? {
+        Backend::Jack => {
+            info!(
+                "Playing through JACK as '{}_out'",
+                audio_config.jack.client_name
+            );
+            let device = jack_device.ok_or_else(|| anyhow!("JACK device disappeared"))?;
+            Ok((Backend::Jack, device))
+        }
+        Backend::Default => {
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or(anyhow::Error::msg("No output device available"))?;
+            Ok((Backend::Default, device))
+        }
+    }
+}
+
+#[cfg(feature = "jack")]
+fn open_jack_device(jack_config: &JackConfig) -> Option<cpal::Device> {
+    let mut host = cpal::platform::JackHost::new().ok()?;
+    // Don't spawn a server of our own; a missing server should be reported, not papered over.
+    host.set_start_server_automatically(false);
+    // With a pattern we connect the ports ourselves once the stream is running.
+    host.set_connect_automatically(false);
+    host.output_device_with_name(&jack_config.client_name)
+        .map(cpal::Device::from)
+}
+
+#[cfg(not(feature = "jack"))]
+fn open_jack_device(_jack_config: &JackConfig) -> Option<cpal::Device> {
+    None
+}
+
+/// Connects the synth's JACK outputs to the ports matching `pattern`, pairing them up in order.
+///
+/// Call this after the stream has started, since that is when cpal registers the ports.
+#[cfg(feature = "jack")]
+pub fn connect_jack_outputs(client_name: &str, pattern: &str) -> Result<()> {
+    let (client, _) = jack::Client::new(
+        &format!("{}_connect", client_name),
+        jack::ClientOptions::NO_START_SERVER,
+    )?;
+
+    let own_ports = client.ports(
+        Some(&format!(
+            "^{}:out_",
+            regex_escape(&format!("{}_out", client_name))
+        )),
+        None,
+        jack::PortFlags::IS_OUTPUT,
+    );
+    let targets = client.ports(
+        Some(&glob_to_regex(pattern)),
+        None,
+        jack::PortFlags::IS_INPUT,
+    );
+    if targets.is_empty() {
+        warn!("No JACK input ports match '{}'", pattern);
+    }
+
+    for (source, destination) in own_ports.iter().zip(targets.iter()) {
+        match client.connect_ports_by_name(source, destination) {
+            Ok(()) => info!("Connected {} -> {}", source, destination),
+            Err(err) => warn!("Unable to connect {} -> {}: {}", source, destination, err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "jack"))]
+pub fn connect_jack_outputs(_client_name: &str, _pattern: &str) -> Result<()> {
+    Ok(())
+}
+
+/// Turns a `*`/`?` glob into an anchored regex, the pattern syntax JACK's port lookup uses.
+#[cfg(feature = "jack")]
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for character in glob.chars() {
+        match character {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            _ => regex.push_str(&regex_escape(&character.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(feature = "jack")]
+fn regex_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_default_backend_is_used_whether_or_not_jack_is_running() {
+        for jack_available in [false, true] {
+            assert_eq!(
+                select_backend(None, Backend::Default, jack_available).unwrap(),
+                Backend::Default
+            );
+            assert_eq!(
+                select_backend(Some(Backend::Default), Backend::Jack, jack_available).unwrap(),
+                Backend::Default
+            );
+        }
+    }
+
+    #[test]
+    fn jack_is_used_when_it_is_running() {
+        assert_eq!(
+            select_backend(Some(Backend::Jack), Backend::Default, true).unwrap(),
+            Backend::Jack
+        );
+        assert_eq!(
+            select_backend(None, Backend::Jack, true).unwrap(),
+            Backend::Jack
+        );
+    }
+
+    #[test]
+    fn jack_from_the_config_falls_back_to_the_default_host_when_it_is_not_running() {
+        assert_eq!(
+            select_backend(None, Backend::Jack, false).unwrap(),
+            Backend::Default
+        );
+    }
+
+    #[test]
+    fn jack_asked_for_on_the_command_line_is_an_error_when_it_is_not_running() {
+        let error = select_backend(Some(Backend::Jack), Backend::Default, false).unwrap_err();
+        assert!(error.to_string().contains("--backend jack"));
+        assert!(select_backend(Some(Backend::Jack), Backend::Jack, false).is_err());
+    }
+
+    #[test]
+    fn backend_names_parse() {
+        assert_eq!("default".parse::<Backend>().unwrap(), Backend::Default);
+        assert_eq!("jack".parse::<Backend>().unwrap(), Backend::Jack);
+        assert!("pulse".parse::<Backend>().is_err());
+        assert!("JACK".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn audio_config_reads_the_backend_and_jack_settings() {
+        let config: AudioConfig = serde_yaml::from_str(
+            "backend: jack\njack:\n  client_name: synth\n  auto_connect: \"system:playback_*\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.backend, Backend::Jack);
+        assert_eq!(config.jack.client_name, "synth");
+        assert_eq!(
+            config.jack.auto_connect.as_deref(),
+            Some("system:playback_*")
+        );
+
+        let config: AudioConfig = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(config.backend, Backend::Default);
+        assert_eq!(config.jack.client_name, "visiosynth");
+        assert_eq!(config.jack.auto_connect, None);
+    }
+}
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::synth::{AudioBuffer, ShaperCurve};
+
+/// The bus voices go to unless routed elsewhere. It is always there, at full level with no
+/// shaper when the config doesn't declare it.
+pub const MAIN_BUS: &str = "main";
+
+/// A bus voices can be routed to, mixed into the master chain at a level of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BusConfig {
+    pub name: String,
+    /// Level the bus is mixed in at; 1.0 leaves it as the voices made it.
+    pub gain: f32,
+    /// A curve the bus alone is bent through before its gain, on top of the master wave shaper.
+    pub shaper: Option<ShaperCurve>,
+    /// How hard the bus drives its shaper.
+    pub drive: f32,
+}
+
+impl Default for BusConfig {
+    fn default() -> Self {
+        BusConfig {
+            name: MAIN_BUS.to_string(),
+            gain: 1.0,
+            shaper: None,
+            drive: 1.0,
+        }
+    }
+}
+
+/// Which bus a voice is mixed on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BusId(usize);
+
+impl BusId {
+    pub const MAIN: BusId = BusId(0);
+}
+
+struct Bus {
+    name: String,
+    gain: f32,
+    shaper: Option<fn(f32) -> f32>,
+    drive: f32,
+    /// The bus's voices for the block being rendered.
+    buffer: AudioBuffer,
+}
+
+/// The buses voices are mixed on before they reach the master chain.
+///
+/// Each bus renders into a buffer of its own, which is bent through the bus's shaper, scaled by
+/// its gain and summed into the output. The buffers keep their size from block to block, so
+/// they only grow when a block is longer than any before it. With nothing but an untouched main
+/// bus there is nothing to do between the voices and the output, so the engine writes the
+/// voices straight into the output instead.
+pub struct BusGraph {
+    buses: Vec<Bus>,
+}
+
+impl BusGraph {
+    /// Builds the buses in `configs`, with the main bus first. A name declared twice keeps its
+    /// first settings.
+    pub fn new(configs: &[BusConfig], num_channels: usize) -> Self {
+        let main = configs
+            .iter()
+            .find(|config| config.name == MAIN_BUS)
+            .cloned()
+            .unwrap_or_default();
+        let mut buses = vec![Bus::new(&main, num_channels)];
+        for config in configs {
+            if buses.iter().any(|bus| bus.name == config.name) {
+                if config.name != MAIN_BUS {
+                    warn!("Bus {:?} is declared twice, keeping the first", config.name);
+                }
+                continue;
+            }
+            buses.push(Bus::new(config, num_channels));
+        }
+        BusGraph { buses }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buses.is_empty()
+    }
+
+    /// The buses' names, the main bus first.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.buses.iter().map(|bus| bus.name.as_str())
+    }
+
+    /// The bus called `name`, if there is one.
+    pub fn id(&self, name: &str) -> Option<BusId> {
+        self.buses
+            .iter()
+            .position(|bus| bus.name == name)
+            .map(BusId)
+    }
+
+    /// The bus a voice routed to `name` is mixed on: the main bus when unrouted, or routed to a
+    /// bus that doesn't exist.
+    pub fn route(&self, name: Option<&str>) -> BusId {
+        name.and_then(|name| self.id(name)).unwrap_or(BusId::MAIN)
+    }
+
+    /// Whether voices can be written straight into the output: the main bus is the only one, at
+    /// full level with no shaper.
+    pub fn is_direct(&self) -> bool {
+        match self.buses.as_slice() {
+            [main] => main.gain == 1.0 && main.shaper.is_none(),
+            _ => false,
+        }
+    }
+
+    /// Clears every bus for a block laid out like `output`.
+    pub fn begin_block(&mut self, output: &AudioBuffer) {
+        for bus in self.buses.iter_mut() {
+            bus.buffer.num_channels = output.num_channels;
+            bus.buffer.data.clear();
+            bus.buffer.data.resize(output.data.len(), 0.0);
+        }
+    }
+
+    /// The buffer `bus`'s voices are mixed into for the current block.
+    pub fn buffer_mut(&mut self, bus: BusId) -> &mut AudioBuffer {
+        &mut self.buses[bus.0].buffer
+    }
+
+    /// Runs every bus through its shaper and gain and adds it to `output`.
+    pub fn mix_into(&mut self, output: &mut AudioBuffer) {
+        for bus in self.buses.iter_mut() {
+            if let Some(shaper) = bus.shaper {
+                for sample in bus.buffer.data.iter_mut() {
+                    *sample = shaper(*sample * bus.drive);
+                }
+            }
+            for (sample, bus_sample) in output.data.iter_mut().zip(&bus.buffer.data) {
+                *sample += bus_sample * bus.gain;
+            }
+        }
+    }
+}
+
+impl Bus {
+    fn new(config: &BusConfig, num_channels: usize) -> Self {
+        Bus {
+            name: config.name.clone(),
+            gain: config.gain.max(0.0),
+            shaper: config.shaper.map(ShaperCurve::transfer_fn),
+            drive: config.drive,
+            buffer: AudioBuffer {
+                data: Vec::new(),
+                num_channels,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bus(name: &str, gain: f32, shaper: Option<ShaperCurve>) -> BusConfig {
+        BusConfig {
+            name: name.to_string(),
+            gain,
+            shaper,
+            drive: 1.0,
+        }
+    }
+
+    #[test]
+    fn the_main_bus_comes_first_and_a_name_declared_twice_keeps_its_first_settings() {
+        let graph = BusGraph::new(
+            &[
+                bus("bass", 0.5, None),
+                bus(MAIN_BUS, 0.8, None),
+                bus("bass", 2.0, None),
+                bus("ribbon", 1.0, None),
+            ],
+            2,
+        );
+        assert_eq!(
+            graph.names().collect::<Vec<_>>(),
+            ["main", "bass", "ribbon"]
+        );
+        assert_eq!(graph.id(MAIN_BUS), Some(BusId::MAIN));
+        assert_eq!(graph.buses[0].gain, 0.8);
+        assert_eq!(graph.buses[1].gain, 0.5);
+
+        let graph = BusGraph::new(&[], 1);
+        assert_eq!(graph.names().collect::<Vec<_>>(), ["main"]);
+        assert_eq!(graph.buses[0].gain, 1.0);
+    }
+
+    #[test]
+    fn unrouted_voices_and_unknown_buses_go_to_the_main_bus() {
+        let graph = BusGraph::new(&[bus("bass", 1.0, None)], 1);
+        assert_eq!(graph.route(Some("bass")), BusId(1));
+        assert_eq!(graph.route(Some("drums")), BusId::MAIN);
+        assert_eq!(graph.route(None), BusId::MAIN);
+    }
+
+    #[test]
+    fn only_an_untouched_main_bus_is_written_to_directly() {
+        assert!(BusGraph::new(&[], 1).is_direct());
+        assert!(BusGraph::new(&[bus(MAIN_BUS, 1.0, None)], 1).is_direct());
+        assert!(!BusGraph::new(&[bus(MAIN_BUS, 0.5, None)], 1).is_direct());
+        assert!(!BusGraph::new(&[bus(MAIN_BUS, 1.0, Some(ShaperCurve::Tanh))], 1).is_direct());
+        assert!(!BusGraph::new(&[bus("bass", 1.0, None)], 1).is_direct());
+    }
+
+    #[test]
+    fn each_bus_is_shaped_and_scaled_on_its_own_before_the_mix() {
+        let mut graph = BusGraph::new(
+            &[
+                bus("quiet", 0.25, None),
+                BusConfig {
+                    drive: 4.0,
+                    ..bus("clipped", 1.0, Some(ShaperCurve::HardClip))
+                },
+            ],
+            1,
+        );
+        let mut output = AudioBuffer {
+            data: vec![0.0; 3],
+            num_channels: 1,
+        };
+        graph.begin_block(&output);
+        graph
+            .buffer_mut(BusId::MAIN)
+            .data
+            .copy_from_slice(&[0.5, -0.5, 0.0]);
+        graph
+            .buffer_mut(BusId(1))
+            .data
+            .copy_from_slice(&[0.5, 0.0, 0.5]);
+        graph
+            .buffer_mut(BusId(2))
+            .data
+            .copy_from_slice(&[0.0, 0.125, 0.5]);
+        graph.mix_into(&mut output);
+        // Main as it is, the quiet bus at a quarter, the clipped one driven into the ceiling.
+        assert_eq!(output.data, [0.625, 0.0, 1.125]);
+    }
+
+    #[test]
+    fn a_new_block_starts_every_bus_silent_and_shaped_like_the_output() {
+        let mut graph = BusGraph::new(&[bus("bass", 1.0, None)], 1);
+        let output = AudioBuffer {
+            data: vec![0.0; 512],
+            num_channels: 2,
+        };
+        graph.begin_block(&output);
+        graph.buffer_mut(BusId(1)).data.fill(0.3);
+        let capacity = graph.buffer_mut(BusId(1)).data.capacity();
+
+        let shorter = AudioBuffer {
+            data: vec![0.0; 128],
+            num_channels: 2,
+        };
+        graph.begin_block(&shorter);
+        let buffer = graph.buffer_mut(BusId(1));
+        assert_eq!(buffer.num_channels(), 2);
+        assert_eq!(buffer.data, vec![0.0; 128]);
+        // The buffer keeps its room for the longer block.
+        assert_eq!(buffer.data.capacity(), capacity);
+    }
+}
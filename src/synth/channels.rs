@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+
+/// What device channels past the signal's own get.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtraChannels {
+    /// Left silent, so a stereo signal on a surround device plays from the front pair only.
+    #[default]
+    Silent,
+    /// Repeat the signal's channels in turn: left, right, left, right, ...
+    Duplicate,
+}
+
+/// How the synth's signal is laid out across the output device's channels.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelRoutingConfig {
+    /// A mono signal always plays from every channel; this only applies to a signal with more
+    /// than one channel, on a device with more channels still.
+    pub extra_channels: ExtraChannels,
+}
+
+/// Lays the interleaved `source`, with `source_channels` channels, out over `out`, with
+/// `out_channels` channels, one frame at a time.
+///
+/// A mono source is copied to every output channel. Otherwise the source's channels go to the
+/// first output channels in order, and the rest are filled as `extra_channels` says. A source
+/// with more channels than the output is mixed down to mono on a mono device and loses its
+/// extra channels on any other.
+///
+/// Stops at whichever of `source` and `out` runs out of whole frames first.
+pub fn route_channels(
+    source: &[f32],
+    source_channels: usize,
+    out: &mut [f32],
+    out_channels: usize,
+    extra_channels: ExtraChannels,
+) {
+    let source_channels = source_channels.max(1);
+    let out_channels = out_channels.max(1);
+    let frames = source
+        .chunks_exact(source_channels)
+        .zip(out.chunks_exact_mut(out_channels));
+
+    for (source_frame, out_frame) in frames {
+        if out_channels == 1 {
+            out_frame[0] = source_frame.iter().sum::<f32>() / source_channels as f32;
+            continue;
+        }
+        for (channel, sample) in out_frame.iter_mut().enumerate() {
+            *sample = if source_channels == 1 {
+                source_frame[0]
+            } else if channel < source_channels {
+                source_frame[channel]
+            } else {
+                match extra_channels {
+                    ExtraChannels::Silent => 0.0,
+                    ExtraChannels::Duplicate => source_frame[channel % source_channels],
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Routes `source` onto a device with `out_channels` channels.
+    fn route(
+        source: &[f32],
+        source_channels: usize,
+        out_channels: usize,
+        extra_channels: ExtraChannels,
+    ) -> Vec<f32> {
+        let frames = source.len() / source_channels;
+        let mut out = vec![f32::NAN; frames * out_channels];
+        route_channels(
+            source,
+            source_channels,
+            &mut out,
+            out_channels,
+            extra_channels,
+        );
+        out
+    }
+
+    #[test]
+    fn a_mono_signal_plays_from_every_channel() {
+        let source = [0.1, -0.2, 0.3];
+        for out_channels in 1..=8 {
+            for extra_channels in [ExtraChannels::Silent, ExtraChannels::Duplicate] {
+                let out = route(&source, 1, out_channels, extra_channels);
+                for (frame, &sample) in out.chunks_exact(out_channels).zip(&source) {
+                    assert!(frame.iter().all(|&routed| routed == sample));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_stereo_signal_fills_the_front_pair_and_leaves_the_rest_silent() {
+        let source = [0.1, -0.1, 0.2, -0.2];
+        let out = route(&source, 2, 6, ExtraChannels::Silent);
+        assert_eq!(
+            out,
+            [
+                0.1, -0.1, 0.0, 0.0, 0.0, 0.0, //
+                0.2, -0.2, 0.0, 0.0, 0.0, 0.0,
+            ]
+        );
+    }
+
+    #[test]
+    fn a_stereo_signal_can_repeat_across_the_extra_channels() {
+        let source = [0.1, -0.1];
+        let out = route(&source, 2, 5, ExtraChannels::Duplicate);
+        assert_eq!(out, [0.1, -0.1, 0.1, -0.1, 0.1]);
+    }
+
+    #[test]
+    fn a_stereo_signal_is_mixed_down_on_a_mono_device() {
+        let source = [0.5, 0.25, -0.5, 0.5];
+        assert_eq!(route(&source, 2, 1, ExtraChannels::Silent), [0.375, 0.0]);
+    }
+
+    #[test]
+    fn routing_stops_at_the_shorter_buffer() {
+        let source = [0.1, 0.2, 0.3, 0.4];
+        let mut out = [f32::NAN; 4];
+        route_channels(&source, 1, &mut out, 2, ExtraChannels::Silent);
+        assert_eq!(out, [0.1, 0.1, 0.2, 0.2]);
+
+        let mut out = [f32::NAN; 5];
+        route_channels(&source[..1], 1, &mut out, 2, ExtraChannels::Silent);
+        assert_eq!(&out[..2], [0.1, 0.1]);
+        assert!(out[2..].iter().all(|sample| sample.is_nan()));
+    }
+
+    #[test]
+    fn extra_channels_parse_from_snake_case() {
+        let config: ChannelRoutingConfig =
+            serde_yaml::from_str("extra_channels: duplicate").unwrap();
+        assert_eq!(config.extra_channels, ExtraChannels::Duplicate);
+        let config: ChannelRoutingConfig = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(config.extra_channels, ExtraChannels::Silent);
+    }
+}
@@ -0,0 +1,269 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Settings for lowering the synth's quality while rendering takes too long, and raising it
+/// again once there is time to spare.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CpuBudgetConfig {
+    /// Off by default; quality only changes when asked to.
+    pub enabled: bool,
+    /// Share of a block's duration, in percent, rendering it may take.
+    pub cpu_budget_percent: f32,
+    /// Blocks in a row over budget before quality is lowered a step.
+    pub over_budget_blocks: usize,
+    /// Share of a block's duration, in percent, rendering has to stay under for quality to be
+    /// raised again. Kept below the budget, so quality doesn't flip back and forth.
+    pub recover_percent: f32,
+    /// Blocks in a row under `recover_percent` before quality is raised a step.
+    pub recover_blocks: usize,
+    /// Most voices sounding at once at the lowest quality.
+    pub reduced_max_voices: usize,
+}
+
+impl Default for CpuBudgetConfig {
+    fn default() -> Self {
+        CpuBudgetConfig {
+            enabled: false,
+            cpu_budget_percent: 70.0,
+            over_budget_blocks: 8,
+            recover_percent: 40.0,
+            recover_blocks: 1000,
+            reduced_max_voices: 8,
+        }
+    }
+}
+
+/// A step down from full quality. The steps are taken in the order listed and given back in
+/// reverse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityStep {
+    /// The wave shaper runs without oversampling.
+    Oversampling,
+    /// New notes play a single voice instead of a unison stack.
+    Unison,
+    /// The voice limit drops to `reduced_max_voices`.
+    Polyphony,
+    /// Every voice reads its wavetable from the nearest entry, without interpolating.
+    Interpolation,
+}
+
+impl QualityStep {
+    pub const ALL: [QualityStep; 4] = [
+        QualityStep::Oversampling,
+        QualityStep::Unison,
+        QualityStep::Polyphony,
+        QualityStep::Interpolation,
+    ];
+
+    /// The level the step is taken at: quality runs without it from this level down.
+    pub fn level(self) -> usize {
+        self as usize + 1
+    }
+
+    /// The step taken to go from `level - 1` down to `level`.
+    pub fn at_level(level: usize) -> Option<QualityStep> {
+        level
+            .checked_sub(1)
+            .and_then(|index| QualityStep::ALL.get(index).copied())
+    }
+}
+
+impl fmt::Display for QualityStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QualityStep::Oversampling => "wave shaper oversampling",
+            QualityStep::Unison => "unison for new notes",
+            QualityStep::Polyphony => "full voice limit",
+            QualityStep::Interpolation => "wavetable interpolation",
+        })
+    }
+}
+
+/// How many steps quality can be lowered by.
+pub const MAX_QUALITY_LEVEL: usize = QualityStep::ALL.len();
+
+/// Decides how far below full quality the synth runs, from how long each block takes to
+/// render.
+///
+/// Quality drops a step after `over_budget_blocks` blocks in a row over the budget, and comes
+/// back a step after `recover_blocks` in a row well under it. Blocks in between count towards
+/// neither.
+#[derive(Debug)]
+pub struct CpuBudgetController {
+    config: CpuBudgetConfig,
+    /// Steps below full quality, from 0 to `MAX_QUALITY_LEVEL`.
+    level: usize,
+    over_budget: usize,
+    under_budget: usize,
+}
+
+impl CpuBudgetController {
+    pub fn new(config: CpuBudgetConfig) -> Self {
+        CpuBudgetController {
+            config,
+            level: 0,
+            over_budget: 0,
+            under_budget: 0,
+        }
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Moves to `level`, as when quality is set by hand, starting the counts over.
+    pub fn set_level(&mut self, level: usize) {
+        self.level = level.min(MAX_QUALITY_LEVEL);
+        self.over_budget = 0;
+        self.under_budget = 0;
+    }
+
+    /// Records a block whose rendering took `load` of its duration, 1.0 being all of it.
+    /// Returns the new level when it changes.
+    pub fn record(&mut self, load: f32) -> Option<usize> {
+        let budget = self.config.cpu_budget_percent / 100.0;
+        let recover = self
+            .config
+            .recover_percent
+            .min(self.config.cpu_budget_percent)
+            / 100.0;
+        if load > budget {
+            self.under_budget = 0;
+            self.over_budget += 1;
+            if self.over_budget < self.config.over_budget_blocks.max(1)
+                || self.level == MAX_QUALITY_LEVEL
+            {
+                return None;
+            }
+            self.set_level(self.level + 1);
+            if let Some(step) = QualityStep::at_level(self.level) {
+                warn!(
+                    "Rendering is over its CPU budget of {}%, turning off {}",
+                    self.config.cpu_budget_percent, step
+                );
+            }
+            Some(self.level)
+        } else if load < recover {
+            self.over_budget = 0;
+            self.under_budget += 1;
+            if self.under_budget < self.config.recover_blocks.max(1) || self.level == 0 {
+                return None;
+            }
+            if let Some(step) = QualityStep::at_level(self.level) {
+                info!("Rendering is back under its CPU budget, restoring {}", step);
+            }
+            self.set_level(self.level - 1);
+            Some(self.level)
+        } else {
+            self.over_budget = 0;
+            self.under_budget = 0;
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> CpuBudgetController {
+        CpuBudgetController::new(CpuBudgetConfig {
+            enabled: true,
+            over_budget_blocks: 3,
+            recover_blocks: 5,
+            ..CpuBudgetConfig::default()
+        })
+    }
+
+    /// Feeds `loads` to `controller`, returning the block index and new level of each change.
+    fn changes(controller: &mut CpuBudgetController, loads: &[f32]) -> Vec<(usize, usize)> {
+        loads
+            .iter()
+            .enumerate()
+            .filter_map(|(block, &load)| controller.record(load).map(|level| (block, level)))
+            .collect()
+    }
+
+    #[test]
+    fn quality_steps_down_after_enough_blocks_over_budget_and_stops_at_the_bottom() {
+        let mut controller = controller();
+        assert_eq!(
+            changes(&mut controller, &[0.9; 20]),
+            [(2, 1), (5, 2), (8, 3), (11, 4)]
+        );
+        assert_eq!(controller.level(), MAX_QUALITY_LEVEL);
+    }
+
+    #[test]
+    fn quality_steps_back_up_one_level_per_quiet_stretch() {
+        let mut controller = controller();
+        changes(&mut controller, &[0.9; 6]);
+        assert_eq!(controller.level(), 2);
+        assert_eq!(changes(&mut controller, &[0.3; 20]), [(4, 1), (9, 0)]);
+        assert_eq!(controller.level(), 0);
+    }
+
+    #[test]
+    fn blocks_between_the_thresholds_hold_the_level_and_restart_both_counts() {
+        let mut controller = controller();
+        changes(&mut controller, &[0.9; 3]);
+        assert_eq!(controller.level(), 1);
+        // Above the recovery level but within budget: no change however long it lasts.
+        assert!(changes(&mut controller, &[0.5; 100]).is_empty());
+
+        // Two blocks over, one in between, two over: never three in a row.
+        assert!(changes(&mut controller, &[0.9, 0.9, 0.5, 0.9, 0.9]).is_empty());
+        // Four quiet blocks, one in between, four quiet: never five in a row.
+        let loads = [0.3, 0.3, 0.3, 0.3, 0.5, 0.3, 0.3, 0.3, 0.3];
+        assert!(changes(&mut controller, &loads).is_empty());
+        assert_eq!(controller.level(), 1);
+    }
+
+    #[test]
+    fn one_block_over_budget_restarts_the_recovery_count() {
+        let mut controller = controller();
+        changes(&mut controller, &[0.9; 3]);
+        let mut loads = vec![0.3; 4];
+        loads.push(0.9);
+        loads.extend([0.3; 5]);
+        assert_eq!(changes(&mut controller, &loads), [(9, 0)]);
+    }
+
+    #[test]
+    fn load_hovering_around_the_budget_does_not_flip_the_level() {
+        let mut controller = controller();
+        changes(&mut controller, &[0.9; 3]);
+        // Just over, then just under the budget, well clear of the recovery level.
+        let loads: Vec<f32> = (0..1_000)
+            .map(|block| if block % 3 == 2 { 0.65 } else { 0.75 })
+            .collect();
+        assert!(changes(&mut controller, &loads).is_empty());
+        assert_eq!(controller.level(), 1);
+    }
+
+    #[test]
+    fn a_level_set_by_hand_is_clamped_and_starts_the_counts_over() {
+        let mut controller = controller();
+        controller.record(0.9);
+        controller.record(0.9);
+        controller.set_level(10);
+        assert_eq!(controller.level(), MAX_QUALITY_LEVEL);
+        controller.set_level(1);
+        // The two blocks over budget before were forgotten.
+        assert_eq!(controller.record(0.9), None);
+        assert_eq!(changes(&mut controller, &[0.9; 2]), [(1, 2)]);
+    }
+
+    #[test]
+    fn steps_are_taken_in_order_one_per_level() {
+        assert_eq!(QualityStep::at_level(0), None);
+        for (index, step) in QualityStep::ALL.into_iter().enumerate() {
+            assert_eq!(step.level(), index + 1);
+            assert_eq!(QualityStep::at_level(step.level()), Some(step));
+        }
+        assert_eq!(QualityStep::at_level(MAX_QUALITY_LEVEL + 1), None);
+    }
+}
@@ -0,0 +1,247 @@
+use std::f32::consts::TAU;
+
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{AudioBuffer, AudioNode};
+
+/// Settings for the DC blocker at the end of the signal chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DcBlockerConfig {
+    pub enabled: bool,
+    /// Frequency in Hz below which the output is rolled off. Low enough to leave the lowest
+    /// notes alone, high enough to settle quickly after an offset appears.
+    pub cutoff: f32,
+}
+
+impl Default for DcBlockerConfig {
+    fn default() -> Self {
+        DcBlockerConfig {
+            enabled: true,
+            cutoff: 20.0,
+        }
+    }
+}
+
+/// Lowest cutoff the blocker accepts, in Hz. Lower still would take seconds to settle.
+const MIN_CUTOFF: f32 = 1.0;
+
+/// Output below this is taken as silence. Left alone, the filter's memory would decay into
+/// denormal numbers after the sound stops and stay there, which is slow on most CPUs.
+const SILENCE: f32 = 1e-20;
+
+/// Removes any constant offset and sub-audio rumble from the signal, such as the wave shaper
+/// adds when it bends a wave unevenly.
+///
+/// A one-pole high-pass, `y[n] = x[n] - x[n-1] + R·y[n-1]`, with its own memory for each
+/// channel that carries over from one block to the next.
+#[derive(Debug)]
+pub struct DcBlockerNode {
+    sample_rate: f32,
+    cutoff: f32,
+    /// How much of the previous output each sample keeps, just under 1.0.
+    coefficient: f32,
+    /// The previous input and output sample of each channel, allocated on the first block.
+    state: Vec<(f32, f32)>,
+}
+
+impl DcBlockerNode {
+    pub fn new(cutoff: f32, sample_rate: f32) -> Self {
+        let mut dc_blocker = DcBlockerNode {
+            sample_rate,
+            cutoff,
+            coefficient: 0.0,
+            state: Vec::new(),
+        };
+        dc_blocker.set_cutoff(cutoff);
+        dc_blocker
+    }
+
+    pub fn cutoff(&self) -> f32 {
+        self.cutoff
+    }
+
+    /// Sets the cutoff in Hz, kept well below the Nyquist frequency. The filter's memory is
+    /// kept, so the change doesn't click.
+    pub fn set_cutoff(&mut self, cutoff: f32) {
+        let max_cutoff = (self.sample_rate * 0.25).max(MIN_CUTOFF);
+        self.cutoff = if cutoff.is_finite() {
+            cutoff.clamp(MIN_CUTOFF, max_cutoff)
+        } else {
+            DcBlockerConfig::default().cutoff.min(max_cutoff)
+        };
+        self.coefficient = (-TAU * self.cutoff / self.sample_rate).exp();
+    }
+
+    /// Changes the sample rate the cutoff is counted in. The filter's memory belongs to the
+    /// old rate, so it is cleared.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        if sample_rate <= 0.0 || sample_rate == self.sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.set_cutoff(self.cutoff);
+        self.reset();
+    }
+}
+
+impl AudioNode for DcBlockerNode {
+    fn process(&mut self, input: &AudioBuffer, output: &mut AudioBuffer) {
+        let num_channels = input.num_channels();
+        assert_eq!(num_channels, output.num_channels());
+
+        if self.state.len() != num_channels {
+            self.state = vec![(0.0, 0.0); num_channels];
+        }
+
+        let coefficient = self.coefficient;
+        for (i, (previous_input, previous_output)) in self.state.iter_mut().enumerate() {
+            let input_channel = input.channel(i);
+            let output_channel = output.channel_mut(i);
+            for (input_sample, output_sample) in input_channel.iter().zip(output_channel.iter_mut())
+            {
+                let mut filtered = *input_sample - *previous_input + coefficient * *previous_output;
+                if filtered.abs() < SILENCE {
+                    filtered = 0.0;
+                }
+                *previous_input = *input_sample;
+                *previous_output = filtered;
+                *output_sample = filtered;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.fill((0.0, 0.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// Runs `samples` through `dc_blocker` as one mono block.
+    fn filter(dc_blocker: &mut DcBlockerNode, samples: &[f32]) -> Vec<f32> {
+        let input = AudioBuffer {
+            data: samples.to_vec(),
+            num_channels: 1,
+        };
+        let mut output = input.clone();
+        dc_blocker.process(&input, &mut output);
+        output.data
+    }
+
+    /// The amplitude and phase, in radians, of `samples` at `frequency`, over a whole number
+    /// of its cycles.
+    fn amplitude_and_phase(samples: &[f32], frequency: f32) -> (f64, f64) {
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (i, &sample)| {
+                let angle = std::f64::consts::TAU * (frequency / SAMPLE_RATE) as f64 * i as f64;
+                (
+                    re + sample as f64 * angle.sin(),
+                    im + sample as f64 * angle.cos(),
+                )
+            });
+        let len = samples.len() as f64;
+        (2.0 * (re * re + im * im).sqrt() / len, im.atan2(re))
+    }
+
+    #[test]
+    fn a_constant_offset_is_gone_within_the_settling_time() {
+        let mut dc_blocker = DcBlockerNode::new(20.0, SAMPLE_RATE);
+        let output = filter(&mut dc_blocker, &vec![0.5; SAMPLE_RATE as usize]);
+        // The offset comes straight through at first, then decays with a time constant of
+        // 1 / (2π·20) s, 382 samples.
+        assert_eq!(output[0], 0.5);
+        let time_constant = (SAMPLE_RATE / (TAU * 20.0)) as usize;
+        assert!(output[time_constant] < 0.5 * 0.37);
+        assert!(output[5 * time_constant] < 0.5 * 0.01);
+        assert!(output[SAMPLE_RATE as usize / 5] < 1e-9);
+        assert_eq!(output[SAMPLE_RATE as usize - 1], 0.0);
+        assert!(output.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+
+    #[test]
+    fn a_100_hz_sine_passes_with_little_loss_or_phase_shift() {
+        let mut dc_blocker = DcBlockerNode::new(20.0, SAMPLE_RATE);
+        let input: Vec<f32> = (0..SAMPLE_RATE as usize)
+            .map(|i| (TAU * 100.0 * i as f32 / SAMPLE_RATE).sin())
+            .collect();
+        let output = filter(&mut dc_blocker, &input);
+
+        // Measured over the settled second half, 50 whole cycles.
+        let half = input.len() / 2;
+        let (input_amplitude, input_phase) = amplitude_and_phase(&input[half..], 100.0);
+        let (output_amplitude, output_phase) = amplitude_and_phase(&output[half..], 100.0);
+        let loss_db = 20.0 * (input_amplitude / output_amplitude).log10();
+        assert!((0.0..0.5).contains(&loss_db), "{}", loss_db);
+        // A one-pole high-pass leads by atan(20 / 100), about 11 degrees.
+        let shift = (output_phase - input_phase).to_degrees();
+        assert!((5.0..15.0).contains(&shift), "{}", shift);
+    }
+
+    #[test]
+    fn the_filter_carries_its_state_from_one_block_to_the_next() {
+        let input: Vec<f32> = (0..4_800)
+            .map(|i| 0.3 + 0.5 * (TAU * 55.0 * i as f32 / SAMPLE_RATE).sin())
+            .collect();
+        let whole = filter(&mut DcBlockerNode::new(20.0, SAMPLE_RATE), &input);
+
+        let mut dc_blocker = DcBlockerNode::new(20.0, SAMPLE_RATE);
+        let mut in_blocks = Vec::new();
+        let mut start = 0;
+        for len in [37, 256, 1, 512, 99].into_iter().cycle() {
+            let end = (start + len).min(input.len());
+            in_blocks.extend(filter(&mut dc_blocker, &input[start..end]));
+            start = end;
+            if start == input.len() {
+                break;
+            }
+        }
+        assert_eq!(in_blocks, whole);
+    }
+
+    #[test]
+    fn each_channel_is_filtered_on_its_own() {
+        let mut dc_blocker = DcBlockerNode::new(20.0, SAMPLE_RATE);
+        let input = AudioBuffer {
+            data: [vec![0.5; 480], vec![-0.25; 480]].concat(),
+            num_channels: 2,
+        };
+        let mut output = input.clone();
+        dc_blocker.process(&input, &mut output);
+        let left = filter(&mut DcBlockerNode::new(20.0, SAMPLE_RATE), &[0.5; 480]);
+        let right = filter(&mut DcBlockerNode::new(20.0, SAMPLE_RATE), &[-0.25; 480]);
+        assert_eq!(output.channel(0), left);
+        assert_eq!(output.channel(1), right);
+    }
+
+    #[test]
+    fn a_reset_or_new_sample_rate_starts_the_filter_from_silence() {
+        let mut dc_blocker = DcBlockerNode::new(20.0, SAMPLE_RATE);
+        filter(&mut dc_blocker, &[0.5; 1_000]);
+        dc_blocker.reset();
+        assert_eq!(filter(&mut dc_blocker, &[0.5]), [0.5]);
+
+        filter(&mut dc_blocker, &[0.5; 1_000]);
+        dc_blocker.set_sample_rate(96_000.0);
+        assert_eq!(filter(&mut dc_blocker, &[0.5]), [0.5]);
+        // The cutoff stays put in Hz, so the decay is half as fast per sample.
+        assert_eq!(dc_blocker.cutoff(), 20.0);
+        assert!((dc_blocker.coefficient - (-TAU * 20.0 / 96_000.0).exp()).abs() < 1e-7);
+    }
+
+    #[test]
+    fn the_cutoff_is_kept_in_range() {
+        let mut dc_blocker = DcBlockerNode::new(0.0, SAMPLE_RATE);
+        assert_eq!(dc_blocker.cutoff(), MIN_CUTOFF);
+        dc_blocker.set_cutoff(40_000.0);
+        assert_eq!(dc_blocker.cutoff(), SAMPLE_RATE * 0.25);
+        dc_blocker.set_cutoff(f32::NAN);
+        assert_eq!(dc_blocker.cutoff(), 20.0);
+    }
+}
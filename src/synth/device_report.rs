@@ -0,0 +1,381 @@
+use std::fmt;
+
+use anyhow::Result;
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{
+    BufferSize, SampleFormat, StreamConfig, SupportedBufferSize, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+use tracing::{info, warn};
+
+/// Buffers longer than this many frames add noticeable latency between a key and its sound.
+pub const HIGH_LATENCY_FRAMES: u32 = 2048;
+/// Sample rates below this lose the top of the audible range.
+pub const MIN_RECOMMENDED_SAMPLE_RATE: u32 = 44100;
+
+/// Something about the output device that will make the synth worse to play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceWarning {
+    /// The output is mono while a stereo feature, such as the split visualizer, is enabled.
+    MonoOutput,
+    /// The smallest buffer the device allows is over `HIGH_LATENCY_FRAMES`.
+    HighLatency { frames: u32 },
+    /// The sample rate is below `MIN_RECOMMENDED_SAMPLE_RATE`.
+    LowSampleRate { sample_rate: u32 },
+}
+
+impl fmt::Display for DeviceWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceWarning::MonoOutput => {
+                write!(
+                    f,
+                    "the output is mono, so both channels will sound the same"
+                )
+            }
+            DeviceWarning::HighLatency { frames } => write!(
+                f,
+                "the device buffers at least {} frames, so expect high latency",
+                frames
+            ),
+            DeviceWarning::LowSampleRate { sample_rate } => write!(
+                f,
+                "the sample rate is {} Hz, below the {} Hz high notes need",
+                sample_rate, MIN_RECOMMENDED_SAMPLE_RATE
+            ),
+        }
+    }
+}
+
+/// What the output device can do, what was picked, and what to warn the user about.
+#[derive(Debug, Clone)]
+pub struct DeviceReport {
+    pub host: String,
+    pub device: String,
+    pub chosen: SupportedStreamConfig,
+    pub supported: Vec<SupportedStreamConfigRange>,
+    pub warnings: Vec<DeviceWarning>,
+}
+
+impl DeviceReport {
+    /// Builds the report from the device's capabilities. `stereo_wanted` is whether a feature
+    /// that needs two channels is enabled.
+    pub fn new(
+        host: &str,
+        device: &str,
+        supported: Vec<SupportedStreamConfigRange>,
+        chosen: SupportedStreamConfig,
+        stereo_wanted: bool,
+    ) -> Self {
+        let mut warnings = Vec::new();
+        if stereo_wanted && chosen.channels() < 2 {
+            warnings.push(DeviceWarning::MonoOutput);
+        }
+        if let SupportedBufferSize::Range { min, .. } = *chosen.buffer_size() {
+            if min > HIGH_LATENCY_FRAMES {
+                warnings.push(DeviceWarning::HighLatency { frames: min });
+            }
+        }
+        if chosen.sample_rate().0 < MIN_RECOMMENDED_SAMPLE_RATE {
+            warnings.push(DeviceWarning::LowSampleRate {
+                sample_rate: chosen.sample_rate().0,
+            });
+        }
+
+        DeviceReport {
+            host: host.to_string(),
+            device: device.to_string(),
+            chosen,
+            supported,
+            warnings,
+        }
+    }
+
+    /// Asks `device` for its capabilities and builds the report for `chosen`.
+    pub fn probe(
+        host: &str,
+        device: &cpal::Device,
+        chosen: SupportedStreamConfig,
+        stereo_wanted: bool,
+    ) -> Result<Self> {
+        let supported = device.supported_output_configs()?.collect();
+        Ok(DeviceReport::new(
+            host,
+            &device.name()?,
+            supported,
+            chosen,
+            stereo_wanted,
+        ))
+    }
+
+    /// Logs the report, one line at a time, with a warning for each problem found.
+    pub fn log(&self) {
+        for line in self.to_string().lines() {
+            info!("{}", line);
+        }
+        for warning in self.warnings.iter() {
+            warn!("Audio device: {}", warning);
+        }
+    }
+}
+
+impl fmt::Display for DeviceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Audio device: {} ({})", self.device, self.host)?;
+        writeln!(
+            f,
+            "  using {} at {} Hz, {} channel(s), buffer {}",
+            self.chosen.sample_format(),
+            self.chosen.sample_rate().0,
+            self.chosen.channels(),
+            buffer_size_text(self.chosen.buffer_size())
+        )?;
+        writeln!(f, "  supported configs:")?;
+        for range in self.supported.iter() {
+            writeln!(
+                f,
+                "    {} at {}-{} Hz, {} channel(s), buffer {}",
+                range.sample_format(),
+                range.min_sample_rate().0,
+                range.max_sample_rate().0,
+                range.channels(),
+                buffer_size_text(range.buffer_size())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The settings an output stream was actually opened with, as opposed to what the device
+/// offers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioConfigInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Frames per callback, or `None` when the device picks its own.
+    pub buffer_size: Option<u32>,
+    pub sample_format: SampleFormat,
+}
+
+impl AudioConfigInfo {
+    /// Describes a stream opened with `config`, its samples in `sample_format`.
+    pub fn new(config: &StreamConfig, sample_format: SampleFormat) -> Self {
+        AudioConfigInfo {
+            sample_rate: config.sample_rate.0,
+            channels: config.channels,
+            buffer_size: match config.buffer_size {
+                BufferSize::Fixed(frames) => Some(frames),
+                BufferSize::Default => None,
+            },
+            sample_format,
+        }
+    }
+
+    /// Logs the settings as one line, each as a field of its own for log collectors.
+    pub fn log(&self) {
+        info!(
+            sample_rate = self.sample_rate,
+            channels = self.channels,
+            buffer_size = self.buffer_size,
+            sample_format = %self.sample_format,
+            "Audio stream: {}",
+            self
+        );
+    }
+}
+
+impl fmt::Display for AudioConfigInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {} Hz, {} channel(s), buffer ",
+            self.sample_format, self.sample_rate, self.channels
+        )?;
+        match self.buffer_size {
+            Some(frames) => write!(f, "{} frames", frames),
+            None => write!(f, "chosen by the device"),
+        }
+    }
+}
+
+fn buffer_size_text(buffer_size: &SupportedBufferSize) -> String {
+    match buffer_size {
+        SupportedBufferSize::Range { min, max } => format!("{}-{} frames", min, max),
+        SupportedBufferSize::Unknown => "unknown".to_string(),
+    }
+}
+
+/// Prints the default host's output devices, with a full report for each when `verbose`.
+pub fn list_output_devices(verbose: bool, stereo_wanted: bool) -> Result<()> {
+    let host = cpal::default_host();
+    let host_name = host.id().name();
+    println!("Output devices on {}:", host_name);
+    for device in host.output_devices()? {
+        let name = device.name()?;
+        if !verbose {
+            println!("  {}", name);
+            continue;
+        }
+        match device.default_output_config() {
+            Ok(chosen) => {
+                let report = DeviceReport::probe(host_name, &device, chosen, stereo_wanted)?;
+                print!("{}", report);
+                for warning in report.warnings.iter() {
+                    println!("  warning: {}", warning);
+                }
+            }
+            Err(err) => println!("Audio device: {} (no default config: {})", name, err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SampleRate;
+
+    fn config(channels: u16, sample_rate: u32, min_buffer: u32) -> SupportedStreamConfig {
+        SupportedStreamConfig::new(
+            channels,
+            SampleRate(sample_rate),
+            SupportedBufferSize::Range {
+                min: min_buffer,
+                max: 8192,
+            },
+            SampleFormat::F32,
+        )
+    }
+
+    fn supported() -> Vec<SupportedStreamConfigRange> {
+        vec![
+            SupportedStreamConfigRange::new(
+                2,
+                SampleRate(44_100),
+                SampleRate(96_000),
+                SupportedBufferSize::Range { min: 64, max: 8192 },
+                SampleFormat::F32,
+            ),
+            SupportedStreamConfigRange::new(
+                1,
+                SampleRate(8_000),
+                SampleRate(48_000),
+                SupportedBufferSize::Unknown,
+                SampleFormat::I16,
+            ),
+        ]
+    }
+
+    fn report(chosen: SupportedStreamConfig, stereo_wanted: bool) -> DeviceReport {
+        DeviceReport::new("ALSA", "Test Device", supported(), chosen, stereo_wanted)
+    }
+
+    #[test]
+    fn a_stereo_device_at_a_normal_rate_and_buffer_has_no_warnings() {
+        assert!(report(config(2, 48_000, 64), true).warnings.is_empty());
+        assert!(report(config(2, 44_100, HIGH_LATENCY_FRAMES), true)
+            .warnings
+            .is_empty());
+    }
+
+    #[test]
+    fn mono_output_is_only_a_problem_when_stereo_is_wanted() {
+        assert_eq!(
+            report(config(1, 48_000, 64), true).warnings,
+            [DeviceWarning::MonoOutput]
+        );
+        assert!(report(config(1, 48_000, 64), false).warnings.is_empty());
+    }
+
+    #[test]
+    fn large_buffers_and_low_sample_rates_are_warned_about() {
+        assert_eq!(
+            report(config(2, 48_000, 4096), true).warnings,
+            [DeviceWarning::HighLatency { frames: 4096 }]
+        );
+        assert_eq!(
+            report(config(1, 22_050, 4096), true).warnings,
+            [
+                DeviceWarning::MonoOutput,
+                DeviceWarning::HighLatency { frames: 4096 },
+                DeviceWarning::LowSampleRate {
+                    sample_rate: 22_050
+                },
+            ]
+        );
+        // A device that doesn't say how it buffers isn't assumed to be slow.
+        let unknown = SupportedStreamConfig::new(
+            2,
+            SampleRate(48_000),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        assert!(report(unknown, true).warnings.is_empty());
+    }
+
+    #[test]
+    fn the_report_lists_the_chosen_config_and_every_supported_range() {
+        let text = report(config(2, 48_000, 64), true).to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Audio device: Test Device (ALSA)",
+                "  using f32 at 48000 Hz, 2 channel(s), buffer 64-8192 frames",
+                "  supported configs:",
+                "    f32 at 44100-96000 Hz, 2 channel(s), buffer 64-8192 frames",
+                "    i16 at 8000-48000 Hz, 1 channel(s), buffer unknown",
+            ]
+        );
+    }
+
+    #[test]
+    fn the_audio_config_info_carries_over_what_the_stream_was_opened_with() {
+        let fixed = StreamConfig {
+            channels: 2,
+            sample_rate: SampleRate(48_000),
+            buffer_size: BufferSize::Fixed(256),
+        };
+        assert_eq!(
+            AudioConfigInfo::new(&fixed, SampleFormat::F32),
+            AudioConfigInfo {
+                sample_rate: 48_000,
+                channels: 2,
+                buffer_size: Some(256),
+                sample_format: SampleFormat::F32,
+            }
+        );
+
+        let device_default = StreamConfig {
+            channels: 1,
+            sample_rate: SampleRate(44_100),
+            buffer_size: BufferSize::Default,
+        };
+        let info = AudioConfigInfo::new(&device_default, SampleFormat::I16);
+        assert_eq!(info.sample_rate, 44_100);
+        assert_eq!(info.channels, 1);
+        assert_eq!(info.buffer_size, None);
+        assert_eq!(info.sample_format, SampleFormat::I16);
+    }
+
+    #[test]
+    fn the_audio_config_info_reads_as_one_line() {
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: SampleRate(48_000),
+            buffer_size: BufferSize::Fixed(256),
+        };
+        assert_eq!(
+            AudioConfigInfo::new(&config, SampleFormat::F32).to_string(),
+            "f32 at 48000 Hz, 2 channel(s), buffer 256 frames"
+        );
+        let config = StreamConfig {
+            buffer_size: BufferSize::Default,
+            ..config
+        };
+        assert_eq!(
+            AudioConfigInfo::new(&config, SampleFormat::I16).to_string(),
+            "i16 at 48000 Hz, 2 channel(s), buffer chosen by the device"
+        );
+    }
+}
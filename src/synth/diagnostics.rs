@@ -0,0 +1,665 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::synth::{keys::event_history::DEFAULT_EVENT_HISTORY, AudioBuffer};
+
+/// Settings for the diagnostics the engine logs while running.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiagnosticsConfig {
+    /// Warn when more voices than this are sounding at once.
+    pub polyphony_warning_threshold: usize,
+    /// Minimum time between two repeated warnings, in seconds.
+    pub warning_interval: f32,
+    /// Time every audio callback and keep a rolling max/average of how long they take.
+    pub measure_callback_time: bool,
+    /// Number of recent callbacks the rolling statistics cover.
+    pub timing_window: usize,
+    /// Time every rendered frame and log a rolling max/average on exit.
+    pub measure_frame_time: bool,
+    /// Warn when the output's constant offset ahead of the DC blocker is further from zero
+    /// than this, which points at a stage that pushes the wave off-center.
+    pub dc_warning_threshold: f32,
+    /// Number of recent note events kept for the voice dump to log, oldest forgotten first.
+    pub event_history: usize,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        DiagnosticsConfig {
+            polyphony_warning_threshold: 16,
+            warning_interval: 1.0,
+            measure_callback_time: false,
+            timing_window: 64,
+            measure_frame_time: false,
+            dc_warning_threshold: 0.05,
+            event_history: DEFAULT_EVENT_HISTORY,
+        }
+    }
+}
+
+/// Lets an event through at most once per `interval`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        RateLimiter {
+            interval,
+            last: None,
+        }
+    }
+
+    /// Returns whether the event at `now` should go through, and if so starts a new window.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.last {
+            Some(last) if now.saturating_duration_since(last) < self.interval => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Warns when the number of sounding voices climbs past the configured threshold.
+#[derive(Debug)]
+pub struct PolyphonyMonitor {
+    threshold: usize,
+    limiter: RateLimiter,
+}
+
+impl PolyphonyMonitor {
+    pub fn new(config: &DiagnosticsConfig) -> Self {
+        PolyphonyMonitor {
+            threshold: config.polyphony_warning_threshold,
+            limiter: RateLimiter::new(Duration::from_secs_f32(config.warning_interval.max(0.0))),
+        }
+    }
+
+    /// Checks `voice_count` against the threshold. Returns whether a warning was logged.
+    pub fn check(&mut self, voice_count: usize, now: Instant) -> bool {
+        if voice_count <= self.threshold || !self.limiter.allow(now) {
+            return false;
+        }
+        warn!(
+            "{} voices playing, above the polyphony warning threshold of {}",
+            voice_count, self.threshold
+        );
+        true
+    }
+}
+
+/// Seconds the DC meter's reading is averaged over.
+const DC_METER_TIME_CONSTANT: f32 = 0.5;
+
+/// The offset the `DcMeter` last measured, shared so it can be read outside the audio thread.
+#[derive(Debug, Default)]
+pub struct DcLevel {
+    bits: AtomicU32,
+}
+
+impl DcLevel {
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.bits.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, level: f32) {
+        self.bits.store(level.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Measures the constant offset in the signal: each block's mean on the channel furthest from
+/// zero, smoothed over `DC_METER_TIME_CONSTANT`. Warns when it stays past the configured
+/// threshold.
+#[derive(Debug)]
+pub struct DcMeter {
+    sample_rate: f32,
+    threshold: f32,
+    level: f32,
+    shared: Arc<DcLevel>,
+    limiter: RateLimiter,
+}
+
+impl DcMeter {
+    pub fn new(config: &DiagnosticsConfig, sample_rate: f32) -> Self {
+        DcMeter {
+            sample_rate,
+            threshold: config.dc_warning_threshold,
+            level: 0.0,
+            shared: Arc::default(),
+            limiter: RateLimiter::new(Duration::from_secs_f32(config.warning_interval.max(0.0))),
+        }
+    }
+
+    /// The smoothed offset, as of the last block measured.
+    pub fn level(&self) -> &Arc<DcLevel> {
+        &self.shared
+    }
+
+    /// Takes `buffer`'s mean into the reading. Returns whether a warning was logged.
+    pub fn measure(&mut self, buffer: &AudioBuffer, now: Instant) -> bool {
+        let num_frames = buffer.num_frames();
+        if num_frames == 0 {
+            return false;
+        }
+        let mean = (0..buffer.num_channels())
+            .map(|i| buffer.channel(i).iter().sum::<f32>() / num_frames as f32)
+            .fold(0.0f32, |furthest, mean| {
+                if mean.abs() > furthest.abs() {
+                    mean
+                } else {
+                    furthest
+                }
+            });
+        if !mean.is_finite() {
+            return false;
+        }
+        let blend =
+            1.0 - (-(num_frames as f32) / (DC_METER_TIME_CONSTANT * self.sample_rate)).exp();
+        self.level += (mean - self.level) * blend;
+        self.shared.set(self.level);
+
+        if self.level.abs() <= self.threshold || !self.limiter.allow(now) {
+            return false;
+        }
+        warn!(
+            "DC offset of {:.3} ahead of the DC blocker, above the warning threshold of {}",
+            self.level, self.threshold
+        );
+        true
+    }
+}
+
+/// How often the watchdog may warn about an overrun.
+const OVERRUN_WARNING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A gap between callbacks longer than this many blocks counts as a system-level underrun.
+const GAP_TOLERANCE: f32 = 1.5;
+
+/// Counters kept by the `CallbackWatchdog`, shared so they can be read from outside the audio
+/// thread.
+#[derive(Debug, Default)]
+pub struct WatchdogCounters {
+    pub callbacks: AtomicU64,
+    pub over_half_budget: AtomicU64,
+    pub over_80_percent_budget: AtomicU64,
+    pub over_budget: AtomicU64,
+    pub callback_gaps: AtomicU64,
+}
+
+impl WatchdogCounters {
+    pub fn summary(&self) -> WatchdogSummary {
+        WatchdogSummary {
+            callbacks: self.callbacks.load(Ordering::Relaxed),
+            over_half_budget: self.over_half_budget.load(Ordering::Relaxed),
+            over_80_percent_budget: self.over_80_percent_budget.load(Ordering::Relaxed),
+            over_budget: self.over_budget.load(Ordering::Relaxed),
+            callback_gaps: self.callback_gaps.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of the watchdog counters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogSummary {
+    pub callbacks: u64,
+    pub over_half_budget: u64,
+    pub over_80_percent_budget: u64,
+    pub over_budget: u64,
+    pub callback_gaps: u64,
+}
+
+impl fmt::Display for WatchdogSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} callbacks, {} over 50% of budget, {} over 80%, {} overruns, {} gaps",
+            self.callbacks,
+            self.over_half_budget,
+            self.over_80_percent_budget,
+            self.over_budget,
+            self.callback_gaps
+        )
+    }
+}
+
+/// Measures each audio callback against its time budget and watches for gaps between callbacks.
+///
+/// An overrun is our own processing taking longer than the audio it produced. A gap is the
+/// callback arriving late, which points at the system rather than at us.
+#[derive(Debug)]
+pub struct CallbackWatchdog {
+    counters: Arc<WatchdogCounters>,
+    overrun_limiter: RateLimiter,
+    last_callback: Option<(Instant, Option<Duration>)>,
+    last_budget: Duration,
+}
+
+impl Default for CallbackWatchdog {
+    fn default() -> Self {
+        CallbackWatchdog {
+            counters: Arc::default(),
+            overrun_limiter: RateLimiter::new(OVERRUN_WARNING_INTERVAL),
+            last_callback: None,
+            last_budget: Duration::ZERO,
+        }
+    }
+}
+
+impl CallbackWatchdog {
+    pub fn counters(&self) -> &Arc<WatchdogCounters> {
+        &self.counters
+    }
+
+    /// Notes the arrival of a callback. `stream_time` is the backend's timestamp for it when
+    /// available; otherwise the gap is measured with `now`.
+    pub fn callback_started(&mut self, stream_time: Option<Duration>, now: Instant) {
+        if let Some((last_instant, last_stream_time)) = self.last_callback {
+            let interval = match (stream_time, last_stream_time) {
+                (Some(current), Some(last)) => current.saturating_sub(last),
+                _ => now.saturating_duration_since(last_instant),
+            };
+            if !self.last_budget.is_zero() && interval > self.last_budget.mul_f32(GAP_TOLERANCE) {
+                self.counters.callback_gaps.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.last_callback = Some((now, stream_time));
+    }
+
+    /// Records a callback that took `elapsed` to produce `frames` frames at `sample_rate`,
+    /// finishing at `now`. Returns whether an overrun warning was logged.
+    pub fn record(
+        &mut self,
+        elapsed: Duration,
+        frames: usize,
+        sample_rate: f32,
+        voice_count: usize,
+        effects: &[&str],
+        now: Instant,
+    ) -> bool {
+        let budget = Duration::from_secs_f32(frames as f32 / sample_rate);
+        self.last_budget = budget;
+        self.counters.callbacks.fetch_add(1, Ordering::Relaxed);
+        if budget.is_zero() {
+            return false;
+        }
+
+        let load = elapsed.as_secs_f32() / budget.as_secs_f32();
+        if load > 0.5 {
+            self.counters
+                .over_half_budget
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        if load > 0.8 {
+            self.counters
+                .over_80_percent_budget
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        if load > 1.0 {
+            self.counters.over_budget.fetch_add(1, Ordering::Relaxed);
+            if self.overrun_limiter.allow(now) {
+                warn!(
+                    "Audio callback overran its budget: {:.2} ms for {:.2} ms of audio, {} voices, effects: [{}]",
+                    elapsed.as_secs_f32() * 1000.0,
+                    budget.as_secs_f32() * 1000.0,
+                    voice_count,
+                    effects.join(", ")
+                );
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Max and average over the most recent `capacity` durations.
+#[derive(Debug)]
+pub struct RollingStats {
+    durations: VecDeque<Duration>,
+    capacity: usize,
+    sum: Duration,
+}
+
+impl RollingStats {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        RollingStats {
+            durations: VecDeque::with_capacity(capacity),
+            capacity,
+            sum: Duration::ZERO,
+        }
+    }
+
+    /// Adds a duration, dropping the oldest once the window is full.
+    pub fn push(&mut self, duration: Duration) {
+        if self.durations.len() == self.capacity {
+            if let Some(oldest) = self.durations.pop_front() {
+                self.sum -= oldest;
+            }
+        }
+        self.durations.push_back(duration);
+        self.sum += duration;
+    }
+
+    pub fn len(&self) -> usize {
+        self.durations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.durations.is_empty()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.durations.iter().max().copied().unwrap_or_default()
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.durations.is_empty() {
+            Duration::ZERO
+        } else {
+            self.sum / self.durations.len() as u32
+        }
+    }
+}
+
+/// The latest callback timing statistics, shared so they can be read outside the audio thread.
+#[derive(Debug, Default)]
+pub struct CallbackTiming {
+    pub last_nanos: AtomicU64,
+    pub max_nanos: AtomicU64,
+    pub average_nanos: AtomicU64,
+    pub budget_nanos: AtomicU64,
+}
+
+impl CallbackTiming {
+    pub fn summary(&self) -> CallbackTimingSummary {
+        let load = |nanos: &AtomicU64| Duration::from_nanos(nanos.load(Ordering::Relaxed));
+        CallbackTimingSummary {
+            last: load(&self.last_nanos),
+            max: load(&self.max_nanos),
+            average: load(&self.average_nanos),
+            budget: load(&self.budget_nanos),
+        }
+    }
+}
+
+/// A snapshot of the callback timing statistics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackTimingSummary {
+    pub last: Duration,
+    pub max: Duration,
+    pub average: Duration,
+    /// How long the audio produced by the last callback lasts, i.e. its deadline.
+    pub budget: Duration,
+}
+
+impl fmt::Display for CallbackTimingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent_of_budget = |duration: Duration| {
+            if self.budget.is_zero() {
+                0.0
+            } else {
+                duration.as_secs_f32() / self.budget.as_secs_f32() * 100.0
+            }
+        };
+        write!(
+            f,
+            "avg {:.3} ms ({:.0}%), max {:.3} ms ({:.0}%) of a {:.3} ms budget",
+            self.average.as_secs_f32() * 1000.0,
+            percent_of_budget(self.average),
+            self.max.as_secs_f32() * 1000.0,
+            percent_of_budget(self.max),
+            self.budget.as_secs_f32() * 1000.0
+        )
+    }
+}
+
+/// How often the callback timer logs its statistics.
+const TIMING_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Times whole audio callbacks and publishes rolling statistics into `CallbackTiming`.
+#[derive(Debug)]
+pub struct CallbackTimer {
+    stats: RollingStats,
+    timing: Arc<CallbackTiming>,
+    log_limiter: RateLimiter,
+}
+
+impl CallbackTimer {
+    pub fn new(config: &DiagnosticsConfig) -> Self {
+        CallbackTimer {
+            stats: RollingStats::new(config.timing_window),
+            timing: Arc::default(),
+            log_limiter: RateLimiter::new(TIMING_LOG_INTERVAL),
+        }
+    }
+
+    pub fn timing(&self) -> &Arc<CallbackTiming> {
+        &self.timing
+    }
+
+    /// Records a callback that took `elapsed` to fill `frames` frames at `sample_rate`.
+    pub fn record(&mut self, elapsed: Duration, frames: usize, sample_rate: f32) {
+        self.stats.push(elapsed);
+        let budget = Duration::from_secs_f32(frames as f32 / sample_rate);
+
+        let nanos = |duration: Duration| duration.as_nanos().min(u64::MAX as u128) as u64;
+        self.timing
+            .last_nanos
+            .store(nanos(elapsed), Ordering::Relaxed);
+        self.timing
+            .max_nanos
+            .store(nanos(self.stats.max()), Ordering::Relaxed);
+        self.timing
+            .average_nanos
+            .store(nanos(self.stats.average()), Ordering::Relaxed);
+        self.timing
+            .budget_nanos
+            .store(nanos(budget), Ordering::Relaxed);
+
+        if self.log_limiter.allow(Instant::now()) {
+            info!(
+                "Audio callback over the last {} callbacks: {}",
+                self.stats.len(),
+                self.timing.summary()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_rate_limiter_lets_one_event_through_per_window() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(1));
+        let start = Instant::now();
+        assert!(limiter.allow(start));
+        let allowed = (1..100)
+            .filter(|i| limiter.allow(start + Duration::from_millis(*i * 9)))
+            .count();
+        assert_eq!(allowed, 0);
+        assert!(limiter.allow(start + Duration::from_secs(1)));
+        assert!(!limiter.allow(start + Duration::from_millis(1_500)));
+        assert!(limiter.allow(start + Duration::from_millis(2_000)));
+    }
+
+    #[test]
+    fn polyphony_warnings_start_past_the_threshold_and_are_rate_limited() {
+        let mut monitor = PolyphonyMonitor::new(&DiagnosticsConfig {
+            polyphony_warning_threshold: 4,
+            warning_interval: 1.0,
+            ..DiagnosticsConfig::default()
+        });
+        let start = Instant::now();
+        assert!(!monitor.check(4, start));
+        assert!(monitor.check(5, start));
+        assert!(!monitor.check(8, start + Duration::from_millis(500)));
+        assert!(monitor.check(8, start + Duration::from_secs(1)));
+    }
+
+    /// 480 frames at 48 kHz, a 10 ms budget.
+    const FRAMES: usize = 480;
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    #[test]
+    fn the_watchdog_counts_callbacks_by_how_much_of_their_budget_they_took() {
+        let mut watchdog = CallbackWatchdog::default();
+        let start = Instant::now();
+        for (i, millis) in [1, 6, 9, 12, 20].into_iter().enumerate() {
+            let now = start + Duration::from_millis(10 * i as u64);
+            watchdog.record(
+                Duration::from_millis(millis),
+                FRAMES,
+                SAMPLE_RATE,
+                3,
+                &[],
+                now,
+            );
+        }
+        assert_eq!(
+            watchdog.counters().summary(),
+            WatchdogSummary {
+                callbacks: 5,
+                over_half_budget: 4,
+                over_80_percent_budget: 3,
+                over_budget: 2,
+                callback_gaps: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn overrun_warnings_are_logged_at_most_once_a_second() {
+        let mut watchdog = CallbackWatchdog::default();
+        let start = Instant::now();
+        let overrun = |watchdog: &mut CallbackWatchdog, millis: u64| {
+            watchdog.record(
+                Duration::from_millis(15),
+                FRAMES,
+                SAMPLE_RATE,
+                8,
+                &["tremolo"],
+                start + Duration::from_millis(millis),
+            )
+        };
+        assert!(overrun(&mut watchdog, 0));
+        let warned = (1..100).filter(|i| overrun(&mut watchdog, i * 10)).count();
+        assert_eq!(warned, 0);
+        assert!(overrun(&mut watchdog, 1_000));
+        assert_eq!(watchdog.counters().summary().over_budget, 101);
+    }
+
+    #[test]
+    fn a_late_callback_counts_as_a_gap() {
+        let mut watchdog = CallbackWatchdog::default();
+        let start = Instant::now();
+        let mut now = start;
+        // Callbacks on time, then one delayed by two blocks, measured by the clock.
+        for delay in [10, 10, 10, 30, 10] {
+            now += Duration::from_millis(delay);
+            watchdog.callback_started(None, now);
+            watchdog.record(Duration::from_millis(1), FRAMES, SAMPLE_RATE, 1, &[], now);
+        }
+        assert_eq!(watchdog.counters().summary().callback_gaps, 1);
+
+        // The backend's timestamps win over the clock when it gives them.
+        let mut watchdog = CallbackWatchdog::default();
+        for (i, stream_millis) in [0, 10, 20, 50, 60].into_iter().enumerate() {
+            let now = start + Duration::from_millis(10 * i as u64);
+            watchdog.callback_started(Some(Duration::from_millis(stream_millis)), now);
+            watchdog.record(Duration::from_millis(1), FRAMES, SAMPLE_RATE, 1, &[], now);
+        }
+        assert_eq!(watchdog.counters().summary().callback_gaps, 1);
+    }
+
+    #[test]
+    fn rolling_stats_track_the_max_and_average_over_the_window() {
+        let ms = Duration::from_millis;
+        let mut stats = RollingStats::new(3);
+        assert!(stats.is_empty());
+        assert_eq!(stats.max(), Duration::ZERO);
+        assert_eq!(stats.average(), Duration::ZERO);
+
+        stats.push(ms(2));
+        stats.push(ms(8));
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats.max(), ms(8));
+        assert_eq!(stats.average(), ms(5));
+
+        stats.push(ms(5));
+        assert_eq!(stats.max(), ms(8));
+        assert_eq!(stats.average(), ms(5));
+
+        // The window is full, so each new duration pushes the oldest out.
+        stats.push(ms(2));
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats.average(), ms(5));
+        stats.push(ms(2));
+        assert_eq!(stats.max(), ms(5));
+        assert_eq!(stats.average(), ms(3));
+        stats.push(ms(2));
+        assert_eq!(stats.max(), ms(2));
+        assert_eq!(stats.average(), ms(2));
+    }
+
+    #[test]
+    fn rolling_stats_keep_at_least_one_duration() {
+        let mut stats = RollingStats::new(0);
+        stats.push(Duration::from_millis(4));
+        stats.push(Duration::from_millis(1));
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats.max(), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn the_callback_timer_publishes_its_stats_and_budget() {
+        let mut timer = CallbackTimer::new(&DiagnosticsConfig {
+            timing_window: 2,
+            ..DiagnosticsConfig::default()
+        });
+        for millis in [1, 3, 2] {
+            timer.record(Duration::from_millis(millis), 480, 48_000.0);
+        }
+        let summary = timer.timing().summary();
+        assert_eq!(summary.last, Duration::from_millis(2));
+        assert_eq!(summary.max, Duration::from_millis(3));
+        assert_eq!(summary.average, Duration::from_micros(2_500));
+        assert_eq!(summary.budget, Duration::from_millis(10));
+        assert_eq!(
+            summary.to_string(),
+            "avg 2.500 ms (25%), max 3.000 ms (30%) of a 10.000 ms budget"
+        );
+    }
+
+    #[test]
+    fn the_dc_meter_settles_on_the_offset_of_the_channel_furthest_from_zero() {
+        let mut meter = DcMeter::new(&DiagnosticsConfig::default(), SAMPLE_RATE);
+        let buffer = AudioBuffer {
+            data: [vec![0.02; FRAMES], vec![-0.1; FRAMES]].concat(),
+            num_channels: 2,
+        };
+        let start = Instant::now();
+        // Past the threshold of 0.05 only after enough blocks to pull the reading that far, then
+        // no more than one warning per interval.
+        let warnings: Vec<usize> = (0..200)
+            .filter(|&i| meter.measure(&buffer, start + Duration::from_millis(10 * i as u64)))
+            .collect();
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings[0] > 20);
+        // Two seconds, four time constants, in.
+        let level = meter.level().get();
+        assert!((level + 0.1).abs() < 0.003, "{}", level);
+    }
+}
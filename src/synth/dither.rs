@@ -0,0 +1,288 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cpal::{FromSample, Sample, SampleFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::synth::SplitMix64;
+
+/// Bits of precision from which rounding noise is too far down to be worth dithering.
+const UNDITHERED_BITS: u32 = 24;
+
+/// How samples are dithered on their way to an integer output format.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DitherMode {
+    /// Samples are rounded to the nearest step, which turns quiet signals into distortion.
+    Off,
+    /// Triangular noise of up to a step either way is added before rounding, leaving a steady
+    /// hiss in place of the distortion.
+    #[default]
+    Tpdf,
+    /// As `tpdf`, with each sample's rounding error taken off the next one on its channel,
+    /// which tilts the hiss up towards the top of the spectrum where it is harder to hear.
+    NoiseShaped,
+}
+
+/// Settings for converting the synth's samples to the output device's format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DitherConfig {
+    /// Only applies to integer formats under 24 bits; float output is passed through untouched.
+    pub mode: DitherMode,
+    /// Seed for the noise, so a render dithers the same way every time. Unset seeds from the
+    /// clock.
+    pub seed: Option<u64>,
+}
+
+impl Default for DitherConfig {
+    fn default() -> Self {
+        DitherConfig {
+            mode: DitherMode::Tpdf,
+            seed: None,
+        }
+    }
+}
+
+/// Converts the engine's samples to an integer output format, dithering them on the way.
+///
+/// Samples are rounded to the format's steps here, so the conversion to the integer type that
+/// follows is exact. Each channel draws its noise from a generator of its own, and keeps its own
+/// rounding error when noise shaping.
+#[derive(Debug)]
+pub struct OutputConverter {
+    mode: DitherMode,
+    /// One step of the output format, in full scale.
+    step: f32,
+    rngs: Vec<SplitMix64>,
+    /// Each channel's rounding error on its last sample, for noise shaping.
+    errors: Vec<f32>,
+}
+
+impl OutputConverter {
+    /// A converter for `bits`-bit samples, interleaved over `channels`.
+    pub fn new(config: &DitherConfig, bits: u32, channels: usize) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+        });
+        let mut seeds = SplitMix64::new(seed);
+        let channels = channels.max(1);
+        OutputConverter {
+            mode: config.mode,
+            step: 2.0f32.powi(1 - bits as i32),
+            rngs: (0..channels)
+                .map(|_| SplitMix64::new(seeds.next_u64()))
+                .collect(),
+            errors: vec![0.0; channels],
+        }
+    }
+
+    /// The converter samples in `format` need, or `None` when they can be converted as they
+    /// are: for float formats, formats of 24 bits or more, or with dithering off.
+    pub fn for_format(
+        config: &DitherConfig,
+        format: SampleFormat,
+        channels: usize,
+    ) -> Option<Self> {
+        let bits = format.sample_size() as u32 * 8;
+        (config.mode != DitherMode::Off && !format.is_float() && bits < UNDITHERED_BITS)
+            .then(|| OutputConverter::new(config, bits, channels))
+    }
+
+    /// Dithers `sample` on `channel` and rounds it to the nearest step the format can hold.
+    pub fn quantize(&mut self, sample: f32, channel: usize) -> f32 {
+        let target = match self.mode {
+            DitherMode::NoiseShaped => sample - self.errors[channel],
+            DitherMode::Off | DitherMode::Tpdf => sample,
+        };
+        // The difference of two uniform draws is triangular over a step either way.
+        let noise = match self.mode {
+            DitherMode::Off => 0.0,
+            DitherMode::Tpdf | DitherMode::NoiseShaped => {
+                let rng = &mut self.rngs[channel];
+                (rng.next_unit() - rng.next_unit()) * self.step
+            }
+        };
+        let rounded = ((target + noise) / self.step).round() * self.step;
+        // Only the rounding is fed back, not the noise or any clipping, so the noise stays
+        // white and a clipped peak doesn't bleed into the samples after it.
+        self.errors[channel] = rounded - (target + noise);
+        rounded.clamp(-1.0, 1.0 - self.step)
+    }
+
+    /// Converts the interleaved samples in `input` into `output`.
+    pub fn convert<T: Sample + FromSample<f32>>(&mut self, input: &[f32], output: &mut [T]) {
+        let channels = self.rngs.len();
+        for (index, (out, sample)) in output.iter_mut().zip(input).enumerate() {
+            *out = T::from_sample(self.quantize(*sample, index % channels));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::TAU;
+
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48_000;
+    /// One step of 16-bit output, in full scale.
+    const LSB: f32 = 1.0 / 32_768.0;
+
+    fn converter(mode: DitherMode, seed: u64, channels: usize) -> OutputConverter {
+        OutputConverter::new(
+            &DitherConfig {
+                mode,
+                seed: Some(seed),
+            },
+            16,
+            channels,
+        )
+    }
+
+    /// `input`, mono, converted to 16-bit samples.
+    fn to_i16(converter: &mut OutputConverter, input: &[f32]) -> Vec<i16> {
+        let mut output = vec![0i16; input.len()];
+        converter.convert(input, &mut output);
+        output
+    }
+
+    /// Two seconds of a 750 Hz sine fading evenly from 6 steps to silence.
+    fn fade() -> Vec<f32> {
+        let len = 2 * SAMPLE_RATE;
+        (0..len)
+            .map(|i| {
+                let level = 6.0 * LSB * (1.0 - i as f32 / len as f32);
+                level * (TAU * 750.0 * i as f64 / SAMPLE_RATE as f64).sin() as f32
+            })
+            .collect()
+    }
+
+    /// The amplitude of `samples` at `frequency`, over a whole number of its cycles.
+    fn amplitude_at(samples: &[f64], frequency: f64) -> f64 {
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (i, sample)| {
+                let angle = TAU * frequency * i as f64 / SAMPLE_RATE as f64;
+                (re + sample * angle.cos(), im + sample * angle.sin())
+            });
+        2.0 * re.hypot(im) / samples.len() as f64
+    }
+
+    /// The harmonics up to the ninth against the 750 Hz fundamental.
+    fn distortion(samples: &[i16]) -> f64 {
+        let samples: Vec<f64> = samples.iter().map(|&sample| sample as f64).collect();
+        let fundamental = amplitude_at(&samples, 750.0);
+        let harmonics = (2..=9)
+            .map(|harmonic| amplitude_at(&samples, 750.0 * harmonic as f64).powi(2))
+            .sum::<f64>();
+        harmonics.sqrt() / fundamental
+    }
+
+    #[test]
+    fn only_integer_formats_under_24_bits_get_a_converter() {
+        let config = DitherConfig::default();
+        assert!(OutputConverter::for_format(&config, SampleFormat::I16, 2).is_some());
+        assert!(OutputConverter::for_format(&config, SampleFormat::U16, 2).is_some());
+        assert!(OutputConverter::for_format(&config, SampleFormat::I8, 2).is_some());
+        assert!(OutputConverter::for_format(&config, SampleFormat::I32, 2).is_none());
+        assert!(OutputConverter::for_format(&config, SampleFormat::F32, 2).is_none());
+        assert!(OutputConverter::for_format(&config, SampleFormat::F64, 2).is_none());
+        let off = DitherConfig {
+            mode: DitherMode::Off,
+            seed: None,
+        };
+        assert!(OutputConverter::for_format(&off, SampleFormat::I16, 2).is_none());
+    }
+
+    #[test]
+    fn dithered_silence_is_a_centred_hiss_of_at_most_one_step() {
+        for mode in [DitherMode::Tpdf, DitherMode::NoiseShaped] {
+            let output = to_i16(&mut converter(mode, 7, 1), &vec![0.0; SAMPLE_RATE]);
+            let mean = output.iter().map(|&s| s as f64).sum::<f64>() / output.len() as f64;
+            assert!(mean.abs() < 0.01, "{:?} {}", mode, mean);
+            let largest = output.iter().map(|s| s.unsigned_abs()).max().unwrap();
+            let bound = if mode == DitherMode::Tpdf { 1 } else { 2 };
+            assert!(largest <= bound, "{:?} {}", mode, largest);
+            // Triangular noise over a step either way rounds to a step off a quarter of the time.
+            let off_zero = output.iter().filter(|&&s| s != 0).count() as f64 / output.len() as f64;
+            assert!(off_zero > 0.2, "{:?} {}", mode, off_zero);
+        }
+    }
+
+    #[test]
+    fn a_dithered_fade_has_far_less_distortion_than_the_plain_conversion() {
+        let fade = fade();
+        let first_second = &fade[..SAMPLE_RATE];
+        // What the output did before there was a converter.
+        let plain: Vec<i16> = first_second.iter().map(|&s| i16::from_sample(s)).collect();
+        let plain = distortion(&plain);
+        let tpdf = distortion(&to_i16(
+            &mut converter(DitherMode::Tpdf, 1, 1),
+            first_second,
+        ));
+        let shaped = distortion(&to_i16(
+            &mut converter(DitherMode::NoiseShaped, 1, 1),
+            first_second,
+        ));
+        assert!(plain > 0.03, "{}", plain);
+        assert!(tpdf < plain / 5.0, "{} {}", tpdf, plain);
+        assert!(shaped < plain / 5.0, "{} {}", shaped, plain);
+
+        // Once the fade is over, what is left is the hiss, centred on zero.
+        let output = to_i16(&mut converter(DitherMode::Tpdf, 1, 1), &fade);
+        let tail = &output[output.len() - SAMPLE_RATE / 10..];
+        let mean = tail.iter().map(|&s| s as f64).sum::<f64>() / tail.len() as f64;
+        assert!(mean.abs() < 0.05, "{}", mean);
+        assert!(tail.iter().all(|s| s.unsigned_abs() <= 1));
+    }
+
+    #[test]
+    fn noise_shaping_moves_the_hiss_away_from_low_frequencies() {
+        /// The spread of the error's averages over runs of 32 samples, which only the low
+        /// frequencies in it get through.
+        fn low_frequency_error(mode: DitherMode) -> f64 {
+            let output = to_i16(&mut converter(mode, 3, 1), &vec![0.0; SAMPLE_RATE]);
+            let means: Vec<f64> = output
+                .chunks(32)
+                .map(|run| run.iter().map(|&s| s as f64).sum::<f64>() / 32.0)
+                .collect();
+            means.iter().map(|mean| mean * mean).sum::<f64>() / means.len() as f64
+        }
+        // The triangular noise itself stays white, so only the rounding's share, a third of the
+        // total, is moved up.
+        let tpdf = low_frequency_error(DitherMode::Tpdf);
+        let shaped = low_frequency_error(DitherMode::NoiseShaped);
+        assert!(shaped < tpdf * 0.8, "{} {}", shaped, tpdf);
+    }
+
+    #[test]
+    fn a_seeded_run_repeats_exactly_with_each_channel_on_its_own_noise() {
+        let input = vec![0.0; 2 * 4_800];
+        let first = to_i16(&mut converter(DitherMode::Tpdf, 42, 2), &input);
+        let again = to_i16(&mut converter(DitherMode::Tpdf, 42, 2), &input);
+        let other_seed = to_i16(&mut converter(DitherMode::Tpdf, 43, 2), &input);
+        assert_eq!(first, again);
+        assert_ne!(first, other_seed);
+        let left: Vec<i16> = first.iter().step_by(2).copied().collect();
+        let right: Vec<i16> = first.iter().skip(1).step_by(2).copied().collect();
+        assert_ne!(left, right);
+    }
+
+    #[test]
+    fn samples_land_on_the_format_grid_and_full_scale_is_held_to_its_range() {
+        let mut converter = converter(DitherMode::Tpdf, 5, 1);
+        for sample in [0.3, -0.71, 1e-6, 0.5 * LSB] {
+            let quantized = converter.quantize(sample, 0);
+            assert_eq!(quantized / LSB, (quantized / LSB).round());
+            assert!((quantized - sample).abs() <= 1.5 * LSB);
+        }
+        for _ in 0..100 {
+            assert!(converter.quantize(1.0, 0) <= 1.0 - LSB);
+            assert!(converter.quantize(-1.0, 0) >= -1.0);
+        }
+        assert_eq!(to_i16(&mut converter, &[2.0, -2.0]), [i16::MAX, i16::MIN]);
+    }
+}
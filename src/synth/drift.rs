@@ -0,0 +1,253 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::synth::SplitMix64;
+
+/// Slow random wandering of every voice's pitch and phase, like an analog oscillator that never
+/// quite holds still. Off by default.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DriftConfig {
+    /// Most a voice's pitch wanders from its note, in cents. Zero holds the pitch steady.
+    pub cents: f32,
+    /// Most a voice's phase wanders from where it would otherwise be, in cycles. Zero holds
+    /// the phase steady.
+    pub phase: f32,
+    /// How far the wandering typically gets in a second, as a share of its range.
+    pub rate: f32,
+    /// Seed for the wandering, so a performance drifts the same way every time. Unset seeds
+    /// from the clock.
+    pub seed: Option<u64>,
+}
+
+impl Default for DriftConfig {
+    fn default() -> Self {
+        DriftConfig {
+            cents: 0.0,
+            phase: 0.0,
+            rate: 0.5,
+            seed: None,
+        }
+    }
+}
+
+impl DriftConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.cents > 0.0 || self.phase > 0.0
+    }
+}
+
+/// Hands every new voice a drift seed of its own, so no two voices wander alike.
+#[derive(Debug)]
+pub struct DriftSeeds {
+    seed: u64,
+    voices: AtomicU64,
+}
+
+impl DriftSeeds {
+    pub fn new(config: &DriftConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+        });
+        DriftSeeds {
+            seed,
+            voices: AtomicU64::new(0),
+        }
+    }
+
+    /// The seed for the next voice.
+    pub fn next_seed(&self) -> u64 {
+        let voice = self.voices.fetch_add(1, Ordering::Relaxed);
+        SplitMix64::new(self.seed.wrapping_add(voice)).next_u64()
+    }
+}
+
+/// A random walk between -1 and 1 that bounces back off either end.
+#[derive(Debug, Clone, Copy, Default)]
+struct BoundedWalk {
+    position: f32,
+}
+
+impl BoundedWalk {
+    fn step(&mut self, by: f32) -> f32 {
+        let mut position = self.position + by;
+        if position > 1.0 {
+            position = 2.0 - position;
+        } else if position < -1.0 {
+            position = -2.0 - position;
+        }
+        self.position = position.clamp(-1.0, 1.0);
+        self.position
+    }
+}
+
+/// Where one voice's pitch and phase have wandered to.
+///
+/// Each moves in a random walk of its own, scaled so it spreads as far in a second whatever
+/// the block size, and never past the configured range.
+#[derive(Debug, Clone)]
+pub struct Drift {
+    config: DriftConfig,
+    rng: SplitMix64,
+    pitch: BoundedWalk,
+    phase: BoundedWalk,
+}
+
+impl Drift {
+    /// A voice's drift, starting on pitch and wandering as `seed` has it.
+    pub fn new(config: DriftConfig, seed: u64) -> Self {
+        Drift {
+            config,
+            rng: SplitMix64::new(seed),
+            pitch: BoundedWalk::default(),
+            phase: BoundedWalk::default(),
+        }
+    }
+
+    /// How far the pitch has wandered, in cents.
+    pub fn cents(&self) -> f32 {
+        self.pitch.position * self.config.cents
+    }
+
+    /// Wanders on by `seconds`. Returns the factor the voice's frequency is off by and how far
+    /// its phase moved, in cycles.
+    pub fn advance(&mut self, seconds: f32) -> (f32, f32) {
+        // Uniform steps of this size have unit variance, so after a second of steps the walk
+        // has typically got `rate` from where it started.
+        let step = self.config.rate * (3.0 * seconds.max(0.0)).sqrt();
+        self.pitch.step(self.rng.next_signed() * step);
+        let last_phase = self.phase.position;
+        let phase = self.phase.step(self.rng.next_signed() * step);
+        (
+            2.0f32.powf(self.cents() / 1200.0),
+            (phase - last_phase) * self.config.phase,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 512-frame block at 48 kHz, in seconds.
+    const BLOCK: f32 = 512.0 / 48_000.0;
+
+    fn config(cents: f32) -> DriftConfig {
+        DriftConfig {
+            cents,
+            phase: 0.1,
+            ..DriftConfig::default()
+        }
+    }
+
+    /// How far the pitch has wandered after each of `blocks` blocks of `seconds`.
+    fn path(seed: u64, blocks: usize, seconds: f32) -> Vec<f32> {
+        let mut drift = Drift::new(config(10.0), seed);
+        (0..blocks)
+            .map(|_| {
+                drift.advance(seconds);
+                drift.cents()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_pitch_wanders_within_its_range_and_gets_around_it() {
+        // A minute of blocks.
+        let path = path(42, 5_625, BLOCK);
+        assert!(path.iter().all(|cents| cents.abs() <= 10.0));
+        let furthest = path
+            .iter()
+            .fold(0.0f32, |furthest, cents| furthest.max(cents.abs()));
+        assert!(furthest > 8.0, "{}", furthest);
+        // Slowly: no block moves it more than a small share of the range.
+        let largest_step = path
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0f32, f32::max);
+        assert!(
+            largest_step < 10.0 * 0.5 * (3.0 * BLOCK).sqrt(),
+            "{}",
+            largest_step
+        );
+    }
+
+    #[test]
+    fn the_frequency_factor_matches_the_cents() {
+        let mut drift = Drift::new(config(25.0), 7);
+        for _ in 0..1_000 {
+            let (factor, phase_shift) = drift.advance(BLOCK);
+            assert!((factor - 2.0f32.powf(drift.cents() / 1200.0)).abs() < 1e-6);
+            assert!((0.5f32.powf(25.0 / 1200.0)..=2.0f32.powf(25.0 / 1200.0)).contains(&factor));
+            // The phase stays within its range, so no one step can move it further than that.
+            assert!(phase_shift.abs() <= 0.2);
+        }
+    }
+
+    #[test]
+    fn a_seed_repeats_its_path_and_another_seed_takes_a_different_one() {
+        assert_eq!(path(42, 1_000, BLOCK), path(42, 1_000, BLOCK));
+        assert_ne!(path(42, 1_000, BLOCK), path(43, 1_000, BLOCK));
+    }
+
+    #[test]
+    fn the_spread_after_a_second_does_not_depend_on_the_block_size() {
+        /// The mean squared wander after a second, over many voices.
+        fn spread(block_frames: usize) -> f32 {
+            let seconds = block_frames as f32 / 48_000.0;
+            let blocks = 48_000 / block_frames;
+            let voices = 400;
+            (0..voices)
+                .map(|seed| {
+                    let cents = *path(seed, blocks, seconds).last().unwrap() / 10.0;
+                    cents * cents
+                })
+                .sum::<f32>()
+                / voices as f32
+        }
+        let small = spread(64);
+        let large = spread(1_000);
+        assert!((small / large - 1.0).abs() < 0.25, "{} {}", small, large);
+    }
+
+    #[test]
+    fn every_voice_gets_a_seed_of_its_own_and_a_seeded_config_repeats_them() {
+        let seeded = DriftConfig {
+            seed: Some(9),
+            ..DriftConfig::default()
+        };
+        let seeds = DriftSeeds::new(&seeded);
+        let first: Vec<u64> = (0..8).map(|_| seeds.next_seed()).collect();
+        let mut unique = first.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), first.len());
+        let again = DriftSeeds::new(&seeded);
+        assert_eq!((0..8).map(|_| again.next_seed()).collect::<Vec<_>>(), first);
+    }
+
+    #[test]
+    fn the_walk_bounces_back_off_either_end() {
+        let mut walk = BoundedWalk { position: 0.9 };
+        assert!((walk.step(0.3) - 0.8).abs() < 1e-6);
+        let mut walk = BoundedWalk { position: -0.9 };
+        assert!((walk.step(-0.3) - -0.8).abs() < 1e-6);
+        // A step too long to bounce back from once still ends up in range.
+        assert!(walk.step(5.0).abs() <= 1.0);
+    }
+
+    #[test]
+    fn drift_is_off_until_it_has_a_range() {
+        assert!(!DriftConfig::default().is_enabled());
+        assert!(config(5.0).is_enabled());
+        let phase_only = DriftConfig {
+            phase: 0.1,
+            ..DriftConfig::default()
+        };
+        assert!(phase_only.is_enabled());
+    }
+}
@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{AudioBuffer, ParamId, WaveShaperConfig, DEFAULT_GAIN};
+
+/// What pushes the wave shaper's drive up while notes play.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriveFollows {
+    /// The drive stays where it is set.
+    #[default]
+    None,
+    /// The velocity of the hardest-struck held voice, from 0 to 1.
+    Velocity,
+    /// The level of the mix going into the shaper, with one voice at the default gain as 1.
+    Amplitude,
+}
+
+/// Moves the wave shaper's drive with the playing, block by block.
+///
+/// The followed value is smoothed and scaled by the configured amount, then added to the base
+/// drive, so turning the drive by hand still works while it is modulated. The result stays
+/// within the `Drive` parameter's range.
+#[derive(Debug, Clone)]
+pub struct DriveModulation {
+    follows: DriveFollows,
+    amount: f32,
+    smoothing: f32,
+    sample_rate: f32,
+    /// The followed value after smoothing.
+    level: f32,
+}
+
+impl DriveModulation {
+    pub fn new(config: &WaveShaperConfig, sample_rate: f32) -> Self {
+        DriveModulation {
+            follows: config.drive_follows,
+            amount: config.follow_amount,
+            smoothing: config.follow_smoothing,
+            sample_rate,
+            level: 0.0,
+        }
+    }
+
+    /// The drive for the next block of `mix`, starting from `base_drive`, with `velocity` the
+    /// velocity of the hardest-struck held voice. `None` when the drive follows nothing.
+    pub fn next_drive(
+        &mut self,
+        base_drive: f32,
+        velocity: Option<u8>,
+        mix: &AudioBuffer,
+    ) -> Option<f32> {
+        let target = match self.follows {
+            DriveFollows::None => return None,
+            DriveFollows::Velocity => {
+                velocity.map_or(0.0, |velocity| velocity.min(127) as f32 / 127.0)
+            }
+            DriveFollows::Amplitude => {
+                let sum_of_squares: f32 = mix.data.iter().map(|sample| sample * sample).sum();
+                // A sine's peak, from its RMS.
+                let level = (sum_of_squares / mix.data.len().max(1) as f32).sqrt()
+                    * std::f32::consts::SQRT_2;
+                level / DEFAULT_GAIN
+            }
+        };
+
+        let smoothing_samples = self.smoothing * self.sample_rate;
+        let coefficient = if smoothing_samples > 0.0 {
+            1.0 - (-(mix.num_frames() as f32) / smoothing_samples).exp()
+        } else {
+            1.0
+        };
+        self.level += (target - self.level) * coefficient;
+
+        let (min, max) = ParamId::Drive.range();
+        Some((base_drive + self.amount * self.level).clamp(min, max))
+    }
+}
@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+
+use crate::synth::AudioBuffer;
+
+/// Which bus gets quieter while the other is playing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuckedBus {
+    /// The backing source ducks under the synth.
+    #[default]
+    Backing,
+    /// The synth ducks under the backing source.
+    Synth,
+}
+
+/// How the level of the bus doing the ducking is measured each block.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LevelDetector {
+    #[default]
+    Peak,
+    Rms,
+}
+
+/// Settings for mixing a backing source with the synth, one ducking under the other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuckingConfig {
+    pub enabled: bool,
+    /// Level, from 0.0 to 1.0, above which the other bus counts as playing.
+    pub threshold: f32,
+    /// How far the ducked bus is turned down, in dB.
+    pub amount_db: f32,
+    /// Seconds to reach the full reduction once the other bus starts playing.
+    pub attack: f32,
+    /// Seconds to come back up once it has gone quiet.
+    pub release: f32,
+    pub detector: LevelDetector,
+    pub ducked: DuckedBus,
+}
+
+impl Default for DuckingConfig {
+    fn default() -> Self {
+        DuckingConfig {
+            enabled: false,
+            threshold: 0.01,
+            amount_db: 12.0,
+            attack: 0.01,
+            release: 0.3,
+            detector: LevelDetector::Peak,
+            ducked: DuckedBus::Backing,
+        }
+    }
+}
+
+/// Sums the synth and a backing bus, turning one down while the other is playing.
+///
+/// The level is measured once per block; the gain then ramps towards its target sample by
+/// sample, reaching it within the attack or release time.
+#[derive(Debug)]
+pub struct DuckingMixer {
+    config: DuckingConfig,
+    sample_rate: f32,
+    /// Gain currently applied to the ducked bus.
+    gain: f32,
+}
+
+impl DuckingMixer {
+    pub fn new(config: DuckingConfig, sample_rate: f32) -> Self {
+        DuckingMixer {
+            config,
+            sample_rate,
+            gain: 1.0,
+        }
+    }
+
+    pub fn config(&self) -> &DuckingConfig {
+        &self.config
+    }
+
+    /// Gain the ducked bus was last mixed at.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Writes `synth` plus `backing` into `output`, ducking whichever bus the config says.
+    pub fn mix(&mut self, synth: &AudioBuffer, backing: &AudioBuffer, output: &mut AudioBuffer) {
+        assert_eq!(synth.data.len(), backing.data.len());
+        assert_eq!(synth.data.len(), output.data.len());
+
+        let (key, ducked) = match self.config.ducked {
+            DuckedBus::Backing => (synth, backing),
+            DuckedBus::Synth => (backing, synth),
+        };
+        if !self.config.enabled {
+            for ((sample, key), ducked) in output.data.iter_mut().zip(&key.data).zip(&ducked.data) {
+                *sample = key + ducked;
+            }
+            return;
+        }
+
+        let ducked_gain = 10.0f32.powf(-self.config.amount_db.abs() / 20.0);
+        let (target, ramp_time) = if self.level(&key.data) > self.config.threshold {
+            (ducked_gain, self.config.attack)
+        } else {
+            (1.0, self.config.release)
+        };
+        // The ramp covers the whole range from unity to the full reduction in `ramp_time`.
+        let step = if ramp_time > 0.0 {
+            (1.0 - ducked_gain) / (ramp_time * self.sample_rate)
+        } else {
+            f32::INFINITY
+        };
+
+        // The buffers are planar, so each channel ramps over the same frames.
+        let num_frames = output.num_frames();
+        let start_gain = self.gain;
+        for (index, ((sample, key), ducked)) in output
+            .data
+            .iter_mut()
+            .zip(&key.data)
+            .zip(&ducked.data)
+            .enumerate()
+        {
+            let frame = index % num_frames;
+            let gain = ramp_towards(start_gain, target, step * (frame + 1) as f32);
+            *sample = key + ducked * gain;
+        }
+        self.gain = ramp_towards(start_gain, target, step * num_frames as f32);
+    }
+
+    fn level(&self, samples: &[f32]) -> f32 {
+        match self.config.detector {
+            LevelDetector::Peak => samples
+                .iter()
+                .fold(0.0, |peak, sample| sample.abs().max(peak)),
+            LevelDetector::Rms if samples.is_empty() => 0.0,
+            LevelDetector::Rms => (samples.iter().map(|sample| sample * sample).sum::<f32>()
+                / samples.len() as f32)
+                .sqrt(),
+        }
+    }
+}
+
+/// Moves `gain` by up to `amount` towards `target`, without overshooting it.
+pub(crate) fn ramp_towards(gain: f32, target: f32, amount: f32) -> f32 {
+    if target < gain {
+        (gain - amount).max(target)
+    } else {
+        (gain + amount).min(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    const BLOCK: usize = 64;
+
+    /// Mixes a constant backing level of 0.5 under a synth that plays from block `on` to block
+    /// `off`, returning the backing's gain at every sample.
+    fn backing_gains(config: DuckingConfig, blocks: usize, on: usize, off: usize) -> Vec<f32> {
+        let mut mixer = DuckingMixer::new(config, SAMPLE_RATE);
+        let backing = AudioBuffer {
+            data: vec![0.5; 2 * BLOCK],
+            num_channels: 2,
+        };
+        let mut gains = Vec::new();
+        for block in 0..blocks {
+            let level = if (on..off).contains(&block) { 0.2 } else { 0.0 };
+            let synth = AudioBuffer {
+                data: vec![level; 2 * BLOCK],
+                num_channels: 2,
+            };
+            let mut output = AudioBuffer {
+                data: vec![0.0; 2 * BLOCK],
+                num_channels: 2,
+            };
+            mixer.mix(&synth, &backing, &mut output);
+            // Both channels ramp alike.
+            assert_eq!(output.channel(0), output.channel(1));
+            gains.extend(
+                output
+                    .channel(0)
+                    .iter()
+                    .map(|sample| (sample - level) / 0.5),
+            );
+        }
+        gains
+    }
+
+    fn config() -> DuckingConfig {
+        DuckingConfig {
+            enabled: true,
+            ..DuckingConfig::default()
+        }
+    }
+
+    #[test]
+    fn the_backing_dips_by_the_configured_amount_within_the_attack() {
+        let config = config();
+        let ducked = 10.0f32.powf(-config.amount_db / 20.0);
+        let attack = (config.attack * SAMPLE_RATE) as usize;
+        let on = 10;
+        let gains = backing_gains(config, 40, on, 40);
+        let start = on * BLOCK;
+        assert!(gains[..start].iter().all(|gain| (gain - 1.0).abs() < 1e-6));
+        // Still on its way down just before the attack time is up, and all the way down at it.
+        assert!(gains[start + attack - 2] > ducked + 1e-4);
+        assert!((gains[start + attack - 1] - ducked).abs() < 1e-4);
+        assert!(gains[start + attack..]
+            .iter()
+            .all(|gain| (gain - ducked).abs() < 1e-4));
+        let dip_db = 20.0 * gains.last().unwrap().log10();
+        assert!((dip_db + 12.0).abs() < 0.01, "{} dB", dip_db);
+    }
+
+    #[test]
+    fn the_backing_recovers_within_the_release() {
+        let config = config();
+        let release = (config.release * SAMPLE_RATE) as usize;
+        let (on, off) = (0, 20);
+        let blocks = off + release / BLOCK + 10;
+        let gains = backing_gains(config, blocks, on, off);
+        let stop = off * BLOCK;
+        // The release never dips back down, and it is back at unity right on time.
+        assert!(gains[stop..stop + release]
+            .windows(2)
+            .all(|pair| pair[1] >= pair[0]));
+        assert!(gains[stop + release / 2] > gains[stop] + 0.3);
+        assert!(gains[stop + release - 2] < 1.0 - 1e-5);
+        assert!(gains[stop + release - 1..]
+            .iter()
+            .all(|gain| (gain - 1.0).abs() < 1e-5));
+    }
+
+    #[test]
+    fn the_synth_can_duck_under_the_backing_instead() {
+        let mut mixer = DuckingMixer::new(
+            DuckingConfig {
+                ducked: DuckedBus::Synth,
+                attack: 0.0,
+                ..config()
+            },
+            SAMPLE_RATE,
+        );
+        let synth = AudioBuffer {
+            data: vec![0.2; BLOCK],
+            num_channels: 1,
+        };
+        let backing = AudioBuffer {
+            data: vec![0.5; BLOCK],
+            num_channels: 1,
+        };
+        let mut output = AudioBuffer {
+            data: vec![0.0; BLOCK],
+            num_channels: 1,
+        };
+        mixer.mix(&synth, &backing, &mut output);
+        let ducked = 10.0f32.powf(-12.0 / 20.0);
+        assert!(output
+            .data
+            .iter()
+            .all(|sample| (sample - (0.5 + 0.2 * ducked)).abs() < 1e-6));
+    }
+
+    #[test]
+    fn the_detector_measures_peak_or_rms() {
+        let samples = [0.5, -0.5, 0.5, -0.5, 0.0, 0.0, 0.0, 0.0];
+        let peak = DuckingMixer::new(config(), SAMPLE_RATE);
+        assert_eq!(peak.level(&samples), 0.5);
+        let rms = DuckingMixer::new(
+            DuckingConfig {
+                detector: LevelDetector::Rms,
+                ..config()
+            },
+            SAMPLE_RATE,
+        );
+        assert!((rms.level(&samples) - 0.5f32 / 2.0f32.sqrt()).abs() < 1e-6);
+        assert_eq!(rms.level(&[]), 0.0);
+    }
+
+    #[test]
+    fn disabled_ducking_sums_the_buses() {
+        let gains = backing_gains(DuckingConfig::default(), 10, 2, 10);
+        assert!(gains.iter().all(|gain| (gain - 1.0).abs() < 1e-6));
+    }
+}
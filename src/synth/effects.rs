@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+
+/// The wave shaper's transfer function.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShaperCurve {
+    /// Folds back past full scale, adding bright, bell-like harmonics when driven hard.
+    #[default]
+    Sine,
+    /// Rounds off smoothly towards full scale, like an overdriven amplifier.
+    Tanh,
+    /// Cuts straight off at full scale, for a harsh, buzzy edge.
+    HardClip,
+}
+
+impl ShaperCurve {
+    pub fn transfer_fn(self) -> fn(f32) -> f32 {
+        match self {
+            ShaperCurve::Sine => f32::sin,
+            ShaperCurve::Tanh => f32::tanh,
+            ShaperCurve::HardClip => |x: f32| x.clamp(-1.0, 1.0),
+        }
+    }
+}
+
+/// What the effects are set to when the synth starts, before any key or parameter changes
+/// them. How they behave once running is set in the `tremolo` and `wave_shaper` sections.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectsConfig {
+    /// Tremolo rate in Hz.
+    pub tremolo_rate: f32,
+    /// Tremolo depth, from 0.0 to 1.0.
+    pub tremolo_depth: f32,
+    pub shaper_curve: ShaperCurve,
+    /// Level of the whole output, from 0.0 (silent) to 1.0.
+    pub master_volume: f32,
+}
+
+impl Default for EffectsConfig {
+    fn default() -> Self {
+        EffectsConfig {
+            tremolo_rate: 5.0,
+            tremolo_depth: 0.5,
+            shaper_curve: ShaperCurve::Sine,
+            master_volume: 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::TremoloEffect;
+
+    #[test]
+    fn an_effects_section_sets_the_tremolo_it_builds() {
+        let effects: EffectsConfig = serde_yaml::from_str(
+            "tremolo_rate: 7.5\ntremolo_depth: 0.25\nshaper_curve: hard_clip\nmaster_volume: 0.8\n",
+        )
+        .unwrap();
+        assert_eq!(effects.shaper_curve, ShaperCurve::HardClip);
+        assert_eq!(effects.master_volume, 0.8);
+        // Built the way the app builds its tremolo.
+        let tremolo = TremoloEffect::builder()
+            .rate(effects.tremolo_rate)
+            .depth(effects.tremolo_depth)
+            .build(48_000.0);
+        assert!((tremolo.get_rate() - 7.5).abs() < 1e-3);
+        assert!((tremolo.get_depth() - 0.25).abs() < 1e-3);
+    }
+
+    #[test]
+    fn missing_settings_keep_the_old_defaults() {
+        let effects: EffectsConfig = serde_yaml::from_str("tremolo_depth: 0.75\n").unwrap();
+        assert_eq!(effects.tremolo_rate, 5.0);
+        assert_eq!(effects.tremolo_depth, 0.75);
+        assert_eq!(effects.shaper_curve, ShaperCurve::Sine);
+        assert_eq!(effects.master_volume, 1.0);
+    }
+
+    #[test]
+    fn each_curve_has_its_own_shape() {
+        let sine = ShaperCurve::Sine.transfer_fn();
+        let tanh = ShaperCurve::Tanh.transfer_fn();
+        let hard_clip = ShaperCurve::HardClip.transfer_fn();
+        assert_eq!(sine(0.5), 0.5f32.sin());
+        assert_eq!(tanh(0.5), 0.5f32.tanh());
+        assert_eq!(hard_clip(0.5), 0.5);
+        assert_eq!(hard_clip(3.0), 1.0);
+        assert_eq!(hard_clip(-3.0), -1.0);
+        // The sine folds back past full scale; the others don't.
+        assert!(sine(3.0) < sine(1.5));
+        assert!(tanh(3.0) > tanh(1.5));
+    }
+}
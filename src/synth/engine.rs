@@ -0,0 +1,2593 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::{debug, info, trace, warn};
+
+use crate::synth::{
+    diagnostics::{CallbackWatchdog, DcLevel, DcMeter, PolyphonyMonitor, WatchdogCounters},
+    keys::keys::frequency_to_midi_note,
+    log_limit::{log_rate_limited, HOT_PATH_LOG_INTERVAL},
+    oscillator::warn_limited_frequency,
+    param_history::{ParamChange, UndoStep},
+    params::{
+        steal_policy_from_param, steal_policy_to_param, waveform_from_param, waveform_to_param,
+    },
+    performance::DEFAULT_VELOCITY,
+    ribbon::ribbon_frequency,
+    unison::pan_gains,
+    voice_stealing::select_victim,
+    AudioBuffer, AudioNode, BusConfig, BusGraph, BusId, CpuBudgetConfig, CpuBudgetController,
+    CycleReference, DcBlockerConfig, DcBlockerNode, DiagnosticsConfig, DriftSeeds, DriveModulation,
+    DuckingConfig, DuckingMixer, EffectsConfig, Interpolation, KeyZone, KeyboardSplitConfig,
+    LimiterConfig, LimiterNode, Looper, LooperConfig, LooperState, MuteConfig, MuteGain, NoteEvent,
+    NoteId, NoteState, NoteVariation, Oscillator, OscillatorConfig, OscillatorWaveform,
+    OversampledNode, Oversampling, ParamId, PatchRandomizer, QualityStep, RandomizerConfig,
+    ReferenceRoute, ReferenceTone, ReferenceToneConfig, RibbonConfig, RibbonVoice, Scale,
+    SmoothedParam, StartupEvent, StealPolicy, StrikeVariation, TremoloEffect, UnisonConfig,
+    VisualTap, VoiceCandidate, WaveShaperConfig, WaveShaperNode, WaveformSequence,
+    WaveformSequenceConfig, MAX_QUALITY_LEVEL,
+};
+
+/// The wave shaper at the end of the chain, run oversampled when configured.
+type WaveShaper = OversampledNode<WaveShaperNode<fn(f32) -> f32>>;
+
+/// The synthesis half of the audio callback: turns the shared note state into samples.
+///
+/// The engine knows nothing about devices or windows, so the same code drives the cpal stream
+/// and offline rendering.
+pub struct SynthEngine {
+    sample_rate: f32,
+    num_channels: usize,
+    waveform_type: Arc<RwLock<OscillatorWaveform>>,
+    note_state: Arc<Mutex<NoteState>>,
+    octave_shift: Arc<RwLock<i32>>,
+    global_time: Arc<AtomicU64>,
+    tremolo_effect: Arc<TremoloEffect>,
+    scale: Arc<Mutex<Scale>>,
+    wave_shaper_node: WaveShaper,
+    /// Drive as set, before `drive_modulation` adds to it.
+    drive: SmoothedParam,
+    /// Tremolo rate and depth as set, passed on to the tremolo as they glide there.
+    tremolo_rate: SmoothedParam,
+    tremolo_depth: SmoothedParam,
+    drive_modulation: DriveModulation,
+    ducking_mixer: DuckingMixer,
+    oscillator_config: OscillatorConfig,
+    /// Per-strike variation of new voices, and what each note's last strike did.
+    note_variation: NoteVariation,
+    /// Where each new voice's drift takes it.
+    drift_seeds: DriftSeeds,
+    ribbon_config: RibbonConfig,
+    ribbon_voice: Option<RibbonVoice>,
+    waveform_sequence_config: WaveformSequenceConfig,
+    waveform_sequence: Option<Arc<WaveformSequence>>,
+    polyphony_monitor: PolyphonyMonitor,
+    watchdog: CallbackWatchdog,
+    /// Voices that refused to start because their frequency was NaN or infinite.
+    refused_voices: Arc<AtomicU64>,
+    /// Most live voices sounding at once, not counting loop voices. Released voices only count
+    /// while there is a steal policy to take them over.
+    max_voices: Option<usize>,
+    /// Which voice a new note takes over at the voice limit; `Off` holds the new note back.
+    steal_policy: StealPolicy,
+    /// Policy among held voices when `steal_policy` is `ReleasedFirst` and none are fading out.
+    steal_fallback: StealPolicy,
+    mute_gain: MuteGain,
+    /// Whether the mute key was on as of the last block.
+    muted: bool,
+    /// Whether the wave shaper was bypassed as of the last block.
+    wave_shaper_bypassed: bool,
+    /// The reference tone's own voice, outside the voice limit.
+    reference_tone: ReferenceTone,
+    /// Whether the reference tone key was on as of the last block.
+    reference_tone_on: bool,
+    /// Draws the patches the random patch key and `--random-patch` switch to.
+    patch_randomizer: PatchRandomizer,
+    /// Takes any constant offset out of the output, unless it is turned off.
+    dc_blocker: Option<DcBlockerNode>,
+    /// Measures the offset going into the DC blocker, for the diagnostics.
+    dc_meter: DcMeter,
+    /// Holds the output's peaks down, when turned on.
+    limiter: Option<LimiterNode>,
+    /// The buses the voices are mixed on before the wave shaper.
+    bus_graph: BusGraph,
+    /// The bus the ribbon's voice is mixed on.
+    ribbon_bus: BusId,
+    /// Lowers the quality while rendering runs over its CPU budget, when turned on.
+    cpu_budget: Option<CpuBudgetController>,
+    /// Steps below full quality the engine runs at, shared with whatever shows it.
+    quality_level: Arc<AtomicUsize>,
+    /// The wave shaper's oversampling at full quality.
+    oversampling: Oversampling,
+    /// The voice limit once quality is lowered as far as `QualityStep::Polyphony`.
+    reduced_max_voices: usize,
+    /// Where the visualizer's audio is taken from, as of the last block.
+    visual_tap: VisualTap,
+    /// Where the lowest voice's cycle stood at the end of the last block, kept while the visual
+    /// tap shows a single cycle.
+    cycle_reference: Option<CycleReference>,
+    /// The last block's mix from before the effects, kept while the visual tap uses it.
+    pre_effects_buffer: AudioBuffer,
+    /// Octave shift the sounding voices are tuned for, once a block has been rendered.
+    voiced_octave_shift: Option<i32>,
+    keyboard_split: KeyboardSplitConfig,
+}
+
+impl SynthEngine {
+    pub fn builder() -> SynthEngineBuilder {
+        SynthEngineBuilder::default()
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    /// Changes the channel count of the buffers `render` returns, for hosts that only learn it
+    /// once they start pulling audio.
+    pub fn set_num_channels(&mut self, num_channels: usize) {
+        self.num_channels = num_channels.max(1);
+    }
+
+    pub fn note_state(&self) -> &Arc<Mutex<NoteState>> {
+        &self.note_state
+    }
+
+    pub fn waveform_type(&self) -> &Arc<RwLock<OscillatorWaveform>> {
+        &self.waveform_type
+    }
+
+    /// Where the visualizer's audio is taken from, as of the last block rendered.
+    pub fn visual_tap(&self) -> VisualTap {
+        self.visual_tap
+    }
+
+    /// Where the lowest-pitched voice's cycle stood at the end of the last block, or `None`
+    /// when none is playing. Only kept up to date while `visual_tap` shows a single cycle.
+    pub fn cycle_reference(&self) -> Option<CycleReference> {
+        self.cycle_reference
+    }
+
+    /// The last block's mix from before the wave shaper and the mute, laid out like the
+    /// buffer it was rendered into. Only kept up to date while `visual_tap` uses it.
+    pub fn pre_effects(&self) -> &AudioBuffer {
+        &self.pre_effects_buffer
+    }
+
+    /// Counters of callbacks that ran close to or over their time budget.
+    pub fn watchdog_counters(&self) -> Arc<WatchdogCounters> {
+        Arc::clone(self.watchdog.counters())
+    }
+
+    /// Count of voices that refused to start because their frequency was NaN or infinite.
+    pub fn refused_voices(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.refused_voices)
+    }
+
+    /// The output's constant offset ahead of the DC blocker, smoothed over the last blocks.
+    pub fn dc_level(&self) -> Arc<DcLevel> {
+        Arc::clone(self.dc_meter.level())
+    }
+
+    /// Steps below full quality the engine runs at; see `QualityStep`.
+    pub fn quality_level(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.quality_level)
+    }
+
+    /// Whether quality has been lowered as far as `step`.
+    fn quality_lowered(&self, step: QualityStep) -> bool {
+        self.quality_level.load(Ordering::Relaxed) >= step.level()
+    }
+
+    /// Runs `level` steps below full quality from the next block on. The unison and voice limit
+    /// steps only change what new notes get, so nothing already sounding is cut off; the
+    /// interpolation step reaches every voice, since that is where its time goes.
+    fn set_quality(&mut self, level: usize) {
+        let level = level.min(MAX_QUALITY_LEVEL);
+        if let Some(cpu_budget) = self.cpu_budget.as_mut() {
+            cpu_budget.set_level(level);
+        }
+        self.quality_level.store(level, Ordering::Relaxed);
+        let oversampling = if self.quality_lowered(QualityStep::Oversampling) {
+            Oversampling::X1
+        } else {
+            self.oversampling
+        };
+        self.wave_shaper_node
+            .set_oversampling(oversampling, self.sample_rate);
+        let interpolation = self.interpolation();
+        if let Ok(mut note_state) = self.note_state.lock() {
+            for oscillator in note_state.oscillators.iter_mut() {
+                oscillator.set_interpolation(interpolation);
+            }
+        }
+        if let Some(ribbon_voice) = self.ribbon_voice.as_mut() {
+            ribbon_voice
+                .oscillator_mut()
+                .set_interpolation(interpolation);
+        }
+    }
+
+    /// How voices read their wavetables, dropped to the nearest entry with quality lowered
+    /// that far.
+    fn interpolation(&self) -> Interpolation {
+        if self.quality_lowered(QualityStep::Interpolation) {
+            Interpolation::Nearest
+        } else {
+            self.oscillator_config.interpolation
+        }
+    }
+
+    /// Most live voices sounding at once, lowered with the quality.
+    fn voice_limit(&self) -> Option<usize> {
+        if self.quality_lowered(QualityStep::Polyphony) {
+            let reduced = self.reduced_max_voices.max(1);
+            Some(
+                self.max_voices
+                    .map_or(reduced, |max_voices| max_voices.min(reduced)),
+            )
+        } else {
+            self.max_voices
+        }
+    }
+
+    /// Sets `param` to `value`, clamped to the parameter's range. Smoothed parameters glide
+    /// to it.
+    pub fn set_param(&mut self, param: ParamId, value: f32) -> Result<()> {
+        self.apply_param(param, value)?;
+        Ok(())
+    }
+
+    /// What `param` is set to, or `None` when the setting has no number, as with a custom
+    /// waveform. Smoothed parameters give the value they are gliding to.
+    pub fn param_value(&self, param: ParamId) -> Option<f32> {
+        let value = match param {
+            ParamId::Waveform => {
+                return waveform_to_param(
+                    *self
+                        .waveform_type
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner),
+                )
+            }
+            ParamId::OctaveShift => *self
+                .octave_shift
+                .read()
+                .unwrap_or_else(PoisonError::into_inner) as f32,
+            ParamId::TremoloEnabled => {
+                if self.tremolo_effect.enabled.load(Ordering::Relaxed) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            ParamId::TremoloRate => self.tremolo_rate.target(),
+            ParamId::TremoloDepth => self.tremolo_depth.target(),
+            ParamId::Drive => self.drive.target(),
+            ParamId::StealPolicy => steal_policy_to_param(self.steal_policy),
+            ParamId::ReferencePitch => self.reference_tone.frequency(),
+            ParamId::Attack => self.keyboard_split.lead.attack,
+            ParamId::Decay => self.keyboard_split.lead.decay,
+            ParamId::Sustain => self.keyboard_split.lead.sustain,
+            ParamId::Release => self.keyboard_split.lead.release,
+            ParamId::Quality => self.quality_level.load(Ordering::Relaxed) as f32,
+        };
+        Some(value)
+    }
+
+    /// Sets `param` as `set_param` does. Returns the value set.
+    fn apply_param(&mut self, param: ParamId, value: f32) -> Result<f32> {
+        let value = param.clamp(value)?;
+        match param {
+            ParamId::Waveform => {
+                // Taken with the note state locked, like key handling, so a block never sees
+                // the waveform change halfway through.
+                let _note_state = self.note_state.lock();
+                *self
+                    .waveform_type
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = waveform_from_param(value);
+            }
+            ParamId::OctaveShift => {
+                *self
+                    .octave_shift
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = value.round() as i32;
+            }
+            ParamId::TremoloEnabled => {
+                let enabled = value > 0.5;
+                if self.tremolo_effect.enabled.load(Ordering::Relaxed) != enabled {
+                    self.tremolo_effect.toggle();
+                }
+            }
+            ParamId::TremoloRate => self.tremolo_rate.set_target(value),
+            ParamId::TremoloDepth => self.tremolo_depth.set_target(value),
+            ParamId::Drive => self.drive.set_target(value),
+            ParamId::StealPolicy => self.steal_policy = steal_policy_from_param(value),
+            ParamId::ReferencePitch => self.reference_tone.set_frequency(value),
+            ParamId::Attack => self.keyboard_split.lead.attack = value,
+            ParamId::Decay => self.keyboard_split.lead.decay = value,
+            ParamId::Sustain => self.keyboard_split.lead.sustain = value,
+            ParamId::Release => self.keyboard_split.lead.release = value,
+            ParamId::Quality => self.set_quality(value.round() as usize),
+        }
+        trace!("Set {} to {}", param, value);
+        Ok(value)
+    }
+
+    /// Sets every value in `values`, in order, logging any that can't be set.
+    fn apply_params(&mut self, values: &[(ParamId, f32)]) {
+        for &(param, value) in values {
+            if let Err(err) = self.apply_param(param, value) {
+                warn!("{:#}", err);
+            }
+        }
+    }
+
+    /// Draws a random patch and sets each of its parameters, as `set_param` would. Returns what
+    /// it changed as one step, for an undo history to record.
+    pub fn apply_random_patch(&mut self) -> UndoStep {
+        let patch = self.patch_randomizer.next_patch();
+        if patch.values.is_empty() {
+            warn!("Every parameter is locked; the random patch changes nothing");
+        }
+        let mut changes = Vec::with_capacity(patch.values.len());
+        for &(param, value) in &patch.values {
+            let before = self.param_value(param);
+            match self.apply_param(param, value) {
+                Ok(after) => {
+                    if let Some(before) = before {
+                        changes.push(ParamChange {
+                            param,
+                            before,
+                            after,
+                        });
+                    }
+                }
+                Err(err) => warn!("Random patch: {:#}", err),
+            }
+        }
+        info!("Random {}", patch);
+        UndoStep {
+            label: format!("random patch {}", patch.number),
+            changes,
+        }
+    }
+
+    /// Tells the watchdog a device callback has arrived, with the backend's timestamp for it
+    /// when there is one.
+    pub fn callback_started(&mut self, stream_time: Option<Duration>) {
+        self.watchdog.callback_started(stream_time, Instant::now());
+    }
+
+    /// Applies `events` in order, as if they had been played before any sound was made.
+    pub fn apply_startup_events(&self, events: &[StartupEvent]) {
+        for event in events {
+            info!("Startup: {:?}", event);
+            let note_event = match event {
+                StartupEvent::SetOctave(octave) => {
+                    if let Ok(mut octave_shift) = self.octave_shift.write() {
+                        *octave_shift = (*octave).clamp(-2, 2);
+                    }
+                    continue;
+                }
+                StartupEvent::SetWaveform(waveform) => NoteEvent::ChangeWaveform(*waveform),
+                StartupEvent::ToggleTremolo => NoteEvent::ToggleTremolo,
+                StartupEvent::ChangeKey(key) => NoteEvent::ChangeKey(key.clone()),
+                StartupEvent::ToggleWaveformSequence => NoteEvent::ToggleWaveformSequence,
+            };
+            self.note_state.lock().unwrap().handle_event(
+                note_event,
+                &self.waveform_type,
+                &self.tremolo_effect,
+                &self.scale,
+            );
+        }
+    }
+
+    /// Builds the voices for `note` struck at `velocity`, with the preset of the keyboard zone
+    /// it was played from: one voice, or a detuned and panned stack of them with unison, varied
+    /// as `variation` says. They are tuned for the octave shift the other voices are at, and
+    /// are left for the caller to start. Returns no voices when the note has no playable
+    /// frequency.
+    fn build_voices(
+        &self,
+        note: &str,
+        zone: KeyZone,
+        velocity: u8,
+        waveform: OscillatorWaveform,
+        waveform_sequence: Option<&Arc<WaveformSequence>>,
+        variation: StrikeVariation,
+    ) -> Vec<Oscillator> {
+        let preset = self.keyboard_split.preset(zone);
+        let Some(frequency) = self
+            .scale
+            .lock()
+            .ok()
+            .and_then(|scale| scale.calculate_frequency(note))
+        else {
+            return Vec::new();
+        };
+        // We adjust the frequency based on the octave shift to allow the synthesizer to play
+        // notes in different octaves. This gives the user more control over the pitch range of
+        // the synthesizer.
+        let octave_shift = self.voiced_octave_shift.unwrap_or(0) + preset.octave;
+        let adjusted_frequency = frequency * 2.0f32.powf(octave_shift as f32);
+        let limited = self
+            .oscillator_config
+            .frequency_limits
+            .apply(adjusted_frequency, self.sample_rate);
+        warn_limited_frequency(note, adjusted_frequency, limited);
+        // A voice with no playable frequency would only render garbage.
+        let Some(adjusted_frequency) = limited.frequency() else {
+            self.refused_voices.fetch_add(1, Ordering::Relaxed);
+            return Vec::new();
+        };
+        let waveform = self.keyboard_split.waveform(zone, waveform);
+        let bus = self.bus_graph.route(preset.bus.as_deref());
+        let single_voice;
+        let unison = if self.quality_lowered(QualityStep::Unison) {
+            single_voice = UnisonConfig {
+                voices: 1,
+                ..self.oscillator_config.unison.clone()
+            };
+            &single_voice
+        } else {
+            &self.oscillator_config.unison
+        };
+        let voices = unison
+            .detune_offsets()
+            .into_iter()
+            .zip(unison.pans())
+            .zip(unison.phases());
+        voices
+            .enumerate()
+            .map(|(unison_voice, ((detune, pan), phase))| {
+                let mut oscillator = Oscillator::builder()
+                    .frequency(adjusted_frequency * 2.0f32.powf(detune / 1200.0))
+                    .sample_rate(self.sample_rate)
+                    .note(note.to_string())
+                    .waveform(waveform)
+                    .interpolation(self.interpolation())
+                    .phase(variation.phase + phase)
+                    .frequency_limits(self.oscillator_config.frequency_limits)
+                    .velocity(velocity)
+                    .velocity_to_attack(self.oscillator_config.velocity_to_attack)
+                    .glide_rate(self.oscillator_config.retune_rate)
+                    .attack_time(preset.attack * variation.attack)
+                    .decay_time(preset.decay)
+                    .sustain_level(preset.sustain)
+                    .release_time(preset.release)
+                    .release_scaling(self.oscillator_config.release_scaling)
+                    .drift(self.oscillator_config.drift, self.drift_seeds.next_seed())
+                    .tremolo_effect(Arc::clone(&self.tremolo_effect))
+                    .build();
+                oscillator.zone = zone;
+                oscillator.bus = bus;
+                oscillator.unison_voice = unison_voice;
+                oscillator.pan = pan;
+                oscillator.set_gain(oscillator.gain() * unison.voice_gain() * variation.gain);
+                match self.oscillator_config.stereo_blend.waveforms() {
+                    Some(waveforms) => oscillator.set_stereo_waveforms(Some(waveforms)),
+                    None => apply_waveform(&mut oscillator, waveform, waveform_sequence, true),
+                }
+                oscillator
+            })
+            .collect()
+    }
+
+    /// The current engine time in seconds.
+    pub fn current_time(&self) -> f32 {
+        self.global_time.load(Ordering::Relaxed) as f32 / self.sample_rate
+    }
+
+    /// Renders `num_frames` frames of the current note state into a new buffer, each channel
+    /// after the other.
+    pub fn render(&mut self, num_frames: usize) -> AudioBuffer {
+        let mut output_buffer = AudioBuffer {
+            data: vec![0.0; num_frames * self.num_channels],
+            num_channels: self.num_channels,
+        };
+        self.process(&mut output_buffer);
+        output_buffer
+    }
+
+    /// Renders the synth like `process` and mixes `backing` in with it, one ducking under the
+    /// other as the ducking config says. `backing` must be laid out like `output_buffer`.
+    pub fn process_with_backing(&mut self, backing: &AudioBuffer, output_buffer: &mut AudioBuffer) {
+        self.process_voices(output_buffer);
+        let synth_buffer = output_buffer.clone();
+        self.ducking_mixer
+            .mix(&synth_buffer, backing, output_buffer);
+        self.process_master(output_buffer);
+    }
+
+    /// Fills `output_buffer` with the mix of all playing voices, passed through the wave shaper,
+    /// the DC blocker and the mute.
+    pub fn process(&mut self, output_buffer: &mut AudioBuffer) {
+        self.process_voices(output_buffer);
+        self.process_master(output_buffer);
+    }
+
+    /// The end of the chain, once everything heard is mixed into `output_buffer`: the reference
+    /// tone routed after the effects, then the DC blocker, the limiter and the mute.
+    fn process_master(&mut self, output_buffer: &mut AudioBuffer) {
+        self.reference_tone.mix_into(
+            ReferenceRoute::PostEffects,
+            self.reference_tone_on,
+            output_buffer,
+        );
+        self.dc_meter.measure(output_buffer, Instant::now());
+        if let Some(dc_blocker) = self.dc_blocker.as_mut() {
+            let input = output_buffer.clone();
+            dc_blocker.process(&input, output_buffer);
+        }
+        if let Some(limiter) = self.limiter.as_mut() {
+            let input = output_buffer.clone();
+            limiter.process(&input, output_buffer);
+        }
+        self.mute_gain.process(self.muted, output_buffer);
+    }
+
+    /// Fills `output_buffer` with the mix of all playing voices, passed through the wave shaper
+    /// unless it is bypassed. A reference tone routed before the effects is mixed in with the
+    /// voices; one routed after them is left to the caller.
+    fn process_voices(&mut self, output_buffer: &mut AudioBuffer) {
+        let started = Instant::now();
+        let mut voice_count = 0;
+        let mut loudest_velocity = None;
+        output_buffer
+            .data
+            .iter_mut()
+            .for_each(|sample| *sample = 0.0);
+        let sample_rate = self.sample_rate;
+        let num_frames = output_buffer.num_frames();
+        let direct = self.bus_graph.is_direct();
+        if !direct {
+            self.bus_graph.begin_block(output_buffer);
+        }
+
+        // The tremolo is handed its rate and depth once a block as they glide to a new setting.
+        if !self.tremolo_rate.is_settled() {
+            let rate = self.tremolo_rate.next_block(num_frames).last();
+            self.tremolo_effect.set_rate(rate);
+        }
+        if !self.tremolo_depth.is_settled() {
+            let depth = self.tremolo_depth.next_block(num_frames).last();
+            self.tremolo_effect.set_depth(depth);
+        }
+
+        let mut random_patch = false;
+        let mut param_requests = Vec::new();
+        if let Ok(mut note_state) = self.note_state.lock() {
+            let note_state = &mut *note_state;
+            self.muted = note_state.muted;
+            self.wave_shaper_bypassed = note_state.wave_shaper_bypassed;
+            self.reference_tone_on = note_state.reference_tone;
+            random_patch = std::mem::take(&mut note_state.random_patch_requested);
+            param_requests.append(&mut note_state.param_requests);
+            // The tap is read once a block too, so it switches between blocks, never inside one.
+            self.visual_tap = note_state.visual_tap;
+            // An envelope edited on screen shapes the lead notes started from this block on.
+            if let Some(envelope) = note_state.envelope_edit.take() {
+                self.keyboard_split.lead.set_envelope(envelope);
+            }
+            // The waveform is read once per block, while the note state is locked, so voices
+            // started this block and those already playing always agree on it. Key handling
+            // changes it with the note state locked too, so it can't change halfway through.
+            let waveform = *self
+                .waveform_type
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Ok(octave_shift) = self.octave_shift.read() {
+                // Voices already sounding when the octave shift changes glide to their new
+                // pitch rather than jumping there.
+                let retune = *octave_shift - *self.voiced_octave_shift.get_or_insert(*octave_shift);
+                if retune != 0 {
+                    for oscillator in note_state.oscillators.iter_mut() {
+                        let frequency = oscillator.target_frequency() * 2.0f32.powf(retune as f32);
+                        oscillator.glide_to(frequency);
+                    }
+                }
+                self.voiced_octave_shift = Some(*octave_shift);
+
+                let playing_notes: Vec<(NoteId, usize)> =
+                    note_state.playing_notes.clone().into_iter().collect();
+
+                let current_sample = self
+                    .global_time
+                    .fetch_add(num_frames as u64, Ordering::Relaxed);
+
+                note_state
+                    .performance_log
+                    .advance(current_sample + num_frames as u64, sample_rate);
+
+                let waveform_sequence = self
+                    .waveform_sequence
+                    .as_ref()
+                    .filter(|_| note_state.waveform_sequence_enabled);
+                let apply_to_playing = self.waveform_sequence_config.apply_to_playing;
+
+                // The looper's queued commands take effect at the start of the block, before
+                // anything played in it is recorded. Its notes land on their exact samples.
+                let loop_events = note_state
+                    .looper
+                    .advance(current_sample, current_sample + num_frames as u64);
+
+                // Voices whose note is no longer held are released rather than dropped, so
+                // their envelope can fade out. They are removed once the release has finished.
+                // Loop voices are left to the looper.
+                for oscillator in note_state.oscillators.iter_mut() {
+                    if oscillator.looped {
+                        continue;
+                    }
+                    let held = playing_notes
+                        .iter()
+                        .any(|(id, holds)| *holds > 0 && oscillator.plays(id));
+                    if !held && !oscillator.is_released() {
+                        oscillator.release(current_sample);
+                        let id = oscillator.note_id();
+                        note_state.performance_log.note_off(current_sample, &id);
+                        note_state.looper.record(current_sample, &id, false);
+                    }
+                }
+
+                // We iterate over the playing notes to check if any new notes have been
+                // pressed. If a new note is detected and it's not already being played by an
+                // existing oscillator, we create a new oscillator for that note. this allows
+                // multiple oscillators to be played simultaneously, enabling polyphony in the
+                // synthesizer. A voice still fading out after release doesn't count, so a
+                // quickly repeated note starts a fresh voice. Past the voice limit, new notes
+                // take over a voice picked by the steal policy, or wait until one frees up.
+                'notes: for (id, holds) in playing_notes.iter() {
+                    let note = &id.note;
+                    // A note whose voice was stolen earlier in the block is let go, and
+                    // mustn't start again.
+                    let still_playing = note_state
+                        .playing_notes
+                        .get(id)
+                        .is_some_and(|&count| count > 0);
+                    if *holds > 0
+                        && still_playing
+                        && !note_state
+                            .oscillators
+                            .iter()
+                            .any(|osc| !osc.looped && osc.plays(id) && !osc.is_released())
+                    {
+                        if let Some(max_voices) = self.voice_limit() {
+                            // With a steal policy, voices fading out keep their slot until they
+                            // finish, so `ReleasedFirst` has them to choose from.
+                            let stealing = self.steal_policy != StealPolicy::Off;
+                            while note_state
+                                .oscillators
+                                .iter()
+                                .filter(|osc| {
+                                    !osc.looped
+                                        && (stealing || !osc.is_released())
+                                        && osc.unison_voice == 0
+                                })
+                                .count()
+                                >= max_voices
+                            {
+                                if !steal_voice(
+                                    note_state,
+                                    self.steal_policy,
+                                    self.steal_fallback,
+                                    current_sample,
+                                ) {
+                                    log_rate_limited!(
+                                        debug,
+                                        HOT_PATH_LOG_INTERVAL,
+                                        "Voice limit reached, holding back {}",
+                                        note
+                                    );
+                                    continue 'notes;
+                                }
+                            }
+                        }
+                        let velocity = note_state
+                            .note_velocities
+                            .get(id)
+                            .copied()
+                            .unwrap_or(DEFAULT_VELOCITY);
+                        let zone = note_state.note_zones.get(id).copied().unwrap_or_default();
+                        let variation = self
+                            .note_variation
+                            .strike(note, sounding_phase(&note_state.oscillators, note));
+                        let oscillators = self.build_voices(
+                            note,
+                            zone,
+                            velocity,
+                            waveform,
+                            waveform_sequence,
+                            variation,
+                        );
+                        // The middle of the stack is the undetuned voice, whose pitch is the
+                        // note's.
+                        let Some(frequency) = oscillators
+                            .get(oscillators.len() / 2)
+                            .map(|osc| osc.get_frequency())
+                        else {
+                            continue;
+                        };
+                        for mut oscillator in oscillators {
+                            oscillator.start(current_sample);
+                            oscillator.source = id.source.clone();
+                            note_state.add_oscillator(oscillator);
+                        }
+                        note_state.looper.record(current_sample, id, true);
+
+                        if let Some(key) = frequency_to_midi_note(frequency)
+                            .and_then(|key| u8::try_from(key).ok())
+                            .filter(|key| *key < 128)
+                        {
+                            note_state
+                                .performance_log
+                                .note_on(current_sample, id, key, velocity);
+                        }
+                    }
+                }
+
+                // Loop notes get voices of their own, within a budget of their own so a busy
+                // loop can't crowd out live playing. A loop that stops lets its notes go.
+                let max_loop_voices = note_state.looper.config().max_voices;
+                for (time, note, is_on) in loop_events {
+                    if is_on {
+                        let loop_voices = note_state
+                            .oscillators
+                            .iter()
+                            .filter(|osc| osc.looped && !osc.is_released() && osc.unison_voice == 0)
+                            .count();
+                        if loop_voices >= max_loop_voices {
+                            log_rate_limited!(
+                                debug,
+                                HOT_PATH_LOG_INTERVAL,
+                                "Loop voice budget full, skipping {}",
+                                note
+                            );
+                            continue;
+                        }
+                        let variation = self
+                            .note_variation
+                            .strike(&note, sounding_phase(&note_state.oscillators, &note));
+                        for mut oscillator in self.build_voices(
+                            &note,
+                            KeyZone::Lead,
+                            DEFAULT_VELOCITY,
+                            waveform,
+                            waveform_sequence,
+                            variation,
+                        ) {
+                            oscillator.start(time);
+                            oscillator.looped = true;
+                            note_state.add_oscillator(oscillator);
+                        }
+                    } else {
+                        // Every voice of the note's unison stack is let go together.
+                        let mut released = vec![false; self.oscillator_config.unison.voices.max(1)];
+                        for oscillator in note_state.oscillators.iter_mut() {
+                            if oscillator.looped
+                                && oscillator.note == note
+                                && !oscillator.is_released()
+                            {
+                                let Some(done) = released.get_mut(oscillator.unison_voice) else {
+                                    continue;
+                                };
+                                if !*done {
+                                    *done = true;
+                                    oscillator.release(time);
+                                }
+                            }
+                        }
+                    }
+                }
+                if !matches!(note_state.looper.state(), LooperState::Playing { .. }) {
+                    for oscillator in note_state.oscillators.iter_mut() {
+                        if oscillator.looped {
+                            oscillator.release(current_sample);
+                        }
+                    }
+                }
+
+                voice_count = note_state.oscillators.len();
+                self.polyphony_monitor.check(voice_count, started);
+                loudest_velocity = note_state
+                    .oscillators
+                    .iter()
+                    .filter(|osc| !osc.is_released())
+                    .map(|osc| osc.velocity)
+                    .max();
+
+                // We update the waveform of each oscillator if the global waveform type has
+                // changed. This allows the user to switch between different waveforms (e.g.,
+                // sine, square, sawtooth) in real-time, providing variety in the timbre of the
+                // synthesized sound. Voices following the waveform sequence are left to it, and
+                // voices blending two waveforms keep theirs.
+                for oscillator in note_state.oscillators.iter_mut() {
+                    if oscillator.stereo_waveforms().is_none() {
+                        let waveform = self.keyboard_split.waveform(oscillator.zone, waveform);
+                        apply_waveform(oscillator, waveform, waveform_sequence, apply_to_playing);
+                    }
+
+                    // We generate the waveform samples for each oscillator and accummulate
+                    // them in the output buffer. This is done to mix the contributions of all
+                    // active oscillators and create the final synthesized sound. Each voice is
+                    // scaled by its own gain, which keeps the mix from clipping and lets
+                    // layered voices be balanced against each other. A unison voice is panned
+                    // across the channels when the output is stereo, and so is each waveform of
+                    // a stereo blend. Voices routed to a bus of their own are mixed there, and
+                    // the buses into the output once all are in.
+                    let gain = oscillator.gain();
+                    let (generated_samples, blend_samples) =
+                        oscillator.generate_layers(current_sample, num_frames);
+                    let bus_buffer = if direct {
+                        &mut *output_buffer
+                    } else {
+                        self.bus_graph.buffer_mut(oscillator.bus)
+                    };
+                    // Each waveform's left and right gains on a stereo output, and its gain on
+                    // every channel of any other. Kept in arrays, as this runs for every voice
+                    // in every block.
+                    let (stereo_gains, mono_gain) = match &blend_samples {
+                        None => ([pan_gains(oscillator.pan), [0.0; 2]], 1.0),
+                        // Without a stereo field the two are mixed evenly.
+                        Some(_) => (
+                            self.oscillator_config
+                                .stereo_blend
+                                .layer_gains(oscillator.pan),
+                            0.5,
+                        ),
+                    };
+                    let channels = bus_buffer.num_channels();
+                    let layers = [Some(&generated_samples), blend_samples.as_ref()];
+                    for (layer_samples, stereo_gains) in layers.into_iter().zip(stereo_gains) {
+                        let Some(layer_samples) = layer_samples else {
+                            continue;
+                        };
+                        let mut mix = |channel: usize, pan_gain: f32| {
+                            let channel_gain = gain * pan_gain;
+                            for (sample, generated) in bus_buffer
+                                .channel_mut(channel)
+                                .iter_mut()
+                                .zip(layer_samples)
+                            {
+                                *sample += generated * channel_gain;
+                            }
+                        };
+                        if channels == 2 {
+                            for (channel, &pan_gain) in stereo_gains.iter().enumerate() {
+                                mix(channel, pan_gain);
+                            }
+                        } else {
+                            for channel in 0..channels {
+                                mix(channel, mono_gain);
+                            }
+                        }
+                    }
+                    // A blended voice is silent once both of its waveforms are, so the left
+                    // samples, already mixed, are reused for the louder of the two.
+                    let mut loudest_samples = generated_samples;
+                    if let Some(blend_samples) = &blend_samples {
+                        for (left, right) in loudest_samples.iter_mut().zip(blend_samples) {
+                            *left = left.abs().max(right.abs());
+                        }
+                    }
+                    oscillator
+                        .track_silence(&loudest_samples, self.oscillator_config.silence_threshold);
+                }
+                // Finished voices report where they left off, for the note's next strike to
+                // carry on from.
+                for oscillator in note_state.oscillators.iter() {
+                    if oscillator.is_finished() && oscillator.unison_voice == 0 {
+                        self.note_variation
+                            .voice_finished(&oscillator.note, oscillator.get_phase());
+                    }
+                }
+                note_state.oscillators.retain(|osc| !osc.is_finished());
+
+                // The single-cycle display lines up on the lowest voice still sounding.
+                self.cycle_reference = if self.visual_tap == VisualTap::SingleCycle {
+                    note_state
+                        .oscillators
+                        .iter()
+                        .min_by(|a, b| a.get_frequency().total_cmp(&b.get_frequency()))
+                        .map(|oscillator| CycleReference {
+                            frequency: oscillator.get_frequency(),
+                            phase: oscillator.get_phase(),
+                        })
+                } else {
+                    None
+                };
+
+                // Voices that have gone quiet are dropped too, even if their note is still
+                // marked as held, so a stuck note doesn't keep costing CPU. The note is marked
+                // released as well, or the next block would start it again.
+                if self.oscillator_config.silence_timeout > 0.0 {
+                    let timeout_samples =
+                        (self.oscillator_config.silence_timeout * sample_rate) as u64;
+                    let (silent, sounding): (Vec<Oscillator>, Vec<Oscillator>) = note_state
+                        .oscillators
+                        .drain(..)
+                        .partition(|osc| osc.silent_samples() >= timeout_samples);
+                    note_state.oscillators = sounding;
+                    for oscillator in silent {
+                        debug!("Dropping silent voice {}", oscillator.note);
+                        if oscillator.unison_voice == 0 {
+                            self.note_variation
+                                .voice_finished(&oscillator.note, oscillator.get_phase());
+                        }
+                        if !oscillator.is_released() && !oscillator.looped {
+                            let id = oscillator.note_id();
+                            note_state.release_note(&id);
+                            note_state.performance_log.note_off(current_sample, &id);
+                        }
+                    }
+                }
+
+                // The ribbon plays a mono voice of its own that glides between touch positions
+                // and is dropped as soon as the strip is released.
+                match note_state.ribbon_touch {
+                    Some(normalized_x) => {
+                        let target_frequency = self.scale.lock().ok().and_then(|scale| {
+                            let root_frequency = scale.calculate_frequency(&scale.root_note)?
+                                * 2.0f32.powf(*octave_shift as f32);
+                            Some(ribbon_frequency(
+                                normalized_x,
+                                root_frequency,
+                                self.ribbon_config.range_octaves,
+                                self.ribbon_config.quantize.then_some(&*scale),
+                            ))
+                        });
+
+                        let is_new_voice = self.ribbon_voice.is_none();
+                        let target_frequency = target_frequency.filter(|frequency| {
+                            let valid = frequency.is_finite();
+                            if !valid {
+                                log_rate_limited!(
+                                    warn,
+                                    HOT_PATH_LOG_INTERVAL,
+                                    "The ribbon asked for {} Hz, ignoring it",
+                                    frequency
+                                );
+                                if is_new_voice {
+                                    self.refused_voices.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                            valid
+                        });
+                        if let Some(target_frequency) = target_frequency {
+                            let interpolation = self.interpolation();
+                            let ribbon_voice = self.ribbon_voice.get_or_insert_with(|| {
+                                let mut ribbon_voice = RibbonVoice::new(
+                                    target_frequency,
+                                    sample_rate,
+                                    waveform,
+                                    Arc::clone(&self.tremolo_effect),
+                                    current_sample,
+                                );
+                                ribbon_voice
+                                    .oscillator_mut()
+                                    .set_interpolation(interpolation);
+                                ribbon_voice
+                                    .oscillator_mut()
+                                    .set_frequency_limits(self.oscillator_config.frequency_limits);
+                                ribbon_voice.oscillator_mut().bus = self.ribbon_bus;
+                                ribbon_voice
+                            });
+                            ribbon_voice.set_target_frequency(target_frequency);
+                            apply_waveform(
+                                ribbon_voice.oscillator_mut(),
+                                waveform,
+                                waveform_sequence,
+                                is_new_voice || apply_to_playing,
+                            );
+
+                            let gain = ribbon_voice.oscillator().gain();
+                            let glide_scale = self
+                                .ribbon_config
+                                .quantize_glide
+                                .then(|| self.scale.lock().ok())
+                                .flatten();
+                            let generated_samples = ribbon_voice.generate_block(
+                                current_sample,
+                                num_frames,
+                                self.ribbon_config.glide_time,
+                                sample_rate,
+                                glide_scale.as_deref(),
+                            );
+                            let bus_buffer = if direct {
+                                &mut *output_buffer
+                            } else {
+                                self.bus_graph.buffer_mut(ribbon_voice.oscillator().bus)
+                            };
+                            for channel in 0..bus_buffer.num_channels() {
+                                for (sample, generated) in bus_buffer
+                                    .channel_mut(channel)
+                                    .iter_mut()
+                                    .zip(&generated_samples)
+                                {
+                                    *sample += generated * gain;
+                                }
+                            }
+                        }
+                    }
+                    None => self.ribbon_voice = None,
+                }
+            }
+        }
+
+        if !direct {
+            self.bus_graph.mix_into(output_buffer);
+        }
+
+        // Setting the waveform takes the note state lock, so `SetParam` events and the patch
+        // wait until it is let go; their waveform and tremolo are heard from the next block.
+        self.apply_params(&param_requests);
+        if random_patch {
+            let patch = self.apply_random_patch();
+            if !patch.changes.is_empty() {
+                if let Ok(mut note_state) = self.note_state.lock() {
+                    note_state.random_patches.push(patch);
+                }
+            }
+        }
+
+        self.reference_tone.mix_into(
+            ReferenceRoute::PreEffects,
+            self.reference_tone_on,
+            output_buffer,
+        );
+
+        // The visualizer's pre-effects tap shows the mix as the voices made it.
+        if self.visual_tap.uses_pre_effects() {
+            self.pre_effects_buffer.num_channels = output_buffer.num_channels;
+            self.pre_effects_buffer.data.clear();
+            self.pre_effects_buffer
+                .data
+                .extend_from_slice(&output_buffer.data);
+        }
+
+        // We apply the wave shaper effect to the output buffer to introduce distortion and
+        // enhance the harmonic content of the synthesized sound. This is done to make the
+        // sound more interesting and expressive. Bypassed, the mix is left as the voices made it.
+        // The drive glides to a new setting a block at a time, since the shaper works out its
+        // makeup gain whenever the drive changes.
+        let base_drive = self.drive.next_block(num_frames).last();
+        if !self.wave_shaper_bypassed {
+            let dry_buffer = output_buffer.clone();
+            let drive = self
+                .drive_modulation
+                .next_drive(base_drive, loudest_velocity, &dry_buffer)
+                .unwrap_or(base_drive);
+            let wave_shaper = self.wave_shaper_node.node_mut();
+            if (wave_shaper.drive() - drive).abs() > 1e-4 {
+                wave_shaper.set_drive(drive);
+            }
+            self.wave_shaper_node.process(&dry_buffer, output_buffer);
+        }
+
+        let tremolo = self.tremolo_effect.enabled.load(Ordering::Relaxed);
+        let effects: &[&str] = match (tremolo, self.wave_shaper_bypassed) {
+            (true, false) => &["tremolo", "wave shaper"],
+            (true, true) => &["tremolo"],
+            (false, false) => &["wave shaper"],
+            (false, true) => &[],
+        };
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(started);
+        self.watchdog
+            .record(elapsed, num_frames, sample_rate, voice_count, effects, now);
+
+        // The quality changes between blocks, never inside one.
+        if num_frames > 0 {
+            let load = elapsed.as_secs_f32() * sample_rate / num_frames as f32;
+            if let Some(level) = self
+                .cpu_budget
+                .as_mut()
+                .and_then(|cpu_budget| cpu_budget.record(load))
+            {
+                self.set_quality(level);
+            }
+        }
+    }
+}
+
+pub struct SynthEngineBuilder {
+    num_channels: usize,
+    waveform_type: Option<Arc<RwLock<OscillatorWaveform>>>,
+    note_state: Option<Arc<Mutex<NoteState>>>,
+    octave_shift: Option<Arc<RwLock<i32>>>,
+    global_time: Option<Arc<AtomicU64>>,
+    tremolo_effect: Option<Arc<TremoloEffect>>,
+    scale: Option<Arc<Mutex<Scale>>>,
+    oscillator_config: OscillatorConfig,
+    ribbon_config: RibbonConfig,
+    diagnostics_config: DiagnosticsConfig,
+    waveform_sequence_config: WaveformSequenceConfig,
+    wave_shaper_config: WaveShaperConfig,
+    ducking_config: DuckingConfig,
+    looper_config: LooperConfig,
+    mute_config: MuteConfig,
+    effects_config: EffectsConfig,
+    reference_tone_config: ReferenceToneConfig,
+    randomizer_config: RandomizerConfig,
+    dc_blocker_config: DcBlockerConfig,
+    limiter_config: LimiterConfig,
+    cpu_budget_config: CpuBudgetConfig,
+    quality_level: Option<Arc<AtomicUsize>>,
+    buses: Vec<BusConfig>,
+    keyboard_split: KeyboardSplitConfig,
+    max_voices: Option<usize>,
+}
+
+impl Default for SynthEngineBuilder {
+    fn default() -> Self {
+        SynthEngineBuilder {
+            num_channels: 1,
+            waveform_type: None,
+            note_state: None,
+            octave_shift: None,
+            global_time: None,
+            tremolo_effect: None,
+            scale: None,
+            oscillator_config: OscillatorConfig::default(),
+            ribbon_config: RibbonConfig::default(),
+            diagnostics_config: DiagnosticsConfig::default(),
+            waveform_sequence_config: WaveformSequenceConfig::default(),
+            wave_shaper_config: WaveShaperConfig::default(),
+            ducking_config: DuckingConfig::default(),
+            looper_config: LooperConfig::default(),
+            mute_config: MuteConfig::default(),
+            effects_config: EffectsConfig::default(),
+            reference_tone_config: ReferenceToneConfig::default(),
+            randomizer_config: RandomizerConfig::default(),
+            dc_blocker_config: DcBlockerConfig::default(),
+            limiter_config: LimiterConfig::default(),
+            cpu_budget_config: CpuBudgetConfig::default(),
+            quality_level: None,
+            buses: Vec::new(),
+            keyboard_split: KeyboardSplitConfig::default(),
+            max_voices: None,
+        }
+    }
+}
+
+impl SynthEngineBuilder {
+    /// Builds the engine, creating fresh state for anything that wasn't shared in.
+    pub fn build(self, sample_rate: f32) -> SynthEngine {
+        let note_state = self
+            .note_state
+            .unwrap_or_else(|| Arc::new(Mutex::new(NoteState::new())));
+        if let Ok(mut note_state) = note_state.lock() {
+            note_state.looper = Looper::new(self.looper_config, sample_rate);
+        }
+        let voice_stealing = self.oscillator_config.voice_stealing.clone();
+        let tremolo_effect = self.tremolo_effect.unwrap_or_else(|| {
+            Arc::new(
+                TremoloEffect::builder()
+                    .rate(self.effects_config.tremolo_rate)
+                    .depth(self.effects_config.tremolo_depth)
+                    .enabled(false)
+                    .build(sample_rate),
+            )
+        });
+        // The tremolo may be shared in already set up, so its smoothed settings start from
+        // wherever it is.
+        let tremolo_rate = SmoothedParam::new(
+            tremolo_effect.get_rate(),
+            ParamId::TremoloRate.smoothing(),
+            sample_rate,
+        );
+        let tremolo_depth = SmoothedParam::new(
+            tremolo_effect.get_depth(),
+            ParamId::TremoloDepth.smoothing(),
+            sample_rate,
+        );
+        let bus_graph = BusGraph::new(&self.buses, self.num_channels);
+        let routes = [
+            ("bass zone", &self.keyboard_split.bass.bus),
+            ("lead zone", &self.keyboard_split.lead.bus),
+            ("ribbon", &self.ribbon_config.bus),
+        ];
+        for (source, bus) in routes {
+            if let Some(bus) = bus.as_deref().filter(|bus| bus_graph.id(bus).is_none()) {
+                warn!(
+                    "The {} is routed to unknown bus {:?}, using the main bus",
+                    source, bus
+                );
+            }
+        }
+        let ribbon_bus = bus_graph.route(self.ribbon_config.bus.as_deref());
+
+        SynthEngine {
+            sample_rate,
+            num_channels: self.num_channels,
+            waveform_type: self
+                .waveform_type
+                .unwrap_or_else(|| Arc::new(RwLock::new(OscillatorWaveform::Sine))),
+            note_state,
+            octave_shift: self
+                .octave_shift
+                .unwrap_or_else(|| Arc::new(RwLock::new(0))),
+            global_time: self
+                .global_time
+                .unwrap_or_else(|| Arc::new(AtomicU64::new(0))),
+            tremolo_effect,
+            scale: self.scale.unwrap_or_else(|| {
+                Arc::new(Mutex::new(Scale {
+                    root_note: "C".to_string(),
+                    intervals: vec![2, 2, 1, 2, 2, 2, 1],
+                }))
+            }),
+            // We create a wave shaper node with the configured transfer function to apply
+            // distortion to the audio output. This adds character and richness to the sound.
+            wave_shaper_node: {
+                let mut wave_shaper_node =
+                    WaveShaperNode::new(self.effects_config.shaper_curve.transfer_fn());
+                wave_shaper_node.set_drive(self.wave_shaper_config.drive);
+                OversampledNode::new(
+                    wave_shaper_node,
+                    self.wave_shaper_config.oversampling,
+                    sample_rate,
+                )
+            },
+            drive: SmoothedParam::new(
+                self.wave_shaper_config.drive,
+                ParamId::Drive.smoothing(),
+                sample_rate,
+            ),
+            tremolo_rate,
+            tremolo_depth,
+            drive_modulation: DriveModulation::new(&self.wave_shaper_config, sample_rate),
+            ducking_mixer: DuckingMixer::new(self.ducking_config, sample_rate),
+            note_variation: NoteVariation::new(self.oscillator_config.variation.clone()),
+            drift_seeds: DriftSeeds::new(&self.oscillator_config.drift),
+            oscillator_config: self.oscillator_config,
+            ribbon_config: self.ribbon_config,
+            ribbon_voice: None,
+            waveform_sequence: WaveformSequence::new(&self.waveform_sequence_config, sample_rate)
+                .map(Arc::new),
+            waveform_sequence_config: self.waveform_sequence_config,
+            polyphony_monitor: PolyphonyMonitor::new(&self.diagnostics_config),
+            watchdog: CallbackWatchdog::default(),
+            refused_voices: Arc::default(),
+            max_voices: self.max_voices.or(voice_stealing.max_voices),
+            steal_policy: voice_stealing.steal_policy,
+            steal_fallback: voice_stealing.fallback,
+            mute_gain: MuteGain::new(self.mute_config, sample_rate)
+                .with_volume(self.effects_config.master_volume),
+            muted: false,
+            wave_shaper_bypassed: false,
+            reference_tone: ReferenceTone::new(self.reference_tone_config, sample_rate),
+            reference_tone_on: false,
+            patch_randomizer: PatchRandomizer::new(&self.randomizer_config),
+            dc_blocker: self
+                .dc_blocker_config
+                .enabled
+                .then(|| DcBlockerNode::new(self.dc_blocker_config.cutoff, sample_rate)),
+            dc_meter: DcMeter::new(&self.diagnostics_config, sample_rate),
+            limiter: self
+                .limiter_config
+                .enabled
+                .then(|| LimiterNode::new(self.limiter_config, sample_rate)),
+            bus_graph,
+            ribbon_bus,
+            cpu_budget: self
+                .cpu_budget_config
+                .enabled
+                .then(|| CpuBudgetController::new(self.cpu_budget_config.clone())),
+            quality_level: self.quality_level.unwrap_or_default(),
+            oversampling: self.wave_shaper_config.oversampling,
+            reduced_max_voices: self.cpu_budget_config.reduced_max_voices,
+            visual_tap: VisualTap::default(),
+            cycle_reference: None,
+            pre_effects_buffer: AudioBuffer {
+                data: Vec::new(),
+                num_channels: self.num_channels,
+            },
+            voiced_octave_shift: None,
+            keyboard_split: self.keyboard_split,
+        }
+    }
+
+    pub fn num_channels(mut self, num_channels: usize) -> Self {
+        self.num_channels = num_channels;
+        self
+    }
+
+    pub fn waveform_type(mut self, waveform_type: Arc<RwLock<OscillatorWaveform>>) -> Self {
+        self.waveform_type = Some(waveform_type);
+        self
+    }
+
+    pub fn note_state(mut self, note_state: Arc<Mutex<NoteState>>) -> Self {
+        self.note_state = Some(note_state);
+        self
+    }
+
+    pub fn octave_shift(mut self, octave_shift: Arc<RwLock<i32>>) -> Self {
+        self.octave_shift = Some(octave_shift);
+        self
+    }
+
+    pub fn global_time(mut self, global_time: Arc<AtomicU64>) -> Self {
+        self.global_time = Some(global_time);
+        self
+    }
+
+    pub fn tremolo_effect(mut self, tremolo_effect: Arc<TremoloEffect>) -> Self {
+        self.tremolo_effect = Some(tremolo_effect);
+        self
+    }
+
+    pub fn scale(mut self, scale: Arc<Mutex<Scale>>) -> Self {
+        self.scale = Some(scale);
+        self
+    }
+
+    pub fn oscillator_config(mut self, oscillator_config: OscillatorConfig) -> Self {
+        self.oscillator_config = oscillator_config;
+        self
+    }
+
+    pub fn ribbon_config(mut self, ribbon_config: RibbonConfig) -> Self {
+        self.ribbon_config = ribbon_config;
+        self
+    }
+
+    pub fn diagnostics_config(mut self, diagnostics_config: DiagnosticsConfig) -> Self {
+        self.diagnostics_config = diagnostics_config;
+        self
+    }
+
+    pub fn waveform_sequence_config(
+        mut self,
+        waveform_sequence_config: WaveformSequenceConfig,
+    ) -> Self {
+        self.waveform_sequence_config = waveform_sequence_config;
+        self
+    }
+
+    pub fn wave_shaper_config(mut self, wave_shaper_config: WaveShaperConfig) -> Self {
+        self.wave_shaper_config = wave_shaper_config;
+        self
+    }
+
+    pub fn ducking_config(mut self, ducking_config: DuckingConfig) -> Self {
+        self.ducking_config = ducking_config;
+        self
+    }
+
+    pub fn looper_config(mut self, looper_config: LooperConfig) -> Self {
+        self.looper_config = looper_config;
+        self
+    }
+
+    pub fn mute_config(mut self, mute_config: MuteConfig) -> Self {
+        self.mute_config = mute_config;
+        self
+    }
+
+    /// Startup effect settings: the wave shaper's curve, the master volume, and the tremolo's
+    /// rate and depth when no tremolo is shared in.
+    pub fn effects_config(mut self, effects_config: EffectsConfig) -> Self {
+        self.effects_config = effects_config;
+        self
+    }
+
+    pub fn reference_tone_config(mut self, reference_tone_config: ReferenceToneConfig) -> Self {
+        self.reference_tone_config = reference_tone_config;
+        self
+    }
+
+    pub fn randomizer_config(mut self, randomizer_config: RandomizerConfig) -> Self {
+        self.randomizer_config = randomizer_config;
+        self
+    }
+
+    pub fn dc_blocker_config(mut self, dc_blocker_config: DcBlockerConfig) -> Self {
+        self.dc_blocker_config = dc_blocker_config;
+        self
+    }
+
+    pub fn limiter_config(mut self, limiter_config: LimiterConfig) -> Self {
+        self.limiter_config = limiter_config;
+        self
+    }
+
+    pub fn cpu_budget_config(mut self, cpu_budget_config: CpuBudgetConfig) -> Self {
+        self.cpu_budget_config = cpu_budget_config;
+        self
+    }
+
+    /// Where the engine keeps how far below full quality it runs, for showing elsewhere.
+    pub fn quality_level(mut self, quality_level: Arc<AtomicUsize>) -> Self {
+        self.quality_level = Some(quality_level);
+        self
+    }
+
+    /// Buses the zones and the ribbon can be routed to, besides the main bus.
+    pub fn buses(mut self, buses: Vec<BusConfig>) -> Self {
+        self.buses = buses;
+        self
+    }
+
+    /// Presets for the bass and lead halves of the keyboard.
+    pub fn keyboard_split(mut self, keyboard_split: KeyboardSplitConfig) -> Self {
+        self.keyboard_split = keyboard_split;
+        self
+    }
+
+    /// Most live voices sounding at once, overriding the oscillator config's limit. Unlimited by
+    /// default.
+    pub fn max_voices(mut self, max_voices: usize) -> Self {
+        self.max_voices = Some(max_voices);
+        self
+    }
+}
+
+/// Takes a voice away for a new note by `policy`, dropping the victim's whole unison stack. A
+/// victim whose note is still held has its note let go, so it doesn't start again while its key
+/// stays down. False when the policy steals nothing.
+fn steal_voice(
+    note_state: &mut NoteState,
+    policy: StealPolicy,
+    fallback: StealPolicy,
+    current_sample: u64,
+) -> bool {
+    let leaders: Vec<&Oscillator> = note_state
+        .oscillators
+        .iter()
+        .filter(|osc| !osc.looped && osc.unison_voice == 0)
+        .collect();
+    let candidates: Vec<VoiceCandidate> = leaders
+        .iter()
+        .map(|osc| {
+            let info = osc.voice_debug_info();
+            VoiceCandidate {
+                amplitude: info.amplitude,
+                age_samples: info.age_samples,
+                released: osc.is_released(),
+                frequency: info.freq,
+            }
+        })
+        .collect();
+    let Some(victim) = select_victim(policy, fallback, &candidates) else {
+        return false;
+    };
+    let id = leaders[victim].note_id();
+    let start_sample = leaders[victim].start_sample();
+    let released = candidates[victim].released;
+
+    note_state
+        .oscillators
+        .retain(|osc| osc.looped || !osc.plays(&id) || osc.start_sample() != start_sample);
+    debug!(
+        "Stole the {} voice of {} ({})",
+        if released { "released" } else { "held" },
+        id.note,
+        policy.label()
+    );
+    if !released {
+        note_state.release_note(&id);
+        note_state.performance_log.note_off(current_sample, &id);
+        note_state.looper.record(current_sample, &id, false);
+    }
+    true
+}
+
+/// Phase of the most recently struck voice of `note` that is still sounding, for a new strike
+/// to continue from.
+fn sounding_phase(oscillators: &[Oscillator], note: &str) -> Option<f32> {
+    oscillators
+        .iter()
+        .rev()
+        .find(|osc| osc.note == note && osc.unison_voice == 0)
+        .map(|osc| osc.get_phase())
+}
+
+/// Points `oscillator` at the running waveform sequence, or at the global `waveform` when the
+/// sequence is off.
+///
+/// Voices only join a running sequence when `join_sequence` is set, which is always the case
+/// for new voices and for playing ones when the sequence is configured to take them over.
+fn apply_waveform(
+    oscillator: &mut Oscillator,
+    waveform: OscillatorWaveform,
+    waveform_sequence: Option<&Arc<WaveformSequence>>,
+    join_sequence: bool,
+) {
+    match waveform_sequence {
+        Some(_) if oscillator.has_waveform_sequence() => return,
+        Some(waveform_sequence) if join_sequence => {
+            oscillator.set_waveform_sequence(Some(Arc::clone(waveform_sequence)));
+            return;
+        }
+        Some(_) => (),
+        None if oscillator.has_waveform_sequence() => oscillator.set_waveform_sequence(None),
+        None => (),
+    }
+    if oscillator.get_waveform() != waveform {
+        oscillator.set_waveform(waveform);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{
+        DriveFollows, EffectsConfig, EnvelopeStage, FrequencyLimits, InitialConfig, ReferenceRoute,
+        ReferenceToneConfig, ShaperCurve, StartPhase, StealPolicy, StereoBlendConfig,
+        VariationConfig, VoiceStealingConfig, MAIN_BUS,
+    };
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    const BLOCK: usize = 256;
+
+    fn voices(engine: &SynthEngine) -> Vec<crate::synth::VoiceInfo> {
+        engine.note_state().lock().unwrap().voice_debug_info()
+    }
+
+    #[test]
+    fn a_released_voice_is_reported_finished_and_removed() {
+        let mut engine = SynthEngine::builder().build(SAMPLE_RATE);
+        let id = NoteId::shared("A".to_string());
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(id.clone(), None);
+        engine.render(BLOCK);
+        assert_eq!(voices(&engine).len(), 1);
+
+        engine.note_state().lock().unwrap().stop_note(&id);
+        engine.render(BLOCK);
+        // The voice is released rather than dropped, and fades out.
+        let voice = voices(&engine);
+        assert_eq!(voice.len(), 1);
+        assert_eq!(voice[0].envelope_stage, EnvelopeStage::Release);
+
+        let mut blocks = 0;
+        while !voices(&engine).is_empty() {
+            blocks += 1;
+            assert!(
+                blocks < 10 * SAMPLE_RATE as usize / BLOCK,
+                "voice never removed"
+            );
+            engine.render(BLOCK);
+        }
+        // Nothing is left sounding once it is gone.
+        assert!(engine
+            .render(BLOCK)
+            .data
+            .iter()
+            .all(|sample| sample.abs() < 1e-6));
+    }
+
+    /// The voices' mix, ahead of the effects, of the block after one with `note` held, with
+    /// its voice mixed at `gain`.
+    fn render_at_gain(note: &str, gain: f32) -> Vec<f32> {
+        let mut engine = SynthEngine::builder().build(SAMPLE_RATE);
+        {
+            let mut note_state = engine.note_state().lock().unwrap();
+            note_state.visual_tap = VisualTap::PreEffects;
+            note_state.start_note(NoteId::shared(note.to_string()), None);
+        }
+        engine.render(BLOCK);
+        for oscillator in engine.note_state().lock().unwrap().oscillators.iter_mut() {
+            oscillator.set_gain(gain);
+        }
+        engine.render(BLOCK);
+        engine.pre_effects().data.clone()
+    }
+
+    #[test]
+    fn each_voice_is_mixed_in_at_its_own_gain() {
+        let full = render_at_gain("A", 1.0);
+        let half = render_at_gain("A", 0.5);
+        assert!(full.iter().any(|sample| sample.abs() > 0.01));
+        for (full, half) in full.iter().zip(&half) {
+            assert!(
+                (full * 0.5 - half).abs() < 1e-6,
+                "{} against {}",
+                full,
+                half
+            );
+        }
+    }
+
+    #[test]
+    fn startup_events_apply_in_order_before_the_first_note() {
+        let waveform_type = Arc::new(RwLock::new(OscillatorWaveform::Sine));
+        let octave_shift = Arc::new(RwLock::new(0));
+        let scale = Arc::new(Mutex::new(InitialConfig::default().scale()));
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::clone(&waveform_type))
+            .octave_shift(Arc::clone(&octave_shift))
+            .scale(Arc::clone(&scale))
+            .build(SAMPLE_RATE);
+        engine.apply_startup_events(&[
+            StartupEvent::SetWaveform(OscillatorWaveform::Sawtooth),
+            StartupEvent::SetOctave(-1),
+            StartupEvent::SetWaveform(OscillatorWaveform::Square),
+            // Out of range octaves are clamped.
+            StartupEvent::SetOctave(7),
+            StartupEvent::ChangeKey("D".to_string()),
+        ]);
+        assert_eq!(*waveform_type.read().unwrap(), OscillatorWaveform::Square);
+        assert_eq!(*octave_shift.read().unwrap(), 2);
+        assert_eq!(scale.lock().unwrap().root_note, "D");
+
+        // The first note starts with everything already set.
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(NoteId::shared("A".to_string()), None);
+        engine.render(BLOCK);
+        let note_state = engine.note_state().lock().unwrap();
+        assert_eq!(
+            note_state.oscillators[0].get_waveform(),
+            OscillatorWaveform::Square
+        );
+        let frequency = InitialConfig::default()
+            .scale()
+            .calculate_frequency("A")
+            .unwrap();
+        assert!((note_state.oscillators[0].get_frequency() - frequency * 4.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn a_held_voice_that_stays_silent_is_pruned() {
+        // Ten blocks of 256 are 2560 samples, past a timeout of 2500.
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Silence)))
+            .oscillator_config(OscillatorConfig {
+                silence_timeout: 2_500.0 / SAMPLE_RATE,
+                ..OscillatorConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        let id = NoteId::shared("A".to_string());
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(id.clone(), None);
+        for block in 1..10 {
+            engine.render(BLOCK);
+            assert_eq!(voices(&engine).len(), 1, "block {}", block);
+        }
+        engine.render(BLOCK);
+        assert!(voices(&engine).is_empty());
+        // The note is let go too, so the next block doesn't start it again.
+        assert!(!engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .playing_notes
+            .contains_key(&id));
+        engine.render(BLOCK);
+        assert!(voices(&engine).is_empty());
+    }
+
+    #[test]
+    fn every_voice_takes_a_new_waveform_within_one_block() {
+        let waveform_type = Arc::new(RwLock::new(OscillatorWaveform::Sine));
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::clone(&waveform_type))
+            .build(SAMPLE_RATE);
+        for note in ["C", "E"] {
+            engine
+                .note_state()
+                .lock()
+                .unwrap()
+                .start_note(NoteId::shared(note.to_string()), None);
+        }
+        engine.render(BLOCK);
+
+        // A note struck in the same block as the change starts with the new waveform too.
+        *waveform_type.write().unwrap() = OscillatorWaveform::Square;
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(NoteId::shared("G".to_string()), None);
+        engine.render(BLOCK);
+        let note_state = engine.note_state().lock().unwrap();
+        assert_eq!(note_state.oscillators.len(), 3);
+        for oscillator in &note_state.oscillators {
+            assert_eq!(
+                oscillator.get_waveform(),
+                OscillatorWaveform::Square,
+                "{}",
+                oscillator.note
+            );
+        }
+    }
+
+    #[test]
+    fn a_note_shifted_past_the_maximum_frequency_is_clamped() {
+        let limits = FrequencyLimits {
+            max: Some(1_000.0),
+            ..FrequencyLimits::default()
+        };
+        let frequency_of = |engine: &SynthEngine| {
+            engine.note_state().lock().unwrap().oscillators[0].get_frequency()
+        };
+        let a = InitialConfig::default()
+            .scale()
+            .calculate_frequency("A")
+            .unwrap();
+        assert!(a * 4.0 > 1_000.0);
+
+        // Shifted up two octaves before the note starts.
+        let octave_shift = Arc::new(RwLock::new(2));
+        let mut engine = SynthEngine::builder()
+            .octave_shift(Arc::clone(&octave_shift))
+            .oscillator_config(OscillatorConfig {
+                frequency_limits: limits,
+                ..OscillatorConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(NoteId::shared("A".to_string()), None);
+        engine.render(BLOCK);
+        assert_eq!(frequency_of(&engine), 1_000.0);
+
+        // Shifted up two octaves while it is held.
+        *octave_shift.write().unwrap() = 0;
+        let mut engine = SynthEngine::builder()
+            .octave_shift(octave_shift)
+            .oscillator_config(OscillatorConfig {
+                frequency_limits: limits,
+                ..OscillatorConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(NoteId::shared("A".to_string()), None);
+        engine.render(BLOCK);
+        assert!((frequency_of(&engine) - a).abs() < 0.01);
+        for _ in 0..2 {
+            engine
+                .note_state()
+                .lock()
+                .unwrap()
+                .change_octave("up".to_string());
+        }
+        // Long enough for the glide up to finish.
+        for _ in 0..SAMPLE_RATE as usize / BLOCK {
+            engine.render(BLOCK);
+        }
+        assert_eq!(frequency_of(&engine), 1_000.0);
+    }
+
+    /// Whether a voice of `id` is sounding and not yet let go.
+    fn sounding(engine: &SynthEngine, id: &NoteId) -> usize {
+        let note_state = engine.note_state();
+        let note_state = note_state.lock().unwrap();
+        note_state
+            .oscillators
+            .iter()
+            .filter(|osc| osc.plays(id) && !osc.is_released())
+            .count()
+    }
+
+    #[cfg(feature = "visualization")]
+    #[test]
+    fn releasing_bass_c_leaves_treble_c_sounding() {
+        use crate::synth::{KeyZone, NoteSource};
+        use winit::keyboard::{KeyCode, PhysicalKey};
+
+        let mut engine = SynthEngine::builder().build(SAMPLE_RATE);
+        let bass = NoteId::new(
+            "C".to_string(),
+            NoteSource::Key(PhysicalKey::Code(KeyCode::KeyZ)),
+        );
+        let treble = NoteId::new(
+            "C".to_string(),
+            NoteSource::Key(PhysicalKey::Code(KeyCode::KeyA)),
+        );
+        {
+            let note_state = engine.note_state();
+            let mut note_state = note_state.lock().unwrap();
+            note_state.start_note_in_zone(bass.clone(), None, KeyZone::Bass);
+            note_state.start_note(treble.clone(), None);
+        }
+        engine.render(BLOCK);
+        assert_eq!(sounding(&engine, &bass), 1);
+        assert_eq!(sounding(&engine, &treble), 1);
+
+        engine.note_state().lock().unwrap().stop_note(&bass);
+        engine.render(BLOCK);
+        assert_eq!(sounding(&engine, &bass), 0);
+        assert_eq!(sounding(&engine, &treble), 1);
+        assert!(engine
+            .render(BLOCK)
+            .data
+            .iter()
+            .any(|sample| sample.abs() > 1e-3));
+    }
+
+    #[test]
+    fn merged_unison_presses_share_one_voice() {
+        let mut engine = SynthEngine::builder().build(SAMPLE_RATE);
+        let c = NoteId::shared("C".to_string());
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(c.clone(), None);
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(c.clone(), None);
+        engine.render(BLOCK);
+        assert_eq!(sounding(&engine, &c), 1);
+
+        engine.note_state().lock().unwrap().stop_note(&c);
+        engine.render(BLOCK);
+        assert_eq!(sounding(&engine, &c), 1);
+        engine.note_state().lock().unwrap().stop_note(&c);
+        engine.render(BLOCK);
+        assert_eq!(sounding(&engine, &c), 0);
+    }
+
+    #[test]
+    fn a_bass_zone_note_starts_with_the_bass_preset() {
+        use crate::synth::{KeyZone, KeyboardSplitConfig, ZonePreset};
+
+        let keyboard_split = KeyboardSplitConfig {
+            enabled: true,
+            bass: ZonePreset {
+                waveform: Some(OscillatorWaveform::Square),
+                octave: -1,
+                ..ZonePreset::default()
+            },
+            lead: ZonePreset::default(),
+        };
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sine)))
+            .keyboard_split(keyboard_split)
+            .build(SAMPLE_RATE);
+        let bass = NoteId::shared("A".to_string());
+        let lead = NoteId::shared("E".to_string());
+        {
+            let note_state = engine.note_state();
+            let mut note_state = note_state.lock().unwrap();
+            note_state.start_note_in_zone(bass.clone(), None, KeyZone::Bass);
+            note_state.start_note(lead.clone(), None);
+        }
+        engine.render(BLOCK);
+
+        let note_state = engine.note_state();
+        let note_state = note_state.lock().unwrap();
+        let voice = |id: &NoteId| {
+            note_state
+                .oscillators
+                .iter()
+                .find(|osc| osc.plays(id))
+                .unwrap()
+        };
+        assert_eq!(voice(&bass).get_waveform(), OscillatorWaveform::Square);
+        assert_eq!(voice(&bass).zone, KeyZone::Bass);
+        assert_eq!(voice(&lead).get_waveform(), OscillatorWaveform::Sine);
+        assert_eq!(voice(&lead).zone, KeyZone::Lead);
+        // An octave down from where the lead would play it.
+        let a = InitialConfig::default()
+            .scale()
+            .calculate_frequency("A")
+            .unwrap();
+        assert!((voice(&bass).get_frequency() - a / 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn with_the_split_off_the_bass_keys_play_the_lead_preset() {
+        use crate::synth::{KeyZone, KeyboardSplitConfig, ZonePreset};
+
+        let keyboard_split = KeyboardSplitConfig {
+            enabled: false,
+            bass: ZonePreset {
+                waveform: Some(OscillatorWaveform::Square),
+                ..ZonePreset::default()
+            },
+            lead: ZonePreset::default(),
+        };
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Triangle)))
+            .keyboard_split(keyboard_split)
+            .build(SAMPLE_RATE);
+        engine.note_state().lock().unwrap().start_note_in_zone(
+            NoteId::shared("A".to_string()),
+            None,
+            KeyZone::Bass,
+        );
+        engine.render(BLOCK);
+        let note_state = engine.note_state();
+        let note_state = note_state.lock().unwrap();
+        assert_eq!(
+            note_state.oscillators[0].get_waveform(),
+            OscillatorWaveform::Triangle
+        );
+    }
+
+    /// A second of the first channel of a sine A4 struck at `velocity`, once the drive has
+    /// settled, with the shaper's drive following `drive_follows`.
+    fn render_struck(velocity: u8, drive_follows: DriveFollows) -> Vec<f32> {
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sine)))
+            .wave_shaper_config(WaveShaperConfig {
+                drive_follows,
+                ..WaveShaperConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(NoteId::shared("A".to_string()), Some(velocity));
+        for _ in 0..40 {
+            engine.render(BLOCK);
+        }
+        let output = engine.render(SAMPLE_RATE as usize);
+        output.data[..output.num_frames()].to_vec()
+    }
+
+    /// Level of `frequency` in `samples`, through a Hann window.
+    fn amplitude_at(samples: &[f32], frequency: f32) -> f64 {
+        let len = samples.len() as f64;
+        let (re, im, window_sum) = samples.iter().enumerate().fold(
+            (0.0, 0.0, 0.0),
+            |(re, im, window_sum), (i, &sample)| {
+                let window = 0.5 - 0.5 * (std::f64::consts::TAU * i as f64 / len).cos();
+                let angle = std::f64::consts::TAU * (frequency / SAMPLE_RATE) as f64 * i as f64;
+                let sample = sample as f64 * window;
+                (
+                    re + sample * angle.cos(),
+                    im - sample * angle.sin(),
+                    window_sum + window,
+                )
+            },
+        );
+        2.0 * (re * re + im * im).sqrt() / window_sum
+    }
+
+    /// The third harmonic against the fundamental of a sine A4, in dB.
+    fn third_harmonic_db(samples: &[f32]) -> f64 {
+        20.0 * (amplitude_at(samples, 1320.0) / amplitude_at(samples, 440.0)).log10()
+    }
+
+    #[test]
+    fn a_hard_note_drives_the_shaper_harder_when_the_drive_follows_velocity() {
+        let soft = third_harmonic_db(&render_struck(30, DriveFollows::Velocity));
+        let hard = third_harmonic_db(&render_struck(127, DriveFollows::Velocity));
+        let hard_unfollowed = third_harmonic_db(&render_struck(127, DriveFollows::None));
+        assert!(hard > soft + 20.0, "hard {} dB, soft {} dB", hard, soft);
+        assert!(
+            hard > hard_unfollowed + 10.0,
+            "following {} dB, not {} dB",
+            hard,
+            hard_unfollowed
+        );
+    }
+
+    #[test]
+    fn without_drive_following_velocity_changes_the_level_but_barely_the_tone() {
+        let soft = render_struck(30, DriveFollows::None);
+        let hard = render_struck(127, DriveFollows::None);
+        let level = |samples: &[f32]| amplitude_at(samples, 440.0);
+        assert!(level(&hard) > 2.0 * level(&soft));
+        for samples in [&soft, &hard] {
+            assert!(
+                third_harmonic_db(samples) < -55.0,
+                "{}",
+                third_harmonic_db(samples)
+            );
+        }
+    }
+
+    /// A trill of A and B, each struck four times, rendered with `variation`.
+    fn render_trill(variation: VariationConfig) -> Vec<f32> {
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sawtooth)))
+            .oscillator_config(OscillatorConfig {
+                variation,
+                ..OscillatorConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        let mut rendered = Vec::new();
+        for strike in 0..8 {
+            let id = NoteId::shared(if strike % 2 == 0 { "A" } else { "B" }.to_string());
+            engine
+                .note_state()
+                .lock()
+                .unwrap()
+                .start_note(id.clone(), None);
+            rendered.extend(engine.render(4 * BLOCK).data);
+            engine.note_state().lock().unwrap().stop_note(&id);
+            rendered.extend(engine.render(BLOCK).data);
+        }
+        rendered
+    }
+
+    fn varied(seed: Option<u64>) -> VariationConfig {
+        VariationConfig {
+            start_phase: StartPhase::Random,
+            amplitude_db: 2.0,
+            attack: 0.3,
+            seed,
+        }
+    }
+
+    #[test]
+    fn with_variation_off_a_trill_renders_the_same_every_time() {
+        let first = render_trill(VariationConfig::default());
+        assert!(first.iter().any(|sample| sample.abs() > 0.01));
+        assert_eq!(first, render_trill(VariationConfig::default()));
+    }
+
+    #[test]
+    fn a_seeded_variation_renders_the_same_every_time_but_varies_the_strikes() {
+        let first = render_trill(varied(Some(7)));
+        assert_eq!(first, render_trill(varied(Some(7))));
+        assert_ne!(first, render_trill(varied(Some(8))));
+        assert_ne!(first, render_trill(VariationConfig::default()));
+        // The first two strikes of A start differently.
+        let strike = 5 * BLOCK;
+        assert_ne!(first[..BLOCK], first[2 * strike..2 * strike + BLOCK]);
+    }
+
+    #[test]
+    fn a_continued_retrigger_picks_up_the_phase_of_the_releasing_voice() {
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sine)))
+            .oscillator_config(OscillatorConfig {
+                variation: VariationConfig {
+                    start_phase: StartPhase::Continue,
+                    ..VariationConfig::default()
+                },
+                ..OscillatorConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        let id = NoteId::shared("A".to_string());
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(id.clone(), None);
+        // Part way through a cycle, so a restart from zero would jump.
+        engine.render(BLOCK + 37);
+        engine.note_state().lock().unwrap().stop_note(&id);
+        engine.render(BLOCK);
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(id.clone(), None);
+        engine.render(BLOCK);
+
+        let note_state = engine.note_state().lock().unwrap();
+        let phases: Vec<f32> = note_state
+            .oscillators
+            .iter()
+            .filter(|osc| osc.note == "A")
+            .map(|osc| osc.get_phase())
+            .collect();
+        assert_eq!(phases.len(), 2, "{:?}", phases);
+        assert!(phases[0] > 0.0);
+        // Both voices have run the same block since, so a continuous phase keeps them level.
+        assert!((phases[0] - phases[1]).abs() < 1e-4, "{:?}", phases);
+    }
+
+    /// The voices' mix of a block of a held sawtooth A through a hard-driven wave shaper, and
+    /// the mix as it was before the shaper.
+    fn shaped_and_dry(bypassed: bool) -> (Vec<f32>, Vec<f32>) {
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sawtooth)))
+            .wave_shaper_config(WaveShaperConfig {
+                drive: 6.0,
+                ..WaveShaperConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        {
+            let mut note_state = engine.note_state().lock().unwrap();
+            note_state.visual_tap = VisualTap::PreEffects;
+            note_state.wave_shaper_bypassed = bypassed;
+            note_state.start_note(NoteId::shared("A".to_string()), None);
+        }
+        let mut output = AudioBuffer {
+            data: vec![0.0; BLOCK],
+            num_channels: 1,
+        };
+        for _ in 0..40 {
+            engine.process_voices(&mut output);
+        }
+        (output.data, engine.pre_effects().data.clone())
+    }
+
+    #[test]
+    fn a_bypassed_wave_shaper_leaves_the_mix_as_it_was() {
+        let (output, dry) = shaped_and_dry(true);
+        assert!(dry.iter().any(|sample| sample.abs() > 0.01));
+        assert_eq!(output, dry);
+
+        let (output, dry) = shaped_and_dry(false);
+        let difference = output
+            .iter()
+            .zip(&dry)
+            .fold(0.0f32, |most, (shaped, dry)| most.max((shaped - dry).abs()));
+        assert!(difference > 0.01, "{}", difference);
+    }
+
+    /// The notes sounding after C, E and G are struck in turn with two voices to go round,
+    /// stolen by `steal_policy`.
+    fn chord_past_two_voices(steal_policy: StealPolicy) -> Vec<String> {
+        let mut engine = SynthEngine::builder()
+            .oscillator_config(OscillatorConfig {
+                voice_stealing: VoiceStealingConfig {
+                    max_voices: Some(2),
+                    steal_policy,
+                    fallback: StealPolicy::Oldest,
+                },
+                ..OscillatorConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        for note in ["C", "E", "G"] {
+            engine
+                .note_state()
+                .lock()
+                .unwrap()
+                .start_note(NoteId::shared(note.to_string()), None);
+            engine.render(BLOCK);
+        }
+        let mut notes: Vec<String> = voices(&engine)
+            .into_iter()
+            .map(|voice| voice.note)
+            .collect();
+        notes.sort();
+        notes
+    }
+
+    #[test]
+    fn a_note_past_the_voice_limit_steals_the_voice_the_policy_picks() {
+        assert_eq!(chord_past_two_voices(StealPolicy::Oldest), ["E", "G"]);
+        assert_eq!(chord_past_two_voices(StealPolicy::Lowest), ["E", "G"]);
+        assert_eq!(chord_past_two_voices(StealPolicy::Highest), ["C", "G"]);
+        // Off, the new note waits for a free voice.
+        assert_eq!(chord_past_two_voices(StealPolicy::Off), ["C", "E"]);
+    }
+
+    fn engine_with_effects(effects_config: EffectsConfig) -> SynthEngine {
+        SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sine)))
+            .effects_config(effects_config)
+            .build(SAMPLE_RATE)
+    }
+
+    /// Loudest sample of the output once a held A has settled.
+    fn settled_peak(engine: &mut SynthEngine) -> f32 {
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(NoteId::shared("A".to_string()), None);
+        for _ in 0..40 {
+            engine.render(BLOCK);
+        }
+        engine
+            .render(BLOCK)
+            .data
+            .iter()
+            .fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn the_effects_section_sets_the_engines_own_tremolo_and_volume() {
+        let engine = engine_with_effects(EffectsConfig {
+            tremolo_rate: 7.5,
+            tremolo_depth: 0.25,
+            ..EffectsConfig::default()
+        });
+        assert!((engine.tremolo_effect.get_rate() - 7.5).abs() < 1e-3);
+        assert!((engine.tremolo_effect.get_depth() - 0.25).abs() < 1e-3);
+
+        let full = settled_peak(&mut engine_with_effects(EffectsConfig::default()));
+        let half = settled_peak(&mut engine_with_effects(EffectsConfig {
+            master_volume: 0.5,
+            ..EffectsConfig::default()
+        }));
+        assert!(full > 0.01);
+        assert!(
+            (half / full - 0.5).abs() < 0.01,
+            "{} against {}",
+            half,
+            full
+        );
+    }
+
+    #[test]
+    fn the_effects_section_picks_the_shaper_curve() {
+        let engine = engine_with_effects(EffectsConfig {
+            shaper_curve: ShaperCurve::HardClip,
+            ..EffectsConfig::default()
+        });
+        let transfer_fn = engine.wave_shaper_node.node().transfer_fn;
+        // Past full scale a hard clip holds at 1, where the default sine would fold back.
+        assert_eq!(transfer_fn(3.0), 1.0);
+        assert_eq!(transfer_fn(-3.0), -1.0);
+    }
+
+    /// Total harmonic distortion of the reference tone, switched on through a hard-clipping
+    /// shaper at drive 8 and joining the chain at `route`: harmonics 2 to 5 against the
+    /// fundamental.
+    fn reference_tone_thd(route: ReferenceRoute) -> f64 {
+        let mut engine = SynthEngine::builder()
+            .effects_config(EffectsConfig {
+                shaper_curve: ShaperCurve::HardClip,
+                ..EffectsConfig::default()
+            })
+            .wave_shaper_config(WaveShaperConfig {
+                drive: 8.0,
+                ..WaveShaperConfig::default()
+            })
+            .reference_tone_config(ReferenceToneConfig {
+                route,
+                ..ReferenceToneConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        engine.note_state().lock().unwrap().reference_tone = true;
+        engine.render(BLOCK * 8);
+        let output = engine.render(SAMPLE_RATE as usize);
+        let samples = &output.data[..output.num_frames()];
+        let harmonics: f64 = (2..=5)
+            .map(|harmonic| amplitude_at(samples, 440.0 * harmonic as f32).powi(2))
+            .sum();
+        harmonics.sqrt() / amplitude_at(samples, 440.0)
+    }
+
+    #[test]
+    fn the_reference_tone_stays_a_clean_sine_after_the_effects() {
+        let clean = reference_tone_thd(ReferenceRoute::PostEffects);
+        let shaped = reference_tone_thd(ReferenceRoute::PreEffects);
+        assert!(clean < 0.001, "THD {}", clean);
+        assert!(shaped > 0.1, "THD {}", shaped);
+    }
+
+    /// A third of a second of the first channel once the notes are going: A on the bass keys
+    /// and E on the note keys, each if asked for, with the bass zone routed to `bass_bus` and
+    /// `buses` declared.
+    fn render_zones(
+        buses: Vec<BusConfig>,
+        bass_bus: &str,
+        play_bass: bool,
+        play_lead: bool,
+    ) -> Vec<f32> {
+        use crate::synth::{KeyZone, KeyboardSplitConfig, ZonePreset};
+
+        let keyboard_split = KeyboardSplitConfig {
+            enabled: true,
+            bass: ZonePreset {
+                bus: Some(bass_bus.to_string()),
+                ..ZonePreset::default()
+            },
+            lead: ZonePreset::default(),
+        };
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sine)))
+            .keyboard_split(keyboard_split)
+            .buses(buses)
+            .build(SAMPLE_RATE);
+        {
+            let note_state = engine.note_state();
+            let mut note_state = note_state.lock().unwrap();
+            if play_bass {
+                note_state.start_note_in_zone(NoteId::shared("A".to_string()), None, KeyZone::Bass);
+            }
+            if play_lead {
+                note_state.start_note(NoteId::shared("E".to_string()), None);
+            }
+        }
+        for _ in 0..20 {
+            engine.render(BLOCK);
+        }
+        let output = engine.render(SAMPLE_RATE as usize / 3);
+        output.data[..output.num_frames()].to_vec()
+    }
+
+    fn bus(name: &str, gain: f32, shaper: Option<ShaperCurve>, drive: f32) -> BusConfig {
+        BusConfig {
+            name: name.to_string(),
+            gain,
+            shaper,
+            drive,
+        }
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn buses_at_different_gains_mix_their_voices_at_those_levels() {
+        let buses = vec![bus("loud", 1.0, None, 1.0), bus("quiet", 0.25, None, 1.0)];
+        let loud = render_zones(buses.clone(), "loud", true, false);
+        let quiet = render_zones(buses, "quiet", true, false);
+        let ratio = rms(&quiet) / rms(&loud);
+        assert!((ratio - 0.25).abs() < 0.005, "{}", ratio);
+    }
+
+    #[test]
+    fn a_bus_shaper_bends_only_the_voices_on_that_bus() {
+        let clipped = || vec![bus("bass", 1.0, Some(ShaperCurve::HardClip), 40.0)];
+        let clean = || vec![bus("bass", 1.0, None, 1.0)];
+        // The bass note alone is squared off by its bus's clip...
+        let bass_clipped = render_zones(clipped(), "bass", true, false);
+        let bass_clean = render_zones(clean(), "bass", true, false);
+        assert!(third_harmonic_db(&bass_clipped) > -20.0);
+        assert!(third_harmonic_db(&bass_clean) < -50.0);
+        // ...while the lead note on the main bus comes out the same, clip or no clip.
+        let lead_clipped = render_zones(clipped(), "bass", false, true);
+        let lead_clean = render_zones(clean(), "bass", false, true);
+        assert_eq!(lead_clipped, lead_clean);
+    }
+
+    #[test]
+    fn going_through_the_buses_renders_the_same_as_writing_straight_to_the_output() {
+        // The default main bus alone takes the direct path, an extra bus the bus graph.
+        for (bass, lead) in [(true, true), (true, false), (false, true)] {
+            let direct = render_zones(Vec::new(), MAIN_BUS, bass, lead);
+            let declared_main = render_zones(vec![BusConfig::default()], MAIN_BUS, bass, lead);
+            let through_buses =
+                render_zones(vec![bus("spare", 1.0, None, 1.0)], MAIN_BUS, bass, lead);
+            assert_eq!(direct, declared_main);
+            assert_eq!(direct, through_buses);
+        }
+    }
+
+    #[test]
+    fn a_voice_ages_with_the_engine_time_from_the_block_it_started_in() {
+        let mut engine = SynthEngine::builder().build(SAMPLE_RATE);
+        engine.render(BLOCK);
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(NoteId::shared("A".to_string()), None);
+        let age = |engine: &SynthEngine| {
+            let note_state = engine.note_state();
+            let note_state = note_state.lock().unwrap();
+            note_state.oscillators[0].age(engine.current_time())
+        };
+        let mut ages = Vec::new();
+        for _ in 0..4 {
+            engine.render(BLOCK);
+            ages.push(age(&engine));
+        }
+        let block = BLOCK as f32 / SAMPLE_RATE;
+        for (blocks, age) in ages.iter().enumerate() {
+            assert!(
+                (age - (blocks + 1) as f32 * block).abs() < 1e-6,
+                "{:?}",
+                ages
+            );
+        }
+    }
+
+    #[test]
+    fn lowering_quality_gives_new_notes_a_single_voice_and_leaves_sounding_ones_alone() {
+        let mut engine = SynthEngine::builder()
+            .oscillator_config(OscillatorConfig {
+                unison: UnisonConfig {
+                    voices: 3,
+                    ..UnisonConfig::default()
+                },
+                ..OscillatorConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        let c = NoteId::shared("C".to_string());
+        let e = NoteId::shared("E".to_string());
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(c.clone(), None);
+        engine.render(BLOCK);
+        assert_eq!(sounding(&engine, &c), 3);
+
+        engine
+            .set_param(ParamId::Quality, QualityStep::Unison.level() as f32)
+            .unwrap();
+        assert_eq!(engine.param_value(ParamId::Quality), Some(2.0));
+        assert_eq!(engine.quality_level().load(Ordering::Relaxed), 2);
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(e.clone(), None);
+        engine.render(BLOCK);
+        assert_eq!(sounding(&engine, &c), 3);
+        assert_eq!(sounding(&engine, &e), 1);
+
+        // Back at full quality, new notes get the whole stack again.
+        engine.set_param(ParamId::Quality, 0.0).unwrap();
+        let g = NoteId::shared("G".to_string());
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(g.clone(), None);
+        engine.render(BLOCK);
+        assert_eq!(sounding(&engine, &g), 3);
+    }
+
+    #[test]
+    fn a_quality_level_past_the_last_step_is_held_at_the_lowest_quality() {
+        let mut engine = SynthEngine::builder().build(SAMPLE_RATE);
+        engine.set_param(ParamId::Quality, 99.0).unwrap();
+        assert_eq!(
+            engine.param_value(ParamId::Quality),
+            Some(MAX_QUALITY_LEVEL as f32)
+        );
+        assert_eq!(engine.interpolation(), Interpolation::Nearest);
+        assert!(engine.quality_lowered(QualityStep::Polyphony));
+    }
+
+    /// The left and right channels of A held for a second on a stereo engine.
+    fn render_stereo(
+        waveform: OscillatorWaveform,
+        stereo_blend: StereoBlendConfig,
+    ) -> [Vec<f32>; 2] {
+        let mut engine = SynthEngine::builder()
+            .waveform_type(Arc::new(RwLock::new(waveform)))
+            .oscillator_config(OscillatorConfig {
+                stereo_blend,
+                ..OscillatorConfig::default()
+            })
+            .build(SAMPLE_RATE);
+        engine.set_num_channels(2);
+        engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .start_note(NoteId::shared("A".to_string()), None);
+        for _ in 0..40 {
+            engine.render(BLOCK);
+        }
+        let output = engine.render(SAMPLE_RATE as usize);
+        [output.channel(0).to_vec(), output.channel(1).to_vec()]
+    }
+
+    /// How alike two signals are in shape, whatever their levels: 1 for the same shape.
+    fn correlation(a: &[f32], b: &[f32]) -> f32 {
+        let dot = |x: &[f32], y: &[f32]| x.iter().zip(y).map(|(x, y)| x * y).sum::<f32>();
+        dot(a, b) / (dot(a, a) * dot(b, b)).sqrt()
+    }
+
+    #[test]
+    fn a_stereo_blend_plays_the_left_wavetable_on_the_left_and_the_right_on_the_right() {
+        let blend = StereoBlendConfig {
+            enabled: true,
+            left: OscillatorWaveform::Sawtooth,
+            right: OscillatorWaveform::Square,
+            width: 1.0,
+        };
+        let [left, right] = render_stereo(OscillatorWaveform::Sine, blend);
+        let [sawtooth, _] =
+            render_stereo(OscillatorWaveform::Sawtooth, StereoBlendConfig::default());
+        let [square, _] = render_stereo(OscillatorWaveform::Square, StereoBlendConfig::default());
+
+        assert!(
+            correlation(&left, &sawtooth) > 0.999,
+            "{}",
+            correlation(&left, &sawtooth)
+        );
+        assert!(
+            correlation(&right, &square) > 0.999,
+            "{}",
+            correlation(&right, &square)
+        );
+        // Each side has only its own waveform in it.
+        assert!(
+            correlation(&left, &square) < 0.9,
+            "{}",
+            correlation(&left, &square)
+        );
+        assert!(
+            correlation(&right, &sawtooth) < 0.9,
+            "{}",
+            correlation(&right, &sawtooth)
+        );
+    }
+
+    #[test]
+    fn a_random_patch_sets_its_parameters_through_the_registry_and_leaves_locked_ones() {
+        let config = RandomizerConfig {
+            seed: Some(12),
+            locked: vec!["drive".to_string()],
+            ..RandomizerConfig::default()
+        };
+        let mut engine = SynthEngine::builder()
+            .randomizer_config(config.clone())
+            .build(SAMPLE_RATE);
+        let drive = engine.param_value(ParamId::Drive);
+        let mut twin = PatchRandomizer::new(&config);
+
+        for _ in 0..5 {
+            engine.apply_random_patch();
+            let patch = twin.next_patch();
+            for (param, value) in patch.values {
+                assert_eq!(engine.param_value(param), Some(value), "{}", param);
+            }
+            assert_eq!(engine.param_value(ParamId::Drive), drive);
+        }
+    }
+
+    #[test]
+    fn set_param_events_are_set_after_the_next_block() {
+        let mut engine = SynthEngine::builder().build(SAMPLE_RATE);
+        engine.note_state().lock().unwrap().param_requests.extend([
+            (ParamId::Drive, 3.0),
+            (ParamId::Waveform, 1.0),
+            (ParamId::Drive, 2.5),
+        ]);
+        assert_ne!(engine.param_value(ParamId::Drive), Some(2.5));
+
+        engine.render(BLOCK);
+        assert_eq!(engine.param_value(ParamId::Drive), Some(2.5));
+        assert_eq!(engine.param_value(ParamId::Waveform), Some(1.0));
+        assert!(engine
+            .note_state()
+            .lock()
+            .unwrap()
+            .param_requests
+            .is_empty());
+    }
+
+    #[test]
+    fn a_random_patch_is_handed_back_for_the_undo_history() {
+        let config = RandomizerConfig {
+            seed: Some(3),
+            ..RandomizerConfig::default()
+        };
+        let mut engine = SynthEngine::builder()
+            .randomizer_config(config)
+            .build(SAMPLE_RATE);
+        let before: Vec<Option<f32>> = ParamId::ALL
+            .iter()
+            .map(|&param| engine.param_value(param))
+            .collect();
+        engine.note_state().lock().unwrap().random_patch_requested = true;
+        engine.render(BLOCK);
+
+        let patches = std::mem::take(&mut engine.note_state().lock().unwrap().random_patches);
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].label, "random patch 1");
+        assert!(!patches[0].changes.is_empty());
+        for change in &patches[0].changes {
+            let index = ParamId::ALL.iter().position(|&param| param == change.param);
+            assert_eq!(
+                Some(change.before),
+                before[index.unwrap()],
+                "{}",
+                change.param
+            );
+            assert_eq!(
+                engine.param_value(change.param),
+                Some(change.after),
+                "{}",
+                change.param
+            );
+        }
+    }
+}
@@ -0,0 +1,117 @@
+/// Moves a frequency towards a target at a steady rate in octaves per second, so a pitch change
+/// glides instead of jumping.
+///
+/// The glide is even on a musical scale: an octave takes as long from 110 Hz as from 880 Hz. It
+/// lands exactly on the target once it is within one step, rather than creeping up on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrequencySlew {
+    current: f32,
+    target: f32,
+}
+
+impl FrequencySlew {
+    pub fn new(frequency: f32) -> Self {
+        FrequencySlew {
+            current: frequency,
+            target: frequency,
+        }
+    }
+
+    /// The frequency the slew has reached.
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    /// The frequency the slew is heading for.
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.current == self.target
+    }
+
+    /// Starts gliding towards `target` from wherever the slew is now.
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    /// Goes straight to `frequency`, dropping any glide in progress.
+    pub fn jump_to(&mut self, frequency: f32) {
+        self.current = frequency;
+        self.target = frequency;
+    }
+
+    /// Moves `seconds` worth of the way towards the target at `rate` octaves per second and
+    /// returns the new frequency. A rate of zero or less jumps straight to the target, as does a
+    /// frequency the distance can't be measured from, such as zero.
+    pub fn advance(&mut self, seconds: f32, rate: f32) -> f32 {
+        let distance = (self.target / self.current).log2();
+        let step = rate * seconds.max(0.0);
+        if rate <= 0.0 || !distance.is_finite() || distance.abs() <= step {
+            self.current = self.target;
+        } else {
+            self.current *= 2.0f32.powf(step.copysign(distance));
+        }
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_octave_takes_the_same_time_from_any_pitch() {
+        let mut all_steps = Vec::new();
+        for start in [55.0, 440.0, 3_520.0] {
+            let mut slew = FrequencySlew::new(start);
+            slew.set_target(start * 2.0);
+            let mut steps = 0;
+            while !slew.is_settled() {
+                slew.advance(0.01, 10.0);
+                steps += 1;
+                assert!(steps < 100, "never settled from {}", start);
+            }
+            assert_eq!(slew.current(), start * 2.0);
+            all_steps.push(steps);
+        }
+        // A tenth of an octave a step, give or take a rounding error at the end.
+        assert!((10..=11).contains(&all_steps[0]));
+        assert!(all_steps.iter().all(|&steps| steps == all_steps[0]));
+    }
+
+    #[test]
+    fn a_glide_down_lands_exactly_on_the_target() {
+        let mut slew = FrequencySlew::new(880.0);
+        slew.set_target(261.63);
+        let mut previous = slew.current();
+        while !slew.is_settled() {
+            let frequency = slew.advance(0.003, 20.0);
+            assert!(frequency < previous && frequency >= 261.63);
+            previous = frequency;
+        }
+        assert_eq!(slew.current().to_bits(), 261.63f32.to_bits());
+    }
+
+    #[test]
+    fn a_rate_of_zero_or_an_unmeasurable_distance_jumps() {
+        let mut slew = FrequencySlew::new(440.0);
+        slew.set_target(880.0);
+        assert_eq!(slew.advance(0.001, 0.0), 880.0);
+
+        let mut slew = FrequencySlew::new(0.0);
+        slew.set_target(440.0);
+        assert_eq!(slew.advance(0.001, 20.0), 440.0);
+    }
+
+    #[test]
+    fn jumping_drops_the_glide_in_progress() {
+        let mut slew = FrequencySlew::new(440.0);
+        slew.set_target(880.0);
+        slew.advance(0.01, 20.0);
+        slew.jump_to(220.0);
+        assert!(slew.is_settled());
+        assert_eq!(slew.advance(1.0, 20.0), 220.0);
+    }
+}
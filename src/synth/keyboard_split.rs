@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use crate::synth::OscillatorWaveform;
+
+/// Which half of a split keyboard a note was played from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyZone {
+    /// The `bass_notes` keys.
+    Bass,
+    /// The `notes` keys, and anything not played from a key.
+    #[default]
+    Lead,
+}
+
+/// The sound a zone's notes start with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZonePreset {
+    /// Waveform the zone's notes play. Unset follows the waveform keys; set, the zone keeps
+    /// this waveform whatever they select.
+    pub waveform: Option<OscillatorWaveform>,
+    /// Envelope times in seconds, and the sustain level from 0 to 1.
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    /// Octaves the zone plays above (or, negative, below) the octave keys' shift.
+    pub octave: i32,
+    /// Bus the zone's notes are mixed on; unset, or a bus that isn't declared, is the main bus.
+    pub bus: Option<String>,
+}
+
+impl Default for ZonePreset {
+    fn default() -> Self {
+        ZonePreset {
+            waveform: None,
+            attack: 0.5,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.5,
+            octave: 0,
+            bus: None,
+        }
+    }
+}
+
+impl ZonePreset {
+    pub fn envelope(&self) -> EnvelopeShape {
+        EnvelopeShape {
+            attack: self.attack,
+            decay: self.decay,
+            sustain: self.sustain,
+            release: self.release,
+        }
+    }
+
+    pub fn set_envelope(&mut self, envelope: EnvelopeShape) {
+        self.attack = envelope.attack;
+        self.decay = envelope.decay;
+        self.sustain = envelope.sustain;
+        self.release = envelope.release;
+    }
+}
+
+/// The four settings of an ADSR envelope: times in seconds, and the sustain level from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnvelopeShape {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+/// A keyboard split: the bass keys play one preset and the note keys another.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyboardSplitConfig {
+    /// Off, every note plays the lead preset.
+    pub enabled: bool,
+    pub bass: ZonePreset,
+    pub lead: ZonePreset,
+}
+
+impl KeyboardSplitConfig {
+    /// The preset notes from `zone` start with.
+    pub fn preset(&self, zone: KeyZone) -> &ZonePreset {
+        match zone {
+            KeyZone::Bass if self.enabled => &self.bass,
+            _ => &self.lead,
+        }
+    }
+
+    /// The waveform a voice from `zone` plays while the waveform keys have `selected` chosen.
+    pub fn waveform(&self, zone: KeyZone, selected: OscillatorWaveform) -> OscillatorWaveform {
+        self.preset(zone).waveform.unwrap_or(selected)
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::{HashMap, HashSet};
+
+use tracing::{debug, warn};
+use winit::keyboard::{Key, NamedKey, SmolStr};
+
+use crate::synth::{Config, CycleDirection, KeyZone, NoteEvent};
+
+/// A key as the bindings see it: the key without modifiers, plus whether Shift is held.
+///
+/// Built straight from winit's `Key`, so looking one up never formats or allocates.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeyId {
+    pub key: Key,
+    pub shift: bool,
+}
+
+impl KeyId {
+    pub fn new(key: Key, shift: bool) -> Self {
+        KeyId { key, shift }
+    }
+}
+
+/// The config's bindings compiled into ready-to-send events.
+///
+/// The serde structs in `Config` stay the file format; this is what the event loop consults on
+/// every keystroke. Note bindings are stored twice, once as played plain and once with the
+/// configured shift behavior already applied. A key bound to both a note and an action does the
+/// action.
+#[derive(Debug, Default, PartialEq)]
+pub struct ResolvedBindings {
+    bindings: HashMap<KeyId, NoteEvent>,
+    /// The keys bound in `bass_notes`, which make up the bass zone of a split keyboard.
+    bass_keys: HashSet<Key>,
+}
+
+impl ResolvedBindings {
+    /// Compiles the bindings in `config`, warning about and skipping keys that can't be parsed.
+    pub fn from_config(config: &Config) -> Self {
+        let mut resolved = ResolvedBindings::default();
+        let keybindings = &config.keybindings;
+        // Notes go in first so that actions bound to the same keys replace them.
+        resolved.insert_notes(config);
+
+        // Actions behave the same with or without Shift held.
+        for (key, waveform) in config.action_keys.change_waveform.iter() {
+            resolved.insert_action(key, NoteEvent::ChangeWaveform(*waveform));
+        }
+        resolved.insert_action(
+            &keybindings.octave.up,
+            NoteEvent::ChangeOctave("up".to_string()),
+        );
+        resolved.insert_action(
+            &keybindings.octave.down,
+            NoteEvent::ChangeOctave("down".to_string()),
+        );
+        if let Some(cycle_keys) = &keybindings.waveform_cycle {
+            resolved.insert_action(
+                &cycle_keys.next,
+                NoteEvent::CycleWaveform(CycleDirection::Next),
+            );
+            resolved.insert_action(
+                &cycle_keys.previous,
+                NoteEvent::CycleWaveform(CycleDirection::Previous),
+            );
+        }
+        resolved.insert_action(&keybindings.tremolo.toggle, NoteEvent::ToggleTremolo);
+        if let Some(note_name_keys) = &keybindings.note_names {
+            resolved.insert_action(&note_name_keys.toggle, NoteEvent::ToggleNoteNames);
+        }
+        if let Some(waveform_sequence_keys) = &keybindings.waveform_sequence {
+            resolved.insert_action(
+                &waveform_sequence_keys.toggle,
+                NoteEvent::ToggleWaveformSequence,
+            );
+        }
+        if let Some(midi_export_keys) = &keybindings.midi_export {
+            resolved.insert_action(&midi_export_keys.export, NoteEvent::ExportMidi);
+        }
+        if let Some(looper_keys) = &keybindings.looper {
+            resolved.insert_action(&looper_keys.toggle, NoteEvent::ToggleLoop);
+            resolved.insert_action(&looper_keys.undo, NoteEvent::UndoLoopOverdub);
+            resolved.insert_action(&looper_keys.clear, NoteEvent::ClearLoop);
+        }
+        if let Some(mute_keys) = &keybindings.mute {
+            resolved.insert_action(&mute_keys.toggle, NoteEvent::ToggleMute);
+        }
+        if let Some(wave_shaper_keys) = &keybindings.wave_shaper {
+            resolved.insert_action(&wave_shaper_keys.bypass, NoteEvent::ToggleWaveShaperBypass);
+        }
+        if let Some(reference_tone_keys) = &keybindings.reference_tone {
+            resolved.insert_action(&reference_tone_keys.toggle, NoteEvent::ToggleReferenceTone);
+        }
+        if let Some(randomizer_keys) = &keybindings.randomizer {
+            resolved.insert_action(&randomizer_keys.random_patch, NoteEvent::RandomPatch);
+        }
+        if let Some(undo_keys) = &keybindings.undo {
+            resolved.insert_action(&undo_keys.undo, NoteEvent::Undo);
+            resolved.insert_action(&undo_keys.redo, NoteEvent::Redo);
+            resolved.insert_action(&undo_keys.dump, NoteEvent::DumpUndoHistory);
+        }
+        if let Some(visual_tap_keys) = &keybindings.visual_tap {
+            resolved.insert_action(&visual_tap_keys.cycle, NoteEvent::CycleVisualTap);
+        }
+        if let Some(display_keys) = &keybindings.display {
+            resolved.insert_action(&display_keys.gain_up, NoteEvent::DisplayGainUp);
+            resolved.insert_action(&display_keys.gain_down, NoteEvent::DisplayGainDown);
+            resolved.insert_action(&display_keys.auto_gain, NoteEvent::ToggleAutoGain);
+        }
+        if let Some(accessibility_keys) = &keybindings.accessibility {
+            resolved.insert_action(
+                &accessibility_keys.high_contrast,
+                NoteEvent::ToggleHighContrast,
+            );
+            resolved.insert_action(
+                &accessibility_keys.reduced_motion,
+                NoteEvent::ToggleReducedMotion,
+            );
+        }
+        if let Some(help_keys) = &keybindings.help {
+            resolved.insert_action(&help_keys.toggle, NoteEvent::ToggleHelp);
+        }
+        if let Some(debug_keys) = &keybindings.debug {
+            resolved.insert_action(&debug_keys.dump_voices, NoteEvent::DumpVoices);
+        }
+
+        // Key change bindings don't map to an event yet.
+        for (key, change) in keybindings.key_change.keys.iter() {
+            debug!("Key change binding {} -> {} is not handled", key, change);
+        }
+
+        resolved
+    }
+
+    /// Returns the event bound to `key_id`.
+    pub fn lookup(&self, key_id: &KeyId) -> Option<&NoteEvent> {
+        self.bindings.get(key_id)
+    }
+
+    /// The zone of a split keyboard `key` plays in.
+    pub fn zone(&self, key: &Key) -> KeyZone {
+        if self.bass_keys.contains(key) {
+            KeyZone::Bass
+        } else {
+            KeyZone::Lead
+        }
+    }
+
+    /// Every binding, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&KeyId, &NoteEvent)> {
+        self.bindings.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// Binds the keys in `notes` and `bass_notes` to the notes they play.
+    fn insert_notes(&mut self, config: &Config) {
+        let keybindings = &config.keybindings;
+        let scale = config.initial.scale();
+        let note_keys = keybindings
+            .notes
+            .keys
+            .keys()
+            .chain(keybindings.bass_notes.keys.keys());
+        for key_str in note_keys {
+            let Some(key) = parse_key(key_str) else {
+                warn!("Ignoring note binding with unrecognized key: {}", key_str);
+                continue;
+            };
+            if keybindings.bass_notes.keys.contains_key(key_str) {
+                self.bass_keys.insert(key.clone());
+            }
+            for shift in [false, true] {
+                let Some(note) = keybindings.resolve_note(key_str, shift) else {
+                    continue;
+                };
+                if scale.calculate_frequency(&note).is_none() {
+                    warn!("Ignoring binding of {} to unknown note {}", key_str, note);
+                    continue;
+                }
+                self.bindings
+                    .insert(KeyId::new(key.clone(), shift), NoteEvent::On(note));
+            }
+        }
+    }
+
+    fn insert_action(&mut self, key_str: &str, event: NoteEvent) {
+        let Some(key) = parse_key(key_str) else {
+            warn!("Ignoring action binding with unrecognized key: {}", key_str);
+            return;
+        };
+        for shift in [true, false] {
+            self.bindings
+                .insert(KeyId::new(key.clone(), shift), event.clone());
+        }
+    }
+}
+
+/// Parses a key as written in the config: `Character("a")`, `Named(ArrowUp)`, or a bare key name
+/// such as `F1`.
+pub fn parse_key(key_str: &str) -> Option<Key> {
+    if let Some(character) = key_str
+        .strip_prefix("Character(\"")
+        .and_then(|rest| rest.strip_suffix("\")"))
+    {
+        return Some(Key::Character(SmolStr::new(character)));
+    }
+
+    let name = key_str
+        .strip_prefix("Named(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .unwrap_or(key_str);
+    parse_named_key(name).map(Key::Named)
+}
+
+/// Names `key` the way the help screen shows it: characters uppercased, named keys by name.
+pub fn key_label(key: &Key) -> String {
+    match key {
+        Key::Character(character) => character.to_uppercase(),
+        Key::Named(named_key) => format!("{:?}", named_key),
+        other => format!("{:?}", other),
+    }
+}
+
+/// The named keys that can be bound in the config.
+fn parse_named_key(name: &str) -> Option<NamedKey> {
+    let named_key = match name {
+        "ArrowUp" => NamedKey::ArrowUp,
+        "ArrowDown" => NamedKey::ArrowDown,
+        "ArrowLeft" => NamedKey::ArrowLeft,
+        "ArrowRight" => NamedKey::ArrowRight,
+        "Shift" => NamedKey::Shift,
+        "Control" => NamedKey::Control,
+        "Alt" => NamedKey::Alt,
+        "Space" => NamedKey::Space,
+        "Enter" => NamedKey::Enter,
+        "Tab" => NamedKey::Tab,
+        "Escape" => NamedKey::Escape,
+        "Backspace" => NamedKey::Backspace,
+        "Delete" => NamedKey::Delete,
+        "Insert" => NamedKey::Insert,
+        "Home" => NamedKey::Home,
+        "End" => NamedKey::End,
+        "PageUp" => NamedKey::PageUp,
+        "PageDown" => NamedKey::PageDown,
+        "F1" => NamedKey::F1,
+        "F2" => NamedKey::F2,
+        "F3" => NamedKey::F3,
+        "F4" => NamedKey::F4,
+        "F5" => NamedKey::F5,
+        "F6" => NamedKey::F6,
+        "F7" => NamedKey::F7,
+        "F8" => NamedKey::F8,
+        "F9" => NamedKey::F9,
+        "F10" => NamedKey::F10,
+        "F11" => NamedKey::F11,
+        "F12" => NamedKey::F12,
+        _ => return None,
+    };
+    Some(named_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::OscillatorWaveform;
+
+    /// A config binding every category of key, with Q bound to both a note and an action.
+    const FIXTURE: &str = r#"
+keybindings:
+  notes:
+    keys:
+      'Character("a")': 'C'
+      'Character("s")': 'D'
+      'Character("q")': 'E'
+      'Character("x")': 'H'
+  bass_notes:
+    keys:
+      'Character("z")': 'C-1'
+  key_change:
+    keys:
+      'Character("k")': 'G'
+  octave:
+    up: 'Named(ArrowUp)'
+    down: 'Named(ArrowDown)'
+  tremolo:
+    toggle: 'Named(Shift)'
+  shift_behavior: octave_up
+  debug:
+    dump_voices: 'F12'
+  note_names:
+    toggle: 'F1'
+  waveform_cycle:
+    next: 'Named(ArrowRight)'
+    previous: 'Named(ArrowLeft)'
+  waveform_sequence:
+    toggle: 'F2'
+  midi_export:
+    export: 'F3'
+  looper:
+    toggle: 'F4'
+    undo: 'F5'
+    clear: 'F6'
+  mute:
+    toggle: 'Character("q")'
+  wave_shaper:
+    bypass: 'F7'
+  reference_tone:
+    toggle: 'F8'
+  randomizer:
+    random_patch: 'F9'
+  undo:
+    undo: 'Character("u")'
+    redo: 'Character("r")'
+    dump: 'F10'
+  visual_tap:
+    cycle: 'F11'
+  display:
+    gain_up: 'Character("=")'
+    gain_down: 'Character("-")'
+    auto_gain: 'Character("g")'
+  accessibility:
+    high_contrast: 'Character("h")'
+    reduced_motion: 'Character("m")'
+  help:
+    toggle: 'Character("?")'
+action_keys:
+  toggle_notes: {}
+  change_waveform:
+    'Character("1")': Sine
+    'Character("2")': Square
+"#;
+
+    fn fixture() -> ResolvedBindings {
+        ResolvedBindings::from_config(&serde_yaml::from_str(FIXTURE).unwrap())
+    }
+
+    fn key_id(key_str: &str, shift: bool) -> KeyId {
+        KeyId::new(parse_key(key_str).unwrap(), shift)
+    }
+
+    #[test]
+    fn every_action_is_bound_with_and_without_shift() {
+        let bindings = fixture();
+        let actions = [
+            (
+                "Character(\"1\")",
+                NoteEvent::ChangeWaveform(OscillatorWaveform::Sine),
+            ),
+            (
+                "Character(\"2\")",
+                NoteEvent::ChangeWaveform(OscillatorWaveform::Square),
+            ),
+            ("Named(ArrowUp)", NoteEvent::ChangeOctave("up".to_string())),
+            (
+                "Named(ArrowDown)",
+                NoteEvent::ChangeOctave("down".to_string()),
+            ),
+            (
+                "Named(ArrowRight)",
+                NoteEvent::CycleWaveform(CycleDirection::Next),
+            ),
+            (
+                "Named(ArrowLeft)",
+                NoteEvent::CycleWaveform(CycleDirection::Previous),
+            ),
+            ("Named(Shift)", NoteEvent::ToggleTremolo),
+            ("F12", NoteEvent::DumpVoices),
+            ("F1", NoteEvent::ToggleNoteNames),
+            ("F2", NoteEvent::ToggleWaveformSequence),
+            ("F3", NoteEvent::ExportMidi),
+            ("F4", NoteEvent::ToggleLoop),
+            ("F5", NoteEvent::UndoLoopOverdub),
+            ("F6", NoteEvent::ClearLoop),
+            ("Character(\"q\")", NoteEvent::ToggleMute),
+            ("F7", NoteEvent::ToggleWaveShaperBypass),
+            ("F8", NoteEvent::ToggleReferenceTone),
+            ("F9", NoteEvent::RandomPatch),
+            ("Character(\"u\")", NoteEvent::Undo),
+            ("Character(\"r\")", NoteEvent::Redo),
+            ("F10", NoteEvent::DumpUndoHistory),
+            ("F11", NoteEvent::CycleVisualTap),
+            ("Character(\"=\")", NoteEvent::DisplayGainUp),
+            ("Character(\"-\")", NoteEvent::DisplayGainDown),
+            ("Character(\"g\")", NoteEvent::ToggleAutoGain),
+            ("Character(\"h\")", NoteEvent::ToggleHighContrast),
+            ("Character(\"m\")", NoteEvent::ToggleReducedMotion),
+            ("Character(\"?\")", NoteEvent::ToggleHelp),
+        ];
+        for (key_str, event) in actions {
+            for shift in [false, true] {
+                assert_eq!(
+                    bindings.lookup(&key_id(key_str, shift)),
+                    Some(&event),
+                    "{} shift {}",
+                    key_str,
+                    shift
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn notes_resolve_to_their_shifted_variant() {
+        let bindings = fixture();
+        let plain = key_id("Character(\"a\")", false);
+        let shifted = key_id("Character(\"a\")", true);
+        assert_eq!(
+            bindings.lookup(&plain),
+            Some(&NoteEvent::On("C".to_string()))
+        );
+        assert_eq!(
+            bindings.lookup(&shifted),
+            Some(&NoteEvent::On("C+1".to_string()))
+        );
+        assert_eq!(bindings.zone(&plain.key), KeyZone::Lead);
+    }
+
+    #[test]
+    fn bass_notes_are_bound_in_the_bass_zone() {
+        let bindings = fixture();
+        let bass = key_id("Character(\"z\")", false);
+        assert_eq!(
+            bindings.lookup(&bass),
+            Some(&NoteEvent::On("C-1".to_string()))
+        );
+        assert_eq!(bindings.zone(&bass.key), KeyZone::Bass);
+    }
+
+    #[test]
+    fn an_action_wins_over_a_note_on_the_same_key() {
+        let bindings = fixture();
+        let key = key_id("Character(\"q\")", false);
+        assert_eq!(bindings.lookup(&key), Some(&NoteEvent::ToggleMute));
+        assert_eq!(
+            bindings.lookup(&KeyId::new(key.key.clone(), true)),
+            Some(&NoteEvent::ToggleMute)
+        );
+    }
+
+    #[test]
+    fn unknown_notes_and_unbound_keys_look_up_nothing() {
+        let bindings = fixture();
+        assert_eq!(bindings.lookup(&key_id("Character(\"x\")", false)), None);
+        assert_eq!(bindings.lookup(&key_id("Character(\"k\")", false)), None);
+        assert_eq!(bindings.lookup(&key_id("Character(\"w\")", false)), None);
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::synth::NoteEvent;
+
+/// Events kept by default before the oldest are forgotten.
+pub const DEFAULT_EVENT_HISTORY: usize = 256;
+
+/// A note event as the event history keeps it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// When the event was handled, from when the history was created.
+    pub time: Duration,
+    pub event: NoteEvent,
+}
+
+/// The most recent note events, oldest first, for tracking down timing problems and stuck
+/// notes. Holds at most `capacity` of them; each new one past that pushes out the oldest.
+#[derive(Debug)]
+pub struct EventHistory {
+    capacity: usize,
+    started: Instant,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl EventHistory {
+    /// An empty history holding up to `capacity` events. A capacity of 0 keeps none.
+    pub fn new(capacity: usize) -> Self {
+        EventHistory {
+            capacity,
+            started: Instant::now(),
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds `event`, handled now, forgetting the oldest event if the history is full.
+    pub fn record(&mut self, event: &NoteEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(HistoryEntry {
+            time: self.started.elapsed(),
+            event: event.clone(),
+        });
+    }
+
+    /// The events kept, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Logs every event kept, oldest first, with its time in seconds.
+    pub fn log(&self) {
+        info!("Last {} note event(s)", self.entries.len());
+        for entry in &self.entries {
+            info!("{:>10.3}s {:?}", entry.time.as_secs_f64(), entry.event);
+        }
+    }
+}
+
+impl Default for EventHistory {
+    fn default() -> Self {
+        EventHistory::new(DEFAULT_EVENT_HISTORY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(history: &EventHistory) -> Vec<NoteEvent> {
+        history.entries().map(|entry| entry.event.clone()).collect()
+    }
+
+    fn on(note: &str) -> NoteEvent {
+        NoteEvent::On(note.to_string())
+    }
+
+    #[test]
+    fn events_are_kept_in_the_order_they_were_handled() {
+        let mut history = EventHistory::new(4);
+        for event in [on("C"), on("E"), NoteEvent::Off("C".to_string())] {
+            history.record(&event);
+        }
+        assert_eq!(
+            events(&history),
+            [on("C"), on("E"), NoteEvent::Off("C".to_string())]
+        );
+        assert_eq!(history.len(), 3);
+        let times: Vec<Duration> = history.entries().map(|entry| entry.time).collect();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn a_full_history_forgets_the_oldest_event_for_each_new_one() {
+        let mut history = EventHistory::new(4);
+        let notes = ["C", "D", "E", "F", "G", "A"];
+        for (count, note) in notes.iter().enumerate() {
+            history.record(&on(note));
+            assert_eq!(history.len(), (count + 1).min(4));
+        }
+        assert_eq!(events(&history), [on("E"), on("F"), on("G"), on("A")]);
+        assert_eq!(history.capacity(), 4);
+    }
+
+    #[test]
+    fn a_history_of_no_events_keeps_none() {
+        let mut history = EventHistory::new(0);
+        history.record(&on("C"));
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn clearing_empties_the_history_but_keeps_its_size() {
+        let mut history = EventHistory::new(2);
+        history.record(&on("C"));
+        history.clear();
+        assert!(history.is_empty());
+        for note in ["D", "E", "F"] {
+            history.record(&on(note));
+        }
+        assert_eq!(events(&history), [on("E"), on("F")]);
+    }
+}
@@ -0,0 +1,513 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+use tracing::{info, warn};
+
+use crate::synth::{
+    keys::keys::{ActionKeys, KeyBindings},
+    Config,
+};
+
+/// The config sections a keymap file holds.
+pub const KEYMAP_SECTIONS: [&str; 2] = ["keybindings", "action_keys"];
+
+/// A keymap file as `--export-keymap` writes it: the bindings sections and nothing else.
+#[derive(Serialize)]
+struct KeymapFile<'a> {
+    keybindings: &'a KeyBindings,
+    action_keys: &'a ActionKeys,
+}
+
+/// Loads the config at `path`, taking its bindings from a keymap file when there is one.
+///
+/// The keymap is `keymap_override` when given, or else the config's own `keymap:`, which is
+/// relative to the config file's directory. The config's inline `keybindings` and `action_keys`
+/// are merged on top of the keymap's key by key, so an inline entry replaces just that entry
+/// and an inline `null` removes it.
+pub fn load_config(path: &Path, keymap_override: Option<&Path>) -> Result<Config> {
+    info!(
+        "Attempting to open the configuration file: '{}'",
+        path.display()
+    );
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read the config file {}", path.display()))?;
+    let mut config: Value = serde_yaml::from_str(&contents)?;
+
+    let keymap = config.get("keymap").and_then(Value::as_str).map(Path::new);
+    if let Some(keymap_path) = keymap_path(path, keymap, keymap_override) {
+        info!("Loading key bindings from '{}'", keymap_path.display());
+        let contents = fs::read_to_string(&keymap_path)
+            .with_context(|| format!("Unable to read the keymap file {}", keymap_path.display()))?;
+        let keymap: Value = serde_yaml::from_str(&contents).with_context(|| {
+            format!("Unable to parse the keymap file {}", keymap_path.display())
+        })?;
+        apply_keymap(&mut config, keymap);
+    }
+
+    Ok(serde_yaml::from_value(config)?)
+}
+
+/// The keymap file the config at `path` takes its bindings from: `keymap_override` when given,
+/// or else the config's own `keymap`, relative to the config file's directory.
+fn keymap_path(
+    path: &Path,
+    keymap: Option<&Path>,
+    keymap_override: Option<&Path>,
+) -> Option<PathBuf> {
+    match keymap_override {
+        Some(keymap_path) => Some(keymap_path.to_path_buf()),
+        None => keymap.map(|keymap_path| {
+            path.parent()
+                .unwrap_or_else(|| Path::new(""))
+                .join(keymap_path)
+        }),
+    }
+}
+
+/// Watches the config file and the keymap it takes its bindings from, so edits to either are
+/// picked up while running. The files are checked by modification time when polled.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    keymap_override: Option<PathBuf>,
+    /// Each watched file, with its modification time when last checked. None when it couldn't
+    /// be read.
+    watched: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl ConfigWatcher {
+    /// Watches the config at `path`, which `config` was loaded from with `keymap_override`, and
+    /// its keymap.
+    pub fn new(path: &Path, keymap_override: Option<&Path>, config: &Config) -> Self {
+        let mut watcher = ConfigWatcher {
+            path: path.to_path_buf(),
+            keymap_override: keymap_override.map(Path::to_path_buf),
+            watched: Vec::new(),
+        };
+        watcher.watch(config);
+        watcher
+    }
+
+    /// The files being watched: the config, then the keymap if there is one.
+    pub fn watched(&self) -> impl Iterator<Item = &Path> {
+        self.watched.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Loads the config again if either file has changed since the last poll. A config that
+    /// doesn't load is logged and skipped until the files change again.
+    pub fn poll(&mut self) -> Option<Config> {
+        let mut changed = false;
+        for (path, modified) in &mut self.watched {
+            let now = modified_time(path);
+            changed |= now != *modified;
+            *modified = now;
+        }
+        if !changed {
+            return None;
+        }
+        match load_config(&self.path, self.keymap_override.as_deref()) {
+            Ok(config) => {
+                // The reloaded config may name a different keymap.
+                self.watch(&config);
+                Some(config)
+            }
+            Err(err) => {
+                warn!("{:#}; keeping the config already loaded", err);
+                None
+            }
+        }
+    }
+
+    fn watch(&mut self, config: &Config) {
+        let keymap = keymap_path(
+            &self.path,
+            config.keymap.as_deref(),
+            self.keymap_override.as_deref(),
+        );
+        self.watched = std::iter::once(self.path.clone())
+            .chain(keymap)
+            .map(|path| {
+                let modified = modified_time(&path);
+                (path, modified)
+            })
+            .collect();
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Puts `keymap`'s bindings sections into `config`, with whatever `config` already has in them
+/// merged on top.
+pub fn apply_keymap(config: &mut Value, keymap: Value) {
+    let Value::Mapping(config) = config else {
+        return;
+    };
+    for section in KEYMAP_SECTIONS {
+        let Some(mut merged) = keymap.get(section).cloned() else {
+            continue;
+        };
+        // A section left empty in the config overrides nothing.
+        if let Some(overrides) = config
+            .remove(section)
+            .filter(|overrides| !overrides.is_null())
+        {
+            merge_overrides(&mut merged, overrides);
+        }
+        config.insert(Value::from(section), merged);
+    }
+}
+
+/// Merges `overrides` into `base` key by key, all the way down. A `null` removes the key it is
+/// under; anything other than a mapping replaces what was there.
+pub fn merge_overrides(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Mapping(base), Value::Mapping(overrides)) => merge_mappings(base, overrides),
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn merge_mappings(base: &mut Mapping, overrides: Mapping) {
+    for (key, value) in overrides {
+        if value.is_null() {
+            base.remove(&key);
+        } else if let Some(existing) = base.get_mut(&key) {
+            merge_overrides(existing, value);
+        } else {
+            base.insert(key, value);
+        }
+    }
+}
+
+/// Writes `config`'s bindings to `path` as a keymap file another config can load with
+/// `keymap:` or `--keymap`.
+pub fn export_keymap(config: &Config, path: &Path) -> Result<()> {
+    let keymap = KeymapFile {
+        keybindings: &config.keybindings,
+        action_keys: &config.action_keys,
+    };
+    fs::write(path, serde_yaml::to_string(&keymap)?)
+        .with_context(|| format!("Unable to write the keymap file {}", path.display()))?;
+    info!("Wrote the key bindings to '{}'", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    /// An empty directory of its own for `name`, under the system's temporary directory.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("visiosynth-keymap-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn an_override_replaces_adds_and_removes_single_entries() {
+        let mut base = yaml(
+            r#"
+notes:
+  keys:
+    'Character("a")': 'C'
+    'Character("s")': 'D'
+    'Character("d")': 'E'
+octave:
+  up: 'Named(ArrowUp)'
+  down: 'Named(ArrowDown)'
+"#,
+        );
+        let overrides = yaml(
+            r#"
+notes:
+  keys:
+    'Character("s")': 'D_SHARP'
+    'Character("q")': 'B'
+    'Character("d")': null
+octave:
+  up: 'PageUp'
+"#,
+        );
+        merge_overrides(&mut base, overrides);
+        assert_eq!(
+            base,
+            yaml(
+                r#"
+notes:
+  keys:
+    'Character("a")': 'C'
+    'Character("s")': 'D_SHARP'
+    'Character("q")': 'B'
+octave:
+  up: 'PageUp'
+  down: 'Named(ArrowDown)'
+"#
+            )
+        );
+    }
+
+    #[test]
+    fn a_non_mapping_override_replaces_the_whole_value() {
+        let mut base = yaml("keys: {a: C}");
+        merge_overrides(&mut base, yaml("keys: [x]"));
+        assert_eq!(base, yaml("keys: [x]"));
+        let mut base = yaml("plain");
+        merge_overrides(&mut base, yaml("{a: 1}"));
+        assert_eq!(base, yaml("{a: 1}"));
+    }
+
+    #[test]
+    fn the_keymap_fills_the_bindings_sections_under_the_inline_overrides() {
+        let mut config = yaml(
+            r#"
+keybindings:
+  notes:
+    keys:
+      'Character("a")': 'G'
+action_keys: null
+volume: 0.5
+"#,
+        );
+        let keymap = yaml(
+            r#"
+keybindings:
+  notes:
+    keys:
+      'Character("a")': 'C'
+      'Character("s")': 'D'
+action_keys:
+  toggle_notes: {}
+"#,
+        );
+        apply_keymap(&mut config, keymap);
+        assert_eq!(
+            config,
+            yaml(
+                r#"
+keybindings:
+  notes:
+    keys:
+      'Character("a")': 'G'
+      'Character("s")': 'D'
+action_keys:
+  toggle_notes: {}
+volume: 0.5
+"#
+            )
+        );
+        // A keymap without a section leaves the config's own alone.
+        let mut config = yaml("action_keys: {toggle_notes: {}}");
+        apply_keymap(&mut config, yaml("keybindings: {}"));
+        assert_eq!(
+            config,
+            yaml("{action_keys: {toggle_notes: {}}, keybindings: {}}")
+        );
+    }
+
+    /// Loading a keymap all the way to the bindings it resolves to, which needs the windowing
+    /// side's key types.
+    #[cfg(feature = "visualization")]
+    mod loading {
+        use super::*;
+        use crate::synth::keys::bindings::{parse_key, KeyId, ResolvedBindings};
+        use crate::synth::NoteEvent;
+
+        const SETTINGS: &str = "resources/config/settings.yaml";
+
+        /// The bundled settings with the bindings sections taken out, as YAML.
+        fn settings_without_bindings() -> Mapping {
+            let Value::Mapping(mut settings) = yaml(&fs::read_to_string(SETTINGS).unwrap()) else {
+                panic!("the settings aren't a mapping");
+            };
+            for section in KEYMAP_SECTIONS {
+                settings.remove(section);
+            }
+            settings
+        }
+
+        fn event(bindings: &ResolvedBindings, key_str: &str) -> Option<NoteEvent> {
+            bindings
+                .lookup(&KeyId::new(parse_key(key_str).unwrap(), false))
+                .cloned()
+        }
+
+        #[test]
+        fn an_exported_keymap_loads_back_to_the_same_bindings_from_another_directory() {
+            let original = load_config(Path::new(SETTINGS), None).unwrap();
+            let dir = scratch_dir("round-trip");
+            fs::create_dir_all(dir.join("keymaps")).unwrap();
+            export_keymap(&original, &dir.join("keymaps/exported.yaml")).unwrap();
+
+            // The path is taken from the config's directory, not the working one.
+            let mut settings = settings_without_bindings();
+            settings.insert(Value::from("keymap"), Value::from("keymaps/exported.yaml"));
+            let config_path = dir.join("settings.yaml");
+            fs::write(&config_path, serde_yaml::to_string(&settings).unwrap()).unwrap();
+            let loaded = load_config(&config_path, None).unwrap();
+
+            assert_eq!(
+                ResolvedBindings::from_config(&loaded),
+                ResolvedBindings::from_config(&original)
+            );
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn a_keymap_given_on_the_command_line_wins_and_inline_entries_still_apply() {
+            let original = load_config(Path::new(SETTINGS), None).unwrap();
+            let dir = scratch_dir("override");
+            export_keymap(&original, &dir.join("laptop.yaml")).unwrap();
+
+            let mut settings = settings_without_bindings();
+            settings.insert(Value::from("keymap"), Value::from("missing.yaml"));
+            settings.insert(
+                Value::from("keybindings"),
+                yaml(
+                    r#"
+    notes:
+      keys:
+        'Character("a")': 'G'
+        'Character("s")': null
+        'Character("r")': 'B'
+    "#,
+                ),
+            );
+            let config_path = dir.join("settings.yaml");
+            fs::write(&config_path, serde_yaml::to_string(&settings).unwrap()).unwrap();
+            // The config's own keymap doesn't exist, so it has to be the one from the command line.
+            assert!(load_config(&config_path, None).is_err());
+            let loaded = load_config(&config_path, Some(&dir.join("laptop.yaml"))).unwrap();
+            let bindings = ResolvedBindings::from_config(&loaded);
+
+            let note = |note: &str| Some(NoteEvent::On(note.to_string()));
+            assert_eq!(event(&bindings, r#"Character("a")"#), note("G"));
+            assert_eq!(event(&bindings, r#"Character("s")"#), None);
+            assert_eq!(event(&bindings, r#"Character("r")"#), note("B"));
+            assert_eq!(event(&bindings, r#"Character("d")"#), note("E"));
+            assert_eq!(
+                bindings.len(),
+                ResolvedBindings::from_config(&original).len()
+            );
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    mod watching {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        use super::*;
+
+        /// A config with nothing in it but `extra` and empty bindings sections.
+        const SETTINGS: &str = "keybindings:\n  notes:\n    keys: {}\n  bass_notes:\n    keys: {}\n  key_change:\n    keys: {}\n  octave:\n    up: 'Named(ArrowUp)'\n    down: 'Named(ArrowDown)'\n  tremolo:\n    toggle: 'Named(Shift)'\n  shift_behavior: octave_up\naction_keys:\n  toggle_notes: {}\n  change_waveform: {}\n";
+
+        /// Writes `contents` to `path`, dated `seconds` after the epoch so each write is seen
+        /// as a change however coarse the file system's clock.
+        fn write_at(path: &Path, contents: &str, seconds: u64) {
+            fs::write(path, contents).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(seconds))
+                .unwrap();
+        }
+
+        fn settings_with_keymap(keymap: &str) -> String {
+            format!("keymap: {}\n{}", keymap, SETTINGS)
+        }
+
+        fn keymap_playing(note: &str) -> String {
+            format!(
+                "keybindings:\n  notes:\n    keys:\n      'Character(\"a\")': '{}'\n",
+                note
+            )
+        }
+
+        fn note_on_a(config: &Config) -> Option<String> {
+            config
+                .keybindings
+                .notes
+                .keys
+                .get("Character(\"a\")")
+                .cloned()
+        }
+
+        #[test]
+        fn a_change_to_the_config_or_its_keymap_reloads_it_once() {
+            let dir = scratch_dir("watch");
+            let config_path = dir.join("settings.yaml");
+            write_at(&config_path, &settings_with_keymap("keys.yaml"), 1_000);
+            write_at(&dir.join("keys.yaml"), &keymap_playing("C"), 1_000);
+            let config = load_config(&config_path, None).unwrap();
+            let mut watcher = ConfigWatcher::new(&config_path, None, &config);
+            assert_eq!(
+                watcher.watched().collect::<Vec<_>>(),
+                [config_path.as_path(), dir.join("keys.yaml").as_path()]
+            );
+            assert!(watcher.poll().is_none());
+
+            write_at(&dir.join("keys.yaml"), &keymap_playing("D"), 2_000);
+            let reloaded = watcher.poll().unwrap();
+            assert_eq!(note_on_a(&reloaded).as_deref(), Some("D"));
+            assert!(watcher.poll().is_none());
+
+            // The config now takes its bindings from another keymap, which is watched instead.
+            write_at(&dir.join("other.yaml"), &keymap_playing("E"), 3_000);
+            write_at(&config_path, &settings_with_keymap("other.yaml"), 3_000);
+            let reloaded = watcher.poll().unwrap();
+            assert_eq!(note_on_a(&reloaded).as_deref(), Some("E"));
+            assert_eq!(
+                watcher.watched().collect::<Vec<_>>(),
+                [config_path.as_path(), dir.join("other.yaml").as_path()]
+            );
+            write_at(&dir.join("keys.yaml"), &keymap_playing("F"), 4_000);
+            assert!(watcher.poll().is_none());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn a_config_that_fails_to_load_is_skipped_until_it_changes_again() {
+            let dir = scratch_dir("watch-broken");
+            let config_path = dir.join("settings.yaml");
+            write_at(&config_path, SETTINGS, 1_000);
+            let config = load_config(&config_path, None).unwrap();
+            let mut watcher = ConfigWatcher::new(&config_path, None, &config);
+
+            write_at(&config_path, "keybindings: [", 2_000);
+            assert!(watcher.poll().is_none());
+            assert!(watcher.poll().is_none());
+            write_at(&config_path, SETTINGS, 3_000);
+            assert!(watcher.poll().is_some());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn a_keymap_given_on_the_command_line_is_the_one_watched() {
+            let dir = scratch_dir("watch-override");
+            let config_path = dir.join("settings.yaml");
+            write_at(&config_path, &settings_with_keymap("keys.yaml"), 1_000);
+            write_at(&dir.join("laptop.yaml"), &keymap_playing("C"), 1_000);
+            let keymap = dir.join("laptop.yaml");
+            let config = load_config(&config_path, Some(&keymap)).unwrap();
+            let watcher = ConfigWatcher::new(&config_path, Some(&keymap), &config);
+            assert_eq!(
+                watcher.watched().collect::<Vec<_>>(),
+                [config_path.as_path(), keymap.as_path()]
+            );
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -1,29 +1,214 @@
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, trace};
 
-use crate::synth::OscillatorWaveform;
+use crate::synth::{
+    AudioConfig, BusConfig, CpuBudgetConfig, DcBlockerConfig, DiagnosticsConfig, DuckingConfig,
+    EffectsConfig, EnvelopeShape, InitialConfig, KeyboardSplitConfig, LimiterConfig, LooperConfig,
+    MidiExportConfig, MuteConfig, OscillatorConfig, OscillatorWaveform, ParamId, RandomizerConfig,
+    ReferenceToneConfig, RibbonConfig, Score, StartupEvent, TremoloConfig, UndoConfig,
+    WaveShaperConfig, WaveformSequenceConfig,
+};
 
 pub const NOTE_SEQUENCE: [&str; 13] = [
     "C", "C_SHARP", "D", "D_SHARP", "E", "F", "F_SHARP", "G", "G_SHARP", "A", "A_SHARP", "B",
     "C_HIGH",
 ];
 
-#[derive(Debug)]
+/// Splits an optional trailing octave offset off a note name, e.g. `"C+1"` -> `("C", 1)`.
+pub fn split_octave_offset(note: &str) -> (&str, i32) {
+    if let Some(pos) = note.rfind(['+', '-']) {
+        if pos > 0 {
+            if let Ok(offset) = note[pos..].parse::<i32>() {
+                return (&note[..pos], offset);
+            }
+        }
+    }
+    (note, 0)
+}
+
+/// Moves a note by `semitones`, naming the result from `NOTE_SEQUENCE` with an octave offset
+/// suffix when it leaves the base octave. Returns `None` for notes outside `NOTE_SEQUENCE`.
+pub fn transpose_note(note: &str, semitones: i32) -> Option<String> {
+    let (name, octave) = split_octave_offset(note);
+    let index = NOTE_SEQUENCE.iter().position(|&n| n == name)? as i32;
+    let chromatic = index + octave * 12 + semitones;
+    let name = NOTE_SEQUENCE[chromatic.rem_euclid(12) as usize];
+    let octave = chromatic.div_euclid(12);
+    if octave == 0 {
+        Some(name.to_string())
+    } else {
+        Some(format!("{}{:+}", name, octave))
+    }
+}
+
+/// MIDI note number of A4, the 440 Hz reference pitch.
+const A4_MIDI_NOTE: i32 = 69;
+
+/// Parses a note in scientific pitch notation such as `"A4"`, `"C#3"` or `"Db5"` into a MIDI note
+/// number. Accidentals may be `#`/`b` or `♯`/`♭`, and the octave may be negative (`"C-1"` is 0).
+pub fn parse_note_name(note: &str) -> Option<i32> {
+    let mut chars = note.trim().chars();
+    let pitch_class = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next() {
+        Some('#') | Some('♯') => (1, &rest[rest.chars().next()?.len_utf8()..]),
+        Some('b') | Some('♭') => (-1, &rest[rest.chars().next()?.len_utf8()..]),
+        _ => (0, rest),
+    };
+
+    if octave.is_empty() || octave.starts_with('+') {
+        return None;
+    }
+    let octave: i32 = octave.parse().ok()?;
+    Some((octave + 1) * 12 + pitch_class + accidental)
+}
+
+/// Names a MIDI note number in scientific pitch notation, spelling accidentals as sharps.
+pub fn midi_note_to_name(midi_note: i32) -> String {
+    const PITCH_CLASSES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!(
+        "{}{}",
+        PITCH_CLASSES[midi_note.rem_euclid(12) as usize],
+        midi_note.div_euclid(12) - 1
+    )
+}
+
+/// The MIDI note number closest to `frequency`, or `None` for frequencies that aren't positive.
+pub fn frequency_to_midi_note(frequency: f32) -> Option<i32> {
+    (frequency.is_finite() && frequency > 0.0)
+        .then(|| (A4_MIDI_NOTE as f32 + 12.0 * (frequency / 440.0).log2()).round() as i32)
+}
+
+/// Frequency of a MIDI note number in twelve-tone equal temperament with A4 at 440 Hz.
+pub fn midi_note_frequency(midi_note: i32) -> f32 {
+    440.0 * 2.0f32.powf((midi_note - A4_MIDI_NOTE) as f32 / 12.0)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum NoteEvent {
     On(String),
     Off(String),
     ChangeWaveform(OscillatorWaveform),
     ChangeOctave(String),
+    CycleWaveform(CycleDirection),
     ToggleTremolo,
     ChangeKey(String),
+    DumpVoices,
+    ToggleNoteNames,
+    ToggleWaveformSequence,
+    ExportMidi,
+    ToggleLoop,
+    UndoLoopOverdub,
+    ClearLoop,
+    ToggleMute,
+    ToggleWaveShaperBypass,
+    ToggleReferenceTone,
+    RandomPatch,
+    Undo,
+    Redo,
+    DumpUndoHistory,
+    CycleVisualTap,
+    DisplayGainUp,
+    DisplayGainDown,
+    ToggleAutoGain,
+    ToggleHighContrast,
+    ToggleReducedMotion,
+    ToggleHelp,
+    /// The lead zone's envelope, as edited on screen.
+    SetEnvelope(EnvelopeShape),
+    /// Sets a parameter, as `SynthEngine::set_param` does, after the next block.
+    SetParam(ParamId, f32),
+    RibbonStart {
+        normalized_x: f32,
+    },
+    RibbonMove {
+        normalized_x: f32,
+    },
+    RibbonEnd {
+        normalized_x: f32,
+    },
+}
+
+/// Which way a cycling key steps through its list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CycleDirection {
+    Next,
+    Previous,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// A keymap file the bindings are loaded from, relative to this file's directory. The
+    /// `keybindings` and `action_keys` here are then merged on top of it, entry by entry.
+    #[serde(default)]
+    pub keymap: Option<PathBuf>,
     pub keybindings: KeyBindings,
     pub action_keys: ActionKeys,
+    #[serde(default)]
+    pub ribbon: RibbonConfig,
+    #[serde(default)]
+    pub oscillator: OscillatorConfig,
+    #[serde(default)]
+    pub diagnostics: DiagnosticsConfig,
+    #[serde(default)]
+    pub audio: AudioConfig,
+    #[serde(default)]
+    pub waveform_sequence: WaveformSequenceConfig,
+    #[serde(default)]
+    pub midi_export: MidiExportConfig,
+    #[serde(default)]
+    pub wave_shaper: WaveShaperConfig,
+    #[serde(default)]
+    pub tremolo: TremoloConfig,
+    #[serde(default)]
+    pub keyboard_split: KeyboardSplitConfig,
+    #[serde(default)]
+    pub ducking: DuckingConfig,
+    #[serde(default)]
+    pub looper: LooperConfig,
+    #[serde(default)]
+    pub mute: MuteConfig,
+    #[serde(default)]
+    pub effects: EffectsConfig,
+    #[serde(default)]
+    pub reference_tone: ReferenceToneConfig,
+    #[serde(default)]
+    pub randomizer: RandomizerConfig,
+    #[serde(default)]
+    pub undo: UndoConfig,
+    #[serde(default)]
+    pub dc_blocker: DcBlockerConfig,
+    #[serde(default)]
+    pub limiter: LimiterConfig,
+    #[serde(default)]
+    pub cpu_budget: CpuBudgetConfig,
+    /// Buses voices can be mixed on before the master chain, besides the main bus.
+    #[serde(default)]
+    pub buses: Vec<BusConfig>,
+    #[serde(default)]
+    pub initial: InitialConfig,
+    /// Settings applied in order when the synth starts.
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub on_startup: Vec<StartupEvent>,
+    /// Score played live with `--demo`, in the same format as `--render` takes.
+    #[serde(default)]
+    pub demo: Score,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,6 +218,88 @@ pub struct KeyBindings {
     pub bass_notes: BassNoteKeys,
     pub key_change: KeyChangeKeys,
     pub tremolo: TremoloKeys,
+    #[serde(default)]
+    pub shift_behavior: ShiftBehavior,
+    /// Treat keys playing the same pitch as one note, so releasing any of them stops it. Off by
+    /// default, where each key's note starts and stops on its own.
+    #[serde(default)]
+    pub merge_unison_notes: bool,
+    #[serde(default)]
+    pub notes_shifted: Option<NoteKeys>,
+    #[serde(default)]
+    pub debug: Option<DebugKeys>,
+    #[serde(default)]
+    pub note_names: Option<NoteNameKeys>,
+    #[serde(default)]
+    pub waveform_cycle: Option<WaveformCycleKeys>,
+    #[serde(default)]
+    pub waveform_sequence: Option<WaveformSequenceKeys>,
+    #[serde(default)]
+    pub midi_export: Option<MidiExportKeys>,
+    #[serde(default)]
+    pub looper: Option<LooperKeys>,
+    #[serde(default)]
+    pub mute: Option<MuteKeys>,
+    #[serde(default)]
+    pub wave_shaper: Option<WaveShaperKeys>,
+    #[serde(default)]
+    pub reference_tone: Option<ReferenceToneKeys>,
+    #[serde(default)]
+    pub randomizer: Option<RandomizerKeys>,
+    #[serde(default)]
+    pub undo: Option<UndoKeys>,
+    #[serde(default)]
+    pub visual_tap: Option<VisualTapKeys>,
+    #[serde(default)]
+    pub display: Option<DisplayKeys>,
+    #[serde(default)]
+    pub accessibility: Option<AccessibilityKeys>,
+    #[serde(default)]
+    pub help: Option<HelpKeys>,
+}
+
+impl KeyBindings {
+    /// Resolves the note bound to `key`, applying `shift_behavior` when Shift is held.
+    pub fn resolve_note(&self, key: &str, shift_pressed: bool) -> Option<String> {
+        if shift_pressed && self.shift_behavior == ShiftBehavior::SeparateBinding {
+            if let Some(note) = self
+                .notes_shifted
+                .as_ref()
+                .and_then(|shifted| shifted.keys.get(key))
+            {
+                return Some(note.clone());
+            }
+        }
+
+        let note = self
+            .notes
+            .keys
+            .get(key)
+            .or_else(|| self.bass_notes.keys.get(key))?;
+
+        let semitones = match (shift_pressed, self.shift_behavior) {
+            (true, ShiftBehavior::OctaveUp) => 12,
+            (true, ShiftBehavior::Sharp) => 1,
+            _ => 0,
+        };
+        if semitones == 0 {
+            return Some(note.clone());
+        }
+        Some(transpose_note(note, semitones).unwrap_or_else(|| note.clone()))
+    }
+}
+
+/// What holding Shift does to a note key.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShiftBehavior {
+    /// Play the same note one octave higher.
+    #[default]
+    OctaveUp,
+    /// Raise the note by a semitone.
+    Sharp,
+    /// Look the key up in `notes_shifted` instead.
+    SeparateBinding,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,6 +307,107 @@ pub struct TremoloKeys {
     pub toggle: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NoteNameKeys {
+    pub toggle: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaveformCycleKeys {
+    pub next: String,
+    pub previous: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaveformSequenceKeys {
+    pub toggle: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MidiExportKeys {
+    /// Writes everything played so far to `midi_export.path`.
+    pub export: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LooperKeys {
+    /// Starts recording, then closes the loop and plays it, then stops and restarts it.
+    pub toggle: String,
+    /// Drops the most recent overdub pass.
+    pub undo: String,
+    pub clear: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MuteKeys {
+    /// Fades the output out, or back in to where it was.
+    pub toggle: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaveShaperKeys {
+    /// Takes the wave shaper out of the chain, so the oscillators are heard as they mix, or puts
+    /// it back.
+    pub bypass: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReferenceToneKeys {
+    /// Fades the reference tone in, or back out.
+    pub toggle: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RandomizerKeys {
+    /// Draws a random patch and switches to it.
+    pub random_patch: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UndoKeys {
+    /// Puts back the parameters as they were before the last change.
+    pub undo: String,
+    /// Makes the last undone change again.
+    pub redo: String,
+    /// Logs every change there is to undo and redo.
+    pub dump: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VisualTapKeys {
+    /// Steps the visualizer through the pre-effects mix, the output and both at once.
+    pub cycle: String,
+}
+
+/// Keys for the waveform display's vertical scaling.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DisplayKeys {
+    pub gain_up: String,
+    pub gain_down: String,
+    /// Turns auto-gain, which keeps quiet and loud passages both visible, on or off.
+    pub auto_gain: String,
+}
+
+/// Keys switching the accessibility options while playing.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessibilityKeys {
+    pub high_contrast: String,
+    pub reduced_motion: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelpKeys {
+    /// Shows or hides the list of key bindings.
+    pub toggle: String,
+}
+
+/// Keys for developer diagnostics.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DebugKeys {
+    /// Logs a table of every active voice.
+    pub dump_voices: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WaveformKeys {
     pub keys: HashMap<String, OscillatorWaveform>,
@@ -109,23 +477,280 @@ impl Scale {
         // Optionally adjust intervals if changing modes
     }
 
+    /// Calculates the frequency of `note`, given either as a `NOTE_SEQUENCE` name with an optional
+    /// octave offset (`"C_SHARP"`, `"A-1"`) or in scientific pitch notation (`"C#3"`). Names that
+    /// fit both readings, like `"A-1"`, are taken as `NOTE_SEQUENCE` names.
     pub fn calculate_frequency(&self, note: &str) -> Option<f32> {
-        let a4_index = 12; // A4 is the 13th note in the sequence (including accidentals), but index 12 in a 0-indexed array
+        // The bare names are the octave up from middle C, so "A" is A4.
+        let a4_index = NOTE_SEQUENCE.iter().position(|&n| n == "A").unwrap_or(9);
         let a4_frequency = 440.0;
 
-        debug!("Calculating frequency for note: {}", note);
+        trace!("Calculating frequency for note: {}", note);
 
-        if let Some(note_index) = NOTE_SEQUENCE.iter().position(|&n| n == note.to_uppercase()) {
-            let semitone_distance = note_index as i32 - a4_index as i32;
+        let (name, octave_offset) = split_octave_offset(note);
+        if let Some(note_index) = NOTE_SEQUENCE.iter().position(|&n| n == name.to_uppercase()) {
+            let semitone_distance = note_index as i32 + octave_offset * 12 - a4_index as i32;
             let frequency = a4_frequency * (2.0f32).powf(semitone_distance as f32 / 12.0);
-            debug!(
+            trace!(
                 "Note index: {}, Semitone distance: {}, Frequency: {}",
-                note_index, semitone_distance, frequency
+                note_index,
+                semitone_distance,
+                frequency
             );
             Some(frequency)
+        } else if let Some(midi_note) = parse_note_name(note) {
+            let frequency = midi_note_frequency(midi_note);
+            trace!("MIDI note: {}, Frequency: {}", midi_note, frequency);
+            Some(frequency)
         } else {
-            debug!("Note not found in sequence: {}", note);
+            trace!("Note not found in sequence: {}", note);
             None
         }
     }
+
+    /// Checks `calculate_frequency` for every `NOTE_SEQUENCE` name at each of `octave_offsets`
+    /// against twelve-tone equal temperament with A4 at 440 Hz, and returns the notes more than
+    /// `tolerance_cents` out. Each name's expected pitch is read from its scientific pitch
+    /// spelling, so it doesn't share `calculate_frequency`'s indexing into `NOTE_SEQUENCE`.
+    pub fn check_tuning(
+        &self,
+        octave_offsets: RangeInclusive<i32>,
+        tolerance_cents: f32,
+    ) -> Vec<TuningError> {
+        let mut errors = Vec::new();
+        for octave_offset in octave_offsets {
+            for name in NOTE_SEQUENCE {
+                let note = match octave_offset {
+                    0 => name.to_string(),
+                    offset => format!("{}{:+}", name, offset),
+                };
+                let (pitch, octave) = match name.strip_suffix("_HIGH") {
+                    Some(pitch) => (pitch, 5 + octave_offset),
+                    None => (name, 4 + octave_offset),
+                };
+                let spelled = format!("{}{}", pitch.replace("_SHARP", "#"), octave);
+                let Some(expected) = parse_note_name(&spelled).map(midi_note_frequency) else {
+                    continue;
+                };
+                let actual = self.calculate_frequency(&note);
+                let in_tune = actual.is_some_and(|actual| {
+                    (1200.0 * (actual / expected).log2()).abs() <= tolerance_cents
+                });
+                if !in_tune {
+                    errors.push(TuningError {
+                        note,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// A note `Scale::check_tuning` found out of tune.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuningError {
+    pub note: String,
+    /// Its frequency in twelve-tone equal temperament, in Hz.
+    pub expected: f32,
+    /// What `calculate_frequency` gave, if anything.
+    pub actual: Option<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_bindings(shift_behavior: &str) -> KeyBindings {
+        serde_yaml::from_str(&format!(
+            r#"
+notes:
+  keys:
+    'Character("a")': 'C'
+    'Character("k")': 'B'
+bass_notes:
+  keys:
+    'Character("z")': 'C-1'
+key_change:
+  keys: {{}}
+octave:
+  up: 'Named(ArrowUp)'
+  down: 'Named(ArrowDown)'
+tremolo:
+  toggle: 'Named(Shift)'
+shift_behavior: {}
+notes_shifted:
+  keys:
+    'Character("a")': 'G'
+"#,
+            shift_behavior
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn unshifted_notes_are_the_same_in_every_mode() {
+        for mode in ["octave_up", "sharp", "separate_binding"] {
+            let bindings = key_bindings(mode);
+            assert_eq!(
+                bindings.resolve_note("Character(\"a\")", false).as_deref(),
+                Some("C")
+            );
+            assert_eq!(
+                bindings.resolve_note("Character(\"z\")", false).as_deref(),
+                Some("C-1")
+            );
+        }
+    }
+
+    #[test]
+    fn octave_up_plays_an_octave_higher() {
+        let bindings = key_bindings("octave_up");
+        assert_eq!(bindings.shift_behavior, ShiftBehavior::OctaveUp);
+        assert_eq!(
+            bindings.resolve_note("Character(\"a\")", true).as_deref(),
+            Some("C+1")
+        );
+        // Shifting a note already below the base octave brings it back into it.
+        assert_eq!(
+            bindings.resolve_note("Character(\"z\")", true).as_deref(),
+            Some("C")
+        );
+    }
+
+    #[test]
+    fn sharp_raises_a_semitone_and_carries_into_the_next_octave() {
+        let bindings = key_bindings("sharp");
+        assert_eq!(
+            bindings.resolve_note("Character(\"a\")", true).as_deref(),
+            Some("C_SHARP")
+        );
+        assert_eq!(
+            bindings.resolve_note("Character(\"k\")", true).as_deref(),
+            Some("C+1")
+        );
+    }
+
+    #[test]
+    fn separate_binding_reads_notes_shifted() {
+        let bindings = key_bindings("separate_binding");
+        assert_eq!(
+            bindings.resolve_note("Character(\"a\")", true).as_deref(),
+            Some("G")
+        );
+        // Keys missing from `notes_shifted` play their plain note.
+        assert_eq!(
+            bindings.resolve_note("Character(\"k\")", true).as_deref(),
+            Some("B")
+        );
+    }
+
+    #[test]
+    fn unbound_keys_resolve_to_nothing() {
+        let bindings = key_bindings("octave_up");
+        assert_eq!(bindings.resolve_note("Character(\"q\")", false), None);
+        assert_eq!(bindings.resolve_note("Character(\"q\")", true), None);
+    }
+
+    #[test]
+    fn transpose_note_names_the_octave_offset() {
+        assert_eq!(transpose_note("B", 1).as_deref(), Some("C+1"));
+        assert_eq!(transpose_note("C", -1).as_deref(), Some("B-1"));
+        assert_eq!(transpose_note("A-1", 12).as_deref(), Some("A"));
+        assert_eq!(transpose_note("C_HIGH", 0).as_deref(), Some("C+1"));
+        assert_eq!(transpose_note("H", 1), None);
+    }
+
+    fn scale() -> Scale {
+        Scale {
+            root_note: "C".to_string(),
+            intervals: vec![2, 2, 1, 2, 2, 2, 1],
+        }
+    }
+
+    #[test]
+    fn a4_is_440_hz_and_a5_an_octave_up() {
+        let scale = scale();
+        assert_eq!(scale.calculate_frequency("A4"), Some(440.0));
+        assert_eq!(scale.calculate_frequency("A5"), Some(880.0));
+        assert_eq!(scale.calculate_frequency("A3"), Some(220.0));
+    }
+
+    #[test]
+    fn note_names_parse_accidentals_and_octaves() {
+        assert_eq!(parse_note_name("A4"), Some(69));
+        assert_eq!(parse_note_name("C#3"), Some(49));
+        assert_eq!(parse_note_name("Db5"), Some(73));
+        assert_eq!(parse_note_name("C♯3"), parse_note_name("C#3"));
+        assert_eq!(parse_note_name("D♭5"), parse_note_name("Db5"));
+        assert_eq!(parse_note_name("c-1"), Some(0));
+        assert_eq!(parse_note_name("A"), None);
+        assert_eq!(parse_note_name("A+1"), None);
+        assert_eq!(parse_note_name("H4"), None);
+    }
+
+    #[test]
+    fn bare_names_keep_their_meaning_beside_octave_numbers() {
+        let scale = scale();
+        assert_eq!(scale.calculate_frequency("A"), Some(440.0));
+        assert_eq!(scale.calculate_frequency("A-1"), Some(220.0));
+        assert_eq!(
+            scale.calculate_frequency("C_SHARP"),
+            scale.calculate_frequency("C#4")
+        );
+        assert_eq!(scale.calculate_frequency("nonsense"), None);
+    }
+
+    #[test]
+    fn midi_note_names_round_trip() {
+        for midi_note in [0, 49, 60, 69, 127] {
+            assert_eq!(
+                parse_note_name(&midi_note_to_name(midi_note)),
+                Some(midi_note)
+            );
+        }
+        assert_eq!(frequency_to_midi_note(440.0), Some(69));
+        assert_eq!(frequency_to_midi_note(0.0), None);
+    }
+
+    #[test]
+    fn the_reference_notes_match_equal_temperament_in_both_spellings() {
+        let scale = scale();
+        for (name, spelled, hz) in [
+            ("C", "C4", 261.6256),
+            ("E", "E4", 329.6276),
+            ("A", "A4", 440.0),
+            ("C_HIGH", "C5", 523.2511),
+            ("C+1", "C5", 523.2511),
+        ] {
+            for note in [name, spelled] {
+                let frequency = scale.calculate_frequency(note).unwrap();
+                assert!(
+                    (frequency - hz).abs() < 0.01,
+                    "{} is {} Hz",
+                    note,
+                    frequency
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_note_is_in_tune_across_the_keyboard() {
+        assert_eq!(scale().check_tuning(-4..=4, 0.5), Vec::new());
+        // Octave offsets go up and down in whole octaves.
+        let scale = scale();
+        for offset in -3..=3 {
+            let a = scale
+                .calculate_frequency(&format!("A{:+}", offset))
+                .unwrap();
+            assert!(
+                (a - 440.0 * 2f32.powi(offset)).abs() < 1e-3 * a,
+                "{}",
+                offset
+            );
+        }
+    }
 }
@@ -1,2 +1,6 @@
+#[cfg(feature = "visualization")]
+pub mod bindings;
+pub mod event_history;
+pub mod keymap;
 pub mod keys;
 pub mod note_state;
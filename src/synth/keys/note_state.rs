@@ -1,12 +1,89 @@
 use std::sync::{Arc, Mutex, RwLock};
 
-use crate::synth::{NoteEvent, Oscillator, OscillatorWaveform, Scale, TremoloEffect};
+use tracing::info;
+#[cfg(feature = "visualization")]
+use winit::keyboard::PhysicalKey;
+
+use crate::synth::{
+    keys::event_history::EventHistory, looper::LoopCommand, DeviceReport, EnvelopeShape, KeyZone,
+    Looper, NoteEvent, Oscillator, OscillatorWaveform, ParamId, PerformanceLog, Scale,
+    TremoloEffect, UndoStep, VisualTap, VoiceInfo,
+};
+
+/// What is holding a note down.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NoteSource {
+    /// Played by name, as the demo, scripts, offline rendering and the C interface do. All
+    /// presses of a pitch from here are one note, which keeps sounding until each of them has
+    /// been released, so two inputs playing the same pitch can't cut each other off.
+    Shared,
+    /// Held on a physical key. Two keys bound to the same pitch play two voices that start
+    /// and stop on their own.
+    #[cfg(feature = "visualization")]
+    Key(PhysicalKey),
+}
+
+/// One held note: its pitch and what is holding it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NoteId {
+    pub note: String,
+    pub source: NoteSource,
+}
+
+impl NoteId {
+    pub fn new(note: String, source: NoteSource) -> Self {
+        NoteId { note, source }
+    }
+
+    /// A note played by name, shared by everything else that plays the same name.
+    pub fn shared(note: String) -> Self {
+        NoteId::new(note, NoteSource::Shared)
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct NoteState {
-    pub playing_notes: std::collections::HashMap<String, bool>,
-    pub activation_order: std::collections::HashMap<String, usize>,
+    /// How many presses are holding each note. A note is dropped from here once the last of
+    /// them is released.
+    pub playing_notes: std::collections::HashMap<NoteId, usize>,
+    pub activation_order: std::collections::HashMap<NoteId, usize>,
     pub oscillators: Vec<Oscillator>,
+    /// Where the ribbon is being touched, from 0.0 at its left edge to 1.0 at its right.
+    pub ribbon_touch: Option<f32>,
+    /// Whether the waveform steps through the configured waveform sequence.
+    pub waveform_sequence_enabled: bool,
+    /// The most recent events handled, for tracking down timing problems and stuck notes. The
+    /// voice dump logs it too.
+    pub event_history: EventHistory,
+    /// Every note the engine has started and stopped, for MIDI export.
+    pub performance_log: PerformanceLog,
+    /// The output device's capabilities, logged with the voice dump.
+    pub device_report: Option<DeviceReport>,
+    /// Records the notes played and repeats them; the engine drives it every block.
+    pub looper: Looper,
+    /// Velocities of notes started with a velocity; other notes play at `DEFAULT_VELOCITY`.
+    pub note_velocities: std::collections::HashMap<NoteId, u8>,
+    /// Keyboard zones of notes played from the bass zone; other notes are in the lead zone.
+    pub note_zones: std::collections::HashMap<NoteId, KeyZone>,
+    /// Whether the mute key has the output faded down.
+    pub muted: bool,
+    /// Whether the wave shaper is taken out of the chain, leaving the raw oscillator mix.
+    pub wave_shaper_bypassed: bool,
+    /// Whether the reference tone key has the tone sounding.
+    pub reference_tone: bool,
+    /// Whether the random patch key was pressed since the last block; the engine draws the
+    /// patch.
+    pub random_patch_requested: bool,
+    /// Values sent by `SetParam` events since the last block, in order; the engine sets them
+    /// after the block, gliding smoothed parameters to them.
+    pub param_requests: Vec<(ParamId, f32)>,
+    /// Random patches the engine has set, with each parameter's value before, waiting for the
+    /// window's undo history to take them.
+    pub random_patches: Vec<UndoStep>,
+    /// Where the visualizer's audio is taken from; the engine picks it up at the next block.
+    pub visual_tap: VisualTap,
+    /// An envelope edited on screen, waiting for the engine to take it up for new lead notes.
+    pub envelope_edit: Option<EnvelopeShape>,
 }
 
 impl NoteState {
@@ -15,6 +92,22 @@ impl NoteState {
             playing_notes: std::collections::HashMap::new(),
             activation_order: std::collections::HashMap::new(),
             oscillators: Vec::new(),
+            ribbon_touch: None,
+            waveform_sequence_enabled: false,
+            event_history: EventHistory::default(),
+            performance_log: PerformanceLog::default(),
+            device_report: None,
+            looper: Looper::default(),
+            note_velocities: std::collections::HashMap::new(),
+            note_zones: std::collections::HashMap::new(),
+            muted: false,
+            wave_shaper_bypassed: false,
+            reference_tone: false,
+            random_patch_requested: false,
+            param_requests: Vec::new(),
+            random_patches: Vec::new(),
+            visual_tap: VisualTap::default(),
+            envelope_edit: None,
         }
     }
 
@@ -34,6 +127,7 @@ impl NoteState {
         scale: &Arc<Mutex<Scale>>,
     ) {
         println!("Event: {:?}", event);
+        self.event_history.record(&event);
         match event {
             NoteEvent::On(note) => self.note_on(note),
             NoteEvent::Off(note) => self.note_off(note),
@@ -42,6 +136,12 @@ impl NoteState {
                     *waveform_type = waveform;
                 }
             }
+            NoteEvent::CycleWaveform(direction) => {
+                if let Ok(mut waveform_type) = waveform_type.write() {
+                    *waveform_type = waveform_type.cycled(direction);
+                    info!("Waveform: {}", waveform_type.name());
+                }
+            }
             NoteEvent::ChangeOctave(direction) => self.change_octave(direction),
             NoteEvent::ToggleTremolo => {
                 tremolo_effect.toggle();
@@ -50,6 +150,103 @@ impl NoteState {
                 let mut scale = scale.lock().unwrap();
                 scale.change_root_note(new_key);
             }
+            NoteEvent::RibbonStart { normalized_x } => self.ribbon_touch = Some(normalized_x),
+            NoteEvent::RibbonMove { normalized_x } => {
+                if self.ribbon_touch.is_some() {
+                    self.ribbon_touch = Some(normalized_x);
+                }
+            }
+            NoteEvent::RibbonEnd { .. } => self.ribbon_touch = None,
+            NoteEvent::SetEnvelope(envelope) => self.envelope_edit = Some(envelope),
+            NoteEvent::DumpVoices => {
+                self.dump_voices();
+                self.event_history.log();
+                if let Some(device_report) = &self.device_report {
+                    device_report.log();
+                }
+            }
+            NoteEvent::ToggleWaveformSequence => {
+                self.waveform_sequence_enabled = !self.waveform_sequence_enabled;
+                info!(
+                    "Waveform sequence {}",
+                    if self.waveform_sequence_enabled {
+                        "on"
+                    } else {
+                        "off"
+                    }
+                );
+            }
+            NoteEvent::ToggleMute => {
+                self.muted = !self.muted;
+                info!("Output {}", if self.muted { "muted" } else { "unmuted" });
+            }
+            NoteEvent::ToggleWaveShaperBypass => {
+                self.wave_shaper_bypassed = !self.wave_shaper_bypassed;
+                info!(
+                    "Wave shaper {}",
+                    if self.wave_shaper_bypassed {
+                        "bypassed"
+                    } else {
+                        "on"
+                    }
+                );
+            }
+            NoteEvent::ToggleReferenceTone => {
+                self.reference_tone = !self.reference_tone;
+                info!(
+                    "Reference tone {}",
+                    if self.reference_tone { "on" } else { "off" }
+                );
+            }
+            NoteEvent::RandomPatch => self.random_patch_requested = true,
+            NoteEvent::SetParam(param, value) => self.param_requests.push((param, value)),
+            NoteEvent::CycleVisualTap => {
+                self.visual_tap = self.visual_tap.next();
+                info!("Visualizing the {} signal", self.visual_tap.label());
+            }
+            NoteEvent::ToggleLoop => self.looper.request(LoopCommand::Toggle),
+            NoteEvent::UndoLoopOverdub => self.looper.request(LoopCommand::UndoOverdub),
+            NoteEvent::ClearLoop => self.looper.request(LoopCommand::Clear),
+            // The window handles these.
+            NoteEvent::ToggleHelp
+            | NoteEvent::ToggleNoteNames
+            | NoteEvent::ExportMidi
+            | NoteEvent::DisplayGainUp
+            | NoteEvent::DisplayGainDown
+            | NoteEvent::ToggleAutoGain
+            | NoteEvent::ToggleHighContrast
+            | NoteEvent::ToggleReducedMotion
+            | NoteEvent::Undo
+            | NoteEvent::Redo
+            | NoteEvent::DumpUndoHistory => (),
+        }
+    }
+
+    /// Collects debug info for every voice, in the order they were started.
+    pub fn voice_debug_info(&self) -> Vec<VoiceInfo> {
+        self.oscillators
+            .iter()
+            .map(Oscillator::voice_debug_info)
+            .collect()
+    }
+
+    /// Logs a table of every voice.
+    pub fn dump_voices(&self) {
+        let voices = self.voice_debug_info();
+        info!("{} active voice(s)", voices.len());
+        info!(
+            "{:<12} {:>10} {:<9} {:>9} {:>12}",
+            "note", "freq", "stage", "amplitude", "age_samples"
+        );
+        for voice in voices.iter() {
+            info!(
+                "{:<12} {:>10.2} {:<9} {:>9.3} {:>12}",
+                voice.note,
+                voice.freq,
+                format!("{:?}", voice.envelope_stage),
+                voice.amplitude,
+                voice.age_samples
+            );
         }
     }
 
@@ -61,30 +258,179 @@ impl NoteState {
         };
 
         for oscillator in self.oscillators.iter_mut() {
-            let new_frequency = oscillator.get_frequency() * 2.0f32.powf(octave_shift as f32);
-            oscillator.set_frequency(new_frequency);
+            let new_frequency = oscillator.target_frequency() * 2.0f32.powf(octave_shift as f32);
+            oscillator.glide_to(new_frequency);
         }
     }
 
     pub fn note_on(&mut self, note: String) {
-        // info!("Note on: {}", note);
-        self.playing_notes.insert(note, true);
+        self.start_note(NoteId::shared(note), None);
+    }
+
+    /// Starts `note` struck at a MIDI `velocity` from 1 to 127.
+    pub fn note_on_with_velocity(&mut self, note: String, velocity: u8) {
+        self.start_note(NoteId::shared(note), Some(velocity));
     }
 
     pub fn note_off(&mut self, note: String) {
-        // info!("Note off: {}", note);
-        self.playing_notes.insert(note, false);
+        self.stop_note(&NoteId::shared(note));
+    }
+
+    /// Starts the note `id`, struck at a MIDI `velocity` from 1 to 127, or at
+    /// `DEFAULT_VELOCITY` without one.
+    pub fn start_note(&mut self, id: NoteId, velocity: Option<u8>) {
+        self.start_note_in_zone(id, velocity, KeyZone::Lead);
+    }
+
+    /// Starts the note `id` like `start_note`, with the preset of keyboard zone `zone`.
+    pub fn start_note_in_zone(&mut self, id: NoteId, velocity: Option<u8>, zone: KeyZone) {
+        match zone {
+            KeyZone::Lead => self.note_zones.remove(&id),
+            zone => self.note_zones.insert(id.clone(), zone),
+        };
+        match velocity {
+            Some(velocity) => {
+                self.note_velocities
+                    .insert(id.clone(), velocity.clamp(1, 127));
+            }
+            None => {
+                self.note_velocities.remove(&id);
+            }
+        }
+        *self.playing_notes.entry(id).or_insert(0) += 1;
+    }
+
+    /// Releases one press of the note `id`. The note stops once every press of it has been
+    /// released, and notes of the same pitch held by something else keep sounding.
+    pub fn stop_note(&mut self, id: &NoteId) {
+        if let Some(holds) = self.playing_notes.get_mut(id) {
+            *holds = holds.saturating_sub(1);
+            if *holds == 0 {
+                self.playing_notes.remove(id);
+            }
+        }
     }
 
+    /// Stops the note `id` however many presses are holding it, as when its voice is stolen.
+    pub fn release_note(&mut self, id: &NoteId) {
+        self.playing_notes.remove(id);
+    }
+
+    /// Whether anything is holding `note`.
     pub fn is_playing(&self, note: &String) -> bool {
-        *self.playing_notes.get(note).unwrap_or(&false)
+        self.playing_notes
+            .iter()
+            .any(|(id, holds)| *holds > 0 && id.note == *note)
     }
 
     pub fn find_active_note(&self) -> Option<String> {
         self.playing_notes
             .iter()
-            .filter(|(_note, &is_playing)| is_playing)
-            .max_by_key(|(note, _)| self.activation_order.get(*note))
-            .map(|(note, _)| note.clone())
+            .filter(|(_id, &holds)| holds > 0)
+            .max_by_key(|(id, _)| self.activation_order.get(*id))
+            .map(|(id, _)| id.note.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands `event` to `note_state` with a default waveform, tremolo and scale.
+    fn handle(note_state: &mut NoteState, event: NoteEvent) {
+        let waveform_type = Arc::new(RwLock::new(OscillatorWaveform::Sine));
+        let tremolo_effect = Arc::new(TremoloEffect::builder().build(48_000.0));
+        let scale = Arc::new(Mutex::new(Scale {
+            root_note: "C".to_string(),
+            intervals: vec![2, 2, 1, 2, 2, 2, 1],
+        }));
+        note_state.handle_event(event, &waveform_type, &tremolo_effect, &scale);
+    }
+
+    #[test]
+    fn every_event_handled_goes_into_the_history_in_order_up_to_its_size() {
+        let mut note_state = NoteState::new();
+        note_state.event_history = EventHistory::new(3);
+        let events = [
+            NoteEvent::On("C".to_string()),
+            NoteEvent::ToggleTremolo,
+            NoteEvent::On("E".to_string()),
+            NoteEvent::Off("C".to_string()),
+            NoteEvent::Off("E".to_string()),
+        ];
+        for (count, event) in events.iter().enumerate() {
+            handle(&mut note_state, event.clone());
+            let kept: Vec<NoteEvent> = note_state
+                .event_history
+                .entries()
+                .map(|entry| entry.event.clone())
+                .collect();
+            let first = (count + 1).saturating_sub(3);
+            assert_eq!(kept, events[first..=count]);
+        }
+    }
+
+    #[test]
+    fn two_note_ons_and_one_note_off_leave_the_note_playing() {
+        let mut note_state = NoteState::new();
+        let c = "C".to_string();
+        handle(&mut note_state, NoteEvent::On(c.clone()));
+        handle(&mut note_state, NoteEvent::On(c.clone()));
+        handle(&mut note_state, NoteEvent::Off(c.clone()));
+        assert!(note_state.is_playing(&c));
+        assert_eq!(note_state.find_active_note(), Some(c.clone()));
+
+        handle(&mut note_state, NoteEvent::Off(c.clone()));
+        assert!(!note_state.is_playing(&c));
+    }
+
+    #[test]
+    fn a_stray_note_off_does_not_leave_a_later_press_short_of_a_hold() {
+        let mut note_state = NoteState::new();
+        let id = NoteId::shared("E".to_string());
+        note_state.stop_note(&id);
+        note_state.start_note(id.clone(), None);
+        note_state.stop_note(&id);
+        note_state.stop_note(&id);
+        assert!(!note_state.is_playing(&id.note));
+
+        // One press still takes one release, however many came before.
+        note_state.start_note(id.clone(), None);
+        assert!(note_state.is_playing(&id.note));
+        note_state.stop_note(&id);
+        assert!(!note_state.is_playing(&id.note));
+    }
+
+    #[test]
+    fn releasing_a_note_stops_it_however_many_presses_hold_it() {
+        let mut note_state = NoteState::new();
+        let id = NoteId::shared("G".to_string());
+        for _ in 0..3 {
+            note_state.start_note(id.clone(), None);
+        }
+        note_state.release_note(&id);
+        assert!(!note_state.is_playing(&id.note));
+    }
+
+    #[cfg(feature = "visualization")]
+    #[test]
+    fn a_key_and_a_shared_source_hold_the_same_pitch_separately() {
+        use winit::keyboard::KeyCode;
+
+        let mut note_state = NoteState::new();
+        let shared = NoteId::shared("A".to_string());
+        let key = NoteId::new(
+            "A".to_string(),
+            NoteSource::Key(PhysicalKey::Code(KeyCode::KeyH)),
+        );
+        note_state.start_note(shared.clone(), None);
+        note_state.start_note(key.clone(), None);
+
+        // The key's release doesn't take the shared press with it.
+        note_state.stop_note(&key);
+        note_state.stop_note(&key);
+        assert!(note_state.is_playing(&shared.note));
+        note_state.stop_note(&shared);
+        assert!(!note_state.is_playing(&shared.note));
     }
 }
@@ -0,0 +1,422 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{AudioBuffer, AudioNode};
+
+/// Settings for the limiter at the end of the signal chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimiterConfig {
+    /// Off by default; the wave shaper already keeps most mixes below full scale.
+    pub enabled: bool,
+    /// Level in dBFS the output's peaks are held to.
+    pub threshold: f32,
+    /// Width in dB of the range around the threshold over which limiting eases in. 0 is a hard
+    /// corner, where peaks are untouched right up to the threshold.
+    pub knee: f32,
+    /// Seconds the gain takes to recover after a peak, to about 37% of the reduction.
+    pub release: f32,
+    /// Seconds the output is delayed by so the gain can start coming down before a peak
+    /// arrives, rather than jumping down on it. 0 adds no delay, and the gain drops right on
+    /// the peak.
+    pub lookahead: f32,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        LimiterConfig {
+            enabled: false,
+            threshold: -1.0,
+            knee: 0.0,
+            release: 0.05,
+            lookahead: 0.0,
+        }
+    }
+}
+
+/// The level in dB a peak at `level` dB comes out at, for a limiter holding peaks to
+/// `threshold` with a knee `knee` dB wide.
+///
+/// Within the knee the curve is a parabola that leaves the straight line below it and meets
+/// the flat ceiling above it without a corner, so the gain reduction eases in.
+pub fn limited_level(level: f32, threshold: f32, knee: f32) -> f32 {
+    let overshoot = level - threshold;
+    if knee > 0.0 && overshoot.abs() <= knee / 2.0 {
+        level - (overshoot + knee / 2.0).powi(2) / (2.0 * knee)
+    } else if overshoot > 0.0 {
+        threshold
+    } else {
+        level
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-10).log10()
+}
+
+/// Holds the output's peaks to a threshold, turning the gain down on every channel at once so
+/// the stereo image doesn't shift.
+///
+/// With a lookahead the signal is delayed, and the gain eases down over the lookahead time
+/// to reach what a peak calls for just as the peak comes out. Without one it drops the moment
+/// a peak calls for it. Either way it recovers over the release time.
+#[derive(Debug)]
+pub struct LimiterNode {
+    config: LimiterConfig,
+    /// How much of the gain's remaining recovery is left after each sample.
+    release_coefficient: f32,
+    /// Frames the output is delayed by.
+    lookahead: usize,
+    /// Each channel's samples waiting to come out, `lookahead` of them.
+    delay_lines: Vec<VecDeque<f32>>,
+    /// The reduction each frame still in the delay lines calls for, and the one coming in,
+    /// the oldest first.
+    pending_reductions: VecDeque<f32>,
+    /// Gain reduction in dB as of the last sample, 0 or below.
+    reduction: f32,
+}
+
+impl LimiterNode {
+    pub fn new(config: LimiterConfig, sample_rate: f32) -> Self {
+        let release_coefficient = if config.release > 0.0 {
+            (-1.0 / (config.release * sample_rate)).exp()
+        } else {
+            0.0
+        };
+        let lookahead = (config.lookahead.max(0.0) * sample_rate).round() as usize;
+        LimiterNode {
+            config,
+            release_coefficient,
+            lookahead,
+            delay_lines: Vec::new(),
+            pending_reductions: VecDeque::with_capacity(lookahead + 1),
+            reduction: 0.0,
+        }
+    }
+
+    /// The gain reduction in dB as of the last sample processed, 0 or below.
+    pub fn reduction(&self) -> f32 {
+        self.reduction
+    }
+
+    /// The gain reduction in dB a peak at `level` dB calls for.
+    fn target_reduction(&self, level: f32) -> f32 {
+        limited_level(level, self.config.threshold, self.config.knee.max(0.0)) - level
+    }
+
+    /// The reduction the frame coming out now needs so that every pending peak is on its way
+    /// down in time: each one's reduction, eased in linearly over the frames before it
+    /// comes out.
+    fn lookahead_reduction(&self) -> f32 {
+        let frames = self.pending_reductions.len() as f32;
+        self.pending_reductions
+            .iter()
+            .enumerate()
+            .map(|(age, reduction)| reduction * (frames - age as f32) / frames)
+            .fold(0.0, f32::min)
+    }
+
+    /// Starts the delay lines over, empty, for `num_channels` channels.
+    fn reset_delay_lines(&mut self, num_channels: usize) {
+        self.delay_lines = vec![VecDeque::from(vec![0.0; self.lookahead]); num_channels];
+        self.pending_reductions.clear();
+        self.pending_reductions.resize(self.lookahead, 0.0);
+    }
+}
+
+impl AudioNode for LimiterNode {
+    fn process(&mut self, input: &AudioBuffer, output: &mut AudioBuffer) {
+        assert_eq!(input.num_channels(), output.num_channels());
+        let num_frames = input.num_frames();
+        let num_channels = input.num_channels();
+        if self.delay_lines.len() != num_channels {
+            self.reset_delay_lines(num_channels);
+        }
+
+        // Channels are laid out one after the other, so a frame's samples are `num_frames`
+        // apart.
+        for frame in 0..num_frames {
+            let peak = (0..num_channels)
+                .map(|channel| input.data[channel * num_frames + frame].abs())
+                .fold(0.0f32, f32::max);
+            self.pending_reductions
+                .push_back(self.target_reduction(gain_to_db(peak)));
+            let target = self.lookahead_reduction();
+            self.pending_reductions.pop_front();
+            self.reduction = if target < self.reduction {
+                target
+            } else {
+                target + (self.reduction - target) * self.release_coefficient
+            };
+            let gain = db_to_gain(self.reduction);
+            for channel in 0..num_channels {
+                let index = channel * num_frames + frame;
+                let delay_line = &mut self.delay_lines[channel];
+                delay_line.push_back(input.data[index]);
+                let delayed = delay_line.pop_front().unwrap_or_default();
+                output.data[index] = delayed * gain;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.reduction = 0.0;
+        self.reset_delay_lines(self.delay_lines.len());
+    }
+
+    fn latency_samples(&self) -> usize {
+        self.lookahead
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// The gain in dB a limiter at -6 dBFS with a knee `knee` dB wide applies to levels swept
+    /// from 6 dB below the threshold to 6 dB above, a tenth of a dB apart.
+    fn gain_curve(knee: f32) -> Vec<f32> {
+        (-60..=60)
+            .map(|step| {
+                let level = -6.0 + step as f32 * 0.1;
+                limited_level(level, -6.0, knee) - level
+            })
+            .collect()
+    }
+
+    /// The largest change in the curve's slope from one step to the next.
+    fn largest_bend(curve: &[f32]) -> f32 {
+        curve
+            .windows(3)
+            .map(|three| (three[2] - 2.0 * three[1] + three[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn a_soft_knee_eases_the_gain_in_where_a_hard_knee_turns_a_corner() {
+        // At the threshold itself a hard knee hasn't started, a 6 dB one is a quarter of the
+        // knee's width in.
+        assert_eq!(limited_level(-6.0, -6.0, 0.0), -6.0);
+        assert!((limited_level(-6.0, -6.0, 6.0) - -6.75).abs() < 1e-6);
+
+        let hard = gain_curve(0.0);
+        let soft = gain_curve(6.0);
+        assert!(largest_bend(&hard) > 0.09, "{}", largest_bend(&hard));
+        assert!(largest_bend(&soft) < 0.002, "{}", largest_bend(&soft));
+        // Both only ever turn the gain down, and more so the louder the peak.
+        for curve in [&hard, &soft] {
+            assert!(curve.iter().all(|&gain| gain <= 0.0));
+            assert!(curve.windows(2).all(|pair| pair[1] <= pair[0] + 1e-6));
+        }
+        // The soft knee reduces more inside the knee and the same outside it.
+        assert!(soft.iter().zip(&hard).all(|(soft, hard)| soft <= hard));
+        assert_eq!(soft[..30], hard[..30]);
+        assert!((soft[120] - hard[120]).abs() < 1e-5);
+    }
+
+    #[test]
+    fn the_soft_knee_meets_the_straight_line_and_the_ceiling_at_its_edges() {
+        for knee in [2.0, 6.0, 12.0] {
+            let below = -6.0 - knee / 2.0;
+            let above = -6.0 + knee / 2.0;
+            assert!((limited_level(below, -6.0, knee) - below).abs() < 1e-5);
+            assert!((limited_level(above, -6.0, knee) - -6.0).abs() < 1e-5);
+        }
+    }
+
+    /// The loudest sample of `output` from `from` on.
+    fn peak(output: &AudioBuffer, from: usize) -> f32 {
+        (0..output.num_channels())
+            .flat_map(|channel| output.channel(channel)[from..].iter())
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn a_full_scale_sine_comes_out_with_its_peaks_at_the_threshold() {
+        for knee in [0.0, 6.0] {
+            let config = LimiterConfig {
+                enabled: true,
+                threshold: -6.0,
+                knee,
+                ..LimiterConfig::default()
+            };
+            let mut limiter = LimiterNode::new(config, SAMPLE_RATE);
+            let sine: Vec<f32> = (0..4_800)
+                .map(|i| (std::f32::consts::TAU * 220.0 * i as f32 / SAMPLE_RATE).sin())
+                .collect();
+            let input = AudioBuffer {
+                data: [sine.clone(), sine].concat(),
+                num_channels: 2,
+            };
+            let mut output = input.clone();
+            limiter.process(&input, &mut output);
+            let ceiling = db_to_gain(-6.0);
+            assert!(peak(&output, 0) <= ceiling + 1e-6, "{}", peak(&output, 0));
+            assert!(
+                peak(&output, 2_400) > ceiling * 0.95,
+                "{}",
+                peak(&output, 2_400)
+            );
+            assert_eq!(output.channel(0), output.channel(1));
+        }
+    }
+
+    #[test]
+    fn the_gain_is_linked_across_channels_and_recovers_over_the_release_time() {
+        let config = LimiterConfig {
+            enabled: true,
+            threshold: -6.0,
+            release: 0.01,
+            ..LimiterConfig::default()
+        };
+        let mut limiter = LimiterNode::new(config, SAMPLE_RATE);
+        // One loud frame on the left, then quiet.
+        let mut left = vec![0.1; 4_800];
+        left[0] = 1.0;
+        let input = AudioBuffer {
+            data: [left, vec![0.1; 4_800]].concat(),
+            num_channels: 2,
+        };
+        let mut output = input.clone();
+        limiter.process(&input, &mut output);
+        // The right channel is turned down with the left.
+        assert!((output.channel(1)[0] - 0.1 * db_to_gain(-6.0)).abs() < 1e-6);
+        // After one release time the reduction is down to about 37%, and gone after ten.
+        let after_release = gain_to_db(output.channel(1)[480] / 0.1);
+        assert!(
+            (after_release - -6.0 * 0.368).abs() < 0.05,
+            "{}",
+            after_release
+        );
+        assert!(limiter.reduction() > -6.0 * 1e-4);
+        assert!(output.channel(1).windows(2).all(|pair| pair[1] >= pair[0]));
+    }
+
+    #[test]
+    fn quiet_signals_pass_untouched() {
+        let mut limiter = LimiterNode::new(LimiterConfig::default(), SAMPLE_RATE);
+        let input = AudioBuffer {
+            data: (0..512).map(|i| 0.5 * (i as f32 * 0.05).sin()).collect(),
+            num_channels: 1,
+        };
+        let mut output = input.clone();
+        limiter.process(&input, &mut output);
+        assert_eq!(output.data, input.data);
+        assert_eq!(limiter.reduction(), 0.0);
+    }
+
+    /// A limiter at -6 dBFS looking `lookahead` frames ahead.
+    fn looking_ahead(lookahead: usize) -> LimiterNode {
+        let config = LimiterConfig {
+            enabled: true,
+            threshold: -6.0,
+            lookahead: lookahead as f32 / SAMPLE_RATE,
+            ..LimiterConfig::default()
+        };
+        LimiterNode::new(config, SAMPLE_RATE)
+    }
+
+    /// A quiet mono signal `len` frames long with a full scale spike at frame `at`.
+    fn spike(len: usize, at: usize) -> AudioBuffer {
+        let mut data = vec![0.1; len];
+        data[at] = 1.0;
+        AudioBuffer {
+            data,
+            num_channels: 1,
+        }
+    }
+
+    /// Runs `input` through `limiter` in blocks of `block` frames.
+    fn limit(limiter: &mut LimiterNode, input: &AudioBuffer, block: usize) -> Vec<f32> {
+        input
+            .data
+            .chunks(block)
+            .flat_map(|chunk| {
+                let input = AudioBuffer {
+                    data: chunk.to_vec(),
+                    num_channels: 1,
+                };
+                let mut output = input.clone();
+                limiter.process(&input, &mut output);
+                output.data
+            })
+            .collect()
+    }
+
+    #[test]
+    fn with_a_lookahead_the_gain_dips_ahead_of_the_peak_by_the_lookahead() {
+        const LOOKAHEAD: usize = 48;
+        const PEAK: usize = 1_000;
+        let mut limiter = looking_ahead(LOOKAHEAD);
+        assert_eq!(limiter.latency_samples(), LOOKAHEAD);
+        let input = spike(2_000, PEAK);
+        let output = limit(&mut limiter, &input, 2_000);
+
+        // The output is the input delayed, so the gain on each input frame is what came out
+        // for it over what went in.
+        let gain: Vec<f32> = (LOOKAHEAD..output.len())
+            .map(|frame| output[frame] / input.data[frame - LOOKAHEAD])
+            .collect();
+        // Untouched until the peak is a lookahead away from coming out, ...
+        assert!(gain[..PEAK - LOOKAHEAD].iter().all(|&gain| gain == 1.0));
+        // ... then down step by step, ...
+        let dip = &gain[PEAK - LOOKAHEAD..=PEAK];
+        assert!(dip[0] < 1.0);
+        assert!(dip.windows(2).all(|pair| pair[1] < pair[0]));
+        // ... to hold the peak to the threshold just as it comes out.
+        assert!((output[PEAK + LOOKAHEAD] - db_to_gain(-6.0)).abs() < 1e-4);
+        assert!(output
+            .iter()
+            .all(|sample| *sample <= db_to_gain(-6.0) + 1e-4));
+    }
+
+    #[test]
+    fn without_a_lookahead_the_gain_drops_right_on_the_peak() {
+        const PEAK: usize = 1_000;
+        let mut limiter = looking_ahead(0);
+        assert_eq!(limiter.latency_samples(), 0);
+        let input = spike(2_000, PEAK);
+        let output = limit(&mut limiter, &input, 2_000);
+        assert_eq!(output[..PEAK], input.data[..PEAK]);
+        assert!((output[PEAK] - db_to_gain(-6.0)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn a_looking_ahead_limiter_renders_the_same_at_any_block_size() {
+        let input = spike(3_000, 1_500);
+        let whole = limit(&mut looking_ahead(96), &input, 3_000);
+        for block in [1, 7, 64, 256, 1_000] {
+            assert_eq!(
+                limit(&mut looking_ahead(96), &input, block),
+                whole,
+                "{}",
+                block
+            );
+        }
+    }
+
+    #[test]
+    fn quiet_signals_come_out_delayed_by_the_lookahead_and_otherwise_untouched() {
+        let mut limiter = looking_ahead(32);
+        let input = AudioBuffer {
+            data: (0..500).map(|i| 0.3 * (i as f32 * 0.05).sin()).collect(),
+            num_channels: 1,
+        };
+        let output = limit(&mut limiter, &input, 100);
+        assert!(output[..32].iter().all(|&sample| sample == 0.0));
+        assert_eq!(output[32..], input.data[..468]);
+
+        // A reset drops what was waiting in the delay.
+        limiter.reset();
+        let output = limit(&mut limiter, &input, 500);
+        assert!(output[..32].iter().all(|&sample| sample == 0.0));
+        assert_eq!(output[32..], input.data[..468]);
+    }
+}
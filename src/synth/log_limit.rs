@@ -0,0 +1,184 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// How often a rate-limited message on the audio path may be logged.
+pub const HOT_PATH_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lets a log message through at most once per interval, from any thread.
+///
+/// Unlike `RateLimiter` it needs no `&mut` and no lock, so it can sit in a `static` at the call
+/// site and be checked from the audio thread: a check is a clock read and an atomic load, plus
+/// a compare-and-swap when the message goes out.
+#[derive(Debug)]
+pub struct LogLimiter {
+    /// Nanoseconds since `clock_origin` the last message went out at, plus one; zero before the
+    /// first.
+    last: AtomicU64,
+}
+
+impl LogLimiter {
+    pub const fn new() -> Self {
+        LogLimiter {
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a message may go out now, `interval` or more after the last one. When two
+    /// threads ask at once only one of them is let through.
+    pub fn allow(&self, interval: Duration) -> bool {
+        let now = clock_origin().elapsed().as_nanos() as u64 + 1;
+        let last = self.last.load(Ordering::Relaxed);
+        if last != 0 && now.saturating_sub(last) < interval.as_nanos() as u64 {
+            return false;
+        }
+        self.last
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl Default for LogLimiter {
+    fn default() -> Self {
+        LogLimiter::new()
+    }
+}
+
+/// The instant log times are measured from, fixed the first time a limiter is checked.
+fn clock_origin() -> Instant {
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    *ORIGIN.get_or_init(Instant::now)
+}
+
+/// Logs like `tracing`'s `$level!` macro, but at most once per `$interval` from this call site.
+/// The message is only formatted when it goes out, so a suppressed call costs no allocation.
+macro_rules! log_rate_limited {
+    ($level:ident, $interval:expr, $($arg:tt)+) => {{
+        static LIMITER: $crate::synth::log_limit::LogLimiter =
+            $crate::synth::log_limit::LogLimiter::new();
+        if LIMITER.allow($interval) {
+            ::tracing::$level!($($arg)+);
+        }
+    }};
+}
+
+/// Logs a warning the first time this call site is reached, and never again.
+macro_rules! warn_once {
+    ($($arg:tt)+) => {{
+        static LOGGED: ::std::sync::atomic::AtomicBool = ::std::sync::atomic::AtomicBool::new(false);
+        if !LOGGED.load(::std::sync::atomic::Ordering::Relaxed)
+            && !LOGGED.swap(true, ::std::sync::atomic::Ordering::Relaxed)
+        {
+            ::tracing::warn!($($arg)+);
+        }
+    }};
+}
+
+pub(crate) use {log_rate_limited, warn_once};
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    use super::*;
+
+    /// Counts the events that reach the subscriber.
+    struct CountingLayer(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for CountingLayer {
+        fn on_event(&self, _event: &tracing::Event<'_>, _context: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Runs `body` with a subscriber installed on this thread, returning how many events it saw.
+    fn count_events(body: impl FnOnce()) -> usize {
+        let events = Arc::new(AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry().with(CountingLayer(events.clone()));
+        tracing::subscriber::with_default(subscriber, body);
+        events.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn a_rate_limited_site_called_a_million_times_logs_a_bounded_number_of_events() {
+        let start = Instant::now();
+        let events = count_events(|| {
+            for i in 0..1_000_000 {
+                log_rate_limited!(warn, HOT_PATH_LOG_INTERVAL, "underrun {}", i);
+            }
+        });
+        // One event, plus one more for each interval the loop took to run.
+        let intervals = start.elapsed().as_secs_f64() / HOT_PATH_LOG_INTERVAL.as_secs_f64();
+        assert!(events >= 1);
+        assert!(events <= 1 + intervals.ceil() as usize, "{}", events);
+    }
+
+    #[test]
+    fn warn_once_logs_only_the_first_call() {
+        let events = count_events(|| {
+            for i in 0..1_000_000 {
+                warn_once!("no output device {}", i);
+            }
+        });
+        assert_eq!(events, 1);
+    }
+
+    #[test]
+    fn each_site_has_its_own_limit() {
+        let events = count_events(|| {
+            for _ in 0..1000 {
+                log_rate_limited!(warn, HOT_PATH_LOG_INTERVAL, "first site");
+                log_rate_limited!(warn, HOT_PATH_LOG_INTERVAL, "second site");
+            }
+        });
+        assert_eq!(events, 2);
+    }
+
+    #[test]
+    fn the_limiter_lets_a_message_through_again_once_the_interval_has_passed() {
+        let limiter = LogLimiter::new();
+        let interval = Duration::from_millis(20);
+        assert!(limiter.allow(interval));
+        assert!(!limiter.allow(interval));
+        assert!(!limiter.allow(interval));
+        std::thread::sleep(interval * 2);
+        assert!(limiter.allow(interval));
+        assert!(!limiter.allow(interval));
+    }
+
+    #[test]
+    fn a_zero_interval_lets_every_message_through() {
+        let limiter = LogLimiter::new();
+        for _ in 0..10 {
+            assert!(limiter.allow(Duration::ZERO));
+        }
+    }
+
+    #[test]
+    fn only_one_of_several_threads_asking_at_once_is_let_through() {
+        let limiter = Arc::new(LogLimiter::new());
+        let allowed = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let allowed = allowed.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        if limiter.allow(Duration::from_secs(60)) {
+                            allowed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(allowed.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,549 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::synth::NoteId;
+
+/// Settings for the phrase looper.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LooperConfig {
+    /// Longest loop, in seconds. Recording turns into playback on its own once it gets there.
+    pub max_length: f32,
+    /// Round the loop length to whole bars at `tempo`.
+    pub quantize_to_bars: bool,
+    /// Tempo in beats per minute, with four beats to the bar, for `quantize_to_bars`.
+    pub tempo: f32,
+    /// Most loop voices sounding at once. Loop voices don't count against live playing.
+    pub max_voices: usize,
+}
+
+impl Default for LooperConfig {
+    fn default() -> Self {
+        LooperConfig {
+            max_length: 30.0,
+            quantize_to_bars: false,
+            tempo: 120.0,
+            max_voices: 8,
+        }
+    }
+}
+
+/// Something the looper's keys asked for, carried out at the start of the next block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopCommand {
+    /// Starts recording, starts playback, or stops and restarts playback, depending on the
+    /// state.
+    Toggle,
+    /// Drops the notes added by the most recent overdub pass.
+    UndoOverdub,
+    Clear,
+}
+
+/// Where the looper is in its record/play cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LooperState {
+    Empty,
+    /// Capturing the first pass, which started at engine sample `start`.
+    Recording {
+        start: u64,
+    },
+    /// Repeating every `length` samples from `start`. Pass `n` begins at `start + n * length`
+    /// and overdubs layer `first_layer + n`: the recorded pass is layer 0, and playback that
+    /// resumes after a stop carries on from the layers already in the loop.
+    Playing {
+        start: u64,
+        length: u64,
+        first_layer: u64,
+    },
+    /// Holding a loop of `length` samples without playing it.
+    Stopped {
+        length: u64,
+    },
+}
+
+/// A note change in the loop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopEvent {
+    /// Samples from the start of the loop.
+    pub offset: u64,
+    pub note: String,
+    pub is_on: bool,
+    /// The pass the note was played in: 0 for the first recording, `n` for an overdub during
+    /// pass `n`.
+    pub layer: u64,
+}
+
+/// Records the notes played live and plays them back on repeat, layering overdubs on top.
+///
+/// The looper works on note events rather than audio, so loop notes go through the normal voice
+/// path and follow waveform and effect changes. All times are engine samples.
+#[derive(Debug)]
+pub struct Looper {
+    config: LooperConfig,
+    sample_rate: f32,
+    state: LooperState,
+    /// Sorted by offset.
+    events: Vec<LoopEvent>,
+    /// Notes that started in the loop and haven't stopped yet, with the layer they started in.
+    /// Two keys holding the same note name are told apart.
+    recording_notes: HashMap<NoteId, u64>,
+    pending: Vec<LoopCommand>,
+}
+
+impl Default for Looper {
+    fn default() -> Self {
+        Looper::new(LooperConfig::default(), 44100.0)
+    }
+}
+
+impl Looper {
+    pub fn new(config: LooperConfig, sample_rate: f32) -> Self {
+        Looper {
+            config,
+            sample_rate,
+            state: LooperState::Empty,
+            events: Vec::new(),
+            recording_notes: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &LooperConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> LooperState {
+        self.state
+    }
+
+    pub fn events(&self) -> &[LoopEvent] {
+        &self.events
+    }
+
+    /// Queues `command` for the engine to carry out at the start of its next block.
+    pub fn request(&mut self, command: LoopCommand) {
+        self.pending.push(command);
+    }
+
+    /// Carries out `command` at engine sample `now`.
+    pub fn apply(&mut self, command: LoopCommand, now: u64) {
+        match command {
+            LoopCommand::Toggle => self.toggle(now),
+            LoopCommand::UndoOverdub => self.undo_overdub(),
+            LoopCommand::Clear => self.clear(),
+        }
+    }
+
+    fn toggle(&mut self, now: u64) {
+        self.state = match self.state {
+            LooperState::Empty => {
+                info!("Looper recording");
+                LooperState::Recording { start: now }
+            }
+            LooperState::Recording { start } => self.close_recording(start, now),
+            LooperState::Playing { length, .. } => {
+                self.close_held_notes(length);
+                info!("Looper stopped");
+                LooperState::Stopped { length }
+            }
+            // Resuming starts a pass at `now` that plays every layer recorded so far.
+            LooperState::Stopped { length } => {
+                info!("Looper playing");
+                LooperState::Playing {
+                    start: now,
+                    length,
+                    first_layer: self.next_layer(),
+                }
+            }
+        };
+    }
+
+    /// Ends the first pass at `now` and works out the loop length.
+    fn close_recording(&mut self, start: u64, now: u64) -> LooperState {
+        let length = self.loop_length(now.saturating_sub(start));
+        self.events.retain(|event| event.offset < length);
+        self.close_held_notes(length);
+        info!(
+            "Looper playing a {:.2}s loop of {} notes",
+            length as f32 / self.sample_rate,
+            self.events.iter().filter(|event| event.is_on).count()
+        );
+        LooperState::Playing {
+            start,
+            length,
+            first_layer: 0,
+        }
+    }
+
+    /// Layer after the newest one in the loop.
+    fn next_layer(&self) -> u64 {
+        self.events
+            .iter()
+            .map(|event| event.layer + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Stops the notes still held in the loop at its end, so it can't leave them ringing
+    /// forever.
+    fn close_held_notes(&mut self, length: u64) {
+        for (id, layer) in self.recording_notes.drain() {
+            self.events.push(LoopEvent {
+                offset: length - 1,
+                note: id.note,
+                is_on: false,
+                layer,
+            });
+        }
+        self.events.sort_by_key(|event| event.offset);
+    }
+
+    /// Length of a loop recorded over `recorded` samples: at most `max_length`, rounded to whole
+    /// bars when quantizing, and never empty.
+    pub fn loop_length(&self, recorded: u64) -> u64 {
+        let max_samples = self.max_samples();
+        let mut length = recorded.min(max_samples);
+        if self.config.quantize_to_bars && self.config.tempo > 0.0 {
+            let bar = (4.0 * 60.0 / self.config.tempo * self.sample_rate) as u64;
+            if bar > 0 {
+                let bars = ((length as f64 / bar as f64).round() as u64).max(1);
+                length = (bars * bar).min(max_samples.max(bar));
+            }
+        }
+        length.max(1)
+    }
+
+    fn max_samples(&self) -> u64 {
+        (self.config.max_length.max(0.0) * self.sample_rate) as u64
+    }
+
+    fn undo_overdub(&mut self) {
+        let Some(layer) = self.events.iter().map(|event| event.layer).max() else {
+            return;
+        };
+        if layer == 0 {
+            return;
+        }
+        self.events.retain(|event| event.layer != layer);
+        self.recording_notes
+            .retain(|_, note_layer| *note_layer != layer);
+        info!("Looper undid overdub pass {}", layer);
+    }
+
+    fn clear(&mut self) {
+        self.state = LooperState::Empty;
+        self.events.clear();
+        self.recording_notes.clear();
+        info!("Looper cleared");
+    }
+
+    /// Adds the note `id` played live at `now` to the loop, when it is recording or
+    /// overdubbing.
+    pub fn record(&mut self, now: u64, id: &NoteId, is_on: bool) {
+        let (start, length, first_layer) = match self.state {
+            LooperState::Recording { start } => (start, None, 0),
+            LooperState::Playing {
+                start,
+                length,
+                first_layer,
+            } => (start, Some(length), first_layer),
+            LooperState::Empty | LooperState::Stopped { .. } => return,
+        };
+        let elapsed = now.saturating_sub(start);
+        let (offset, layer) = match length {
+            Some(length) => (elapsed % length, first_layer + elapsed / length),
+            None => (elapsed, 0),
+        };
+
+        let layer = if is_on {
+            self.recording_notes.insert(id.clone(), layer);
+            layer
+        } else {
+            // A note that started before the loop did has nothing to stop.
+            match self.recording_notes.remove(id) {
+                Some(on_layer) => on_layer,
+                None => return,
+            }
+        };
+        let index = self.events.partition_point(|event| event.offset <= offset);
+        self.events.insert(
+            index,
+            LoopEvent {
+                offset,
+                note: id.note.clone(),
+                is_on,
+                layer,
+            },
+        );
+    }
+
+    /// Carries out queued commands at `from`, then returns the loop's note events that fall in
+    /// the block `[from, to)`, as `(engine sample, note, is_on)` in time order.
+    ///
+    /// A pass doesn't replay notes from its own layer, since those were just played live.
+    pub fn advance(&mut self, from: u64, to: u64) -> Vec<(u64, String, bool)> {
+        for command in std::mem::take(&mut self.pending) {
+            self.apply(command, from);
+        }
+        if let LooperState::Recording { start } = self.state {
+            if to.saturating_sub(start) > self.max_samples() {
+                let end = start + self.max_samples();
+                self.state = self.close_recording(start, end);
+            }
+        }
+
+        let LooperState::Playing {
+            start,
+            length,
+            first_layer,
+        } = self.state
+        else {
+            return Vec::new();
+        };
+        let mut due = Vec::new();
+        if to <= start {
+            return due;
+        }
+        let first_pass = from.saturating_sub(start) / length;
+        let last_pass = (to - 1 - start) / length;
+        for pass in first_pass..=last_pass {
+            let pass_start = start + pass * length;
+            let layer = first_layer + pass;
+            for event in self.events.iter().filter(|event| event.layer < layer) {
+                let time = pass_start + event.offset;
+                if (from..to).contains(&time) {
+                    due.push((time, event.note.clone(), event.is_on));
+                }
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    const BLOCK: u64 = 64;
+    /// Where the recording starts, well into the engine's run.
+    const START: u64 = 10_000;
+    const LENGTH: u64 = 4_800;
+
+    /// Runs the looper in blocks from `from` until `to`, first playing each of `live` notes,
+    /// given as `(engine sample, note, is_on)`, at the start of its block the way the engine
+    /// records them. Returns the onsets the loop played, as `(engine sample, note)`.
+    fn run(
+        looper: &mut Looper,
+        from: u64,
+        to: u64,
+        live: &[(u64, &str, bool)],
+    ) -> Vec<(u64, String)> {
+        let mut onsets = Vec::new();
+        for block_start in (from..to).step_by(BLOCK as usize) {
+            let due = looper.advance(block_start, block_start + BLOCK);
+            for &(time, note, is_on) in live {
+                if (block_start..block_start + BLOCK).contains(&time) {
+                    looper.record(time, &NoteId::shared(note.to_string()), is_on);
+                }
+            }
+            onsets.extend(
+                due.into_iter()
+                    .filter(|(_, _, is_on)| *is_on)
+                    .map(|(time, note, _)| (time, note)),
+            );
+        }
+        onsets
+    }
+
+    /// A looper holding a two-note phrase, C at 640 and E at 2560 samples into a loop of
+    /// `LENGTH`, just switched over to playback at `START + LENGTH`.
+    fn recorded_phrase() -> Looper {
+        let mut looper = Looper::new(LooperConfig::default(), SAMPLE_RATE);
+        looper.request(LoopCommand::Toggle);
+        let phrase = [
+            (START + 640, "C", true),
+            (START + 1920, "C", false),
+            (START + 2560, "E", true),
+            (START + 3840, "E", false),
+        ];
+        assert!(run(&mut looper, START, START + LENGTH, &phrase).is_empty());
+        looper.request(LoopCommand::Toggle);
+        looper
+    }
+
+    fn onsets(times: &[(u64, &str)]) -> Vec<(u64, String)> {
+        times
+            .iter()
+            .map(|(time, note)| (*time, note.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn onsets_repeat_every_loop_length_with_overdubs_layered() {
+        let mut looper = recorded_phrase();
+        // G is overdubbed 1280 samples into the first pass of playback.
+        let pass = |n: u64| START + n * LENGTH;
+        let overdub = [(pass(1) + 1280, "G", true), (pass(1) + 1600, "G", false)];
+        let played = run(&mut looper, pass(1), pass(4), &overdub);
+        assert_eq!(
+            looper.state(),
+            LooperState::Playing {
+                start: START,
+                length: LENGTH,
+                first_layer: 0,
+            }
+        );
+
+        // The phrase comes round on the exact sample each pass; G joins it from the pass after
+        // the one it was played in.
+        assert_eq!(
+            played,
+            onsets(&[
+                (pass(1) + 640, "C"),
+                (pass(1) + 2560, "E"),
+                (pass(2) + 640, "C"),
+                (pass(2) + 1280, "G"),
+                (pass(2) + 2560, "E"),
+                (pass(3) + 640, "C"),
+                (pass(3) + 1280, "G"),
+                (pass(3) + 2560, "E"),
+            ])
+        );
+        let layers: Vec<(&str, u64)> = looper
+            .events()
+            .iter()
+            .map(|event| (event.note.as_str(), event.layer))
+            .collect();
+        assert_eq!(
+            layers,
+            [("C", 0), ("G", 1), ("G", 1), ("C", 0), ("E", 0), ("E", 0)]
+        );
+    }
+
+    #[test]
+    fn resuming_plays_every_layer_from_the_start_of_a_pass() {
+        let mut looper = recorded_phrase();
+        let overdub = [
+            (START + LENGTH + 1280, "G", true),
+            (START + LENGTH + 1600, "G", false),
+        ];
+        run(&mut looper, START + LENGTH, START + 2 * LENGTH, &overdub);
+        looper.request(LoopCommand::Toggle);
+        run(
+            &mut looper,
+            START + 2 * LENGTH,
+            START + 2 * LENGTH + BLOCK,
+            &[],
+        );
+        assert_eq!(looper.state(), LooperState::Stopped { length: LENGTH });
+
+        // Picked up again at an odd time, the whole loop, overdub included, plays from there.
+        let resume = 123_456;
+        looper.request(LoopCommand::Toggle);
+        let overdub = [(resume + 4000, "B", true), (resume + 4100, "B", false)];
+        let played = run(&mut looper, resume, resume + 2 * LENGTH, &overdub);
+        assert_eq!(
+            played,
+            onsets(&[
+                (resume + 640, "C"),
+                (resume + 1280, "G"),
+                (resume + 2560, "E"),
+                (resume + LENGTH + 640, "C"),
+                (resume + LENGTH + 1280, "G"),
+                (resume + LENGTH + 2560, "E"),
+                (resume + LENGTH + 4000, "B"),
+            ])
+        );
+    }
+
+    #[test]
+    fn resuming_before_a_loop_length_has_passed_starts_at_the_top() {
+        let mut looper = recorded_phrase();
+        looper.request(LoopCommand::Toggle);
+        run(&mut looper, START + LENGTH, START + LENGTH + BLOCK, &[]);
+
+        // Engine time can be less than the loop length after the clock is reset.
+        let resume = 1_000;
+        looper.request(LoopCommand::Toggle);
+        let played = run(&mut looper, resume, resume + LENGTH, &[]);
+        assert_eq!(played, onsets(&[(resume + 640, "C"), (resume + 2560, "E")]));
+    }
+
+    #[test]
+    fn undo_drops_the_newest_overdub() {
+        let mut looper = recorded_phrase();
+        let pass = |n: u64| START + n * LENGTH;
+        let overdubs = [
+            (pass(1) + 1280, "G", true),
+            (pass(1) + 1600, "G", false),
+            (pass(2) + 3200, "B", true),
+            (pass(2) + 3300, "B", false),
+        ];
+        run(&mut looper, pass(1), pass(3), &overdubs);
+        looper.request(LoopCommand::UndoOverdub);
+        let played = run(&mut looper, pass(3), pass(4), &[]);
+        assert_eq!(
+            played,
+            onsets(&[
+                (pass(3) + 640, "C"),
+                (pass(3) + 1280, "G"),
+                (pass(3) + 2560, "E"),
+            ])
+        );
+    }
+
+    #[test]
+    fn loop_length_is_capped_and_quantized() {
+        let looper = Looper::new(
+            LooperConfig {
+                max_length: 10.0,
+                quantize_to_bars: true,
+                tempo: 120.0,
+                ..LooperConfig::default()
+            },
+            SAMPLE_RATE,
+        );
+        // A bar at 120 BPM is two seconds.
+        assert_eq!(looper.loop_length(90_000), 96_000);
+        assert_eq!(looper.loop_length(10), 96_000);
+        assert_eq!(looper.loop_length(1_000_000), 480_000);
+    }
+
+    #[cfg(feature = "visualization")]
+    #[test]
+    fn two_keys_on_the_same_note_are_looped_separately() {
+        use crate::synth::NoteSource;
+        use winit::keyboard::{KeyCode, PhysicalKey};
+
+        let bass = NoteId::new(
+            "C".to_string(),
+            NoteSource::Key(PhysicalKey::Code(KeyCode::KeyZ)),
+        );
+        let treble = NoteId::new(
+            "C".to_string(),
+            NoteSource::Key(PhysicalKey::Code(KeyCode::KeyA)),
+        );
+        let mut looper = Looper::new(LooperConfig::default(), SAMPLE_RATE);
+        looper.request(LoopCommand::Toggle);
+        looper.advance(START, START + BLOCK);
+        looper.record(START + 100, &bass, true);
+        looper.record(START + 200, &treble, true);
+        looper.record(START + 300, &bass, false);
+        // The treble C is still held when recording stops, and is closed at the loop's end.
+        looper.request(LoopCommand::Toggle);
+        looper.advance(START + LENGTH, START + LENGTH + BLOCK);
+
+        let events: Vec<(u64, bool)> = looper
+            .events()
+            .iter()
+            .map(|event| (event.offset, event.is_on))
+            .collect();
+        assert_eq!(
+            events,
+            [(100, true), (200, true), (300, false), (LENGTH - 1, false)]
+        );
+    }
+}
@@ -1,22 +1,112 @@
 pub mod adsr_envelope;
+pub mod audio_check;
 pub mod audiobuffer;
+pub mod backend;
+pub mod bus;
+pub mod channels;
+pub mod cpu_budget;
+pub mod device_report;
+pub mod dc_blocker;
+pub mod diagnostics;
+pub mod dither;
+pub mod drift;
+pub mod drive_modulation;
+pub mod ducking;
+pub mod effects;
+pub mod engine;
+pub mod frequency_slew;
+pub mod keyboard_split;
 pub mod keys;
+pub mod limiter;
+pub mod log_limit;
+pub mod looper;
 pub mod modulator;
+pub mod mute;
 pub mod node;
 pub mod oscillator;
+pub mod oversampling;
+pub mod param_history;
+pub mod params;
+pub mod performance;
+pub mod randomizer;
+pub mod reference_tone;
+pub mod render;
+pub mod ribbon;
+pub mod sample_clip;
+pub mod score;
+pub mod smoothing;
+pub mod script;
+pub mod stereo_blend;
 pub mod tremolo;
+pub mod unison;
 pub mod utils;
+pub mod variation;
+pub mod visual_feed;
+pub mod visual_tap;
+pub mod voice_stealing;
 pub mod waveform_generator;
+pub mod waveform_sequence;
 
-pub use adsr_envelope::AmplitudeEnvelope;
+pub use adsr_envelope::{AmplitudeEnvelope, EnvelopeStage, ReleaseScalingConfig};
+pub use audio_check::{check_offline_render, estimate_fundamental, AudioCheck};
 pub use audiobuffer::AudioBuffer;
+pub use backend::{AudioConfig, Backend, JackConfig};
+pub use bus::{BusConfig, BusGraph, BusId, MAIN_BUS};
+pub use channels::{route_channels, ChannelRoutingConfig, ExtraChannels};
+pub use cpu_budget::{CpuBudgetConfig, CpuBudgetController, QualityStep, MAX_QUALITY_LEVEL};
+pub use device_report::{AudioConfigInfo, DeviceReport, DeviceWarning};
+pub use dc_blocker::{DcBlockerConfig, DcBlockerNode};
+pub use diagnostics::{
+    CallbackTimer, CallbackTiming, CallbackTimingSummary, CallbackWatchdog, DcLevel, DcMeter,
+    DiagnosticsConfig, PolyphonyMonitor, RateLimiter, RollingStats, WatchdogCounters,
+    WatchdogSummary,
+};
+pub use dither::{DitherConfig, DitherMode, OutputConverter};
+pub use drift::{Drift, DriftConfig, DriftSeeds};
+pub use drive_modulation::{DriveFollows, DriveModulation};
+pub use ducking::{DuckedBus, DuckingConfig, DuckingMixer, LevelDetector};
+pub use effects::{EffectsConfig, ShaperCurve};
+pub use engine::{SynthEngine, SynthEngineBuilder};
+pub use frequency_slew::FrequencySlew;
+pub use keyboard_split::{EnvelopeShape, KeyZone, KeyboardSplitConfig, ZonePreset};
+#[cfg(feature = "visualization")]
+pub use keys::bindings::{key_label, KeyId, ResolvedBindings};
 pub use keys::{
+    event_history::{EventHistory, HistoryEntry},
+    keymap::{export_keymap, load_config, ConfigWatcher},
     keys::Scale,
-    keys::{Config, NoteEvent},
-    note_state::NoteState,
+    keys::{Config, CycleDirection, NoteEvent},
+    note_state::{NoteId, NoteSource, NoteState},
+};
+pub use limiter::{limited_level, LimiterConfig, LimiterNode};
+pub use looper::{LoopCommand, LoopEvent, Looper, LooperConfig, LooperState};
+pub use mute::{MuteConfig, MuteGain};
+pub use node::{
+    AudioNode, DelayNode, DivisionFeel, NoteDivision, PingPongDelayNode, WaveShaperConfig,
+    WaveShaperNode,
+};
+pub use oscillator::{Oscillator, OscillatorConfig, OscillatorWaveform, VoiceInfo, DEFAULT_GAIN};
+pub use oversampling::{design_halfband, OversampledNode, Oversampling};
+pub use param_history::{ParamChange, ParamHistory, UndoConfig, UndoStep};
+pub use params::ParamId;
+pub use performance::{MidiExportConfig, PerformanceEvent, PerformanceEventKind, PerformanceLog};
+pub use randomizer::{PatchRandomizer, RandomPatch, RandomizerConfig};
+pub use reference_tone::{ReferenceRoute, ReferenceTone, ReferenceToneConfig};
+pub use ribbon::{quantize_to_scale, RibbonConfig, RibbonVoice};
+pub use sample_clip::{ClipPlayer, LoopRegion, SampleClip};
+pub use score::{Score, ScoreNote};
+pub use smoothing::{ParamBlock, SmoothedParam, Smoothing};
+pub use script::{Demo, InitialConfig, StartupEvent};
+pub use stereo_blend::StereoBlendConfig;
+pub use tremolo::{TremoloConfig, TremoloEffect};
+pub use unison::{pan_gains, DetuneCurve, UnisonConfig};
+pub use variation::{NoteVariation, SplitMix64, StartPhase, StrikeVariation, VariationConfig};
+pub use visual_feed::{CycleReference, VisualFeed};
+pub use visual_tap::VisualTap;
+pub use voice_stealing::{select_victim, StealPolicy, VoiceCandidate, VoiceStealingConfig};
+pub use waveform_generator::{FrequencyLimits, Interpolation, LimitedFrequency, WaveformGenerator};
+pub use waveform_sequence::{SequenceShape, StepRate, WaveformSequence, WaveformSequenceConfig};
+pub use audiobuffer::{
+    downsample_channel, rms_db, visual_downsample_factor, windowed_average, DownsampleConfig,
+    DownsampleWindow, DownsampledAudioData, VisualFrame, DEFAULT_VISUAL_FPS, MAX_VISUAL_SAMPLES,
 };
-pub use node::{AudioNode, WaveShaperNode};
-pub use oscillator::{Oscillator, OscillatorWaveform};
-pub use tremolo::TremoloEffect;
-pub use waveform_generator::WaveformGenerator;
-pub use audiobuffer::DownsampledAudioData;
@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{ducking::ramp_towards, AudioBuffer};
+
+/// Settings for the mute key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MuteConfig {
+    /// Seconds to fade out when muting and back in when unmuting. Short enough to feel instant,
+    /// long enough not to click.
+    pub fade_time: f32,
+    /// Level the output is turned down to while muted, from 0.0 (silent) to 1.0. Set it above
+    /// zero to dim rather than mute.
+    pub level: f32,
+}
+
+impl Default for MuteConfig {
+    fn default() -> Self {
+        MuteConfig {
+            fade_time: 0.02,
+            level: 0.0,
+        }
+    }
+}
+
+/// The master gain the mute key turns down, fading between levels so toggling never clicks.
+#[derive(Debug)]
+pub struct MuteGain {
+    config: MuteConfig,
+    sample_rate: f32,
+    /// Level while not muted; muting scales it by the mute level.
+    volume: f32,
+    gain: f32,
+}
+
+impl MuteGain {
+    pub fn new(config: MuteConfig, sample_rate: f32) -> Self {
+        MuteGain {
+            config,
+            sample_rate,
+            volume: 1.0,
+            gain: 1.0,
+        }
+    }
+
+    /// Sets the master volume the output starts at, from 0.0 to 1.0.
+    pub fn with_volume(mut self, volume: f32) -> Self {
+        self.volume = if volume.is_finite() {
+            volume.clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        self.gain = self.volume;
+        self
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn config(&self) -> &MuteConfig {
+        &self.config
+    }
+
+    /// Gain the last sample was scaled by.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// The gain the output fades towards.
+    pub fn target(&self, muted: bool) -> f32 {
+        if muted {
+            self.volume * self.config.level.clamp(0.0, 1.0)
+        } else {
+            self.volume
+        }
+    }
+
+    /// Scales `buffer` by the gain, moving it a sample at a time towards the level for `muted`.
+    pub fn process(&mut self, muted: bool, buffer: &mut AudioBuffer) {
+        let target = self.target(muted);
+        if self.gain == target {
+            if target != 1.0 {
+                buffer.data.iter_mut().for_each(|sample| *sample *= target);
+            }
+            return;
+        }
+
+        // The fade covers the whole way from silence to full level in `fade_time`.
+        let step = if self.config.fade_time > 0.0 {
+            1.0 / (self.config.fade_time * self.sample_rate)
+        } else {
+            f32::INFINITY
+        };
+        // Every channel ramps from the same gain, since they are laid out one after the other.
+        let start = self.gain;
+        for channel in 0..buffer.num_channels() {
+            let mut gain = start;
+            for sample in buffer.channel_mut(channel).iter_mut() {
+                gain = ramp_towards(gain, target, step);
+                *sample *= gain;
+            }
+            self.gain = gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    const BLOCK: usize = 64;
+
+    /// The gain applied to each sample of `blocks` blocks of a stereo signal at 1.0.
+    fn gains(mute: &mut MuteGain, muted: bool, blocks: usize) -> Vec<f32> {
+        let mut gains = Vec::new();
+        for _ in 0..blocks {
+            let mut buffer = AudioBuffer {
+                data: vec![1.0; 2 * BLOCK],
+                num_channels: 2,
+            };
+            mute.process(muted, &mut buffer);
+            assert_eq!(buffer.channel(0), buffer.channel(1));
+            gains.extend_from_slice(buffer.channel(0));
+        }
+        gains
+    }
+
+    #[test]
+    fn muting_ramps_to_silence_and_back_to_the_volume() {
+        let mut mute = MuteGain::new(MuteConfig::default(), SAMPLE_RATE).with_volume(0.8);
+        // 20 ms is 960 samples from silence to full level, so 768 from 0.8.
+        let fade = (0.8 * 0.02 * SAMPLE_RATE) as usize;
+
+        let down = gains(&mut mute, true, 20);
+        assert!(down
+            .windows(2)
+            .all(|pair| pair[1] < pair[0] || pair[1] == 0.0));
+        // No sample jumps by more than one step of the fade.
+        let step = 1.0 / (0.02 * SAMPLE_RATE);
+        assert!((0.8 - down[0]) <= step + 1e-6);
+        assert!(down[fade - 2] > 0.0);
+        assert!(down[fade..].iter().all(|gain| *gain == 0.0));
+        assert_eq!(mute.gain(), 0.0);
+
+        let up = gains(&mut mute, false, 20);
+        assert!(up
+            .windows(2)
+            .all(|pair| pair[1] > pair[0] || pair[1] == 0.8));
+        assert!(up[fade - 2] < 0.8);
+        assert!(up[fade..].iter().all(|gain| (gain - 0.8).abs() < 1e-6));
+        assert_eq!(mute.volume(), 0.8);
+    }
+
+    #[test]
+    fn a_dim_level_turns_the_output_down_rather_than_off() {
+        let config = MuteConfig {
+            level: 0.25,
+            ..MuteConfig::default()
+        };
+        let mut mute = MuteGain::new(config, SAMPLE_RATE).with_volume(0.8);
+        assert_eq!(mute.target(true), 0.2);
+        let dimmed = gains(&mut mute, true, 20);
+        assert!((dimmed.last().unwrap() - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn a_bad_volume_falls_back_to_full() {
+        let mute = MuteGain::new(MuteConfig::default(), SAMPLE_RATE).with_volume(f32::NAN);
+        assert_eq!(mute.volume(), 1.0);
+        let mute = MuteGain::new(MuteConfig::default(), SAMPLE_RATE).with_volume(3.0);
+        assert_eq!(mute.volume(), 1.0);
+    }
+}
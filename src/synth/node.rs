@@ -1,11 +1,106 @@
-use crate::synth::AudioBuffer;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{AudioBuffer, DriveFollows, Oversampling, StepRate};
 
 pub trait AudioNode {
     fn process(&mut self, input: &AudioBuffer, output: &mut AudioBuffer);
+
+    /// Clears anything the node carries from one block to the next, such as echoes still in a
+    /// delay line, so the next block starts from silence. Stateless nodes have nothing to do.
+    fn reset(&mut self) {}
+
+    /// How many samples late the node's output is relative to its input, for lining it up
+    /// with other paths. Nodes that don't delay the signal report 0.
+    fn latency_samples(&self) -> usize {
+        0
+    }
 }
 
+/// Settings for the wave shaper at the end of the signal chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WaveShaperConfig {
+    /// Gain applied before the transfer function. Higher values saturate harder; the output is
+    /// compensated so the level stays roughly the same.
+    pub drive: f32,
+    /// How far above the sample rate the shaper runs, to keep the harmonics it adds from
+    /// aliasing. Higher costs more CPU and adds a little latency.
+    pub oversampling: Oversampling,
+    /// What raises the drive while notes play, on top of `drive`.
+    pub drive_follows: DriveFollows,
+    /// Drive added when the followed value is at 1.
+    pub follow_amount: f32,
+    /// Seconds the drive takes to follow.
+    pub follow_smoothing: f32,
+}
+
+impl Default for WaveShaperConfig {
+    fn default() -> Self {
+        WaveShaperConfig {
+            drive: 1.0,
+            oversampling: Oversampling::default(),
+            drive_follows: DriveFollows::default(),
+            follow_amount: 2.0,
+            follow_smoothing: 0.05,
+        }
+    }
+}
+
+/// Number of points of the test sine used to level-match the drive.
+const MAKEUP_GAIN_POINTS: usize = 256;
+
+/// Amplitude of the test sine, around the level a few voices mix to.
+const MAKEUP_GAIN_LEVEL: f32 = 0.5;
+
 pub struct WaveShaperNode<F: FnMut(f32) -> f32> {
     pub transfer_fn: F,
+    drive: f32,
+    makeup_gain: f32,
+}
+
+impl<F: FnMut(f32) -> f32> WaveShaperNode<F> {
+    pub fn new(transfer_fn: F) -> Self {
+        WaveShaperNode {
+            transfer_fn,
+            drive: 1.0,
+            makeup_gain: 1.0,
+        }
+    }
+
+    pub fn drive(&self) -> f32 {
+        self.drive
+    }
+
+    /// Sets the pre-gain. The post-gain is chosen so that a sine at `MAKEUP_GAIN_LEVEL` comes
+    /// out at the same RMS level as it does at a drive of 1.0.
+    pub fn set_drive(&mut self, drive: f32) {
+        self.drive = if drive.is_finite() && drive > 0.0 {
+            drive
+        } else {
+            1.0
+        };
+        let reference = self.sine_rms(1.0);
+        let driven = self.sine_rms(self.drive);
+        self.makeup_gain = if driven > f32::EPSILON {
+            reference / driven
+        } else {
+            1.0
+        };
+    }
+
+    /// RMS of the test sine passed through the transfer function at `drive`.
+    fn sine_rms(&mut self, drive: f32) -> f32 {
+        let sum_of_squares: f32 = (0..MAKEUP_GAIN_POINTS)
+            .map(|i| {
+                let phase = i as f32 / MAKEUP_GAIN_POINTS as f32 * std::f32::consts::TAU;
+                (self.transfer_fn)(drive * MAKEUP_GAIN_LEVEL * phase.sin()).powi(2)
+            })
+            .sum();
+        (sum_of_squares / MAKEUP_GAIN_POINTS as f32).sqrt()
+    }
 }
 
 impl<F: FnMut(f32) -> f32> AudioNode for WaveShaperNode<F> {
@@ -14,14 +109,530 @@ impl<F: FnMut(f32) -> f32> AudioNode for WaveShaperNode<F> {
         assert_eq!(num_channels, output.num_channels());
 
         let transfer_fn = &mut self.transfer_fn;
+        let (drive, makeup_gain) = (self.drive, self.makeup_gain);
+
+        for i in 0..num_channels {
+            let input_channel = input.channel(i);
+            let output_channel = output.channel_mut(i);
+            for (input_sample, output_sample) in input_channel.iter().zip(output_channel.iter_mut())
+            {
+                *output_sample = transfer_fn(*input_sample * drive) * makeup_gain;
+            }
+        }
+    }
+}
+
+/// How a note division's plain length is stretched.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DivisionFeel {
+    #[default]
+    Straight,
+    /// One and a half times as long.
+    Dotted,
+    /// Two thirds as long, three in the time of two.
+    Triplet,
+}
+
+/// A musical note length such as `1/4` or `1/8 dotted`, as a fraction of a 4/4 bar.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteDivision {
+    pub length: StepRate,
+    pub feel: DivisionFeel,
+}
+
+impl NoteDivision {
+    pub fn new(length: StepRate, feel: DivisionFeel) -> Self {
+        NoteDivision { length, feel }
+    }
+
+    /// Length in beats, with four beats to the bar.
+    pub fn beats(&self) -> f32 {
+        let stretch = match self.feel {
+            DivisionFeel::Straight => 1.0,
+            DivisionFeel::Dotted => 1.5,
+            DivisionFeel::Triplet => 2.0 / 3.0,
+        };
+        self.length.beats() * stretch
+    }
+
+    /// Length in seconds at `bpm` beats per minute.
+    pub fn seconds(&self, bpm: f32) -> f32 {
+        self.beats() * 60.0 / bpm
+    }
+}
+
+impl FromStr for NoteDivision {
+    type Err = anyhow::Error;
+
+    /// Parses a fraction of a bar, optionally followed by `dotted` or `triplet`.
+    fn from_str(text: &str) -> Result<Self> {
+        let (length, feel) = match text.trim().split_once(char::is_whitespace) {
+            Some((length, "dotted")) => (length, DivisionFeel::Dotted),
+            Some((length, "triplet")) => (length, DivisionFeel::Triplet),
+            Some((_, feel)) => {
+                return Err(anyhow!(
+                    "Unknown note division '{}' in '{}', expected 'dotted' or 'triplet'",
+                    feel.trim(),
+                    text
+                ))
+            }
+            None => (text, DivisionFeel::Straight),
+        };
+        Ok(NoteDivision::new(length.parse()?, feel))
+    }
+}
+
+/// Echoes the input after a fixed time, feeding a share of each echo back into the line.
+pub struct DelayNode {
+    sample_rate: f32,
+    delay_samples: usize,
+    feedback: f32,
+    mix: f32,
+    /// One ring buffer of `delay_samples` per channel, allocated on the first block.
+    lines: Vec<Vec<f32>>,
+    position: usize,
+}
+
+impl DelayNode {
+    pub fn new(sample_rate: f32, delay_time: f32) -> Self {
+        let mut delay = DelayNode {
+            sample_rate,
+            delay_samples: 1,
+            feedback: 0.3,
+            mix: 0.3,
+            lines: Vec::new(),
+            position: 0,
+        };
+        delay.set_delay_time(delay_time);
+        delay
+    }
+
+    /// Sets the delay in seconds. Changing it clears the echoes already in the line.
+    pub fn set_delay_time(&mut self, delay_time: f32) {
+        let delay_samples = delay_samples(delay_time, self.sample_rate);
+        if delay_samples != self.delay_samples {
+            self.delay_samples = delay_samples;
+            self.lines.clear();
+            self.position = 0;
+        }
+    }
+
+    /// Sets the delay to `division` at `bpm` beats per minute, e.g. a dotted eighth.
+    pub fn set_tempo_sync(&mut self, bpm: f32, division: NoteDivision) {
+        self.set_delay_time(division.seconds(bpm));
+    }
+
+    /// Length of the delay line in samples per channel.
+    pub fn delay_samples(&self) -> usize {
+        self.delay_samples
+    }
+
+    /// Sets the share of each echo fed back into the line. Kept below 1.0 so the echoes die
+    /// away.
+    pub fn set_feedback(&mut self, feedback: f32) {
+        self.feedback = feedback.clamp(0.0, 0.95);
+    }
 
+    /// Sets the share of the delayed signal in the output, from 0.0 (dry) to 1.0 (wet only).
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+}
+
+/// Length of a delay line for `delay_time` seconds, at least one sample.
+fn delay_samples(delay_time: f32, sample_rate: f32) -> usize {
+    if delay_time.is_finite() && delay_time > 0.0 {
+        ((delay_time * sample_rate).round() as usize).max(1)
+    } else {
+        1
+    }
+}
+
+impl AudioNode for DelayNode {
+    fn process(&mut self, input: &AudioBuffer, output: &mut AudioBuffer) {
+        let num_channels = input.num_channels();
+        assert_eq!(num_channels, output.num_channels());
+
+        if self.lines.len() != num_channels {
+            self.lines = vec![vec![0.0; self.delay_samples]; num_channels];
+            self.position = 0;
+        }
+
+        let (feedback, mix) = (self.feedback, self.mix);
         for i in 0..num_channels {
+            let line = &mut self.lines[i];
+            let mut position = self.position;
             let input_channel = input.channel(i);
             let output_channel = output.channel_mut(i);
             for (input_sample, output_sample) in input_channel.iter().zip(output_channel.iter_mut())
             {
-                *output_sample = transfer_fn(*input_sample);
+                let delayed = line[position];
+                line[position] = *input_sample + delayed * feedback;
+                *output_sample = *input_sample * (1.0 - mix) + delayed * mix;
+                position = (position + 1) % line.len();
+            }
+        }
+        self.position = (self.position + input.num_frames()) % self.delay_samples;
+    }
+
+    fn reset(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.fill(0.0);
+        }
+        self.position = 0;
+    }
+}
+
+/// A stereo delay whose echoes bounce between the channels: a sound on the left echoes first
+/// on the right, then on the left, and so on. Buffers that aren't stereo pass through dry.
+pub struct PingPongDelayNode {
+    sample_rate: f32,
+    delay_samples: usize,
+    feedback: f32,
+    mix: f32,
+    /// Echoes on their way to the right channel, fed by the left input and the left echoes.
+    to_right: Vec<f32>,
+    /// Echoes on their way to the left channel, fed by the right input and the right echoes.
+    to_left: Vec<f32>,
+    position: usize,
+}
+
+impl PingPongDelayNode {
+    pub fn new(sample_rate: f32, delay_time: f32) -> Self {
+        let delay_samples = delay_samples(delay_time, sample_rate);
+        PingPongDelayNode {
+            sample_rate,
+            delay_samples,
+            feedback: 0.3,
+            mix: 0.3,
+            to_right: vec![0.0; delay_samples],
+            to_left: vec![0.0; delay_samples],
+            position: 0,
+        }
+    }
+
+    /// Sets the time between echoes in seconds. Changing it clears the echoes already in the
+    /// lines.
+    pub fn set_delay_time(&mut self, delay_time: f32) {
+        let delay_samples = delay_samples(delay_time, self.sample_rate);
+        if delay_samples != self.delay_samples {
+            self.delay_samples = delay_samples;
+            self.to_right = vec![0.0; delay_samples];
+            self.to_left = vec![0.0; delay_samples];
+            self.position = 0;
+        }
+    }
+
+    /// Sets the time between echoes to `division` at `bpm` beats per minute.
+    pub fn set_tempo_sync(&mut self, bpm: f32, division: NoteDivision) {
+        self.set_delay_time(division.seconds(bpm));
+    }
+
+    /// Length of each delay line in samples.
+    pub fn delay_samples(&self) -> usize {
+        self.delay_samples
+    }
+
+    /// Sets the share of each echo fed across to the other channel. Kept below 1.0 so the
+    /// echoes die away.
+    pub fn set_feedback(&mut self, feedback: f32) {
+        self.feedback = feedback.clamp(0.0, 0.95);
+    }
+
+    /// Sets the share of the delayed signal in the output, from 0.0 (dry) to 1.0 (wet only).
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+}
+
+impl AudioNode for PingPongDelayNode {
+    fn process(&mut self, input: &AudioBuffer, output: &mut AudioBuffer) {
+        assert_eq!(input.num_channels(), output.num_channels());
+        if input.num_channels() != 2 {
+            output.data.copy_from_slice(&input.data);
+            return;
+        }
+
+        let (feedback, mix) = (self.feedback, self.mix);
+        let num_frames = input.num_frames();
+        let (left_output, right_output) = output.data.split_at_mut(num_frames);
+        let frames = input
+            .channel(0)
+            .iter()
+            .zip(input.channel(1))
+            .zip(left_output.iter_mut().zip(right_output.iter_mut()));
+        for ((left_input, right_input), (left_sample, right_sample)) in frames {
+            let position = self.position;
+            let right_echo = self.to_right[position];
+            let left_echo = self.to_left[position];
+            self.to_right[position] = *left_input + left_echo * feedback;
+            self.to_left[position] = *right_input + right_echo * feedback;
+            *left_sample = *left_input * (1.0 - mix) + left_echo * mix;
+            *right_sample = *right_input * (1.0 - mix) + right_echo * mix;
+            self.position = (position + 1) % self.delay_samples;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.to_right.fill(0.0);
+        self.to_left.fill(0.0);
+        self.position = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CYCLE: usize = 512;
+
+    /// Four cycles of a sine at `MAKEUP_GAIN_LEVEL`, as a mono buffer.
+    fn sine_buffer() -> AudioBuffer {
+        AudioBuffer {
+            data: (0..4 * CYCLE)
+                .map(|i| {
+                    MAKEUP_GAIN_LEVEL * (i as f32 / CYCLE as f32 * std::f32::consts::TAU).sin()
+                })
+                .collect(),
+            num_channels: 1,
+        }
+    }
+
+    /// Amplitude of the `harmonic`th multiple of the sine's frequency in `samples`.
+    fn harmonic_amplitude(samples: &[f32], harmonic: usize) -> f32 {
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (i, sample)| {
+                let phase = (harmonic * i) as f32 / CYCLE as f32 * std::f32::consts::TAU;
+                (re + sample * phase.cos(), im + sample * phase.sin())
+            });
+        2.0 * (re * re + im * im).sqrt() / samples.len() as f32
+    }
+
+    /// Energy in the 2nd to 15th harmonics relative to the fundamental.
+    fn distortion(samples: &[f32]) -> f32 {
+        let harmonics: f32 = (2..16)
+            .map(|harmonic| harmonic_amplitude(samples, harmonic).powi(2))
+            .sum();
+        harmonics.sqrt() / harmonic_amplitude(samples, 1)
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    fn shaped(input: &AudioBuffer, drive: f32) -> Vec<f32> {
+        let mut shaper = WaveShaperNode::new(|sample: f32| sample.tanh());
+        shaper.set_drive(drive);
+        let mut output = AudioBuffer {
+            data: vec![0.0; input.data.len()],
+            num_channels: 1,
+        };
+        shaper.process(input, &mut output);
+        output.data
+    }
+
+    #[test]
+    fn more_drive_adds_harmonics_at_the_same_level() {
+        let input = sine_buffer();
+        let reference = rms(&shaped(&input, 1.0));
+        let mut last_distortion = distortion(&input.data);
+        assert!(last_distortion < 1e-3);
+        for drive in [1.0, 2.0, 4.0, 8.0] {
+            let output = shaped(&input, drive);
+            let distortion = distortion(&output);
+            assert!(
+                distortion > last_distortion,
+                "drive {}: {} after {}",
+                drive,
+                distortion,
+                last_distortion
+            );
+            last_distortion = distortion;
+
+            // Level-matched to a drive of 1.0, within a tenth of a decibel.
+            let level_db = 20.0 * (rms(&output) / reference).log10();
+            assert!(level_db.abs() < 0.1, "drive {}: {} dB", drive, level_db);
+        }
+    }
+
+    #[test]
+    fn a_bad_drive_falls_back_to_unity() {
+        for drive in [0.0, -2.0, f32::NAN, f32::INFINITY] {
+            let mut shaper = WaveShaperNode::new(|sample: f32| sample.tanh());
+            shaper.set_drive(drive);
+            assert_eq!(shaper.drive(), 1.0, "{}", drive);
+        }
+    }
+
+    fn division(text: &str) -> NoteDivision {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn an_eighth_note_delay_at_120_bpm_is_a_quarter_second_line() {
+        let mut delay = DelayNode::new(44_100.0, 0.5);
+        delay.set_tempo_sync(120.0, division("1/8"));
+        assert_eq!(delay.delay_samples(), 11_025);
+
+        // The ring buffer is allocated at that length, and an impulse comes back out exactly
+        // that many samples later.
+        delay.set_mix(1.0);
+        delay.set_feedback(0.0);
+        let mut input = AudioBuffer {
+            data: vec![0.0; 12_000],
+            num_channels: 1,
+        };
+        input.data[0] = 1.0;
+        let mut output = AudioBuffer {
+            data: vec![0.0; 12_000],
+            num_channels: 1,
+        };
+        delay.process(&input, &mut output);
+        assert_eq!(delay.lines[0].len(), 11_025);
+        let echo = output.data.iter().position(|sample| *sample != 0.0);
+        assert_eq!(echo, Some(11_025));
+    }
+
+    #[test]
+    fn dotted_and_triplet_divisions_stretch_the_delay() {
+        let mut delay = DelayNode::new(44_100.0, 0.5);
+        delay.set_tempo_sync(120.0, division("1/8 dotted"));
+        assert_eq!(delay.delay_samples(), 16_538);
+        delay.set_tempo_sync(120.0, division("1/4 triplet"));
+        assert_eq!(delay.delay_samples(), 14_700);
+        delay.set_tempo_sync(90.0, division("1/4"));
+        assert_eq!(delay.delay_samples(), 29_400);
+    }
+
+    #[test]
+    fn note_divisions_parse() {
+        assert_eq!(division("1/8").beats(), 0.5);
+        assert_eq!(division("1/8 dotted").feel, DivisionFeel::Dotted);
+        assert_eq!(division(" 1/2 triplet ").feel, DivisionFeel::Triplet);
+        assert!("1/8 swung".parse::<NoteDivision>().is_err());
+        assert!("eighth".parse::<NoteDivision>().is_err());
+    }
+
+    #[test]
+    fn ping_pong_echoes_alternate_between_the_channels() {
+        const DELAY: usize = 100;
+        let mut delay = PingPongDelayNode::new(48_000.0, DELAY as f32 / 48_000.0);
+        assert_eq!(delay.delay_samples(), DELAY);
+        delay.set_mix(1.0);
+        delay.set_feedback(0.5);
+
+        // A left-only impulse, then silence, over blocks that don't line up with the delay so
+        // the lines have to carry over from one block to the next.
+        let block = 64;
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for start in (0..6 * DELAY).step_by(block) {
+            let mut input = AudioBuffer {
+                data: vec![0.0; 2 * block],
+                num_channels: 2,
+            };
+            if start == 0 {
+                input.data[0] = 1.0;
             }
+            let mut output = AudioBuffer {
+                data: vec![0.0; 2 * block],
+                num_channels: 2,
+            };
+            delay.process(&input, &mut output);
+            left.extend_from_slice(output.channel(0));
+            right.extend_from_slice(output.channel(1));
         }
+
+        let echoes = |samples: &[f32]| -> Vec<(usize, f32)> {
+            samples
+                .iter()
+                .enumerate()
+                .filter(|(_, sample)| sample.abs() > 1e-6)
+                .map(|(i, sample)| (i, *sample))
+                .collect()
+        };
+        // Wet only, so the dry impulse isn't heard; each echo is half the one before.
+        assert_eq!(
+            echoes(&right),
+            [(DELAY, 1.0), (3 * DELAY, 0.25), (5 * DELAY, 0.0625)]
+        );
+        assert_eq!(
+            echoes(&left),
+            [(2 * DELAY, 0.5), (4 * DELAY, 0.125), (6 * DELAY, 0.03125)]
+        );
+    }
+
+    #[test]
+    fn ping_pong_passes_mono_through_dry() {
+        let mut delay = PingPongDelayNode::new(48_000.0, 0.01);
+        delay.set_mix(1.0);
+        let input = AudioBuffer {
+            data: vec![0.25; 100],
+            num_channels: 1,
+        };
+        let mut output = AudioBuffer {
+            data: vec![0.0; 100],
+            num_channels: 1,
+        };
+        delay.process(&input, &mut output);
+        assert_eq!(output.data, input.data);
+    }
+
+    /// Runs one block of `samples` frames of silence through `node`, with an impulse at the
+    /// start of the first channel if `impulse` is set.
+    fn run_block(
+        node: &mut dyn AudioNode,
+        samples: usize,
+        num_channels: usize,
+        impulse: bool,
+    ) -> AudioBuffer {
+        let mut input = AudioBuffer {
+            data: vec![0.0; samples * num_channels],
+            num_channels,
+        };
+        if impulse {
+            input.data[0] = 1.0;
+        }
+        let mut output = AudioBuffer {
+            data: vec![0.0; samples * num_channels],
+            num_channels,
+        };
+        node.process(&input, &mut output);
+        output
+    }
+
+    #[test]
+    fn reset_clears_the_echoes_in_a_delay_line() {
+        let mut delay = DelayNode::new(48_000.0, 100.0 / 48_000.0);
+        delay.set_mix(1.0);
+        delay.set_feedback(0.9);
+        // Fill the line with a loud, long-ringing tail, stopping partway through it.
+        for _ in 0..5 {
+            run_block(&mut delay, 70, 1, true);
+        }
+        delay.reset();
+
+        let output = run_block(&mut delay, 250, 1, true);
+        let echoes: Vec<usize> = (0..output.data.len())
+            .filter(|&i| output.data[i] != 0.0)
+            .collect();
+        assert_eq!(echoes, [100, 200]);
+        assert_eq!(output.data[100], 1.0);
+    }
+
+    #[test]
+    fn reset_clears_the_echoes_in_a_ping_pong_delay() {
+        let mut delay = PingPongDelayNode::new(48_000.0, 100.0 / 48_000.0);
+        delay.set_mix(1.0);
+        delay.set_feedback(0.9);
+        for _ in 0..5 {
+            run_block(&mut delay, 70, 2, true);
+        }
+        delay.reset();
+
+        let output = run_block(&mut delay, 150, 2, false);
+        assert!(output.data.iter().all(|sample| *sample == 0.0));
     }
 }
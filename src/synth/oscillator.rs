@@ -1,11 +1,45 @@
 use std::sync::{atomic::Ordering, Arc};
 
 use serde_derive::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{trace, warn};
 
-use crate::synth::{AmplitudeEnvelope, TremoloEffect, WaveformGenerator};
+use crate::synth::{
+    keys::note_state::{NoteId, NoteSource},
+    log_limit::{LogLimiter, HOT_PATH_LOG_INTERVAL},
+    performance::DEFAULT_VELOCITY,
+    waveform_generator::{FrequencyLimits, LimitedFrequency},
+    AmplitudeEnvelope, BusId, CycleDirection, Drift, DriftConfig, EnvelopeStage, FrequencySlew,
+    Interpolation, KeyZone, ReleaseScalingConfig, StereoBlendConfig, TremoloEffect, UnisonConfig,
+    VariationConfig, VoiceStealingConfig, WaveformGenerator, WaveformSequence,
+};
 
-use super::tremolo::Tremolo;
+/// Shared by every voice, so a chord of bad notes logs one warning rather than one each.
+static FREQUENCY_WARNINGS: LogLimiter = LogLimiter::new();
+
+/// Logs, at most once a second, that `note` asked for a frequency it couldn't have.
+pub(crate) fn warn_limited_frequency(note: &str, frequency: f32, limited: LimitedFrequency) {
+    if let LimitedFrequency::InRange(_) = limited {
+        return;
+    }
+    if !FREQUENCY_WARNINGS.allow(HOT_PATH_LOG_INTERVAL) {
+        return;
+    }
+    match limited {
+        LimitedFrequency::InRange(_) => (),
+        LimitedFrequency::Clamped(clamped) => warn!(
+            "Voice {} asked for {} Hz, clamped to {} Hz",
+            note, frequency, clamped
+        ),
+        LimitedFrequency::Invalid => warn!(
+            "Voice {} asked for an invalid frequency ({} Hz), ignoring it",
+            note, frequency
+        ),
+    }
+}
+
+/// Gain a voice is mixed at when struck at `DEFAULT_VELOCITY`. Low enough that a handful of
+/// voices don't clip.
+pub const DEFAULT_GAIN: f32 = 0.1;
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub enum OscillatorWaveform {
@@ -14,15 +48,112 @@ pub enum OscillatorWaveform {
     Square,
     Sawtooth,
     Triangle,
+    /// A waveform registered with `WaveformGenerator::register_wavetable`, by the index it
+    /// returned.
+    Custom(usize),
+}
+
+impl OscillatorWaveform {
+    /// The built-in waveforms, in the order the waveform keys and the `Waveform` parameter
+    /// list them. Custom wavetables aren't included, since they are registered at run time.
+    pub fn all() -> &'static [OscillatorWaveform] {
+        &[
+            OscillatorWaveform::Silence,
+            OscillatorWaveform::Sine,
+            OscillatorWaveform::Square,
+            OscillatorWaveform::Sawtooth,
+            OscillatorWaveform::Triangle,
+        ]
+    }
+
+    /// The waveform's name, as the config writes it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OscillatorWaveform::Silence => "Silence",
+            OscillatorWaveform::Sine => "Sine",
+            OscillatorWaveform::Square => "Square",
+            OscillatorWaveform::Sawtooth => "Sawtooth",
+            OscillatorWaveform::Triangle => "Triangle",
+            OscillatorWaveform::Custom(_) => "Custom",
+        }
+    }
+
+    /// The built-in waveform called `name`, ignoring case.
+    pub fn from_name(name: &str) -> Option<OscillatorWaveform> {
+        OscillatorWaveform::all()
+            .iter()
+            .find(|waveform| waveform.name().eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    /// The built-in waveform `direction` of this one in `all()`, wrapping around at either
+    /// end. A custom wavetable steps onto the first or last built-in waveform.
+    pub fn cycled(self, direction: CycleDirection) -> OscillatorWaveform {
+        let all = OscillatorWaveform::all();
+        let index = all.iter().position(|waveform| *waveform == self);
+        let index = match (direction, index) {
+            (CycleDirection::Next, Some(index)) => (index + 1) % all.len(),
+            (CycleDirection::Next, None) => 0,
+            (CycleDirection::Previous, Some(index)) => (index + all.len() - 1) % all.len(),
+            (CycleDirection::Previous, None) => all.len() - 1,
+        };
+        all[index]
+    }
+}
+
+/// A snapshot of one voice, for debugging.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceInfo {
+    pub note: String,
+    pub freq: f32,
+    pub envelope_stage: EnvelopeStage,
+    pub amplitude: f32,
+    pub age_samples: u64,
 }
 
 #[derive(Debug)]
 pub struct Oscillator {
     waveform_generator: WaveformGenerator,
+    /// The right-hand waveform of a stereo blend, played alongside `waveform_generator`'s.
+    blend_generator: Option<WaveformGenerator>,
     envelope: AmplitudeEnvelope,
+    /// How the release follows how long the voice was held.
+    release_scaling: ReleaseScalingConfig,
     tremolo_effect: Arc<TremoloEffect>,
     pub note: String,
-    start_time: Option<f32>,
+    /// What is holding the voice's note, so it is released along with that and nothing else.
+    pub source: NoteSource,
+    /// The half of a split keyboard the voice was played from, which picks its preset.
+    pub zone: KeyZone,
+    /// The bus the voice is mixed on.
+    pub bus: BusId,
+    /// MIDI velocity the note was struck with.
+    pub velocity: u8,
+    /// Where the voice sits in its note's unison stack, counting from 0. A note without unison
+    /// has only voice 0.
+    pub unison_voice: usize,
+    /// Position in the stereo field, from -1.0 (left) to 1.0 (right).
+    pub pan: f32,
+    start_sample: Option<u64>,
+    release_sample: Option<u64>,
+    /// Engine sample index of the next sample this voice will generate.
+    position: u64,
+    /// Pattern that drives the waveform instead of `set_waveform` while it is set.
+    waveform_sequence: Option<Arc<WaveformSequence>>,
+    /// Level the voice is mixed at.
+    gain: f32,
+    /// Consecutive samples, up to the last one generated, that were below the silence threshold.
+    silent_samples: u64,
+    /// Whether the looper plays this voice rather than a held key.
+    pub looped: bool,
+    /// Pitch the voice plays, and the one `glide_to` is taking it to.
+    frequency_slew: FrequencySlew,
+    /// Octaves per second `glide_to` moves the pitch by.
+    glide_rate: f32,
+    /// How far the pitch and phase have wandered, for a voice that drifts.
+    drift: Option<Drift>,
+    /// How much of the tremolo is applied, easing between 0 and 1 as it is switched.
+    tremolo_mix: f32,
 }
 
 impl Oscillator {
@@ -37,17 +168,42 @@ impl Oscillator {
         release_time: f32,
         tremolo_effect: Arc<TremoloEffect>,
     ) -> Self {
+        // A voice started with the tremolo already on joins in at full depth.
+        let tremolo_mix = if tremolo_effect.enabled.load(Ordering::Relaxed) {
+            1.0
+        } else {
+            0.0
+        };
         Oscillator {
             waveform_generator: WaveformGenerator::new(waveform, frequency, sample_rate),
+            blend_generator: None,
             envelope: AmplitudeEnvelope {
                 attack_time,
                 decay_time,
                 sustain_level,
                 release_time,
+                release_fade: None,
             },
+            release_scaling: ReleaseScalingConfig::default(),
             tremolo_effect,
             note,
-            start_time: None,
+            source: NoteSource::Shared,
+            zone: KeyZone::default(),
+            bus: BusId::MAIN,
+            velocity: DEFAULT_VELOCITY,
+            unison_voice: 0,
+            pan: 0.0,
+            start_sample: None,
+            release_sample: None,
+            position: 0,
+            waveform_sequence: None,
+            gain: DEFAULT_GAIN,
+            silent_samples: 0,
+            looped: false,
+            frequency_slew: FrequencySlew::new(frequency),
+            glide_rate: 0.0,
+            drift: None,
+            tremolo_mix,
         }
     }
 
@@ -55,69 +211,356 @@ impl Oscillator {
         OscillatorBuilder::default()
     }
 
-    pub fn generate_wave(&mut self, current_time: f32, num_samples: usize) -> Vec<f32> {
+    /// Generates `num_samples` samples starting at engine sample `current_sample`.
+    ///
+    /// A voice that was never started starts at the first sample it generates; one started
+    /// later than `current_sample` is silent until then. A stereo blend's right-hand waveform
+    /// is generated too, but left out.
+    pub fn generate_wave(&mut self, current_sample: u64, num_samples: usize) -> Vec<f32> {
+        self.generate_layers(current_sample, num_samples).0
+    }
+
+    /// Generates `num_samples` samples as `generate_wave` does, along with the right-hand
+    /// waveform's for a stereo blend.
+    pub fn generate_layers(
+        &mut self,
+        current_sample: u64,
+        num_samples: usize,
+    ) -> (Vec<f32>, Option<Vec<f32>>) {
         let mut output = Vec::with_capacity(num_samples);
-        let start_time = self.start_time.unwrap_or(current_time);
+        let mut blend_output = self
+            .blend_generator
+            .as_ref()
+            .map(|_| Vec::with_capacity(num_samples));
+        let start_sample = *self.start_sample.get_or_insert(current_sample);
 
-        let tremolo_enabled = self.tremolo_effect.enabled.load(Ordering::Relaxed);
+        // A glide towards a new pitch moves on a step at the start of each block, and so does
+        // drift, around wherever the glide has got to.
+        if !self.frequency_slew.is_settled() || self.drift.is_some() {
+            let seconds = num_samples as f32 / self.waveform_generator.sample_rate;
+            let mut frequency = self.frequency_slew.advance(seconds, self.glide_rate);
+            if let Some(drift) = &mut self.drift {
+                let (factor, phase_shift) = drift.advance(seconds);
+                frequency *= factor;
+                if phase_shift != 0.0 {
+                    for generator in self.generators_mut() {
+                        let phase = generator.get_phase();
+                        generator.set_phase(phase + phase_shift);
+                    }
+                }
+            }
+            if let Some(blend_generator) = &mut self.blend_generator {
+                blend_generator.set_frequency(frequency);
+            }
+            let limited = self.waveform_generator.set_frequency(frequency);
+            warn_limited_frequency(&self.note, frequency, limited);
+        }
 
         for i in 0..num_samples {
-            let sample_time = current_time + i as f32 / self.waveform_generator.sample_rate;
+            let sample_index = current_sample + i as u64;
+            // A voice started partway through the block stays silent until its start.
+            if sample_index < start_sample {
+                output.push(0.0);
+                if let Some(blend_output) = &mut blend_output {
+                    blend_output.push(0.0);
+                }
+                continue;
+            }
+            if let Some(waveform_sequence) = &self.waveform_sequence {
+                let shape = waveform_sequence.shape_at(sample_index);
+                self.waveform_generator
+                    .set_morph(shape.from, shape.to, shape.mix);
+            }
             let sample = self.waveform_generator.get_sample();
 
-            let envelope_value = self.envelope.amplitude_at_time(sample_time - start_time);
-            let mut output_sample = sample * envelope_value;
+            let envelope_value = self.envelope.amplitude_released(
+                self.seconds_since(start_sample, sample_index),
+                self.release_sample
+                    .map(|release| self.seconds_since(start_sample, release)),
+            );
+            let mut level = envelope_value;
 
-            if tremolo_enabled {
-                let rate = self.tremolo_effect.get_rate();
-                let depth = self.tremolo_effect.get_depth();
-                let mut tremolo = Tremolo::new(rate, depth, self.waveform_generator.sample_rate);
-                output_sample = tremolo.process(output_sample, self.waveform_generator.sample_rate);
+            // Switching the tremolo fades it in or out rather than jumping, so neither the
+            // sound nor the visualizer steps.
+            let sample_rate = self.waveform_generator.sample_rate;
+            self.tremolo_mix = self.tremolo_effect.next_mix(self.tremolo_mix, sample_rate);
+            if self.tremolo_mix > 0.0 {
+                level *= self
+                    .tremolo_effect
+                    .gain(sample_index, sample_rate, self.tremolo_mix);
             }
 
-            output.push(output_sample);
+            output.push(sample * level);
+            if let (Some(blend_output), Some(blend_generator)) =
+                (&mut blend_output, &mut self.blend_generator)
+            {
+                blend_output.push(blend_generator.get_sample() * level);
+            }
         }
 
-        output
+        self.position = current_sample + num_samples as u64;
+        (output, blend_output)
     }
 
-    pub fn start_note(&mut self, start_time: f32) {
-        self.start_time = Some(start_time);
+    /// The voice's waveform generators: its own, and the right-hand one of a stereo blend.
+    fn generators_mut(&mut self) -> impl Iterator<Item = &mut WaveformGenerator> {
+        std::iter::once(&mut self.waveform_generator).chain(self.blend_generator.as_mut())
     }
 
-    pub fn release_note(&mut self, current_time: f32) {
-        if let Some(start_time) = self.start_time {
-            let envelope_value = self.envelope.amplitude_at_time(current_time - start_time);
-            if envelope_value <= 0.0 {
-                self.start_time = None;
-            }
+    /// The held note this voice plays.
+    pub fn note_id(&self) -> NoteId {
+        NoteId::new(self.note.clone(), self.source.clone())
+    }
+
+    /// Whether this voice plays the held note `id`.
+    pub fn plays(&self, id: &NoteId) -> bool {
+        self.note == id.note && self.source == id.source
+    }
+
+    /// Starts the voice's envelope at engine sample `start_sample`.
+    pub fn start(&mut self, start_sample: u64) {
+        self.start_sample = Some(start_sample);
+        self.release_sample = None;
+        self.envelope.release_fade = None;
+        self.position = start_sample;
+    }
+
+    /// Releases the voice at engine sample `release_sample`, with a release as long as release
+    /// scaling makes it for how long the voice was held. Releasing twice keeps the first.
+    pub fn release(&mut self, release_sample: u64) {
+        if self.release_sample.is_none() {
+            self.release_sample = Some(release_sample);
+            self.envelope.release_fade = self.start_sample.map(|start| {
+                self.release_scaling.release_time(
+                    self.envelope.release_time,
+                    self.seconds_since(start, release_sample),
+                )
+            });
+        }
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.release_sample.is_some()
+    }
+
+    /// Whether the voice has been released and its release has faded out, so it can be dropped.
+    pub fn is_finished(&self) -> bool {
+        self.is_released() && self.envelope_stage() == EnvelopeStage::Finished
+    }
+
+    pub fn voice_debug_info(&self) -> VoiceInfo {
+        VoiceInfo {
+            note: self.note.clone(),
+            freq: self.get_frequency(),
+            envelope_stage: self.envelope_stage(),
+            amplitude: self.amplitude(),
+            age_samples: self
+                .start_sample
+                .map_or(0, |start| self.position.saturating_sub(start)),
+        }
+    }
+
+    /// Engine sample the voice was started at, once it has been.
+    pub fn start_sample(&self) -> Option<u64> {
+        self.start_sample
+    }
+
+    /// Seconds the voice has been sounding as of engine time `current_time`, in seconds as
+    /// `SynthEngine::current_time` gives it. Zero before the voice starts, or if it never has.
+    pub fn age(&self, current_time: f32) -> f32 {
+        self.start_sample.map_or(0.0, |start| {
+            (current_time - start as f32 / self.waveform_generator.sample_rate).max(0.0)
+        })
+    }
+
+    fn envelope_stage(&self) -> EnvelopeStage {
+        match self.start_sample {
+            Some(start) => self.envelope.stage_at_time(
+                self.seconds_since(start, self.position),
+                self.release_sample
+                    .map(|release| self.seconds_since(start, release)),
+            ),
+            None => EnvelopeStage::Idle,
+        }
+    }
+
+    fn amplitude(&self) -> f32 {
+        match self.start_sample {
+            Some(start) => self.envelope.amplitude_released(
+                self.seconds_since(start, self.position),
+                self.release_sample
+                    .map(|release| self.seconds_since(start, release)),
+            ),
+            None => 0.0,
         }
     }
 
+    fn seconds_since(&self, start_sample: u64, sample: u64) -> f32 {
+        (sample as i64 - start_sample as i64) as f32 / self.waveform_generator.sample_rate
+    }
+
     pub fn set_waveform(&mut self, waveform: OscillatorWaveform) {
-        debug!("Setting waveform to {:?}", waveform);
-        self.waveform_generator = WaveformGenerator::new(
-            waveform,
-            self.waveform_generator.get_frequency(),
-            self.waveform_generator.sample_rate,
-        );
-        debug!(
+        trace!("Setting waveform to {:?}", waveform);
+        self.waveform_generator = self.generator_for(waveform);
+        trace!(
             "Waveform set to {:?}",
             self.waveform_generator.get_waveform()
         )
     }
 
-    pub fn set_frequency(&mut self, frequency: f32) {
-        self.waveform_generator.set_frequency(frequency);
+    /// A generator playing `waveform` at the voice's frequency, phase, interpolation and limits.
+    fn generator_for(&self, waveform: OscillatorWaveform) -> WaveformGenerator {
+        let frequency = self.waveform_generator.get_frequency();
+        let mut generator =
+            WaveformGenerator::new(waveform, frequency, self.waveform_generator.sample_rate);
+        generator.set_interpolation(self.waveform_generator.get_interpolation());
+        generator.set_phase(self.waveform_generator.get_phase());
+        generator.set_frequency_limits(self.waveform_generator.get_frequency_limits());
+        generator.set_frequency(frequency);
+        generator
+    }
+
+    /// Plays the first waveform of `waveforms` on the left and the second on the right, in
+    /// phase with each other, in place of any waveform sequence. `None` goes back to the one
+    /// waveform, the left one.
+    pub fn set_stereo_waveforms(
+        &mut self,
+        waveforms: Option<(OscillatorWaveform, OscillatorWaveform)>,
+    ) {
+        let Some((left, right)) = waveforms else {
+            self.blend_generator = None;
+            return;
+        };
+        self.set_waveform_sequence(None);
+        self.set_waveform(left);
+        self.blend_generator = Some(self.generator_for(right));
+    }
+
+    /// The left and right waveforms of a stereo blend, if the voice plays one.
+    pub fn stereo_waveforms(&self) -> Option<(OscillatorWaveform, OscillatorWaveform)> {
+        self.blend_generator.as_ref().map(|blend_generator| {
+            (
+                self.waveform_generator.get_waveform(),
+                blend_generator.get_waveform(),
+            )
+        })
+    }
+
+    /// Sets the frequency, clamped to the voice's limits, dropping any glide in progress. A NaN
+    /// or infinite frequency is ignored. Either case logs a rate-limited warning naming the
+    /// note.
+    pub fn set_frequency(&mut self, frequency: f32) -> LimitedFrequency {
+        self.frequency_slew.jump_to(frequency);
+        if let Some(blend_generator) = &mut self.blend_generator {
+            blend_generator.set_frequency(frequency);
+        }
+        let limited = self.waveform_generator.set_frequency(frequency);
+        warn_limited_frequency(&self.note, frequency, limited);
+        limited
+    }
+
+    /// Changes the range the voice's frequency is clamped to.
+    pub fn set_frequency_limits(&mut self, frequency_limits: FrequencyLimits) {
+        let frequency = self.get_frequency();
+        if let Some(blend_generator) = &mut self.blend_generator {
+            blend_generator.set_frequency_limits(frequency_limits);
+        }
+        let limited = self
+            .waveform_generator
+            .set_frequency_limits(frequency_limits);
+        warn_limited_frequency(&self.note, frequency, limited);
     }
 
     pub fn get_frequency(&self) -> f32 {
         self.waveform_generator.get_frequency()
     }
 
+    /// Glides to `frequency` at the voice's glide rate, a step each block, settling exactly on
+    /// it. The frequency is clamped to the voice's limits as it goes.
+    pub fn glide_to(&mut self, frequency: f32) {
+        self.frequency_slew.set_target(frequency);
+    }
+
+    /// The frequency the voice is gliding to, or playing once it gets there, before it is
+    /// clamped to the voice's limits.
+    pub fn target_frequency(&self) -> f32 {
+        self.frequency_slew.target()
+    }
+
+    /// Sets how fast `glide_to` moves the pitch, in octaves per second. Zero or less jumps
+    /// straight to the new pitch at the next block.
+    pub fn set_glide_rate(&mut self, glide_rate: f32) {
+        self.glide_rate = glide_rate;
+    }
+
+    /// Lets the pitch and phase wander as `drift` has them, from the next block on, or holds
+    /// them steady with `None`. The frequency set or glided to stays the one drift wanders
+    /// around.
+    pub fn set_drift(&mut self, drift: Option<Drift>) {
+        self.drift = drift;
+    }
+
+    /// How far the pitch has drifted from the frequency set or glided to, in cents.
+    pub fn drift_cents(&self) -> f32 {
+        self.drift.as_ref().map_or(0.0, Drift::cents)
+    }
+
     pub fn get_waveform(&self) -> OscillatorWaveform {
         self.waveform_generator.get_waveform()
     }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        for generator in self.generators_mut() {
+            generator.set_interpolation(interpolation);
+        }
+    }
+
+    /// Sets where in its cycle the oscillator is, in cycles from `0.0` to `1.0`.
+    pub fn set_phase(&mut self, phase: f32) {
+        for generator in self.generators_mut() {
+            generator.set_phase(phase);
+        }
+    }
+
+    pub fn get_phase(&self) -> f32 {
+        self.waveform_generator.get_phase()
+    }
+
+    /// Lets `waveform_sequence` pick the waveform sample by sample, or hands it back to
+    /// `set_waveform` with `None`.
+    pub fn set_waveform_sequence(&mut self, waveform_sequence: Option<Arc<WaveformSequence>>) {
+        if waveform_sequence.is_none() {
+            self.waveform_generator.clear_morph();
+        }
+        self.waveform_sequence = waveform_sequence;
+    }
+
+    pub fn has_waveform_sequence(&self) -> bool {
+        self.waveform_sequence.is_some()
+    }
+
+    /// Counts how long the voice has been silent, given the samples it just generated. Any
+    /// sample at or above `threshold` resets the count.
+    pub fn track_silence(&mut self, samples: &[f32], threshold: f32) {
+        match samples.iter().rposition(|sample| sample.abs() >= threshold) {
+            Some(last_loud) => self.silent_samples = (samples.len() - last_loud - 1) as u64,
+            None => self.silent_samples += samples.len() as u64,
+        }
+    }
+
+    /// Consecutive samples the voice has been silent for.
+    pub fn silent_samples(&self) -> u64 {
+        self.silent_samples
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    /// Level the voice is mixed at.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
 }
 
 pub struct OscillatorBuilder {
@@ -130,6 +573,15 @@ pub struct OscillatorBuilder {
     sustain_level: f32,
     release_time: f32,
     tremolo_effect: Option<Arc<TremoloEffect>>,
+    interpolation: Interpolation,
+    phase: f32,
+    frequency_limits: FrequencyLimits,
+    velocity: u8,
+    velocity_to_attack: f32,
+    gain: Option<f32>,
+    glide_rate: f32,
+    release_scaling: ReleaseScalingConfig,
+    drift: Option<Drift>,
 }
 
 impl Default for OscillatorBuilder {
@@ -144,6 +596,15 @@ impl Default for OscillatorBuilder {
             sustain_level: 0.7,
             release_time: 0.2,
             tremolo_effect: None,
+            interpolation: Interpolation::default(),
+            phase: 0.0,
+            frequency_limits: FrequencyLimits::default(),
+            velocity: DEFAULT_VELOCITY,
+            velocity_to_attack: 0.0,
+            gain: None,
+            glide_rate: 0.0,
+            release_scaling: ReleaseScalingConfig::default(),
+            drift: None,
         }
     }
 }
@@ -160,17 +621,38 @@ impl OscillatorBuilder {
             )
         });
 
-        Oscillator::new(
+        let mut oscillator = Oscillator::new(
             self.frequency,
             self.sample_rate,
             self.waveform,
             self.note,
-            self.attack_time,
+            AmplitudeEnvelope::velocity_attack_time(
+                self.attack_time,
+                self.velocity,
+                self.velocity_to_attack,
+            ),
             self.decay_time,
             self.sustain_level,
             self.release_time,
             tremolo_effect,
-        )
+        );
+        oscillator.set_interpolation(self.interpolation);
+        oscillator.set_phase(self.phase);
+        // The generator starts within the default limits, so the frequency is set again once
+        // the configured ones are in place.
+        oscillator
+            .waveform_generator
+            .set_frequency_limits(self.frequency_limits);
+        oscillator.set_frequency(self.frequency);
+        oscillator.set_glide_rate(self.glide_rate);
+        oscillator.set_drift(self.drift);
+        oscillator.release_scaling = self.release_scaling;
+        oscillator.velocity = self.velocity;
+        oscillator.set_gain(
+            self.gain
+                .unwrap_or(DEFAULT_GAIN * self.velocity.min(127) as f32 / DEFAULT_VELOCITY as f32),
+        );
+        oscillator
     }
 
     pub fn tremolo_effect(mut self, effect: Arc<TremoloEffect>) -> Self {
@@ -183,6 +665,16 @@ impl OscillatorBuilder {
         self
     }
 
+    pub fn sample_rate(mut self, sample_rate: f32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn note(mut self, note: String) -> Self {
+        self.note = note;
+        self
+    }
+
     pub fn waveform(mut self, waveform: OscillatorWaveform) -> Self {
         self.waveform = waveform;
         self
@@ -193,8 +685,568 @@ impl OscillatorBuilder {
         self
     }
 
+    pub fn decay_time(mut self, decay_time: f32) -> Self {
+        self.decay_time = decay_time;
+        self
+    }
+
+    pub fn sustain_level(mut self, sustain_level: f32) -> Self {
+        self.sustain_level = sustain_level;
+        self
+    }
+
     pub fn release_time(mut self, release_time: f32) -> Self {
         self.release_time = release_time;
         self
     }
+
+    pub fn interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    /// Initial phase offset in cycles, in `[0, 1)`. `0.25` starts a quarter-cycle in, which lets
+    /// stacked oscillators be aligned or spread apart.
+    pub fn phase(mut self, phase: f32) -> Self {
+        self.phase = phase;
+        self
+    }
+
+    /// MIDI velocity the note was struck with, from 0 to 127.
+    pub fn velocity(mut self, velocity: u8) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
+    /// How much velocity shortens the attack, from 0.0 (not at all) to 1.0 (no attack at full
+    /// velocity).
+    pub fn velocity_to_attack(mut self, velocity_to_attack: f32) -> Self {
+        self.velocity_to_attack = velocity_to_attack;
+        self
+    }
+
+    /// Level the voice is mixed at. Defaults to `DEFAULT_GAIN` scaled by the velocity relative
+    /// to `DEFAULT_VELOCITY`.
+    pub fn gain(mut self, gain: f32) -> Self {
+        self.gain = Some(gain);
+        self
+    }
+
+    /// Range the voice's frequency is clamped to.
+    pub fn frequency_limits(mut self, frequency_limits: FrequencyLimits) -> Self {
+        self.frequency_limits = frequency_limits;
+        self
+    }
+
+    /// Octaves per second the voice glides by when its pitch changes while it plays. Defaults
+    /// to 0.0, which jumps.
+    pub fn glide_rate(mut self, glide_rate: f32) -> Self {
+        self.glide_rate = glide_rate;
+        self
+    }
+
+    /// How the release follows how long the voice was held. Defaults to not at all.
+    pub fn release_scaling(mut self, release_scaling: ReleaseScalingConfig) -> Self {
+        self.release_scaling = release_scaling;
+        self
+    }
+
+    /// How far the voice's pitch and phase wander, with `seed` picking the way they go.
+    /// Defaults to not at all.
+    pub fn drift(mut self, config: DriftConfig, seed: u64) -> Self {
+        self.drift = config.is_enabled().then(|| Drift::new(config, seed));
+        self
+    }
+}
+
+/// Oscillator settings shared by every voice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OscillatorConfig {
+    pub interpolation: Interpolation,
+    /// Range every voice's frequency is clamped to.
+    pub frequency_limits: FrequencyLimits,
+    /// How much harder-struck notes shorten their attack, from 0.0 (not at all) to 1.0 (no
+    /// attack at full velocity).
+    pub velocity_to_attack: f32,
+    /// Output level below which a voice counts as silent.
+    pub silence_threshold: f32,
+    /// Seconds a voice may stay silent before it is dropped, even if its note is still held.
+    /// Zero or less keeps silent voices around.
+    pub silence_timeout: f32,
+    /// How fast sounding notes glide to their new pitch when the octave shift changes, in
+    /// octaves per second. The default takes 50 ms per octave; zero jumps.
+    pub retune_rate: f32,
+    /// Detuned copies of each note, spread across the stereo field.
+    pub unison: UnisonConfig,
+    /// Small differences between strikes of the same note.
+    pub variation: VariationConfig,
+    /// Longer releases for notes held longer.
+    pub release_scaling: ReleaseScalingConfig,
+    /// The voice limit, and which voice a new note takes over past it.
+    pub voice_stealing: VoiceStealingConfig,
+    /// Slow random wandering of each voice's pitch and phase.
+    pub drift: DriftConfig,
+    /// A second waveform for every voice, with the two panned apart.
+    pub stereo_blend: StereoBlendConfig,
+}
+
+impl Default for OscillatorConfig {
+    fn default() -> Self {
+        OscillatorConfig {
+            interpolation: Interpolation::default(),
+            frequency_limits: FrequencyLimits::default(),
+            velocity_to_attack: 0.0,
+            silence_threshold: 1e-4,
+            silence_timeout: 0.5,
+            retune_rate: 20.0,
+            unison: UnisonConfig::default(),
+            variation: VariationConfig::default(),
+            release_scaling: ReleaseScalingConfig::default(),
+            voice_stealing: VoiceStealingConfig::default(),
+            drift: DriftConfig::default(),
+            stereo_blend: StereoBlendConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    const START: u64 = 1_000;
+    /// 15 ms after the start, during the decay.
+    const RELEASE: u64 = START + 720;
+    /// 50 ms of release fade after that.
+    const FINISH: u64 = RELEASE + 2_400;
+
+    fn voice() -> Oscillator {
+        Oscillator::builder()
+            .sample_rate(SAMPLE_RATE)
+            .attack_time(0.01)
+            .decay_time(0.01)
+            .sustain_level(0.5)
+            .release_time(0.05)
+            .build()
+    }
+
+    #[test]
+    fn a_voice_goes_from_start_through_release_to_finished_at_any_buffer_size() {
+        for block in [1, 64, 256, 1_000, 4_096] {
+            let mut voice = voice();
+            assert_eq!(voice.voice_debug_info().envelope_stage, EnvelopeStage::Idle);
+            voice.start(START);
+            voice.release(RELEASE);
+            assert!(voice.is_released());
+
+            let mut position = 0;
+            let mut stages = vec![EnvelopeStage::Idle];
+            while !voice.is_finished() {
+                assert!(position < FINISH + 10_000, "block {} never finished", block);
+                let samples = voice.generate_wave(position, block);
+                for (i, sample) in samples.iter().enumerate() {
+                    let sample_index = position + i as u64;
+                    // A sample either side of the end is left for rounding.
+                    if !(START..=FINISH).contains(&sample_index) {
+                        assert_eq!(*sample, 0.0, "block {} sample {}", block, sample_index);
+                    }
+                }
+                position += block as u64;
+                let info = voice.voice_debug_info();
+                assert_eq!(info.age_samples, position.saturating_sub(START));
+                if stages.last() != Some(&info.envelope_stage) {
+                    stages.push(info.envelope_stage);
+                }
+            }
+
+            assert!(
+                (FINISH..=FINISH + block as u64 + 1).contains(&position),
+                "block {} finished at {}",
+                block,
+                position
+            );
+            assert_eq!(voice.voice_debug_info().amplitude, 0.0);
+            // Whatever a block skips over, the stages only ever move forward.
+            let order = |stage: &EnvelopeStage| {
+                [
+                    EnvelopeStage::Idle,
+                    EnvelopeStage::Attack,
+                    EnvelopeStage::Decay,
+                    EnvelopeStage::Release,
+                    EnvelopeStage::Finished,
+                ]
+                .iter()
+                .position(|s| s == stage)
+            };
+            assert!(stages.windows(2).all(|w| order(&w[0]) < order(&w[1])));
+            assert_eq!(stages.last(), Some(&EnvelopeStage::Finished));
+        }
+    }
+
+    #[test]
+    fn a_voice_is_not_finished_until_it_is_released() {
+        let mut voice = voice();
+        voice.start(0);
+        // The envelope runs out on its own long before this, but the note is still held.
+        voice.generate_wave(0, 48_000);
+        assert!(!voice.is_finished());
+        voice.release(48_000);
+        assert!(voice.is_finished());
+    }
+
+    #[test]
+    fn releasing_twice_keeps_the_first_release() {
+        let mut voice = voice();
+        voice.start(0);
+        voice.release(RELEASE - START);
+        voice.release(10 * RELEASE);
+        voice.generate_wave(0, (FINISH - START) as usize + 64);
+        assert!(voice.is_finished());
+    }
+
+    #[test]
+    fn restarting_a_voice_clears_its_release() {
+        let mut voice = voice();
+        voice.start(0);
+        voice.release(100);
+        voice.generate_wave(0, 48_000);
+        assert!(voice.is_finished());
+        voice.start(48_000);
+        assert!(!voice.is_released());
+        voice.generate_wave(48_000, 64);
+        assert_eq!(
+            voice.voice_debug_info().envelope_stage,
+            EnvelopeStage::Attack
+        );
+    }
+
+    #[test]
+    fn the_builder_starts_the_voice_at_its_phase_offset() {
+        for phase in [0.0, 0.25, 0.5] {
+            let voice = Oscillator::builder().phase(phase).build();
+            assert_eq!(voice.get_phase(), phase);
+        }
+        // Offsets past a whole cycle wrap into it.
+        let voice = Oscillator::builder().phase(1.25).build();
+        assert_eq!(voice.get_phase(), 0.25);
+    }
+
+    /// First sample at which a voice struck at `velocity` has left its attack.
+    fn attack_end(velocity: u8) -> u64 {
+        let mut voice = Oscillator::builder()
+            .sample_rate(SAMPLE_RATE)
+            .attack_time(0.05)
+            .velocity(velocity)
+            .velocity_to_attack(0.8)
+            .build();
+        voice.start(0);
+        (0..SAMPLE_RATE as u64)
+            .find(|&sample| {
+                voice.generate_wave(sample, 1);
+                voice.voice_debug_info().envelope_stage != EnvelopeStage::Attack
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn a_hard_note_finishes_its_attack_before_a_soft_one() {
+        let soft = attack_end(20);
+        let hard = attack_end(120);
+        assert!(hard < soft, "hard {} soft {}", hard, soft);
+        // 120/127 of 0.8 takes about three quarters off the 2400-sample attack.
+        assert!((hard as f32 - 2_400.0 * (1.0 - 0.8 * 120.0 / 127.0)).abs() <= 2.0);
+    }
+
+    #[test]
+    fn the_gain_defaults_from_the_velocity_unless_set() {
+        let voice = Oscillator::builder().velocity(DEFAULT_VELOCITY).build();
+        assert_eq!(voice.gain(), DEFAULT_GAIN);
+        let voice = Oscillator::builder().velocity(DEFAULT_VELOCITY / 2).build();
+        assert_eq!(voice.gain(), DEFAULT_GAIN * 0.5);
+        let voice = Oscillator::builder().velocity(20).gain(0.5).build();
+        assert_eq!(voice.gain(), 0.5);
+    }
+
+    #[test]
+    fn silence_is_counted_from_the_last_loud_sample() {
+        let mut voice = voice();
+        voice.track_silence(&[0.0; 100], 1e-4);
+        voice.track_silence(&[0.0; 100], 1e-4);
+        assert_eq!(voice.silent_samples(), 200);
+        let mut block = [0.0; 100];
+        block[89] = 0.5;
+        voice.track_silence(&block, 1e-4);
+        assert_eq!(voice.silent_samples(), 10);
+        voice.track_silence(&[5e-5; 100], 1e-4);
+        assert_eq!(voice.silent_samples(), 110);
+    }
+
+    #[test]
+    fn an_octave_up_on_a_held_note_glides_there_and_lands_exactly() {
+        const BLOCK: usize = 256;
+        // 20 octaves a second takes 50 ms, 2400 samples, to go up one.
+        let mut voice = Oscillator::builder()
+            .sample_rate(SAMPLE_RATE)
+            .frequency(440.0)
+            .glide_rate(20.0)
+            .build();
+        voice.start(0);
+        voice.generate_wave(0, BLOCK);
+        voice.glide_to(880.0);
+
+        let mut position = BLOCK as u64;
+        let mut frequencies = Vec::new();
+        while position < BLOCK as u64 + 2_400 {
+            voice.generate_wave(position, BLOCK);
+            frequencies.push(voice.frequency_slew.current());
+            position += BLOCK as u64;
+        }
+        // The pitch rises every block on the way, and is there within the glide time, rounded
+        // up to whole blocks.
+        assert!(frequencies.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(frequencies[..frequencies.len() - 1]
+            .iter()
+            .all(|&frequency| frequency < 880.0));
+        assert_eq!(frequencies.last(), Some(&880.0));
+
+        voice.generate_wave(position, BLOCK);
+        let fresh = Oscillator::builder()
+            .sample_rate(SAMPLE_RATE)
+            .frequency(880.0)
+            .build();
+        assert_eq!(
+            voice.waveform_generator.phase_inc().to_bits(),
+            fresh.waveform_generator.phase_inc().to_bits()
+        );
+    }
+
+    #[test]
+    fn a_glide_rate_of_zero_jumps_at_the_next_block() {
+        let mut voice = Oscillator::builder()
+            .sample_rate(SAMPLE_RATE)
+            .frequency(440.0)
+            .build();
+        voice.start(0);
+        voice.glide_to(880.0);
+        voice.generate_wave(0, 64);
+        assert_eq!(voice.frequency_slew.current(), 880.0);
+        assert_eq!(voice.waveform_generator.phase_inc(), 880.0 / SAMPLE_RATE);
+    }
+
+    /// Samples a voice held for `held` seconds, with its release scaled proportionally to the
+    /// hold, takes to fall silent once let go. The envelope's own release is long enough that
+    /// it is still sounding when let go.
+    fn release_tail(held: f32) -> u64 {
+        let mut voice = Oscillator::builder()
+            .sample_rate(SAMPLE_RATE)
+            .attack_time(0.01)
+            .decay_time(0.01)
+            .sustain_level(0.5)
+            .release_time(1.0)
+            .release_scaling(ReleaseScalingConfig {
+                amount: 1.0,
+                reference_hold: 0.2,
+                ..ReleaseScalingConfig::default()
+            })
+            .build();
+        let release = START + (held * SAMPLE_RATE) as u64;
+        voice.start(START);
+        voice.release(release);
+        let mut position = START;
+        while !voice.is_finished() {
+            voice.generate_wave(position, 64);
+            position += 64;
+        }
+        position - release
+    }
+
+    #[test]
+    fn a_note_held_twice_as_long_takes_twice_as_long_to_release() {
+        let short = release_tail(0.2);
+        let long = release_tail(0.4);
+        // The preset's second held for the reference hold, give or take a block.
+        assert!(short.abs_diff(48_000) <= 64, "{}", short);
+        assert!(
+            long.abs_diff(2 * short) <= 128,
+            "{} against {}",
+            long,
+            short
+        );
+    }
+
+    #[test]
+    fn all_lists_every_built_in_waveform_once() {
+        let all = OscillatorWaveform::all();
+        for waveform in all {
+            // A new variant fails to compile here until it is listed or ruled out.
+            match waveform {
+                OscillatorWaveform::Silence
+                | OscillatorWaveform::Sine
+                | OscillatorWaveform::Square
+                | OscillatorWaveform::Sawtooth
+                | OscillatorWaveform::Triangle => {}
+                OscillatorWaveform::Custom(_) => panic!("custom wavetables aren't built in"),
+            }
+            assert_eq!(all.iter().filter(|other| *other == waveform).count(), 1);
+        }
+        assert_eq!(all.len(), 5);
+    }
+
+    #[test]
+    fn waveform_names_round_trip_and_match_the_config() {
+        assert_eq!(
+            OscillatorWaveform::from_name("Sine"),
+            Some(OscillatorWaveform::Sine)
+        );
+        assert_eq!(OscillatorWaveform::Sine.name(), "Sine");
+        for &waveform in OscillatorWaveform::all() {
+            assert_eq!(
+                OscillatorWaveform::from_name(waveform.name()),
+                Some(waveform)
+            );
+            let written = serde_yaml::to_string(&waveform).unwrap();
+            assert_eq!(written.trim(), waveform.name());
+        }
+        assert_eq!(
+            OscillatorWaveform::from_name("sAWtooth"),
+            Some(OscillatorWaveform::Sawtooth)
+        );
+        assert_eq!(OscillatorWaveform::from_name("Custom"), None);
+        assert_eq!(OscillatorWaveform::from_name("Noise"), None);
+    }
+
+    #[test]
+    fn cycling_forward_from_triangle_wraps_to_the_first_waveform() {
+        let first = OscillatorWaveform::all()[0];
+        assert_eq!(
+            OscillatorWaveform::Triangle.cycled(CycleDirection::Next),
+            first
+        );
+        assert_eq!(
+            first.cycled(CycleDirection::Previous),
+            OscillatorWaveform::Triangle
+        );
+    }
+
+    #[test]
+    fn cycling_visits_every_waveform_in_order_either_way() {
+        let all = OscillatorWaveform::all();
+        let mut waveform = all[0];
+        for expected in all.iter().cycle().skip(1).take(2 * all.len()) {
+            waveform = waveform.cycled(CycleDirection::Next);
+            assert_eq!(waveform, *expected);
+        }
+        for _ in 0..all.len() {
+            let previous = waveform.cycled(CycleDirection::Previous);
+            assert_eq!(previous.cycled(CycleDirection::Next), waveform);
+            waveform = previous;
+        }
+    }
+
+    #[test]
+    fn cycling_from_a_custom_wavetable_steps_onto_the_built_in_ends() {
+        let all = OscillatorWaveform::all();
+        let custom = OscillatorWaveform::Custom(3);
+        assert_eq!(custom.cycled(CycleDirection::Next), all[0]);
+        assert_eq!(custom.cycled(CycleDirection::Previous), all[all.len() - 1]);
+    }
+
+    #[test]
+    fn age_is_zero_until_the_voice_starts_then_counts_up_with_the_time() {
+        let mut voice = voice();
+        assert_eq!(voice.age(0.0), 0.0);
+        assert_eq!(voice.age(5.0), 0.0);
+
+        voice.start(SAMPLE_RATE as u64);
+        assert_eq!(voice.age(0.0), 0.0);
+        assert_eq!(voice.age(0.5), 0.0);
+        assert_eq!(voice.age(1.0), 0.0);
+        let ages: Vec<f32> = [1.25, 2.0, 3.5].iter().map(|&now| voice.age(now)).collect();
+        assert_eq!(ages, [0.25, 1.0, 2.5]);
+        // Still counting after the release.
+        voice.release(2 * SAMPLE_RATE as u64);
+        assert_eq!(voice.age(4.0), 3.0);
+    }
+
+    #[test]
+    fn a_drifting_voice_wanders_off_its_pitch_but_stays_within_the_configured_cents() {
+        let drift = DriftConfig {
+            cents: 10.0,
+            ..DriftConfig::default()
+        };
+        let mut voice = Oscillator::builder()
+            .frequency(440.0)
+            .sample_rate(SAMPLE_RATE)
+            .waveform(OscillatorWaveform::Sine)
+            .drift(drift, 42)
+            .build();
+        let bound = 440.0 * 2.0f32.powf(10.0 / 1200.0);
+        let mut furthest = 0.0f32;
+        // Ten seconds of blocks.
+        for block in 0..940 {
+            voice.generate_wave(block * 512, 512);
+            let frequency = voice.get_frequency();
+            assert!(
+                (440.0 * 440.0 / bound..=bound).contains(&frequency),
+                "{}",
+                frequency
+            );
+            let cents = 1200.0 * (frequency / 440.0).log2();
+            assert!((cents - voice.drift_cents()).abs() < 1e-3);
+            furthest = furthest.max(cents.abs());
+        }
+        assert!(furthest > 3.0, "{}", furthest);
+        // The note it is tuned to stays put underneath.
+        assert_eq!(voice.target_frequency(), 440.0);
+    }
+
+    #[test]
+    fn a_voice_without_drift_holds_its_pitch() {
+        let mut voice = Oscillator::builder()
+            .frequency(440.0)
+            .sample_rate(SAMPLE_RATE)
+            .drift(DriftConfig::default(), 42)
+            .build();
+        let frequency = voice.get_frequency();
+        assert!((frequency - 440.0).abs() < 1e-3);
+        for block in 0..100 {
+            voice.generate_wave(block * 512, 512);
+            assert_eq!(voice.get_frequency(), frequency);
+        }
+        assert_eq!(voice.drift_cents(), 0.0);
+    }
+
+    #[test]
+    fn a_blended_voice_plays_one_wavetable_on_each_side() {
+        // An envelope long enough to still be sounding at the end.
+        let started = |waveform| {
+            let mut voice = Oscillator::builder()
+                .sample_rate(SAMPLE_RATE)
+                .waveform(waveform)
+                .release_time(10.0)
+                .build();
+            voice.start(0);
+            voice
+        };
+        let mut blended = started(OscillatorWaveform::Sine);
+        blended.set_stereo_waveforms(Some((
+            OscillatorWaveform::Sawtooth,
+            OscillatorWaveform::Square,
+        )));
+        let mut sawtooth = started(OscillatorWaveform::Sawtooth);
+        let mut square = started(OscillatorWaveform::Square);
+
+        for block in 0..20 {
+            let position = block * 256;
+            let (left, right) = blended.generate_layers(position, 256);
+            let right = right.expect("a blended voice has a right-hand waveform");
+            assert_eq!(left, sawtooth.generate_wave(position, 256));
+            assert_eq!(right, square.generate_wave(position, 256));
+            assert_ne!(left, right);
+        }
+        // Without the blend the voice plays its one waveform again.
+        blended.set_stereo_waveforms(None);
+        assert_eq!(blended.generate_layers(20 * 256, 256).1, None);
+    }
 }
@@ -0,0 +1,481 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{AudioBuffer, AudioNode};
+
+/// How far above the sample rate a nonlinear stage runs, so the harmonics it adds past
+/// Nyquist are filtered off instead of folding back down as aliasing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Oversampling {
+    /// Runs at the sample rate, with no filtering and no latency.
+    #[default]
+    #[serde(rename = "1x")]
+    X1,
+    #[serde(rename = "2x")]
+    X2,
+    #[serde(rename = "4x")]
+    X4,
+}
+
+impl Oversampling {
+    pub fn factor(&self) -> usize {
+        1 << self.stages()
+    }
+
+    /// Halfband stages, each doubling the rate.
+    fn stages(&self) -> usize {
+        match self {
+            Oversampling::X1 => 0,
+            Oversampling::X2 => 1,
+            Oversampling::X4 => 2,
+        }
+    }
+}
+
+/// Highest frequency the filters keep flat, as a fraction of the sample rate. 18 kHz at 44.1 kHz.
+const PASSBAND_FRACTION: f64 = 0.41;
+
+/// Above this the filters don't bother keeping the response flat, so high sample rates get
+/// wider transition bands and shorter filters.
+const PASSBAND_LIMIT_HZ: f64 = 20_000.0;
+
+/// How far the filters push down everything that would alias, in dB.
+const STOPBAND_ATTENUATION_DB: f64 = 90.0;
+
+/// Zeroth-order modified Bessel function of the first kind, for the Kaiser window.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+/// Designs a Kaiser-windowed halfband lowpass for doubling audio at `sample_rate`, or halving
+/// it back down to `sample_rate`. It cuts off at the lower rate's Nyquist frequency, keeps
+/// everything below `PASSBAND_FRACTION` of `sample_rate` flat, and is sized for the transition
+/// band between the two.
+///
+/// The length is of the form 4k + 3, so every other tap but the middle one is zero and the
+/// filter's delay is a whole number of samples at the lower rate.
+pub fn design_halfband(sample_rate: f32) -> Vec<f32> {
+    let sample_rate = sample_rate as f64;
+    let passband_edge = (PASSBAND_FRACTION * sample_rate).min(PASSBAND_LIMIT_HZ);
+    // Normalized to the doubled rate, the passband ends where the stopband's mirror image
+    // around a quarter of the rate begins.
+    let transition = ((sample_rate - 2.0 * passband_edge) / (2.0 * sample_rate)).max(0.01);
+    let estimate =
+        ((STOPBAND_ATTENUATION_DB - 7.95) / (2.285 * 2.0 * PI * transition)).ceil() as usize + 1;
+    let len = estimate.saturating_sub(3).div_ceil(4) * 4 + 3;
+
+    let beta = 0.1102 * (STOPBAND_ATTENUATION_DB - 8.7);
+    let middle = (len / 2) as isize;
+    (0..len)
+        .map(|index| {
+            let offset = index as isize - middle;
+            let sinc = if offset == 0 {
+                0.5
+            } else if offset % 2 == 0 {
+                0.0
+            } else {
+                (PI * offset as f64 / 2.0).sin() / (PI * offset as f64)
+            };
+            let position = offset as f64 / middle as f64;
+            let window =
+                bessel_i0(beta * (1.0 - position * position).max(0.0).sqrt()) / bessel_i0(beta);
+            (sinc * window) as f32
+        })
+        .collect()
+}
+
+/// One channel's filter memory for one doubling stage.
+#[derive(Debug, Clone)]
+struct HalfbandState {
+    /// Lower-rate input of the upsampler, most recent last, with `taps / 2` samples of history
+    /// in front.
+    up_history: Vec<f32>,
+    /// Higher-rate input of the downsampler, most recent last, with `taps - 1` samples of
+    /// history in front.
+    down_history: Vec<f32>,
+}
+
+/// A doubling stage: a halfband filter used both to interpolate up and to decimate down.
+#[derive(Debug, Clone)]
+struct HalfbandStage {
+    taps: Vec<f32>,
+    channels: Vec<HalfbandState>,
+}
+
+impl HalfbandStage {
+    fn new(sample_rate: f32) -> Self {
+        HalfbandStage {
+            taps: design_halfband(sample_rate),
+            channels: Vec::new(),
+        }
+    }
+
+    /// Makes sure there is filter memory for `count` channels, starting from silence.
+    fn ensure_channels(&mut self, count: usize) {
+        while self.channels.len() < count {
+            self.channels.push(HalfbandState {
+                up_history: vec![0.0; self.taps.len() / 2],
+                down_history: vec![0.0; self.taps.len() - 1],
+            });
+        }
+    }
+
+    /// Doubles the rate of `input` into `output`, which gets twice as many samples. The input
+    /// is zero-stuffed in effect; each output phase is worked out from its own half of the
+    /// taps, so the zeros are never multiplied.
+    fn upsample(&mut self, channel: usize, input: &[f32], output: &mut Vec<f32>) {
+        let (taps, state) = (&self.taps, &mut self.channels[channel]);
+        let history = taps.len() / 2;
+        state.up_history.extend_from_slice(input);
+        output.clear();
+        for index in 0..input.len() {
+            let newest = history + index;
+            for phase in 0..2 {
+                let sum: f32 = taps
+                    .iter()
+                    .skip(phase)
+                    .step_by(2)
+                    .enumerate()
+                    .map(|(k, tap)| tap * state.up_history[newest - k])
+                    .sum();
+                // Half the samples of the zero-stuffed signal are zeros, so the gain is doubled
+                // to keep the level.
+                output.push(2.0 * sum);
+            }
+        }
+        state.up_history.drain(..input.len());
+    }
+
+    /// Halves the rate of `input` into `output`, filtering off everything above the lower
+    /// rate's Nyquist frequency first.
+    fn downsample(&mut self, channel: usize, input: &[f32], output: &mut [f32]) {
+        let (taps, state) = (&self.taps, &mut self.channels[channel]);
+        let history = taps.len() - 1;
+        state.down_history.extend_from_slice(input);
+        for (index, sample) in output.iter_mut().enumerate() {
+            let newest = history + 2 * index;
+            *sample = taps
+                .iter()
+                .enumerate()
+                .map(|(k, tap)| tap * state.down_history[newest - k])
+                .sum();
+        }
+        state.down_history.drain(..input.len());
+    }
+
+    /// Delay of the stage's interpolation and decimation together, in samples at the lower
+    /// rate.
+    fn latency(&self) -> f32 {
+        (self.taps.len() - 1) as f32 / 2.0
+    }
+
+    fn reset(&mut self) {
+        self.channels.clear();
+    }
+}
+
+/// Runs `node` at a multiple of the sample rate.
+///
+/// Each block is interpolated up through halfband filters, processed, then filtered and
+/// decimated back down, so a nonlinear node's harmonics above the original Nyquist frequency
+/// are removed rather than aliased. At `Oversampling::X1` the node runs as it is.
+pub struct OversampledNode<N: AudioNode> {
+    node: N,
+    oversampling: Oversampling,
+    /// One stage per doubling, the lowest rate first.
+    stages: Vec<HalfbandStage>,
+    /// The block at each stage's higher rate on the way up.
+    upsampled: Vec<Vec<f32>>,
+    inner_input: AudioBuffer,
+    inner_output: AudioBuffer,
+}
+
+impl<N: AudioNode> OversampledNode<N> {
+    /// Wraps `node` for audio at `sample_rate`. The filters are designed here, for that rate.
+    pub fn new(node: N, oversampling: Oversampling, sample_rate: f32) -> Self {
+        let stages = (0..oversampling.stages())
+            .map(|stage| HalfbandStage::new(sample_rate * (1 << stage) as f32))
+            .collect();
+        OversampledNode {
+            node,
+            oversampling,
+            stages,
+            upsampled: vec![Vec::new(); oversampling.stages()],
+            inner_input: AudioBuffer {
+                data: Vec::new(),
+                num_channels: 1,
+            },
+            inner_output: AudioBuffer {
+                data: Vec::new(),
+                num_channels: 1,
+            },
+        }
+    }
+
+    pub fn oversampling(&self) -> Oversampling {
+        self.oversampling
+    }
+
+    /// Switches to running the node at `oversampling`, with filters designed afresh for
+    /// `sample_rate`. The filters start out empty, and the latency changes with the factor.
+    pub fn set_oversampling(&mut self, oversampling: Oversampling, sample_rate: f32) {
+        if oversampling == self.oversampling {
+            return;
+        }
+        self.stages = (0..oversampling.stages())
+            .map(|stage| HalfbandStage::new(sample_rate * (1 << stage) as f32))
+            .collect();
+        self.upsampled = vec![Vec::new(); oversampling.stages()];
+        self.oversampling = oversampling;
+    }
+
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+
+    pub fn node_mut(&mut self) -> &mut N {
+        &mut self.node
+    }
+}
+
+impl<N: AudioNode> AudioNode for OversampledNode<N> {
+    fn process(&mut self, input: &AudioBuffer, output: &mut AudioBuffer) {
+        if self.stages.is_empty() {
+            self.node.process(input, output);
+            return;
+        }
+
+        let num_channels = input.num_channels();
+        assert_eq!(num_channels, output.num_channels());
+        let factor = self.oversampling.factor();
+        let frames = input.num_frames();
+        for stage in self.stages.iter_mut() {
+            stage.ensure_channels(num_channels);
+        }
+
+        self.inner_input.num_channels = num_channels;
+        self.inner_input.data.resize(input.data.len() * factor, 0.0);
+        for channel in 0..num_channels {
+            let mut block = input.channel(channel);
+            for (stage, upsampled) in self.stages.iter_mut().zip(self.upsampled.iter_mut()) {
+                stage.upsample(channel, block, upsampled);
+                block = upsampled;
+            }
+            self.inner_input.channel_mut(channel).copy_from_slice(block);
+        }
+
+        self.inner_output.num_channels = num_channels;
+        self.inner_output
+            .data
+            .resize(input.data.len() * factor, 0.0);
+        self.node.process(&self.inner_input, &mut self.inner_output);
+
+        for channel in 0..num_channels {
+            let mut block = self.inner_output.channel(channel).to_vec();
+            for stage in self.stages.iter_mut().rev() {
+                let mut decimated = vec![0.0; block.len() / 2];
+                stage.downsample(channel, &block, &mut decimated);
+                block = decimated;
+            }
+            output.channel_mut(channel)[..frames].copy_from_slice(&block);
+        }
+    }
+
+    fn reset(&mut self) {
+        for stage in self.stages.iter_mut() {
+            stage.reset();
+        }
+        self.node.reset();
+    }
+
+    /// The filters' delay plus the node's own, in samples at the outer rate.
+    fn latency_samples(&self) -> usize {
+        let filters: f32 = self
+            .stages
+            .iter()
+            .enumerate()
+            .map(|(stage, filter)| filter.latency() / (1 << stage) as f32)
+            .sum();
+        let node = self.node.latency_samples() as f32 / self.oversampling.factor() as f32;
+        (filters + node).round() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+    const BLOCK: usize = 512;
+
+    /// Passes its input straight through.
+    struct Linear;
+
+    impl AudioNode for Linear {
+        fn process(&mut self, input: &AudioBuffer, output: &mut AudioBuffer) {
+            output.data.copy_from_slice(&input.data);
+        }
+    }
+
+    /// Saturates hard, the kind of curve that aliases.
+    struct Tanh;
+
+    impl AudioNode for Tanh {
+        fn process(&mut self, input: &AudioBuffer, output: &mut AudioBuffer) {
+            for (out, sample) in output.data.iter_mut().zip(&input.data) {
+                *out = (4.0 * sample).tanh();
+            }
+        }
+    }
+
+    /// A second of a sine at `frequency` through `node`, in blocks, after a tenth of a second
+    /// to fill the filters.
+    fn render<N: AudioNode>(node: &mut N, frequency: f32, amplitude: f32) -> Vec<f32> {
+        let total = (1.1 * SAMPLE_RATE) as usize;
+        let mut rendered = Vec::with_capacity(total);
+        let mut start = 0;
+        while start < total {
+            let frames = BLOCK.min(total - start);
+            let input = AudioBuffer {
+                data: (start..start + frames)
+                    .map(|i| {
+                        amplitude
+                            * (2.0 * PI * frequency as f64 * i as f64 / SAMPLE_RATE as f64).sin()
+                                as f32
+                    })
+                    .collect(),
+                num_channels: 1,
+            };
+            let mut output = AudioBuffer {
+                data: vec![0.0; frames],
+                num_channels: 1,
+            };
+            node.process(&input, &mut output);
+            rendered.extend_from_slice(&output.data);
+            start += frames;
+        }
+        rendered.split_off(total - SAMPLE_RATE as usize)
+    }
+
+    /// Amplitude of the `frequency` component of `samples`, under a Hann window.
+    fn amplitude_at(samples: &[f32], frequency: f32) -> f64 {
+        let len = samples.len() as f64;
+        let (re, im, window_sum) = samples.iter().enumerate().fold(
+            (0.0, 0.0, 0.0),
+            |(re, im, window_sum), (i, &sample)| {
+                let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / len).cos();
+                let angle = 2.0 * PI * frequency as f64 * i as f64 / SAMPLE_RATE as f64;
+                let sample = sample as f64 * window;
+                (
+                    re + sample * angle.cos(),
+                    im - sample * angle.sin(),
+                    window_sum + window,
+                )
+            },
+        );
+        2.0 * (re * re + im * im).sqrt() / window_sum
+    }
+
+    fn db(ratio: f64) -> f64 {
+        20.0 * ratio.log10()
+    }
+
+    #[test]
+    fn four_times_oversampling_pushes_down_the_aliases_of_a_saturated_sine() {
+        // The 5th, 7th and 9th harmonics of 5 kHz fold back to 19.1, 9.1 and 0.9 kHz.
+        let aliases = [19_100.0, 9_100.0, 900.0];
+        let plain = render(
+            &mut OversampledNode::new(Tanh, Oversampling::X1, SAMPLE_RATE),
+            5_000.0,
+            0.9,
+        );
+        let oversampled = render(
+            &mut OversampledNode::new(Tanh, Oversampling::X4, SAMPLE_RATE),
+            5_000.0,
+            0.9,
+        );
+        for alias in aliases {
+            let before = amplitude_at(&plain, alias);
+            let after = amplitude_at(&oversampled, alias);
+            assert!(
+                db(before / after) > 40.0,
+                "{} Hz: {:.1} dB before, {:.1} dB after",
+                alias,
+                db(before),
+                db(after)
+            );
+        }
+        // The wanted tone and its third harmonic come through either way.
+        for harmonic in [5_000.0, 15_000.0] {
+            let before = amplitude_at(&plain, harmonic);
+            let after = amplitude_at(&oversampled, harmonic);
+            assert!(db(after / before).abs() < 1.0, "{} Hz", harmonic);
+        }
+    }
+
+    #[test]
+    fn a_linear_node_comes_out_flat_to_18_khz() {
+        for oversampling in [Oversampling::X2, Oversampling::X4] {
+            let mut node = OversampledNode::new(Linear, oversampling, SAMPLE_RATE);
+            for frequency in [50.0, 1_000.0, 5_000.0, 10_000.0, 15_000.0, 18_000.0] {
+                let gain = db(amplitude_at(&render(&mut node, frequency, 0.5), frequency) / 0.5);
+                assert!(
+                    gain.abs() < 0.1,
+                    "{:?} at {} Hz: {:.3} dB",
+                    oversampling,
+                    frequency,
+                    gain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn an_impulse_comes_out_at_the_reported_latency() {
+        for oversampling in [Oversampling::X1, Oversampling::X2, Oversampling::X4] {
+            let mut node = OversampledNode::new(Linear, oversampling, SAMPLE_RATE);
+            let mut impulse = AudioBuffer {
+                data: vec![0.0; BLOCK],
+                num_channels: 1,
+            };
+            impulse.data[0] = 1.0;
+            let mut output = AudioBuffer {
+                data: vec![0.0; BLOCK],
+                num_channels: 1,
+            };
+            node.process(&impulse, &mut output);
+            let peak = output
+                .data
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+                .map(|(index, _)| index);
+            assert_eq!(peak, Some(node.latency_samples()), "{:?}", oversampling);
+        }
+    }
+
+    #[test]
+    fn halfband_filters_have_zeros_at_every_other_tap() {
+        let taps = design_halfband(SAMPLE_RATE);
+        assert_eq!(taps.len() % 4, 3);
+        let middle = taps.len() / 2;
+        assert_eq!(taps[middle], 0.5);
+        for (index, tap) in taps.iter().enumerate() {
+            if index != middle && (index as isize - middle as isize) % 2 == 0 {
+                assert_eq!(*tap, 0.0, "tap {}", index);
+            }
+        }
+        // Symmetric, so the delay is the same at every frequency.
+        assert!(taps.iter().eq(taps.iter().rev()));
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::synth::ParamId;
+
+/// Settings for undoing parameter changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UndoConfig {
+    /// Most undo steps kept; the oldest are forgotten past this. 0 turns undo off.
+    pub depth: usize,
+    /// Seconds between input gestures within which another one on the same control joins the
+    /// last undo step, so a held key or a run of presses undoes in one go.
+    pub coalesce_window: f32,
+}
+
+impl Default for UndoConfig {
+    fn default() -> Self {
+        UndoConfig {
+            depth: 100,
+            coalesce_window: 0.5,
+        }
+    }
+}
+
+/// One parameter moved from one value to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParamChange {
+    pub param: ParamId,
+    pub before: f32,
+    pub after: f32,
+}
+
+/// Changes undone and redone together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndoStep {
+    /// What made the changes: the control moved, or what changed several at once.
+    pub label: String,
+    /// The changes, each parameter once, in the order they were first made.
+    pub changes: Vec<ParamChange>,
+}
+
+impl UndoStep {
+    /// The values that undo the step, the last change first.
+    pub fn before_values(&self) -> Vec<(ParamId, f32)> {
+        self.changes
+            .iter()
+            .rev()
+            .map(|change| (change.param, change.before))
+            .collect()
+    }
+
+    /// The values that redo the step, in the order they were made.
+    pub fn after_values(&self) -> Vec<(ParamId, f32)> {
+        self.changes
+            .iter()
+            .map(|change| (change.param, change.after))
+            .collect()
+    }
+}
+
+impl fmt::Display for UndoStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.label)?;
+        for (index, change) in self.changes.iter().enumerate() {
+            let separator = if index == 0 { " " } else { ", " };
+            write!(
+                f,
+                "{}{} {} -> {}",
+                separator, change.param, change.before, change.after
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Undo and redo for parameter changes, kept by the window beside the input that made them.
+///
+/// Each input gesture is a step of its own, except that one with the same label as the last,
+/// coming within the coalescing window of it, joins it: each parameter keeps the value from
+/// before the first gesture and takes the one after the latest. A random patch has a label of
+/// its own, so it is always one step. Making a change drops anything there was to redo. Undo
+/// and redo hand back the values to set; the window sends them to the engine as `SetParam`
+/// events.
+#[derive(Debug)]
+pub struct ParamHistory {
+    config: UndoConfig,
+    /// Steps that can be undone, the oldest first.
+    undo: VecDeque<UndoStep>,
+    /// Steps that can be redone, the most recently undone last.
+    redo: Vec<UndoStep>,
+    /// When the input behind the last undo step came, while further input may still join it.
+    open_since: Option<Instant>,
+}
+
+impl ParamHistory {
+    pub fn new(config: UndoConfig) -> Self {
+        ParamHistory {
+            config,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            open_since: None,
+        }
+    }
+
+    /// Records `changes`, made by the input gesture `label` that came at `at`.
+    pub fn record(&mut self, label: impl Into<String>, changes: Vec<ParamChange>, at: Instant) {
+        if self.config.depth == 0 || changes.is_empty() {
+            return;
+        }
+        let label = label.into();
+        let window = Duration::from_secs_f32(self.config.coalesce_window.max(0.0));
+        let joins = self.open_since.is_some_and(|last| {
+            at.saturating_duration_since(last) <= window
+                && self.undo.back().is_some_and(|step| step.label == label)
+        });
+        if joins {
+            self.redo.clear();
+            self.open_since = Some(at);
+            let step = self
+                .undo
+                .back_mut()
+                .expect("an open step is on the undo list");
+            for change in changes {
+                match step
+                    .changes
+                    .iter_mut()
+                    .find(|made| made.param == change.param)
+                {
+                    Some(made) => made.after = change.after,
+                    None => step.changes.push(change),
+                }
+            }
+            // A gesture that ends where it started leaves nothing to undo.
+            step.changes.retain(|change| change.before != change.after);
+            if step.changes.is_empty() {
+                self.undo.pop_back();
+                self.open_since = None;
+            }
+            return;
+        }
+        let changes: Vec<ParamChange> = changes
+            .into_iter()
+            .filter(|change| change.before != change.after)
+            .collect();
+        if changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        if self.undo.len() == self.config.depth {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(UndoStep { label, changes });
+        self.open_since = Some(at);
+    }
+
+    /// Takes the last step off the undo list and puts it on the redo list. Returns it, for the
+    /// caller to set its `before_values`.
+    pub fn undo(&mut self) -> Option<UndoStep> {
+        let step = self.undo.pop_back()?;
+        self.redo.push(step.clone());
+        self.open_since = None;
+        Some(step)
+    }
+
+    /// Takes the last undone step off the redo list and puts it back on the undo list. Returns
+    /// it, for the caller to set its `after_values`.
+    pub fn redo(&mut self) -> Option<UndoStep> {
+        let step = self.redo.pop()?;
+        self.undo.push_back(step.clone());
+        self.open_since = None;
+        Some(step)
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.open_since = None;
+    }
+
+    /// Clears the history when the config is reloaded, which may change parameters out from
+    /// under it, with a warning when there was anything to lose.
+    pub fn reload(&mut self, config: UndoConfig) {
+        if !self.undo.is_empty() || !self.redo.is_empty() {
+            warn!(
+                "Config reloaded; the undo history ({} to undo, {} to redo) is cleared",
+                self.undo.len(),
+                self.redo.len()
+            );
+        }
+        self.config = config;
+        self.clear();
+    }
+
+    /// The steps that can be undone, the oldest first.
+    pub fn undo_steps(&self) -> impl Iterator<Item = &UndoStep> {
+        self.undo.iter()
+    }
+
+    /// The steps that can be redone, the next to redo first.
+    pub fn redo_steps(&self) -> impl Iterator<Item = &UndoStep> {
+        self.redo.iter().rev()
+    }
+
+    /// Logs every step there is to undo and redo.
+    pub fn log(&self) {
+        info!("{} step(s) to undo, newest last", self.undo.len());
+        for step in self.undo_steps() {
+            info!("  {}", step);
+        }
+        info!("{} step(s) to redo, next first", self.redo.len());
+        for step in self.redo_steps() {
+            info!("  {}", step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Parameter values as the engine would hold them, changed by scripted gestures.
+    struct Params {
+        values: HashMap<ParamId, f32>,
+        start: Instant,
+    }
+
+    impl Params {
+        fn new() -> Self {
+            Params {
+                values: [
+                    (ParamId::Drive, 1.0),
+                    (ParamId::TremoloRate, 5.0),
+                    (ParamId::Attack, 0.5),
+                    (ParamId::Sustain, 0.7),
+                ]
+                .into_iter()
+                .collect(),
+                start: Instant::now(),
+            }
+        }
+
+        /// Sets `values` by the gesture `label` at `seconds` into the script, recording it.
+        fn gesture(
+            &mut self,
+            history: &mut ParamHistory,
+            label: &str,
+            values: &[(ParamId, f32)],
+            seconds: f32,
+        ) {
+            let changes = values
+                .iter()
+                .map(|&(param, after)| ParamChange {
+                    param,
+                    before: self.values.insert(param, after).unwrap(),
+                    after,
+                })
+                .collect();
+            let at = self.start + Duration::from_secs_f32(seconds);
+            history.record(label, changes, at);
+        }
+
+        /// Sets `values`, as the engine does the `SetParam` events an undo or redo sends.
+        fn set(&mut self, values: Vec<(ParamId, f32)>) {
+            self.values.extend(values);
+        }
+    }
+
+    fn history(depth: usize) -> ParamHistory {
+        ParamHistory::new(UndoConfig {
+            depth,
+            coalesce_window: 0.5,
+        })
+    }
+
+    #[test]
+    fn a_burst_of_presses_on_one_control_undoes_as_one_step() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        for (press, seconds) in [0.0, 0.2, 0.4, 0.6, 0.8].into_iter().enumerate() {
+            let drive = 1.0 + 0.5 * (press + 1) as f32;
+            params.gesture(&mut history, "drive", &[(ParamId::Drive, drive)], seconds);
+        }
+
+        let steps: Vec<&UndoStep> = history.undo_steps().collect();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(
+            steps[0].changes,
+            [ParamChange {
+                param: ParamId::Drive,
+                before: 1.0,
+                after: 3.5,
+            }]
+        );
+        params.set(history.undo().unwrap().before_values());
+        assert_eq!(params.values[&ParamId::Drive], 1.0);
+    }
+
+    #[test]
+    fn a_pause_longer_than_the_window_starts_a_new_step() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 2.0)], 0.0);
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 3.0)], 0.4);
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 4.0)], 1.0);
+
+        let afters: Vec<f32> = history
+            .undo_steps()
+            .map(|step| step.changes[0].after)
+            .collect();
+        assert_eq!(afters, [3.0, 4.0]);
+    }
+
+    #[test]
+    fn gestures_on_different_controls_never_join() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 2.0)], 0.0);
+        params.gesture(
+            &mut history,
+            "tremolo_rate",
+            &[(ParamId::TremoloRate, 7.0)],
+            0.1,
+        );
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 3.0)], 0.2);
+
+        let labels: Vec<&str> = history
+            .undo_steps()
+            .map(|step| step.label.as_str())
+            .collect();
+        assert_eq!(labels, ["drive", "tremolo_rate", "drive"]);
+    }
+
+    #[test]
+    fn a_drag_across_several_parameters_keeps_each_ones_first_value() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        params.gesture(&mut history, "envelope", &[(ParamId::Attack, 0.3)], 0.0);
+        params.gesture(
+            &mut history,
+            "envelope",
+            &[(ParamId::Attack, 0.2), (ParamId::Sustain, 0.4)],
+            0.1,
+        );
+        params.gesture(&mut history, "envelope", &[(ParamId::Sustain, 0.5)], 0.2);
+
+        let step = history.undo().unwrap();
+        assert_eq!(
+            step.changes,
+            [
+                ParamChange {
+                    param: ParamId::Attack,
+                    before: 0.5,
+                    after: 0.2,
+                },
+                ParamChange {
+                    param: ParamId::Sustain,
+                    before: 0.7,
+                    after: 0.5,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn undoing_everything_restores_the_exact_starting_values() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        let start = params.values.clone();
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 2.37)], 0.0);
+        params.gesture(
+            &mut history,
+            "tremolo_rate",
+            &[(ParamId::TremoloRate, 0.1)],
+            2.0,
+        );
+        params.gesture(&mut history, "envelope", &[(ParamId::Attack, 0.013)], 4.0);
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 0.25)], 6.0);
+
+        while let Some(step) = history.undo() {
+            params.set(step.before_values());
+        }
+        assert_eq!(params.values, start);
+    }
+
+    #[test]
+    fn redo_after_undo_puts_the_values_back() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 2.0)], 0.0);
+        params.gesture(
+            &mut history,
+            "tremolo_rate",
+            &[(ParamId::TremoloRate, 7.0)],
+            1.0,
+        );
+        let changed = params.values.clone();
+
+        params.set(history.undo().unwrap().before_values());
+        params.set(history.undo().unwrap().before_values());
+        assert!(history.undo().is_none());
+        params.set(history.redo().unwrap().after_values());
+        assert_eq!(params.values[&ParamId::Drive], 2.0);
+        assert_eq!(params.values[&ParamId::TremoloRate], 5.0);
+        params.set(history.redo().unwrap().after_values());
+        assert!(history.redo().is_none());
+        assert_eq!(params.values, changed);
+    }
+
+    #[test]
+    fn a_new_change_drops_what_there_was_to_redo() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 2.0)], 0.0);
+        params.set(history.undo().unwrap().before_values());
+        params.gesture(
+            &mut history,
+            "tremolo_rate",
+            &[(ParamId::TremoloRate, 7.0)],
+            1.0,
+        );
+
+        assert_eq!(history.redo_steps().count(), 0);
+        assert!(history.redo().is_none());
+    }
+
+    #[test]
+    fn a_change_right_after_an_undo_is_a_step_of_its_own() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 2.0)], 0.0);
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 3.0)], 1.0);
+        params.set(history.undo().unwrap().before_values());
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 4.0)], 1.1);
+
+        let changes: Vec<(f32, f32)> = history
+            .undo_steps()
+            .map(|step| (step.changes[0].before, step.changes[0].after))
+            .collect();
+        assert_eq!(changes, [(1.0, 2.0), (2.0, 4.0)]);
+    }
+
+    #[test]
+    fn past_the_depth_the_oldest_steps_are_forgotten() {
+        let mut history = history(3);
+        let mut params = Params::new();
+        for step in 0..5 {
+            let drive = 2.0 + step as f32;
+            params.gesture(
+                &mut history,
+                "drive",
+                &[(ParamId::Drive, drive)],
+                step as f32,
+            );
+        }
+
+        let afters: Vec<f32> = history
+            .undo_steps()
+            .map(|step| step.changes[0].after)
+            .collect();
+        assert_eq!(afters, [4.0, 5.0, 6.0]);
+        while let Some(step) = history.undo() {
+            params.set(step.before_values());
+        }
+        // The first two changes went with their steps, so undo stops short of the start.
+        assert_eq!(params.values[&ParamId::Drive], 3.0);
+    }
+
+    #[test]
+    fn a_random_patch_undoes_as_one_step_restoring_every_parameter() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        let start = params.values.clone();
+        params.gesture(
+            &mut history,
+            "random patch 1",
+            &[
+                (ParamId::Drive, 4.0),
+                (ParamId::TremoloRate, 9.0),
+                (ParamId::Attack, 0.01),
+                (ParamId::Sustain, 0.2),
+            ],
+            0.0,
+        );
+        let first = params.values.clone();
+        // The next patch right after it is its own step, since its label differs.
+        params.gesture(
+            &mut history,
+            "random patch 2",
+            &[(ParamId::Drive, 2.0), (ParamId::Sustain, 0.9)],
+            0.1,
+        );
+
+        assert_eq!(history.undo_steps().count(), 2);
+        params.set(history.undo().unwrap().before_values());
+        assert_eq!(params.values[&ParamId::Drive], 4.0);
+        params.set(history.undo().unwrap().before_values());
+        assert_eq!(params.values, start);
+        params.set(history.redo().unwrap().after_values());
+        assert_eq!(params.values, first);
+    }
+
+    #[test]
+    fn a_gesture_that_ends_where_it_started_leaves_nothing_to_undo() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 2.0)], 0.0);
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 1.0)], 0.2);
+        params.gesture(&mut history, "sustain", &[(ParamId::Sustain, 0.7)], 0.4);
+
+        assert_eq!(history.undo_steps().count(), 0);
+    }
+
+    #[test]
+    fn a_depth_of_zero_records_nothing() {
+        let mut history = history(0);
+        let mut params = Params::new();
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 2.0)], 0.0);
+
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn a_reload_clears_both_lists_and_takes_the_new_depth() {
+        let mut history = history(100);
+        let mut params = Params::new();
+        for step in 0..3 {
+            let drive = 2.0 + step as f32;
+            params.gesture(
+                &mut history,
+                "drive",
+                &[(ParamId::Drive, drive)],
+                step as f32,
+            );
+        }
+        history.undo();
+        history.reload(UndoConfig {
+            depth: 1,
+            coalesce_window: 0.5,
+        });
+        assert_eq!(history.undo_steps().count(), 0);
+        assert_eq!(history.redo_steps().count(), 0);
+
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 8.0)], 10.0);
+        params.gesture(&mut history, "drive", &[(ParamId::Drive, 9.0)], 20.0);
+        assert_eq!(history.undo_steps().count(), 1);
+    }
+}
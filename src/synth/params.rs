@@ -0,0 +1,203 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+use crate::synth::{OscillatorWaveform, Smoothing, StealPolicy, MAX_QUALITY_LEVEL};
+
+/// Waveforms in the order the `Waveform` parameter numbers them.
+const WAVEFORMS: [OscillatorWaveform; 5] = [
+    OscillatorWaveform::Silence,
+    OscillatorWaveform::Sine,
+    OscillatorWaveform::Square,
+    OscillatorWaveform::Sawtooth,
+    OscillatorWaveform::Triangle,
+];
+
+/// Voice stealing policies in the order the `StealPolicy` parameter numbers them.
+const STEAL_POLICIES: [StealPolicy; 6] = [
+    StealPolicy::Off,
+    StealPolicy::Oldest,
+    StealPolicy::Quietest,
+    StealPolicy::ReleasedFirst,
+    StealPolicy::Lowest,
+    StealPolicy::Highest,
+];
+
+/// An engine setting that can be changed by number or name, for hosts that drive the engine
+/// without the keyboard.
+///
+/// The numbers are part of the C interface, so new parameters go on the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ParamId {
+    /// 0 silence, 1 sine, 2 square, 3 sawtooth, 4 triangle.
+    Waveform = 0,
+    /// Octaves every new note is shifted by.
+    OctaveShift = 1,
+    /// Tremolo on when above 0.5.
+    TremoloEnabled = 2,
+    /// Tremolo rate in Hz.
+    TremoloRate = 3,
+    /// Tremolo depth, from 0.0 to 1.0.
+    TremoloDepth = 4,
+    /// Wave shaper pre-gain.
+    Drive = 5,
+    /// Voice given up past the voice limit: 0 none, 1 oldest, 2 quietest, 3 released first,
+    /// 4 lowest, 5 highest.
+    StealPolicy = 6,
+    /// Reference tone pitch in Hz.
+    ReferencePitch = 7,
+    /// Envelope attack time of new lead notes, in seconds.
+    Attack = 8,
+    /// Envelope decay time of new lead notes, in seconds.
+    Decay = 9,
+    /// Envelope sustain level of new lead notes, from 0.0 to 1.0.
+    Sustain = 10,
+    /// Envelope release time of new lead notes, in seconds.
+    Release = 11,
+    /// Steps below full quality, from 0 (full) to 4; see `QualityStep`.
+    Quality = 12,
+}
+
+impl ParamId {
+    /// Every parameter, in number order.
+    pub const ALL: [ParamId; 13] = [
+        ParamId::Waveform,
+        ParamId::OctaveShift,
+        ParamId::TremoloEnabled,
+        ParamId::TremoloRate,
+        ParamId::TremoloDepth,
+        ParamId::Drive,
+        ParamId::StealPolicy,
+        ParamId::ReferencePitch,
+        ParamId::Attack,
+        ParamId::Decay,
+        ParamId::Sustain,
+        ParamId::Release,
+        ParamId::Quality,
+    ];
+
+    pub fn from_index(index: u32) -> Option<ParamId> {
+        ParamId::ALL.get(index as usize).copied()
+    }
+
+    pub fn index(&self) -> u32 {
+        *self as u32
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParamId::Waveform => "waveform",
+            ParamId::OctaveShift => "octave_shift",
+            ParamId::TremoloEnabled => "tremolo_enabled",
+            ParamId::TremoloRate => "tremolo_rate",
+            ParamId::TremoloDepth => "tremolo_depth",
+            ParamId::Drive => "drive",
+            ParamId::StealPolicy => "steal_policy",
+            ParamId::ReferencePitch => "reference_pitch",
+            ParamId::Attack => "attack",
+            ParamId::Decay => "decay",
+            ParamId::Sustain => "sustain",
+            ParamId::Release => "release",
+            ParamId::Quality => "quality",
+        }
+    }
+
+    /// The lowest and highest value the parameter takes; values outside are clamped.
+    pub fn range(&self) -> (f32, f32) {
+        match self {
+            ParamId::Waveform => (0.0, (WAVEFORMS.len() - 1) as f32),
+            ParamId::OctaveShift => (-4.0, 4.0),
+            ParamId::TremoloEnabled => (0.0, 1.0),
+            ParamId::TremoloRate => (0.0, 20.0),
+            ParamId::TremoloDepth => (0.0, 1.0),
+            ParamId::Drive => (0.1, 10.0),
+            ParamId::StealPolicy => (0.0, (STEAL_POLICIES.len() - 1) as f32),
+            ParamId::ReferencePitch => (20.0, 2000.0),
+            ParamId::Attack | ParamId::Decay | ParamId::Release => (0.001, 10.0),
+            ParamId::Sustain => (0.0, 1.0),
+            ParamId::Quality => (0.0, MAX_QUALITY_LEVEL as f32),
+        }
+    }
+
+    /// How the engine moves the parameter to a new value. Settings that only take whole steps
+    /// jump, as do the envelope's, which only shape notes as they start; the rest glide, so
+    /// turning them while sound plays doesn't click.
+    pub fn smoothing(&self) -> Smoothing {
+        match self {
+            ParamId::Waveform
+            | ParamId::OctaveShift
+            | ParamId::TremoloEnabled
+            | ParamId::StealPolicy
+            | ParamId::Attack
+            | ParamId::Decay
+            | ParamId::Sustain
+            | ParamId::Release
+            | ParamId::Quality => Smoothing::None,
+            ParamId::TremoloRate => Smoothing::Exponential {
+                time_constant: 0.05,
+            },
+            ParamId::TremoloDepth | ParamId::Drive => Smoothing::Linear { time: 0.02 },
+            ParamId::ReferencePitch => Smoothing::Exponential {
+                time_constant: 0.02,
+            },
+        }
+    }
+
+    /// Clamps `value` into the parameter's range, refusing NaN and infinities.
+    pub fn clamp(&self, value: f32) -> Result<f32> {
+        if !value.is_finite() {
+            bail!("{} can't be set to {}", self.name(), value);
+        }
+        let (min, max) = self.range();
+        Ok(value.clamp(min, max))
+    }
+}
+
+impl fmt::Display for ParamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ParamId {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match ParamId::ALL.iter().find(|param| param.name() == name) {
+            Some(param) => Ok(*param),
+            None => bail!("Unknown parameter '{}'", name),
+        }
+    }
+}
+
+/// The waveform a `Waveform` parameter value selects, rounding to the nearest number.
+pub fn waveform_from_param(value: f32) -> OscillatorWaveform {
+    let (min, max) = ParamId::Waveform.range();
+    WAVEFORMS[value.round().clamp(min, max) as usize]
+}
+
+/// The `Waveform` parameter value that selects `waveform`, or `None` for a custom waveform,
+/// which has no number.
+pub fn waveform_to_param(waveform: OscillatorWaveform) -> Option<f32> {
+    WAVEFORMS
+        .iter()
+        .position(|other| *other == waveform)
+        .map(|index| index as f32)
+}
+
+/// The voice stealing policy a `StealPolicy` parameter value selects, rounding to the nearest
+/// number.
+pub fn steal_policy_from_param(value: f32) -> StealPolicy {
+    let (min, max) = ParamId::StealPolicy.range();
+    STEAL_POLICIES[value.round().clamp(min, max) as usize]
+}
+
+/// The `StealPolicy` parameter value that selects `steal_policy`.
+pub fn steal_policy_to_param(steal_policy: StealPolicy) -> f32 {
+    STEAL_POLICIES
+        .iter()
+        .position(|other| *other == steal_policy)
+        .unwrap_or_default() as f32
+}
@@ -0,0 +1,402 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::synth::log_limit::warn_once;
+use crate::synth::NoteId;
+
+/// Velocity recorded for every note, since the computer keyboard doesn't sense how hard a key
+/// was struck.
+pub const DEFAULT_VELOCITY: u8 = 100;
+
+/// Settings for recording what was played and exporting it as a MIDI file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MidiExportConfig {
+    /// Ticks per quarter note in the exported file.
+    pub ppq: u16,
+    /// Tempo written to the file, in beats per minute.
+    pub tempo: f32,
+    /// Most note events kept; the oldest are dropped beyond this.
+    pub max_events: usize,
+    /// Where the export key writes the file.
+    pub path: String,
+    /// Export to this file when the window is closed. `--export-midi <path>` sets it.
+    pub export_on_exit: Option<String>,
+}
+
+impl Default for MidiExportConfig {
+    fn default() -> Self {
+        MidiExportConfig {
+            ppq: 480,
+            tempo: 120.0,
+            max_events: 100_000,
+            path: "performance.mid".to_string(),
+            export_on_exit: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerformanceEventKind {
+    NoteOn { velocity: u8 },
+    NoteOff,
+}
+
+/// A note starting or stopping at an engine sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerformanceEvent {
+    pub sample: u64,
+    pub key: u8,
+    pub kind: PerformanceEventKind,
+}
+
+/// A bounded log of the notes the engine played, timestamped in engine samples.
+#[derive(Debug)]
+pub struct PerformanceLog {
+    events: VecDeque<PerformanceEvent>,
+    capacity: usize,
+    dropped: u64,
+    /// MIDI key sounding for each held note, so its note-off matches its note-on even when
+    /// two keys hold the same note name.
+    held: HashMap<NoteId, u8>,
+    end_sample: u64,
+    sample_rate: f32,
+}
+
+impl Default for PerformanceLog {
+    fn default() -> Self {
+        PerformanceLog::new(MidiExportConfig::default().max_events)
+    }
+}
+
+impl PerformanceLog {
+    pub fn new(capacity: usize) -> Self {
+        PerformanceLog {
+            events: VecDeque::new(),
+            capacity: capacity.max(1),
+            dropped: 0,
+            held: HashMap::new(),
+            end_sample: 0,
+            sample_rate: 44100.0,
+        }
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &PerformanceEvent> {
+        self.events.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Records the note `id` starting at `sample`, sounding as MIDI `key`.
+    pub fn note_on(&mut self, sample: u64, id: &NoteId, key: u8, velocity: u8) {
+        self.held.insert(id.clone(), key);
+        self.push(PerformanceEvent {
+            sample,
+            key,
+            kind: PerformanceEventKind::NoteOn { velocity },
+        });
+    }
+
+    /// Records the note `id` stopping at `sample`. Notes that were never started are ignored.
+    pub fn note_off(&mut self, sample: u64, id: &NoteId) {
+        if let Some(key) = self.held.remove(id) {
+            self.push(PerformanceEvent {
+                sample,
+                key,
+                kind: PerformanceEventKind::NoteOff,
+            });
+        }
+    }
+
+    /// Moves the end of the recording to `end_sample`, the engine sample reached so far.
+    pub fn advance(&mut self, end_sample: u64, sample_rate: f32) {
+        self.end_sample = self.end_sample.max(end_sample);
+        self.sample_rate = sample_rate;
+    }
+
+    fn push(&mut self, event: PerformanceEvent) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+            self.dropped += 1;
+            warn_once!(
+                "The performance log is full at {} note events; the oldest are being dropped",
+                self.capacity
+            );
+        }
+        self.events.push_back(event);
+    }
+
+    /// Converts the log into a single-track (type 0) standard MIDI file.
+    ///
+    /// Time starts at the first recorded event. Notes still held are stopped at the end of the
+    /// recording, and note-offs whose note-on was dropped from the log are left out.
+    pub fn to_smf(&self, config: &MidiExportConfig) -> Smf<'static> {
+        let ppq = config.ppq.clamp(1, u15::max_value().as_int());
+        let tempo = if config.tempo.is_finite() && config.tempo > 0.0 {
+            config.tempo
+        } else {
+            MidiExportConfig::default().tempo
+        };
+        let origin = self.events.front().map_or(0, |event| event.sample);
+        let ticks_per_sample = tempo as f64 / 60.0 * ppq as f64 / self.sample_rate as f64;
+        let tick_at =
+            |sample: u64| (sample.saturating_sub(origin) as f64 * ticks_per_sample).round() as u64;
+
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(
+                (60_000_000.0 / tempo as f64).round() as u32,
+            ))),
+        }];
+        let mut last_tick = 0;
+        let mut push = |tick: u64, kind: TrackEventKind<'static>| {
+            let delta = tick
+                .saturating_sub(last_tick)
+                .min(u28::max_value().as_int() as u64);
+            track.push(TrackEvent {
+                delta: u28::new(delta as u32),
+                kind,
+            });
+            last_tick = tick.max(last_tick);
+        };
+
+        let mut sounding: HashMap<u8, usize> = HashMap::new();
+        for event in self.events.iter() {
+            let tick = tick_at(event.sample);
+            match event.kind {
+                PerformanceEventKind::NoteOn { velocity } => {
+                    *sounding.entry(event.key).or_default() += 1;
+                    push(
+                        tick,
+                        midi(MidiMessage::NoteOn {
+                            key: u7::from_int_lossy(event.key),
+                            vel: u7::from_int_lossy(velocity.max(1)),
+                        }),
+                    );
+                }
+                PerformanceEventKind::NoteOff => match sounding.get_mut(&event.key) {
+                    Some(count) if *count > 0 => {
+                        *count -= 1;
+                        push(tick, note_off(event.key));
+                    }
+                    _ => (),
+                },
+            }
+        }
+
+        let end_tick = tick_at(self.end_sample);
+        let mut held: Vec<(u8, usize)> = sounding.into_iter().filter(|(_, n)| *n > 0).collect();
+        held.sort_unstable();
+        for (key, count) in held {
+            for _ in 0..count {
+                push(end_tick, note_off(key));
+            }
+        }
+        push(end_tick, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(ppq)),
+        ));
+        smf.tracks.push(track);
+        smf
+    }
+
+    /// Writes the log to `path` as a standard MIDI file.
+    pub fn export(&self, path: &Path, config: &MidiExportConfig) -> Result<()> {
+        if self.dropped > 0 {
+            warn!(
+                "The performance log overflowed; the oldest {} note events are missing from the export",
+                self.dropped
+            );
+        }
+        self.to_smf(config)
+            .save(path)
+            .with_context(|| format!("Failed to write MIDI file '{}'", path.display()))?;
+        info!(
+            "Exported {} note events to '{}'",
+            self.events.len(),
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+fn midi(message: MidiMessage) -> TrackEventKind<'static> {
+    TrackEventKind::Midi {
+        channel: u4::new(0),
+        message,
+    }
+}
+
+fn note_off(key: u8) -> TrackEventKind<'static> {
+    midi(MidiMessage::NoteOff {
+        key: u7::from_int_lossy(key),
+        vel: u7::new(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    fn id(note: &str) -> NoteId {
+        NoteId::shared(note.to_string())
+    }
+
+    /// The note events of `smf`'s only track as (tick, key, velocity), velocity 0 for note-off.
+    fn parsed_notes(smf: &Smf) -> Vec<(u64, u8, u8)> {
+        assert_eq!(smf.tracks.len(), 1);
+        let mut tick = 0;
+        let mut notes = Vec::new();
+        for event in &smf.tracks[0] {
+            tick += event.delta.as_int() as u64;
+            if let TrackEventKind::Midi { message, .. } = event.kind {
+                match message {
+                    MidiMessage::NoteOn { key, vel } => {
+                        notes.push((tick, key.as_int(), vel.as_int()))
+                    }
+                    MidiMessage::NoteOff { key, .. } => notes.push((tick, key.as_int(), 0)),
+                    _ => (),
+                }
+            }
+        }
+        notes
+    }
+
+    #[test]
+    fn an_exported_performance_parses_back_with_its_notes_velocities_and_timing() {
+        let mut log = PerformanceLog::new(100);
+        // Starts a second in, to check the export is timed from the first note.
+        let origin = 48_000;
+        let script: [(u64, &str, u8, u8); 3] = [
+            (0, "C4", 60, 100),
+            (12_025, "E4", 64, 64),
+            (24_000, "G4", 67, 127),
+        ];
+        for (sample, note, key, velocity) in script {
+            log.note_on(origin + sample, &id(note), key, velocity);
+        }
+        log.note_off(origin + 36_010, &id("C4"));
+        log.note_off(origin + 47_990, &id("E4"));
+        // G4 is still held when the recording ends.
+        log.advance(origin + 96_000, SAMPLE_RATE);
+
+        let config = MidiExportConfig::default();
+        let mut bytes = Vec::new();
+        log.to_smf(&config).write_std(&mut bytes).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.header.format, Format::SingleTrack);
+        assert_eq!(smf.header.timing, Timing::Metrical(u15::new(480)));
+        assert!(
+            smf.tracks[0]
+                .iter()
+                .any(|event| event.kind
+                    == TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000))))
+        );
+
+        // 120 BPM at 480 PPQ is 960 ticks a second, 50 samples a tick at 48 kHz.
+        let tick_of = |sample: u64| sample as f64 / 50.0;
+        let expected = [
+            (tick_of(0), 60, 100),
+            (tick_of(12_025), 64, 64),
+            (tick_of(24_000), 67, 127),
+            (tick_of(36_010), 60, 0),
+            (tick_of(47_990), 64, 0),
+            (tick_of(96_000), 67, 0),
+        ];
+        let notes = parsed_notes(&smf);
+        assert_eq!(notes.len(), expected.len());
+        for ((tick, key, velocity), (expected_tick, expected_key, expected_velocity)) in
+            notes.into_iter().zip(expected)
+        {
+            assert_eq!(key, expected_key);
+            assert_eq!(velocity, expected_velocity, "key {}", key);
+            assert!(
+                (tick as f64 - expected_tick).abs() <= 1.0,
+                "key {} at tick {}, expected {}",
+                key,
+                tick,
+                expected_tick
+            );
+        }
+    }
+
+    #[test]
+    fn note_offs_without_a_note_on_are_left_out() {
+        let mut log = PerformanceLog::new(100);
+        log.note_off(10, &id("C4"));
+        log.note_on(100, &id("D4"), 62, 90);
+        log.note_off(200, &id("D4"));
+        log.note_off(300, &id("D4"));
+        log.advance(400, SAMPLE_RATE);
+        let notes = parsed_notes(&log.to_smf(&MidiExportConfig::default()));
+        assert_eq!(
+            notes
+                .iter()
+                .map(|(_, key, velocity)| (*key, *velocity))
+                .collect::<Vec<_>>(),
+            [(62, 90), (62, 0)]
+        );
+    }
+
+    #[test]
+    fn a_full_log_drops_its_oldest_events() {
+        let mut log = PerformanceLog::new(2);
+        log.note_on(0, &id("C4"), 60, 100);
+        log.note_on(1, &id("D4"), 62, 100);
+        log.note_on(2, &id("E4"), 64, 100);
+        let keys: Vec<u8> = log.events().map(|event| event.key).collect();
+        assert_eq!(keys, [62, 64]);
+    }
+
+    #[cfg(feature = "visualization")]
+    #[test]
+    fn two_keys_on_the_same_note_are_logged_separately() {
+        use crate::synth::NoteSource;
+        use winit::keyboard::{KeyCode, PhysicalKey};
+
+        let bass = NoteId::new(
+            "C".to_string(),
+            NoteSource::Key(PhysicalKey::Code(KeyCode::KeyZ)),
+        );
+        let treble = NoteId::new(
+            "C".to_string(),
+            NoteSource::Key(PhysicalKey::Code(KeyCode::KeyA)),
+        );
+        let mut log = PerformanceLog::new(100);
+        log.note_on(0, &bass, 48, 100);
+        log.note_on(10, &treble, 60, 100);
+        log.note_off(20, &bass);
+        log.note_off(30, &treble);
+        let events: Vec<(u64, u8, bool)> = log
+            .events()
+            .map(|event| {
+                (
+                    event.sample,
+                    event.key,
+                    event.kind == PerformanceEventKind::NoteOff,
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (0, 48, false),
+                (10, 60, false),
+                (20, 48, true),
+                (30, 60, true)
+            ]
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::synth::{params::waveform_from_param, ParamId, SplitMix64};
+
+/// Candidates drawn for one patch at most; past this the one farthest from the recent patches
+/// is taken, near or not.
+const MAX_ATTEMPTS: usize = 32;
+
+/// The parameters a random patch sets, each with the range it is drawn from unless the config
+/// gives another. The ranges keep clear of silence, harsh drive and envelopes too slow to
+/// play. Voice stealing, the reference tone and the quality level are left alone, since they
+/// aren't part of how a patch sounds.
+const DEFAULT_RANGES: [(ParamId, f32, f32); 10] = [
+    (ParamId::Waveform, 1.0, 4.0),
+    (ParamId::OctaveShift, -1.0, 1.0),
+    (ParamId::TremoloEnabled, 0.0, 1.0),
+    (ParamId::TremoloRate, 2.0, 8.0),
+    (ParamId::TremoloDepth, 0.1, 0.6),
+    (ParamId::Drive, 0.5, 3.0),
+    (ParamId::Attack, 0.002, 0.5),
+    (ParamId::Decay, 0.05, 1.0),
+    (ParamId::Sustain, 0.3, 1.0),
+    (ParamId::Release, 0.05, 1.5),
+];
+
+/// How random patches are drawn, for the random patch key and `--random-patch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RandomizerConfig {
+    /// Lowest and highest value to draw each parameter from, by parameter name, in place of
+    /// the built-in range. Naming a parameter that random patches otherwise leave alone brings
+    /// it in.
+    pub ranges: HashMap<String, [f32; 2]>,
+    /// Parameters random patches never change, by name.
+    pub locked: Vec<String>,
+    /// How many of the last patches a new one has to differ from.
+    pub history: usize,
+    /// Least a new patch may differ from each of those, as the root-mean-square difference of
+    /// its parameters, each measured across its range. Zero lets a patch come up twice.
+    pub min_distance: f32,
+    /// Seed for the patches, so a session draws the same ones in the same order every time.
+    /// Unset seeds from the clock; the seed is logged with every patch either way.
+    pub seed: Option<u64>,
+    /// Applies a random patch before the first sound, as `--random-patch` does.
+    pub on_startup: bool,
+}
+
+impl Default for RandomizerConfig {
+    fn default() -> Self {
+        RandomizerConfig {
+            ranges: HashMap::new(),
+            locked: Vec::new(),
+            history: 4,
+            min_distance: 0.2,
+            seed: None,
+            on_startup: false,
+        }
+    }
+}
+
+/// A patch the randomizer drew: a value for each parameter it sets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RandomPatch {
+    /// Which patch of the session this is, counting from 1.
+    pub number: usize,
+    /// The session's seed; the same seed draws the same patches in the same order.
+    pub seed: u64,
+    pub values: Vec<(ParamId, f32)>,
+}
+
+impl fmt::Display for RandomPatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "patch {} from seed {}:", self.number, self.seed)?;
+        for (index, (param, value)) in self.values.iter().enumerate() {
+            let separator = if index == 0 { " " } else { ", " };
+            match param {
+                ParamId::Waveform => write!(
+                    f,
+                    "{}{} {}",
+                    separator,
+                    param,
+                    waveform_from_param(*value).name()
+                )?,
+                _ if is_stepped(*param) => write!(f, "{}{} {:.0}", separator, param, value)?,
+                _ => write!(f, "{}{} {:.3}", separator, param, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Draws random patches, each one kept from landing too close to the last few.
+///
+/// Every parameter is drawn uniformly across its range, except times, rates and the drive,
+/// which are drawn evenly on a log scale so short settings come up as often as long ones.
+/// Parameters that only take whole steps are drawn a step at a time.
+#[derive(Debug)]
+pub struct PatchRandomizer {
+    seed: u64,
+    rng: SplitMix64,
+    /// The parameters a patch sets, in number order, with the range each is drawn from.
+    ranges: Vec<(ParamId, f32, f32)>,
+    history: usize,
+    min_distance: f32,
+    /// Where each of the last patches sat in its ranges, the oldest first.
+    recent: VecDeque<Vec<f32>>,
+    patches: usize,
+}
+
+impl PatchRandomizer {
+    pub fn new(config: &RandomizerConfig) -> Self {
+        let mut ranges = DEFAULT_RANGES.to_vec();
+        for (name, [low, high]) in &config.ranges {
+            let param = match name.parse::<ParamId>() {
+                Ok(param) => param,
+                Err(err) => {
+                    warn!("Ignoring the randomizer range for '{}': {:#}", name, err);
+                    continue;
+                }
+            };
+            let (Ok(low), Ok(high)) = (param.clamp(*low), param.clamp(*high)) else {
+                warn!("Ignoring the randomizer's non-finite range for {}", param);
+                continue;
+            };
+            ranges.retain(|(other, _, _)| *other != param);
+            ranges.push((param, low.min(high), low.max(high)));
+        }
+        for name in &config.locked {
+            match name.parse::<ParamId>() {
+                Ok(param) => ranges.retain(|(other, _, _)| *other != param),
+                Err(err) => warn!("Ignoring the randomizer lock on '{}': {:#}", name, err),
+            }
+        }
+        // The config's ranges come out of a map in no particular order, and the draws have
+        // to happen in the same order for a seed to mean anything.
+        ranges.sort_by_key(|(param, _, _)| param.index());
+
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+        });
+        PatchRandomizer {
+            seed,
+            rng: SplitMix64::new(seed),
+            ranges,
+            history: config.history,
+            min_distance: config.min_distance,
+            recent: VecDeque::new(),
+            patches: 0,
+        }
+    }
+
+    /// Draws the next patch.
+    pub fn next_patch(&mut self) -> RandomPatch {
+        let mut best = self.draw();
+        let mut best_distance = self.distance_to_recent(&best);
+        for _ in 1..MAX_ATTEMPTS {
+            if best_distance >= self.min_distance {
+                break;
+            }
+            let candidate = self.draw();
+            let distance = self.distance_to_recent(&candidate);
+            if distance > best_distance {
+                best = candidate;
+                best_distance = distance;
+            }
+        }
+
+        let values = self
+            .ranges
+            .iter()
+            .zip(&best)
+            .map(|(&(param, low, high), &position)| (param, value_at(param, low, high, position)))
+            .collect();
+        if self.history > 0 {
+            if self.recent.len() == self.history {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(best);
+        }
+        self.patches += 1;
+        RandomPatch {
+            number: self.patches,
+            seed: self.seed,
+            values,
+        }
+    }
+
+    /// A candidate patch, as where each parameter sits in its range, from 0 to 1.
+    fn draw(&mut self) -> Vec<f32> {
+        let mut positions = Vec::with_capacity(self.ranges.len());
+        for &(param, low, high) in &self.ranges {
+            let unit = self.rng.next_unit();
+            let position = if is_stepped(param) {
+                let steps = (high.round() - low.round()).max(0.0);
+                if steps == 0.0 {
+                    0.0
+                } else {
+                    (unit * (steps + 1.0)).floor().min(steps) / steps
+                }
+            } else {
+                unit
+            };
+            positions.push(position);
+        }
+        positions
+    }
+
+    /// How far `candidate` is from the nearest of the recent patches; infinite when there are
+    /// none to be near.
+    fn distance_to_recent(&self, candidate: &[f32]) -> f32 {
+        self.recent
+            .iter()
+            .map(|recent| {
+                let squares: f32 = recent
+                    .iter()
+                    .zip(candidate)
+                    .map(|(a, b)| (a - b) * (a - b))
+                    .sum();
+                (squares / candidate.len().max(1) as f32).sqrt()
+            })
+            .fold(f32::INFINITY, f32::min)
+    }
+}
+
+/// Whether `param` only takes whole steps.
+fn is_stepped(param: ParamId) -> bool {
+    matches!(
+        param,
+        ParamId::Waveform
+            | ParamId::OctaveShift
+            | ParamId::TremoloEnabled
+            | ParamId::StealPolicy
+            | ParamId::Quality
+    )
+}
+
+/// Whether `param` is drawn evenly on a log scale rather than a linear one.
+fn is_logarithmic(param: ParamId) -> bool {
+    matches!(
+        param,
+        ParamId::TremoloRate | ParamId::Drive | ParamId::Attack | ParamId::Decay | ParamId::Release
+    )
+}
+
+/// The value `position` of the way from `low` to `high` stands for.
+fn value_at(param: ParamId, low: f32, high: f32, position: f32) -> f32 {
+    if is_stepped(param) {
+        low.round() + (position * (high.round() - low.round())).round()
+    } else if is_logarithmic(param) && low > 0.0 {
+        low * (high / low).powf(position)
+    } else {
+        low + position * (high - low)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seeded(seed: u64) -> RandomizerConfig {
+        RandomizerConfig {
+            seed: Some(seed),
+            ..RandomizerConfig::default()
+        }
+    }
+
+    fn patches(config: &RandomizerConfig, count: usize) -> Vec<RandomPatch> {
+        let mut randomizer = PatchRandomizer::new(config);
+        (0..count).map(|_| randomizer.next_patch()).collect()
+    }
+
+    fn value(patch: &RandomPatch, param: ParamId) -> Option<f32> {
+        patch
+            .values
+            .iter()
+            .find(|(other, _)| *other == param)
+            .map(|(_, value)| *value)
+    }
+
+    #[test]
+    fn a_fixed_seed_draws_exactly_the_same_patches_in_the_same_order() {
+        let first = patches(&seeded(7), 20);
+        assert_eq!(first, patches(&seeded(7), 20));
+        assert_eq!(first[0].number, 1);
+        assert_eq!(first[19].number, 20);
+        assert!(first.iter().all(|patch| patch.seed == 7));
+        // The seed is in the summary, for drawing the patch again.
+        assert!(first[2]
+            .to_string()
+            .starts_with("patch 3 from seed 7: waveform "));
+
+        let other = patches(&seeded(8), 20);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn a_seed_draws_the_same_patches_whatever_order_the_ranges_were_given_in() {
+        let mut config = seeded(3);
+        for (name, range) in [
+            ("reference_pitch", [100.0, 200.0]),
+            ("attack", [0.01, 0.02]),
+            ("octave_shift", [0.0, 2.0]),
+            ("steal_policy", [1.0, 3.0]),
+        ] {
+            config.ranges.insert(name.to_string(), range);
+        }
+        let first = patches(&config, 10);
+        for _ in 0..5 {
+            // A map built again may iterate in another order.
+            config.ranges = config.ranges.clone().into_iter().collect();
+            assert_eq!(patches(&config, 10), first);
+        }
+    }
+
+    #[test]
+    fn every_value_stays_within_its_range_and_the_registry_range() {
+        for patch in patches(&seeded(11), 500) {
+            assert_eq!(patch.values.len(), DEFAULT_RANGES.len());
+            for (param, low, high) in DEFAULT_RANGES {
+                let value = value(&patch, param).unwrap();
+                assert!((low..=high).contains(&value), "{} {}", param, value);
+                assert_eq!(param.clamp(value).unwrap(), value, "{}", param);
+                if is_stepped(param) {
+                    assert_eq!(value, value.round(), "{}", param);
+                }
+            }
+            // Never silence.
+            assert_ne!(value(&patch, ParamId::Waveform), Some(0.0));
+        }
+    }
+
+    #[test]
+    fn stepped_and_logarithmic_parameters_cover_their_whole_range() {
+        let patches = patches(&seeded(5), 400);
+        let values = |param| -> Vec<f32> {
+            patches
+                .iter()
+                .map(|patch| value(patch, param).unwrap())
+                .collect()
+        };
+        // Every waveform and octave comes up.
+        for waveform in 1..=4 {
+            assert!(values(ParamId::Waveform).contains(&(waveform as f32)));
+        }
+        for octave in -1..=1 {
+            assert!(values(ParamId::OctaveShift).contains(&(octave as f32)));
+        }
+        // Attacks from 2 to 500 ms: drawn on a log scale, about half fall under 32 ms, the
+        // geometric middle, where a linear draw would put almost none.
+        let attacks = values(ParamId::Attack);
+        let short = attacks.iter().filter(|&&attack| attack < 0.0316).count();
+        assert!((150..250).contains(&short), "{}", short);
+    }
+
+    #[test]
+    fn locked_parameters_are_never_set_and_overridden_ranges_are_kept() {
+        let mut config = seeded(9);
+        config.locked = vec!["drive".to_string(), "waveform".to_string()];
+        config.ranges.insert("sustain".to_string(), [0.9, 0.8]);
+        // Brought in by naming it.
+        config
+            .ranges
+            .insert("reference_pitch".to_string(), [400.0, 480.0]);
+        // Past the registry's range, which wins.
+        config
+            .ranges
+            .insert("tremolo_rate".to_string(), [10.0, 50.0]);
+        // Ignored.
+        config.ranges.insert("loudness".to_string(), [0.0, 1.0]);
+        config.ranges.insert("decay".to_string(), [f32::NAN, 1.0]);
+        config.locked.push("pan".to_string());
+
+        for patch in patches(&config, 200) {
+            assert_eq!(value(&patch, ParamId::Drive), None);
+            assert_eq!(value(&patch, ParamId::Waveform), None);
+            let sustain = value(&patch, ParamId::Sustain).unwrap();
+            assert!((0.8..=0.9).contains(&sustain), "{}", sustain);
+            let pitch = value(&patch, ParamId::ReferencePitch).unwrap();
+            assert!((400.0..=480.0).contains(&pitch), "{}", pitch);
+            let rate = value(&patch, ParamId::TremoloRate).unwrap();
+            assert!((10.0..=20.0).contains(&rate), "{}", rate);
+            let decay = value(&patch, ParamId::Decay).unwrap();
+            assert!((0.05..=1.0).contains(&decay), "{}", decay);
+            // Still in number order.
+            assert!(patch
+                .values
+                .windows(2)
+                .all(|pair| pair[0].0.index() < pair[1].0.index()));
+        }
+    }
+
+    #[test]
+    fn locking_everything_draws_empty_patches() {
+        let mut config = seeded(1);
+        config.locked = ParamId::ALL.iter().map(|param| param.to_string()).collect();
+        assert!(patches(&config, 3)
+            .iter()
+            .all(|patch| patch.values.is_empty()));
+    }
+
+    #[test]
+    fn a_candidate_too_close_to_a_recent_patch_is_redrawn() {
+        let config = seeded(21);
+        let mut randomizer = PatchRandomizer::new(&config);
+        // The candidate the next patch would start from, nudged a hair to make a recent patch
+        // it is nearly identical to.
+        let candidate = PatchRandomizer::new(&config).draw();
+        let near: Vec<f32> = candidate.iter().map(|position| position * 0.99).collect();
+        assert!(randomizer.distance_to_recent(&candidate).is_infinite());
+        randomizer.recent.push_back(near.clone());
+        assert!(randomizer.distance_to_recent(&candidate) < config.min_distance);
+
+        randomizer.next_patch();
+        let drawn = randomizer.recent.back().unwrap();
+        assert_ne!(*drawn, candidate);
+        let squares: f32 = drawn
+            .iter()
+            .zip(&near)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum();
+        assert!((squares / near.len() as f32).sqrt() >= config.min_distance);
+
+        // Without the rejection, the near-identical candidate goes through.
+        let mut accepting = PatchRandomizer::new(&RandomizerConfig {
+            min_distance: 0.0,
+            ..config
+        });
+        accepting.recent.push_back(near);
+        accepting.next_patch();
+        assert_eq!(*accepting.recent.back().unwrap(), candidate);
+    }
+
+    #[test]
+    fn with_one_free_parameter_patches_alternate_away_from_the_last() {
+        let mut config = seeded(4);
+        config.locked = ParamId::ALL
+            .iter()
+            .filter(|param| **param != ParamId::TremoloEnabled)
+            .map(|param| param.to_string())
+            .collect();
+        config.history = 1;
+        let values: Vec<f32> = patches(&config, 20)
+            .iter()
+            .map(|patch| value(patch, ParamId::TremoloEnabled).unwrap())
+            .collect();
+        assert!(
+            values.windows(2).all(|pair| pair[0] != pair[1]),
+            "{:?}",
+            values
+        );
+
+        // With no history to differ from, the same patch can come up twice running.
+        config.history = 0;
+        let values: Vec<f32> = patches(&config, 20)
+            .iter()
+            .map(|patch| value(patch, ParamId::TremoloEnabled).unwrap())
+            .collect();
+        assert!(
+            values.windows(2).any(|pair| pair[0] == pair[1]),
+            "{:?}",
+            values
+        );
+    }
+
+    #[test]
+    fn the_history_keeps_only_the_last_patches() {
+        let mut config = seeded(2);
+        config.history = 3;
+        let mut randomizer = PatchRandomizer::new(&config);
+        for count in 1..=10 {
+            randomizer.next_patch();
+            assert_eq!(randomizer.recent.len(), count.min(3));
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::f64::consts::TAU;
+
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{ducking::ramp_towards, AudioBuffer, ParamId, SmoothedParam};
+
+/// Where in the chain the reference tone joins the output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReferenceRoute {
+    /// Mixed in with the voices, so the wave shaper colors it like everything else.
+    PreEffects,
+    /// Added after the effects, so it stays a clean sine to tune against.
+    #[default]
+    PostEffects,
+}
+
+/// Settings for the reference tone, a plain sine to tune an instrument or an ear against.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReferenceToneConfig {
+    /// Pitch in Hz.
+    pub frequency: f32,
+    /// Level, from 0.0 to 1.0.
+    pub level: f32,
+    /// Seconds the tone fades in and out over when toggled, so it never clicks.
+    pub fade_time: f32,
+    pub route: ReferenceRoute,
+}
+
+impl Default for ReferenceToneConfig {
+    fn default() -> Self {
+        ReferenceToneConfig {
+            frequency: 440.0,
+            level: 0.25,
+            fade_time: 0.02,
+            route: ReferenceRoute::PostEffects,
+        }
+    }
+}
+
+/// The reference tone's own voice: a sine outside the voice allocator, untouched by the
+/// octave shift, the waveform keys and the envelopes.
+#[derive(Debug)]
+pub struct ReferenceTone {
+    config: ReferenceToneConfig,
+    sample_rate: f32,
+    /// Pitch in Hz, gliding to each new setting.
+    frequency: SmoothedParam,
+    /// Position in the cycle, from 0.0 to 1.0. Kept in double precision so the pitch doesn't
+    /// wander over a long hold.
+    phase: f64,
+    gain: f32,
+}
+
+impl ReferenceTone {
+    pub fn new(config: ReferenceToneConfig, sample_rate: f32) -> Self {
+        ReferenceTone {
+            frequency: SmoothedParam::new(
+                config.frequency,
+                ParamId::ReferencePitch.smoothing(),
+                sample_rate,
+            ),
+            config,
+            sample_rate,
+            phase: 0.0,
+            gain: 0.0,
+        }
+    }
+
+    pub fn config(&self) -> &ReferenceToneConfig {
+        &self.config
+    }
+
+    /// The pitch the tone is at, or gliding to.
+    pub fn frequency(&self) -> f32 {
+        self.frequency.target()
+    }
+
+    /// Changes the pitch, gliding there while the tone sounds. The phase carries on, so the
+    /// change doesn't click.
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency.set_target(frequency);
+    }
+
+    /// Whether the tone can be heard, including while it fades out.
+    pub fn is_sounding(&self) -> bool {
+        self.gain > 0.0
+    }
+
+    /// Adds the tone to every channel of `buffer` if it joins the chain at `route`, fading
+    /// towards full level while `on` and towards silence otherwise.
+    pub fn mix_into(&mut self, route: ReferenceRoute, on: bool, buffer: &mut AudioBuffer) {
+        if route != self.config.route {
+            return;
+        }
+        if !on && !self.is_sounding() {
+            // Nothing is heard to glide from, so the tone starts at its new pitch.
+            self.frequency.set_immediate(self.frequency.target());
+            return;
+        }
+        let target = if on { 1.0 } else { 0.0 };
+        let step = if self.config.fade_time > 0.0 {
+            1.0 / (self.config.fade_time * self.sample_rate)
+        } else {
+            f32::INFINITY
+        };
+        let level = self.config.level.clamp(0.0, 1.0);
+        // Channels are laid out one after the other, so each frame's sample goes in at the
+        // same offset into every channel.
+        let num_frames = buffer.num_frames();
+        let frequency = self.frequency.next_block(num_frames);
+        for frame in 0..num_frames {
+            let increment = frequency.value(frame, num_frames) as f64 / self.sample_rate as f64;
+            self.gain = ramp_towards(self.gain, target, step);
+            let sample = (self.phase * TAU).sin() as f32 * level * self.gain;
+            for channel in 0..buffer.num_channels() {
+                buffer.data[channel * num_frames + frame] += sample;
+            }
+            self.phase = (self.phase + increment).fract();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// `frames` mono frames of `tone` at the default route, switched `on`.
+    fn render(tone: &mut ReferenceTone, on: bool, frames: usize) -> Vec<f32> {
+        let mut buffer = AudioBuffer {
+            data: vec![0.0; frames],
+            num_channels: 1,
+        };
+        tone.mix_into(ReferenceRoute::PostEffects, on, &mut buffer);
+        buffer.data
+    }
+
+    fn largest_step(samples: &[f32]) -> f32 {
+        samples
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn toggling_fades_the_tone_in_and_out_without_a_click() {
+        let config = ReferenceToneConfig::default();
+        let mut tone = ReferenceTone::new(config.clone(), SAMPLE_RATE);
+        let fade = (config.fade_time * SAMPLE_RATE) as usize;
+        let mut samples = render(&mut tone, true, 4 * fade);
+        samples.extend(render(&mut tone, false, 2 * fade));
+
+        // No sample moves further than the full-level sine does on its own.
+        let steady_step = config.level * std::f32::consts::TAU * config.frequency / SAMPLE_RATE;
+        assert!(largest_step(&samples) <= steady_step * 1.001);
+        // Under full level until the fade is over, there after, and silent once faded out.
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak(&samples[..fade / 2]) < config.level * 0.51);
+        assert!((peak(&samples[2 * fade..4 * fade]) - config.level).abs() < 1e-3);
+        assert!(samples[5 * fade..].iter().all(|&sample| sample == 0.0));
+        assert!(!tone.is_sounding());
+    }
+
+    #[test]
+    fn the_tone_only_joins_the_chain_at_its_route() {
+        let mut tone = ReferenceTone::new(ReferenceToneConfig::default(), SAMPLE_RATE);
+        let mut buffer = AudioBuffer {
+            data: vec![0.0; 256],
+            num_channels: 1,
+        };
+        tone.mix_into(ReferenceRoute::PreEffects, true, &mut buffer);
+        assert!(buffer.data.iter().all(|&sample| sample == 0.0));
+        assert!(!tone.is_sounding());
+    }
+
+    #[test]
+    fn every_channel_gets_the_same_tone() {
+        let mut tone = ReferenceTone::new(ReferenceToneConfig::default(), SAMPLE_RATE);
+        let mut buffer = AudioBuffer {
+            data: vec![0.0; 2 * 512],
+            num_channels: 2,
+        };
+        tone.mix_into(ReferenceRoute::PostEffects, true, &mut buffer);
+        assert_eq!(buffer.data[..512], buffer.data[512..]);
+        assert!(buffer.data.iter().any(|&sample| sample != 0.0));
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::Path;
+
+use anyhow::Result;
+use tracing::info;
+
+use crate::synth::{Score, SynthEngine};
+
+/// Largest block handed to the engine at once when no note event falls inside it.
+const BLOCK_SIZE: usize = 512;
+
+/// Plays `score` through `engine` as fast as possible, returning the rendered mono samples.
+///
+/// Blocks are split at note boundaries so every note starts and stops on its exact sample.
+pub fn render_score(engine: &mut SynthEngine, score: &Score) -> Vec<f32> {
+    let sample_rate = engine.sample_rate();
+    let to_samples = |seconds: f32| (seconds * sample_rate).round() as usize;
+
+    let mut events: Vec<(usize, bool, &str)> = Vec::with_capacity(score.notes.len() * 2);
+    for note in score.notes.iter() {
+        events.push((to_samples(note.start), true, &note.note));
+        events.push((to_samples(note.start + note.duration), false, &note.note));
+    }
+    // Note offs sort before note ons at the same position.
+    events.sort_by_key(|(position, is_on, _)| (*position, *is_on));
+
+    let total_samples = to_samples(score.duration());
+    let mut output = Vec::with_capacity(total_samples);
+    let mut pending = events.into_iter().peekable();
+
+    while output.len() < total_samples {
+        while let Some((_, is_on, note)) =
+            pending.next_if(|(position, _, _)| *position <= output.len())
+        {
+            let mut note_state = engine.note_state().lock().unwrap();
+            if is_on {
+                note_state.note_on(note.to_string());
+            } else {
+                note_state.note_off(note.to_string());
+            }
+        }
+
+        let next_event = pending
+            .peek()
+            .map_or(total_samples, |(position, _, _)| *position);
+        let block_size = (next_event.min(total_samples) - output.len()).min(BLOCK_SIZE);
+        output.extend(engine.render(block_size).data);
+    }
+
+    output
+}
+
+/// Writes mono samples to a 32-bit float WAV file.
+pub fn write_wav(path: &Path, samples: &[f32], sample_rate: u32) -> Result<()> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for &sample in samples {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Renders the score at `score_path` offline and writes the result to `out_path`.
+pub fn render_to_wav(score_path: &Path, out_path: &Path, sample_rate: u32) -> Result<()> {
+    let score = Score::load(score_path)?;
+    info!(
+        "Rendering {} notes ({:.2}s) from '{}'",
+        score.notes.len(),
+        score.duration(),
+        score_path.display()
+    );
+
+    let mut engine = SynthEngine::builder().build(sample_rate as f32);
+    let samples = render_score(&mut engine, &score);
+    write_wav(out_path, &samples, sample_rate)?;
+
+    info!(
+        "Wrote {} samples to '{}'",
+        samples.len(),
+        out_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::ScoreNote;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn a_two_note_sequence_renders_to_a_wav_of_the_right_length_with_sound_where_the_notes_are() {
+        let score = Score {
+            notes: vec![
+                ScoreNote {
+                    note: "C".to_string(),
+                    start: 0.0,
+                    duration: 0.5,
+                },
+                ScoreNote {
+                    note: "E".to_string(),
+                    start: 1.5,
+                    duration: 0.5,
+                },
+            ],
+        };
+        let mut engine = SynthEngine::builder().build(SAMPLE_RATE as f32);
+        let samples = render_score(&mut engine, &score);
+
+        let path =
+            std::env::temp_dir().join(format!("visiosynth-render-{}.wav", std::process::id()));
+        write_wav(&path, &samples, SAMPLE_RATE).unwrap();
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        let read: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(spec.channels, 1);
+        assert_eq!(spec.sample_rate, SAMPLE_RATE);
+        assert_eq!(read.len(), (2.0 * SAMPLE_RATE as f32).round() as usize);
+        assert_eq!(read, samples);
+
+        let window = |from: f32, to: f32| {
+            &read[(from * SAMPLE_RATE as f32) as usize..(to * SAMPLE_RATE as f32) as usize]
+        };
+        assert!(rms(window(0.2, 0.5)) > 0.01, "first note missing");
+        // The first note has faded out well before the second starts.
+        assert!(rms(window(1.1, 1.5)) < 1e-4, "sound between the notes");
+        assert!(rms(window(1.7, 2.0)) > 0.01, "second note missing");
+    }
+}
@@ -0,0 +1,292 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{Oscillator, OscillatorWaveform, Scale, TremoloEffect};
+
+/// Settings for the playable pitch ribbon along the bottom of the window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RibbonConfig {
+    /// Height of the strip as a fraction of the window height.
+    pub height: f32,
+    /// Pitch range of the strip in octaves, centered on the scale root.
+    pub range_octaves: f32,
+    /// Snap the pitch to the current scale instead of gliding freely.
+    pub quantize: bool,
+    /// Time constant of the pitch glide in seconds. Zero jumps straight to the target.
+    pub glide_time: f32,
+    /// Keep the pitch on the current scale while it glides too, so a glide steps through the
+    /// degrees between two positions instead of sliding past them.
+    pub quantize_glide: bool,
+    /// Bus the ribbon's voice is mixed on; unset is the main bus.
+    pub bus: Option<String>,
+}
+
+impl Default for RibbonConfig {
+    fn default() -> Self {
+        RibbonConfig {
+            height: 0.1,
+            range_octaves: 2.0,
+            quantize: false,
+            glide_time: 0.05,
+            quantize_glide: false,
+            bus: None,
+        }
+    }
+}
+
+/// Maps a position along the ribbon to a frequency.
+///
+/// The left edge sits `range_octaves / 2` below `root_frequency` and the right edge the same
+/// distance above it. With `scale` given, the pitch snaps to the nearest degree of that scale.
+pub fn ribbon_frequency(
+    normalized_x: f32,
+    root_frequency: f32,
+    range_octaves: f32,
+    scale: Option<&Scale>,
+) -> f32 {
+    let mut semitones = (normalized_x.clamp(0.0, 1.0) - 0.5) * range_octaves * 12.0;
+    if let Some(scale) = scale {
+        semitones = nearest_scale_semitone(semitones, &scale.intervals);
+    }
+    root_frequency * 2.0f32.powf(semitones / 12.0)
+}
+
+/// Snaps `frequency` to the closest degree of `scale`, in whichever octave is nearest. Left as it
+/// is when the scale has no intervals, or a root it can't find a frequency for.
+pub fn quantize_to_scale(frequency: f32, scale: &Scale) -> f32 {
+    let root_frequency = match scale.calculate_frequency(&scale.root_note) {
+        Some(root_frequency) if !scale.intervals.is_empty() && frequency > 0.0 => root_frequency,
+        _ => return frequency,
+    };
+    let semitones = 12.0 * (frequency / root_frequency).log2();
+    root_frequency * 2.0f32.powf(nearest_scale_semitone(semitones, &scale.intervals) / 12.0)
+}
+
+/// Rounds a semitone offset from the root to the closest degree of a scale given by its intervals.
+fn nearest_scale_semitone(semitones: f32, intervals: &[i32]) -> f32 {
+    let octave = (semitones / 12.0).floor();
+    let within_octave = semitones - octave * 12.0;
+
+    let mut degree = 0;
+    let mut nearest = 0.0f32;
+    for step in intervals.iter() {
+        degree += step;
+        if (degree as f32 - within_octave).abs() < (nearest - within_octave).abs() {
+            nearest = degree as f32;
+        }
+    }
+    octave * 12.0 + nearest
+}
+
+/// The single voice played by the ribbon, gliding towards the touched pitch.
+#[derive(Debug)]
+pub struct RibbonVoice {
+    oscillator: Oscillator,
+    frequency: f32,
+    target_frequency: f32,
+}
+
+impl RibbonVoice {
+    pub fn new(
+        frequency: f32,
+        sample_rate: f32,
+        waveform: OscillatorWaveform,
+        tremolo_effect: Arc<TremoloEffect>,
+        start_sample: u64,
+    ) -> Self {
+        let mut oscillator = Oscillator::builder()
+            .frequency(frequency)
+            .sample_rate(sample_rate)
+            .note("RIBBON".to_string())
+            .waveform(waveform)
+            .tremolo_effect(tremolo_effect)
+            .build();
+        oscillator.start(start_sample);
+        RibbonVoice {
+            oscillator,
+            frequency,
+            target_frequency: frequency,
+        }
+    }
+
+    pub fn set_target_frequency(&mut self, frequency: f32) {
+        self.target_frequency = frequency;
+    }
+
+    pub fn oscillator(&self) -> &Oscillator {
+        &self.oscillator
+    }
+
+    pub fn oscillator_mut(&mut self) -> &mut Oscillator {
+        &mut self.oscillator
+    }
+
+    /// Moves the pitch one block closer to the target, then renders the block. With `scale`
+    /// given, the block plays at the scale degree nearest the gliding pitch.
+    pub fn generate_block(
+        &mut self,
+        current_sample: u64,
+        num_samples: usize,
+        glide_time: f32,
+        sample_rate: f32,
+        scale: Option<&Scale>,
+    ) -> Vec<f32> {
+        let block_time = num_samples as f32 / sample_rate;
+        let amount = if glide_time > 0.0 {
+            1.0 - (-block_time / glide_time).exp()
+        } else {
+            1.0
+        };
+        self.frequency += (self.target_frequency - self.frequency) * amount;
+        let frequency = match scale {
+            Some(scale) => quantize_to_scale(self.frequency, scale),
+            None => self.frequency,
+        };
+        self.oscillator.set_frequency(frequency);
+        self.oscillator.generate_wave(current_sample, num_samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: f32 = 261.63;
+
+    fn major() -> Scale {
+        Scale {
+            root_note: "C".to_string(),
+            intervals: vec![2, 2, 1, 2, 2, 2, 1],
+        }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() <= b * 1e-5
+    }
+
+    #[test]
+    fn the_edges_sit_half_the_range_either_side_of_the_root() {
+        assert!(close(ribbon_frequency(0.0, ROOT, 2.0, None), ROOT / 2.0));
+        assert!(close(ribbon_frequency(0.5, ROOT, 2.0, None), ROOT));
+        assert!(close(ribbon_frequency(1.0, ROOT, 2.0, None), ROOT * 2.0));
+        assert!(close(ribbon_frequency(1.0, ROOT, 4.0, None), ROOT * 4.0));
+    }
+
+    #[test]
+    fn positions_past_the_edges_are_clamped() {
+        assert_eq!(
+            ribbon_frequency(-0.5, ROOT, 2.0, None),
+            ribbon_frequency(0.0, ROOT, 2.0, None)
+        );
+        assert_eq!(
+            ribbon_frequency(1.5, ROOT, 2.0, None),
+            ribbon_frequency(1.0, ROOT, 2.0, None)
+        );
+    }
+
+    #[test]
+    fn free_glides_between_degrees_and_quantized_snaps_to_them() {
+        // 1.2 semitones above the root over a two octave strip.
+        let x = 0.5 + 1.2 / 24.0;
+        let free = ribbon_frequency(x, ROOT, 2.0, None);
+        assert!(close(free, ROOT * 2.0f32.powf(1.2 / 12.0)));
+        let quantized = ribbon_frequency(x, ROOT, 2.0, Some(&major()));
+        assert!(close(quantized, ROOT * 2.0f32.powf(2.0 / 12.0)));
+        // Below the root the nearest degree is in the octave under it.
+        let quantized = ribbon_frequency(0.5 - 0.8 / 24.0, ROOT, 2.0, Some(&major()));
+        assert!(close(quantized, ROOT * 2.0f32.powf(-1.0 / 12.0)));
+    }
+
+    #[test]
+    fn quantized_edges_land_on_the_root_octaves() {
+        let scale = major();
+        assert!(close(
+            ribbon_frequency(0.0, ROOT, 2.0, Some(&scale)),
+            ROOT / 2.0
+        ));
+        assert!(close(
+            ribbon_frequency(1.0, ROOT, 2.0, Some(&scale)),
+            ROOT * 2.0
+        ));
+    }
+
+    /// The frequency of D4, E4 and so on: `semitones` above middle C, as the scale tunes it.
+    fn c4_plus(semitones: f32) -> f32 {
+        let c4 = major().calculate_frequency("C").unwrap();
+        c4 * 2.0f32.powf(semitones / 12.0)
+    }
+
+    #[test]
+    fn a_frequency_between_two_degrees_snaps_to_the_nearer() {
+        let scale = major();
+        // Between D4 and E4, nearer D4, and between E4 and F4, nearer F4.
+        assert!(close(quantize_to_scale(305.0, &scale), c4_plus(2.0)));
+        assert!(close(quantize_to_scale(340.0, &scale), c4_plus(5.0)));
+        // Up into the next octave, and down into the one below.
+        assert!(close(quantize_to_scale(510.0, &scale), c4_plus(12.0)));
+        assert!(close(quantize_to_scale(150.0, &scale), c4_plus(-10.0)));
+        // Just under the root rounds up to it rather than down to B3.
+        assert!(close(
+            quantize_to_scale(c4_plus(-0.4), &scale),
+            c4_plus(0.0)
+        ));
+        assert!(close(
+            quantize_to_scale(c4_plus(-0.6), &scale),
+            c4_plus(-1.0)
+        ));
+    }
+
+    #[test]
+    fn a_frequency_on_a_degree_stays_put() {
+        let scale = major();
+        for semitones in [-12.0, 0.0, 2.0, 4.0, 5.0, 7.0, 9.0, 11.0, 12.0, 24.0] {
+            let frequency = c4_plus(semitones);
+            assert!(close(quantize_to_scale(frequency, &scale), frequency));
+        }
+    }
+
+    #[test]
+    fn without_a_usable_scale_the_frequency_is_left_alone() {
+        let empty = Scale {
+            root_note: "C".to_string(),
+            intervals: Vec::new(),
+        };
+        assert_eq!(quantize_to_scale(305.0, &empty), 305.0);
+        let no_root = Scale {
+            root_note: "nowhere".to_string(),
+            ..major()
+        };
+        assert_eq!(quantize_to_scale(305.0, &no_root), 305.0);
+        assert_eq!(quantize_to_scale(0.0, &major()), 0.0);
+    }
+
+    #[test]
+    fn a_quantized_glide_steps_up_through_the_scale() {
+        let mut voice = RibbonVoice::new(
+            c4_plus(0.0),
+            48_000.0,
+            OscillatorWaveform::Sine,
+            Arc::new(TremoloEffect::builder().build(48_000.0)),
+            0,
+        );
+        voice.set_target_frequency(c4_plus(12.0));
+        let scale = major();
+        let mut heard = Vec::new();
+        for block in 0..100 {
+            voice.generate_block(block * 480, 480, 0.1, 48_000.0, Some(&scale));
+            heard.push(voice.oscillator().get_frequency());
+        }
+        // Every block plays a degree of the scale, never falling back, and the glide passes
+        // through each degree of the octave on its way up. The first block is already past
+        // halfway to D.
+        assert!(heard.windows(2).all(|pair| pair[1] >= pair[0]));
+        heard.dedup();
+        let degrees = [2.0, 4.0, 5.0, 7.0, 9.0, 11.0, 12.0];
+        assert_eq!(heard.len(), degrees.len(), "{:?}", heard);
+        for (frequency, semitones) in heard.iter().zip(degrees) {
+            assert!(close(*frequency, c4_plus(semitones)), "{:?}", heard);
+        }
+    }
+}
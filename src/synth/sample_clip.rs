@@ -0,0 +1,201 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+
+/// A sustain region of a clip, as sample indices. `end` is exclusive, so playback wraps from
+/// `end - 1` straight to `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopRegion {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A mono audio clip held in memory, with an optional sustain loop.
+#[derive(Debug, Clone)]
+pub struct SampleClip {
+    samples: Arc<[f32]>,
+    sample_rate: u32,
+    loop_region: Option<LoopRegion>,
+}
+
+impl SampleClip {
+    pub fn from_samples(samples: Vec<f32>, sample_rate: u32) -> Self {
+        SampleClip {
+            samples: samples.into(),
+            sample_rate,
+            loop_region: None,
+        }
+    }
+
+    /// Loads a WAV file, mixing multichannel files down to mono.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut reader = hound::WavReader::open(path)
+            .with_context(|| format!("Failed to open clip '{}'", path.display()))?;
+        let spec = reader.spec();
+        let interleaved: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let channels = spec.channels.max(1) as usize;
+        let samples = interleaved
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+            .collect();
+        Ok(SampleClip::from_samples(samples, spec.sample_rate))
+    }
+
+    /// Sets the region to loop over while a note is held.
+    pub fn with_loop(mut self, start: usize, end: usize) -> Result<Self> {
+        if start >= end || end > self.samples.len() {
+            bail!(
+                "Loop {}..{} doesn't fit a clip of {} samples",
+                start,
+                end,
+                self.samples.len()
+            );
+        }
+        self.loop_region = Some(LoopRegion { start, end });
+        Ok(self)
+    }
+
+    pub fn loop_region(&self) -> Option<LoopRegion> {
+        self.loop_region
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Starts a playback of the clip from its first sample.
+    pub fn play(&self) -> ClipPlayer {
+        ClipPlayer {
+            clip: self.clone(),
+            position: 0,
+            held: true,
+        }
+    }
+}
+
+/// Plays a `SampleClip` once, looping its sustain region for as long as the note is held.
+#[derive(Debug, Clone)]
+pub struct ClipPlayer {
+    clip: SampleClip,
+    position: usize,
+    held: bool,
+}
+
+impl ClipPlayer {
+    /// Lets playback run on past the loop to the end of the clip.
+    pub fn release(&mut self) {
+        self.held = false;
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Whether playback has reached the end of the clip.
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.clip.len()
+    }
+
+    /// The next sample, or silence once the clip has finished.
+    pub fn next_sample(&mut self) -> f32 {
+        let Some(&sample) = self.clip.samples.get(self.position) else {
+            return 0.0;
+        };
+        self.position += 1;
+        if let Some(region) = self.clip.loop_region {
+            if self.held && self.position == region.end {
+                self.position = region.start;
+            }
+        }
+        sample
+    }
+
+    /// Fills `buffer` with the next samples.
+    pub fn fill(&mut self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clip whose every sample is its own index, so a sample shows where it was read from.
+    fn numbered_clip(len: usize) -> SampleClip {
+        SampleClip::from_samples((0..len).map(|i| i as f32).collect(), 48_000)
+    }
+
+    #[test]
+    fn a_held_note_loops_the_sustain_region_indefinitely() {
+        let clip = numbered_clip(300).with_loop(100, 200).unwrap();
+        let mut player = clip.play();
+        let mut lead_in = [0.0; 100];
+        player.fill(&mut lead_in);
+        assert!(lead_in.iter().enumerate().all(|(i, s)| *s == i as f32));
+
+        let mut block = [0.0; 1_000];
+        for _ in 0..1_000 {
+            player.fill(&mut block);
+            assert!(!player.is_finished());
+            assert!(block.iter().all(|s| (100.0..200.0).contains(s)));
+            // Each pass through the region is contiguous, wrapping from 199 to 100.
+            for pair in block.windows(2) {
+                assert!(pair[1] == pair[0] + 1.0 || (pair[0] == 199.0 && pair[1] == 100.0));
+            }
+        }
+    }
+
+    #[test]
+    fn releasing_plays_on_to_the_end_of_the_clip() {
+        let clip = numbered_clip(300).with_loop(100, 200).unwrap();
+        let mut player = clip.play();
+        let mut block = vec![0.0; 450];
+        player.fill(&mut block);
+        player.release();
+        let mut rest = Vec::new();
+        while !player.is_finished() {
+            rest.push(player.next_sample());
+        }
+        assert_eq!(rest.first(), Some(&150.0));
+        assert_eq!(rest.last(), Some(&299.0));
+        assert_eq!(player.next_sample(), 0.0);
+    }
+
+    #[test]
+    fn a_clip_without_a_loop_plays_once() {
+        let mut player = numbered_clip(10).play();
+        let mut block = [1.0; 15];
+        player.fill(&mut block);
+        assert!(player.is_finished());
+        assert_eq!(block[9], 9.0);
+        assert_eq!(&block[10..], &[0.0; 5]);
+    }
+
+    #[test]
+    fn loops_outside_the_clip_are_rejected() {
+        assert!(numbered_clip(300).with_loop(200, 100).is_err());
+        assert!(numbered_clip(300).with_loop(100, 100).is_err());
+        assert!(numbered_clip(300).with_loop(100, 301).is_err());
+        assert!(numbered_clip(300).with_loop(0, 300).is_ok());
+    }
+}
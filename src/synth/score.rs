@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use serde::{Deserialize, Serialize};
+
+use crate::synth::keys::keys::transpose_note;
+
+/// MIDI note number of middle C, which the bare names in `NOTE_SEQUENCE` sit around.
+const MIDDLE_C: i32 = 60;
+
+/// Tempo assumed by MIDI files until they say otherwise (120 BPM).
+const DEFAULT_MICROS_PER_BEAT: f64 = 500_000.0;
+
+/// A single note of a score, in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreNote {
+    pub note: String,
+    pub start: f32,
+    pub duration: f32,
+}
+
+/// A list of timed notes for offline rendering.
+///
+/// Sequences are YAML files of the form `notes: [{ note: C, start: 0.0, duration: 0.5 }]`;
+/// standard MIDI files are converted on load.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    pub notes: Vec<ScoreNote>,
+}
+
+impl Score {
+    /// Loads a score, treating `.mid`/`.midi` files as MIDI and anything else as a YAML sequence.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read score '{}'", path.display()))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi") => {
+                Self::from_midi(&bytes)
+            }
+            _ => Ok(serde_yaml::from_slice(&bytes)?),
+        }
+    }
+
+    /// Converts the note on/off pairs of every track of a MIDI file into score notes.
+    pub fn from_midi(bytes: &[u8]) -> Result<Self> {
+        let smf = Smf::parse(bytes).context("Failed to parse MIDI file")?;
+
+        // Flatten all tracks into one list of events at absolute tick positions.
+        let mut events = Vec::new();
+        for track in smf.tracks.iter() {
+            let mut tick = 0u64;
+            for event in track.iter() {
+                tick += event.delta.as_int() as u64;
+                events.push((tick, event.kind));
+            }
+        }
+        events.sort_by_key(|(tick, _)| *tick);
+
+        let mut score = Score::default();
+        let mut held: HashMap<(u8, u8), f32> = HashMap::new();
+        let mut micros_per_beat = DEFAULT_MICROS_PER_BEAT;
+        let mut last_tick = 0u64;
+        let mut seconds = 0.0f64;
+
+        for (tick, kind) in events {
+            let seconds_per_tick = match smf.header.timing {
+                Timing::Metrical(ticks_per_beat) => {
+                    micros_per_beat / 1_000_000.0 / ticks_per_beat.as_int() as f64
+                }
+                Timing::Timecode(fps, subframes) => 1.0 / fps.as_f32() as f64 / subframes as f64,
+            };
+            seconds += (tick - last_tick) as f64 * seconds_per_tick;
+            last_tick = tick;
+            let time = seconds as f32;
+
+            match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                    micros_per_beat = tempo.as_int() as f64;
+                }
+                TrackEventKind::Midi { channel, message } => {
+                    let (key, note_on) = match message {
+                        MidiMessage::NoteOn { key, vel } => (key.as_int(), vel.as_int() > 0),
+                        MidiMessage::NoteOff { key, .. } => (key.as_int(), false),
+                        _ => continue,
+                    };
+                    let id = (channel.as_int(), key);
+                    if note_on {
+                        held.entry(id).or_insert(time);
+                    } else if let Some(start) = held.remove(&id) {
+                        if let Some(note) = midi_note_name(key) {
+                            score.notes.push(ScoreNote {
+                                note,
+                                start,
+                                duration: time - start,
+                            });
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        score
+            .notes
+            .sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+        Ok(score)
+    }
+
+    /// Every note on (`true`) and off (`false`) as `(seconds, is_on, note)`, in time order with
+    /// note offs before note ons at the same time.
+    pub fn events(&self) -> Vec<(f32, bool, &str)> {
+        let mut events: Vec<(f32, bool, &str)> = Vec::with_capacity(self.notes.len() * 2);
+        for note in self.notes.iter() {
+            events.push((note.start, true, &note.note));
+            events.push((note.start + note.duration, false, &note.note));
+        }
+        events.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        events
+    }
+
+    /// The time at which the last note ends.
+    pub fn duration(&self) -> f32 {
+        self.notes
+            .iter()
+            .map(|note| note.start + note.duration)
+            .fold(0.0, f32::max)
+    }
+}
+
+/// Names a MIDI note number the way the keyboard config does, e.g. 61 -> "C_SHARP", 72 -> "C+1".
+pub fn midi_note_name(key: u8) -> Option<String> {
+    transpose_note("C", key as i32 - MIDDLE_C)
+}
@@ -0,0 +1,272 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::synth::{NoteState, OscillatorWaveform, Scale, Score};
+
+/// What the synth is set to when it starts, before `on_startup` runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InitialConfig {
+    pub waveform: OscillatorWaveform,
+    /// Root of the scale, named as `change_key` takes it.
+    pub root_note: String,
+    /// Semitones from each degree of the scale to the next, starting at the root.
+    pub intervals: Vec<i32>,
+}
+
+impl Default for InitialConfig {
+    fn default() -> Self {
+        InitialConfig {
+            waveform: OscillatorWaveform::Silence,
+            root_note: "C".to_string(),
+            intervals: vec![2, 2, 1, 2, 2, 2, 1],
+        }
+    }
+}
+
+impl InitialConfig {
+    /// The scale to start in. A root that isn't a note name falls back to the default's.
+    pub fn scale(&self) -> Scale {
+        let scale = Scale {
+            root_note: self.root_note.clone(),
+            intervals: self.intervals.clone(),
+        };
+        if scale.calculate_frequency(&scale.root_note).is_some() {
+            return scale;
+        }
+        let root_note = InitialConfig::default().root_note;
+        warn!(
+            "Unknown initial root note {:?}, starting in {}",
+            self.root_note, root_note
+        );
+        Scale { root_note, ..scale }
+    }
+}
+
+/// A setting applied when the synth starts, before any sound is made.
+///
+/// Written in the config as a list under `on_startup`, e.g.
+/// `[{ set_waveform: Sawtooth }, { set_octave: -1 }, toggle_tremolo]`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartupEvent {
+    SetWaveform(OscillatorWaveform),
+    /// Octave shift, from -2 to 2.
+    SetOctave(i32),
+    ToggleTremolo,
+    ChangeKey(String),
+    ToggleWaveformSequence,
+}
+
+#[derive(Debug, Default)]
+struct DemoState {
+    running: bool,
+    /// Notes the demo has started and not yet stopped.
+    held: HashSet<String>,
+}
+
+/// A score played live through the engine on a background thread, until it ends or is
+/// cancelled.
+#[derive(Debug, Clone)]
+pub struct Demo {
+    score: Arc<Score>,
+    state: Arc<(Mutex<DemoState>, Condvar)>,
+}
+
+impl Demo {
+    pub fn new(score: Score) -> Self {
+        Demo {
+            score: Arc::new(score),
+            state: Arc::default(),
+        }
+    }
+
+    /// Starts playing the score into `note_state` in real time.
+    pub fn start(&self, note_state: Arc<Mutex<NoteState>>) {
+        let (lock, _) = &*self.state;
+        lock.lock().unwrap().running = true;
+        info!(
+            "Playing a demo of {} notes ({:.1}s)",
+            self.score.notes.len(),
+            self.score.duration()
+        );
+
+        let demo = self.clone();
+        std::thread::spawn(move || demo.run(&note_state));
+    }
+
+    fn run(&self, note_state: &Mutex<NoteState>) {
+        let (lock, condvar) = &*self.state;
+        let started = Instant::now();
+        for (time, is_on, note) in self.score.events() {
+            let deadline = started + Duration::from_secs_f32(time.max(0.0));
+            {
+                let mut state = lock.lock().unwrap();
+                loop {
+                    if !state.running {
+                        return;
+                    }
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    state = condvar.wait_timeout(state, deadline - now).unwrap().0;
+                }
+            }
+
+            // The note state is locked before the demo state, as `cancel` is called with it
+            // held, so a cancel can't slip in between checking and playing the note.
+            let mut note_state = note_state.lock().unwrap();
+            let mut state = lock.lock().unwrap();
+            if !state.running {
+                return;
+            }
+            if is_on {
+                state.held.insert(note.to_string());
+                note_state.note_on(note.to_string());
+            } else {
+                state.held.remove(note);
+                note_state.note_off(note.to_string());
+            }
+        }
+
+        lock.lock().unwrap().running = false;
+        info!("Demo finished");
+    }
+
+    /// Stops the demo and releases the notes it was holding. Returns whether it was still
+    /// playing.
+    pub fn cancel(&self, note_state: &mut NoteState) -> bool {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if !state.running {
+            return false;
+        }
+        state.running = false;
+        for note in state.held.drain() {
+            note_state.note_off(note);
+        }
+        condvar.notify_all();
+        info!("Demo cancelled");
+        true
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.0.lock().unwrap().running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::ScoreNote;
+
+    fn score(notes: &[(&str, f32, f32)]) -> Score {
+        Score {
+            notes: notes
+                .iter()
+                .map(|(note, start, duration)| ScoreNote {
+                    note: note.to_string(),
+                    start: *start,
+                    duration: *duration,
+                })
+                .collect(),
+        }
+    }
+
+    /// Waits up to two seconds for `done` to hold of the note state.
+    fn wait_for(note_state: &Mutex<NoteState>, done: impl Fn(&NoteState) -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            if done(&note_state.lock().unwrap()) {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn cancelling_a_demo_releases_its_notes_and_plays_no_more() {
+        let note_state = Arc::new(Mutex::new(NoteState::new()));
+        let demo = Demo::new(score(&[
+            ("C", 0.0, 30.0),
+            ("E", 0.0, 30.0),
+            ("G", 0.2, 1.0),
+        ]));
+        demo.start(Arc::clone(&note_state));
+        assert!(wait_for(&note_state, |note_state| {
+            note_state.is_playing(&"C".to_string()) && note_state.is_playing(&"E".to_string())
+        }));
+
+        // A note the player is holding themselves is left alone.
+        note_state.lock().unwrap().note_on("A".to_string());
+        assert!(demo.cancel(&mut note_state.lock().unwrap()));
+        assert!(!demo.is_running());
+        std::thread::sleep(Duration::from_millis(300));
+        let note_state = note_state.lock().unwrap();
+        for note in ["C", "E", "G"] {
+            assert!(!note_state.is_playing(&note.to_string()), "{}", note);
+        }
+        assert!(note_state.is_playing(&"A".to_string()));
+    }
+
+    #[test]
+    fn a_demo_that_has_ended_has_nothing_to_cancel() {
+        let note_state = Arc::new(Mutex::new(NoteState::new()));
+        let demo = Demo::new(score(&[("C", 0.0, 0.01)]));
+        demo.start(Arc::clone(&note_state));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while demo.is_running() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!demo.is_running());
+        assert!(!demo.cancel(&mut note_state.lock().unwrap()));
+        assert!(note_state.lock().unwrap().playing_notes.is_empty());
+    }
+
+    #[test]
+    fn an_initial_section_sets_the_starting_waveform_and_scale() {
+        let initial: InitialConfig = serde_yaml::from_str(
+            "waveform: Sawtooth\nroot_note: D\nintervals: [2, 1, 2, 2, 2, 1, 2]\n",
+        )
+        .unwrap();
+        assert_eq!(initial.waveform, OscillatorWaveform::Sawtooth);
+        let scale = initial.scale();
+        assert_eq!(scale.root_note, "D");
+        assert_eq!(scale.intervals, [2, 1, 2, 2, 2, 1, 2]);
+    }
+
+    #[test]
+    fn a_partial_initial_section_keeps_the_defaults_for_the_rest() {
+        let initial: InitialConfig = serde_yaml::from_str("root_note: F_SHARP").unwrap();
+        assert_eq!(initial.waveform, OscillatorWaveform::Silence);
+        let scale = initial.scale();
+        assert_eq!(scale.root_note, "F_SHARP");
+        assert_eq!(scale.intervals, InitialConfig::default().intervals);
+    }
+
+    #[test]
+    fn an_unknown_root_note_starts_in_c() {
+        let initial: InitialConfig = serde_yaml::from_str("root_note: H").unwrap();
+        let scale = initial.scale();
+        assert_eq!(scale.root_note, "C");
+        assert_eq!(scale.intervals, InitialConfig::default().intervals);
+    }
+
+    #[test]
+    fn the_bundled_settings_start_on_a_sine_in_c_major() {
+        let config =
+            crate::synth::load_config(std::path::Path::new("resources/config/settings.yaml"), None)
+                .unwrap();
+        assert_eq!(config.initial.waveform, OscillatorWaveform::Sine);
+        let scale = config.initial.scale();
+        assert_eq!(scale.root_note, "C");
+        assert_eq!(scale.intervals, [2, 2, 1, 2, 2, 2, 1]);
+        assert!(config.on_startup.is_empty());
+    }
+}
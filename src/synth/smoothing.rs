@@ -0,0 +1,392 @@
+use serde::{Deserialize, Serialize};
+
+/// How far an exponential approach may be from its target, relative to the size of the target
+/// (or absolutely, for targets below 1), before it is snapped onto it and counts as settled.
+const EXPONENTIAL_SETTLE_THRESHOLD: f32 = 1e-5;
+
+/// How a parameter moves to a new value.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Smoothing {
+    /// Jumps straight to it, for settings that only take whole steps.
+    #[default]
+    None,
+    /// Moves in a straight line, reaching it after `time` seconds.
+    Linear { time: f32 },
+    /// Closes in on it exponentially, covering about 63% of the remaining distance every
+    /// `time_constant` seconds.
+    Exponential { time_constant: f32 },
+}
+
+/// A block's worth of a smoothed parameter's values, in the cheapest form that describes them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamBlock<'a> {
+    /// The same value for every sample; the parameter has settled.
+    Constant(f32),
+    /// A straight line from `start`, the value before the block, to `end`, the value of its
+    /// last sample.
+    Ramp { start: f32, end: f32 },
+    /// A value for every sample.
+    Values(&'a [f32]),
+}
+
+impl ParamBlock<'_> {
+    /// The value of sample `index` of a block of `frames`.
+    pub fn value(&self, index: usize, frames: usize) -> f32 {
+        match *self {
+            ParamBlock::Constant(value) => value,
+            ParamBlock::Ramp { start, end } => {
+                start + (end - start) * (index + 1) as f32 / frames.max(1) as f32
+            }
+            ParamBlock::Values(values) => values[index],
+        }
+    }
+
+    /// The value of the block's last sample, for consumers that only change once a block.
+    pub fn last(&self) -> f32 {
+        match *self {
+            ParamBlock::Constant(value) => value,
+            ParamBlock::Ramp { end, .. } => end,
+            ParamBlock::Values(values) => values.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// A parameter that glides to each new value instead of jumping, so changing it while sound
+/// plays doesn't click.
+///
+/// It is read a block at a time with `next_block`. Once it has reached its target it lands on
+/// it exactly and reads back as `ParamBlock::Constant` without doing any work.
+#[derive(Debug, Clone)]
+pub struct SmoothedParam {
+    smoothing: Smoothing,
+    sample_rate: f32,
+    current: f32,
+    target: f32,
+    /// Samples left in a linear ramp.
+    remaining: u32,
+    /// How far a linear ramp moves each sample.
+    step: f32,
+    /// How much of the distance to the target an exponential approach keeps each sample.
+    coefficient: f32,
+    /// Scratch space for blocks whose values have to be listed one by one.
+    values: Vec<f32>,
+}
+
+impl SmoothedParam {
+    pub fn new(value: f32, smoothing: Smoothing, sample_rate: f32) -> Self {
+        let mut param = SmoothedParam {
+            smoothing,
+            sample_rate,
+            current: value,
+            target: value,
+            remaining: 0,
+            step: 0.0,
+            coefficient: 0.0,
+            values: Vec::new(),
+        };
+        param.update_coefficient();
+        param
+    }
+
+    pub fn smoothing(&self) -> Smoothing {
+        self.smoothing
+    }
+
+    /// The value as of the last sample read.
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Whether the parameter has reached its target exactly and will stay there until it is
+    /// given a new one.
+    pub fn is_settled(&self) -> bool {
+        self.current == self.target
+    }
+
+    /// Starts moving towards `target` from wherever the parameter is now. A linear ramp
+    /// already under way starts over from its current value and takes its full time again.
+    pub fn set_target(&mut self, target: f32) {
+        if !target.is_finite() || target == self.target {
+            return;
+        }
+        self.target = target;
+        match self.smoothing {
+            Smoothing::Linear { time } => {
+                let samples = (time * self.sample_rate).round();
+                if samples >= 1.0 {
+                    self.remaining = samples as u32;
+                    self.step = (self.target - self.current) / self.remaining as f32;
+                } else {
+                    self.jump_to_target();
+                }
+            }
+            Smoothing::Exponential { .. } if self.coefficient > 0.0 => {}
+            Smoothing::None | Smoothing::Exponential { .. } => self.jump_to_target(),
+        }
+    }
+
+    /// Jumps straight to `value`, as when a preset is loaded with nothing playing.
+    pub fn set_immediate(&mut self, value: f32) {
+        if value.is_finite() {
+            self.target = value;
+            self.jump_to_target();
+        }
+    }
+
+    /// Changes how the parameter moves. A move under way carries on in the new way.
+    pub fn set_smoothing(&mut self, smoothing: Smoothing) {
+        self.smoothing = smoothing;
+        self.update_coefficient();
+        if !self.is_settled() {
+            let target = self.target;
+            self.target = self.current;
+            self.set_target(target);
+        }
+    }
+
+    /// Changes the sample rate the smoothing times are counted in. A linear ramp under way
+    /// keeps the time it has left.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        if sample_rate <= 0.0 || sample_rate == self.sample_rate {
+            return;
+        }
+        if self.remaining > 0 {
+            let remaining = (self.remaining as f32 * sample_rate / self.sample_rate).round();
+            self.remaining = remaining.max(1.0) as u32;
+            self.step = (self.target - self.current) / self.remaining as f32;
+        }
+        self.sample_rate = sample_rate;
+        self.update_coefficient();
+    }
+
+    /// The parameter's values over the next `frames` samples, moving it along.
+    pub fn next_block(&mut self, frames: usize) -> ParamBlock<'_> {
+        if self.is_settled() || frames == 0 {
+            return ParamBlock::Constant(self.current);
+        }
+        match self.smoothing {
+            Smoothing::Linear { .. } if self.remaining as usize >= frames => {
+                let start = self.current;
+                self.remaining -= frames as u32;
+                self.current = self.linear_value();
+                ParamBlock::Ramp {
+                    start,
+                    end: self.current,
+                }
+            }
+            Smoothing::Linear { .. } => {
+                self.values.clear();
+                for _ in 0..frames {
+                    if self.remaining > 0 {
+                        self.remaining -= 1;
+                        self.current = self.linear_value();
+                    }
+                    self.values.push(self.current);
+                }
+                ParamBlock::Values(&self.values)
+            }
+            Smoothing::Exponential { .. } => {
+                let threshold = EXPONENTIAL_SETTLE_THRESHOLD * self.target.abs().max(1.0);
+                self.values.clear();
+                for _ in 0..frames {
+                    if self.current != self.target {
+                        let next = self.target + (self.current - self.target) * self.coefficient;
+                        // Close to the target a step can round back onto the same value, which
+                        // would leave the approach stuck short of it.
+                        self.current =
+                            if (next - self.target).abs() <= threshold || next == self.current {
+                                self.target
+                            } else {
+                                next
+                            };
+                    }
+                    self.values.push(self.current);
+                }
+                ParamBlock::Values(&self.values)
+            }
+            Smoothing::None => {
+                self.jump_to_target();
+                ParamBlock::Constant(self.current)
+            }
+        }
+    }
+
+    /// Where a linear ramp is with `remaining` samples to go, counted back from the target so
+    /// the last step lands on it exactly.
+    fn linear_value(&self) -> f32 {
+        if self.remaining == 0 {
+            self.target
+        } else {
+            self.target - self.step * self.remaining as f32
+        }
+    }
+
+    fn jump_to_target(&mut self) {
+        self.current = self.target;
+        self.remaining = 0;
+        self.step = 0.0;
+    }
+
+    fn update_coefficient(&mut self) {
+        self.coefficient = match self.smoothing {
+            Smoothing::Exponential { time_constant } if time_constant > 0.0 => {
+                (-1.0 / (time_constant * self.sample_rate)).exp()
+            }
+            _ => 0.0,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linear(time: f32, sample_rate: f32) -> SmoothedParam {
+        SmoothedParam::new(0.0, Smoothing::Linear { time }, sample_rate)
+    }
+
+    fn exponential(time_constant: f32, sample_rate: f32) -> SmoothedParam {
+        SmoothedParam::new(0.0, Smoothing::Exponential { time_constant }, sample_rate)
+    }
+
+    /// Every value of the next `frames` samples.
+    fn read(param: &mut SmoothedParam, frames: usize) -> Vec<f32> {
+        let block = param.next_block(frames);
+        (0..frames)
+            .map(|index| block.value(index, frames))
+            .collect()
+    }
+
+    #[test]
+    fn a_linear_ramp_lands_exactly_on_its_target_on_time() {
+        for sample_rate in [44_100.0, 96_000.0] {
+            for target in [1.0, 0.3, -7.25, 1_234.5] {
+                let mut param = linear(0.01, sample_rate);
+                param.set_target(target);
+                let samples = (0.01 * sample_rate).round() as usize;
+                let values = read(&mut param, samples);
+                assert_ne!(values[samples - 2], target);
+                assert_eq!(values[samples - 1], target);
+                assert!(param.is_settled());
+                // Evenly spaced along the way.
+                let step = target / samples as f32;
+                assert!((values[0] - step).abs() <= step.abs() * 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn a_ramp_read_in_uneven_blocks_lands_on_the_same_sample() {
+        let mut param = linear(0.01, 48_000.0);
+        param.set_target(1.0);
+        let mut values = Vec::new();
+        for frames in [7, 100, 64, 300, 9, 20] {
+            values.extend(read(&mut param, frames));
+        }
+        assert_eq!(values.iter().position(|&value| value == 1.0), Some(479));
+        assert!(values.windows(2).all(|pair| pair[1] >= pair[0]));
+    }
+
+    #[test]
+    fn an_exponential_approach_covers_63_percent_in_one_time_constant() {
+        for sample_rate in [44_100.0, 96_000.0] {
+            let mut param = exponential(0.01, sample_rate);
+            param.set_target(1.0);
+            let values = read(&mut param, (0.01 * sample_rate) as usize);
+            let expected = 1.0 - (-1.0f32).exp();
+            assert!(
+                (values.last().unwrap() - expected).abs() < 1e-3,
+                "{} at {}",
+                values.last().unwrap(),
+                sample_rate
+            );
+            // Five time constants later it has settled on the target exactly.
+            read(&mut param, (0.2 * sample_rate) as usize);
+            assert!(param.is_settled());
+            assert_eq!(param.current(), 1.0);
+        }
+    }
+
+    #[test]
+    fn a_new_target_mid_ramp_carries_on_from_where_it_was() {
+        let mut param = linear(0.01, 48_000.0);
+        param.set_target(1.0);
+        let before = read(&mut param, 240);
+        let halfway = *before.last().unwrap();
+        assert!((halfway - 0.5).abs() < 1e-3);
+        param.set_target(0.0);
+        let after = read(&mut param, 480);
+        // No jump, then a full-length ramp down to the new target.
+        assert!((after[0] - halfway).abs() <= 0.5 / 480.0 + 1e-6);
+        assert_eq!(after[479], 0.0);
+        assert!(param.is_settled());
+    }
+
+    #[test]
+    fn a_new_target_mid_approach_turns_without_a_jump() {
+        let mut param = exponential(0.005, 48_000.0);
+        param.set_target(1.0);
+        let before = *read(&mut param, 100).last().unwrap();
+        param.set_target(-1.0);
+        let after = read(&mut param, 100);
+        assert!((after[0] - before).abs() < 0.02);
+        assert!(after.windows(2).all(|pair| pair[1] < pair[0]));
+    }
+
+    #[test]
+    fn a_settled_parameter_costs_nothing() {
+        let mut param = exponential(0.01, 48_000.0);
+        assert!(param.is_settled());
+        assert_eq!(param.next_block(512), ParamBlock::Constant(0.0));
+        // Nothing was listed sample by sample.
+        assert_eq!(param.values.capacity(), 0);
+
+        param.set_target(0.5);
+        read(&mut param, 48_000);
+        assert_eq!(param.next_block(512), ParamBlock::Constant(0.5));
+        // Setting the target it already has doesn't start anything.
+        param.set_target(0.5);
+        assert_eq!(param.next_block(512), ParamBlock::Constant(0.5));
+    }
+
+    #[test]
+    fn a_ramp_spanning_the_block_is_described_by_its_ends() {
+        let mut param = linear(0.1, 48_000.0);
+        param.set_target(1.0);
+        match param.next_block(480) {
+            ParamBlock::Ramp { start, end } => {
+                assert_eq!(start, 0.0);
+                assert!((end - 0.1).abs() < 1e-5);
+            }
+            block => panic!("{:?}", block),
+        }
+    }
+
+    #[test]
+    fn unsmoothed_and_instant_changes_jump() {
+        let mut param = SmoothedParam::new(0.0, Smoothing::None, 48_000.0);
+        param.set_target(3.0);
+        assert_eq!(param.next_block(64), ParamBlock::Constant(3.0));
+        let mut param = linear(0.01, 48_000.0);
+        param.set_immediate(2.0);
+        assert_eq!(param.next_block(64), ParamBlock::Constant(2.0));
+        // A target that isn't a number is ignored.
+        param.set_target(f32::NAN);
+        assert_eq!(param.target(), 2.0);
+    }
+
+    #[test]
+    fn a_new_sample_rate_keeps_the_time_a_ramp_has_left() {
+        let mut param = linear(0.01, 48_000.0);
+        param.set_target(1.0);
+        read(&mut param, 240);
+        param.set_sample_rate(96_000.0);
+        let values = read(&mut param, 480);
+        assert_eq!(values.iter().position(|&value| value == 1.0), Some(479));
+    }
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+use crate::synth::{unison::pan_gains, OscillatorWaveform};
+
+/// Two waveforms played by every voice at once, one panned towards each side, for a wide sound
+/// from a single note. Off by default.
+///
+/// While it is on, keyboard voices play these waveforms whatever the waveform keys, the split
+/// zones or a waveform sequence have selected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StereoBlendConfig {
+    pub enabled: bool,
+    /// Waveform panned to the left.
+    pub left: OscillatorWaveform,
+    /// Waveform panned to the right.
+    pub right: OscillatorWaveform,
+    /// How far each waveform is panned to its side, from 0 (both centered, mixed evenly) to 1
+    /// (hard left and right). Only heard on a stereo output.
+    pub width: f32,
+}
+
+impl Default for StereoBlendConfig {
+    fn default() -> Self {
+        StereoBlendConfig {
+            enabled: false,
+            left: OscillatorWaveform::Sawtooth,
+            right: OscillatorWaveform::Square,
+            width: 1.0,
+        }
+    }
+}
+
+impl StereoBlendConfig {
+    /// The left and right waveforms, when the blend is on.
+    pub fn waveforms(&self) -> Option<(OscillatorWaveform, OscillatorWaveform)> {
+        self.enabled.then_some((self.left, self.right))
+    }
+
+    /// Left and right gains for each waveform of a voice at `pan`, the left waveform's first.
+    ///
+    /// Each waveform is panned `width` from the voice towards its side. Both are turned down as
+    /// they move in, so a channel is no louder with the two centered than with one hard over,
+    /// and a centered blend plays each at half level, as a mono output does.
+    pub fn layer_gains(&self, pan: f32) -> [[f32; 2]; 2] {
+        let width = self.width.clamp(0.0, 1.0);
+        let level = 1.0 / (2.0 - width);
+        [pan - width, pan + width].map(|pan| pan_gains(pan).map(|gain| gain * level))
+    }
+}
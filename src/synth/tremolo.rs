@@ -1,5 +1,6 @@
+use serde::{Deserialize, Serialize};
 use std::f32::consts::PI;
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use tracing::debug;
 
@@ -7,12 +8,44 @@ const TWO_PI: f32 = 2.0 * PI;
 const TREMOLO_TABLE_SIZE: usize = 1024;
 const SCALE_FACTOR: u32 = 1000;
 
+/// `swell_start` before the first sample after the tremolo was switched on has been processed.
+const SWELL_NOT_STARTED: u64 = u64::MAX;
+
+/// How the tremolo fades in and out when it is switched on and off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TremoloConfig {
+    /// Seconds the tremolo takes to reach its full depth once switched on.
+    pub attack: f32,
+    /// Seconds the tremolo takes to die away once switched off.
+    pub release: f32,
+    /// Seconds the depth swells up over from nothing once switched on, for slow, expressive
+    /// swells. Unlike `attack` it is shared by every voice, so notes struck partway through
+    /// join the swell where it has got to. 0 is full depth at once.
+    pub depth_attack: f32,
+}
+
+impl Default for TremoloConfig {
+    fn default() -> Self {
+        TremoloConfig {
+            attack: 0.05,
+            release: 0.1,
+            depth_attack: 0.0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TremoloEffect {
     tremolo: Arc<Mutex<Tremolo>>,
     pub enabled: AtomicBool,
     rate: AtomicU32,
     depth: AtomicU32,
+    attack: f32,
+    release: f32,
+    depth_attack: f32,
+    /// Engine sample the depth swell started at, the first one processed after switching on.
+    swell_start: AtomicU64,
 }
 
 impl TremoloEffect {
@@ -22,7 +55,6 @@ impl TremoloEffect {
     }
 
     pub fn process(&mut self, sample: f32, sample_rate: f32) -> f32 {
-        debug!("Processing sample: {}", sample);
         if self.enabled.load(Ordering::Relaxed) {
             let mut tremolo = self.tremolo.lock().unwrap();
             tremolo.process(sample, sample_rate)
@@ -36,6 +68,8 @@ impl TremoloEffect {
         if enabled {
             let mut tremolo = self.tremolo.lock().unwrap();
             tremolo.reset();
+        } else {
+            self.swell_start.store(SWELL_NOT_STARTED, Ordering::Relaxed);
         }
     }
 
@@ -45,7 +79,7 @@ impl TremoloEffect {
     }
 
     pub fn set_depth(&self, depth: f32) {
-        self.rate
+        self.depth
             .store((depth * SCALE_FACTOR as f32) as u32, Ordering::Relaxed);
     }
 
@@ -56,6 +90,60 @@ impl TremoloEffect {
     pub fn get_depth(&self) -> f32 {
         self.depth.load(Ordering::Relaxed) as f32 / SCALE_FACTOR as f32
     }
+
+    /// Moves `mix`, how much of the tremolo is applied from 0 to 1, one sample towards fully on
+    /// or fully off, whichever the effect is switched to. It gets there over the attack or
+    /// release time, or at once when that is zero.
+    pub fn next_mix(&self, mix: f32, sample_rate: f32) -> f32 {
+        let (target, time) = if self.enabled.load(Ordering::Relaxed) {
+            (1.0, self.attack)
+        } else {
+            (0.0, self.release)
+        };
+        if time <= 0.0 {
+            return target;
+        }
+        let step = 1.0 / (time * sample_rate);
+        if mix < target {
+            (mix + step).min(target)
+        } else {
+            (mix - step).max(target)
+        }
+    }
+
+    /// How far the depth has swelled at engine sample `sample_index`, from 0 to 1. The swell
+    /// starts at the first sample asked about after the tremolo was switched on, and stays
+    /// where it got to once switched off, so the release fades from there.
+    pub fn depth_swell(&self, sample_index: u64, sample_rate: f32) -> f32 {
+        if self.depth_attack <= 0.0 {
+            return 1.0;
+        }
+        let start = match self.swell_start.compare_exchange(
+            SWELL_NOT_STARTED,
+            sample_index,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => sample_index,
+            Err(start) => start,
+        };
+        let elapsed = sample_index.saturating_sub(start) as f32 / sample_rate;
+        (elapsed / self.depth_attack).min(1.0)
+    }
+
+    /// The depth the tremolo applies at engine sample `sample_index` with `mix` of it faded
+    /// in: the set depth, scaled by the mix and by the swell.
+    pub fn effective_depth(&self, sample_index: u64, sample_rate: f32, mix: f32) -> f32 {
+        self.get_depth() * mix * self.depth_swell(sample_index, sample_rate)
+    }
+
+    /// The gain the tremolo puts on engine sample `sample_index`, with `mix` of its depth
+    /// applied. Every voice reads the same sample index, so they all swell together.
+    pub fn gain(&self, sample_index: u64, sample_rate: f32, mix: f32) -> f32 {
+        // Worked out in f64, since an f32 sample count loses the phase within the hour.
+        let phase = (sample_index as f64 * self.get_rate() as f64 / sample_rate as f64).fract();
+        1.0 - self.effective_depth(sample_index, sample_rate, mix) * (phase as f32 * TWO_PI).sin()
+    }
 }
 
 #[derive(Debug)]
@@ -88,7 +176,6 @@ impl Tremolo {
     }
 
     pub fn process(&self, sample: f32, _sample_rate: f32) -> f32 {
-        debug!("Processing sample: {}", sample);
         let table_index = self.table_index.load(Ordering::Relaxed);
         let amplitude = self.tremolo_table[table_index];
 
@@ -111,6 +198,9 @@ pub struct TremoloEffectBuilder {
     rate: f32,
     depth: f32,
     enabled: bool,
+    attack: f32,
+    release: f32,
+    depth_attack: f32,
 }
 
 impl Default for TremoloEffectBuilder {
@@ -120,6 +210,9 @@ impl Default for TremoloEffectBuilder {
             rate: 5.0,
             depth: 0.5,
             enabled: false,
+            attack: 0.0,
+            release: 0.0,
+            depth_attack: 0.0,
         }
     }
 }
@@ -143,6 +236,24 @@ impl TremoloEffectBuilder {
         self
     }
 
+    pub fn attack(mut self, attack: f32) -> Self {
+        debug!("Setting attack: {}", attack);
+        self.attack = attack;
+        self
+    }
+
+    pub fn release(mut self, release: f32) -> Self {
+        debug!("Setting release: {}", release);
+        self.release = release;
+        self
+    }
+
+    pub fn depth_attack(mut self, depth_attack: f32) -> Self {
+        debug!("Setting depth attack: {}", depth_attack);
+        self.depth_attack = depth_attack;
+        self
+    }
+
     pub fn build(self, sample_rate: f32) -> TremoloEffect {
         debug!("Building TremoloEffect with sample rate: {}", sample_rate);
         TremoloEffect {
@@ -150,6 +261,141 @@ impl TremoloEffectBuilder {
             enabled: AtomicBool::new(self.enabled),
             rate: AtomicU32::new((self.rate * SCALE_FACTOR as f32) as u32),
             depth: AtomicU32::new((self.depth * SCALE_FACTOR as f32) as u32),
+            attack: self.attack,
+            release: self.release,
+            depth_attack: self.depth_attack,
+            swell_start: AtomicU64::new(SWELL_NOT_STARTED),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    /// A millisecond, the span each average is taken over.
+    const WINDOW: usize = 48;
+
+    /// Average level, a millisecond at a time, of a steady signal through `effect` for
+    /// `seconds`, with the effect toggled at each of `toggles` samples.
+    fn window_levels(effect: &TremoloEffect, seconds: f32, toggles: &[u64]) -> Vec<f32> {
+        let mut mix = 0.0;
+        let gains: Vec<f32> = (0..(seconds * SAMPLE_RATE) as u64)
+            .map(|sample_index| {
+                if toggles.contains(&sample_index) {
+                    effect.toggle();
+                }
+                mix = effect.next_mix(mix, SAMPLE_RATE);
+                effect.gain(sample_index, SAMPLE_RATE, mix)
+            })
+            .collect();
+        gains
+            .chunks_exact(WINDOW)
+            .map(|window| window.iter().sum::<f32>() / WINDOW as f32)
+            .collect()
+    }
+
+    fn largest_step(levels: &[f32]) -> f32 {
+        levels
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    fn effect(attack: f32, release: f32) -> TremoloEffect {
+        TremoloEffect::builder()
+            .rate(5.0)
+            .depth(0.5)
+            .attack(attack)
+            .release(release)
+            .build(SAMPLE_RATE)
+    }
+
+    #[test]
+    fn toggling_the_tremolo_changes_the_level_gradually() {
+        // Switched on a quarter of the way through a 5 Hz cycle, where it cuts deepest, and off
+        // again a cycle later.
+        let toggles = [2_400, 12_000];
+        let faded = window_levels(&effect(0.05, 0.1), 0.5, &toggles);
+        let instant = window_levels(&effect(0.0, 0.0), 0.5, &toggles);
+
+        assert!(largest_step(&instant) > 0.3, "{}", largest_step(&instant));
+        assert!(largest_step(&faded) < 0.03, "{}", largest_step(&faded));
+        // Both end up untouched once it is off again.
+        assert_eq!(faded.last(), Some(&1.0));
+        assert_eq!(instant.last(), Some(&1.0));
+    }
+
+    #[test]
+    fn the_mix_reaches_full_over_the_attack_and_none_over_the_release() {
+        let effect = effect(0.01, 0.02);
+        effect.toggle();
+        let mut mix = 0.0;
+        let mut samples = 0;
+        while mix < 1.0 {
+            mix = effect.next_mix(mix, SAMPLE_RATE);
+            samples += 1;
+        }
+        assert!((479..=481).contains(&samples), "{}", samples);
+
+        effect.toggle();
+        samples = 0;
+        while mix > 0.0 {
+            mix = effect.next_mix(mix, SAMPLE_RATE);
+            samples += 1;
+        }
+        assert!((959..=961).contains(&samples), "{}", samples);
+    }
+
+    #[test]
+    fn the_depth_swells_from_nothing_to_full_over_the_depth_attack() {
+        let effect = TremoloEffect::builder()
+            .depth(0.5)
+            .depth_attack(1.0)
+            .build(SAMPLE_RATE);
+        effect.toggle();
+        // The swell starts at the first sample asked about, wherever the engine clock is.
+        let start = 100_000;
+        let depths: Vec<f32> = (0..=5)
+            .map(|quarter| {
+                effect.effective_depth(start + quarter * SAMPLE_RATE as u64 / 4, SAMPLE_RATE, 1.0)
+            })
+            .collect();
+        let expected = [0.0, 0.125, 0.25, 0.375, 0.5, 0.5];
+        for (depth, expected) in depths.iter().zip(expected) {
+            assert!((depth - expected).abs() < 1e-6, "{:?}", depths);
         }
+        // Every sample in between rises too, never past the set depth.
+        let mut last = 0.0;
+        for sample_index in (start..start + SAMPLE_RATE as u64).step_by(97) {
+            let depth = effect.effective_depth(sample_index, SAMPLE_RATE, 1.0);
+            assert!(depth >= last && depth <= 0.5);
+            last = depth;
+        }
+    }
+
+    #[test]
+    fn the_swell_starts_again_from_nothing_when_switched_on_again() {
+        let effect = TremoloEffect::builder()
+            .depth(0.5)
+            .depth_attack(1.0)
+            .build(SAMPLE_RATE);
+        effect.toggle();
+        effect.depth_swell(0, SAMPLE_RATE);
+        assert_eq!(effect.depth_swell(48_000, SAMPLE_RATE), 1.0);
+        effect.toggle();
+        effect.toggle();
+        assert_eq!(effect.depth_swell(96_000, SAMPLE_RATE), 0.0);
+        assert_eq!(effect.depth_swell(120_000, SAMPLE_RATE), 0.5);
+    }
+
+    #[test]
+    fn without_a_depth_attack_the_full_depth_applies_at_once() {
+        let effect = effect(0.0, 0.0);
+        effect.toggle();
+        assert_eq!(effect.effective_depth(0, SAMPLE_RATE, 1.0), 0.5);
+        // The fade-in of the mix still scales it.
+        assert_eq!(effect.effective_depth(0, SAMPLE_RATE, 0.5), 0.25);
     }
 }
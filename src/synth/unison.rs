@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+
+/// How the detune grows from the middle of a unison stack out to its edges.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetuneCurve {
+    /// Evenly spaced: each voice out is detuned by the same step more than its neighbour.
+    #[default]
+    Linear,
+    /// Bunched up in the middle: each voice out is detuned about twice as far from its
+    /// neighbour as that one is from the next voice in, so the inner voices stay close to the
+    /// pitch and the outer ones spread wide.
+    Exponential,
+}
+
+impl DetuneCurve {
+    /// Shapes a voice's `position` in the stack, from -1.0 at one edge to 1.0 at the other, into
+    /// its share of the full detune, keeping the sign. `voices` is the size of the stack.
+    pub fn shape(&self, position: f32, voices: usize) -> f32 {
+        match self {
+            DetuneCurve::Linear => position,
+            DetuneCurve::Exponential => {
+                // Voices either side of the middle, which is how many doublings the curve
+                // spans from the innermost voice to the edge.
+                let steps = ((voices as f32 - 1.0) / 2.0).max(1.0);
+                position.signum() * (2.0f32.powf(steps * position.abs()) - 1.0)
+                    / (2.0f32.powf(steps) - 1.0)
+            }
+        }
+    }
+}
+
+/// Stacks several detuned copies of every note, spread across the stereo field.
+///
+/// The voices sit evenly from one edge of the stack to the other. With an odd number of voices
+/// the middle one plays the note dry, in tune and centered, and the rest are detuned and panned
+/// further the further out they are.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UnisonConfig {
+    /// Voices each note plays. 1 is no unison.
+    pub voices: usize,
+    /// Cents between the two outermost voices.
+    pub detune: f32,
+    pub curve: DetuneCurve,
+    /// How far the outermost voices are panned, from 0 (all centered) to 1 (hard left and
+    /// right). Only heard on a stereo output.
+    pub stereo_spread: f32,
+}
+
+impl Default for UnisonConfig {
+    fn default() -> Self {
+        UnisonConfig {
+            voices: 1,
+            detune: 20.0,
+            curve: DetuneCurve::Linear,
+            stereo_spread: 0.0,
+        }
+    }
+}
+
+impl UnisonConfig {
+    /// Where each voice sits in the stack, from -1.0 to 1.0. A single voice sits at 0.0.
+    pub fn positions(&self) -> Vec<f32> {
+        let voices = self.voices.max(1);
+        if voices == 1 {
+            return vec![0.0];
+        }
+        (0..voices)
+            .map(|voice| 2.0 * voice as f32 / (voices - 1) as f32 - 1.0)
+            .collect()
+    }
+
+    /// Each voice's detune in cents, following the curve. The offsets are symmetric around
+    /// zero and the outermost are half the detune either way.
+    pub fn detune_offsets(&self) -> Vec<f32> {
+        let voices = self.voices.max(1);
+        self.positions()
+            .into_iter()
+            .map(|position| self.detune / 2.0 * self.curve.shape(position, voices))
+            .collect()
+    }
+
+    /// Each voice's pan, from -1.0 (left) to 1.0 (right).
+    pub fn pans(&self) -> Vec<f32> {
+        let spread = self.stereo_spread.clamp(0.0, 1.0);
+        self.positions()
+            .into_iter()
+            .map(|position| position * spread)
+            .collect()
+    }
+
+    /// Start phase of each voice in cycles, spread evenly so the stack doesn't start with all
+    /// its voices adding up in one loud peak.
+    pub fn phases(&self) -> Vec<f32> {
+        let voices = self.voices.max(1);
+        (0..voices)
+            .map(|voice| voice as f32 / voices as f32)
+            .collect()
+    }
+
+    /// Gain each voice is scaled by, so a stack sounds about as loud as one voice.
+    pub fn voice_gain(&self) -> f32 {
+        1.0 / (self.voices.max(1) as f32).sqrt()
+    }
+
+    /// Whether the stack needs a stereo output to be heard as configured.
+    pub fn is_stereo(&self) -> bool {
+        self.voices > 1 && self.stereo_spread > 0.0
+    }
+}
+
+/// Left and right gains for a voice at `pan`, from -1.0 (left) to 1.0 (right). A centered
+/// voice plays at full level in both channels, as it would in mono, and panning turns the
+/// other channel down.
+pub fn pan_gains(pan: f32) -> [f32; 2] {
+    let pan = pan.clamp(-1.0, 1.0);
+    [(1.0 - pan).min(1.0), (1.0 + pan).min(1.0)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn five_voices(curve: DetuneCurve) -> UnisonConfig {
+        UnisonConfig {
+            voices: 5,
+            detune: 40.0,
+            curve,
+            stereo_spread: 1.0,
+        }
+    }
+
+    #[test]
+    fn five_voice_offsets_are_symmetric_around_a_dry_middle_voice() {
+        for curve in [DetuneCurve::Linear, DetuneCurve::Exponential] {
+            let offsets = five_voices(curve).detune_offsets();
+            assert_eq!(offsets.len(), 5);
+            assert_eq!(offsets[2], 0.0);
+            for voice in 0..5 {
+                assert!(
+                    (offsets[voice] + offsets[4 - voice]).abs() < 1e-5,
+                    "{:?}",
+                    offsets
+                );
+            }
+            assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+            // The outermost voices are half the detune either way.
+            assert!((offsets[4] - 20.0).abs() < 1e-4, "{:?}", offsets);
+        }
+    }
+
+    #[test]
+    fn five_voice_offsets_follow_the_curve() {
+        // Linear steps evenly out from the middle.
+        let linear = five_voices(DetuneCurve::Linear).detune_offsets();
+        assert!((linear[3] - 10.0).abs() < 1e-4, "{:?}", linear);
+        // Exponential doubles each step out: the inner voice is a third of the way to the edge.
+        let exponential = five_voices(DetuneCurve::Exponential).detune_offsets();
+        assert!(
+            (exponential[3] - 20.0 / 3.0).abs() < 1e-4,
+            "{:?}",
+            exponential
+        );
+        assert!(exponential[3] < linear[3]);
+    }
+
+    #[test]
+    fn the_middle_voice_is_centered_and_the_outer_ones_panned() {
+        let config = five_voices(DetuneCurve::Linear);
+        assert_eq!(config.pans(), [-1.0, -0.5, 0.0, 0.5, 1.0]);
+        assert!(config.is_stereo());
+        assert_eq!(pan_gains(0.0), [1.0, 1.0]);
+        assert_eq!(pan_gains(-1.0), [1.0, 0.0]);
+        assert_eq!(pan_gains(0.5), [0.5, 1.0]);
+        let mono = UnisonConfig {
+            stereo_spread: 0.0,
+            ..config
+        };
+        assert!(mono.pans().iter().all(|&pan| pan == 0.0));
+        assert!(!mono.is_stereo());
+    }
+
+    #[test]
+    fn a_single_voice_plays_dry_at_full_level() {
+        let config = UnisonConfig::default();
+        assert_eq!(config.detune_offsets(), [0.0]);
+        assert_eq!(config.voice_gain(), 1.0);
+    }
+}
@@ -1,6 +1,9 @@
 // visiosynth/src/main.rs
 
-use crate::synth::{DownsampledAudioData, NoteState, OscillatorWaveform, Scale, TremoloEffect};
+use crate::synth::{
+    DownsampleConfig, DownsampledAudioData, NoteState, OscillatorWaveform, Scale, TremoloEffect,
+    VisualFeed, MAX_VISUAL_SAMPLES,
+};
 use anyhow::Result;
 use cpal::traits::{DeviceTrait, StreamTrait};
 use rodio;
@@ -30,8 +33,14 @@ where
     T: cpal::Sample + cpal::SizedSample + cpal::FromSample<f32>,
 {
     let sample_rate: f32 = config.sample_rate.0 as f32;
-    let downsample_factor = (sample_rate / 60.0) as usize;
-    let mut accumulated_samples = Vec::new();
+    // The clip is mono, so the feed sees it before it is copied to every channel.
+    let mut visual_feed = VisualFeed::new(
+        sample_rate,
+        1,
+        MAX_VISUAL_SAMPLES,
+        DownsampleConfig::default(),
+        downsampled_audio_data,
+    );
     let channels = config.channels as usize;
 
     // Load the MP3 file
@@ -65,29 +74,7 @@ where
                 }
             }
 
-            accumulated_samples.extend(output_samples);
-
-            if accumulated_samples.len() >= downsample_factor {
-                let mut downsampled_samples = Vec::new();
-
-                for chunk in accumulated_samples.chunks(downsample_factor) {
-                    let sum: f32 = chunk.iter().sum();
-                    let average = sum / chunk.len() as f32;
-                    downsampled_samples.push(average);
-                }
-
-                if let Ok(mut downsampled_audio_data) = downsampled_audio_data.lock() {
-                    let num_frames = downsampled_samples.len().min(256);
-                    downsampled_audio_data.samples = [[0.0; 16]; 256];
-                    for (i, chunk) in downsampled_samples.chunks(16).enumerate().take(num_frames) {
-                        for (j, &sample) in chunk.iter().enumerate() {
-                            downsampled_audio_data.samples[i][j] = sample;
-                        }
-                    }
-                }
-
-                accumulated_samples.clear();
-            }
+            visual_feed.push_samples(&output_samples);
         },
         err_fn,
         None,
@@ -0,0 +1,298 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Notes whose history is kept at most; the least recently struck are forgotten past this.
+const MAX_HISTORY_NOTES: usize = 128;
+
+/// Where a newly struck voice starts in its waveform's cycle.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartPhase {
+    /// Every voice starts at the top of the cycle, so repeated notes sound identical.
+    #[default]
+    Zero,
+    /// A random point in the cycle.
+    Random,
+    /// Where the note's previous voice was when it was struck again, or where it left off if it
+    /// had already finished, so a quick repeat carries on the same wave.
+    Continue,
+}
+
+/// Small per-strike differences between voices, so a note played over and over doesn't sound
+/// like a machine gun. Everything is off by default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VariationConfig {
+    pub start_phase: StartPhase,
+    /// Most a voice's level is moved up or down by, in dB.
+    pub amplitude_db: f32,
+    /// Most a voice's attack time is lengthened or shortened by, as a fraction of it.
+    pub attack: f32,
+    /// Seed for the random choices, so the same playing varies the same way every time. Unset
+    /// seeds from the clock.
+    pub seed: Option<u64>,
+}
+
+impl Default for VariationConfig {
+    fn default() -> Self {
+        VariationConfig {
+            start_phase: StartPhase::Zero,
+            amplitude_db: 0.0,
+            attack: 0.0,
+            seed: None,
+        }
+    }
+}
+
+/// A small, seedable random number generator (SplitMix64). Good enough for picking phases and
+/// levels; not for anything that needs real randomness.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `[0, 1)`.
+    pub fn next_unit(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A number in `[-1, 1)`.
+    pub fn next_signed(&mut self) -> f32 {
+        2.0 * self.next_unit() - 1.0
+    }
+}
+
+/// How one strike of a note differs from the last.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StrikeVariation {
+    /// Start phase in cycles, in `[0, 1)`.
+    pub phase: f32,
+    /// Factor the voice's gain is scaled by.
+    pub gain: f32,
+    /// Factor the voice's attack time is scaled by.
+    pub attack: f32,
+}
+
+impl Default for StrikeVariation {
+    fn default() -> Self {
+        StrikeVariation {
+            phase: 0.0,
+            gain: 1.0,
+            attack: 1.0,
+        }
+    }
+}
+
+/// What is remembered about a note between strikes.
+#[derive(Debug, Clone, Copy)]
+struct NoteHistory {
+    /// Where the note's last voice left off, in cycles.
+    last_phase: Option<f32>,
+    /// Seed the last strike's variation was drawn from; the next is drawn from the one after.
+    last_seed: u64,
+    /// When the note was last struck, counted in strikes, for forgetting the stalest notes.
+    last_struck: u64,
+}
+
+/// Picks each strike's variation, remembering per note what the previous strike did.
+///
+/// Every note draws from a sequence of its own, seeded from the config's seed and the note's
+/// name, so with a fixed seed a note varies the same way however other notes are played around
+/// it.
+#[derive(Debug, Clone)]
+pub struct NoteVariation {
+    config: VariationConfig,
+    seed: u64,
+    history: HashMap<String, NoteHistory>,
+    strikes: u64,
+}
+
+impl NoteVariation {
+    pub fn new(config: VariationConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+        });
+        NoteVariation {
+            config,
+            seed,
+            history: HashMap::new(),
+            strikes: 0,
+        }
+    }
+
+    pub fn config(&self) -> &VariationConfig {
+        &self.config
+    }
+
+    /// The variation for a new strike of `note`. `sounding_phase` is the phase of a voice of
+    /// the note that is still sounding, which `StartPhase::Continue` carries on from.
+    pub fn strike(&mut self, note: &str, sounding_phase: Option<f32>) -> StrikeVariation {
+        self.strikes += 1;
+        let seed = self.seed;
+        let strikes = self.strikes;
+        let history = self
+            .history
+            .entry(note.to_string())
+            .or_insert_with(|| NoteHistory {
+                last_phase: None,
+                last_seed: note_seed(seed, note),
+                last_struck: strikes,
+            });
+        history.last_struck = strikes;
+        let mut rng = SplitMix64::new(history.last_seed);
+        history.last_seed = rng.next_u64();
+        let last_phase = history.last_phase;
+
+        let mut rng = SplitMix64::new(history.last_seed);
+        let random_phase = rng.next_unit();
+        let variation = StrikeVariation {
+            phase: match self.config.start_phase {
+                StartPhase::Zero => 0.0,
+                StartPhase::Random => random_phase,
+                StartPhase::Continue => sounding_phase.or(last_phase).unwrap_or(0.0),
+            },
+            gain: 10.0f32.powf(rng.next_signed() * self.config.amplitude_db.max(0.0) / 20.0),
+            attack: (1.0 + rng.next_signed() * self.config.attack.clamp(0.0, 1.0)).max(0.0),
+        };
+        self.forget_stalest();
+        variation
+    }
+
+    /// Records where a voice of `note` left off as it is dropped, for the next strike to
+    /// continue from.
+    pub fn voice_finished(&mut self, note: &str, phase: f32) {
+        if let Some(history) = self.history.get_mut(note) {
+            history.last_phase = Some(phase);
+        }
+    }
+
+    fn forget_stalest(&mut self) {
+        while self.history.len() > MAX_HISTORY_NOTES {
+            let Some(stalest) = self
+                .history
+                .iter()
+                .min_by_key(|(_, history)| history.last_struck)
+                .map(|(note, _)| note.clone())
+            else {
+                return;
+            };
+            self.history.remove(&stalest);
+        }
+    }
+}
+
+/// The first seed of `note`'s sequence under `seed`.
+fn note_seed(seed: u64, note: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    note.hash(&mut hasher);
+    seed ^ hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seeded(start_phase: StartPhase, seed: u64) -> NoteVariation {
+        NoteVariation::new(VariationConfig {
+            start_phase,
+            amplitude_db: 3.0,
+            attack: 0.5,
+            seed: Some(seed),
+        })
+    }
+
+    #[test]
+    fn everything_is_off_by_default() {
+        let mut variation = NoteVariation::new(VariationConfig::default());
+        for _ in 0..4 {
+            assert_eq!(variation.strike("A", Some(0.3)), StrikeVariation::default());
+        }
+    }
+
+    #[test]
+    fn a_note_varies_the_same_way_however_other_notes_are_played_around_it() {
+        let mut alone = seeded(StartPhase::Random, 1);
+        let mut trill = seeded(StartPhase::Random, 1);
+        for _ in 0..5 {
+            let expected = alone.strike("A", None);
+            assert_eq!(trill.strike("A", None), expected);
+            trill.strike("B", None);
+        }
+        // And the strikes differ from each other.
+        let mut variation = seeded(StartPhase::Random, 1);
+        assert_ne!(variation.strike("A", None), variation.strike("A", None));
+    }
+
+    #[test]
+    fn the_variation_stays_within_its_ranges() {
+        let mut variation = seeded(StartPhase::Random, 3);
+        for strike in 0..1_000 {
+            let note = ["A", "B", "C"][strike % 3];
+            let StrikeVariation {
+                phase,
+                gain,
+                attack,
+            } = variation.strike(note, None);
+            assert!((0.0..1.0).contains(&phase));
+            let db = 20.0 * gain.log10();
+            assert!((-3.0..=3.0).contains(&db), "{} dB", db);
+            assert!((0.5..=1.5).contains(&attack), "{}", attack);
+        }
+    }
+
+    #[test]
+    fn continue_takes_the_sounding_phase_then_where_the_last_voice_left_off() {
+        let mut variation = seeded(StartPhase::Continue, 0);
+        assert_eq!(variation.strike("A", None).phase, 0.0);
+        assert_eq!(variation.strike("A", Some(0.4)).phase, 0.4);
+        variation.voice_finished("A", 0.7);
+        assert_eq!(variation.strike("A", None).phase, 0.7);
+        // A note never struck has nothing to record.
+        variation.voice_finished("B", 0.2);
+        assert_eq!(variation.strike("B", None).phase, 0.0);
+    }
+
+    #[test]
+    fn the_history_forgets_the_least_recently_struck_notes() {
+        let mut variation = seeded(StartPhase::Continue, 0);
+        variation.strike("first", None);
+        variation.voice_finished("first", 0.5);
+        for note in 0..MAX_HISTORY_NOTES {
+            variation.strike(&note.to_string(), None);
+        }
+        assert_eq!(variation.history.len(), MAX_HISTORY_NOTES);
+        assert_eq!(variation.strike("first", None).phase, 0.0);
+    }
+
+    #[test]
+    fn the_generator_repeats_for_a_seed_and_stays_in_range() {
+        let mut a = SplitMix64::new(42);
+        let mut b = SplitMix64::new(42);
+        for _ in 0..1_000 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        for _ in 0..1_000 {
+            assert!((0.0..1.0).contains(&a.next_unit()));
+            assert!((-1.0..1.0).contains(&a.next_signed()));
+        }
+    }
+}
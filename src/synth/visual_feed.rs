@@ -0,0 +1,719 @@
+use std::sync::{Arc, Mutex};
+
+use tracing::debug;
+
+use crate::synth::{
+    downsample_channel, rms_db, visual_downsample_factor, DownsampleConfig, DownsampledAudioData,
+    VisualFrame, VisualTap, DEFAULT_VISUAL_FPS,
+};
+
+/// Turns audio from any source into the visualizer's frames.
+///
+/// Samples pushed in are cut into visual frames of a fixed length, whatever size the pushes
+/// come in, so every frame covers the same stretch of audio. Each of a frame's first two
+/// channels is averaged down and the frame is queued on the shared `DownsampledAudioData`,
+/// which the visualizer draws one frame at a time. A push too short for a frame is kept for the
+/// next one, and a long push queues all the frames it holds. How much audio makes a frame
+/// follows the visualizer's frame rate, which is read back from the shared data after every
+/// push that completes a frame, so a window moving to another monitor retimes the feed.
+///
+/// Fed from the engine, the feed follows the visual tap: the mix from before the effects, the
+/// output, or both cut into frames side by side, the pre-effects mix as the frame's overlay.
+/// The single-cycle tap keeps frames coming at the same rate, but each one is the last whole
+/// cycle of the output before it ends, found from the lowest voice's phase.
+pub struct VisualFeed {
+    sample_rate: f32,
+    channels: usize,
+    /// The most downsampled values per channel in a frame; the most recent are kept.
+    capacity: usize,
+    config: DownsampleConfig,
+    /// Samples per channel in a frame.
+    downsample_factor: usize,
+    accumulated: Vec<f32>,
+    /// Pre-effects samples gathered alongside `accumulated` while the tap is `Both`.
+    accumulated_overlay: Vec<f32>,
+    /// The tap the gathered samples were taken at.
+    tap: VisualTap,
+    /// The lowest voice's cycle as of the end of the samples pushed, for the single-cycle tap.
+    cycle_reference: Option<CycleReference>,
+    /// Recent output the single-cycle tap takes its cycles from, interleaved. Always holds at
+    /// least the samples in `accumulated`.
+    cycle_history: Vec<f32>,
+    /// The last cycle shown, left and right, held while nothing is playing.
+    held_cycle: Option<(Vec<f32>, Vec<f32>)>,
+    shared: Arc<Mutex<DownsampledAudioData>>,
+}
+
+/// Where the lowest-pitched voice's cycle stood at the end of a block, which lines up the
+/// single-cycle display.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CycleReference {
+    /// The voice's frequency, in Hz.
+    pub frequency: f32,
+    /// Where in its cycle the voice's next sample falls, from 0.0 to 1.0.
+    pub phase: f32,
+}
+
+impl VisualFeed {
+    /// A feed for interleaved audio with `channels` channels at `sample_rate`, publishing frames
+    /// of at most `capacity` values per channel to `shared`.
+    pub fn new(
+        sample_rate: f32,
+        channels: usize,
+        capacity: usize,
+        config: DownsampleConfig,
+        shared: Arc<Mutex<DownsampledAudioData>>,
+    ) -> Self {
+        let mut feed = VisualFeed {
+            sample_rate,
+            channels: channels.max(1),
+            capacity: capacity.max(1),
+            config,
+            downsample_factor: 1,
+            accumulated: Vec::new(),
+            accumulated_overlay: Vec::new(),
+            tap: VisualTap::default(),
+            cycle_reference: None,
+            cycle_history: Vec::new(),
+            held_cycle: None,
+            shared,
+        };
+        feed.retime();
+        feed
+    }
+
+    /// Samples per channel that make up one frame.
+    pub fn downsample_factor(&self) -> usize {
+        self.downsample_factor
+    }
+
+    /// Samples waiting for the next frame.
+    pub fn pending(&self) -> usize {
+        self.accumulated.len()
+    }
+
+    /// Sets where the lowest voice's cycle stands at the end of the next samples pushed, or
+    /// `None` when no voice is playing. Only the single-cycle tap uses it.
+    pub fn set_cycle_reference(&mut self, cycle_reference: Option<CycleReference>) {
+        self.cycle_reference = cycle_reference;
+    }
+
+    /// Adds interleaved samples, publishing every whole frame they complete. Returns how many
+    /// frames were published.
+    pub fn push_samples(&mut self, samples: &[f32]) -> usize {
+        self.push_tapped(VisualTap::PostEffects, samples, samples)
+    }
+
+    /// Adds a block's interleaved samples from before and after the effects, laid out alike,
+    /// and publishes every whole frame the ones `tap` selects complete. `pre_effects` is only
+    /// read when the tap uses it. A change of tap drops whatever was gathered at the old one,
+    /// so no frame is part one tap and part another. Returns how many frames were published.
+    pub fn push_tapped(
+        &mut self,
+        tap: VisualTap,
+        pre_effects: &[f32],
+        post_effects: &[f32],
+    ) -> usize {
+        if tap != self.tap {
+            debug!("Visual tap switched to {}", tap.label());
+            self.tap = tap;
+            self.accumulated.clear();
+            self.accumulated_overlay.clear();
+            self.cycle_history.clear();
+        }
+        match tap {
+            VisualTap::PreEffects => self.accumulated.extend_from_slice(pre_effects),
+            VisualTap::PostEffects => self.accumulated.extend_from_slice(post_effects),
+            VisualTap::Both => {
+                self.accumulated.extend_from_slice(post_effects);
+                self.accumulated_overlay.extend_from_slice(pre_effects);
+            }
+            VisualTap::SingleCycle => {
+                self.accumulated.extend_from_slice(post_effects);
+                self.cycle_history.extend_from_slice(post_effects);
+            }
+        }
+
+        // Frames hold whole audio frames, so every one starts on the first channel.
+        let frame_len = self.frame_len();
+        let frames: Vec<_> = if tap == VisualTap::SingleCycle {
+            let pending_start = self.cycle_history.len() - self.accumulated.len();
+            (1..=self.accumulated.len() / frame_len)
+                .map(|frame| {
+                    let level = rms_db(&self.accumulated[(frame - 1) * frame_len..][..frame_len]);
+                    VisualFrame {
+                        rms_db: level,
+                        ..self.cycle_frame((pending_start + frame * frame_len) / self.channels)
+                    }
+                })
+                .collect()
+        } else {
+            self.accumulated
+                .chunks_exact(frame_len)
+                .enumerate()
+                .map(|(index, frame)| {
+                    let overlay = self
+                        .accumulated_overlay
+                        .get(index * frame_len..(index + 1) * frame_len);
+                    self.frame(frame, overlay)
+                })
+                .collect()
+        };
+        if frames.is_empty() {
+            return 0;
+        }
+        let published = frames.len();
+        self.accumulated.drain(..published * frame_len);
+        let overlay_published = (published * frame_len).min(self.accumulated_overlay.len());
+        self.accumulated_overlay.drain(..overlay_published);
+        if tap == VisualTap::SingleCycle {
+            self.trim_cycle_history();
+        }
+        self.publish(frames);
+        published
+    }
+
+    /// The single-cycle frame ending `end` audio frames into the history: the last whole cycle
+    /// before it, or the last one shown when there is none, dimmed if nothing is playing.
+    fn cycle_frame(&mut self, end: usize) -> VisualFrame {
+        let cycle = self
+            .cycle_reference
+            .and_then(|cycle_reference| self.cycle_before(cycle_reference, end));
+        if let Some(cycle) = &cycle {
+            self.held_cycle = Some(cycle.clone());
+        }
+        let (samples, right_samples) = cycle
+            .or_else(|| self.held_cycle.clone())
+            .unwrap_or_else(|| (vec![0.0; self.capacity], vec![0.0; self.capacity]));
+        VisualFrame {
+            samples,
+            right_samples,
+            dimmed: self.cycle_reference.is_none(),
+            ..VisualFrame::default()
+        }
+    }
+
+    /// The last whole cycle of the history that ends by `end` audio frames in, stretched over
+    /// `capacity` values per channel from one phase zero to the next. `None` when the history
+    /// doesn't reach back a whole cycle.
+    fn cycle_before(
+        &self,
+        cycle_reference: CycleReference,
+        end: usize,
+    ) -> Option<(Vec<f32>, Vec<f32>)> {
+        let history_frames = self.cycle_history.len() / self.channels;
+        let period = self.sample_rate / cycle_reference.frequency;
+        if !period.is_finite() || period < 1.0 || end > history_frames {
+            return None;
+        }
+        // The phase runs back from the reference at the end of the history, a cycle a period.
+        let phase_at_end =
+            (cycle_reference.phase - (history_frames - end) as f32 / period).rem_euclid(1.0);
+        let cycle_end = end as f32 - phase_at_end * period;
+        let cycle_start = cycle_end - period;
+        if cycle_start < 0.0 {
+            return None;
+        }
+        let last = (history_frames - 1) as f32;
+        let steps = (self.capacity - 1).max(1) as f32;
+        let [samples, right_samples] = [0, 1].map(|channel| {
+            (0..self.capacity)
+                .map(|point| {
+                    let mut position = cycle_start + period * point as f32 / steps;
+                    // The cycle's last point can land past the samples played so far; the
+                    // point a cycle earlier stands in for it.
+                    if position > last {
+                        position -= period;
+                    }
+                    self.history_sample(channel, position)
+                })
+                .collect()
+        });
+        Some((samples, right_samples))
+    }
+
+    /// `channel` of the history at a fractional audio frame `position`, interpolated linearly.
+    /// Channels past the last repeat it.
+    fn history_sample(&self, channel: usize, position: f32) -> f32 {
+        let channel = channel.min(self.channels - 1);
+        let last = self.cycle_history.len() / self.channels - 1;
+        let index = (position.max(0.0) as usize).min(last);
+        let fraction = position - index as f32;
+        let sample = |index: usize| self.cycle_history[index * self.channels + channel];
+        let next = sample((index + 1).min(last));
+        sample(index) + fraction * (next - sample(index))
+    }
+
+    /// Drops history the next single-cycle frames can't reach: anything more than two periods
+    /// of the lowest voice back, keeping at least the samples still waiting for a frame.
+    fn trim_cycle_history(&mut self) {
+        let cycle_frames = self.cycle_reference.map_or(0, |cycle_reference| {
+            let period = self.sample_rate / cycle_reference.frequency;
+            if period.is_finite() {
+                (2.0 * period).ceil() as usize + 2
+            } else {
+                0
+            }
+        });
+        let keep = (cycle_frames * self.channels).max(self.accumulated.len());
+        let excess = self.cycle_history.len().saturating_sub(keep);
+        self.cycle_history.drain(..excess);
+    }
+
+    /// Publishes whatever has been gathered as a frame, even if it's short of a full one, as
+    /// when a source ends. Does nothing when nothing is waiting.
+    pub fn flush(&mut self) {
+        if self.accumulated.is_empty() {
+            return;
+        }
+        let overlay =
+            (!self.accumulated_overlay.is_empty()).then_some(&self.accumulated_overlay[..]);
+        let frame = self.frame(&self.accumulated, overlay);
+        self.accumulated.clear();
+        self.accumulated_overlay.clear();
+        self.publish(vec![frame]);
+    }
+
+    /// Interleaved samples in a frame, across all channels, so a frame lasts as long whatever
+    /// the channel count.
+    fn frame_len(&self) -> usize {
+        self.downsample_factor * self.channels
+    }
+
+    /// Averages the first two channels of `interleaved` down to a frame's left and right
+    /// values. A mono source shows the same channel on both sides of a split display.
+    fn downsample(&self, interleaved: &[f32]) -> (Vec<f32>, Vec<f32>) {
+        let channel_factor = (self.downsample_factor / self.channels).max(1);
+        let [left_samples, right_samples] = [0, 1].map(|channel| {
+            downsample_channel(
+                interleaved,
+                self.channels,
+                channel,
+                channel_factor,
+                self.capacity,
+                &self.config,
+            )
+        });
+        (left_samples, right_samples)
+    }
+
+    /// A frame of `interleaved`, with `overlay` downsampled the same way alongside it.
+    fn frame(&self, interleaved: &[f32], overlay: Option<&[f32]>) -> VisualFrame {
+        let (samples, right_samples) = self.downsample(interleaved);
+        VisualFrame {
+            samples,
+            right_samples,
+            overlay: overlay.map(|overlay| self.downsample(overlay)),
+            dimmed: false,
+            rms_db: rms_db(interleaved),
+        }
+    }
+
+    fn publish(&mut self, frames: Vec<VisualFrame>) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.publish(frames);
+            self.downsample_factor = visual_downsample_factor(self.sample_rate, shared.visual_fps);
+        }
+    }
+
+    /// Switches to audio at `sample_rate`, dropping anything gathered at the old rate.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.accumulated.clear();
+        self.accumulated_overlay.clear();
+        self.cycle_history.clear();
+        self.retime();
+    }
+
+    /// Recomputes the frame size from the visualizer's current frame rate.
+    fn retime(&mut self) {
+        let visual_fps = self
+            .shared
+            .lock()
+            .map(|shared| shared.visual_fps)
+            .unwrap_or(DEFAULT_VISUAL_FPS);
+        self.downsample_factor = visual_downsample_factor(self.sample_rate, visual_fps);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+    use std::sync::RwLock;
+
+    use super::*;
+    use crate::synth::{
+        AudioBuffer, AudioNode, DcBlockerConfig, KeyboardSplitConfig, NoteId, OscillatorWaveform,
+        SynthEngine, WaveShaperNode, ZonePreset, MAX_VISUAL_SAMPLES,
+    };
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    fn new_feed(channels: usize) -> (VisualFeed, Arc<Mutex<DownsampledAudioData>>) {
+        let shared = Arc::new(Mutex::new(DownsampledAudioData::new(60.0)));
+        let feed = VisualFeed::new(
+            SAMPLE_RATE,
+            channels,
+            MAX_VISUAL_SAMPLES,
+            DownsampleConfig::default(),
+            Arc::clone(&shared),
+        );
+        (feed, shared)
+    }
+
+    fn published(shared: &Arc<Mutex<DownsampledAudioData>>) -> u64 {
+        shared.lock().unwrap().published_frames()
+    }
+
+    #[test]
+    fn a_second_of_audio_makes_a_frame_per_visual_frame_whatever_the_push_size() {
+        for push in [1, 37, 512, 800, 4_096] {
+            let (mut feed, shared) = new_feed(2);
+            assert_eq!(feed.downsample_factor(), 800);
+            let audio = vec![0.1; 2 * 48_000];
+            let frames: usize = audio
+                .chunks(2 * push)
+                .map(|samples| feed.push_samples(samples))
+                .sum();
+            assert_eq!(frames, 60, "pushes of {}", push);
+            assert_eq!(published(&shared), 60);
+            assert_eq!(feed.pending(), 0);
+        }
+    }
+
+    #[test]
+    fn a_frame_is_published_exactly_at_its_boundary() {
+        let (mut feed, shared) = new_feed(1);
+        assert_eq!(feed.push_samples(&[0.0; 799]), 0);
+        assert_eq!(feed.pending(), 799);
+        assert_eq!(published(&shared), 0);
+        assert_eq!(feed.push_samples(&[0.0]), 1);
+        assert_eq!(feed.pending(), 0);
+        // A push spanning two boundaries publishes both and keeps the rest.
+        assert_eq!(feed.push_samples(&[0.0; 1_700]), 2);
+        assert_eq!(feed.pending(), 100);
+        assert_eq!(published(&shared), 3);
+    }
+
+    #[test]
+    fn flushing_publishes_a_short_frame_and_nothing_when_empty() {
+        let (mut feed, shared) = new_feed(1);
+        feed.flush();
+        assert_eq!(published(&shared), 0);
+
+        feed.push_samples(&[0.5; 300]);
+        feed.flush();
+        assert_eq!(feed.pending(), 0);
+        let mut shared = shared.lock().unwrap();
+        assert_eq!(shared.published_frames(), 1);
+        assert!(shared.next_frame());
+        assert!(!shared.samples.is_empty());
+        assert!(shared.samples.iter().all(|&value| value == 0.5));
+    }
+
+    #[test]
+    fn a_new_sample_rate_retimes_the_frames_and_drops_what_was_gathered() {
+        let (mut feed, shared) = new_feed(1);
+        feed.push_samples(&[0.0; 500]);
+        feed.set_sample_rate(96_000.0);
+        assert_eq!(feed.pending(), 0);
+        assert_eq!(feed.downsample_factor(), 1_600);
+        assert_eq!(feed.push_samples(&[0.0; 1_599]), 0);
+        assert_eq!(feed.push_samples(&[0.0]), 1);
+        assert_eq!(published(&shared), 1);
+    }
+
+    #[test]
+    fn a_new_visual_rate_takes_effect_after_the_next_frame() {
+        let (mut feed, shared) = new_feed(1);
+        shared.lock().unwrap().visual_fps = 120.0;
+        // The frame in progress keeps the size it started with.
+        assert_eq!(feed.push_samples(&[0.0; 800]), 1);
+        assert_eq!(feed.downsample_factor(), 400);
+        assert_eq!(feed.push_samples(&[0.0; 400]), 1);
+    }
+
+    #[test]
+    fn each_side_of_a_frame_comes_from_its_own_channel() {
+        let (mut feed, shared) = new_feed(2);
+        let stereo: Vec<f32> = (0..800).flat_map(|_| [0.5, -0.25]).collect();
+        assert_eq!(feed.push_samples(&stereo), 1);
+        let mut frame = shared.lock().unwrap();
+        assert!(frame.next_frame());
+        assert!(frame.samples.iter().all(|&value| value == 0.5));
+        assert!(frame.right_samples.iter().all(|&value| value == -0.25));
+        drop(frame);
+
+        // A mono source shows on both sides.
+        let (mut feed, shared) = new_feed(1);
+        feed.push_samples(&[0.75; 800]);
+        let mut frame = shared.lock().unwrap();
+        assert!(frame.next_frame());
+        assert_eq!(frame.samples, frame.right_samples);
+        assert!(frame.samples.iter().all(|&value| value == 0.75));
+    }
+
+    #[test]
+    fn callbacks_of_varying_sizes_keep_one_frame_per_redraw() {
+        let (mut feed, shared) = new_feed(2);
+        // Callback sizes in frames, as a device might change them while running: tiny, huge
+        // and uneven.
+        let sizes = [17, 4_096, 64, 1_003, 16_384, 128, 800, 5, 2_400, 333];
+        let mut pushed = 0;
+        let mut drawn = 0;
+        let mut redraws = 0;
+        for &size in sizes.iter().cycle().take(100) {
+            feed.push_samples(&vec![0.1; 2 * size]);
+            pushed += size;
+            // Every frame of audio so far is published, however it was cut up.
+            assert_eq!(published(&shared), (pushed / 800) as u64);
+            // The renderer draws one a redraw, at the visual rate, until it catches up with
+            // the audio.
+            while redraws < pushed / 800 {
+                redraws += 1;
+                drawn += shared.lock().unwrap().next_frame() as usize;
+            }
+        }
+        // A renderer keeping up never goes a redraw without a new frame.
+        assert_eq!(drawn, redraws);
+        assert_eq!(shared.lock().unwrap().queued_frames(), 0);
+    }
+
+    /// A mono frame's worth of a level of 1.95, and the same through a hard clip at 1.0, so
+    /// the two taps are told apart by their level however the frame is averaged down.
+    fn clipped_frame() -> (Vec<f32>, Vec<f32>) {
+        let pre_effects = AudioBuffer {
+            data: vec![1.95; 800],
+            num_channels: 1,
+        };
+        let mut post_effects = pre_effects.clone();
+        WaveShaperNode::new(|x: f32| x.clamp(-1.0, 1.0)).process(&pre_effects, &mut post_effects);
+        (pre_effects.data, post_effects.data)
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples
+            .iter()
+            .fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    /// The frame `tap` publishes from a clipped frame.
+    fn tapped(tap: VisualTap) -> VisualFrame {
+        let (mut feed, shared) = new_feed(1);
+        let (pre_effects, post_effects) = clipped_frame();
+        assert_eq!(feed.push_tapped(tap, &pre_effects, &post_effects), 1);
+        let mut shared = shared.lock().unwrap();
+        assert!(shared.next_frame());
+        VisualFrame {
+            samples: shared.samples.clone(),
+            right_samples: shared.right_samples.clone(),
+            overlay: shared.overlay.clone(),
+            dimmed: shared.dimmed,
+            rms_db: shared.rms_db,
+        }
+    }
+
+    #[test]
+    fn each_tap_publishes_the_streams_it_names() {
+        let pre = tapped(VisualTap::PreEffects);
+        assert!(
+            (peak(&pre.samples) - 1.95).abs() < 1e-4,
+            "{}",
+            peak(&pre.samples)
+        );
+        assert_eq!(pre.overlay, None);
+
+        let post = tapped(VisualTap::PostEffects);
+        assert!(
+            (peak(&post.samples) - 1.0).abs() < 1e-6,
+            "{}",
+            peak(&post.samples)
+        );
+        assert_eq!(post.overlay, None);
+
+        let both = tapped(VisualTap::Both);
+        assert_eq!(both.samples, post.samples);
+        let (overlay, overlay_right) = both.overlay.unwrap();
+        assert_eq!(overlay, pre.samples);
+        assert_eq!(overlay_right, pre.right_samples);
+    }
+
+    #[test]
+    fn switching_taps_drops_what_was_gathered_at_the_old_one() {
+        let (mut feed, shared) = new_feed(1);
+        let (pre_effects, post_effects) = clipped_frame();
+        // Half a frame before the tap, and half a frame after, make no frame.
+        feed.push_tapped(
+            VisualTap::PreEffects,
+            &pre_effects[..400],
+            &post_effects[..400],
+        );
+        feed.push_tapped(
+            VisualTap::PostEffects,
+            &pre_effects[400..],
+            &post_effects[400..],
+        );
+        assert_eq!(published(&shared), 0);
+        // The frame that completes is all post-effects.
+        feed.push_tapped(
+            VisualTap::PostEffects,
+            &pre_effects[..400],
+            &post_effects[..400],
+        );
+        let mut shared = shared.lock().unwrap();
+        assert!(shared.next_frame());
+        assert!(peak(&shared.samples) <= 1.0);
+    }
+
+    #[test]
+    fn each_frame_carries_the_level_of_the_audio_it_was_cut_from() {
+        let (mut feed, shared) = new_feed(1);
+        let sine: Vec<f32> = (0..800)
+            .map(|i| (TAU * 480.0 * i as f32 / SAMPLE_RATE).sin())
+            .collect();
+        feed.push_tapped(VisualTap::PostEffects, &sine, &sine);
+        feed.push_tapped(VisualTap::PostEffects, &[0.0; 800], &[0.0; 800]);
+        // Whole periods of a full scale sine sit 3 dB down, and silence has no level at all.
+        let level = next_published(&shared).rms_db;
+        assert!((level + 3.0103).abs() < 0.01, "{}", level);
+        assert_eq!(next_published(&shared).rms_db, f32::NEG_INFINITY);
+    }
+
+    /// The next frame published to `shared`.
+    fn next_published(shared: &Arc<Mutex<DownsampledAudioData>>) -> VisualFrame {
+        let mut shared = shared.lock().unwrap();
+        assert!(shared.next_frame());
+        VisualFrame {
+            samples: shared.samples.clone(),
+            right_samples: shared.right_samples.clone(),
+            overlay: shared.overlay.clone(),
+            dimmed: shared.dimmed,
+            rms_db: shared.rms_db,
+        }
+    }
+
+    /// Largest difference between `samples`, scaled to a peak of 1, and one sine period
+    /// starting at phase zero stretched over as many points.
+    fn sine_period_error(samples: &[f32]) -> f32 {
+        let peak = peak(samples);
+        let steps = (samples.len() - 1) as f32;
+        samples
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| (sample / peak - (TAU * i as f32 / steps).sin()).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn a_sine_fed_in_blocks_of_any_size_shows_as_one_period_from_phase_zero() {
+        for (channels, block) in [(1, 64), (1, 1_024), (2, 64), (2, 1_024), (2, 37)] {
+            let (mut feed, shared) = new_feed(channels);
+            let mut phase = 0.25;
+            for _ in 0..(48_000 / 10 / block) {
+                let samples: Vec<f32> = (0..block)
+                    .flat_map(|_| {
+                        let sample = 0.8 * (TAU * phase).sin();
+                        phase = (phase + 440.0 / SAMPLE_RATE).fract();
+                        std::iter::repeat_n(sample, channels)
+                    })
+                    .collect();
+                feed.set_cycle_reference(Some(CycleReference {
+                    frequency: 440.0,
+                    phase,
+                }));
+                feed.push_tapped(VisualTap::SingleCycle, &samples, &samples);
+            }
+            assert!(published(&shared) >= 5, "{} channels", channels);
+            while shared.lock().unwrap().queued_frames() > 0 {
+                let frame = next_published(&shared);
+                assert!(!frame.dimmed);
+                assert_eq!(frame.samples.len(), MAX_VISUAL_SAMPLES);
+                assert!((peak(&frame.samples) - 0.8).abs() < 1e-3);
+                let error = sine_period_error(&frame.samples);
+                assert!(
+                    error < 5e-4,
+                    "{} channels, blocks of {}: {}",
+                    channels,
+                    block,
+                    error
+                );
+                assert_eq!(frame.right_samples, frame.samples);
+            }
+        }
+    }
+
+    #[test]
+    fn a_held_440_hz_note_shows_one_period_whatever_the_engine_buffer_size() {
+        for block in [64, 1_024] {
+            let mut engine = SynthEngine::builder()
+                .waveform_type(Arc::new(RwLock::new(OscillatorWaveform::Sine)))
+                // The blocker leads a 440 Hz sine by a few degrees, which is the sound moving,
+                // not the display.
+                .dc_blocker_config(DcBlockerConfig {
+                    enabled: false,
+                    ..DcBlockerConfig::default()
+                })
+                // A level that holds steady, so each cycle is the same shape.
+                .keyboard_split(KeyboardSplitConfig {
+                    lead: ZonePreset {
+                        attack: 0.001,
+                        decay: 0.001,
+                        sustain: 1.0,
+                        release: 100.0,
+                        ..ZonePreset::default()
+                    },
+                    ..KeyboardSplitConfig::default()
+                })
+                .build(SAMPLE_RATE);
+            {
+                let mut note_state = engine.note_state().lock().unwrap();
+                note_state.visual_tap = VisualTap::SingleCycle;
+                note_state.start_note(NoteId::shared("A".to_string()), None);
+            }
+            let (mut feed, shared) = new_feed(engine.num_channels());
+            // A few frames' worth.
+            for _ in 0..(48_000 / 5 / block) {
+                let output = engine.render(block);
+                feed.set_cycle_reference(engine.cycle_reference());
+                feed.push_tapped(engine.visual_tap(), &[], &output.interleaved());
+            }
+            let reference = engine.cycle_reference().expect("the note is playing");
+            assert!(
+                (reference.frequency - 440.0).abs() < 0.1,
+                "{}",
+                reference.frequency
+            );
+
+            let mut frame = next_published(&shared);
+            while shared.lock().unwrap().queued_frames() > 0 {
+                frame = next_published(&shared);
+            }
+            // Stretching 109 samples a cycle linearly is good to about 4e-4; the wavetable's
+            // own error comes on top.
+            let error = sine_period_error(&frame.samples);
+            assert!(error < 1e-3, "blocks of {}: {}", block, error);
+        }
+    }
+
+    #[test]
+    fn with_nothing_playing_the_last_cycle_is_held_dimmed() {
+        let (mut feed, shared) = new_feed(1);
+        let sine: Vec<f32> = (0..1_600)
+            .map(|i| (TAU * 480.0 * i as f32 / SAMPLE_RATE).sin())
+            .collect();
+        feed.set_cycle_reference(Some(CycleReference {
+            frequency: 440.0,
+            phase: (440.0 * 1_600.0 / SAMPLE_RATE).fract(),
+        }));
+        feed.push_tapped(VisualTap::SingleCycle, &sine, &sine);
+        next_published(&shared);
+        let playing = next_published(&shared);
+        assert!(!playing.dimmed);
+
+        feed.set_cycle_reference(None);
+        feed.push_tapped(VisualTap::SingleCycle, &[0.0; 800], &[0.0; 800]);
+        let held = next_published(&shared);
+        assert!(held.dimmed);
+        assert_eq!(held.samples, playing.samples);
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+/// Where in the signal chain the visualizer's audio is taken from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VisualTap {
+    /// The voices' mix before the wave shaper and the mute.
+    PreEffects,
+    /// The output, as it is heard.
+    #[default]
+    PostEffects,
+    /// The output, with the pre-effects mix drawn over it in the theme's overlay color.
+    Both,
+    /// One cycle of the output, from where the lowest-pitched voice's cycle starts, stretched
+    /// across the display so its shape holds still whatever the pitch.
+    SingleCycle,
+}
+
+impl VisualTap {
+    /// The tap after this one, for cycling through them with a key.
+    pub fn next(self) -> Self {
+        match self {
+            VisualTap::PreEffects => VisualTap::PostEffects,
+            VisualTap::PostEffects => VisualTap::Both,
+            VisualTap::Both => VisualTap::SingleCycle,
+            VisualTap::SingleCycle => VisualTap::PreEffects,
+        }
+    }
+
+    /// Whether the engine has to keep a copy of the mix from before the effects.
+    pub fn uses_pre_effects(self) -> bool {
+        matches!(self, VisualTap::PreEffects | VisualTap::Both)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            VisualTap::PreEffects => "pre-effects",
+            VisualTap::PostEffects => "post-effects",
+            VisualTap::Both => "pre- and post-effects",
+            VisualTap::SingleCycle => "lowest note's single-cycle",
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+
+/// Which voice gives way when a new note is played with every voice in use.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StealPolicy {
+    /// Nothing is stolen; the new note waits until a voice frees up.
+    #[default]
+    Off,
+    /// The voice that was started first.
+    Oldest,
+    /// The voice whose envelope is quietest right now.
+    Quietest,
+    /// A voice that is already fading out, the quietest of them; when every voice is held, the
+    /// fallback policy picks among them.
+    ReleasedFirst,
+    /// The lowest-pitched voice, to keep a melody over everything else.
+    Lowest,
+    /// The highest-pitched voice, to keep a bass note under everything else.
+    Highest,
+}
+
+/// How many voices may sound at once and which gives way to a new note past that.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VoiceStealingConfig {
+    /// Most notes sounding at once, counting each unison stack once. Unset plays any number.
+    pub max_voices: Option<usize>,
+    pub steal_policy: StealPolicy,
+    /// Policy among held voices when `released_first` finds none fading out.
+    pub fallback: StealPolicy,
+}
+
+impl Default for VoiceStealingConfig {
+    fn default() -> Self {
+        VoiceStealingConfig {
+            max_voices: None,
+            steal_policy: StealPolicy::Off,
+            fallback: StealPolicy::Oldest,
+        }
+    }
+}
+
+impl StealPolicy {
+    /// The policy among held voices when `ReleasedFirst` finds none fading out. Policies that
+    /// don't pick a voice on their own fall back to `Oldest`.
+    pub fn among_held(self) -> Self {
+        match self {
+            StealPolicy::Off | StealPolicy::ReleasedFirst => StealPolicy::Oldest,
+            policy => policy,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            StealPolicy::Off => "off",
+            StealPolicy::Oldest => "oldest",
+            StealPolicy::Quietest => "quietest",
+            StealPolicy::ReleasedFirst => "released first",
+            StealPolicy::Lowest => "lowest",
+            StealPolicy::Highest => "highest",
+        }
+    }
+}
+
+/// What the allocator knows about a voice that could be stolen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceCandidate {
+    /// The envelope's level right now.
+    pub amplitude: f32,
+    pub age_samples: u64,
+    /// Whether the voice's note has been let go and it is fading out.
+    pub released: bool,
+    pub frequency: f32,
+}
+
+/// The index of the voice in `candidates` that `policy` steals, with `fallback` choosing among
+/// held voices for `ReleasedFirst`. `None` when the policy is `Off` or there are no candidates.
+///
+/// Ties go to the oldest voice, then to the one earliest in `candidates`.
+pub fn select_victim(
+    policy: StealPolicy,
+    fallback: StealPolicy,
+    candidates: &[VoiceCandidate],
+) -> Option<usize> {
+    match policy {
+        StealPolicy::Off => None,
+        StealPolicy::ReleasedFirst => {
+            let released = pick(candidates, |candidate| candidate.released, quieter);
+            released.or_else(|| {
+                let fallback = fallback.among_held();
+                select_victim(fallback, fallback, candidates)
+            })
+        }
+        StealPolicy::Oldest => pick(candidates, |_| true, |_, _| false),
+        StealPolicy::Quietest => pick(candidates, |_| true, quieter),
+        StealPolicy::Lowest => pick(candidates, |_| true, |a, b| a.frequency < b.frequency),
+        StealPolicy::Highest => pick(candidates, |_| true, |a, b| a.frequency > b.frequency),
+    }
+}
+
+fn quieter(a: &VoiceCandidate, b: &VoiceCandidate) -> bool {
+    a.amplitude < b.amplitude
+}
+
+/// The first of the `eligible` candidates that no other is `better` than, breaking ties by age.
+fn pick(
+    candidates: &[VoiceCandidate],
+    eligible: impl Fn(&VoiceCandidate) -> bool,
+    better: impl Fn(&VoiceCandidate, &VoiceCandidate) -> bool,
+) -> Option<usize> {
+    let mut victim: Option<usize> = None;
+    for (index, candidate) in candidates.iter().enumerate() {
+        if !eligible(candidate) {
+            continue;
+        }
+        let replaces = match victim {
+            None => true,
+            Some(current) => {
+                let current = &candidates[current];
+                better(candidate, current)
+                    || (!better(current, candidate) && candidate.age_samples > current.age_samples)
+            }
+        };
+        if replaces {
+            victim = Some(index);
+        }
+    }
+    victim
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voice(amplitude: f32, age_samples: u64, released: bool, frequency: f32) -> VoiceCandidate {
+        VoiceCandidate {
+            amplitude,
+            age_samples,
+            released,
+            frequency,
+        }
+    }
+
+    /// Four voices that each policy tells apart: a loud old bass, a quiet young melody note, a
+    /// middling release and a loud newest note.
+    fn population() -> Vec<VoiceCandidate> {
+        vec![
+            voice(0.9, 48_000, false, 110.0),
+            voice(0.1, 1_000, false, 880.0),
+            voice(0.4, 24_000, true, 440.0),
+            voice(0.8, 10, false, 220.0),
+        ]
+    }
+
+    #[test]
+    fn each_policy_steals_the_voice_it_names() {
+        let voices = population();
+        let steal = |policy| select_victim(policy, StealPolicy::Oldest, &voices);
+        assert_eq!(steal(StealPolicy::Off), None);
+        assert_eq!(steal(StealPolicy::Oldest), Some(0));
+        assert_eq!(steal(StealPolicy::Quietest), Some(1));
+        assert_eq!(steal(StealPolicy::ReleasedFirst), Some(2));
+        assert_eq!(steal(StealPolicy::Lowest), Some(0));
+        assert_eq!(steal(StealPolicy::Highest), Some(1));
+    }
+
+    #[test]
+    fn released_first_takes_the_quietest_release() {
+        let voices = vec![
+            voice(0.5, 100, true, 440.0),
+            voice(0.05, 50, false, 440.0),
+            voice(0.2, 10, true, 440.0),
+        ];
+        assert_eq!(
+            select_victim(StealPolicy::ReleasedFirst, StealPolicy::Oldest, &voices),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn released_first_falls_back_among_held_voices() {
+        let held: Vec<_> = population()
+            .into_iter()
+            .map(|candidate| VoiceCandidate {
+                released: false,
+                ..candidate
+            })
+            .collect();
+        let steal = |fallback| select_victim(StealPolicy::ReleasedFirst, fallback, &held);
+        assert_eq!(steal(StealPolicy::Oldest), Some(0));
+        assert_eq!(steal(StealPolicy::Quietest), Some(1));
+        assert_eq!(steal(StealPolicy::Highest), Some(1));
+        assert_eq!(steal(StealPolicy::Lowest), Some(0));
+        // Fallbacks that pick nothing on their own steal the oldest.
+        assert_eq!(steal(StealPolicy::Off), Some(0));
+        assert_eq!(steal(StealPolicy::ReleasedFirst), Some(0));
+    }
+
+    #[test]
+    fn ties_go_to_the_oldest_then_the_earliest_listed() {
+        let voices = vec![
+            voice(0.5, 100, false, 440.0),
+            voice(0.5, 300, false, 440.0),
+            voice(0.5, 300, false, 440.0),
+            voice(0.5, 200, false, 440.0),
+        ];
+        for policy in [
+            StealPolicy::Oldest,
+            StealPolicy::Quietest,
+            StealPolicy::ReleasedFirst,
+            StealPolicy::Lowest,
+            StealPolicy::Highest,
+        ] {
+            assert_eq!(
+                select_victim(policy, StealPolicy::Quietest, &voices),
+                Some(1),
+                "{}",
+                policy.label()
+            );
+        }
+    }
+
+    #[test]
+    fn there_is_nothing_to_steal_from_no_voices() {
+        assert_eq!(
+            select_victim(StealPolicy::Oldest, StealPolicy::Oldest, &[]),
+            None
+        );
+        assert_eq!(
+            select_victim(StealPolicy::ReleasedFirst, StealPolicy::Quietest, &[]),
+            None
+        );
+    }
+}
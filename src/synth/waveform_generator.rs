@@ -1,35 +1,52 @@
+use crate::synth::log_limit::{log_rate_limited, HOT_PATH_LOG_INTERVAL};
 use crate::synth::OscillatorWaveform;
+use anyhow::{bail, Result};
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use std::f32::consts::PI;
+use std::sync::{Arc, PoisonError, RwLock};
+use tracing::info;
 
 pub const TWO_PI: f32 = 2.0 * PI;
 pub const WAVETABLE_SIZE: usize = 1024;
+/// Default lowest frequency a generator will play. Anything below, including zero and negative
+/// frequencies, is raised to this rather than stalling the phase into DC.
+pub const MIN_FREQUENCY: f32 = 8.0;
+/// Default highest frequency a generator will play, when the sample rate allows it.
+pub const MAX_FREQUENCY: f32 = 20_000.0;
+/// Highest frequency a generator will play by default, as a fraction of the sample rate, to keep
+/// clear of aliasing near Nyquist.
+pub const MAX_FREQUENCY_RATIO: f32 = 0.45;
+
+/// One cycle of a waveform, as played by a `WaveformGenerator`.
+pub type Wavetable = [f32; WAVETABLE_SIZE];
 
 lazy_static! {
-    static ref WAVETABLES: [[f32; WAVETABLE_SIZE]; 5] = [
-        [0.0; WAVETABLE_SIZE],
-        {
+    /// The built-in waveforms, in `OscillatorWaveform` order.
+    static ref WAVETABLES: [Arc<Wavetable>; 5] = [
+        Arc::new([0.0; WAVETABLE_SIZE]),
+        Arc::new({
             let mut wavetable = [0.0; WAVETABLE_SIZE];
             for i in 0..WAVETABLE_SIZE {
                 wavetable[i] = ((i as f32 * TWO_PI) / WAVETABLE_SIZE as f32).sin();
             }
             wavetable
-        },
-        {
+        }),
+        Arc::new({
             let mut wavetable = [0.0; WAVETABLE_SIZE];
             for i in 0..WAVETABLE_SIZE {
                 wavetable[i] = if i < WAVETABLE_SIZE / 2 { 1.0 } else { -1.0 };
             }
             wavetable
-        },
-        {
+        }),
+        Arc::new({
             let mut wavetable = [0.0; WAVETABLE_SIZE];
             for i in 0..WAVETABLE_SIZE {
                 wavetable[i] = 2.0 * (i as f32 / WAVETABLE_SIZE as f32) - 1.0;
             }
             wavetable
-        },
-        {
+        }),
+        Arc::new({
             let mut wavetable = [0.0; WAVETABLE_SIZE];
             for i in 0..WAVETABLE_SIZE {
                 let phase = i as f32 / WAVETABLE_SIZE as f32;
@@ -40,65 +57,662 @@ lazy_static! {
                 };
             }
             wavetable
-        },
+        }),
     ];
+    /// Waveforms registered with `WaveformGenerator::register_wavetable`, indexed by
+    /// `OscillatorWaveform::Custom`.
+    static ref CUSTOM_WAVETABLES: RwLock<Vec<Arc<Wavetable>>> = RwLock::new(Vec::new());
+}
+
+/// How samples between wavetable entries are read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    /// Take the nearest lower table entry, for a gritty aliased chiptune sound. The cheapest.
+    #[serde(alias = "none")]
+    Nearest,
+    /// Blend linearly between adjacent table entries.
+    #[default]
+    Linear,
+    /// Fit a cubic Hermite curve through the four entries around the read position, which
+    /// keeps more of the top end than a straight line and costs about twice as much.
+    CubicHermite,
+}
+
+/// The range of frequencies generators are clamped to.
+///
+/// The clamp is applied to the final frequency, after octave shift and ribbon bend, so no note can
+/// be pushed outside it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrequencyLimits {
+    /// Lowest frequency in Hz. Lower it for deliberate sub-audio experiments; it must stay above
+    /// zero.
+    pub min: f32,
+    /// Highest frequency in Hz. Defaults to the lower of 20 kHz and 0.45 × the sample rate, and
+    /// never goes past Nyquist.
+    pub max: Option<f32>,
+}
+
+impl Default for FrequencyLimits {
+    fn default() -> Self {
+        FrequencyLimits {
+            min: MIN_FREQUENCY,
+            max: None,
+        }
+    }
+}
+
+/// What `FrequencyLimits::apply` made of a frequency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitedFrequency {
+    /// The frequency was in range and is passed through unchanged.
+    InRange(f32),
+    /// The frequency was out of range and was clamped to the nearest bound.
+    Clamped(f32),
+    /// The frequency was NaN or infinite and can't be played at all.
+    Invalid,
+}
+
+impl LimitedFrequency {
+    /// The frequency to play, if there is one.
+    pub fn frequency(&self) -> Option<f32> {
+        match self {
+            LimitedFrequency::InRange(frequency) | LimitedFrequency::Clamped(frequency) => {
+                Some(*frequency)
+            }
+            LimitedFrequency::Invalid => None,
+        }
+    }
+}
+
+impl FrequencyLimits {
+    /// The lowest and highest frequency allowed at `sample_rate`.
+    pub fn bounds(&self, sample_rate: f32) -> (f32, f32) {
+        let nyquist = sample_rate * 0.5;
+        let max = match self.max {
+            Some(max) if max.is_finite() && max > 0.0 => max.min(nyquist),
+            _ => MAX_FREQUENCY.min(sample_rate * MAX_FREQUENCY_RATIO),
+        };
+        let min = if self.min.is_finite() && self.min > 0.0 {
+            self.min
+        } else {
+            MIN_FREQUENCY
+        };
+        (min.min(max), max)
+    }
+
+    /// Clamps `frequency` into the bounds at `sample_rate`.
+    pub fn apply(&self, frequency: f32, sample_rate: f32) -> LimitedFrequency {
+        if !frequency.is_finite() {
+            return LimitedFrequency::Invalid;
+        }
+        let (min, max) = self.bounds(sample_rate);
+        if frequency < min {
+            LimitedFrequency::Clamped(min)
+        } else if frequency > max {
+            LimitedFrequency::Clamped(max)
+        } else {
+            LimitedFrequency::InRange(frequency)
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct WaveformGenerator {
-    wavetable: &'static [f32; WAVETABLE_SIZE],
+    waveform: OscillatorWaveform,
+    wavetable: Arc<Wavetable>,
+    /// Waveform and table blended in by `morph`, when morphing towards another waveform.
+    morph_wavetable: Option<(OscillatorWaveform, Arc<Wavetable>)>,
+    morph: f32,
     phase: f32,
     phase_inc: f32,
+    interpolation: Interpolation,
+    frequency_limits: FrequencyLimits,
     pub sample_rate: f32,
 }
 
 impl WaveformGenerator {
+    /// Creates a generator within the default `FrequencyLimits`. A NaN or infinite `frequency`
+    /// starts it at the lowest allowed frequency.
     pub fn new(waveform: OscillatorWaveform, frequency: f32, sample_rate: f32) -> Self {
-        let wavetable = match waveform {
-            OscillatorWaveform::Silence => &WAVETABLES[0],
-            OscillatorWaveform::Sine => &WAVETABLES[1],
-            OscillatorWaveform::Square => &WAVETABLES[2],
-            OscillatorWaveform::Sawtooth => &WAVETABLES[3],
-            OscillatorWaveform::Triangle => &WAVETABLES[4],
-        };
-        let phase_inc = frequency / sample_rate;
+        let frequency_limits = FrequencyLimits::default();
+        let frequency = frequency_limits
+            .apply(frequency, sample_rate)
+            .frequency()
+            .unwrap_or_else(|| frequency_limits.bounds(sample_rate).0);
         WaveformGenerator {
-            wavetable,
+            waveform,
+            wavetable: wavetable_for(waveform),
+            morph_wavetable: None,
+            morph: 0.0,
             phase: 0.0,
-            phase_inc,
+            phase_inc: frequency / sample_rate,
+            interpolation: Interpolation::default(),
+            frequency_limits,
             sample_rate,
         }
     }
 
+    /// Changes the allowed frequency range, clamping the current frequency into it.
+    pub fn set_frequency_limits(&mut self, frequency_limits: FrequencyLimits) -> LimitedFrequency {
+        self.frequency_limits = frequency_limits;
+        self.set_frequency(self.get_frequency())
+    }
+
+    pub fn get_frequency_limits(&self) -> FrequencyLimits {
+        self.frequency_limits
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    pub fn get_interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    /// Moves the read position to `phase`, in cycles. Values outside `[0, 1)` wrap around, so
+    /// `1.25` is the same as `0.25`.
+    pub fn set_phase(&mut self, phase: f32) {
+        self.phase = wrap_phase(phase);
+    }
+
+    pub fn get_phase(&self) -> f32 {
+        self.phase
+    }
+
     pub fn get_waveform(&self) -> OscillatorWaveform {
-        match self.wavetable {
-            wavetable if *wavetable == WAVETABLES[0] => OscillatorWaveform::Silence,
-            wavetable if *wavetable == WAVETABLES[1] => OscillatorWaveform::Sine,
-            wavetable if *wavetable == WAVETABLES[2] => OscillatorWaveform::Square,
-            wavetable if *wavetable == WAVETABLES[3] => OscillatorWaveform::Sawtooth,
-            wavetable if *wavetable == WAVETABLES[4] => OscillatorWaveform::Triangle,
-            _ => unreachable!(),
+        self.waveform
+    }
+
+    /// Registers `samples`, one cycle of a waveform, as a custom wavetable and returns the
+    /// waveform that plays it. Any length of two or more samples works; the cycle is resampled
+    /// to `WAVETABLE_SIZE` entries, so a table of exactly that size is played as given.
+    pub fn register_wavetable(samples: &[f32]) -> Result<OscillatorWaveform> {
+        if samples.len() < 2 {
+            bail!(
+                "A wavetable needs at least 2 samples, got {}",
+                samples.len()
+            );
+        }
+        if let Some(index) = samples.iter().position(|sample| !sample.is_finite()) {
+            bail!("Wavetable sample {} is {}", index, samples[index]);
         }
+
+        let wavetable = Arc::new(resample_cycle(samples));
+        let mut custom_wavetables = CUSTOM_WAVETABLES
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        custom_wavetables.push(wavetable);
+        let waveform = OscillatorWaveform::Custom(custom_wavetables.len() - 1);
+        info!(
+            "Registered a {}-sample wavetable as {:?}",
+            samples.len(),
+            waveform
+        );
+        Ok(waveform)
     }
 
     pub fn get_sample(&mut self) -> f32 {
-        let index = (self.phase * WAVETABLE_SIZE as f32) as usize;
-        let frac = self.phase * WAVETABLE_SIZE as f32 - index as f32;
-        let sample = self.wavetable[index];
-        let next_sample = self.wavetable[(index + 1) % WAVETABLE_SIZE];
-        let interpolated_sample = sample + frac * (next_sample - sample);
+        let mut sample = self.read_wavetable(&self.wavetable);
+        if let Some((_, morph_wavetable)) = &self.morph_wavetable {
+            let morph_sample = self.read_wavetable(morph_wavetable);
+            sample += self.morph * (morph_sample - sample);
+        }
         self.update_phase();
-        interpolated_sample
+        sample
+    }
+
+    fn read_wavetable(&self, wavetable: &Wavetable) -> f32 {
+        let position = self.phase * WAVETABLE_SIZE as f32;
+        let index = position as usize;
+        let frac = position - index as f32;
+        // A phase of exactly 1.0 lands one past the end, on the entry that starts the next
+        // cycle.
+        let index = index % WAVETABLE_SIZE;
+        let sample = wavetable[index];
+        match self.interpolation {
+            Interpolation::Nearest => sample,
+            Interpolation::Linear => {
+                let next_sample = wavetable[(index + 1) % WAVETABLE_SIZE];
+                sample + frac * (next_sample - sample)
+            }
+            Interpolation::CubicHermite => {
+                let previous = wavetable[(index + WAVETABLE_SIZE - 1) % WAVETABLE_SIZE];
+                let next = wavetable[(index + 1) % WAVETABLE_SIZE];
+                let after_next = wavetable[(index + 2) % WAVETABLE_SIZE];
+                cubic_hermite(previous, sample, next, after_next, frac)
+            }
+        }
+    }
+
+    /// Plays a crossfade between two waveforms: `mix` 0.0 is all `from`, 1.0 all `to`.
+    pub fn set_morph(&mut self, from: OscillatorWaveform, to: OscillatorWaveform, mix: f32) {
+        let mix = mix.clamp(0.0, 1.0);
+        if from == to || mix == 0.0 || mix == 1.0 {
+            self.set_base_waveform(if mix == 1.0 { to } else { from });
+            self.clear_morph();
+        } else {
+            self.set_base_waveform(from);
+            // This runs every sample while a waveform sequence plays, so the table is only
+            // looked up again when the waveform changes.
+            if !matches!(&self.morph_wavetable, Some((waveform, _)) if *waveform == to) {
+                self.morph_wavetable = Some((to, wavetable_for(to)));
+            }
+            self.morph = mix;
+        }
+    }
+
+    fn set_base_waveform(&mut self, waveform: OscillatorWaveform) {
+        if waveform != self.waveform {
+            self.waveform = waveform;
+            self.wavetable = wavetable_for(waveform);
+        }
+    }
+
+    /// Stops morphing, leaving the generator playing its base waveform.
+    pub fn clear_morph(&mut self) {
+        self.morph_wavetable = None;
+        self.morph = 0.0;
     }
+
     pub fn update_phase(&mut self) {
         self.phase = (self.phase + self.phase_inc) % 1.0;
     }
 
-    pub fn set_frequency(&mut self, frequency: f32) {
-        self.phase_inc = frequency / self.sample_rate;
+    /// Sets the frequency, clamped to the generator's limits. An invalid frequency is ignored
+    /// and the generator keeps its current one.
+    pub fn set_frequency(&mut self, frequency: f32) -> LimitedFrequency {
+        let limited = self.frequency_limits.apply(frequency, self.sample_rate);
+        if let Some(frequency) = limited.frequency() {
+            self.phase_inc = frequency / self.sample_rate;
+        }
+        limited
     }
 
     pub fn get_frequency(&self) -> f32 {
         self.phase_inc * self.sample_rate
     }
+
+    /// How far through a cycle the phase moves each sample.
+    pub fn phase_inc(&self) -> f32 {
+        self.phase_inc
+    }
+}
+
+/// The table that plays `waveform`. A custom waveform that was never registered plays silence.
+fn wavetable_for(waveform: OscillatorWaveform) -> Arc<Wavetable> {
+    let wavetable = match waveform {
+        OscillatorWaveform::Silence => &WAVETABLES[0],
+        OscillatorWaveform::Sine => &WAVETABLES[1],
+        OscillatorWaveform::Square => &WAVETABLES[2],
+        OscillatorWaveform::Sawtooth => &WAVETABLES[3],
+        OscillatorWaveform::Triangle => &WAVETABLES[4],
+        OscillatorWaveform::Custom(index) => {
+            let custom_wavetables = CUSTOM_WAVETABLES
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(wavetable) = custom_wavetables.get(index) {
+                return Arc::clone(wavetable);
+            }
+            log_rate_limited!(
+                warn,
+                HOT_PATH_LOG_INTERVAL,
+                "No custom wavetable {} is registered, playing silence",
+                index
+            );
+            &WAVETABLES[0]
+        }
+    };
+    Arc::clone(wavetable)
+}
+
+/// Stretches one cycle of `samples` over `WAVETABLE_SIZE` entries, blending linearly between
+/// neighbours and wrapping from the last sample back to the first.
+fn resample_cycle(samples: &[f32]) -> Wavetable {
+    let mut wavetable = [0.0; WAVETABLE_SIZE];
+    let step = samples.len() as f32 / WAVETABLE_SIZE as f32;
+    for (i, entry) in wavetable.iter_mut().enumerate() {
+        let position = i as f32 * step;
+        let index = position as usize;
+        let frac = position - index as f32;
+        let sample = samples[index];
+        let next_sample = samples[(index + 1) % samples.len()];
+        *entry = sample + frac * (next_sample - sample);
+    }
+    wavetable
+}
+
+/// The 4-point cubic Hermite (Catmull-Rom) curve through `y1` and `y2`, `frac` of the way
+/// from one to the other, with the slope at each taken from the points either side of it.
+fn cubic_hermite(y0: f32, y1: f32, y2: f32, y3: f32, frac: f32) -> f32 {
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * frac + c2) * frac + c1) * frac + y1
+}
+
+/// Wraps a phase in cycles into `[0, 1)`, treating non-finite values as `0`.
+fn wrap_phase(phase: f32) -> f32 {
+    if !phase.is_finite() {
+        log_rate_limited!(
+            warn,
+            HOT_PATH_LOG_INTERVAL,
+            "Invalid oscillator phase {}, using 0 instead",
+            phase
+        );
+        return 0.0;
+    }
+    let wrapped = phase.rem_euclid(1.0);
+    // rem_euclid can round up to exactly 1.0 for tiny negative inputs.
+    if wrapped >= 1.0 {
+        0.0
+    } else {
+        wrapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_reads_the_table_entry_below_without_blending() {
+        let sine = wavetable_for(OscillatorWaveform::Sine);
+        let mut generator = WaveformGenerator::new(OscillatorWaveform::Sine, 440.0, 48_000.0);
+        generator.set_interpolation(Interpolation::Nearest);
+        for position in [10.4, 100.5, 700.9] {
+            generator.set_phase(position / WAVETABLE_SIZE as f32);
+            let index = position as usize;
+            assert_eq!(generator.get_sample(), sine[index]);
+        }
+        // Linear at the same phase blends towards the next entry instead.
+        generator.set_interpolation(Interpolation::Linear);
+        generator.set_phase(100.5 / WAVETABLE_SIZE as f32);
+        let sample = generator.get_sample();
+        assert_ne!(sample, sine[100]);
+        assert!((sample - (sine[100] + sine[101]) / 2.0).abs() < 1e-6);
+    }
+
+    /// Whether `generator` gives finite samples that move, rather than sitting at one value.
+    fn plays_a_finite_moving_wave(generator: &mut WaveformGenerator) -> bool {
+        let samples: Vec<f32> = (0..4_800).map(|_| generator.get_sample()).collect();
+        let (min, max) = samples.iter().fold((f32::MAX, f32::MIN), |(min, max), s| {
+            (min.min(*s), max.max(*s))
+        });
+        samples.iter().all(|s| s.is_finite()) && max - min > 1.0
+    }
+
+    #[test]
+    fn zero_negative_and_nan_frequencies_still_play_a_wave() {
+        for frequency in [0.0, -440.0, f32::NAN, f32::INFINITY] {
+            let mut generator =
+                WaveformGenerator::new(OscillatorWaveform::Sine, frequency, 48_000.0);
+            assert_eq!(generator.get_frequency(), MIN_FREQUENCY, "{}", frequency);
+            assert!(plays_a_finite_moving_wave(&mut generator), "{}", frequency);
+        }
+    }
+
+    #[test]
+    fn setting_a_bad_frequency_keeps_the_wave_going() {
+        let mut generator = WaveformGenerator::new(OscillatorWaveform::Sine, 440.0, 48_000.0);
+        assert_eq!(
+            generator.set_frequency(0.0),
+            LimitedFrequency::Clamped(MIN_FREQUENCY)
+        );
+        assert!(plays_a_finite_moving_wave(&mut generator));
+        generator.set_frequency(-5.0);
+        assert_eq!(generator.get_frequency(), MIN_FREQUENCY);
+        assert!(plays_a_finite_moving_wave(&mut generator));
+        generator.set_frequency(440.0);
+        assert_eq!(generator.set_frequency(f32::NAN), LimitedFrequency::Invalid);
+        assert!((generator.get_frequency() - 440.0).abs() < 1e-3);
+        assert!(plays_a_finite_moving_wave(&mut generator));
+    }
+
+    #[test]
+    fn a_quarter_cycle_phase_offset_runs_a_quarter_period_ahead() {
+        // 480 Hz at 48 kHz is 100 samples a cycle, so a quarter-cycle is 25 samples.
+        let mut aligned = WaveformGenerator::new(OscillatorWaveform::Sine, 480.0, 48_000.0);
+        let mut offset = WaveformGenerator::new(OscillatorWaveform::Sine, 480.0, 48_000.0);
+        offset.set_phase(0.25);
+        let aligned: Vec<f32> = (0..400).map(|_| aligned.get_sample()).collect();
+        let offset: Vec<f32> = (0..375).map(|_| offset.get_sample()).collect();
+        for (i, sample) in offset.iter().enumerate() {
+            assert!(
+                (sample - aligned[i + 25]).abs() < 1e-3,
+                "sample {}: {} against {}",
+                i,
+                sample,
+                aligned[i + 25]
+            );
+        }
+        // A sine a quarter-cycle in starts at its peak.
+        assert!((offset[0] - 1.0).abs() < 1e-3);
+        assert!(aligned[0].abs() < 1e-3);
+    }
+
+    #[test]
+    fn frequencies_are_clamped_at_both_ends() {
+        let limits = FrequencyLimits::default();
+        assert_eq!(
+            limits.apply(1.0, 48_000.0),
+            LimitedFrequency::Clamped(MIN_FREQUENCY)
+        );
+        assert_eq!(
+            limits.apply(0.0, 48_000.0),
+            LimitedFrequency::Clamped(MIN_FREQUENCY)
+        );
+        // The top is 20 kHz, or 0.45 of the sample rate where that is lower.
+        assert_eq!(
+            limits.apply(30_000.0, 96_000.0),
+            LimitedFrequency::Clamped(20_000.0)
+        );
+        assert_eq!(
+            limits.apply(30_000.0, 44_100.0),
+            LimitedFrequency::Clamped(19_845.0)
+        );
+        assert_eq!(
+            limits.apply(30_000.0, 8_000.0),
+            LimitedFrequency::Clamped(3_600.0)
+        );
+
+        // Configured limits move the bounds, but never past Nyquist.
+        let limits = FrequencyLimits {
+            min: 0.5,
+            max: Some(40_000.0),
+        };
+        assert_eq!(limits.apply(1.0, 48_000.0), LimitedFrequency::InRange(1.0));
+        assert_eq!(limits.apply(0.1, 48_000.0), LimitedFrequency::Clamped(0.5));
+        assert_eq!(
+            limits.apply(30_000.0, 48_000.0),
+            LimitedFrequency::Clamped(24_000.0)
+        );
+        // A minimum that isn't above zero falls back to the default.
+        let limits = FrequencyLimits {
+            min: 0.0,
+            max: None,
+        };
+        assert_eq!(limits.bounds(48_000.0).0, MIN_FREQUENCY);
+    }
+
+    #[test]
+    fn nan_and_infinite_frequencies_are_rejected() {
+        let limits = FrequencyLimits::default();
+        for frequency in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(limits.apply(frequency, 48_000.0), LimitedFrequency::Invalid);
+            assert_eq!(limits.apply(frequency, 48_000.0).frequency(), None);
+        }
+        let mut generator = WaveformGenerator::new(OscillatorWaveform::Sine, 440.0, 48_000.0);
+        let phase_inc = generator.phase_inc;
+        assert_eq!(
+            generator.set_frequency(f32::INFINITY),
+            LimitedFrequency::Invalid
+        );
+        assert_eq!(generator.phase_inc.to_bits(), phase_inc.to_bits());
+    }
+
+    #[test]
+    fn musical_frequencies_pass_through_unchanged() {
+        for sample_rate in [44_100.0, 48_000.0, 96_000.0] {
+            let mut generator =
+                WaveformGenerator::new(OscillatorWaveform::Sine, 440.0, sample_rate);
+            // Every key of a piano, A0 to C8.
+            for key in 21..=108 {
+                let frequency = 440.0 * 2.0f32.powf((key as f32 - 69.0) / 12.0);
+                assert_eq!(
+                    generator.set_frequency(frequency),
+                    LimitedFrequency::InRange(frequency)
+                );
+                assert_eq!(
+                    generator.phase_inc.to_bits(),
+                    (frequency / sample_rate).to_bits(),
+                    "key {} at {} Hz",
+                    key,
+                    sample_rate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_registered_ramp_is_read_back_by_get_sample() {
+        let ramp: Vec<f32> = (0..WAVETABLE_SIZE)
+            .map(|i| i as f32 / WAVETABLE_SIZE as f32 * 2.0 - 1.0)
+            .collect();
+        let waveform = WaveformGenerator::register_wavetable(&ramp).unwrap();
+        assert!(matches!(waveform, OscillatorWaveform::Custom(_)));
+
+        let mut generator = WaveformGenerator::new(waveform, 440.0, 48_000.0);
+        generator.set_interpolation(Interpolation::Nearest);
+        for index in [0, 1, 511, 1_000, WAVETABLE_SIZE - 1] {
+            generator.set_phase(index as f32 / WAVETABLE_SIZE as f32);
+            assert_eq!(generator.get_sample(), ramp[index], "entry {}", index);
+        }
+        // A full table plays from start to end, one entry a sample at one table a second.
+        let mut generator =
+            WaveformGenerator::new(waveform, 48_000.0 / WAVETABLE_SIZE as f32, 48_000.0);
+        generator.set_interpolation(Interpolation::Linear);
+        for expected in &ramp[..64] {
+            assert!((generator.get_sample() - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn a_short_cycle_is_stretched_over_the_table() {
+        let waveform = WaveformGenerator::register_wavetable(&[0.0, 1.0, 0.0, -1.0]).unwrap();
+        let mut generator = WaveformGenerator::new(waveform, 440.0, 48_000.0);
+        generator.set_interpolation(Interpolation::Nearest);
+        for (phase, expected) in [(0.0, 0.0), (0.125, 0.5), (0.25, 1.0), (0.75, -1.0)] {
+            generator.set_phase(phase);
+            assert_eq!(generator.get_sample(), expected, "phase {}", phase);
+        }
+    }
+
+    #[test]
+    fn unplayable_cycles_are_refused_and_unknown_tables_are_silent() {
+        assert!(WaveformGenerator::register_wavetable(&[0.5]).is_err());
+        assert!(WaveformGenerator::register_wavetable(&[0.0, f32::NAN, 1.0]).is_err());
+
+        let mut generator =
+            WaveformGenerator::new(OscillatorWaveform::Custom(usize::MAX), 440.0, 48_000.0);
+        assert!((0..512).all(|_| generator.get_sample() == 0.0));
+    }
+
+    const MODES: [Interpolation; 3] = [
+        Interpolation::Nearest,
+        Interpolation::Linear,
+        Interpolation::CubicHermite,
+    ];
+
+    /// RMS difference between a sine generator read back with `interpolation` and the sine
+    /// itself at the phase each sample was read at.
+    fn sine_error(interpolation: Interpolation) -> f64 {
+        // √2 × 440 Hz, which never lands on the table's entries in a repeating pattern.
+        let mut generator =
+            WaveformGenerator::new(OscillatorWaveform::Sine, 440.0 * 2f32.sqrt(), 48_000.0);
+        generator.set_interpolation(interpolation);
+        let squares: f64 = (0..48_000)
+            .map(|_| {
+                let expected = (std::f64::consts::TAU * generator.get_phase() as f64).sin();
+                (generator.get_sample() as f64 - expected).powi(2)
+            })
+            .sum();
+        (squares / 48_000.0).sqrt()
+    }
+
+    #[test]
+    fn cubic_reads_a_sine_back_closer_than_linear_which_beats_nearest() {
+        let [nearest, linear, cubic] = MODES.map(sine_error);
+        // Nearest is off by up to a whole table step, linear by the sag of a chord across one,
+        // cubic by little more than the rounding of the table itself.
+        assert!(nearest > 1e-3, "{}", nearest);
+        assert!(linear < nearest / 100.0, "{} {}", linear, nearest);
+        assert!(cubic < linear / 10.0, "{} {}", cubic, linear);
+    }
+
+    #[test]
+    fn every_mode_repeats_bit_for_bit_across_the_phase_wrap() {
+        for interpolation in MODES {
+            for waveform in [OscillatorWaveform::Sine, OscillatorWaveform::Sawtooth] {
+                // 750 Hz steps exactly a 64th of a cycle, so every cycle is read at the same
+                // phases.
+                let mut generator = WaveformGenerator::new(waveform, 750.0, 48_000.0);
+                generator.set_interpolation(interpolation);
+                let first: Vec<f32> = (0..64).map(|_| generator.get_sample()).collect();
+                assert_eq!(generator.get_phase(), 0.0);
+                for _ in 0..100 {
+                    let cycle: Vec<f32> = (0..64).map(|_| generator.get_sample()).collect();
+                    assert_eq!(
+                        cycle.iter().map(|s| s.to_bits()).collect::<Vec<_>>(),
+                        first.iter().map(|s| s.to_bits()).collect::<Vec<_>>(),
+                        "{:?} {:?}",
+                        interpolation,
+                        waveform
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reading_either_side_of_the_wrap_meets_in_the_middle() {
+        for interpolation in MODES {
+            let mut generator = WaveformGenerator::new(OscillatorWaveform::Sine, 440.0, 48_000.0);
+            generator.set_interpolation(interpolation);
+            generator.set_phase(0.0);
+            let at_start = generator.get_sample();
+            generator.set_phase(1.0 - f32::EPSILON);
+            let at_end = generator.get_sample();
+            // The last read before the wrap is a hair short of the first after it.
+            let step = TWO_PI / WAVETABLE_SIZE as f32;
+            assert!((at_end - at_start).abs() < step, "{:?}", interpolation);
+            assert!(at_end <= at_start, "{:?}", interpolation);
+        }
+    }
+
+    #[test]
+    fn a_phase_of_exactly_one_reads_the_start_of_the_table() {
+        for interpolation in MODES {
+            for waveform in [OscillatorWaveform::Sine, OscillatorWaveform::Square] {
+                let mut generator = WaveformGenerator::new(waveform, 440.0, 48_000.0);
+                generator.set_interpolation(interpolation);
+                generator.set_phase(0.0);
+                let at_zero = generator.get_sample();
+                // `set_phase` wraps 1.0 to 0.0, so put it there directly: the position lands
+                // on index `WAVETABLE_SIZE`, one past the end.
+                generator.phase = 1.0;
+                assert_eq!(
+                    generator.get_sample().to_bits(),
+                    at_zero.to_bits(),
+                    "{:?} {:?}",
+                    interpolation,
+                    waveform
+                );
+                // And the phase carries on from the start of the next cycle.
+                assert!(generator.get_phase() < 0.1);
+            }
+        }
+    }
 }
@@ -0,0 +1,295 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::synth::OscillatorWaveform;
+
+/// Length of one sequence step as a fraction of a 4/4 bar: `1/4` steps every beat, `1` every
+/// bar. Written in the config as a fraction (`1/4`) or a number (`0.25`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "StepRateRepr", into = "String")]
+pub struct StepRate(f32);
+
+impl StepRate {
+    pub fn new(bars: f32) -> Result<Self> {
+        if !(bars.is_finite() && bars > 0.0) {
+            bail!(
+                "Step rate must be a positive fraction of a bar, got {}",
+                bars
+            );
+        }
+        Ok(StepRate(bars))
+    }
+
+    /// Length of a step in bars.
+    pub fn bars(&self) -> f32 {
+        self.0
+    }
+
+    /// Length of a step in beats, with four beats to the bar.
+    pub fn beats(&self) -> f32 {
+        self.0 * 4.0
+    }
+}
+
+impl Default for StepRate {
+    fn default() -> Self {
+        StepRate(0.25)
+    }
+}
+
+impl FromStr for StepRate {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let bars = match text.split_once('/') {
+            Some((numerator, denominator)) => {
+                let numerator: f32 = numerator.trim().parse()?;
+                let denominator: f32 = denominator.trim().parse()?;
+                numerator / denominator
+            }
+            None => text.trim().parse()?,
+        };
+        StepRate::new(bars).map_err(|err| anyhow!("Invalid step rate '{}': {}", text, err))
+    }
+}
+
+impl fmt::Display for StepRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<StepRate> for String {
+    fn from(rate: StepRate) -> Self {
+        rate.to_string()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StepRateRepr {
+    Number(f32),
+    Text(String),
+}
+
+impl TryFrom<StepRateRepr> for StepRate {
+    type Error = anyhow::Error;
+
+    fn try_from(repr: StepRateRepr) -> Result<Self> {
+        match repr {
+            StepRateRepr::Number(bars) => StepRate::new(bars),
+            StepRateRepr::Text(text) => text.parse(),
+        }
+    }
+}
+
+/// Settings for stepping the waveform through a pattern in time with the engine clock.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WaveformSequenceConfig {
+    /// Waveforms to step through, looping at the end.
+    pub steps: Vec<OscillatorWaveform>,
+    /// How long each step lasts.
+    pub rate: StepRate,
+    /// Fraction of each step, from its start, spent morphing from the previous waveform. Zero
+    /// switches instantly on the step.
+    pub morph_time: f32,
+    /// Tempo in beats per minute.
+    pub tempo: f32,
+    /// Also move voices that were already sounding when the sequence was switched on onto it.
+    pub apply_to_playing: bool,
+}
+
+impl Default for WaveformSequenceConfig {
+    fn default() -> Self {
+        WaveformSequenceConfig {
+            steps: vec![
+                OscillatorWaveform::Sine,
+                OscillatorWaveform::Sawtooth,
+                OscillatorWaveform::Square,
+                OscillatorWaveform::Sawtooth,
+            ],
+            rate: StepRate::default(),
+            morph_time: 0.5,
+            tempo: 120.0,
+            apply_to_playing: true,
+        }
+    }
+}
+
+/// The waveform a sequence plays at one sample: a blend from `from` to `to`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SequenceShape {
+    pub from: OscillatorWaveform,
+    pub to: OscillatorWaveform,
+    /// 0.0 is all `from`, 1.0 all `to`.
+    pub mix: f32,
+}
+
+/// A waveform pattern laid out on the engine's sample clock.
+///
+/// Steps are counted from engine sample 0, so every voice following the sequence agrees on
+/// where the steps fall no matter when it started.
+#[derive(Debug, Clone)]
+pub struct WaveformSequence {
+    steps: Vec<OscillatorWaveform>,
+    step_samples: f64,
+    morph_samples: f64,
+}
+
+impl WaveformSequence {
+    /// Lays the configured pattern out at `sample_rate`. Returns `None` when there are no
+    /// steps to play.
+    pub fn new(config: &WaveformSequenceConfig, sample_rate: f32) -> Option<Self> {
+        if config.steps.is_empty() {
+            return None;
+        }
+        let tempo = if config.tempo.is_finite() && config.tempo > 0.0 {
+            config.tempo
+        } else {
+            WaveformSequenceConfig::default().tempo
+        };
+        let step_samples = config.rate.beats() as f64 * 60.0 / tempo as f64 * sample_rate as f64;
+        Some(WaveformSequence {
+            steps: config.steps.clone(),
+            step_samples,
+            morph_samples: step_samples * config.morph_time.clamp(0.0, 1.0) as f64,
+        })
+    }
+
+    /// Length of a step in samples.
+    pub fn step_samples(&self) -> f64 {
+        self.step_samples
+    }
+
+    /// Length of the morph at the start of each step in samples.
+    pub fn morph_samples(&self) -> f64 {
+        self.morph_samples
+    }
+
+    /// Index into the pattern of the step playing at engine sample `sample`.
+    pub fn step_at(&self, sample: u64) -> usize {
+        (sample as f64 / self.step_samples) as usize % self.steps.len()
+    }
+
+    /// The shape to play at engine sample `sample`.
+    pub fn shape_at(&self, sample: u64) -> SequenceShape {
+        let step = (sample as f64 / self.step_samples).floor();
+        let offset = sample as f64 - step * self.step_samples;
+        let index = step as usize % self.steps.len();
+        let to = self.steps[index];
+        let from = self.steps[(index + self.steps.len() - 1) % self.steps.len()];
+
+        // The very first step has nothing to morph from.
+        let mix = if step > 0.0 && offset < self.morph_samples {
+            (offset / self.morph_samples) as f32
+        } else {
+            1.0
+        };
+        SequenceShape { from, to, mix }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    /// A beat at 120 BPM.
+    const BEAT: u64 = 24_000;
+
+    fn sequence() -> (WaveformSequenceConfig, WaveformSequence) {
+        let config = WaveformSequenceConfig {
+            steps: vec![
+                OscillatorWaveform::Sine,
+                OscillatorWaveform::Sawtooth,
+                OscillatorWaveform::Square,
+                OscillatorWaveform::Triangle,
+            ],
+            rate: "1/4".parse().unwrap(),
+            morph_time: 0.5,
+            tempo: 120.0,
+            apply_to_playing: true,
+        };
+        let sequence = WaveformSequence::new(&config, SAMPLE_RATE).unwrap();
+        (config, sequence)
+    }
+
+    #[test]
+    fn the_middle_of_each_beat_plays_its_step() {
+        let (config, sequence) = sequence();
+        assert_eq!(sequence.step_samples(), BEAT as f64);
+        for beat in 0..4 {
+            let middle = beat * BEAT + BEAT / 2;
+            let shape = sequence.shape_at(middle);
+            assert_eq!(shape.to, config.steps[beat as usize], "beat {}", beat);
+            assert_eq!(shape.mix, 1.0, "beat {}", beat);
+            assert_eq!(sequence.step_at(middle), beat as usize);
+        }
+        // The pattern loops after the last step.
+        assert_eq!(sequence.shape_at(4 * BEAT + BEAT / 2).to, config.steps[0]);
+    }
+
+    #[test]
+    fn each_transition_lasts_morph_time_times_the_step() {
+        let (config, sequence) = sequence();
+        let morph = (config.morph_time as f64 * BEAT as f64) as u64;
+        assert_eq!(sequence.morph_samples(), morph as f64);
+
+        // Nothing to morph from before the first step.
+        assert!((0..BEAT).all(|sample| sequence.shape_at(sample).mix == 1.0));
+        for beat in 1..4 {
+            let start = beat * BEAT;
+            let morphing: Vec<u64> = (start..start + BEAT)
+                .filter(|&sample| sequence.shape_at(sample).mix < 1.0)
+                .collect();
+            assert_eq!(morphing.len() as u64, morph, "beat {}", beat);
+            assert_eq!(morphing.first(), Some(&start));
+            assert_eq!(morphing.last(), Some(&(start + morph - 1)));
+
+            let shape = sequence.shape_at(start);
+            assert_eq!(shape.from, config.steps[beat as usize - 1]);
+            assert_eq!(shape.to, config.steps[beat as usize]);
+            assert_eq!(shape.mix, 0.0);
+            let halfway = sequence.shape_at(start + morph / 2);
+            assert!((halfway.mix - 0.5).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn a_zero_morph_time_switches_on_the_step() {
+        let (mut config, _) = sequence();
+        config.morph_time = 0.0;
+        let sequence = WaveformSequence::new(&config, SAMPLE_RATE).unwrap();
+        assert_eq!(sequence.shape_at(BEAT - 1).to, OscillatorWaveform::Sine);
+        let shape = sequence.shape_at(BEAT);
+        assert_eq!(shape.to, OscillatorWaveform::Sawtooth);
+        assert_eq!(shape.mix, 1.0);
+    }
+
+    #[test]
+    fn step_rates_parse_as_fractions_or_numbers() {
+        assert_eq!("1/4".parse::<StepRate>().unwrap().beats(), 1.0);
+        assert_eq!("1".parse::<StepRate>().unwrap().beats(), 4.0);
+        assert_eq!("0.125".parse::<StepRate>().unwrap().bars(), 0.125);
+        assert!("0".parse::<StepRate>().is_err());
+        assert!("1/0".parse::<StepRate>().is_err());
+        assert!("fast".parse::<StepRate>().is_err());
+
+        let config: WaveformSequenceConfig =
+            serde_yaml::from_str("steps: [Sine, Square]\nrate: 1/8\n").unwrap();
+        assert_eq!(config.rate.bars(), 0.125);
+        let config: WaveformSequenceConfig = serde_yaml::from_str("rate: 0.5\n").unwrap();
+        assert_eq!(config.rate.bars(), 0.5);
+    }
+
+    #[test]
+    fn an_empty_pattern_has_no_sequence() {
+        let (mut config, _) = sequence();
+        config.steps.clear();
+        assert!(WaveformSequence::new(&config, SAMPLE_RATE).is_none());
+    }
+}
@@ -0,0 +1,66 @@
+//! Builds examples/ffi_host.c against the library and runs it, proving a C host can link to
+//! the interface declared in include/visiosynth.h, and checks that header is the one the build
+//! generates.
+#![cfg(all(feature = "ffi", unix))]
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The directory cargo built the shared library into for this test run.
+fn library_dir() -> PathBuf {
+    // Tests run from target/<profile>/deps, where the library is built too.
+    let exe = env::current_exe().unwrap();
+    let deps = exe.parent().unwrap().to_path_buf();
+    let built = ["libvisiosynth.so", "libvisiosynth.dylib"]
+        .iter()
+        .any(|name| deps.join(name).exists());
+    assert!(built, "the cdylib wasn't built into {}", deps.display());
+    deps
+}
+
+#[test]
+fn the_example_c_host_links_and_plays() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let library_dir = library_dir();
+    let host = Path::new(env!("CARGO_TARGET_TMPDIR")).join("ffi_host");
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let status = Command::new(compiler)
+        .arg(root.join("examples/ffi_host.c"))
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg(format!("-I{}", root.join("include").display()))
+        .arg(format!("-L{}", library_dir.display()))
+        .arg(format!("-Wl,-rpath,{}", library_dir.display()))
+        .args(["-lvisiosynth", "-lm", "-o"])
+        .arg(&host)
+        .status()
+        .expect("couldn't run the C compiler");
+    assert!(status.success(), "examples/ffi_host.c didn't build");
+
+    let output = Command::new(&host).output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("parameters: 0=waveform 1="));
+    let peaks: Vec<f32> = lines
+        .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(peaks.len(), 4, "{}", stdout);
+    assert!(peaks.iter().all(|peak| *peak > 0.0), "{}", stdout);
+}
+
+#[test]
+fn the_committed_header_matches_the_generated_one() {
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/visiosynth.h"));
+    let committed = include_str!("../include/visiosynth.h");
+    assert!(
+        committed == generated,
+        "include/visiosynth.h is out of date; copy {}/visiosynth.h over it",
+        env!("OUT_DIR")
+    );
+}
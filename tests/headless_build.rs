@@ -0,0 +1,38 @@
+//! Checks the library with the `visualization` feature off, proving the synth builds for
+//! audio-only use without the window and GPU dependencies.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn the_library_builds_without_the_visualization_feature() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // A target directory of its own, so the check doesn't wait on the build running the tests.
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("headless");
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+
+    let output = Command::new(cargo)
+        .args(["check", "--lib", "--no-default-features", "--manifest-path"])
+        .arg(root.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .output()
+        .expect("couldn't run cargo");
+    assert!(
+        output.status.success(),
+        "the library didn't build without `visualization`:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // Nothing from the window or the GPU was compiled in.
+    let log = String::from_utf8_lossy(&output.stderr);
+    for dependency in ["wgpu", "winit"] {
+        assert!(
+            !log.contains(&format!("Checking {} ", dependency))
+                && !log.contains(&format!("Compiling {} ", dependency)),
+            "{} was built:\n{}",
+            dependency,
+            log
+        );
+    }
+}